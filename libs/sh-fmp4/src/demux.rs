@@ -0,0 +1,233 @@
+use bytes::{Buf, Bytes, BytesMut};
+use sh_media::{
+    fill_buffer, ByteReadFilter, Frame, FrameDependency, FrameReadFilter, MediaTime, Stream,
+};
+use tracing::*;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Instant,
+};
+
+use crate::index::{be_u32, read_moof_tracks, FragmentInfo, Mp4Index, TrackDefaults};
+
+/// The largest box which is accepted, so a corrupt size doesn't make the
+/// filter buffer the whole stream.
+const MAX_BOX_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Makes frames of the samples of a track fragment, where `data` is the
+/// data of the fragment starting at `data_offset` in the file.
+pub fn fragment_frames(
+    fragment: &FragmentInfo,
+    stream: &Stream,
+    data: &Bytes,
+    data_offset: u64,
+) -> anyhow::Result<Vec<Frame>> {
+    let mut frames = Vec::with_capacity(fragment.samples.len());
+    let mut decode_time = fragment.decode_time;
+
+    for sample in &fragment.samples {
+        // the offsets and sizes come from the publisher, so they are
+        // checked rather than trusted to fit
+        let start = sample
+            .offset
+            .checked_sub(data_offset)
+            .and_then(|start| usize::try_from(start).ok())
+            .ok_or_else(|| anyhow::anyhow!("Sample is before its data"))?;
+        let end = start
+            .checked_add(sample.size as usize)
+            .filter(|&end| end <= data.len())
+            .ok_or_else(|| anyhow::anyhow!("Sample is outside of its data"))?;
+
+        frames.push(Frame {
+            time: MediaTime {
+                pts: decode_time.saturating_add_signed(sample.composition_offset as i64),
+                dts: Some(decode_time),
+                timebase: stream.timebase,
+            },
+            dependency: if sample.sync {
+                FrameDependency::None
+            } else {
+                FrameDependency::Backwards
+            },
+            buffer: data.slice(start..end),
+            stream: stream.clone(),
+            received: Instant::now(),
+            metadata: None,
+        });
+
+        decode_time = decode_time
+            .checked_add(sample.duration as u64)
+            .ok_or_else(|| anyhow::anyhow!("Sample decode time overflows"))?;
+    }
+
+    Ok(frames)
+}
+
+/// A pull filter which reads the H.264 and AAC frames of a fragmented MP4
+/// stream, like the ones recorded by browsers with `MediaRecorder`.
+pub struct FragmentedMp4ReadFilter {
+    read: Box<dyn ByteReadFilter + Send + Unpin>,
+    buffer: BytesMut,
+    /// Where the start of the buffer is in the stream.
+    position: u64,
+
+    index: Mp4Index,
+    defaults: HashMap<u32, TrackDefaults>,
+    /// The streams of the read tracks, by track ID.
+    streams: Vec<(u32, Stream)>,
+    /// The track fragments of the latest `moof` box, which are waiting for
+    /// their `mdat` box.
+    fragments: Vec<FragmentInfo>,
+    frames: VecDeque<Frame>,
+}
+
+impl FragmentedMp4ReadFilter {
+    pub fn new(read: Box<dyn ByteReadFilter + Send + Unpin>) -> Self {
+        FragmentedMp4ReadFilter {
+            read,
+            buffer: BytesMut::new(),
+            position: 0,
+            index: Mp4Index::default(),
+            defaults: HashMap::new(),
+            streams: Vec::new(),
+            fragments: Vec::new(),
+            frames: VecDeque::new(),
+        }
+    }
+
+    /// Reads the next box, returning its type, where it starts, where its
+    /// content starts and its content.
+    async fn next_box(&mut self) -> anyhow::Result<([u8; 4], u64, u64, Bytes)> {
+        fill_buffer(self.read.as_mut(), &mut self.buffer, 8).await?;
+
+        let kind = [
+            self.buffer[4],
+            self.buffer[5],
+            self.buffer[6],
+            self.buffer[7],
+        ];
+        let (header_size, size) = match be_u32(&self.buffer) {
+            0 => anyhow::bail!("{:?} box without a size can't be streamed", kind),
+            1 => {
+                fill_buffer(self.read.as_mut(), &mut self.buffer, 16).await?;
+                (16, u64::from_be_bytes(self.buffer[8..16].try_into()?))
+            }
+            size => (8, size as u64),
+        };
+
+        if size < header_size || size > MAX_BOX_SIZE {
+            anyhow::bail!("invalid size {} of {:?} box", size, kind);
+        }
+
+        fill_buffer(self.read.as_mut(), &mut self.buffer, size as usize).await?;
+
+        let offset = self.position;
+        let mut content = self.buffer.split_to(size as usize).freeze();
+        content.advance(header_size as usize);
+        self.position += size;
+
+        Ok((kind, offset, offset + header_size, content))
+    }
+
+    /// Reads the next box, adding the frames of its samples if it has any.
+    async fn fetch(&mut self) -> anyhow::Result<()> {
+        let (kind, offset, content_offset, content) = self.next_box().await?;
+
+        match &kind {
+            b"moov" => {
+                self.index.read_moov(&content, &mut self.defaults)?;
+                self.index.moov = content.to_vec();
+                self.streams = self.index.streams()?;
+
+                debug!("Got fMP4 streams {:?}", self.streams);
+            }
+            b"moof" => {
+                self.fragments = read_moof_tracks(&content, offset, &self.defaults)?;
+            }
+            b"mdat" => {
+                for fragment in std::mem::take(&mut self.fragments) {
+                    let stream = self.streams.iter().find(|(id, _)| *id == fragment.track_id);
+
+                    if let Some((_, stream)) = stream {
+                        let frames = fragment_frames(&fragment, stream, &content, content_offset)?;
+                        self.frames.extend(frames);
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl FrameReadFilter for FragmentedMp4ReadFilter {
+    async fn start(&mut self) -> anyhow::Result<Vec<Stream>> {
+        self.read.start().await?;
+
+        while self.streams.is_empty() {
+            self.fetch().await?;
+        }
+
+        Ok(self.streams.iter().map(|(_, s)| s.clone()).collect())
+    }
+
+    async fn read(&mut self) -> anyhow::Result<Frame> {
+        loop {
+            if let Some(frame) = self.frames.pop_front() {
+                return Ok(frame);
+            }
+
+            self.fetch().await?;
+        }
+    }
+}
+
+#[test]
+fn samples_outside_of_their_data_are_rejected() {
+    use crate::index::SampleInfo;
+    use sh_media::{
+        AudioCodecInfo, AudioCodecSpecificInfo, CodecInfo, CodecTypeInfo, Fraction, SoundType,
+    };
+    use std::sync::Arc;
+
+    let stream = Stream {
+        id: 0,
+        codec: Arc::new(CodecInfo {
+            name: "mp3",
+            properties: CodecTypeInfo::Audio(AudioCodecInfo {
+                sample_rate: 48000,
+                sample_bpp: 16,
+                sound_type: SoundType::Stereo,
+                extra: AudioCodecSpecificInfo::Mp3,
+            }),
+        }),
+        timebase: Fraction::new(1, 1000),
+    };
+    let data = Bytes::from_static(&[0; 32]);
+    let frames = |offset, size| {
+        let fragment = FragmentInfo {
+            bytes: 0..0,
+            track_id: 1,
+            decode_time: 0,
+            duration: 0,
+            keyframe: true,
+            samples: vec![SampleInfo {
+                offset,
+                size,
+                duration: 1,
+                composition_offset: 0,
+                sync: true,
+            }],
+        };
+
+        fragment_frames(&fragment, &stream, &data, 100)
+    };
+
+    assert_eq!(frames(100, 32).unwrap()[0].buffer.len(), 32);
+    assert!(frames(99, 1).is_err());
+    assert!(frames(110, 32).is_err());
+    assert!(frames(u64::MAX, u32::MAX).is_err());
+}
@@ -0,0 +1,922 @@
+use sh_media::{
+    mp3_codec_info, AudioCodecInfo, AudioCodecSpecificInfo, Av1CodecConfiguration,
+    BitstreamFraming, CodecInfo, CodecTypeInfo, Fraction, HdrMetadata, HevcDecoderConfiguration,
+    OpusHeader, SoundType, Stream, VideoCodecInfo, VideoCodecSpecificInfo,
+};
+
+use std::{
+    collections::HashMap,
+    io::{Read, Seek, SeekFrom},
+    ops::Range,
+    sync::Arc,
+};
+
+/// Set in the sample flags of samples which are not sync samples.
+const SAMPLE_IS_NON_SYNC: u32 = 0x10000;
+
+/// The most samples a track fragment may have, so a corrupt count doesn't
+/// make the index allocate without bound.
+const MAX_FRAGMENT_SAMPLES: usize = 64 * 1024;
+
+/// The location and timing of the fragments of a fragmented MP4 file, which
+/// is enough to serve the file in segments without rewriting it.
+#[derive(Debug, Clone, Default)]
+pub struct Mp4Index {
+    /// The bytes of the `ftyp` and `moov` boxes, which initialize a decoder.
+    pub init: Range<u64>,
+    /// The timescale of each track by track ID.
+    pub timescales: HashMap<u32, u32>,
+    /// The handler type of each track by track ID, e.g. `vide` or `soun`.
+    pub handlers: HashMap<u32, [u8; 4]>,
+    /// The RFC 6381 codec string of each track by track ID, e.g.
+    /// `avc1.64001f`, if the codec is known.
+    pub codecs: HashMap<u32, String>,
+    /// The content of the `moov` box.
+    pub moov: Vec<u8>,
+    /// Every fragment in file order.
+    pub fragments: Vec<FragmentInfo>,
+}
+
+/// A `moof` box and the `mdat` boxes following it.
+#[derive(Debug, Clone)]
+pub struct FragmentInfo {
+    pub bytes: Range<u64>,
+    pub track_id: u32,
+    /// The decode time of the first sample, in the timescale of the track.
+    pub decode_time: u64,
+    /// The total duration of the samples, in the timescale of the track.
+    pub duration: u64,
+    /// Whether the fragment starts with a sync sample.
+    pub keyframe: bool,
+    pub samples: Vec<SampleInfo>,
+}
+
+/// A sample of a fragment and where its data is in the file.
+#[derive(Debug, Clone, Copy)]
+pub struct SampleInfo {
+    pub offset: u64,
+    pub size: u32,
+    /// In the timescale of the track.
+    pub duration: u32,
+    /// The difference between the presentation and decode time, in the
+    /// timescale of the track.
+    pub composition_offset: i32,
+    pub sync: bool,
+}
+
+impl Mp4Index {
+    /// Reads the box structure of a fragmented MP4 file. Only the `moov` and
+    /// `moof` boxes are read, media data is skipped.
+    pub fn read<R: Read + Seek>(reader: &mut R) -> anyhow::Result<Self> {
+        Self::read_boxes(reader, false)
+    }
+
+    /// Reads the box structure of a fragmented MP4 file like
+    /// [`Mp4Index::read`], while it is still being written. The box at the
+    /// end which is only partly written is left out, along with the
+    /// fragment whose media data it is.
+    pub fn read_growing<R: Read + Seek>(reader: &mut R) -> anyhow::Result<Self> {
+        Self::read_boxes(reader, true)
+    }
+
+    fn read_boxes<R: Read + Seek>(reader: &mut R, growing: bool) -> anyhow::Result<Self> {
+        let mut index = Mp4Index::default();
+        let mut defaults = HashMap::new();
+        let mut offset = reader.seek(SeekFrom::Start(0))?;
+        let end = reader.seek(SeekFrom::End(0))?;
+        // whether the last fragment is still missing its media data
+        let mut awaiting_mdat = false;
+
+        while offset < end {
+            reader.seek(SeekFrom::Start(offset))?;
+
+            let (kind, header_size, size) = match read_box_header(reader, end - offset) {
+                Ok(header) => header,
+                Err(_) if growing => break,
+                Err(e) => return Err(e),
+            };
+            let content_size = (size - header_size) as usize;
+
+            match &kind {
+                b"moov" => {
+                    let moov = read_content(reader, content_size)?;
+                    index.read_moov(&moov, &mut defaults)?;
+                    index.init = 0..offset + size;
+                    index.moov = moov;
+                }
+                b"moof" => {
+                    let moof = read_content(reader, content_size)?;
+                    let mut fragment = read_moof(&moof, offset, &defaults)?;
+                    fragment.bytes = offset..offset + size;
+                    index.fragments.push(fragment);
+                    awaiting_mdat = true;
+                }
+                b"mdat" => {
+                    if let Some(fragment) = index.fragments.last_mut() {
+                        fragment.bytes.end = offset + size;
+                    }
+                    awaiting_mdat = false;
+                }
+                _ => {}
+            }
+
+            offset += size;
+        }
+
+        if growing && awaiting_mdat {
+            index.fragments.pop();
+        }
+
+        if index.init.end == 0 {
+            anyhow::bail!("file has no moov box");
+        }
+        if index.fragments.is_empty() {
+            anyhow::bail!("file is not fragmented");
+        }
+
+        Ok(index)
+    }
+
+    /// The ID of the first video track, if any.
+    pub fn video_track(&self) -> Option<u32> {
+        let mut tracks = self
+            .handlers
+            .iter()
+            .filter(|(_, handler)| *handler == b"vide")
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        tracks.sort_unstable();
+
+        tracks.first().copied()
+    }
+
+    /// The streams of the first H.264 and AAC tracks by track ID, with the
+    /// stream IDs used by the ingests, i.e. 0 for video and 1 for audio.
+    pub fn streams(&self) -> anyhow::Result<Vec<(u32, Stream)>> {
+        let mut streams: Vec<(u32, Stream)> = Vec::new();
+
+        for (kind, content) in boxes(&self.moov) {
+            if &kind != b"trak" {
+                continue;
+            }
+
+            let track_id = match find_box(content, &[b"tkhd"]).map(tkhd_track_id) {
+                Some(id) => id?,
+                None => continue,
+            };
+            let timescale = match self.timescales.get(&track_id) {
+                Some(timescale) => *timescale,
+                None => continue,
+            };
+            let codec = match find_box(content, &[b"mdia", b"minf", b"stbl", b"stsd"])
+                .map(sample_entry_codec_info)
+                .transpose()?
+                .flatten()
+            {
+                Some(codec) => codec,
+                None => continue,
+            };
+
+            let id = match codec.properties {
+                CodecTypeInfo::Video(_) => 0,
+                CodecTypeInfo::Audio(_) => 1,
+            };
+            if streams.iter().any(|(_, s)| s.id == id) {
+                continue;
+            }
+
+            streams.push((
+                track_id,
+                Stream {
+                    id,
+                    codec: Arc::new(codec),
+                    timebase: Fraction::new(1, timescale),
+                },
+            ));
+        }
+
+        if streams.is_empty() {
+            anyhow::bail!("file has no H.264 or AAC track");
+        }
+
+        Ok(streams)
+    }
+
+    /// The codecs of every track, as in the `CODECS` attribute of HLS or
+    /// the `codecs` attribute of DASH.
+    pub fn codecs_string(&self) -> String {
+        let mut tracks = self.codecs.iter().collect::<Vec<_>>();
+        tracks.sort_unstable();
+
+        tracks
+            .into_iter()
+            .map(|(_, codec)| codec.as_str())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    pub(crate) fn read_moov(
+        &mut self,
+        moov: &[u8],
+        defaults: &mut HashMap<u32, TrackDefaults>,
+    ) -> anyhow::Result<()> {
+        for (kind, content) in boxes(moov) {
+            match &kind {
+                b"trak" => {
+                    let track_id = find_box(content, &[b"tkhd"])
+                        .map(tkhd_track_id)
+                        .transpose()?;
+                    let timescale = find_box(content, &[b"mdia", b"mdhd"])
+                        .map(mdhd_timescale)
+                        .transpose()?;
+                    let handler = find_box(content, &[b"mdia", b"hdlr"])
+                        .and_then(|hdlr| hdlr.get(8..12))
+                        .map(|h| [h[0], h[1], h[2], h[3]]);
+                    let codec = find_box(content, &[b"mdia", b"minf", b"stbl", b"stsd"])
+                        .and_then(sample_entry_codec);
+
+                    if let (Some(id), Some(timescale)) = (track_id, timescale) {
+                        self.timescales.insert(id, timescale);
+                        if let Some(handler) = handler {
+                            self.handlers.insert(id, handler);
+                        }
+                        if let Some(codec) = codec {
+                            self.codecs.insert(id, codec);
+                        }
+                    }
+                }
+                b"mvex" => {
+                    for (kind, content) in boxes(content) {
+                        if &kind == b"trex" && content.len() >= 24 {
+                            let track_id = be_u32(&content[4..]);
+                            defaults.insert(
+                                track_id,
+                                TrackDefaults {
+                                    duration: be_u32(&content[12..]),
+                                    size: be_u32(&content[16..]),
+                                    flags: be_u32(&content[20..]),
+                                },
+                            );
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct TrackDefaults {
+    duration: u32,
+    size: u32,
+    flags: u32,
+}
+
+/// Reads the first track fragment of the `moof` box at `offset`.
+fn read_moof(
+    moof: &[u8],
+    offset: u64,
+    defaults: &HashMap<u32, TrackDefaults>,
+) -> anyhow::Result<FragmentInfo> {
+    let traf = boxes(moof)
+        .find(|(kind, _)| kind == b"traf")
+        .map(|(_, content)| content)
+        .ok_or_else(|| anyhow::anyhow!("moof box has no traf box"))?;
+
+    read_traf(traf, moof.len(), offset, defaults)
+}
+
+/// Reads every track fragment of the `moof` box at `offset`, which some
+/// muxers use to interleave tracks.
+pub(crate) fn read_moof_tracks(
+    moof: &[u8],
+    offset: u64,
+    defaults: &HashMap<u32, TrackDefaults>,
+) -> anyhow::Result<Vec<FragmentInfo>> {
+    boxes(moof)
+        .filter(|(kind, _)| kind == b"traf")
+        .map(|(_, traf)| read_traf(traf, moof.len(), offset, defaults))
+        .collect()
+}
+
+/// Reads a track fragment of a `moof` box with `moof_size` bytes of
+/// content at `offset`.
+fn read_traf(
+    traf: &[u8],
+    moof_size: usize,
+    offset: u64,
+    defaults: &HashMap<u32, TrackDefaults>,
+) -> anyhow::Result<FragmentInfo> {
+    let mut fragment = FragmentInfo {
+        bytes: 0..0,
+        track_id: 0,
+        decode_time: 0,
+        duration: 0,
+        keyframe: true,
+        samples: Vec::new(),
+    };
+    let mut track = TrackDefaults::default();
+    let mut first_sample = true;
+
+    // data offsets are relative to the start of the moof box unless a base
+    // is given, and the data of a run without an offset follows the last
+    let mut base_offset = offset;
+    let mut data_offset = offset + 8 + moof_size as u64 + 8;
+
+    for (kind, content) in boxes(traf) {
+        let mut r = Fields::new(content);
+
+        match &kind {
+            b"tfhd" => {
+                let flags = r.u32()? & 0xffffff;
+                fragment.track_id = r.u32()?;
+                track = defaults
+                    .get(&fragment.track_id)
+                    .copied()
+                    .unwrap_or_default();
+
+                if flags & 0x01 != 0 {
+                    base_offset = r.u64()?;
+                }
+                if flags & 0x02 != 0 {
+                    r.skip(4)?; // sample description index
+                }
+                if flags & 0x08 != 0 {
+                    track.duration = r.u32()?;
+                }
+                if flags & 0x10 != 0 {
+                    track.size = r.u32()?;
+                }
+                if flags & 0x20 != 0 {
+                    track.flags = r.u32()?;
+                }
+            }
+            b"tfdt" => {
+                let version = content.first().copied().unwrap_or(0);
+                r.skip(4)?;
+                fragment.decode_time = if version == 1 {
+                    r.u64()?
+                } else {
+                    r.u32()? as u64
+                };
+            }
+            b"trun" => {
+                let flags = r.u32()? & 0xffffff;
+                let samples = r.u32()?;
+                if fragment.samples.len() + samples as usize > MAX_FRAGMENT_SAMPLES {
+                    anyhow::bail!("Track fragment has too many samples");
+                }
+
+                if flags & 0x01 != 0 {
+                    data_offset = base_offset
+                        .checked_add_signed(r.u32()? as i32 as i64)
+                        .ok_or_else(|| anyhow::anyhow!("Track run data offset overflows"))?;
+                }
+                let first_flags = if flags & 0x04 != 0 {
+                    Some(r.u32()?)
+                } else {
+                    None
+                };
+
+                for i in 0..samples {
+                    let duration = if flags & 0x100 != 0 {
+                        r.u32()?
+                    } else {
+                        track.duration
+                    };
+                    let size = if flags & 0x200 != 0 {
+                        r.u32()?
+                    } else {
+                        track.size
+                    };
+                    let sample_flags = if flags & 0x400 != 0 {
+                        r.u32()?
+                    } else {
+                        track.flags
+                    };
+                    // signed in version 1, and in practice in version 0 too
+                    let composition_offset = if flags & 0x800 != 0 {
+                        r.u32()? as i32
+                    } else {
+                        0
+                    };
+
+                    let sample_flags = first_flags.filter(|_| i == 0).unwrap_or(sample_flags);
+                    let sync = sample_flags & SAMPLE_IS_NON_SYNC == 0;
+
+                    if first_sample {
+                        fragment.keyframe = sync;
+                        first_sample = false;
+                    }
+
+                    fragment.duration += duration as u64;
+                    fragment.samples.push(SampleInfo {
+                        offset: data_offset,
+                        size,
+                        duration,
+                        composition_offset,
+                        sync,
+                    });
+                    data_offset = data_offset
+                        .checked_add(size as u64)
+                        .ok_or_else(|| anyhow::anyhow!("Track run data offset overflows"))?;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(fragment)
+}
+
+/// Returns the codec string of the first entry of a `stsd` box.
+fn sample_entry_codec(stsd: &[u8]) -> Option<String> {
+    let (kind, entry) = boxes(stsd.get(8..)?).next()?;
+
+    match &kind {
+        b"avc1" | b"avc3" => {
+            // avcC follows the fields of the visual sample entry
+            let avcc = find_box(entry.get(78..)?, &[b"avcC"])?;
+
+            Some(format!(
+                "{}.{:02x}{:02x}{:02x}",
+                String::from_utf8_lossy(&kind),
+                avcc.get(1)?,
+                avcc.get(2)?,
+                avcc.get(3)?
+            ))
+        }
+        b"vp09" => {
+            // vpcC follows the fields of the visual sample entry, and starts
+            // with its version and flags
+            let vpcc = find_box(entry.get(78..)?, &[b"vpcC"])?;
+
+            Some(format!(
+                "vp09.{:02}.{:02}.{:02}",
+                vpcc.get(4)?,
+                vpcc.get(5)?,
+                vpcc.get(6)? >> 4
+            ))
+        }
+        b"hvc1" | b"hev1" => {
+            // hvcC follows the fields of the visual sample entry
+            let hvcc = find_box(entry.get(78..)?, &[b"hvcC"])?;
+            let codec = HevcDecoderConfiguration::parse(hvcc).ok()?.codec_string();
+
+            Some(codec.replacen("hvc1", &String::from_utf8_lossy(&kind), 1))
+        }
+        b"av01" => {
+            // av1C follows the fields of the visual sample entry
+            let av1c = find_box(entry.get(78..)?, &[b"av1C"])?;
+
+            Some(Av1CodecConfiguration::parse(av1c).ok()?.codec_string())
+        }
+        b"mp4a" => {
+            // esds follows the fields of the audio sample entry
+            let object_type = find_box(entry.get(28..)?, &[b"esds"])
+                .and_then(|esds| esds.get(4..))
+                .and_then(audio_object_type)
+                .unwrap_or(2);
+
+            Some(format!("mp4a.40.{}", object_type))
+        }
+        b"Opus" => Some("opus".to_string()),
+        b".mp3" => Some("mp3".to_string()),
+        _ => None,
+    }
+}
+
+/// Returns the codec parameters of the first entry of a `stsd` box, if it
+/// is H.264, H.265, VP9, AV1, AAC, Opus or MP3.
+fn sample_entry_codec_info(stsd: &[u8]) -> anyhow::Result<Option<CodecInfo>> {
+    let (kind, entry) = match stsd.get(8..).and_then(|entries| boxes(entries).next()) {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+
+    match &kind {
+        b"avc1" | b"avc3" => {
+            let mut r = Fields::new(entry);
+            r.skip(24)?;
+            let size = r.u32()?;
+            let (width, height) = (size >> 16, size & 0xffff);
+
+            let avcc = entry
+                .get(78..)
+                .and_then(|boxes| find_box(boxes, &[b"avcC"]))
+                .ok_or_else(|| anyhow::anyhow!("avc1 sample entry has no avcC box"))?;
+            let (sps, pps) = avcc_parameter_sets(avcc)?;
+
+            Ok(Some(CodecInfo {
+                name: "h264",
+                properties: CodecTypeInfo::Video(VideoCodecInfo {
+                    width,
+                    height,
+                    extra: VideoCodecSpecificInfo::H264 {
+                        bitstream_format: BitstreamFraming::FourByteLength,
+                        profile_indication: avcc[1],
+                        profile_compatibility: avcc[2],
+                        level_indication: avcc[3],
+                        sps: Arc::new(sps.to_vec()),
+                        pps: Arc::new(pps.to_vec()),
+                    },
+                }),
+            }))
+        }
+        b"vp09" => {
+            let mut r = Fields::new(entry);
+            r.skip(24)?;
+            let size = r.u32()?;
+            let (width, height) = (size >> 16, size & 0xffff);
+
+            let vpcc = entry
+                .get(78..)
+                .and_then(|boxes| find_box(boxes, &[b"vpcC"]))
+                .filter(|vpcc| vpcc.len() >= 7)
+                .ok_or_else(|| anyhow::anyhow!("vp09 sample entry has no vpcC box"))?;
+
+            Ok(Some(CodecInfo {
+                name: "vp9",
+                properties: CodecTypeInfo::Video(VideoCodecInfo {
+                    width,
+                    height,
+                    extra: VideoCodecSpecificInfo::Vp9 {
+                        profile: vpcc[4],
+                        level: vpcc[5],
+                        bit_depth: vpcc[6] >> 4,
+                        chroma_subsampling: (vpcc[6] >> 1) & 0x07,
+                        full_range: vpcc[6] & 1 == 1,
+                    },
+                }),
+            }))
+        }
+        b"hvc1" | b"hev1" => {
+            let mut r = Fields::new(entry);
+            r.skip(24)?;
+            let size = r.u32()?;
+            let (width, height) = (size >> 16, size & 0xffff);
+
+            let hvcc = entry
+                .get(78..)
+                .and_then(|boxes| find_box(boxes, &[b"hvcC"]))
+                .ok_or_else(|| anyhow::anyhow!("hvc1 sample entry has no hvcC box"))?;
+            let config = HevcDecoderConfiguration::parse(hvcc)?;
+
+            // the HDR metadata is in its own boxes in MP4, rather than in
+            // SEI of the record
+            let boxes = entry.get(78..).unwrap_or_default();
+            let hdr =
+                HdrMetadata::from_boxes(find_box(boxes, &[b"mdcv"]), find_box(boxes, &[b"clli"]))
+                    .or_else(|| config.hdr_metadata());
+
+            Ok(Some(CodecInfo {
+                name: "h265",
+                properties: CodecTypeInfo::Video(VideoCodecInfo {
+                    width,
+                    height,
+                    extra: VideoCodecSpecificInfo::H265 {
+                        bitstream_format: match config.length_size {
+                            2 => BitstreamFraming::TwoByteLength,
+                            _ => BitstreamFraming::FourByteLength,
+                        },
+                        config: Arc::new(config),
+                        hdr: hdr.map(Arc::new),
+                    },
+                }),
+            }))
+        }
+        b"av01" => {
+            let mut r = Fields::new(entry);
+            r.skip(24)?;
+            let size = r.u32()?;
+            let (width, height) = (size >> 16, size & 0xffff);
+
+            let av1c = entry
+                .get(78..)
+                .and_then(|boxes| find_box(boxes, &[b"av1C"]))
+                .ok_or_else(|| anyhow::anyhow!("av01 sample entry has no av1C box"))?;
+
+            Ok(Some(CodecInfo {
+                name: "av1",
+                properties: CodecTypeInfo::Video(VideoCodecInfo {
+                    width,
+                    height,
+                    extra: VideoCodecSpecificInfo::Av1 {
+                        config: Arc::new(Av1CodecConfiguration::parse(av1c)?),
+                    },
+                }),
+            }))
+        }
+        b"mp4a" => {
+            let mut r = Fields::new(entry);
+            r.skip(16)?;
+            let channels_and_bits = r.u32()?;
+            r.skip(4)?;
+            let sample_rate = r.u32()? >> 16;
+
+            let extra = entry
+                .get(28..)
+                .and_then(|boxes| find_box(boxes, &[b"esds"]))
+                .and_then(|esds| esds.get(4..))
+                .and_then(decoder_specific_info)
+                .ok_or_else(|| anyhow::anyhow!("mp4a sample entry has no AudioSpecificConfig"))?;
+
+            Ok(Some(CodecInfo {
+                name: "AAC",
+                properties: CodecTypeInfo::Audio(AudioCodecInfo {
+                    sample_rate,
+                    sample_bpp: channels_and_bits & 0xffff,
+                    sound_type: if channels_and_bits >> 16 == 1 {
+                        SoundType::Mono
+                    } else {
+                        SoundType::Stereo
+                    },
+                    extra: AudioCodecSpecificInfo::Aac {
+                        extra: extra.to_vec(),
+                    },
+                }),
+            }))
+        }
+        b"Opus" => {
+            // dOps follows the fields of the audio sample entry
+            let dops = entry
+                .get(28..)
+                .and_then(|boxes| find_box(boxes, &[b"dOps"]))
+                .ok_or_else(|| anyhow::anyhow!("Opus sample entry has no dOps box"))?;
+
+            Ok(Some(OpusHeader::parse_dops(dops)?.codec_info()))
+        }
+        b".mp3" => {
+            let mut r = Fields::new(entry);
+            r.skip(16)?;
+            let channels = r.u32()? >> 16;
+            r.skip(4)?;
+            let sample_rate = r.u32()? >> 16;
+
+            Ok(Some(mp3_codec_info(sample_rate, channels as u8)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Returns the first SPS and PPS of an `avcC` box.
+fn avcc_parameter_sets(avcc: &[u8]) -> anyhow::Result<(&[u8], &[u8])> {
+    fn parameter_set<'a>(r: &mut Fields<'a>) -> anyhow::Result<&'a [u8]> {
+        let size = r.take(2)?;
+        r.take(u16::from_be_bytes([size[0], size[1]]) as usize)
+    }
+
+    let mut r = Fields::new(avcc);
+    r.skip(5)?;
+
+    if r.take(1)?[0] & 0x1f == 0 {
+        anyhow::bail!("avcC box has no SPS");
+    }
+    let sps = parameter_set(&mut r)?;
+
+    if r.take(1)?[0] == 0 {
+        anyhow::bail!("avcC box has no PPS");
+    }
+    let pps = parameter_set(&mut r)?;
+
+    Ok((sps, pps))
+}
+
+/// Returns the audio object type in the decoder specific info of an ES
+/// descriptor.
+fn audio_object_type(data: &[u8]) -> Option<u8> {
+    decoder_specific_info(data)?.first().map(|b| b >> 3)
+}
+
+/// Returns the decoder specific info of an ES descriptor, which is the
+/// AudioSpecificConfig of AAC.
+fn decoder_specific_info(mut data: &[u8]) -> Option<&[u8]> {
+    loop {
+        let tag = *data.first()?;
+
+        // the size is encoded in up to four bytes of seven bits each
+        let mut size = 0;
+        let mut header = 1;
+        loop {
+            let byte = *data.get(header)?;
+            size = size << 7 | (byte & 0x7f) as usize;
+            header += 1;
+            if byte & 0x80 == 0 || header == 5 {
+                break;
+            }
+        }
+
+        let content = data.get(header..header + size)?;
+
+        data = match tag {
+            // ES_Descriptor, descend past its fields
+            0x03 => {
+                let flags = *content.get(2)?;
+                let mut skip = 3;
+                if flags & 0x80 != 0 {
+                    skip += 2;
+                }
+                if flags & 0x40 != 0 {
+                    skip += 1 + *content.get(skip)? as usize;
+                }
+                if flags & 0x20 != 0 {
+                    skip += 2;
+                }
+                content.get(skip..)?
+            }
+            // DecoderConfigDescriptor, descend past its fields
+            0x04 => content.get(13..)?,
+            0x05 => return Some(content),
+            _ => data.get(header + size..)?,
+        };
+    }
+}
+
+fn tkhd_track_id(tkhd: &[u8]) -> anyhow::Result<u32> {
+    let mut r = Fields::new(tkhd);
+    let version = r.u32()? >> 24;
+
+    // creation and modification time
+    r.skip(if version == 1 { 16 } else { 8 })?;
+
+    r.u32()
+}
+
+fn mdhd_timescale(mdhd: &[u8]) -> anyhow::Result<u32> {
+    let mut r = Fields::new(mdhd);
+    let version = r.u32()? >> 24;
+
+    // creation and modification time
+    r.skip(if version == 1 { 16 } else { 8 })?;
+
+    r.u32()
+}
+
+/// Reads the header of the box at the current position, returning its
+/// type, the size of the header and the size of the whole box.
+fn read_box_header<R: Read>(reader: &mut R, remaining: u64) -> anyhow::Result<([u8; 4], u64, u64)> {
+    let mut header = [0; 8];
+    reader.read_exact(&mut header)?;
+
+    let kind = [header[4], header[5], header[6], header[7]];
+    let (header_size, size) = match be_u32(&header) {
+        0 => (8, remaining),
+        1 => {
+            let mut large = [0; 8];
+            reader.read_exact(&mut large)?;
+            (16, u64::from_be_bytes(large))
+        }
+        size => (8, size as u64),
+    };
+
+    if size < header_size || size > remaining {
+        anyhow::bail!("invalid size {} of {:?} box", size, kind);
+    }
+
+    Ok((kind, header_size, size))
+}
+
+fn read_content<R: Read>(reader: &mut R, size: usize) -> anyhow::Result<Vec<u8>> {
+    let mut content = vec![0; size];
+    reader.read_exact(&mut content)?;
+
+    Ok(content)
+}
+
+/// Iterates over the child boxes in the content of a box, stopping at the
+/// first malformed one.
+pub(crate) fn boxes(mut data: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    std::iter::from_fn(move || {
+        if data.len() < 8 {
+            return None;
+        }
+
+        let size = be_u32(data) as usize;
+        if size < 8 || size > data.len() {
+            return None;
+        }
+
+        let kind = [data[4], data[5], data[6], data[7]];
+        let content = &data[8..size];
+        data = &data[size..];
+
+        Some((kind, content))
+    })
+}
+
+/// Finds a box by the types of the boxes leading to it, starting in the
+/// content of a box.
+pub(crate) fn find_box<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+    let (first, rest) = path.split_first()?;
+    let content = boxes(data)
+        .find(|(kind, _)| kind == *first)
+        .map(|(_, content)| content)?;
+
+    if rest.is_empty() {
+        Some(content)
+    } else {
+        find_box(content, rest)
+    }
+}
+
+pub(crate) fn be_u32(b: &[u8]) -> u32 {
+    u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+}
+
+/// Reads big-endian fields from the content of a box.
+struct Fields<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Fields { data }
+    }
+
+    fn take(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        if self.data.len() < len {
+            anyhow::bail!("box is too short");
+        }
+
+        let (field, rest) = self.data.split_at(len);
+        self.data = rest;
+
+        Ok(field)
+    }
+
+    fn skip(&mut self, len: usize) -> anyhow::Result<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        self.take(4).map(be_u32)
+    }
+
+    fn u64(&mut self) -> anyhow::Result<u64> {
+        let b = self.take(8)?;
+
+        Ok(u64::from_be_bytes([
+            b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7],
+        ]))
+    }
+}
+
+/// Makes a box of `kind` around `content`, for tests.
+#[cfg(test)]
+fn test_box(kind: &[u8; 4], content: &[u8]) -> Vec<u8> {
+    let mut data = ((content.len() + 8) as u32).to_be_bytes().to_vec();
+    data.extend_from_slice(kind);
+    data.extend_from_slice(content);
+    data
+}
+
+/// Makes a `traf` box with a `tfhd` box giving `base_offset`, and a `trun`
+/// box with a data offset and the given sample sizes.
+#[cfg(test)]
+fn test_traf(base_offset: u64, data_offset: i32, sizes: &[u32]) -> Vec<u8> {
+    let mut tfhd = vec![0, 0, 0, 0x01];
+    tfhd.extend_from_slice(&1u32.to_be_bytes());
+    tfhd.extend_from_slice(&base_offset.to_be_bytes());
+
+    let mut trun = vec![0, 0, 0x02, 0x01];
+    trun.extend_from_slice(&(sizes.len() as u32).to_be_bytes());
+    trun.extend_from_slice(&data_offset.to_be_bytes());
+    for size in sizes {
+        trun.extend_from_slice(&size.to_be_bytes());
+    }
+
+    let mut traf = test_box(b"tfhd", &tfhd);
+    traf.extend(test_box(b"trun", &trun));
+    test_box(b"traf", &traf)
+}
+
+#[test]
+fn reads_track_runs() {
+    let moof = test_traf(1000, 8, &[10, 20]);
+    let fragments = read_moof_tracks(&moof, 0, &HashMap::new()).unwrap();
+
+    let offsets = fragments[0]
+        .samples
+        .iter()
+        .map(|sample| sample.offset)
+        .collect::<Vec<_>>();
+    assert_eq!(offsets, [1008, 1018]);
+}
+
+#[test]
+fn overflowing_track_runs_are_rejected() {
+    let tracks = |moof: Vec<u8>| read_moof_tracks(&moof, 0, &HashMap::new());
+
+    assert!(tracks(test_traf(u64::MAX - 4, 8, &[10])).is_err());
+    assert!(tracks(test_traf(4, -8, &[10])).is_err());
+    assert!(tracks(test_traf(u64::MAX - 20, 8, &[u32::MAX])).is_err());
+
+    // a run which claims more samples than it has fields for
+    let mut trun = vec![0, 0, 0, 0];
+    trun.extend_from_slice(&u32::MAX.to_be_bytes());
+    let moof = test_box(b"traf", &test_box(b"trun", &trun));
+    assert!(tracks(moof).is_err());
+}
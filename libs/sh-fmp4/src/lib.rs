@@ -42,8 +42,9 @@ use av_mp4::boxes::{
 
 use bytes::{BufMut, BytesMut};
 use sh_media::{
-    ByteWriteFilter2, CodecTypeInfo, Frame, FrameDependency, FrameWriteFilter, MediaTime, Stream,
-    VideoCodecSpecificInfo,
+    av1_decoder_configuration, hdr_metadata, hevc_decoder_configuration, vp9_decoder_configuration,
+    AudioCodecSpecificInfo, ByteWriteFilter2, CodecTypeInfo, Frame, FrameDependency,
+    FrameWriteFilter, MediaTime, SoundType, Stream, VideoCodecSpecificInfo,
 };
 use std::{borrow::Cow, io::Write};
 
@@ -51,28 +52,77 @@ use std::collections::HashMap;
 
 use tracing::*;
 
+mod demux;
+mod index;
+mod muxer;
+mod progressive;
+
+pub use demux::*;
+pub use index::*;
+pub use muxer::*;
+pub use progressive::*;
+
+use index::{boxes, find_box};
+use progressive::mp4_box;
+
 pub fn single_frame_fmp4(frame: Frame) -> anyhow::Result<Vec<u8>> {
     let mut buffer = Vec::with_capacity(frame.buffer.len());
 
     write_preamble(&frame.stream, None, &mut buffer)?;
+    write_frame_fragment(&frame, 1, 0, 1800, &mut buffer)?;
+
+    Ok(buffer)
+}
+
+/// Writes the `ftyp` and `moov` boxes of a file with only a video track,
+/// which initializes a decoder for [`keyframe_fragment`]s.
+pub fn video_init_fmp4(video: &Stream) -> anyhow::Result<Vec<u8>> {
+    let mut buffer = Vec::with_capacity(1024);
+
+    write_preamble(video, None, &mut buffer)?;
+
+    Ok(buffer)
+}
+
+/// Writes a fragment with a single keyframe, e.g. for the segments of an
+/// I-frame playlist.
+///
+/// `decode_time` and `duration` are in the timebase of the frame's stream.
+pub fn keyframe_fragment(
+    frame: &Frame,
+    sequence: u32,
+    decode_time: u64,
+    duration: u32,
+) -> anyhow::Result<Vec<u8>> {
+    let mut buffer = Vec::with_capacity(frame.buffer.len() + 1024);
 
-    let duration = 1800;
+    write_frame_fragment(frame, sequence, decode_time, duration, &mut buffer)?;
 
+    Ok(buffer)
+}
+
+fn write_frame_fragment(
+    frame: &Frame,
+    sequence: u32,
+    decode_time: u64,
+    duration: u32,
+    dest: &mut dyn Write,
+) -> anyhow::Result<()> {
     let mut moof = MovieFragmentBox::new(
-        MovieFragmentHeaderBox::new(1),
+        MovieFragmentHeaderBox::new(sequence),
         TrackFragmentBox::new(
             TrackFragmentHeaderBox::new(1, None, None, None, None, None, false, true),
             vec![TrackFragmentRunBox::new(
                 Some(0),
                 None,
                 vec![TrackFragmentSample {
-                    duration: Some(duration as _),
+                    duration: Some(duration),
                     size: Some(frame.buffer.len() as _),
                     flags: None,
                     composition_time_offset: None,
                 }],
             )],
-            Some(TrackFragmentBaseMediaDecodeTimeBox::new(0)),
+            Some(TrackFragmentBaseMediaDecodeTimeBox::new(decode_time)),
         ),
     );
 
@@ -81,10 +131,10 @@ pub fn single_frame_fmp4(frame: Frame) -> anyhow::Result<Vec<u8>> {
 
     let mdat = MediaDataBox::new(Cow::Borrowed(&frame.buffer));
 
-    moof.write(&mut buffer)?;
-    mdat.write(&mut buffer)?;
+    moof.write(dest)?;
+    mdat.write(dest)?;
 
-    Ok(buffer)
+    Ok(())
 }
 
 pub struct FragmentedMp4WriteFilter {
@@ -92,6 +142,10 @@ pub struct FragmentedMp4WriteFilter {
     start_times: HashMap<u32, MediaTime>,
     prev_times: HashMap<u32, MediaTime>,
     sequence_id: u32,
+    streams: Vec<Stream>,
+    align_tracks: bool,
+    /// The time aligned tracks start at, instead of the first written frame.
+    align_start: Option<MediaTime>,
 }
 
 fn write_preamble(
@@ -121,12 +175,183 @@ fn write_preamble(
         tracks,
     );
 
+    let mut moov_bytes = Vec::new();
+    moov.write(&mut moov_bytes)?;
+
+    // av-mp4 has no VP9, HEVC or AV1 sample entry, so the video track is
+    // written with an empty AVC one which is replaced
+    let sample_entry = video.codec.video().and_then(|video| {
+        if let Some(record) = vp9_decoder_configuration(&video.extra) {
+            // version 1, no flags
+            let mut vpcc = vec![1, 0, 0, 0];
+            vpcc.extend_from_slice(&record);
+
+            Some((b"vp09", mp4_box(b"vpcC", &vpcc)))
+        } else if let Some(record) = hevc_decoder_configuration(&video.extra) {
+            let mut config = mp4_box(b"hvcC", &record);
+            // without these HDR streams are tone mapped as if they were SDR
+            if let Some(hdr) = hdr_metadata(&video.extra) {
+                if let Some(mastering_display) = &hdr.mastering_display {
+                    config.extend(mp4_box(b"mdcv", &mastering_display.to_bytes()));
+                }
+                if let Some(content_light_level) = &hdr.content_light_level {
+                    config.extend(mp4_box(b"clli", &content_light_level.to_bytes()));
+                }
+            }
+
+            Some((b"hvc1", config))
+        } else {
+            av1_decoder_configuration(&video.extra)
+                .map(|record| (b"av01", mp4_box(b"av1C", &record)))
+        }
+    });
+    if let Some((entry, config)) = sample_entry {
+        let stsd = [b"moov", b"trak", b"mdia", b"minf", b"stbl", b"stsd"];
+        moov_bytes = replace_box(&moov_bytes, &stsd, &|stsd| {
+            visual_stsd(stsd, entry, &config)
+        });
+    }
+
+    // nor an Opus or MP3 one, so the audio track is written with an empty
+    // mp4a one which is replaced
+    let sound_entry = audio
+        .and_then(|audio| audio.codec.audio())
+        .and_then(|audio| match &audio.extra {
+            AudioCodecSpecificInfo::Aac { .. } => None,
+            AudioCodecSpecificInfo::Opus { header } => {
+                let dops = header.to_dops();
+
+                Some((
+                    b"Opus",
+                    header.channels,
+                    audio.sample_rate,
+                    Some((b"dOps", dops)),
+                ))
+            }
+            AudioCodecSpecificInfo::Mp3 => {
+                let channels = match audio.sound_type {
+                    SoundType::Mono => 1,
+                    SoundType::Stereo => 2,
+                };
+
+                Some((b".mp3", channels, audio.sample_rate, None))
+            }
+        });
+    if let Some((entry, channels, sample_rate, config)) = sound_entry {
+        let config = config.as_ref().map(|(kind, record)| (*kind, &record[..]));
+
+        moov_bytes = replace_box(&moov_bytes, &[b"moov"], &|moov| {
+            replace_sound_stsd(moov, &|stsd| {
+                sound_stsd(stsd, entry, channels, sample_rate, config)
+            })
+        });
+    }
+
     ftyp.write(dest)?;
-    moov.write(dest)?;
+    dest.write_all(&moov_bytes)?;
 
     Ok(())
 }
 
+/// Rebuilds a sequence of boxes with the content of the first box at `path`
+/// replaced by `replace` of it.
+fn replace_box(data: &[u8], path: &[&[u8; 4]], replace: &dyn Fn(&[u8]) -> Vec<u8>) -> Vec<u8> {
+    let (first, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return replace(data),
+    };
+
+    let mut out = Vec::with_capacity(data.len());
+    let mut replaced = false;
+
+    for (kind, content) in boxes(data) {
+        if !replaced && &kind == *first {
+            replaced = true;
+            out.extend(mp4_box(&kind, &replace_box(content, rest, replace)));
+        } else {
+            out.extend(mp4_box(&kind, content));
+        }
+    }
+
+    out
+}
+
+/// Makes the content of a `stsd` box with an `entry` sample entry which is
+/// configured by the `config` boxes, from one with a visual sample entry of
+/// another codec.
+fn visual_stsd(stsd: &[u8], entry: &[u8; 4], config: &[u8]) -> Vec<u8> {
+    let visual = stsd
+        .get(8..)
+        .and_then(|entries| boxes(entries).next())
+        .and_then(|(_, entry)| entry.get(..78));
+    let visual = match visual {
+        Some(visual) => visual,
+        None => return stsd.to_vec(),
+    };
+
+    let mut sample_entry = visual.to_vec();
+    sample_entry.extend_from_slice(config);
+
+    let mut content = stsd[..8].to_vec();
+    content.extend(mp4_box(entry, &sample_entry));
+
+    content
+}
+
+/// Rebuilds the content of a `moov` box with the `stsd` box of its sound
+/// track replaced by `replace` of it.
+fn replace_sound_stsd(moov: &[u8], replace: &dyn Fn(&[u8]) -> Vec<u8>) -> Vec<u8> {
+    let mut out = Vec::with_capacity(moov.len());
+
+    for (kind, content) in boxes(moov) {
+        let is_sound = &kind == b"trak"
+            && find_box(content, &[b"mdia", b"hdlr"]).and_then(|hdlr| hdlr.get(8..12))
+                == Some(b"soun");
+
+        if is_sound {
+            let stsd = [b"mdia", b"minf", b"stbl", b"stsd"];
+            out.extend(mp4_box(&kind, &replace_box(content, &stsd, replace)));
+        } else {
+            out.extend(mp4_box(&kind, content));
+        }
+    }
+
+    out
+}
+
+/// Makes the content of a `stsd` box with an `entry` sample entry, which
+/// may be configured by a box, from one with an audio sample entry of
+/// another codec.
+fn sound_stsd(
+    stsd: &[u8],
+    entry: &[u8; 4],
+    channels: u8,
+    sample_rate: u32,
+    config: Option<(&[u8; 4], &[u8])>,
+) -> Vec<u8> {
+    let audio = stsd
+        .get(8..)
+        .and_then(|entries| boxes(entries).next())
+        .and_then(|(_, entry)| entry.get(..28));
+    let audio = match audio {
+        Some(audio) => audio,
+        None => return stsd.to_vec(),
+    };
+
+    let mut sample_entry = audio.to_vec();
+    sample_entry[16..18].copy_from_slice(&(channels as u16).to_be_bytes());
+    // the sample rate is a 16.16 fixed point number
+    sample_entry[24..28].copy_from_slice(&(sample_rate << 16).to_be_bytes());
+    if let Some((kind, record)) = config {
+        sample_entry.extend(mp4_box(kind, record));
+    }
+
+    let mut content = stsd[..8].to_vec();
+    content.extend(mp4_box(entry, &sample_entry));
+
+    content
+}
+
 impl FragmentedMp4WriteFilter {
     pub fn new(target: Box<dyn ByteWriteFilter2 + Send + Unpin>) -> Self {
         FragmentedMp4WriteFilter {
@@ -134,6 +359,32 @@ impl FragmentedMp4WriteFilter {
             start_times: HashMap::new(),
             prev_times: HashMap::new(),
             sequence_id: 0,
+            streams: Vec::new(),
+            align_tracks: false,
+            align_start: None,
+        }
+    }
+
+    /// Creates a filter where every track starts at the time of the first
+    /// written frame, so the output begins at zero without drifting audio
+    /// and video apart. Frames from before that time are dropped.
+    ///
+    /// The first written frame should be a video keyframe for the output to
+    /// be decodable from the start.
+    pub fn aligned(target: Box<dyn ByteWriteFilter2 + Send + Unpin>) -> Self {
+        FragmentedMp4WriteFilter {
+            align_tracks: true,
+            ..Self::new(target)
+        }
+    }
+
+    /// Creates a filter like [`FragmentedMp4WriteFilter::aligned`] where the
+    /// tracks start at `start`, e.g. to continue the timeline of an earlier
+    /// output.
+    pub fn aligned_at(target: Box<dyn ByteWriteFilter2 + Send + Unpin>, start: MediaTime) -> Self {
+        FragmentedMp4WriteFilter {
+            align_start: Some(start),
+            ..Self::aligned(target)
         }
     }
 
@@ -161,15 +412,23 @@ impl FragmentedMp4WriteFilter {
             .entry(frame.stream.id)
             .or_insert_with(|| frame.time.clone());
 
-        let media_duration = frame.time.clone() - prev_time.clone();
-        let base_offset = prev_time.clone() - start_time.clone();
+        // samples follow each other in decode order, and are shown later
+        // by their composition time offset when frames are reordered
+        let media_duration = frame.time.decode_time() as i64 - prev_time.decode_time() as i64;
+        let base_offset = prev_time
+            .decode_time()
+            .saturating_sub(start_time.decode_time());
+        // runs are written as version 0, whose offsets are unsigned, so a
+        // frame which would be shown before it is decoded is shown as soon
+        // as it is decoded instead
+        let composition_offset = (frame.time.pts as i64 - frame.time.decode_time() as i64).max(0);
 
         let track_id = if frame.stream.is_video() { 1 } else { 2 };
 
-        let duration = if media_duration.duration == 0 {
+        let duration = if media_duration == 0 {
             1800
         } else {
-            media_duration.duration
+            media_duration
         };
 
         let mut moof = MovieFragmentBox::new(
@@ -187,12 +446,11 @@ impl FragmentedMp4WriteFilter {
                         duration: Some(duration as _),
                         size: Some(frame.buffer.len() as _),
                         flags: None,
-                        composition_time_offset: None,
+                        composition_time_offset: Some(composition_offset as _)
+                            .filter(|_| composition_offset != 0),
                     }],
                 )],
-                Some(TrackFragmentBaseMediaDecodeTimeBox::new(
-                    base_offset.duration as u64,
-                )),
+                Some(TrackFragmentBaseMediaDecodeTimeBox::new(base_offset)),
             ),
         );
 
@@ -224,10 +482,27 @@ impl FrameWriteFilter for FragmentedMp4WriteFilter {
         let audio = streams.iter().find(|s| s.is_audio());
         self.write_preamble(video, audio).await?;
 
+        self.streams = streams;
+
         Ok(())
     }
 
     async fn write(&mut self, frame: Frame) -> anyhow::Result<()> {
+        if self.align_tracks && self.start_times.is_empty() {
+            let start = self.align_start.as_ref().unwrap_or(&frame.time);
+
+            for stream in &self.streams {
+                self.start_times
+                    .insert(stream.id, start.in_base(stream.timebase));
+            }
+        }
+
+        if let Some(start_time) = self.start_times.get(&frame.stream.id) {
+            if frame.time.pts < start_time.pts {
+                return Ok(());
+            }
+        }
+
         let start_time = self
             .start_times
             .entry(frame.stream.id)
@@ -248,25 +523,38 @@ impl FrameWriteFilter for FragmentedMp4WriteFilter {
 fn get_sample_entry_for_codec_type(codec: &CodecTypeInfo) -> SampleEntry {
     match codec {
         CodecTypeInfo::Video(video) => {
-            let VideoCodecSpecificInfo::H264 {
-                bitstream_format: _,
-                profile_indication,
-                profile_compatibility,
-                level_indication,
-                ref sps,
-                ref pps,
-            } = video.extra;
-
-            SampleEntry::Avc(AvcSampleEntryBox::new(
-                video.width as u16,
-                video.height as u16,
-                AvcConfigurationBox::new(AvcDecoderConfigurationRecord {
+            let record = match video.extra {
+                VideoCodecSpecificInfo::H264 {
+                    bitstream_format: _,
+                    profile_indication,
+                    profile_compatibility,
+                    level_indication,
+                    ref sps,
+                    ref pps,
+                } => AvcDecoderConfigurationRecord {
                     profile_indication,
                     profile_compatibility,
                     level_indication,
                     sequence_parameter_sets: vec![SequenceParameterSet(sps.to_vec())],
                     picture_parameter_sets: vec![PictureParameterSet(pps.to_vec())],
-                }),
+                },
+                // replaced by a vp09, hvc1 or av01 sample entry once it is
+                // written
+                VideoCodecSpecificInfo::Vp9 { .. }
+                | VideoCodecSpecificInfo::H265 { .. }
+                | VideoCodecSpecificInfo::Av1 { .. } => AvcDecoderConfigurationRecord {
+                    profile_indication: 0,
+                    profile_compatibility: 0,
+                    level_indication: 0,
+                    sequence_parameter_sets: Vec::new(),
+                    picture_parameter_sets: Vec::new(),
+                },
+            };
+
+            SampleEntry::Avc(AvcSampleEntryBox::new(
+                video.width as u16,
+                video.height as u16,
+                AvcConfigurationBox::new(record),
             ))
         }
         CodecTypeInfo::Audio(audio) => SampleEntry::Mp4a(Mpeg4AudioSampleEntryBox::new(
@@ -0,0 +1,50 @@
+use sh_media::{
+    BitstreamFramerFilter, BitstreamFraming, ByteWriteFilter2, FrameWriteFilter, Muxer,
+};
+
+use crate::FragmentedMp4WriteFilter;
+
+/// Muxes frames into CMAF fragmented MP4, with AVC in length prefixed
+/// framing.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CmafMuxer {
+    aligned: bool,
+}
+
+impl CmafMuxer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a muxer where every track starts at the first written frame,
+    /// see [`FragmentedMp4WriteFilter::aligned`].
+    pub fn aligned() -> Self {
+        CmafMuxer { aligned: true }
+    }
+}
+
+impl Muxer for CmafMuxer {
+    fn content_type(&self) -> &'static str {
+        "video/mp4"
+    }
+
+    fn extension(&self) -> &'static str {
+        "mp4"
+    }
+
+    fn mux(
+        &self,
+        output: Box<dyn ByteWriteFilter2 + Send + Unpin>,
+    ) -> Box<dyn FrameWriteFilter + Send + Unpin> {
+        let fmp4_filter = if self.aligned {
+            FragmentedMp4WriteFilter::aligned(output)
+        } else {
+            FragmentedMp4WriteFilter::new(output)
+        };
+
+        Box::new(BitstreamFramerFilter::new(
+            BitstreamFraming::FourByteLength,
+            Box::new(fmp4_filter),
+        ))
+    }
+}
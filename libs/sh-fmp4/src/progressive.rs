@@ -0,0 +1,483 @@
+use std::{collections::HashMap, ops::Range};
+
+use crate::{
+    index::{be_u32, boxes, find_box},
+    Mp4Index, SampleInfo,
+};
+
+/// A progressive MP4 file remuxed from fragmented MP4 files, with the
+/// `moov` box before the media data so players can start playing before
+/// the whole file is downloaded.
+///
+/// Only the header is built in memory, the media data is copied from the
+/// source files as it is written.
+#[derive(Debug, Clone)]
+pub struct ProgressiveMp4 {
+    /// The `ftyp` and `moov` boxes and the header of the `mdat` box.
+    pub header: Vec<u8>,
+    /// The byte ranges of the source files which make up the media data in
+    /// order, along with the index of their source file.
+    pub chunks: Vec<(usize, Range<u64>)>,
+}
+
+/// A run of samples of a track which are contiguous in a source file.
+struct Chunk {
+    file: usize,
+    track_id: u32,
+    bytes: Range<u64>,
+    samples: u32,
+}
+
+impl ProgressiveMp4 {
+    /// Remuxes the fragments of `files`, which are played one after another
+    /// and have to have the same tracks with the same sample entries.
+    pub fn remux(files: &[Mp4Index]) -> anyhow::Result<Self> {
+        Self::remux_samples(files, &HashMap::new())
+    }
+
+    /// Remuxes the part of `files` from `range.start` to `range.end`
+    /// seconds, on the timeline of the files played one after another.
+    ///
+    /// The cut is keyframe-accurate, it starts at the last video keyframe
+    /// at or before `range.start` so the file does not start with frames
+    /// which can't be decoded. Returns where the cut actually starts.
+    pub fn remux_range(files: &[Mp4Index], range: Range<f64>) -> anyhow::Result<(Self, f64)> {
+        let first = files
+            .first()
+            .ok_or_else(|| anyhow::anyhow!("no files to remux"))?;
+        let video = first
+            .video_track()
+            .ok_or_else(|| anyhow::anyhow!("files have no video track"))?;
+        let timescale = first.timescales.get(&video).copied().unwrap_or(1).max(1) as f64;
+
+        let start = (range.start.max(0.0) * timescale) as u64;
+        let mut keyframe = None;
+        let mut time = 0;
+
+        let video_samples = files
+            .iter()
+            .flat_map(|file| &file.fragments)
+            .filter(|fragment| fragment.track_id == video)
+            .flat_map(|fragment| &fragment.samples);
+        for sample in video_samples {
+            if time > start {
+                break;
+            }
+            if sample.sync {
+                keyframe = Some(time);
+            }
+            time += sample.duration as u64;
+        }
+
+        let start = keyframe.unwrap_or(0) as f64 / timescale;
+
+        // the window of decode times to keep, in the timescale of each track
+        let windows = first
+            .timescales
+            .iter()
+            .map(|(track_id, timescale)| {
+                let timescale = *timescale as f64;
+                let window = (start * timescale).round() as u64..(range.end * timescale) as u64;
+
+                (*track_id, window)
+            })
+            .collect();
+
+        Ok((Self::remux_samples(files, &windows)?, start))
+    }
+
+    /// Remuxes the samples of `files` whose decode time is in the window of
+    /// their track, or every sample of the tracks without a window.
+    fn remux_samples(
+        files: &[Mp4Index],
+        windows: &HashMap<u32, Range<u64>>,
+    ) -> anyhow::Result<Self> {
+        let first = files
+            .first()
+            .ok_or_else(|| anyhow::anyhow!("no files to remux"))?;
+
+        let sample_entries = track_sample_entries(&first.moov);
+        for file in &files[1..] {
+            if track_sample_entries(&file.moov) != sample_entries {
+                anyhow::bail!("files have different tracks or codec parameters");
+            }
+        }
+
+        let mut samples = HashMap::<u32, Vec<SampleInfo>>::new();
+        let mut chunks = Vec::<Chunk>::new();
+        let mut times = HashMap::<u32, u64>::new();
+
+        for (i, file) in files.iter().enumerate() {
+            for fragment in &file.fragments {
+                let track = samples.entry(fragment.track_id).or_default();
+                let time = times.entry(fragment.track_id).or_default();
+                let window = windows.get(&fragment.track_id);
+
+                for sample in &fragment.samples {
+                    let decode_time = *time;
+                    *time += sample.duration as u64;
+
+                    if window.is_some_and(|w| !w.contains(&decode_time)) {
+                        continue;
+                    }
+
+                    match chunks.last_mut() {
+                        Some(chunk)
+                            if chunk.file == i
+                                && chunk.track_id == fragment.track_id
+                                && chunk.bytes.end == sample.offset =>
+                        {
+                            chunk.bytes.end += sample.size as u64;
+                            chunk.samples += 1;
+                        }
+                        _ => chunks.push(Chunk {
+                            file: i,
+                            track_id: fragment.track_id,
+                            bytes: sample.offset..sample.offset + sample.size as u64,
+                            samples: 1,
+                        }),
+                    }
+
+                    track.push(*sample);
+                }
+            }
+        }
+
+        if chunks.is_empty() {
+            anyhow::bail!("no samples to remux");
+        }
+
+        let media_size = chunks
+            .iter()
+            .map(|c| c.bytes.end - c.bytes.start)
+            .sum::<u64>();
+
+        let ftyp = ftyp();
+        let large_mdat = media_size + 8 > u32::MAX as u64;
+        let mdat_header_size = if large_mdat { 16 } else { 8 };
+
+        // the size of the moov box only depends on whether chunk offsets
+        // need 64 bits, so it can be measured before the offsets are known
+        let measured = moov(first, &samples, &chunks, 0, false)?;
+        let large_offsets =
+            ftyp.len() as u64 + measured.len() as u64 + mdat_header_size + media_size
+                > u32::MAX as u64;
+
+        let measured = moov(first, &samples, &chunks, 0, large_offsets)?;
+        let data_start = ftyp.len() as u64 + measured.len() as u64 + mdat_header_size;
+        let moov = moov(first, &samples, &chunks, data_start, large_offsets)?;
+
+        let mut header = ftyp;
+        header.extend_from_slice(&moov);
+        if large_mdat {
+            header.extend_from_slice(&1u32.to_be_bytes());
+            header.extend_from_slice(b"mdat");
+            header.extend_from_slice(&(media_size + 16).to_be_bytes());
+        } else {
+            header.extend_from_slice(&(media_size as u32 + 8).to_be_bytes());
+            header.extend_from_slice(b"mdat");
+        }
+
+        Ok(ProgressiveMp4 {
+            header,
+            chunks: chunks.into_iter().map(|c| (c.file, c.bytes)).collect(),
+        })
+    }
+
+    /// The size of the whole file.
+    pub fn size(&self) -> u64 {
+        self.header.len() as u64
+            + self
+                .chunks
+                .iter()
+                .map(|(_, bytes)| bytes.end - bytes.start)
+                .sum::<u64>()
+    }
+}
+
+/// The `stsd` box of every track by track ID.
+fn track_sample_entries(moov: &[u8]) -> HashMap<u32, &[u8]> {
+    boxes(moov)
+        .filter(|(kind, _)| kind == b"trak")
+        .filter_map(|(_, trak)| {
+            let tkhd = find_box(trak, &[b"tkhd"])?;
+            let stsd = find_box(trak, &[b"mdia", b"minf", b"stbl", b"stsd"])?;
+
+            Some((track_id(tkhd)?, stsd))
+        })
+        .collect()
+}
+
+fn ftyp() -> Vec<u8> {
+    let mut content = Vec::new();
+    content.extend_from_slice(b"isom");
+    content.extend_from_slice(&0x200u32.to_be_bytes());
+    for brand in [b"isom", b"iso2", b"avc1", b"mp41"] {
+        content.extend_from_slice(brand);
+    }
+
+    mp4_box(b"ftyp", &content)
+}
+
+/// Builds the `moov` box of the remuxed file from the `moov` box of the
+/// first source file, where the media data starts at `data_start`.
+fn moov(
+    first: &Mp4Index,
+    samples: &HashMap<u32, Vec<SampleInfo>>,
+    chunks: &[Chunk],
+    data_start: u64,
+    large_offsets: bool,
+) -> anyhow::Result<Vec<u8>> {
+    let mvhd = find_box(&first.moov, &[b"mvhd"])
+        .ok_or_else(|| anyhow::anyhow!("moov box has no mvhd box"))?;
+    let movie_timescale = header_timescale(mvhd)
+        .filter(|t| *t > 0)
+        .ok_or_else(|| anyhow::anyhow!("mvhd box is too short"))?;
+
+    // each track's duration in the movie timescale
+    let durations = samples
+        .iter()
+        .map(|(track_id, samples)| {
+            let duration = samples.iter().map(|s| s.duration as u64).sum::<u64>();
+            let timescale = first.timescales.get(track_id).copied().unwrap_or(1).max(1);
+
+            (
+                *track_id,
+                (
+                    duration,
+                    duration * movie_timescale as u64 / timescale as u64,
+                ),
+            )
+        })
+        .collect::<HashMap<_, _>>();
+    let movie_duration = durations.values().map(|(_, d)| *d).max().unwrap_or(0);
+
+    // every chunk with its offset in the remuxed file
+    let mut offset = data_start;
+    let mut offsets = Vec::with_capacity(chunks.len());
+    for chunk in chunks {
+        offsets.push((chunk, offset));
+        offset += chunk.bytes.end - chunk.bytes.start;
+    }
+
+    let mut content = Vec::new();
+
+    for (kind, child) in boxes(&first.moov) {
+        match &kind {
+            b"mvhd" => content.extend(mp4_box(
+                b"mvhd",
+                &with_duration(child, 16, 24, movie_duration),
+            )),
+            b"trak" => {
+                let track_id = find_box(child, &[b"tkhd"])
+                    .and_then(track_id)
+                    .ok_or_else(|| anyhow::anyhow!("trak box has no track ID"))?;
+                let (media_duration, movie_duration) =
+                    durations.get(&track_id).copied().unwrap_or_default();
+
+                let track_chunks = offsets
+                    .iter()
+                    .filter(|(chunk, _)| chunk.track_id == track_id)
+                    .map(|(chunk, offset)| (chunk.samples, *offset))
+                    .collect::<Vec<_>>();
+                let track_samples = samples.get(&track_id).map(Vec::as_slice).unwrap_or(&[]);
+
+                let stbl = stbl(child, track_samples, &track_chunks, large_offsets)?;
+                content.extend(trak(child, media_duration, movie_duration, &stbl));
+            }
+            // the remuxed file is not fragmented
+            b"mvex" => {}
+            _ => content.extend(mp4_box(&kind, child)),
+        }
+    }
+
+    Ok(mp4_box(b"moov", &content))
+}
+
+/// Copies a `trak` box with its durations updated and its sample table
+/// replaced.
+fn trak(trak: &[u8], media_duration: u64, movie_duration: u64, stbl: &[u8]) -> Vec<u8> {
+    let replace = |parent: &[u8], f: &dyn Fn(&[u8; 4], &[u8]) -> Option<Vec<u8>>| {
+        let mut content = Vec::new();
+        for (kind, child) in boxes(parent) {
+            match f(&kind, child) {
+                Some(replaced) => content.extend(replaced),
+                None => content.extend(mp4_box(&kind, child)),
+            }
+        }
+        content
+    };
+
+    let minf = |minf: &[u8]| {
+        replace(minf, &|kind, _| match kind {
+            b"stbl" => Some(stbl.to_vec()),
+            _ => None,
+        })
+    };
+    let mdia = |mdia: &[u8]| {
+        replace(mdia, &|kind, child| match kind {
+            b"mdhd" => Some(mp4_box(
+                b"mdhd",
+                &with_duration(child, 16, 24, media_duration),
+            )),
+            b"minf" => Some(mp4_box(b"minf", &minf(child))),
+            _ => None,
+        })
+    };
+
+    let content = replace(trak, &|kind, child| match kind {
+        b"tkhd" => Some(mp4_box(
+            b"tkhd",
+            &with_duration(child, 20, 28, movie_duration),
+        )),
+        b"mdia" => Some(mp4_box(b"mdia", &mdia(child))),
+        // edits of the fragmented timeline don't apply to the remuxed one
+        b"edts" => Some(Vec::new()),
+        _ => None,
+    });
+
+    mp4_box(b"trak", &content)
+}
+
+/// Builds a sample table for samples stored in chunks of the given number
+/// of samples at the given offsets.
+fn stbl(
+    trak: &[u8],
+    samples: &[SampleInfo],
+    chunks: &[(u32, u64)],
+    large_offsets: bool,
+) -> anyhow::Result<Vec<u8>> {
+    let stsd = find_box(trak, &[b"mdia", b"minf", b"stbl", b"stsd"])
+        .ok_or_else(|| anyhow::anyhow!("trak box has no stsd box"))?;
+
+    let mut content = mp4_box(b"stsd", stsd);
+
+    let durations = run_lengths(samples.iter().map(|s| s.duration));
+    let mut stts = full_box_header(0, durations.len());
+    for (count, duration) in durations {
+        stts.extend_from_slice(&count.to_be_bytes());
+        stts.extend_from_slice(&duration.to_be_bytes());
+    }
+    content.extend(mp4_box(b"stts", &stts));
+
+    if samples.iter().any(|s| s.composition_offset != 0) {
+        // version 1 for signed offsets
+        let offsets = run_lengths(samples.iter().map(|s| s.composition_offset));
+        let mut ctts = full_box_header(1, offsets.len());
+        for (count, offset) in offsets {
+            ctts.extend_from_slice(&count.to_be_bytes());
+            ctts.extend_from_slice(&offset.to_be_bytes());
+        }
+        content.extend(mp4_box(b"ctts", &ctts));
+    }
+
+    // without an stss box every sample is a sync sample
+    if samples.iter().any(|s| !s.sync) {
+        let sync = (1u32..)
+            .zip(samples)
+            .filter(|(_, s)| s.sync)
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        let mut stss = full_box_header(0, sync.len());
+        for i in sync {
+            stss.extend_from_slice(&i.to_be_bytes());
+        }
+        content.extend(mp4_box(b"stss", &stss));
+    }
+
+    let mut stsz = 0u32.to_be_bytes().to_vec();
+    stsz.extend_from_slice(&0u32.to_be_bytes());
+    stsz.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+    for sample in samples {
+        stsz.extend_from_slice(&sample.size.to_be_bytes());
+    }
+    content.extend(mp4_box(b"stsz", &stsz));
+
+    // one entry for every run of chunks with the same number of samples
+    let mut runs = Vec::<(u32, u32)>::new();
+    for (i, (samples, _)) in (1..).zip(chunks) {
+        if runs.last().map(|(_, s)| s) != Some(samples) {
+            runs.push((i, *samples));
+        }
+    }
+    let mut stsc = full_box_header(0, runs.len());
+    for (first_chunk, samples) in runs {
+        stsc.extend_from_slice(&first_chunk.to_be_bytes());
+        stsc.extend_from_slice(&samples.to_be_bytes());
+        stsc.extend_from_slice(&1u32.to_be_bytes());
+    }
+    content.extend(mp4_box(b"stsc", &stsc));
+
+    let mut chunk_offsets = full_box_header(0, chunks.len());
+    for (_, offset) in chunks {
+        if large_offsets {
+            chunk_offsets.extend_from_slice(&offset.to_be_bytes());
+        } else {
+            chunk_offsets.extend_from_slice(&(*offset as u32).to_be_bytes());
+        }
+    }
+    let kind = if large_offsets { b"co64" } else { b"stco" };
+    content.extend(mp4_box(kind, &chunk_offsets));
+
+    Ok(mp4_box(b"stbl", &content))
+}
+
+/// Collapses equal consecutive values into counts and values.
+fn run_lengths<T: PartialEq + Copy>(values: impl Iterator<Item = T>) -> Vec<(u32, T)> {
+    let mut runs = Vec::<(u32, T)>::new();
+
+    for value in values {
+        match runs.last_mut() {
+            Some((count, last)) if *last == value => *count += 1,
+            _ => runs.push((1, value)),
+        }
+    }
+
+    runs
+}
+
+/// The version and flags of a full box followed by an entry count.
+fn full_box_header(version: u8, entries: usize) -> Vec<u8> {
+    let mut content = vec![version, 0, 0, 0];
+    content.extend_from_slice(&(entries as u32).to_be_bytes());
+
+    content
+}
+
+/// Copies the content of an `mvhd`, `mdhd` or `tkhd` box with its duration
+/// replaced, which is at a different offset in version 0 and 1 boxes.
+fn with_duration(content: &[u8], offset_v0: usize, offset_v1: usize, duration: u64) -> Vec<u8> {
+    let mut content = content.to_vec();
+
+    if content.first() == Some(&1) {
+        if let Some(field) = content.get_mut(offset_v1..offset_v1 + 8) {
+            field.copy_from_slice(&duration.to_be_bytes());
+        }
+    } else if let Some(field) = content.get_mut(offset_v0..offset_v0 + 4) {
+        field.copy_from_slice(&(duration.min(u32::MAX as u64) as u32).to_be_bytes());
+    }
+
+    content
+}
+
+/// The timescale of an `mvhd` or `mdhd` box.
+fn header_timescale(content: &[u8]) -> Option<u32> {
+    let offset = if content.first() == Some(&1) { 20 } else { 12 };
+
+    content.get(offset..offset + 4).map(be_u32)
+}
+
+fn track_id(tkhd: &[u8]) -> Option<u32> {
+    let offset = if tkhd.first() == Some(&1) { 20 } else { 12 };
+
+    tkhd.get(offset..offset + 4).map(be_u32)
+}
+
+pub(crate) fn mp4_box(kind: &[u8; 4], content: &[u8]) -> Vec<u8> {
+    let mut b = Vec::with_capacity(8 + content.len());
+    b.extend_from_slice(&(8 + content.len() as u32).to_be_bytes());
+    b.extend_from_slice(kind);
+    b.extend_from_slice(content);
+
+    b
+}
@@ -0,0 +1,183 @@
+//! Muxes known frame sequences and compares the output with golden files
+//! in `tests/golden`, besides checking its box structure.
+//!
+//! A missing golden file fails the test like a different one does. Run the
+//! tests with `UPDATE_GOLDEN=1` to write the golden files after an intended
+//! change to the output, and review the difference before committing them.
+
+use sh_fmp4::{CmafMuxer, Mp4Index};
+use sh_media::{
+    testing::{audio_stream, video_frame, video_stream, FRAME_DURATION},
+    ByteStreamWriteFilter, Frame, FrameWriteFilter, Muxer, Stream,
+};
+
+use std::io::Cursor;
+
+/// About how large the video frames are, see [`video_frame`].
+const VIDEO_FRAME_SIZE: usize = 100;
+
+async fn mux(muxer: &dyn Muxer, streams: Vec<Stream>, frames: &[Frame]) -> Vec<u8> {
+    let (output, bytes) = ByteStreamWriteFilter::new();
+    let mut filter = muxer.mux(Box::new(output));
+
+    filter.start(streams).await.unwrap();
+    for frame in frames {
+        filter.write(frame.clone()).await.unwrap();
+    }
+    drop(filter);
+
+    let mut muxed = Vec::new();
+    while let Ok(chunk) = bytes.try_recv() {
+        muxed.extend_from_slice(&chunk.unwrap());
+    }
+
+    muxed
+}
+
+fn video_frames(stream: &Stream) -> Vec<Frame> {
+    sh_media::testing::video_frames(stream, VIDEO_FRAME_SIZE)
+}
+
+fn interleaved_frames(video: &Stream, audio: &Stream) -> Vec<Frame> {
+    sh_media::testing::interleaved_frames(video, audio, VIDEO_FRAME_SIZE)
+}
+
+fn assert_golden(name: &str, muxed: &[u8]) {
+    sh_media::testing::assert_golden(
+        concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden"),
+        name,
+        muxed,
+    );
+}
+
+/// Checks that every frame became a fragment of its own with the sample
+/// data, sync flag and timing of the frame.
+fn assert_fragments(muxed: &[u8], frames: &[Frame]) {
+    let index = Mp4Index::read(&mut Cursor::new(muxed)).unwrap();
+    assert_eq!(index.fragments.len(), frames.len());
+
+    let mut last_decode_time = [None, None];
+    let mut last_dts = [None, None];
+
+    for (fragment, frame) in index.fragments.iter().zip(frames) {
+        let track = if frame.stream.is_video() { 1 } else { 2 };
+        assert_eq!(fragment.track_id, track);
+        assert_eq!(fragment.samples.len(), 1);
+
+        let sample = &fragment.samples[0];
+        let start = sample.offset as usize;
+        assert_eq!(
+            &muxed[start..start + sample.size as usize],
+            &frame.buffer[..]
+        );
+        assert_eq!(sample.sync, frame.is_keyframe());
+        assert_eq!(
+            sample.composition_offset as i64,
+            frame.time.pts as i64 - frame.time.decode_time() as i64
+        );
+
+        // a sample lasts from the frame before it in its track
+        let slot = track as usize - 1;
+        if let Some(dts) = last_dts[slot] {
+            assert_eq!(sample.duration as u64, frame.time.decode_time() - dts);
+        }
+        if let Some(decode_time) = last_decode_time[slot] {
+            assert!(fragment.decode_time >= decode_time);
+        }
+
+        last_dts[slot] = Some(frame.time.decode_time());
+        last_decode_time[slot] = Some(fragment.decode_time);
+    }
+}
+
+#[tokio::test]
+async fn video_with_reordered_frames() {
+    let video = video_stream();
+    let frames = video_frames(&video);
+
+    let muxed = mux(&CmafMuxer::new(), vec![video], &frames).await;
+
+    let index = Mp4Index::read(&mut Cursor::new(&muxed)).unwrap();
+    assert_eq!(index.timescales.get(&1), Some(&90000));
+    assert_eq!(index.codecs.get(&1).map(|c| &c[..]), Some("avc1.42c01e"));
+
+    assert_fragments(&muxed, &frames);
+    assert_golden("video_with_reordered_frames.mp4", &muxed);
+}
+
+#[tokio::test]
+async fn video_and_audio() {
+    let (video, audio) = (video_stream(), audio_stream());
+    let frames = interleaved_frames(&video, &audio);
+
+    let muxed = mux(&CmafMuxer::new(), vec![video, audio], &frames).await;
+
+    let index = Mp4Index::read(&mut Cursor::new(&muxed)).unwrap();
+    assert_eq!(index.timescales.get(&2), Some(&44100));
+    assert_eq!(index.codecs.get(&2).map(|c| &c[..]), Some("mp4a.40.2"));
+
+    assert_fragments(&muxed, &frames);
+    assert_golden("video_and_audio.mp4", &muxed);
+}
+
+#[tokio::test]
+async fn aligned_tracks_start_at_zero() {
+    let (video, audio) = (video_stream(), audio_stream());
+    // a stream which was joined ten seconds in
+    let frames = interleaved_frames(&video, &audio)
+        .into_iter()
+        .map(|mut frame| {
+            frame.time.pts += frame.stream.timebase.denominator as u64 * 10;
+            frame.time.dts = frame
+                .time
+                .dts
+                .map(|dts| dts + frame.stream.timebase.denominator as u64 * 10);
+            frame
+        })
+        .collect::<Vec<_>>();
+
+    let muxed = mux(&CmafMuxer::aligned(), vec![video, audio], &frames).await;
+
+    let index = Mp4Index::read(&mut Cursor::new(&muxed)).unwrap();
+    for track in [1, 2] {
+        let first = index.fragments.iter().find(|f| f.track_id == track);
+        assert_eq!(first.map(|f| f.decode_time), Some(0));
+    }
+
+    assert_golden("aligned_tracks_start_at_zero.mp4", &muxed);
+}
+
+#[tokio::test]
+async fn negative_composition_offsets_are_clamped() {
+    let video = video_stream();
+    // a broken encoder which shows the second frame before it decodes it
+    let frames = vec![
+        video_frame(&video, 0, 0, 0, true, VIDEO_FRAME_SIZE),
+        video_frame(
+            &video,
+            1,
+            FRAME_DURATION / 2,
+            FRAME_DURATION,
+            false,
+            VIDEO_FRAME_SIZE,
+        ),
+        video_frame(
+            &video,
+            2,
+            2 * FRAME_DURATION,
+            2 * FRAME_DURATION,
+            false,
+            VIDEO_FRAME_SIZE,
+        ),
+    ];
+
+    let muxed = mux(&CmafMuxer::new(), vec![video], &frames).await;
+
+    let index = Mp4Index::read(&mut Cursor::new(&muxed)).unwrap();
+    let offsets = index
+        .fragments
+        .iter()
+        .map(|f| f.samples[0].composition_offset)
+        .collect::<Vec<_>>();
+    assert_eq!(offsets, [0, 0, 0]);
+}
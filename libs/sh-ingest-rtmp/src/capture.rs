@@ -0,0 +1,103 @@
+use rml_rtmp::sessions::ServerSessionEvent;
+use tracing::*;
+
+use std::{
+    fs::File,
+    io::Write,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// A handle which dumps the raw bytes and parsed events of an RTMP session
+/// to disk for a limited time, for diagnosing encoder incompatibilities.
+#[derive(Clone, Default)]
+pub struct RtmpCapture {
+    active: Arc<Mutex<Option<ActiveCapture>>>,
+}
+
+struct ActiveCapture {
+    raw: File,
+    events: File,
+    started: Instant,
+    until: Instant,
+}
+
+impl RtmpCapture {
+    /// Starts capturing to `<path>.rtmp` (raw bytes) and `<path>.events.txt`
+    /// (parsed events) for `duration`. Restarts an already running capture.
+    pub fn start(&self, path: &Path, duration: Duration) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let raw = File::create(path.with_extension("rtmp"))?;
+        let events = File::create(path.with_extension("events.txt"))?;
+
+        let now = Instant::now();
+        *self.active.lock().unwrap() = Some(ActiveCapture {
+            raw,
+            events,
+            started: now,
+            until: now + duration,
+        });
+
+        info!("Capturing RTMP session to {}", path.display());
+
+        Ok(())
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.lock().unwrap().is_some()
+    }
+
+    pub(crate) fn write_bytes(&self, bytes: &[u8]) {
+        self.with_capture(|capture| capture.raw.write_all(bytes));
+    }
+
+    pub(crate) fn write_event(&self, event: &ServerSessionEvent) {
+        self.with_capture(|capture| {
+            let elapsed = capture.started.elapsed().as_millis();
+
+            match event {
+                ServerSessionEvent::AudioDataReceived {
+                    data, timestamp, ..
+                } => writeln!(
+                    capture.events,
+                    "{} audio ts={} len={}",
+                    elapsed,
+                    timestamp.value,
+                    data.len()
+                ),
+                ServerSessionEvent::VideoDataReceived {
+                    data, timestamp, ..
+                } => writeln!(
+                    capture.events,
+                    "{} video ts={} len={}",
+                    elapsed,
+                    timestamp.value,
+                    data.len()
+                ),
+                event => writeln!(capture.events, "{} {:?}", elapsed, event),
+            }
+        });
+    }
+
+    fn with_capture<F: FnOnce(&mut ActiveCapture) -> std::io::Result<()>>(&self, f: F) {
+        let mut active = self.active.lock().unwrap();
+
+        if let Some(capture) = active.as_mut() {
+            let expired = Instant::now() > capture.until;
+
+            if expired {
+                info!("Finished capturing RTMP session");
+            } else if let Err(e) = f(capture) {
+                warn!("Stopping RTMP capture after failing to write: {}", e);
+            } else {
+                return;
+            }
+
+            *active = None;
+        }
+    }
+}
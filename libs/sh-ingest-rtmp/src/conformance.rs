@@ -0,0 +1,123 @@
+use rml_rtmp::{sessions::StreamMetadata, time::RtmpTimestamp};
+use serde::Serialize;
+
+use std::collections::BTreeMap;
+
+/// Warnings beyond this count are dropped to bound the size of a report.
+const MAX_WARNINGS: usize = 100;
+
+/// Keyframe intervals longer than this are reported as a warning.
+const MAX_KEYFRAME_INTERVAL_MS: u32 = 4000;
+
+/// A machine-readable summary of how a publisher behaved during an RTMP
+/// session, to help streamers and encoder vendors debug their settings.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ConformanceReport {
+    /// The fields the publisher sent in its `@setDataFrame` metadata.
+    pub metadata: BTreeMap<String, String>,
+    pub video_codec: Option<String>,
+    pub audio_codec: Option<String>,
+    pub video: TrackTiming,
+    pub audio: TrackTiming,
+    pub warnings: Vec<String>,
+}
+
+/// Timestamp behavior of a single track.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TrackTiming {
+    pub frames: u64,
+    pub keyframes: u64,
+    pub max_tag_size: usize,
+    pub first_timestamp: Option<u32>,
+    pub last_timestamp: Option<u32>,
+    /// The number of times the timestamp did not increase.
+    pub non_monotonic: u64,
+    pub max_gap_ms: u32,
+    pub max_keyframe_interval_ms: u32,
+    #[serde(skip)]
+    last_keyframe: Option<u32>,
+}
+
+impl TrackTiming {
+    fn add(&mut self, timestamp: RtmpTimestamp, size: usize, keyframe: bool) -> Option<String> {
+        let ts = timestamp.value;
+        let mut warning = None;
+
+        self.frames += 1;
+        self.max_tag_size = self.max_tag_size.max(size);
+        self.first_timestamp.get_or_insert(ts);
+
+        if let Some(last) = self.last_timestamp {
+            if ts <= last {
+                self.non_monotonic += 1;
+                warning = Some(format!("timestamp went from {} to {}", last, ts));
+            } else {
+                self.max_gap_ms = self.max_gap_ms.max(ts.wrapping_sub(last));
+            }
+        }
+        self.last_timestamp = Some(ts);
+
+        if keyframe {
+            self.keyframes += 1;
+
+            if let Some(last) = self.last_keyframe {
+                let interval = ts.wrapping_sub(last);
+                if interval > MAX_KEYFRAME_INTERVAL_MS && interval > self.max_keyframe_interval_ms {
+                    warning = Some(format!("keyframe interval of {} ms", interval));
+                }
+                self.max_keyframe_interval_ms = self.max_keyframe_interval_ms.max(interval);
+            }
+            self.last_keyframe = Some(ts);
+        }
+
+        warning
+    }
+}
+
+impl ConformanceReport {
+    pub(crate) fn set_metadata(&mut self, meta: &StreamMetadata) {
+        fn put<T: ToString>(map: &mut BTreeMap<String, String>, key: &str, value: &Option<T>) {
+            if let Some(value) = value {
+                map.insert(key.to_string(), value.to_string());
+            }
+        }
+
+        let map = &mut self.metadata;
+        put(map, "width", &meta.video_width);
+        put(map, "height", &meta.video_height);
+        put(map, "videocodecid", &meta.video_codec);
+        put(map, "framerate", &meta.video_frame_rate);
+        put(map, "videodatarate", &meta.video_bitrate_kbps);
+        put(map, "audiocodecid", &meta.audio_codec);
+        put(map, "audiodatarate", &meta.audio_bitrate_kbps);
+        put(map, "audiosamplerate", &meta.audio_sample_rate);
+        put(map, "audiochannels", &meta.audio_channels);
+        put(map, "stereo", &meta.audio_is_stereo);
+        put(map, "encoder", &meta.encoder);
+
+        if meta.video_frame_rate.is_none() {
+            self.warn("metadata does not contain a frame rate".into());
+        }
+        if meta.video_bitrate_kbps.is_none() {
+            self.warn("metadata does not contain a video bitrate".into());
+        }
+    }
+
+    pub(crate) fn add_video(&mut self, timestamp: RtmpTimestamp, size: usize, keyframe: bool) {
+        if let Some(warning) = self.video.add(timestamp, size, keyframe) {
+            self.warn(format!("video: {}", warning));
+        }
+    }
+
+    pub(crate) fn add_audio(&mut self, timestamp: RtmpTimestamp, size: usize) {
+        if let Some(warning) = self.audio.add(timestamp, size, false) {
+            self.warn(format!("audio: {}", warning));
+        }
+    }
+
+    pub(crate) fn warn(&mut self, warning: String) {
+        if self.warnings.len() < MAX_WARNINGS {
+            self.warnings.push(warning);
+        }
+    }
+}
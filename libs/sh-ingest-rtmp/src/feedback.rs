@@ -0,0 +1,88 @@
+use futures::channel::mpsc::Sender;
+use rml_amf0::Amf0Value;
+use rml_rtmp::{
+    chunk_io::{ChunkSerializer, Packet},
+    messages::RtmpMessage,
+    sessions::ServerSessionConfig,
+    time::RtmpTimestamp,
+};
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::RtmpError;
+
+/// The message stream of the first `createStream`, which is the one
+/// encoders publish on.
+const PUBLISH_STREAM_ID: u32 = 1;
+
+/// A handle which tells the publisher of an RTMP session that its network
+/// can't keep up, so encoders with a dynamic bitrate like OBS can lower it.
+#[derive(Clone)]
+pub struct RtmpFeedback {
+    rtmp_tx: Sender<Packet>,
+    /// Serializes the status messages next to the session's own serializer,
+    /// which can't be reached from outside of it.
+    serializer: Arc<Mutex<ChunkSerializer>>,
+}
+
+impl RtmpFeedback {
+    pub(crate) fn new(rtmp_tx: Sender<Packet>) -> Self {
+        let mut serializer = ChunkSerializer::new();
+        // the session announced its chunk size when it started, so only
+        // the serializer has to know about it
+        let _ = serializer
+            .set_max_chunk_size(ServerSessionConfig::new().chunk_size, RtmpTimestamp::new(0));
+
+        RtmpFeedback {
+            rtmp_tx,
+            serializer: Arc::new(Mutex::new(serializer)),
+        }
+    }
+
+    /// Sends an `onStatus` warning with the code
+    /// `NetStream.Publish.InsufficientBW` to the publisher.
+    ///
+    /// The warning is dropped if the session is gone or its outgoing
+    /// packets are backed up, which is likely on a congested network.
+    pub fn slow_network(&self, description: &str) -> anyhow::Result<()> {
+        let mut info = HashMap::new();
+        info.insert("level".to_string(), Amf0Value::Utf8String("warning".into()));
+        info.insert(
+            "code".to_string(),
+            Amf0Value::Utf8String("NetStream.Publish.InsufficientBW".into()),
+        );
+        info.insert(
+            "description".to_string(),
+            Amf0Value::Utf8String(description.to_string()),
+        );
+
+        let message = RtmpMessage::Amf0Command {
+            command_name: "onStatus".to_string(),
+            transaction_id: 0.0,
+            command_object: Amf0Value::Null,
+            additional_arguments: vec![Amf0Value::Object(info)],
+        };
+        let payload = message
+            .into_message_payload(RtmpTimestamp::new(0), PUBLISH_STREAM_ID)
+            .map_err(RtmpError::MessageSerialization)?;
+
+        // a full header, so it isn't read against the last header the
+        // session's serializer sent on the same chunk stream
+        let packet = self
+            .serializer
+            .lock()
+            .unwrap()
+            .serialize(&payload, true, true)
+            .map_err(RtmpError::ChunkSerialization)?;
+
+        self.rtmp_tx
+            .clone()
+            .try_send(packet)
+            .map_err(|_| anyhow::anyhow!("RTMP session is not sending"))?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,442 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tracing::*;
+
+use sh_media::{
+    fill_buffer, ByteReadFilter, ByteWriteFilter2, Frame, FrameDependency, FrameReadFilter,
+    FrameWriteFilter, MediaTime, Muxer, Stream,
+};
+
+use std::{collections::VecDeque, sync::Arc, time::Instant};
+
+use crate::{
+    audio_frame, composition_time, get_audio_codec_info, get_codec_from_mp4, get_codec_from_nalu,
+    is_ex_video_tag, parse_audio_tag, parse_video_tag,
+    publish::{audio_tag, sequence_header, video_tag},
+    read_ex_video_tag, video_time, RTMP_AAC_TIMEBASE, RTMP_TIMEBASE,
+};
+
+const FLV_SIGNATURE: &[u8] = b"FLV";
+/// The size of the FLV header, which the header can only extend.
+const FLV_HEADER_SIZE: usize = 9;
+const TAG_HEADER_SIZE: usize = 11;
+/// The size of the field after each tag which holds the size of the tag.
+const PREVIOUS_TAG_SIZE: usize = 4;
+
+const TAG_TYPE_AUDIO: u8 = 8;
+const TAG_TYPE_VIDEO: u8 = 9;
+
+/// The largest tag which is accepted, so a corrupt size doesn't make the
+/// filter buffer the whole upload.
+const MAX_TAG_SIZE: usize = 16 * 1024 * 1024;
+
+/// A pull filter which reads the H.264 and AAC or MP3 frames of an FLV file,
+/// like the ones pushed over HTTP by restreaming tools.
+///
+/// The tags are the same as the audio and video messages of RTMP, so they
+/// are parsed the same way as in [`crate::RtmpReadFilter`].
+pub struct FlvReadFilter {
+    read: Box<dyn ByteReadFilter + Send + Unpin>,
+    buffer: BytesMut,
+    /// Which streams the FLV header says there are.
+    has_video: bool,
+    has_audio: bool,
+
+    video_stream: Option<Stream>,
+    audio_stream: Option<Stream>,
+
+    /// The timestamp of the first tag, which the stream starts at.
+    origin: Option<u32>,
+    frames: VecDeque<Frame>,
+}
+
+impl FlvReadFilter {
+    pub fn new(read: Box<dyn ByteReadFilter + Send + Unpin>) -> Self {
+        FlvReadFilter {
+            read,
+            buffer: BytesMut::new(),
+            has_video: false,
+            has_audio: false,
+            video_stream: None,
+            audio_stream: None,
+            origin: None,
+            frames: VecDeque::new(),
+        }
+    }
+
+    async fn read_header(&mut self) -> anyhow::Result<()> {
+        fill_buffer(self.read.as_mut(), &mut self.buffer, FLV_HEADER_SIZE).await?;
+
+        if &self.buffer[..3] != FLV_SIGNATURE {
+            anyhow::bail!("Not an FLV stream");
+        }
+        if self.buffer[4] & 0x05 == 0 {
+            anyhow::bail!("FLV stream has neither audio nor video");
+        }
+
+        self.has_audio = self.buffer[4] & 0x04 != 0;
+        self.has_video = self.buffer[4] & 0x01 != 0;
+
+        let header_size = u32::from_be_bytes(self.buffer[5..9].try_into()?) as usize;
+        if !(FLV_HEADER_SIZE..=MAX_TAG_SIZE).contains(&header_size) {
+            anyhow::bail!("FLV header of {} bytes is invalid", header_size);
+        }
+
+        fill_buffer(
+            self.read.as_mut(),
+            &mut self.buffer,
+            header_size + PREVIOUS_TAG_SIZE,
+        )
+        .await?;
+        self.buffer.advance(header_size + PREVIOUS_TAG_SIZE);
+
+        Ok(())
+    }
+
+    /// Reads the next tag, adding its frame if it has one.
+    async fn fetch(&mut self) -> anyhow::Result<()> {
+        fill_buffer(self.read.as_mut(), &mut self.buffer, TAG_HEADER_SIZE).await?;
+
+        let header = &self.buffer[..TAG_HEADER_SIZE];
+        let tag_type = header[0] & 0x1f;
+        let size = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let timestamp = u32::from_be_bytes([header[7], header[4], header[5], header[6]]);
+
+        if size > MAX_TAG_SIZE {
+            anyhow::bail!("FLV tag of {} bytes is too large", size);
+        }
+
+        fill_buffer(
+            self.read.as_mut(),
+            &mut self.buffer,
+            TAG_HEADER_SIZE + size + PREVIOUS_TAG_SIZE,
+        )
+        .await?;
+        self.buffer.advance(TAG_HEADER_SIZE);
+        let data = self.buffer.split_to(size).freeze();
+        self.buffer.advance(PREVIOUS_TAG_SIZE);
+
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        // tags from a little before the first one, like audio muxed ahead
+        // of the video, start at 0 too
+        let time = timestamp.saturating_sub(*self.origin.get_or_insert(timestamp)) as u64;
+
+        match tag_type {
+            TAG_TYPE_VIDEO => self.add_video_frame(data, time),
+            TAG_TYPE_AUDIO => self.add_audio_frame(data, time),
+            // script data, like the metadata of the stream
+            _ => Ok(()),
+        }
+    }
+
+    fn add_video_frame(&mut self, data: Bytes, time: u64) -> anyhow::Result<()> {
+        if is_ex_video_tag(&data) {
+            if let Some((is_keyframe, buffer)) = read_ex_video_tag(&data, &mut self.video_stream)? {
+                self.push_video_frame(
+                    video_time(time, composition_time(&data)),
+                    is_keyframe,
+                    buffer,
+                )?;
+            }
+
+            return Ok(());
+        }
+
+        let (video_tag, video_packet) = parse_video_tag(&data)?;
+
+        let is_sequence_header = matches!(
+            video_packet.packet_type,
+            flvparse::AvcPacketType::SequenceHeader
+        );
+
+        // the sequence header can be sent again when the encoder restarts
+        let codec = match video_packet.packet_type {
+            flvparse::AvcPacketType::SequenceHeader => Some(get_codec_from_mp4(&video_packet)?),
+            flvparse::AvcPacketType::NALU if self.video_stream.is_none() => {
+                Some(get_codec_from_nalu(&video_packet)?)
+            }
+            flvparse::AvcPacketType::NALU => None,
+            _ => return Ok(()),
+        };
+
+        if let Some(codec) = codec {
+            debug!("Got FLV video parameters {:?}", codec);
+            self.video_stream = Some(Stream {
+                id: 0,
+                codec: Arc::new(codec),
+                timebase: RTMP_TIMEBASE,
+            });
+
+            if is_sequence_header {
+                return Ok(());
+            }
+        }
+
+        let is_keyframe = video_tag.header.frame_type == flvparse::FrameType::Key;
+        self.push_video_frame(
+            video_time(time, composition_time(&data)),
+            is_keyframe,
+            Bytes::copy_from_slice(video_packet.avc_data),
+        )
+    }
+
+    fn push_video_frame(
+        &mut self,
+        time: MediaTime,
+        is_keyframe: bool,
+        buffer: Bytes,
+    ) -> anyhow::Result<()> {
+        let stream = self
+            .video_stream
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("FLV video frame comes before its codec"))?;
+
+        self.frames.push_back(Frame {
+            time,
+            dependency: if is_keyframe {
+                FrameDependency::None
+            } else {
+                FrameDependency::Backwards
+            },
+            buffer,
+            stream,
+            received: Instant::now(),
+            metadata: None,
+        });
+
+        Ok(())
+    }
+
+    fn add_audio_frame(&mut self, data: Bytes, time: u64) -> anyhow::Result<()> {
+        let audio_tag = parse_audio_tag(&data)?;
+
+        if audio_tag.body.data.is_empty() {
+            return Ok(());
+        }
+
+        // an AAC sequence header, which holds the AudioSpecificConfig, or
+        // the first MP3 frame, which starts with the format of the audio
+        let is_aac = matches!(audio_tag.header.sound_format, flvparse::SoundFormat::AAC);
+        if (is_aac && audio_tag.body.data[0] == 0) || (!is_aac && self.audio_stream.is_none()) {
+            let codec = get_audio_codec_info(&audio_tag)?;
+
+            debug!("Got FLV audio parameters {:?}", codec);
+            self.audio_stream = Some(Stream {
+                id: 1,
+                codec: Arc::new(codec),
+                timebase: RTMP_AAC_TIMEBASE,
+            });
+        }
+
+        let (buffer, stream) = match (audio_frame(&audio_tag), &self.audio_stream) {
+            (Some(buffer), Some(stream)) => (buffer, stream.clone()),
+            _ => return Ok(()),
+        };
+
+        let time = MediaTime {
+            pts: time,
+            dts: None,
+            timebase: RTMP_TIMEBASE,
+        };
+
+        self.frames.push_back(Frame {
+            time: time.in_base(RTMP_AAC_TIMEBASE),
+            dependency: FrameDependency::None,
+            buffer,
+            stream,
+            received: Instant::now(),
+            metadata: None,
+        });
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl FrameReadFilter for FlvReadFilter {
+    async fn start(&mut self) -> anyhow::Result<Vec<Stream>> {
+        self.read.start().await?;
+        self.read_header().await?;
+
+        while (self.has_video && self.video_stream.is_none())
+            || (self.has_audio && self.audio_stream.is_none())
+        {
+            self.fetch().await?;
+        }
+
+        if let Some(ref video) = self.video_stream {
+            debug!("Video: {:?}", video);
+        }
+        if let Some(ref audio) = self.audio_stream {
+            debug!("Audio: {:?}", audio);
+        }
+
+        let streams = [self.video_stream.clone(), self.audio_stream.clone()];
+
+        Ok(streams.into_iter().flatten().collect())
+    }
+
+    async fn read(&mut self) -> anyhow::Result<Frame> {
+        loop {
+            if let Some(frame) = self.frames.pop_front() {
+                return Ok(frame);
+            }
+
+            self.fetch().await?;
+        }
+    }
+}
+
+/// A push filter which muxes H.264 and AAC frames into an FLV stream and
+/// writes it to a [`ByteWriteFilter2`].
+///
+/// The tags are made the same way as when publishing over RTMP, with
+/// timestamps starting at the first written frame.
+pub struct FlvWriteFilter {
+    target: Box<dyn ByteWriteFilter2 + Send + Unpin>,
+    /// The first decode time, which the timestamps start at.
+    origin: Option<u64>,
+}
+
+impl FlvWriteFilter {
+    pub fn new(target: Box<dyn ByteWriteFilter2 + Send + Unpin>) -> Self {
+        FlvWriteFilter {
+            target,
+            origin: None,
+        }
+    }
+}
+
+/// Appends a tag, and the size of it which follows every tag.
+fn put_tag(out: &mut BytesMut, tag_type: u8, timestamp: u32, data: &[u8]) {
+    let size = data.len() as u32;
+
+    out.put_u8(tag_type);
+    out.put_slice(&size.to_be_bytes()[1..]);
+    // the low 24 bits of the timestamp, then the high 8 bits
+    out.put_slice(&timestamp.to_be_bytes()[1..]);
+    out.put_u8((timestamp >> 24) as u8);
+    // stream ID, always 0
+    out.put_slice(&[0, 0, 0]);
+    out.put_slice(data);
+    out.put_u32(TAG_HEADER_SIZE as u32 + size);
+}
+
+#[async_trait::async_trait]
+impl FrameWriteFilter for FlvWriteFilter {
+    async fn start(&mut self, streams: Vec<Stream>) -> anyhow::Result<()> {
+        debug!("Muxing {:?} to FLV", streams);
+
+        self.target.start().await?;
+
+        let mut flags = 0;
+        if streams.iter().any(|s| s.is_audio()) {
+            flags |= 0x04;
+        }
+        if streams.iter().any(|s| s.is_video()) {
+            flags |= 0x01;
+        }
+
+        let mut out = BytesMut::new();
+        out.put_slice(FLV_SIGNATURE);
+        out.put_u8(1);
+        out.put_u8(flags);
+        out.put_u32(9);
+        // the size of the tag before the first one
+        out.put_u32(0);
+
+        for stream in &streams {
+            let tag_type = if stream.is_video() {
+                TAG_TYPE_VIDEO
+            } else {
+                TAG_TYPE_AUDIO
+            };
+
+            if let Some(header) = sequence_header(stream) {
+                put_tag(&mut out, tag_type, 0, &header);
+            }
+        }
+
+        self.target.write(out.freeze()).await
+    }
+
+    async fn write(&mut self, frame: Frame) -> anyhow::Result<()> {
+        let time = frame.time.in_base(RTMP_TIMEBASE);
+        let dts = time.dts.unwrap_or(time.pts);
+        let origin = *self.origin.get_or_insert(dts);
+        // FLV timestamps are 32 bits of milliseconds, which wrap around
+        let timestamp = dts.saturating_sub(origin) as u32;
+
+        let (tag_type, tag) = if frame.stream.is_video() {
+            let composition_time = time.pts.saturating_sub(dts) as u32;
+            (TAG_TYPE_VIDEO, video_tag(&frame, composition_time))
+        } else if frame.stream.is_audio() {
+            (TAG_TYPE_AUDIO, audio_tag(&frame.stream, 1, &frame.buffer))
+        } else {
+            return Ok(());
+        };
+
+        let mut out = BytesMut::with_capacity(TAG_HEADER_SIZE + tag.len() + PREVIOUS_TAG_SIZE);
+        put_tag(&mut out, tag_type, timestamp, &tag);
+
+        self.target.write(out.freeze()).await
+    }
+}
+
+/// A [`Muxer`] for FLV, see [`FlvWriteFilter`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FlvMuxer;
+
+impl Muxer for FlvMuxer {
+    fn content_type(&self) -> &'static str {
+        "video/x-flv"
+    }
+
+    fn extension(&self) -> &'static str {
+        "flv"
+    }
+
+    fn mux(
+        &self,
+        output: Box<dyn ByteWriteFilter2 + Send + Unpin>,
+    ) -> Box<dyn FrameWriteFilter + Send + Unpin> {
+        Box::new(FlvWriteFilter::new(output))
+    }
+}
+
+#[tokio::test]
+async fn rejects_invalid_header_sizes() {
+    /// Reads an FLV header which claims to be `size` bytes.
+    struct Header(Option<u32>);
+
+    #[async_trait::async_trait]
+    impl ByteReadFilter for Header {
+        async fn start(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn read(&mut self) -> anyhow::Result<Bytes> {
+            let size = match self.0.take() {
+                Some(size) => size,
+                None => return Ok(Bytes::new()),
+            };
+
+            let mut header = b"FLV\x01\x05".to_vec();
+            header.extend_from_slice(&size.to_be_bytes());
+            Ok(Bytes::from(header))
+        }
+    }
+
+    for size in [0, 8, u32::MAX] {
+        let mut flv = FlvReadFilter::new(Box::new(Header(Some(size))));
+        let error = flv.start().await.unwrap_err();
+        assert!(error.to_string().contains("is invalid"), "{}", error);
+    }
+
+    // a valid header is read until the stream ends
+    let mut flv = FlvReadFilter::new(Box::new(Header(Some(9))));
+    let error = flv.start().await.unwrap_err();
+    assert!(error.to_string().contains("ended"), "{}", error);
+}
@@ -26,17 +26,31 @@ use rml_rtmp::{
 use tokio::net::TcpStream;
 use tracing::*;
 
+use parking_lot::Mutex;
 use sh_media::{
-    split_tcp_filters, AudioCodecInfo, AudioCodecSpecificInfo, BitstreamFraming, ByteReadFilter,
-    ByteWriteFilter2, CodecInfo, CodecTypeInfo, Fraction, Frame, FrameDependency, FrameReadFilter,
-    MediaTime, SoundType, Stream, TcpReadFilter, TcpWriteFilter, VideoCodecInfo,
-    VideoCodecSpecificInfo,
+    split_tcp_filters, AudioCodecInfo, AudioCodecSpecificInfo, Av1CodecConfiguration,
+    BitstreamFraming, ByteReadFilter, ByteWriteFilter2, CodecInfo, CodecTypeInfo, Fraction, Frame,
+    FrameDependency, FrameReadFilter, HevcDecoderConfiguration, MediaTime, Mp3FrameHeader,
+    SoundType, Stream, TcpReadFilter, TcpWriteFilter, VideoCodecInfo, VideoCodecSpecificInfo,
+    Vp9FrameHeader,
 };
 
 use std::{
     cell::RefCell, collections::VecDeque, io::Cursor, net::SocketAddr, sync::Arc, time::Instant,
 };
 
+mod capture;
+mod conformance;
+mod feedback;
+mod flv;
+mod publish;
+
+pub use capture::RtmpCapture;
+pub use conformance::{ConformanceReport, TrackTiming};
+pub use feedback::RtmpFeedback;
+pub use flv::{FlvMuxer, FlvReadFilter, FlvWriteFilter};
+pub use publish::{publish_rtmp, RtmpUrl};
+
 const RTMP_TIMEBASE: Fraction = Fraction::new(1, 1000);
 const RTMP_AAC_TIMEBASE: Fraction = Fraction::new(1, 48000);
 
@@ -53,6 +67,15 @@ pub enum RtmpError {
     #[error("{0}")]
     ServerSession(rml_rtmp::sessions::ServerSessionError),
 
+    #[error("{0}")]
+    ClientSession(rml_rtmp::sessions::ClientSessionError),
+
+    #[error("{0}")]
+    MessageSerialization(rml_rtmp::messages::MessageSerializationError),
+
+    #[error("{0}")]
+    ChunkSerialization(rml_rtmp::chunk_io::ChunkSerializationError),
+
     #[error("Failed to parse video tag")]
     ParseVideoTag,
 
@@ -204,6 +227,9 @@ pub struct RtmpReadFilter {
 
     results: VecDeque<ServerSessionResult>,
     frames: VecDeque<Frame>,
+
+    capture: RtmpCapture,
+    report: Arc<Mutex<ConformanceReport>>,
 }
 
 async fn rtmp_write_task(
@@ -240,11 +266,31 @@ impl RtmpReadFilter {
 
             results: session.results,
             frames: VecDeque::new(),
+
+            capture: RtmpCapture::default(),
+            report: Default::default(),
         }
     }
 
-    fn assign_audio_stream(&mut self, tag: flvparse::AudioTag) -> anyhow::Result<()> {
-        let codec_info = get_audio_codec_info(&tag)?;
+    /// Returns the conformance report of the session, which is updated as
+    /// frames are read.
+    pub fn conformance_report(&self) -> Arc<Mutex<ConformanceReport>> {
+        self.report.clone()
+    }
+
+    /// Returns a handle which can be used to capture the session to disk.
+    pub fn capture(&self) -> RtmpCapture {
+        self.capture.clone()
+    }
+
+    /// Returns a handle which can be used to tell the publisher that its
+    /// network is too slow.
+    pub fn feedback(&self) -> RtmpFeedback {
+        RtmpFeedback::new(self.rtmp_tx.clone())
+    }
+
+    fn assign_audio_stream(&mut self, tag: &flvparse::AudioTag) -> anyhow::Result<()> {
+        let codec_info = get_audio_codec_info(tag)?;
 
         self.audio_stream = Some(Stream {
             id: 1,
@@ -276,12 +322,24 @@ impl RtmpReadFilter {
     }
 
     fn add_video_frame(&mut self, data: Bytes, timestamp: RtmpTimestamp) -> anyhow::Result<()> {
-        let (video_tag, video_packet) = parse_video_tag(&data)?;
+        let (is_keyframe, buffer) = if is_ex_video_tag(&data) {
+            match read_ex_video_tag(&data, &mut self.video_stream)? {
+                Some(frame) => frame,
+                None => return Ok(()),
+            }
+        } else {
+            let (video_tag, video_packet) = parse_video_tag(&data)?;
 
-        if self.video_stream.is_none() {
-            self.assign_video_stream(video_tag, video_packet)?;
-            return Ok(());
-        }
+            if self.video_stream.is_none() {
+                self.assign_video_stream(video_tag, video_packet)?;
+                return Ok(());
+            }
+
+            (
+                video_tag.header.frame_type == flvparse::FrameType::Key,
+                Bytes::copy_from_slice(video_packet.avc_data),
+            )
+        };
 
         if self.prev_video_time.is_none() {
             self.prev_video_time = Some(timestamp);
@@ -294,22 +352,23 @@ impl RtmpReadFilter {
 
         self.video_time += diff.value as u64;
 
-        let time = MediaTime {
-            pts: self.video_time,
-            dts: None,
-            timebase: RTMP_TIMEBASE,
-        };
+        self.report
+            .lock()
+            .add_video(timestamp, data.len(), is_keyframe);
+
+        let time = video_time(self.video_time, composition_time(&data));
 
         let frame = Frame {
             time,
-            dependency: if video_tag.header.frame_type == flvparse::FrameType::Key {
+            dependency: if is_keyframe {
                 FrameDependency::None
             } else {
                 FrameDependency::Backwards
             },
-            buffer: video_packet.avc_data.to_vec().into(),
+            buffer,
             stream: self.video_stream.clone().unwrap(),
             received: Instant::now(),
+            metadata: None,
         };
 
         self.frames.push_back(frame);
@@ -323,9 +382,12 @@ impl RtmpReadFilter {
         let audio_tag = parse_audio_tag(&data)?;
 
         if self.audio_stream.is_none() {
-            self.assign_audio_stream(audio_tag)?;
-            return Ok(());
+            self.assign_audio_stream(&audio_tag)?;
         }
+        let buffer = match audio_frame(&audio_tag) {
+            Some(buffer) => buffer,
+            None => return Ok(()),
+        };
 
         if self.prev_audio_time.is_none() {
             self.prev_audio_time = Some(timestamp);
@@ -338,6 +400,8 @@ impl RtmpReadFilter {
 
         self.audio_time += diff.value as u64;
 
+        self.report.lock().add_audio(timestamp, data.len());
+
         let time = MediaTime {
             pts: self.audio_time,
             dts: None,
@@ -350,9 +414,10 @@ impl RtmpReadFilter {
             time,
             dependency: FrameDependency::None,
 
-            buffer: Bytes::from(audio_tag.body.data[1..].to_vec()),
+            buffer,
             stream: self.audio_stream.clone().unwrap(),
             received: Instant::now(),
+            metadata: None,
         };
 
         self.frames.push_back(frame);
@@ -393,7 +458,10 @@ impl RtmpReadFilter {
         for result in results.into_iter() {
             match result {
                 ServerSessionResult::OutboundResponse(pkt) => self.rtmp_tx.send(pkt).await?,
-                ServerSessionResult::RaisedEvent(evt) => self.process_event(evt).await?,
+                ServerSessionResult::RaisedEvent(evt) => {
+                    self.capture.write_event(&evt);
+                    self.process_event(evt).await?
+                }
                 ServerSessionResult::UnhandleableMessageReceived(_payload) => {}
             }
         }
@@ -403,6 +471,7 @@ impl RtmpReadFilter {
 
     async fn fetch(&mut self) -> anyhow::Result<()> {
         let bytes = self.read_filter.read().await?;
+        self.capture.write_bytes(&bytes);
         let results = self.rtmp_server_session.handle_input(&bytes)?;
 
         self.process_results(results).await?;
@@ -446,6 +515,13 @@ impl FrameReadFilter for RtmpReadFilter {
             debug!("Audio: {:?}", audio);
         }
 
+        {
+            let mut report = self.report.lock();
+            report.set_metadata(&self.meta);
+            report.video_codec = self.video_stream.as_ref().map(|s| format!("{:?}", s.codec));
+            report.audio_codec = self.audio_stream.as_ref().map(|s| format!("{:?}", s.codec));
+        }
+
         let streams = [self.video_stream.clone(), self.audio_stream.clone()];
 
         Ok(streams.into_iter().flatten().collect())
@@ -476,6 +552,225 @@ fn parse_audio_tag(data: &[u8]) -> anyhow::Result<flvparse::AudioTag> {
     Ok(tag)
 }
 
+/// The bit which marks a video tag as Enhanced RTMP, where the low bits
+/// are the packet type and a FourCC of the codec follows.
+const EX_HEADER: u8 = 0x80;
+const EX_PACKET_TYPE_SEQUENCE_START: u8 = 0;
+const EX_PACKET_TYPE_CODED_FRAMES: u8 = 1;
+const EX_PACKET_TYPE_CODED_FRAMES_X: u8 = 3;
+const FOURCC_VP9: &[u8] = b"vp09";
+const FOURCC_HEVC: &[u8] = b"hvc1";
+const FOURCC_AV1: &[u8] = b"av01";
+
+/// The sound format of an Enhanced RTMP audio tag, where the low bits are
+/// the packet type and a FourCC of the codec follows.
+const EX_SOUND_FORMAT: u8 = 9;
+const FOURCC_OPUS: &[u8] = b"Opus";
+
+/// The frame type in the high bits of the first byte of a video tag.
+const EX_FRAME_TYPE_KEY: u8 = 1;
+
+fn is_ex_video_tag(data: &[u8]) -> bool {
+    data.first().is_some_and(|&b| b & EX_HEADER != 0)
+}
+
+/// Reads the signed 24-bit composition time offset of a video tag, which is
+/// how much later than its timestamp a frame is shown when frames are
+/// reordered. Only AVC tags and HEVC coded frames have one.
+fn composition_time(data: &[u8]) -> i32 {
+    let offset = if !is_ex_video_tag(data) {
+        2
+    } else if data.get(1..5) == Some(FOURCC_HEVC) && data[0] & 0x0f == EX_PACKET_TYPE_CODED_FRAMES {
+        5
+    } else {
+        return 0;
+    };
+
+    match data.get(offset..offset + 3) {
+        Some(cts) => i32::from_be_bytes([cts[0], cts[1], cts[2], 0]) >> 8,
+        None => 0,
+    }
+}
+
+/// The time of a video frame, where the timestamp of the tag is when it is
+/// decoded and the composition time offset is added for when it is shown.
+fn video_time(decode_time: u64, composition_time: i32) -> MediaTime {
+    MediaTime {
+        pts: (decode_time as i64 + composition_time as i64).max(0) as u64,
+        dts: Some(decode_time),
+        timebase: RTMP_TIMEBASE,
+    }
+}
+
+#[test]
+fn avc_composition_time() {
+    // an inter frame NALU with a composition time of 66 ms and of -33 ms
+    assert_eq!(composition_time(&[0x27, 0x01, 0x00, 0x00, 0x42]), 66);
+    assert_eq!(composition_time(&[0x27, 0x01, 0xff, 0xff, 0xdf]), -33);
+    // too short for one
+    assert_eq!(composition_time(&[0x27, 0x01]), 0);
+}
+
+#[test]
+fn hevc_composition_time() {
+    let mut tag = vec![0x80 | (2 << 4) | EX_PACKET_TYPE_CODED_FRAMES];
+    tag.extend_from_slice(FOURCC_HEVC);
+    tag.extend_from_slice(&[0x00, 0x00, 0x21]);
+    assert_eq!(composition_time(&tag), 33);
+
+    // frames without a composition time start right after the FourCC
+    tag[0] = 0x80 | (2 << 4) | EX_PACKET_TYPE_CODED_FRAMES_X;
+    assert_eq!(composition_time(&tag), 0);
+}
+
+#[test]
+fn video_time_reorders_frames() {
+    let time = video_time(1000, 66);
+    assert_eq!(time.pts, 1066);
+    assert_eq!(time.dts, Some(1000));
+
+    // a negative offset can't go before the start of the stream
+    let time = video_time(10, -33);
+    assert_eq!(time.pts, 0);
+    assert_eq!(time.dts, Some(10));
+}
+
+/// Reads the frame of an Enhanced RTMP video tag, where VP9, HEVC and AV1
+/// are supported, and replaces `stream` when the format changes.
+///
+/// Returns whether the frame is a keyframe, or `None` for packets which
+/// carry no frame and for frames before the stream is known.
+fn read_ex_video_tag(
+    data: &Bytes,
+    stream: &mut Option<Stream>,
+) -> anyhow::Result<Option<(bool, Bytes)>> {
+    if data.len() < 5 {
+        return Err(RtmpError::ParseVideoTag.into());
+    }
+
+    match &data[1..5] {
+        FOURCC_VP9 => read_vp9_tag(data, stream),
+        FOURCC_HEVC => read_hevc_tag(data, stream),
+        FOURCC_AV1 => read_av1_tag(data, stream),
+        fourcc => anyhow::bail!(
+            "Unsupported Enhanced RTMP codec: {}",
+            String::from_utf8_lossy(fourcc)
+        ),
+    }
+}
+
+fn read_vp9_tag(
+    data: &Bytes,
+    stream: &mut Option<Stream>,
+) -> anyhow::Result<Option<(bool, Bytes)>> {
+    // the vpcC of the sequence start says nothing which isn't also in the
+    // keyframes
+    let packet_type = data[0] & 0x0f;
+    if packet_type != EX_PACKET_TYPE_CODED_FRAMES && packet_type != EX_PACKET_TYPE_CODED_FRAMES_X {
+        return Ok(None);
+    }
+
+    let buffer = data.slice(5..);
+    let header = Vp9FrameHeader::parse(&buffer)
+        .ok_or_else(|| anyhow::anyhow!("Invalid VP9 frame header"))?;
+
+    if let Some(codec) = header.codec_info() {
+        let new_stream = Stream {
+            id: 0,
+            codec: Arc::new(codec),
+            timebase: RTMP_TIMEBASE,
+        };
+
+        if !matches!(stream, Some(s) if s.is_compatible_with(&new_stream)) {
+            debug!("Got VP9 video parameters {:?}", new_stream.codec);
+            *stream = Some(new_stream);
+        }
+    }
+
+    if stream.is_none() {
+        return Ok(None);
+    }
+
+    Ok(Some((header.keyframe.is_some(), buffer)))
+}
+
+/// Reads a HEVC tag, where the sequence start carries the `hvcC` of the
+/// stream and coded frames are NAL units prefixed by their lengths.
+fn read_hevc_tag(
+    data: &Bytes,
+    stream: &mut Option<Stream>,
+) -> anyhow::Result<Option<(bool, Bytes)>> {
+    let buffer = match data[0] & 0x0f {
+        EX_PACKET_TYPE_SEQUENCE_START => {
+            let config = HevcDecoderConfiguration::parse(&data[5..])?;
+            let new_stream = Stream {
+                id: 0,
+                codec: Arc::new(config.codec_info()?),
+                timebase: RTMP_TIMEBASE,
+            };
+
+            if !matches!(stream, Some(s) if s.is_compatible_with(&new_stream)) {
+                debug!("Got HEVC video parameters {:?}", new_stream.codec);
+                *stream = Some(new_stream);
+            }
+
+            return Ok(None);
+        }
+        // after the composition time offset, see `composition_time`
+        EX_PACKET_TYPE_CODED_FRAMES if data.len() >= 8 => data.slice(8..),
+        EX_PACKET_TYPE_CODED_FRAMES => return Err(RtmpError::ParseVideoTag.into()),
+        EX_PACKET_TYPE_CODED_FRAMES_X => data.slice(5..),
+        _ => return Ok(None),
+    };
+
+    if stream.is_none() {
+        return Ok(None);
+    }
+
+    let is_keyframe = (data[0] >> 4) & 0x07 == EX_FRAME_TYPE_KEY;
+
+    Ok(Some((is_keyframe, buffer)))
+}
+
+/// Reads an AV1 tag, where the sequence start carries the `av1C` of the
+/// stream and coded frames are OBUs without a composition time.
+fn read_av1_tag(
+    data: &Bytes,
+    stream: &mut Option<Stream>,
+) -> anyhow::Result<Option<(bool, Bytes)>> {
+    match data[0] & 0x0f {
+        EX_PACKET_TYPE_SEQUENCE_START => {
+            let config = Av1CodecConfiguration::parse(&data[5..])?;
+            let new_stream = Stream {
+                id: 0,
+                codec: Arc::new(config.codec_info()?),
+                timebase: RTMP_TIMEBASE,
+            };
+
+            if !matches!(stream, Some(s) if s.is_compatible_with(&new_stream)) {
+                debug!("Got AV1 video parameters {:?}", new_stream.codec);
+                *stream = Some(new_stream);
+            }
+
+            Ok(None)
+        }
+        EX_PACKET_TYPE_CODED_FRAMES if stream.is_some() => {
+            let is_keyframe = (data[0] >> 4) & 0x07 == EX_FRAME_TYPE_KEY;
+
+            // MP4 samples leave out the temporal delimiter which starts a
+            // temporal unit
+            let buffer = if data[5..].starts_with(&[0x12, 0x00]) {
+                data.slice(7..)
+            } else {
+                data.slice(5..)
+            };
+
+            Ok(Some((is_keyframe, buffer)))
+        }
+        _ => Ok(None),
+    }
+}
+
 fn get_codec_from_nalu(packet: &flvparse::AvcVideoPacket) -> anyhow::Result<CodecInfo> {
     let parameter_sets = find_parameter_sets(packet.avc_data);
     let codec_info = get_video_codec_info(parameter_sets)?;
@@ -570,6 +865,14 @@ fn get_video_codec_info(parameter_sets: ParameterSetContext) -> anyhow::Result<C
 fn get_audio_codec_info(tag: &flvparse::AudioTag) -> anyhow::Result<CodecInfo> {
     let name = match tag.header.sound_format {
         flvparse::SoundFormat::AAC => "AAC",
+        // MP3 has no sequence header, the tags are frames which start with
+        // the format of the audio
+        flvparse::SoundFormat::MP3 | flvparse::SoundFormat::MP3_8KHZ => {
+            let header = Mp3FrameHeader::parse(&tag.body.data)
+                .ok_or_else(|| anyhow::anyhow!("Invalid MP3 frame header"))?;
+
+            return Ok(header.codec_info());
+        }
         _ => anyhow::bail!("Unsupported audio codec {:?}", tag.header.sound_format),
     };
 
@@ -602,6 +905,18 @@ fn get_audio_codec_info(tag: &flvparse::AudioTag) -> anyhow::Result<CodecInfo> {
     })
 }
 
+/// Returns the frame of an audio tag, which for AAC follows the packet type
+/// and is missing from sequence headers.
+fn audio_frame(tag: &flvparse::AudioTag) -> Option<Bytes> {
+    match tag.header.sound_format {
+        flvparse::SoundFormat::AAC => match tag.body.data.split_first() {
+            Some((1, frame)) => Some(Bytes::from(frame.to_vec())),
+            _ => None,
+        },
+        _ => Some(Bytes::from(tag.body.data.to_vec())),
+    }
+}
+
 pub struct SpsHandler;
 pub struct PpsHandler;
 
@@ -0,0 +1,517 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use rml_rtmp::{
+    handshake::{Handshake, HandshakeProcessResult, PeerType},
+    sessions::{
+        ClientSession, ClientSessionConfig, ClientSessionEvent, ClientSessionResult,
+        PublishRequestType, StreamMetadata,
+    },
+    time::RtmpTimestamp,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{tcp::OwnedReadHalf, tcp::OwnedWriteHalf, TcpStream},
+    time::timeout,
+};
+use tracing::*;
+
+use sh_media::{
+    av1_decoder_configuration, frame_nal_units, parse_bitstream, vp9_decoder_configuration,
+    AudioCodecSpecificInfo, BitstreamFraming, CodecTypeInfo, Frame, FrameReadFilter,
+    HevcDecoderConfiguration, SoundType, Stream, VideoCodecSpecificInfo,
+};
+
+use std::time::Duration;
+
+use crate::{
+    RtmpError, EX_HEADER, EX_PACKET_TYPE_CODED_FRAMES, EX_PACKET_TYPE_SEQUENCE_START,
+    EX_SOUND_FORMAT, FOURCC_AV1, FOURCC_HEVC, FOURCC_OPUS, FOURCC_VP9, RTMP_TIMEBASE,
+};
+
+const DEFAULT_RTMP_PORT: u16 = 1935;
+
+/// How long the server has to accept the connection and the publish
+/// request.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where a stream is published to, from a URL like
+/// `rtmp://live.twitch.tv/app/<stream key>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtmpUrl {
+    pub host: String,
+    pub port: u16,
+    pub app: String,
+    pub stream_key: String,
+}
+
+impl std::str::FromStr for RtmpUrl {
+    type Err = anyhow::Error;
+
+    fn from_str(url: &str) -> anyhow::Result<Self> {
+        let rest = match url.strip_prefix("rtmp://") {
+            Some(rest) => rest,
+            None if url.starts_with("rtmps://") => anyhow::bail!("RTMPS is not supported"),
+            None => anyhow::bail!("Not an RTMP URL"),
+        };
+
+        let (authority, path) = rest
+            .split_once('/')
+            .ok_or_else(|| anyhow::anyhow!("RTMP URL has no app"))?;
+        let (app, stream_key) = path
+            .trim_end_matches('/')
+            .rsplit_once('/')
+            .ok_or_else(|| anyhow::anyhow!("RTMP URL has no stream key"))?;
+
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse()?),
+            None => (authority, DEFAULT_RTMP_PORT),
+        };
+
+        if host.is_empty() || app.is_empty() || stream_key.is_empty() {
+            anyhow::bail!("Incomplete RTMP URL");
+        }
+
+        Ok(RtmpUrl {
+            host: host.to_string(),
+            port,
+            app: app.to_string(),
+            stream_key: stream_key.to_string(),
+        })
+    }
+}
+
+/// Publishes the frames of `read` to an RTMP server, like an encoder would,
+/// until the stream ends or the connection fails.
+///
+/// `connected` is called once the server has accepted the stream.
+pub async fn publish_rtmp(
+    url: &RtmpUrl,
+    read: &mut (dyn FrameReadFilter + Send + Unpin),
+    connected: impl FnOnce(),
+) -> anyhow::Result<()> {
+    let streams = read.start().await?;
+
+    let socket = TcpStream::connect((url.host.as_str(), url.port)).await?;
+    socket.set_nodelay(true)?;
+    let (mut socket_read, mut socket_write) = socket.into_split();
+
+    let mut publisher = timeout(
+        HANDSHAKE_TIMEOUT,
+        RtmpPublisher::connect(url, &mut socket_read, &mut socket_write),
+    )
+    .await
+    .map_err(|_| anyhow::anyhow!("RTMP server did not accept the stream in time"))??;
+
+    connected();
+
+    let metadata = publisher
+        .session
+        .publish_metadata(&stream_metadata(&streams))
+        .map_err(RtmpError::ClientSession)?;
+    publisher.handle_results([metadata]);
+    for stream in &streams {
+        if let Some(header) = sequence_header(stream) {
+            publisher.publish(stream, header, 0)?;
+        }
+    }
+    publisher.flush(&mut socket_write).await?;
+
+    let mut buf = vec![0; 4096];
+
+    loop {
+        tokio::select! {
+            frame = read.read() => {
+                // the queue closes when the stream ends
+                let frame = match frame {
+                    Ok(frame) => frame,
+                    Err(_) => return Ok(()),
+                };
+
+                publisher.publish_frame(frame)?;
+                publisher.flush(&mut socket_write).await?;
+            }
+            n = socket_read.read(&mut buf) => {
+                let n = n?;
+                if n == 0 {
+                    anyhow::bail!("RTMP server closed the connection");
+                }
+
+                publisher.handle_input(&buf[..n])?;
+                publisher.flush(&mut socket_write).await?;
+            }
+        }
+    }
+}
+
+/// The client session of a stream being published, with the packets which
+/// are waiting to be written.
+struct RtmpPublisher {
+    session: ClientSession,
+    outgoing: BytesMut,
+    /// The first decode time, which the published timestamps start at.
+    origin: Option<u64>,
+}
+
+impl RtmpPublisher {
+    /// Does the handshake, and connects and publishes to the app and stream
+    /// key of `url`.
+    async fn connect(
+        url: &RtmpUrl,
+        read: &mut OwnedReadHalf,
+        write: &mut OwnedWriteHalf,
+    ) -> anyhow::Result<Self> {
+        let mut handshake = Handshake::new(PeerType::Client);
+        write
+            .write_all(
+                &handshake
+                    .generate_outbound_p0_and_p1()
+                    .map_err(RtmpError::Handshake)?,
+            )
+            .await?;
+
+        let mut buf = vec![0; 4096];
+        let remaining = loop {
+            let n = read.read(&mut buf).await?;
+            if n == 0 {
+                anyhow::bail!("RTMP server closed the connection during the handshake");
+            }
+
+            match handshake
+                .process_bytes(&buf[..n])
+                .map_err(RtmpError::Handshake)?
+            {
+                HandshakeProcessResult::InProgress { response_bytes } => {
+                    write.write_all(&response_bytes).await?;
+                }
+                HandshakeProcessResult::Completed {
+                    response_bytes,
+                    remaining_bytes,
+                } => {
+                    write.write_all(&response_bytes).await?;
+                    break remaining_bytes;
+                }
+            }
+        };
+
+        let mut config = ClientSessionConfig::new();
+        config.tc_url = Some(format!("rtmp://{}:{}/{}", url.host, url.port, url.app));
+
+        let (session, results) = ClientSession::new(config).map_err(RtmpError::ClientSession)?;
+        let mut publisher = RtmpPublisher {
+            session,
+            outgoing: BytesMut::new(),
+            origin: None,
+        };
+
+        publisher.handle_results(results);
+        publisher.handle_input(&remaining)?;
+
+        let request = publisher
+            .session
+            .request_connection(url.app.clone())
+            .map_err(RtmpError::ClientSession)?;
+        publisher.handle_results([request]);
+        publisher
+            .wait_for(read, write, |event| {
+                matches!(event, ClientSessionEvent::ConnectionRequestAccepted)
+            })
+            .await?;
+
+        let request = publisher
+            .session
+            .request_publishing(url.stream_key.clone(), PublishRequestType::Live)
+            .map_err(RtmpError::ClientSession)?;
+        publisher.handle_results([request]);
+        publisher
+            .wait_for(read, write, |event| {
+                matches!(event, ClientSessionEvent::PublishRequestAccepted)
+            })
+            .await?;
+
+        Ok(publisher)
+    }
+
+    /// Reads from the server until it raises an event, failing if it
+    /// rejects a request first.
+    async fn wait_for(
+        &mut self,
+        read: &mut OwnedReadHalf,
+        write: &mut OwnedWriteHalf,
+        is_event: impl Fn(&ClientSessionEvent) -> bool,
+    ) -> anyhow::Result<()> {
+        let mut buf = vec![0; 4096];
+
+        loop {
+            self.flush(write).await?;
+
+            let n = read.read(&mut buf).await?;
+            if n == 0 {
+                anyhow::bail!("RTMP server closed the connection");
+            }
+
+            for event in self.handle_input(&buf[..n])? {
+                match event {
+                    ClientSessionEvent::ConnectionRequestRejected { description } => {
+                        anyhow::bail!("RTMP server rejected the connection: {}", description)
+                    }
+                    event if is_event(&event) => return Ok(()),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Handles bytes from the server, returning the events they raised.
+    fn handle_input(&mut self, bytes: &[u8]) -> anyhow::Result<Vec<ClientSessionEvent>> {
+        let results = self
+            .session
+            .handle_input(bytes)
+            .map_err(RtmpError::ClientSession)?;
+
+        Ok(self.handle_results(results))
+    }
+
+    /// Queues the packets of session results to be written, returning the
+    /// events they raised.
+    fn handle_results(
+        &mut self,
+        results: impl IntoIterator<Item = ClientSessionResult>,
+    ) -> Vec<ClientSessionEvent> {
+        let mut events = Vec::new();
+
+        for result in results {
+            match result {
+                ClientSessionResult::OutboundResponse(packet) => {
+                    self.outgoing.extend_from_slice(&packet.bytes)
+                }
+                ClientSessionResult::RaisedEvent(event) => {
+                    debug!("Got RTMP client event {:?}", event);
+                    events.push(event);
+                }
+                ClientSessionResult::UnhandleableMessageReceived(_payload) => {}
+            }
+        }
+
+        events
+    }
+
+    async fn flush(&mut self, write: &mut OwnedWriteHalf) -> anyhow::Result<()> {
+        if !self.outgoing.is_empty() {
+            let bytes = self.outgoing.split();
+            write.write_all(&bytes).await?;
+        }
+
+        Ok(())
+    }
+
+    fn publish_frame(&mut self, frame: Frame) -> anyhow::Result<()> {
+        let time = frame.time.in_base(RTMP_TIMEBASE);
+        let dts = time.dts.unwrap_or(time.pts);
+        let origin = *self.origin.get_or_insert(dts);
+        let timestamp = dts.saturating_sub(origin);
+
+        let tag = if frame.stream.is_video() {
+            let composition_time = time.pts.saturating_sub(dts) as u32;
+            video_tag(&frame, composition_time)
+        } else if frame.stream.is_audio() {
+            audio_tag(&frame.stream, 1, &frame.buffer)
+        } else {
+            return Ok(());
+        };
+
+        self.publish(&frame.stream, tag, timestamp)
+    }
+
+    fn publish(&mut self, stream: &Stream, tag: Bytes, timestamp: u64) -> anyhow::Result<()> {
+        // RTMP timestamps are 32 bits of milliseconds, which wrap around
+        let timestamp = RtmpTimestamp::new(timestamp as u32);
+
+        let result = if stream.is_video() {
+            self.session.publish_video_data(tag, timestamp, false)
+        } else {
+            self.session.publish_audio_data(tag, timestamp, false)
+        }
+        .map_err(RtmpError::ClientSession)?;
+        self.handle_results([result]);
+
+        Ok(())
+    }
+}
+
+/// Describes the streams in the `onMetaData` message servers expect before
+/// the media.
+fn stream_metadata(streams: &[Stream]) -> StreamMetadata {
+    let mut metadata = StreamMetadata::new();
+    metadata.encoder = Some("streamhead".to_string());
+
+    for stream in streams {
+        match &stream.codec.properties {
+            CodecTypeInfo::Video(video) => {
+                metadata.video_width = Some(video.width);
+                metadata.video_height = Some(video.height);
+            }
+            CodecTypeInfo::Audio(audio) => {
+                let stereo = !matches!(audio.sound_type, SoundType::Mono);
+
+                metadata.audio_sample_rate = Some(audio.sample_rate);
+                metadata.audio_channels = Some(if stereo { 2 } else { 1 });
+                metadata.audio_is_stereo = Some(stereo);
+            }
+        }
+    }
+
+    metadata
+}
+
+/// Makes the tag with the decoder configuration of a stream, which has to
+/// be sent before its frames.
+pub(crate) fn sequence_header(stream: &Stream) -> Option<Bytes> {
+    match &stream.codec.properties {
+        CodecTypeInfo::Video(video) => match &video.extra {
+            VideoCodecSpecificInfo::H264 {
+                profile_indication,
+                profile_compatibility,
+                level_indication,
+                sps,
+                pps,
+                ..
+            } => {
+                let mut tag = BytesMut::new();
+                // keyframe, AVC, sequence header, no composition time
+                tag.put_slice(&[0x17, 0x00, 0x00, 0x00, 0x00]);
+
+                // AVCDecoderConfigurationRecord
+                tag.put_u8(1);
+                tag.put_u8(*profile_indication);
+                tag.put_u8(*profile_compatibility);
+                tag.put_u8(*level_indication);
+                // 4 byte NAL unit lengths
+                tag.put_u8(0xff);
+                tag.put_u8(0xe1);
+                tag.put_u16(sps.len() as u16);
+                tag.put_slice(sps);
+                tag.put_u8(1);
+                tag.put_u16(pps.len() as u16);
+                tag.put_slice(pps);
+
+                Some(tag.freeze())
+            }
+            VideoCodecSpecificInfo::Vp9 { .. } => {
+                let record = vp9_decoder_configuration(&video.extra)?;
+
+                let mut tag = BytesMut::new();
+                // Enhanced RTMP keyframe, sequence start
+                tag.put_u8(EX_HEADER | (1 << 4) | EX_PACKET_TYPE_SEQUENCE_START);
+                tag.put_slice(FOURCC_VP9);
+                tag.put_slice(&record);
+
+                Some(tag.freeze())
+            }
+            VideoCodecSpecificInfo::H265 { config, .. } => {
+                // frames are sent with 4 byte NAL unit lengths
+                let record = HevcDecoderConfiguration {
+                    length_size: 4,
+                    ..(**config).clone()
+                };
+
+                let mut tag = BytesMut::new();
+                // Enhanced RTMP keyframe, sequence start
+                tag.put_u8(EX_HEADER | (1 << 4) | EX_PACKET_TYPE_SEQUENCE_START);
+                tag.put_slice(FOURCC_HEVC);
+                tag.put_slice(&record.to_bytes());
+
+                Some(tag.freeze())
+            }
+            VideoCodecSpecificInfo::Av1 { .. } => {
+                let record = av1_decoder_configuration(&video.extra)?;
+
+                let mut tag = BytesMut::new();
+                // Enhanced RTMP keyframe, sequence start
+                tag.put_u8(EX_HEADER | (1 << 4) | EX_PACKET_TYPE_SEQUENCE_START);
+                tag.put_slice(FOURCC_AV1);
+                tag.put_slice(&record);
+
+                Some(tag.freeze())
+            }
+        },
+        CodecTypeInfo::Audio(audio) => match &audio.extra {
+            AudioCodecSpecificInfo::Aac { extra } => Some(audio_tag(stream, 0, extra)),
+            AudioCodecSpecificInfo::Opus { header } => {
+                Some(audio_tag(stream, 0, &header.to_bytes()))
+            }
+            AudioCodecSpecificInfo::Mp3 => None,
+        },
+    }
+}
+
+/// Makes a video tag of a frame, which is AVC with its NAL units prefixed
+/// by their lengths, or Enhanced RTMP for HEVC, VP9 and AV1.
+pub(crate) fn video_tag(frame: &Frame, composition_time: u32) -> Bytes {
+    if !frame.stream.is_h264() && !frame.stream.is_h265() {
+        let frame_type = if frame.is_keyframe() { 1 } else { 2 };
+        let fourcc = if frame.stream.is_av1() {
+            FOURCC_AV1
+        } else {
+            FOURCC_VP9
+        };
+
+        // Enhanced RTMP coded frames, which have no composition time for VP9
+        // and AV1
+        let mut tag = BytesMut::with_capacity(5 + frame.buffer.len());
+        tag.put_u8(EX_HEADER | (frame_type << 4) | EX_PACKET_TYPE_CODED_FRAMES);
+        tag.put_slice(fourcc);
+        tag.put_slice(&frame.buffer);
+
+        return tag.freeze();
+    }
+
+    let framing = frame
+        .stream
+        .bitstream_format()
+        .unwrap_or(BitstreamFraming::FourByteLength);
+    let data = if framing == BitstreamFraming::FourByteLength {
+        frame.buffer.clone()
+    } else {
+        let nal_units = parse_bitstream(frame.buffer.clone(), framing);
+        frame_nal_units(&nal_units[..], BitstreamFraming::FourByteLength).freeze()
+    };
+
+    let mut tag = BytesMut::with_capacity(8 + data.len());
+    if frame.stream.is_h265() {
+        let frame_type = if frame.is_keyframe() { 1 } else { 2 };
+
+        tag.put_u8(EX_HEADER | (frame_type << 4) | EX_PACKET_TYPE_CODED_FRAMES);
+        tag.put_slice(FOURCC_HEVC);
+    } else {
+        tag.put_u8(if frame.is_keyframe() { 0x17 } else { 0x27 });
+        // NAL units
+        tag.put_u8(1);
+    }
+    // the signed 24-bit composition time offset
+    tag.put_slice(&composition_time.to_be_bytes()[1..]);
+    tag.put_slice(&data);
+
+    tag.freeze()
+}
+
+/// Makes an audio tag, where `packet_type` is 0 for the AudioSpecificConfig
+/// or Opus identification header and 1 for a raw frame. Opus is sent as
+/// Enhanced RTMP, which has the same packet types, and MP3 has no packet
+/// types since it has no sequence header.
+pub(crate) fn audio_tag(stream: &Stream, packet_type: u8, data: &[u8]) -> Bytes {
+    let mut tag = BytesMut::with_capacity(5 + data.len());
+    if stream.is_opus() {
+        tag.put_u8((EX_SOUND_FORMAT << 4) | packet_type);
+        tag.put_slice(FOURCC_OPUS);
+    } else if stream.is_mp3() {
+        // MP3, flagged as 44 kHz 16-bit stereo like AAC, since decoders
+        // read the format from the frames
+        tag.put_u8(0x2f);
+    } else {
+        // AAC, which is always flagged as 44 kHz 16-bit stereo
+        tag.put_u8(0xaf);
+        tag.put_u8(packet_type);
+    }
+    tag.put_slice(data);
+
+    tag.freeze()
+}
@@ -0,0 +1,158 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use tracing::*;
+
+const NAL_STAP_A: u8 = 24;
+const NAL_FU_A: u8 = 28;
+
+/// Reassembles H.264 access units from RTP payloads (RFC 6184), in single
+/// NAL unit, STAP-A or FU-A packets.
+#[derive(Default)]
+pub(crate) struct H264Depacketizer {
+    nal_units: Vec<Bytes>,
+    timestamp: Option<u32>,
+    fragment: Option<BytesMut>,
+}
+
+impl H264Depacketizer {
+    /// Adds the payload of an RTP packet, returning an access unit and its
+    /// timestamp once it is complete.
+    pub(crate) fn push(
+        &mut self,
+        payload: &[u8],
+        timestamp: u32,
+        marker: bool,
+    ) -> Option<(u32, Vec<Bytes>)> {
+        // an access unit ends at its marker, or when the next one starts if
+        // the marker was lost
+        let mut complete = None;
+        if self.timestamp.is_some_and(|t| t != timestamp) {
+            complete = self.take();
+        }
+        self.timestamp = Some(timestamp);
+
+        self.add_payload(payload);
+
+        if marker && complete.is_none() {
+            complete = self.take();
+        }
+
+        complete
+    }
+
+    fn add_payload(&mut self, payload: &[u8]) -> Option<()> {
+        let header = *payload.first()?;
+
+        match header & 0x1f {
+            NAL_STAP_A => {
+                let mut rest = &payload[1..];
+                while rest.len() >= 2 {
+                    let size = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+                    let nal = match rest.get(2..2 + size) {
+                        Some(nal) => nal,
+                        None => break,
+                    };
+
+                    self.nal_units.push(Bytes::copy_from_slice(nal));
+                    rest = &rest[2 + size..];
+                }
+            }
+            NAL_FU_A => {
+                let fu_header = *payload.get(1)?;
+                let data = &payload[2..];
+
+                if fu_header & 0x80 != 0 {
+                    let mut nal = BytesMut::with_capacity(64 * 1024);
+                    nal.put_u8((header & 0xe0) | (fu_header & 0x1f));
+                    nal.put_slice(data);
+                    self.fragment = Some(nal);
+                } else if let Some(nal) = &mut self.fragment {
+                    nal.put_slice(data);
+                }
+
+                if fu_header & 0x40 != 0 {
+                    if let Some(nal) = self.fragment.take() {
+                        self.nal_units.push(nal.freeze());
+                    }
+                }
+            }
+            1..=23 => self.nal_units.push(Bytes::copy_from_slice(payload)),
+            other => debug!("Skipping an H.264 RTP packet of type {}", other),
+        }
+
+        Some(())
+    }
+
+    /// Forgets the NAL unit being reassembled, after packets were lost.
+    pub(crate) fn lost(&mut self) {
+        self.fragment = None;
+    }
+
+    fn take(&mut self) -> Option<(u32, Vec<Bytes>)> {
+        let timestamp = self.timestamp.take()?;
+        self.fragment = None;
+
+        if self.nal_units.is_empty() {
+            return None;
+        }
+
+        Some((timestamp, std::mem::take(&mut self.nal_units)))
+    }
+}
+
+/// Splits the RTP payloads of `mpeg4-generic` audio (RFC 3640) into AAC
+/// frames.
+pub(crate) struct AacDepacketizer {
+    size_length: u32,
+    index_length: u32,
+}
+
+impl AacDepacketizer {
+    pub(crate) fn new(size_length: u32, index_length: u32) -> Self {
+        AacDepacketizer {
+            size_length,
+            index_length,
+        }
+    }
+
+    pub(crate) fn push(&self, payload: &[u8]) -> Vec<Bytes> {
+        let mut frames = Vec::new();
+
+        if payload.len() < 2 || self.size_length == 0 {
+            return frames;
+        }
+
+        let header_bits = u16::from_be_bytes([payload[0], payload[1]]) as usize;
+        let header_bytes = header_bits.div_ceil(8);
+        let au_bits = (self.size_length + self.index_length) as usize;
+
+        let headers = match payload.get(2..2 + header_bytes) {
+            Some(headers) => headers,
+            None => return frames,
+        };
+        let mut data = &payload[2 + header_bytes..];
+
+        // every AU header is a size followed by an index, which is always
+        // zero for AAC-hbr
+        for i in 0..header_bits / au_bits.max(1) {
+            let size = read_bits(headers, i * au_bits, self.size_length as usize) as usize;
+
+            match data.get(..size) {
+                Some(frame) => frames.push(Bytes::copy_from_slice(frame)),
+                None => break,
+            }
+            data = &data[size..];
+        }
+
+        frames
+    }
+}
+
+fn read_bits(data: &[u8], start: usize, len: usize) -> u32 {
+    (start..start + len).fold(0, |value, bit| {
+        let set = data
+            .get(bit / 8)
+            .is_some_and(|byte| byte & (0x80 >> (bit % 8)) != 0);
+
+        (value << 1) | set as u32
+    })
+}
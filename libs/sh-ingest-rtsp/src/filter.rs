@@ -0,0 +1,474 @@
+use bytes::Bytes;
+use h264_reader::{
+    nal::{sps::SeqParameterSet, UnitType},
+    rbsp::decode_nal,
+};
+use sh_media::{
+    frame_nal_units, nut_header, AudioCodecInfo, AudioCodecSpecificInfo, BitstreamFraming,
+    CodecInfo, CodecTypeInfo, Fraction, Frame, FrameDependency, FrameReadFilter, MediaTime,
+    SoundType, Stream, VideoCodecInfo, VideoCodecSpecificInfo,
+};
+use tracing::*;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::Arc,
+    time::Instant,
+};
+
+use crate::{
+    depacketize::{AacDepacketizer, H264Depacketizer},
+    sdp::MediaDescription,
+    Message, RtspConnection, SESSION_ID,
+};
+
+const AAC_SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+const AAC_FRAME_SAMPLES: u64 = 1024;
+
+/// A pull filter which reads H.264 video and AAC audio from the RTP packets
+/// of an RTSP client which is recording.
+pub struct RtspReadFilter {
+    connection: RtspConnection,
+    addr: SocketAddr,
+    /// The tracks by the interleaved channel of their RTP packets.
+    tracks: HashMap<u8, Track>,
+    frames: VecDeque<Frame>,
+
+    video_stream: Option<Stream>,
+    audio_stream: Option<Stream>,
+
+    /// When recording started, which the timestamps of every track are
+    /// aligned to.
+    started: Instant,
+}
+
+enum Depacketizer {
+    H264(H264Depacketizer),
+    Aac(AacDepacketizer),
+}
+
+struct Track {
+    depacketizer: Depacketizer,
+    clock: RtpClock,
+    last_seq: Option<u16>,
+}
+
+impl RtspReadFilter {
+    pub(crate) fn new(
+        connection: RtspConnection,
+        addr: SocketAddr,
+        medias: HashMap<u8, MediaDescription>,
+    ) -> anyhow::Result<Self> {
+        let mut filter = RtspReadFilter {
+            connection,
+            addr,
+            tracks: HashMap::new(),
+            frames: VecDeque::new(),
+            video_stream: None,
+            audio_stream: None,
+            started: Instant::now(),
+        };
+
+        for (channel, media) in medias {
+            let depacketizer = match media.encoding.to_ascii_lowercase().as_str() {
+                "h264" => {
+                    // the parameter sets are usually sent in the SDP, but
+                    // can also be sent in band
+                    if let Some(sets) = media.fmtp.get("sprop-parameter-sets") {
+                        let nal_units = sets
+                            .split(',')
+                            .filter_map(|set| base64::decode(set.trim()).ok())
+                            .map(Bytes::from)
+                            .collect::<Vec<_>>();
+
+                        filter.video_stream =
+                            get_video_codec_info(&nal_units).map(|codec| Stream {
+                                id: 0,
+                                codec: Arc::new(codec),
+                                timebase: Fraction::new(1, media.clock_rate),
+                            });
+                    }
+
+                    Depacketizer::H264(H264Depacketizer::default())
+                }
+                "mpeg4-generic" => {
+                    let config = media
+                        .fmtp
+                        .get("config")
+                        .and_then(|config| decode_hex(config))
+                        .ok_or_else(|| anyhow::anyhow!("AAC audio has no config"))?;
+                    let codec = get_audio_codec_info(config)?;
+
+                    filter.audio_stream = Some(Stream {
+                        id: 1,
+                        codec: Arc::new(codec),
+                        timebase: Fraction::new(1, media.clock_rate),
+                    });
+
+                    let param = |name: &str, default: u32| {
+                        media
+                            .fmtp
+                            .get(name)
+                            .and_then(|v| v.parse().ok())
+                            .unwrap_or(default)
+                    };
+
+                    Depacketizer::Aac(AacDepacketizer::new(
+                        param("sizelength", 13),
+                        param("indexlength", 3),
+                    ))
+                }
+                _ => {
+                    warn!(
+                        "Ignoring unsupported {} RTSP media {:?} from {}",
+                        media.kind, media.encoding, addr
+                    );
+                    continue;
+                }
+            };
+
+            filter.tracks.insert(
+                channel,
+                Track {
+                    depacketizer,
+                    clock: RtpClock::new(media.clock_rate),
+                    last_seq: None,
+                },
+            );
+        }
+
+        if !filter
+            .tracks
+            .values()
+            .any(|t| matches!(t.depacketizer, Depacketizer::H264(_)))
+        {
+            anyhow::bail!("RTSP stream has no H.264 video");
+        }
+
+        Ok(filter)
+    }
+
+    fn has_streams(&self) -> bool {
+        let has_audio = self
+            .tracks
+            .values()
+            .any(|t| matches!(t.depacketizer, Depacketizer::Aac(_)));
+
+        self.video_stream.is_some() && (self.audio_stream.is_some() || !has_audio)
+    }
+
+    async fn next_message(&mut self) -> anyhow::Result<()> {
+        match self.connection.read_message().await? {
+            Message::Interleaved { channel, data } => self.add_rtp(channel, &data),
+            Message::Request(request) => match request.method.as_str() {
+                "TEARDOWN" => {
+                    info!("RTSP client at {} stopped recording", self.addr);
+                    anyhow::bail!("RTSP client stopped recording");
+                }
+                // keepalives, usually GET_PARAMETER or OPTIONS
+                _ => {
+                    self.connection
+                        .respond(&request, 200, "OK", &[("Session", SESSION_ID)])
+                        .await
+                }
+            },
+        }
+    }
+
+    fn add_rtp(&mut self, channel: u8, packet: &[u8]) -> anyhow::Result<()> {
+        // RTCP is sent on the channels without a track
+        let track = match self.tracks.get_mut(&channel) {
+            Some(track) => track,
+            None => return Ok(()),
+        };
+
+        let rtp = match parse_rtp(packet) {
+            Some(rtp) => rtp,
+            None => {
+                debug!("Skipping a malformed RTP packet from {}", self.addr);
+                return Ok(());
+            }
+        };
+
+        let lost = track
+            .last_seq
+            .is_some_and(|last| last.wrapping_add(1) != rtp.seq);
+        track.last_seq = Some(rtp.seq);
+
+        match &mut track.depacketizer {
+            Depacketizer::H264(depacketizer) => {
+                if lost {
+                    depacketizer.lost();
+                }
+
+                if let Some((timestamp, nal_units)) =
+                    depacketizer.push(rtp.payload, rtp.timestamp, rtp.marker)
+                {
+                    let time = track.clock.time(timestamp, self.started);
+                    self.add_video_frame(nal_units, time);
+                }
+            }
+            Depacketizer::Aac(depacketizer) => {
+                let frames = depacketizer.push(rtp.payload);
+                let time = track.clock.time(rtp.timestamp, self.started);
+
+                if let Some(stream) = &self.audio_stream {
+                    for (i, buffer) in (0..).zip(frames) {
+                        self.frames.push_back(Frame {
+                            time: MediaTime {
+                                pts: time + i * AAC_FRAME_SAMPLES,
+                                dts: None,
+                                timebase: stream.timebase,
+                            },
+                            dependency: FrameDependency::None,
+                            buffer,
+                            stream: stream.clone(),
+                            received: Instant::now(),
+                            metadata: None,
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn add_video_frame(&mut self, nal_units: Vec<Bytes>, time: u64) {
+        let nal_units = nal_units
+            .into_iter()
+            .filter(|nal| !nal.is_empty())
+            .filter(|nal| nut_header(nal) != Some(UnitType::AccessUnitDelimiter))
+            .collect::<Vec<_>>();
+
+        if let Some(codec) = get_video_codec_info(&nal_units) {
+            let changed = self
+                .video_stream
+                .as_ref()
+                .map(|s| s.parameter_sets() != codec.video().and_then(|v| v.parameter_sets()))
+                .unwrap_or(true);
+
+            if changed {
+                debug!("Got video parameters {:?}", codec);
+                let timebase = self
+                    .video_stream
+                    .as_ref()
+                    .map_or(Fraction::new(1, 90000), |s| s.timebase);
+
+                self.video_stream = Some(Stream {
+                    id: 0,
+                    codec: Arc::new(codec),
+                    timebase,
+                });
+            }
+        }
+
+        let stream = match &self.video_stream {
+            Some(stream) => stream.clone(),
+            None => return,
+        };
+
+        let is_keyframe = nal_units
+            .iter()
+            .any(|nal| nut_header(nal) == Some(UnitType::SliceLayerWithoutPartitioningIdr));
+
+        self.frames.push_back(Frame {
+            time: MediaTime {
+                pts: time,
+                dts: None,
+                timebase: stream.timebase,
+            },
+            dependency: if is_keyframe {
+                FrameDependency::None
+            } else {
+                FrameDependency::Backwards
+            },
+            buffer: frame_nal_units(&nal_units[..], BitstreamFraming::FourByteLength).freeze(),
+            stream,
+            received: Instant::now(),
+            metadata: None,
+        });
+    }
+}
+
+#[async_trait::async_trait]
+impl FrameReadFilter for RtspReadFilter {
+    async fn start(&mut self) -> anyhow::Result<Vec<Stream>> {
+        while !self.has_streams() {
+            self.next_message().await?;
+        }
+
+        // frames read before every stream was known start mid-stream
+        self.frames.clear();
+
+        Ok(self
+            .video_stream
+            .iter()
+            .chain(self.audio_stream.iter())
+            .cloned()
+            .collect())
+    }
+
+    async fn read(&mut self) -> anyhow::Result<Frame> {
+        loop {
+            if let Some(frame) = self.frames.pop_front() {
+                return Ok(frame);
+            }
+
+            self.next_message().await?;
+        }
+    }
+}
+
+/// Converts 32-bit RTP timestamps to a timeline which does not wrap
+/// around, starting at when the first packet arrived relative to when
+/// recording started.
+///
+/// RTSP clients send RTCP sender reports to relate the timestamps of their
+/// tracks, but aligning the tracks by arrival is close enough for a live
+/// stream.
+struct RtpClock {
+    clock_rate: u32,
+    origin: Option<u64>,
+    last: Option<u32>,
+    offset: u64,
+}
+
+impl RtpClock {
+    const WRAP: u64 = 1 << 32;
+
+    fn new(clock_rate: u32) -> Self {
+        RtpClock {
+            clock_rate,
+            origin: None,
+            last: None,
+            offset: 0,
+        }
+    }
+
+    fn time(&mut self, ts: u32, started: Instant) -> u64 {
+        if let Some(last) = self.last {
+            if last > ts && (last - ts) as u64 > Self::WRAP / 2 {
+                self.offset += Self::WRAP;
+            }
+        }
+        self.last = Some(ts);
+
+        let ts = ts as u64 + self.offset;
+        let clock_rate = self.clock_rate as u64;
+        let origin = *self.origin.get_or_insert_with(|| {
+            // where the first packet arrived on the timeline
+            let arrival = started.elapsed().as_micros() as u64 * clock_rate / 1_000_000;
+            ts.saturating_sub(arrival)
+        });
+
+        ts.saturating_sub(origin)
+    }
+}
+
+struct RtpPacket<'a> {
+    marker: bool,
+    seq: u16,
+    timestamp: u32,
+    payload: &'a [u8],
+}
+
+fn parse_rtp(packet: &[u8]) -> Option<RtpPacket<'_>> {
+    if packet.len() < 12 || packet[0] >> 6 != 2 {
+        return None;
+    }
+
+    let padding = packet[0] & 0x20 != 0;
+    let extension = packet[0] & 0x10 != 0;
+    let csrc_count = (packet[0] & 0x0f) as usize;
+
+    let mut start = 12 + 4 * csrc_count;
+    if extension {
+        let header = packet.get(start..start + 4)?;
+        let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        start += 4 + 4 * len;
+    }
+
+    let mut end = packet.len();
+    if padding {
+        end = end.checked_sub(*packet.last()? as usize)?;
+    }
+
+    Some(RtpPacket {
+        marker: packet[1] & 0x80 != 0,
+        seq: u16::from_be_bytes([packet[2], packet[3]]),
+        timestamp: u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]),
+        payload: packet.get(start..end)?,
+    })
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn get_video_codec_info(nal_units: &[Bytes]) -> Option<CodecInfo> {
+    let sps = nal_units
+        .iter()
+        .find(|nal| nut_header(nal) == Some(UnitType::SeqParameterSet))?;
+    let pps = nal_units
+        .iter()
+        .find(|nal| nut_header(nal) == Some(UnitType::PicParameterSet))?;
+
+    let parsed = SeqParameterSet::from_bytes(&decode_nal(&sps[1..])).ok()?;
+    let (width, height) = parsed.pixel_dimensions().ok()?;
+
+    Some(CodecInfo {
+        name: "h264",
+        properties: CodecTypeInfo::Video(VideoCodecInfo {
+            width,
+            height,
+            extra: VideoCodecSpecificInfo::H264 {
+                bitstream_format: BitstreamFraming::FourByteLength,
+                profile_indication: parsed.profile_idc.into(),
+                profile_compatibility: parsed.constraint_flags.into(),
+                level_indication: parsed.level_idc,
+                sps: Arc::new(sps.to_vec()),
+                pps: Arc::new(pps.to_vec()),
+            },
+        }),
+    })
+}
+
+/// Builds the codec info of an AAC stream from its AudioSpecificConfig.
+fn get_audio_codec_info(config: Vec<u8>) -> anyhow::Result<CodecInfo> {
+    if config.len() < 2 {
+        anyhow::bail!("AAC config is too short");
+    }
+
+    let frequency_index = ((config[0] & 0x07) << 1) | (config[1] >> 7);
+    let channels = (config[1] >> 3) & 0x0f;
+
+    let sample_rate = *AAC_SAMPLE_RATES
+        .get(frequency_index as usize)
+        .ok_or_else(|| anyhow::anyhow!("Invalid AAC sampling frequency index"))?;
+
+    Ok(CodecInfo {
+        name: "AAC",
+        properties: CodecTypeInfo::Audio(AudioCodecInfo {
+            sample_rate,
+            sample_bpp: 16,
+            sound_type: if channels == 1 {
+                SoundType::Mono
+            } else {
+                SoundType::Stereo
+            },
+            extra: AudioCodecSpecificInfo::Aac { extra: config },
+        }),
+    })
+}
@@ -0,0 +1,445 @@
+use bytes::Bytes;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tracing::*;
+
+use std::{collections::HashMap, net::SocketAddr};
+
+mod depacketize;
+mod filter;
+mod sdp;
+
+pub use filter::*;
+
+use sdp::*;
+
+/// The largest RTSP request which is accepted, headers and body.
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+const SESSION_ID: &str = "1";
+
+/// An RTSP client which has announced a stream it wants to publish, but
+/// has not been allowed to record it yet.
+///
+/// Only RTP interleaved in the RTSP connection is supported, which is what
+/// most cameras and encoders use when publishing.
+pub struct RtspRequest {
+    connection: RtspConnection,
+    addr: SocketAddr,
+    announce: Request,
+    medias: Vec<MediaDescription>,
+}
+
+impl RtspRequest {
+    /// Answers requests until the client announces its stream, returning
+    /// the request along with the app and stream key of the announced
+    /// URL, e.g. `rtsp://host/app/key`.
+    pub async fn from_socket(
+        socket: TcpStream,
+        addr: SocketAddr,
+    ) -> anyhow::Result<(Self, String, String)> {
+        socket.set_nodelay(true)?;
+
+        let mut connection = RtspConnection::new(socket);
+
+        loop {
+            let request = match connection.read_message().await? {
+                Message::Request(request) => request,
+                Message::Interleaved { .. } => continue,
+            };
+
+            match request.method.as_str() {
+                "OPTIONS" => connection.respond_options(&request).await?,
+                "ANNOUNCE" => {
+                    let medias = parse_sdp(&String::from_utf8_lossy(&request.body));
+                    let (app, key) = url_app_and_key(&request.uri);
+
+                    return Ok((
+                        RtspRequest {
+                            connection,
+                            addr,
+                            announce: request,
+                            medias,
+                        },
+                        app,
+                        key,
+                    ));
+                }
+                _ => {
+                    connection
+                        .respond(&request, 455, "Method Not Valid in This State", &[])
+                        .await?
+                }
+            }
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Accepts the announced stream and sets up its media until the client
+    /// starts recording.
+    pub async fn accept(mut self) -> anyhow::Result<RtspReadFilter> {
+        self.connection
+            .respond(&self.announce, 200, "OK", &[])
+            .await?;
+
+        // the media each interleaved channel carries
+        let mut channels = HashMap::new();
+
+        loop {
+            let request = match self.connection.read_message().await? {
+                Message::Request(request) => request,
+                Message::Interleaved { .. } => continue,
+            };
+
+            match request.method.as_str() {
+                "SETUP" => {
+                    let media = self.media_index(&request.uri);
+                    let transport = request.header("Transport").unwrap_or_default();
+                    let interleaved = interleaved_channels(transport);
+
+                    match (media, interleaved) {
+                        (Some(media), Some((rtp, rtcp))) => {
+                            channels.insert(rtp, media);
+
+                            let transport =
+                                format!("RTP/AVP/TCP;unicast;interleaved={}-{}", rtp, rtcp);
+                            self.connection
+                                .respond(
+                                    &request,
+                                    200,
+                                    "OK",
+                                    &[("Transport", &transport), ("Session", SESSION_ID)],
+                                )
+                                .await?;
+                        }
+                        (None, _) => {
+                            self.connection
+                                .respond(&request, 404, "Not Found", &[])
+                                .await?
+                        }
+                        (_, None) => {
+                            warn!(
+                                "Rejecting RTSP transport {:?} from {}, only TCP is supported",
+                                transport, self.addr
+                            );
+                            self.connection
+                                .respond(&request, 461, "Unsupported Transport", &[])
+                                .await?
+                        }
+                    }
+                }
+                "RECORD" => {
+                    self.connection
+                        .respond(&request, 200, "OK", &[("Session", SESSION_ID)])
+                        .await?;
+                    break;
+                }
+                "OPTIONS" => self.connection.respond_options(&request).await?,
+                "TEARDOWN" => anyhow::bail!("RTSP client left before recording"),
+                _ => {
+                    self.connection
+                        .respond(&request, 455, "Method Not Valid in This State", &[])
+                        .await?
+                }
+            }
+        }
+
+        let medias = channels
+            .into_iter()
+            .map(|(channel, media)| (channel, self.medias[media].clone()))
+            .collect();
+
+        RtspReadFilter::new(self.connection, self.addr, medias)
+    }
+
+    /// Finds the media a SETUP request refers to by its control URL, which
+    /// may be relative to the announced URL.
+    fn media_index(&self, uri: &str) -> Option<usize> {
+        let by_control = self.medias.iter().position(|media| match &media.control {
+            Some(control) => uri == control || uri.ends_with(&format!("/{}", control)),
+            None => false,
+        });
+
+        // a single media without a control URL is set up with the URL of
+        // the stream itself
+        by_control.or_else(|| (self.medias.len() == 1).then_some(0))
+    }
+}
+
+/// Splits the path of an RTSP URL into the app and the stream key, like
+/// the path of an RTMP URL.
+fn url_app_and_key(uri: &str) -> (String, String) {
+    let path = uri
+        .strip_prefix("rtsp://")
+        .and_then(|rest| rest.split_once('/'))
+        .map(|(_, path)| path)
+        .unwrap_or(uri);
+    let path = path.split('?').next().unwrap_or_default().trim_matches('/');
+
+    match path.split_once('/') {
+        Some((app, key)) => (app.to_string(), key.to_string()),
+        None => (String::new(), path.to_string()),
+    }
+}
+
+/// Reads the RTP and RTCP channels of an interleaved transport, e.g.
+/// `RTP/AVP/TCP;unicast;interleaved=0-1`.
+fn interleaved_channels(transport: &str) -> Option<(u8, u8)> {
+    if !transport.contains("TCP") {
+        return None;
+    }
+
+    let channels = transport
+        .split(';')
+        .find_map(|param| param.trim().strip_prefix("interleaved="))?;
+
+    match channels.split_once('-') {
+        Some((rtp, rtcp)) => Some((rtp.parse().ok()?, rtcp.parse().ok()?)),
+        None => {
+            let rtp = channels.parse::<u8>().ok()?;
+            Some((rtp, rtp.wrapping_add(1)))
+        }
+    }
+}
+
+pub(crate) struct Request {
+    pub(crate) method: String,
+    pub(crate) uri: String,
+    headers: Vec<(String, String)>,
+    pub(crate) body: Bytes,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+pub(crate) enum Message {
+    Request(Request),
+    Interleaved { channel: u8, data: Bytes },
+}
+
+/// An RTSP connection, where requests and interleaved RTP packets are
+/// mixed once recording starts.
+pub(crate) struct RtspConnection {
+    stream: BufReader<TcpStream>,
+}
+
+impl RtspConnection {
+    fn new(socket: TcpStream) -> Self {
+        RtspConnection {
+            stream: BufReader::new(socket),
+        }
+    }
+
+    pub(crate) async fn read_message(&mut self) -> anyhow::Result<Message> {
+        let first = match self.stream.fill_buf().await?.first() {
+            Some(b) => *b,
+            None => anyhow::bail!("RTSP connection closed"),
+        };
+
+        if first == b'$' {
+            let mut header = [0; 4];
+            self.stream.read_exact(&mut header).await?;
+
+            let mut data = vec![0; u16::from_be_bytes([header[2], header[3]]) as usize];
+            self.stream.read_exact(&mut data).await?;
+
+            return Ok(Message::Interleaved {
+                channel: header[1],
+                data: Bytes::from(data),
+            });
+        }
+
+        let mut line = String::new();
+        let mut size = 0;
+        self.read_line(&mut line, &mut size).await?;
+
+        let mut parts = line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_string();
+        let uri = parts.next().unwrap_or_default().to_string();
+
+        let mut headers = Vec::new();
+        loop {
+            line.clear();
+            self.read_line(&mut line, &mut size).await?;
+
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+
+            if let Some((name, value)) = header.split_once(':') {
+                headers.push((name.trim().to_string(), value.trim().to_string()));
+            }
+        }
+
+        let mut request = Request {
+            method,
+            uri,
+            headers,
+            body: Bytes::new(),
+        };
+
+        let length = request
+            .header("Content-Length")
+            .and_then(|l| l.parse::<usize>().ok())
+            .unwrap_or(0);
+        if length > MAX_REQUEST_SIZE.saturating_sub(size) {
+            anyhow::bail!("RTSP request is too large");
+        }
+
+        let mut body = vec![0; length];
+        self.stream.read_exact(&mut body).await?;
+        request.body = Bytes::from(body);
+
+        Ok(Message::Request(request))
+    }
+
+    /// Reads a line of a request which is `size` bytes so far, without
+    /// reading past [`MAX_REQUEST_SIZE`].
+    async fn read_line(&mut self, line: &mut String, size: &mut usize) -> anyhow::Result<()> {
+        let limit = MAX_REQUEST_SIZE.saturating_sub(*size) as u64;
+        let read = (&mut self.stream).take(limit).read_line(line).await?;
+        *size += read;
+
+        if !line.ends_with('\n') {
+            if *size >= MAX_REQUEST_SIZE {
+                anyhow::bail!("RTSP request is too large");
+            }
+
+            anyhow::bail!("RTSP connection closed");
+        }
+
+        Ok(())
+    }
+
+    pub(crate) async fn respond(
+        &mut self,
+        request: &Request,
+        status: u16,
+        reason: &str,
+        headers: &[(&str, &str)],
+    ) -> anyhow::Result<()> {
+        let mut response = format!("RTSP/1.0 {} {}\r\n", status, reason);
+        if let Some(cseq) = request.header("CSeq") {
+            response += &format!("CSeq: {}\r\n", cseq);
+        }
+        for (name, value) in headers {
+            response += &format!("{}: {}\r\n", name, value);
+        }
+        response += "\r\n";
+
+        self.stream.get_mut().write_all(response.as_bytes()).await?;
+
+        Ok(())
+    }
+
+    async fn respond_options(&mut self, request: &Request) -> anyhow::Result<()> {
+        self.respond(
+            request,
+            200,
+            "OK",
+            &[(
+                "Public",
+                "OPTIONS, ANNOUNCE, SETUP, RECORD, TEARDOWN, GET_PARAMETER",
+            )],
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+async fn test_message(input: &[u8]) -> anyhow::Result<Message> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let mut client = TcpStream::connect(listener.local_addr()?).await?;
+    let (socket, _) = listener.accept().await?;
+
+    // closing the client ends the input
+    let input = input.to_vec();
+    tokio::spawn(async move { client.write_all(&input).await });
+
+    RtspConnection::new(socket).read_message().await
+}
+
+#[tokio::test]
+async fn parses_requests_and_interleaved_packets() {
+    let input = b"ANNOUNCE rtsp://host/app/key RTSP/1.0\r\n\
+        CSeq: 2\r\n\
+        Content-Length: 4\r\n\
+        \r\n\
+        v=0\n";
+    let request = match test_message(input).await.unwrap() {
+        Message::Request(request) => request,
+        Message::Interleaved { .. } => panic!("expected a request"),
+    };
+    assert_eq!(request.method, "ANNOUNCE");
+    assert_eq!(request.uri, "rtsp://host/app/key");
+    assert_eq!(request.header("cseq"), Some("2"));
+    assert_eq!(&request.body[..], b"v=0\n");
+
+    match test_message(b"$\x01\x00\x02ab").await.unwrap() {
+        Message::Interleaved { channel, data } => {
+            assert_eq!(channel, 1);
+            assert_eq!(&data[..], b"ab");
+        }
+        Message::Request(_) => panic!("expected an interleaved packet"),
+    }
+}
+
+#[tokio::test]
+async fn truncated_requests_are_rejected() {
+    let inputs: [&[u8]; 5] = [
+        b"",
+        b"OPTIONS rtsp://host/app/key",
+        b"OPTIONS rtsp://host/app/key RTSP/1.0\r\nCSeq: 1\r\n",
+        b"ANNOUNCE rtsp://host/app/key RTSP/1.0\r\nContent-Length: 10\r\n\r\nv=0\n",
+        b"$\x00\x00\x10ab",
+    ];
+
+    for input in inputs {
+        assert!(test_message(input).await.is_err(), "{:?}", input);
+    }
+}
+
+#[tokio::test]
+async fn oversized_requests_are_rejected() {
+    let long_line = vec![b'A'; MAX_REQUEST_SIZE * 2];
+    assert!(test_message(&long_line).await.is_err());
+
+    let mut long_header = b"OPTIONS rtsp://host/app/key RTSP/1.0\r\nX: ".to_vec();
+    long_header.extend(vec![b'a'; MAX_REQUEST_SIZE]);
+    long_header.extend(b"\r\n\r\n");
+    assert!(test_message(&long_header).await.is_err());
+
+    let huge_body = b"ANNOUNCE rtsp://host/app/key RTSP/1.0\r\n\
+        Content-Length: 18446744073709551615\r\n\
+        \r\n";
+    assert!(test_message(huge_body).await.is_err());
+}
+
+#[tokio::test]
+async fn malformed_requests_are_rejected() {
+    // not UTF-8
+    let input = b"OPTIONS rtsp://host/\xff\xfe RTSP/1.0\r\n\r\n";
+    assert!(test_message(input).await.is_err());
+
+    // headers without a value are skipped, and a bad length means no body
+    let input = b"OPTIONS * RTSP/1.0\r\nbroken\r\nContent-Length: many\r\n\r\n";
+    match test_message(input).await.unwrap() {
+        Message::Request(request) => {
+            assert_eq!(request.header("broken"), None);
+            assert!(request.body.is_empty());
+        }
+        Message::Interleaved { .. } => panic!("expected a request"),
+    }
+}
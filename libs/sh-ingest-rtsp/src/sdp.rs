@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+
+/// A media section of the SDP sent with an ANNOUNCE request.
+#[derive(Debug, Clone)]
+pub(crate) struct MediaDescription {
+    /// `video` or `audio`.
+    pub(crate) kind: String,
+    pub(crate) payload_type: u8,
+    /// The encoding name of the payload type, e.g. `H264` or
+    /// `mpeg4-generic`.
+    pub(crate) encoding: String,
+    pub(crate) clock_rate: u32,
+    /// The `a=control` URL, which SETUP requests refer to the media with.
+    pub(crate) control: Option<String>,
+    /// The format parameters of the payload type.
+    pub(crate) fmtp: HashMap<String, String>,
+}
+
+/// Reads the media sections of a session description.
+pub(crate) fn parse_sdp(sdp: &str) -> Vec<MediaDescription> {
+    let mut medias = Vec::<MediaDescription>::new();
+
+    for line in sdp.lines().map(str::trim) {
+        if let Some(media) = line.strip_prefix("m=") {
+            // e.g. "video 0 RTP/AVP 96"
+            let fields = media.split_whitespace().collect::<Vec<_>>();
+            let payload_type = fields.get(3).and_then(|pt| pt.parse().ok());
+
+            if let (Some(kind), Some(payload_type)) = (fields.first(), payload_type) {
+                medias.push(MediaDescription {
+                    kind: kind.to_string(),
+                    payload_type,
+                    encoding: String::new(),
+                    clock_rate: 90000,
+                    control: None,
+                    fmtp: HashMap::new(),
+                });
+            }
+            continue;
+        }
+
+        let media = match medias.last_mut() {
+            Some(media) => media,
+            None => continue,
+        };
+
+        if let Some(control) = line.strip_prefix("a=control:") {
+            media.control = Some(control.to_string());
+        } else if let Some(rtpmap) = line.strip_prefix("a=rtpmap:") {
+            // e.g. "96 H264/90000" or "97 mpeg4-generic/48000/2"
+            if let Some((pt, encoding)) = rtpmap.split_once(' ') {
+                if pt.parse() == Ok(media.payload_type) {
+                    let mut parts = encoding.split('/');
+                    media.encoding = parts.next().unwrap_or_default().to_string();
+                    media.clock_rate = parts
+                        .next()
+                        .and_then(|rate| rate.parse().ok())
+                        .unwrap_or(90000);
+                }
+            }
+        } else if let Some(fmtp) = line.strip_prefix("a=fmtp:") {
+            // e.g. "96 packetization-mode=1;sprop-parameter-sets=..."
+            if let Some((pt, params)) = fmtp.split_once(' ') {
+                if pt.parse() == Ok(media.payload_type) {
+                    media.fmtp = params
+                        .split(';')
+                        .filter_map(|param| param.trim().split_once('='))
+                        .map(|(k, v)| (k.to_ascii_lowercase(), v.to_string()))
+                        .collect();
+                }
+            }
+        }
+    }
+
+    medias
+}
@@ -0,0 +1,268 @@
+use bytes::{Bytes, BytesMut};
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    time::{interval, timeout, Duration, Instant},
+};
+use tracing::*;
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use crate::{handshake::*, packet::*, SrtConnection, CONNECTION_QUEUE};
+
+/// How long a listener has to answer the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often a handshake is sent again while there is no answer.
+const HANDSHAKE_RETRY: Duration = Duration::from_millis(250);
+
+/// The handshake extension field of a version 4 induction, which asks
+/// for datagrams rather than a byte stream.
+const UDT_DGRAM: u16 = 2;
+
+/// The extension field flag of a handshake with a stream ID.
+const FLAG_CONFIG: u16 = 0x4;
+
+/// How often a connection which gets no packets is checked for being
+/// dropped.
+const ROUTE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+const MTU: u32 = 1500;
+
+/// Where a stream is sent to, from a URL like
+/// `srt://host:port?streamid=<key>&latency=<ms>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrtUrl {
+    pub host: String,
+    pub port: u16,
+    pub stream_id: Option<String>,
+    pub latency: Option<Duration>,
+}
+
+impl std::str::FromStr for SrtUrl {
+    type Err = anyhow::Error;
+
+    fn from_str(url: &str) -> anyhow::Result<Self> {
+        let rest = url
+            .strip_prefix("srt://")
+            .ok_or_else(|| anyhow::anyhow!("Not an SRT URL"))?;
+
+        let (authority, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let (host, port) = authority
+            .trim_end_matches('/')
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow::anyhow!("SRT URL has no port"))?;
+
+        if host.is_empty() {
+            anyhow::bail!("SRT URL has no host");
+        }
+
+        let mut stream_id = None;
+        let mut latency = None;
+        for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+            match key {
+                "streamid" => stream_id = Some(value.to_string()),
+                "latency" => latency = Some(Duration::from_millis(value.parse()?)),
+                _ => {}
+            }
+        }
+
+        Ok(SrtUrl {
+            host: host.to_string(),
+            port: port.parse()?,
+            stream_id,
+            latency,
+        })
+    }
+}
+
+impl SrtConnection {
+    /// Calls a listener, which buffers at least `latency` of packets or
+    /// more if the URL or the listener asks for it.
+    pub async fn connect(url: &SrtUrl, latency: Duration) -> anyhow::Result<Self> {
+        let peer = lookup_host((url.host.as_str(), url.port))
+            .await?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("SRT host {} not found", url.host))?;
+        let local: SocketAddr = if peer.is_ipv4() {
+            "0.0.0.0:0".parse()?
+        } else {
+            "[::]:0".parse()?
+        };
+        let socket = Arc::new(UdpSocket::bind(local).await?);
+
+        let random = RandomState::new().build_hasher().finish();
+        let socket_id = (random as u32) | 1;
+        let initial_seq = (random >> 32) as u32 & 0x7fff_ffff;
+        let latency = url.latency.unwrap_or_default().max(latency);
+
+        let induction = Handshake {
+            version: 4,
+            encryption: 0,
+            extension: UDT_DGRAM,
+            initial_seq,
+            mtu: MTU,
+            flow_window: 8192,
+            kind: TYPE_INDUCTION,
+            socket_id,
+            cookie: 0,
+            peer_ip: [0; 16],
+            extensions: Vec::new(),
+        };
+        let response = timeout(
+            HANDSHAKE_TIMEOUT,
+            exchange(&socket, peer, &induction, TYPE_INDUCTION),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("SRT listener at {} did not answer", peer))??;
+
+        let mut extensions = vec![(EXTENSION_HSREQ, hsreq(latency_ms(latency)))];
+        let mut extension = FLAG_HANDSHAKE_EXTENSION;
+        if let Some(stream_id) = &url.stream_id {
+            extensions.push((EXTENSION_SID, encode_stream_id(stream_id)));
+            extension |= FLAG_CONFIG;
+        }
+
+        let conclusion = Handshake {
+            version: 5,
+            extension,
+            kind: TYPE_CONCLUSION,
+            cookie: response.cookie,
+            extensions,
+            ..induction
+        };
+        let response = timeout(
+            HANDSHAKE_TIMEOUT,
+            exchange(&socket, peer, &conclusion, TYPE_CONCLUSION),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("SRT listener at {} did not accept", peer))??;
+
+        let latency = response
+            .delay_ms()
+            .map(|ms| Duration::from_millis(ms as u64))
+            .unwrap_or_default()
+            .max(latency);
+
+        let (tx, rx) = async_channel::bounded(CONNECTION_QUEUE);
+        tokio::spawn(route(socket.clone(), peer, socket_id, tx));
+
+        Ok(SrtConnection {
+            socket,
+            peer,
+            stream_id: url.stream_id.clone(),
+            peer_socket_id: response.socket_id,
+            initial_seq,
+            latency,
+            packets: rx,
+            started: Instant::now(),
+        })
+    }
+}
+
+/// Sends a handshake until the listener answers with one of `kind`, or
+/// rejects it.
+async fn exchange(
+    socket: &UdpSocket,
+    peer: SocketAddr,
+    request: &Handshake,
+    kind: u32,
+) -> anyhow::Result<Handshake> {
+    let packet = control(CONTROL_HANDSHAKE, 0, 0, 0, &request.to_bytes());
+    let mut retry = interval(HANDSHAKE_RETRY);
+    let mut buf = vec![0; 2048];
+
+    loop {
+        tokio::select! {
+            _ = retry.tick() => {
+                socket.send_to(&packet, peer).await?;
+            }
+            received = socket.recv_from(&mut buf) => {
+                let (len, addr) = received?;
+                if addr != peer {
+                    continue;
+                }
+
+                let response = match Packet::parse(&buf[..len]) {
+                    Some(Packet::Control { kind: CONTROL_HANDSHAKE, cif, .. }) => {
+                        Handshake::parse(cif)
+                    }
+                    _ => None,
+                };
+
+                match response {
+                    Some(response) if response.kind == kind => return Ok(response),
+                    // rejections are sent in place of the handshake type
+                    Some(response) if response.kind != TYPE_INDUCTION => {
+                        anyhow::bail!(
+                            "SRT listener at {} rejected the call: {}",
+                            peer,
+                            response.kind
+                        )
+                    }
+                    _ => debug!("Skipping an unexpected SRT packet from {}", peer),
+                }
+            }
+        }
+    }
+}
+
+/// Passes the packets of a listener on to its connection, until the
+/// connection is dropped.
+async fn route(
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    socket_id: u32,
+    connection: async_channel::Sender<Bytes>,
+) {
+    let mut buf = vec![0; 2048];
+
+    loop {
+        // a listener which went quiet doesn't keep the socket open after the
+        // connection is dropped
+        let (len, addr) = match timeout(ROUTE_CHECK_INTERVAL, socket.recv_from(&mut buf)).await {
+            Ok(Ok(received)) => received,
+            Ok(Err(_)) => break,
+            Err(_) if connection.is_closed() => break,
+            Err(_) => continue,
+        };
+        let datagram = &buf[..len];
+
+        let dest = match Packet::parse(datagram) {
+            Some(Packet::Data { dest, .. }) | Some(Packet::Control { dest, .. }) => dest,
+            None => continue,
+        };
+        if addr != peer || dest != socket_id {
+            continue;
+        }
+
+        // lost packets are requested again
+        if let Err(async_channel::TrySendError::Closed(_)) =
+            connection.try_send(Bytes::copy_from_slice(datagram))
+        {
+            break;
+        }
+    }
+}
+
+fn latency_ms(latency: Duration) -> u16 {
+    latency.as_millis().min(u16::MAX as u128) as u16
+}
+
+/// Encodes a stream ID as libsrt does, in 32-bit words in little endian
+/// order.
+fn encode_stream_id(stream_id: &str) -> Bytes {
+    let mut bytes = BytesMut::from(stream_id.as_bytes());
+    let padding = (4 - bytes.len() % 4) % 4;
+    bytes.resize(bytes.len() + padding, 0);
+
+    for word in bytes.chunks_mut(4) {
+        word.reverse();
+    }
+
+    bytes.freeze()
+}
@@ -0,0 +1,434 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use sh_media::ReorderBuffer;
+use tokio::{
+    net::UdpSocket,
+    time::{interval, Duration, Instant},
+};
+use tracing::*;
+
+use std::{collections::VecDeque, net::SocketAddr, sync::Arc};
+
+use crate::packet::*;
+
+/// A caller is considered gone after not sending anything for this long.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often packets are acknowledged and missing packets are requested
+/// again.
+const ACK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The most packets requested in a single NAK.
+const MAX_NAKED_PACKETS: usize = 256;
+
+/// The receive buffer we report, in packets.
+const BUFFER_PACKETS: u32 = 8192;
+
+/// The most packets kept for retransmission to a receiver.
+const SEND_BUFFER_PACKETS: usize = 8192;
+
+/// A caller which has completed its handshake, or a listener we called,
+/// which either sends or receives.
+pub struct SrtConnection {
+    pub(crate) socket: Arc<UdpSocket>,
+    pub(crate) peer: SocketAddr,
+    pub(crate) stream_id: Option<String>,
+    pub(crate) peer_socket_id: u32,
+    pub(crate) initial_seq: u32,
+    pub(crate) latency: Duration,
+    pub(crate) packets: async_channel::Receiver<Bytes>,
+    /// When the connection was accepted, which timestamps count from.
+    pub(crate) started: Instant,
+}
+
+impl SrtConnection {
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// The stream ID the caller connected with, e.g. `live/key` or
+    /// `#!::r=live/key,m=publish`.
+    pub fn stream_id(&self) -> Option<&str> {
+        self.stream_id.as_deref()
+    }
+
+    /// Receives the TS payloads of the caller in order into `output`, until
+    /// the caller disconnects or goes idle, or `output` is closed.
+    pub async fn receive(&self, output: async_channel::Sender<Bytes>) -> anyhow::Result<()> {
+        let mut session = SrtSession::new(self.initial_seq, self.latency);
+        let mut ticker = interval(ACK_INTERVAL);
+
+        let result = loop {
+            tokio::select! {
+                datagram = self.packets.recv() => {
+                    let datagram = match datagram {
+                        Ok(datagram) => datagram,
+                        Err(_) => break Ok(()),
+                    };
+
+                    session.last_packet = Instant::now();
+
+                    match Packet::parse(&datagram) {
+                        Some(Packet::Data { encrypted: true, .. }) => {
+                            break Err(anyhow::anyhow!("SRT caller sent encrypted data"));
+                        }
+                        Some(Packet::Data { seq, payload, .. }) => session.add_data(seq, payload),
+                        Some(Packet::Control { kind: CONTROL_SHUTDOWN, .. }) => {
+                            info!("SRT caller at {} disconnected", self.peer);
+                            return Ok(());
+                        }
+                        // keepalives and ACKACKs only need to keep the
+                        // connection alive
+                        _ => {}
+                    }
+                }
+                _ = ticker.tick() => {
+                    if session.last_packet.elapsed() > IDLE_TIMEOUT {
+                        info!("SRT caller at {} went idle", self.peer);
+                        break Ok(());
+                    }
+
+                    self.send_control(CONTROL_ACK, session.ack_number(), &session.ack()).await?;
+                    if let Some(nak) = session.nak() {
+                        self.send_control(CONTROL_NAK, 0, &nak).await?;
+                    }
+                }
+            }
+
+            while let Some(payload) = session.packets.next_payload() {
+                if output.send(payload).await.is_err() {
+                    break;
+                }
+            }
+
+            if output.is_closed() {
+                break Ok(());
+            }
+        };
+
+        self.close().await;
+
+        result
+    }
+
+    /// Sends the TS payloads of `input` to the caller, until `input` is
+    /// closed or the caller disconnects or goes idle.
+    ///
+    /// Every payload is sent as its own packet, so they should be at most
+    /// seven TS packets. Lost packets are sent again when the caller asks
+    /// for them, until they are too late to be played.
+    pub async fn send(&self, input: async_channel::Receiver<Bytes>) -> anyhow::Result<()> {
+        let mut buffer = SendBuffer::new(self.initial_seq, self.latency);
+        let mut ticker = interval(ACK_INTERVAL);
+        let mut last_packet = Instant::now();
+
+        let result = loop {
+            tokio::select! {
+                payload = input.recv() => {
+                    let payload = match payload {
+                        Ok(payload) => payload,
+                        Err(_) => break Ok(()),
+                    };
+
+                    let packet = buffer.push(payload, self.timestamp());
+                    self.send_data(packet, false).await?;
+                }
+                datagram = self.packets.recv() => {
+                    let datagram = match datagram {
+                        Ok(datagram) => datagram,
+                        Err(_) => break Ok(()),
+                    };
+
+                    last_packet = Instant::now();
+
+                    match Packet::parse(&datagram) {
+                        Some(Packet::Control { kind: CONTROL_ACK, info, cif, .. }) => {
+                            if cif.len() >= 4 {
+                                buffer.ack(be_u32(cif) & 0x7fff_ffff);
+                            }
+
+                            // full ACKs are acknowledged, which is how the
+                            // caller measures the round trip time
+                            if cif.len() > 4 {
+                                self.send_control(CONTROL_ACKACK, info, &[]).await?;
+                            }
+                        }
+                        Some(Packet::Control { kind: CONTROL_NAK, cif, .. }) => {
+                            for seq in parse_loss_list(cif) {
+                                if let Some(packet) = buffer.get(seq) {
+                                    self.send_data(packet, true).await?;
+                                }
+                            }
+                        }
+                        Some(Packet::Control { kind: CONTROL_SHUTDOWN, .. }) => {
+                            info!("SRT caller at {} disconnected", self.peer);
+                            return Ok(());
+                        }
+                        // keepalives only need to keep the connection alive
+                        _ => {}
+                    }
+                }
+                _ = ticker.tick() => {
+                    if last_packet.elapsed() > IDLE_TIMEOUT {
+                        info!("SRT caller at {} went idle", self.peer);
+                        break Ok(());
+                    }
+
+                    buffer.drop_late();
+                }
+            }
+        };
+
+        self.close().await;
+
+        result
+    }
+
+    async fn send_data(&self, packet: &SentPacket, retransmitted: bool) -> anyhow::Result<()> {
+        let packet = data(
+            packet.seq,
+            packet.message,
+            retransmitted,
+            packet.timestamp,
+            self.peer_socket_id,
+            &packet.payload,
+        );
+        self.socket.send_to(&packet, self.peer).await?;
+
+        Ok(())
+    }
+
+    /// Tells the caller the connection is closed, e.g. when its stream ID
+    /// is not accepted.
+    pub async fn close(&self) {
+        if let Err(e) = self.send_control(CONTROL_SHUTDOWN, 0, &[0; 4]).await {
+            debug!("Failed to close SRT connection to {}: {:?}", self.peer, e);
+        }
+    }
+
+    async fn send_control(&self, kind: u16, info: u32, cif: &[u8]) -> anyhow::Result<()> {
+        let packet = control(kind, info, self.timestamp(), self.peer_socket_id, cif);
+        self.socket.send_to(&packet, self.peer).await?;
+
+        Ok(())
+    }
+
+    fn timestamp(&self) -> u32 {
+        self.started.elapsed().as_micros() as u32
+    }
+}
+
+struct SrtSession {
+    packets: ReorderBuffer,
+    acks: u32,
+    last_packet: Instant,
+}
+
+impl SrtSession {
+    fn new(initial_seq: u32, latency: Duration) -> Self {
+        SrtSession {
+            packets: ReorderBuffer::starting_at("SRT", initial_seq as u64, latency),
+            acks: 0,
+            last_packet: Instant::now(),
+        }
+    }
+
+    fn add_data(&mut self, seq: u32, payload: &[u8]) {
+        let reference = self.packets.highest().or(self.packets.next()).unwrap_or(0);
+        let seq = extend_sequence(reference, seq & 0x7fff_ffff);
+        self.packets.insert(seq, payload);
+    }
+
+    /// The sequence number of the next packet expected, which the buffer
+    /// starts with.
+    fn next(&self) -> u64 {
+        self.packets.next().unwrap_or(0)
+    }
+
+    fn ack_number(&mut self) -> u32 {
+        self.acks = self.acks.wrapping_add(1);
+        self.acks
+    }
+
+    /// Builds a full ACK of every packet before the next one expected.
+    fn ack(&self) -> Vec<u8> {
+        let mut cif = BytesMut::with_capacity(28);
+        cif.put_u32((self.next() % SEQUENCE_MODULO) as u32);
+        // RTT and its variance in microseconds, which we don't measure
+        cif.put_u32(100_000);
+        cif.put_u32(50_000);
+        cif.put_u32(
+            BUFFER_PACKETS
+                .saturating_sub(self.packets.len() as u32)
+                .max(2),
+        );
+        // receive rate and link capacity, which are left to the sender
+        cif.put_u32(0);
+        cif.put_u32(0);
+        cif.put_u32(0);
+
+        cif.to_vec()
+    }
+
+    /// Builds a NAK of the missing packets which have not been requested
+    /// recently.
+    fn nak(&mut self) -> Option<Vec<u8>> {
+        let missing = self.packets.request_missing(MAX_NAKED_PACKETS);
+        if missing.is_empty() {
+            return None;
+        }
+
+        Some(loss_list(&missing))
+    }
+}
+
+struct SentPacket {
+    seq: u32,
+    message: u32,
+    timestamp: u32,
+    payload: Bytes,
+    sent: Instant,
+}
+
+/// The packets sent to a receiver which it has not acknowledged yet.
+struct SendBuffer {
+    latency: Duration,
+    packets: VecDeque<SentPacket>,
+    /// The sequence number of the first packet in the buffer, or of the
+    /// next one sent if it is empty.
+    first: u32,
+    next_message: u32,
+}
+
+impl SendBuffer {
+    fn new(initial_seq: u32, latency: Duration) -> Self {
+        SendBuffer {
+            latency,
+            packets: VecDeque::new(),
+            first: initial_seq,
+            next_message: 1,
+        }
+    }
+
+    fn push(&mut self, payload: Bytes, timestamp: u32) -> &SentPacket {
+        if self.packets.len() >= SEND_BUFFER_PACKETS {
+            self.pop();
+        }
+
+        let seq = self.offset_seq(self.packets.len() as u32);
+        let message = self.next_message;
+        self.next_message = (self.next_message + 1) & 0x03ff_ffff;
+
+        self.packets.push_back(SentPacket {
+            seq,
+            message,
+            timestamp,
+            payload,
+            sent: Instant::now(),
+        });
+
+        self.packets.back().unwrap()
+    }
+
+    /// Forgets every packet before `seq`, which the receiver has.
+    fn ack(&mut self, seq: u32) {
+        let acked = self.index_of(seq).unwrap_or(0);
+
+        for _ in 0..acked {
+            self.pop();
+        }
+    }
+
+    fn get(&self, seq: u32) -> Option<&SentPacket> {
+        self.packets.get(self.index_of(seq)?)
+    }
+
+    /// Forgets packets which were sent longer than the latency ago, since
+    /// the receiver drops them anyway.
+    fn drop_late(&mut self) {
+        while self
+            .packets
+            .front()
+            .is_some_and(|packet| packet.sent.elapsed() > self.latency)
+        {
+            self.pop();
+        }
+    }
+
+    fn pop(&mut self) {
+        if self.packets.pop_front().is_some() {
+            self.first = self.offset_seq(1);
+        }
+    }
+
+    /// The index of a packet in the buffer, or where the next one would
+    /// be if `seq` is the sequence number it will be sent with.
+    fn index_of(&self, seq: u32) -> Option<usize> {
+        let index = (seq as u64 + SEQUENCE_MODULO - self.first as u64) % SEQUENCE_MODULO;
+
+        if index <= self.packets.len() as u64 {
+            Some(index as usize)
+        } else {
+            None
+        }
+    }
+
+    fn offset_seq(&self, offset: u32) -> u32 {
+        ((self.first as u64 + offset as u64) % SEQUENCE_MODULO) as u32
+    }
+}
+
+/// Decodes a list of sequence numbers, where a range is its first number
+/// with the highest bit set followed by its last number.
+fn parse_loss_list(cif: &[u8]) -> Vec<u32> {
+    let mut seqs = Vec::new();
+    let mut words = cif.chunks_exact(4).map(be_u32);
+
+    while let Some(word) = words.next() {
+        if word & 0x8000_0000 == 0 {
+            seqs.push(word);
+            continue;
+        }
+
+        let first = word & 0x7fff_ffff;
+        let last = match words.next() {
+            Some(last) => last & 0x7fff_ffff,
+            None => break,
+        };
+
+        // a range longer than the send buffer can't be sent again in full
+        let len = (last as u64 + SEQUENCE_MODULO - first as u64) % SEQUENCE_MODULO;
+        for offset in 0..=len.min(SEND_BUFFER_PACKETS as u64) {
+            seqs.push(((first as u64 + offset) % SEQUENCE_MODULO) as u32);
+        }
+    }
+
+    seqs
+}
+
+/// Encodes a sorted list of sequence numbers, where a range is its first
+/// number with the highest bit set followed by its last number.
+fn loss_list(missing: &[u64]) -> Vec<u8> {
+    let mut ranges = Vec::<(u64, u64)>::new();
+
+    for &seq in missing {
+        match ranges.last_mut() {
+            Some((_, last)) if *last + 1 == seq => *last = seq,
+            _ => ranges.push((seq, seq)),
+        }
+    }
+
+    let mut cif = BytesMut::new();
+    for (first, last) in ranges {
+        let first = (first % SEQUENCE_MODULO) as u32;
+        let last = (last % SEQUENCE_MODULO) as u32;
+
+        if first == last {
+            cif.put_u32(first);
+        } else {
+            cif.put_u32(0x8000_0000 | first);
+            cif.put_u32(last);
+        }
+    }
+
+    cif.to_vec()
+}
@@ -0,0 +1,234 @@
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::packet::be_u32;
+
+pub(crate) const HANDSHAKE_SIZE: usize = 48;
+
+pub(crate) const TYPE_INDUCTION: u32 = 0x0000_0001;
+pub(crate) const TYPE_CONCLUSION: u32 = 0xffff_ffff;
+
+/// Rejection reasons are sent in place of the handshake type.
+pub(crate) const REJECT_BACKLOG: u32 = 1005;
+pub(crate) const REJECT_VERSION: u32 = 1008;
+pub(crate) const REJECT_UNSECURE: u32 = 1011;
+
+/// Sent in the extension field of an induction response, to tell callers
+/// the listener understands version 5 handshakes.
+pub(crate) const SRT_MAGIC: u16 = 0x4a17;
+
+pub(crate) const EXTENSION_HSREQ: u16 = 1;
+pub(crate) const EXTENSION_HSRSP: u16 = 2;
+pub(crate) const EXTENSION_KMREQ: u16 = 3;
+pub(crate) const EXTENSION_SID: u16 = 5;
+
+/// The extension field flag of a handshake with an HSREQ or HSRSP
+/// extension.
+pub(crate) const FLAG_HANDSHAKE_EXTENSION: u16 = 0x1;
+
+pub(crate) const SRT_FLAG_TSBPD_SND: u32 = 0x01;
+pub(crate) const SRT_FLAG_TSBPD_RCV: u32 = 0x02;
+pub(crate) const SRT_FLAG_TLPKTDROP: u32 = 0x08;
+pub(crate) const SRT_FLAG_PERIODIC_NAK: u32 = 0x10;
+pub(crate) const SRT_FLAG_REXMIT: u32 = 0x20;
+
+/// The SRT version we claim to implement, 1.5.0.
+pub(crate) const SRT_VERSION: u32 = 0x0001_0500;
+
+/// The contents of a handshake control packet.
+#[derive(Debug, Clone)]
+pub(crate) struct Handshake {
+    pub(crate) version: u32,
+    pub(crate) encryption: u16,
+    pub(crate) extension: u16,
+    pub(crate) initial_seq: u32,
+    pub(crate) mtu: u32,
+    pub(crate) flow_window: u32,
+    pub(crate) kind: u32,
+    pub(crate) socket_id: u32,
+    pub(crate) cookie: u32,
+    pub(crate) peer_ip: [u8; 16],
+    pub(crate) extensions: Vec<(u16, Bytes)>,
+}
+
+impl Handshake {
+    pub(crate) fn parse(cif: &[u8]) -> Option<Self> {
+        if cif.len() < HANDSHAKE_SIZE {
+            return None;
+        }
+
+        let word = |i: usize| be_u32(&cif[i * 4..]);
+
+        let mut peer_ip = [0; 16];
+        peer_ip.copy_from_slice(&cif[32..48]);
+
+        // each extension is a type, a length in 32-bit words and contents
+        let mut extensions = Vec::new();
+        let mut rest = &cif[HANDSHAKE_SIZE..];
+        while rest.len() >= 4 {
+            let kind = u16::from_be_bytes([rest[0], rest[1]]);
+            let len = u16::from_be_bytes([rest[2], rest[3]]) as usize * 4;
+            let content = rest.get(4..4 + len)?;
+
+            extensions.push((kind, Bytes::copy_from_slice(content)));
+            rest = &rest[4 + len..];
+        }
+
+        Some(Handshake {
+            version: word(0),
+            encryption: (word(1) >> 16) as u16,
+            extension: word(1) as u16,
+            initial_seq: word(2) & 0x7fff_ffff,
+            mtu: word(3),
+            flow_window: word(4),
+            kind: word(5),
+            socket_id: word(6),
+            cookie: word(7),
+            peer_ip,
+            extensions,
+        })
+    }
+
+    pub(crate) fn to_bytes(&self) -> Bytes {
+        let mut cif = BytesMut::with_capacity(HANDSHAKE_SIZE);
+        cif.put_u32(self.version);
+        cif.put_u16(self.encryption);
+        cif.put_u16(self.extension);
+        cif.put_u32(self.initial_seq);
+        cif.put_u32(self.mtu);
+        cif.put_u32(self.flow_window);
+        cif.put_u32(self.kind);
+        cif.put_u32(self.socket_id);
+        cif.put_u32(self.cookie);
+        cif.put_slice(&self.peer_ip);
+
+        for (kind, content) in &self.extensions {
+            cif.put_u16(*kind);
+            cif.put_u16((content.len() / 4) as u16);
+            cif.put_slice(content);
+        }
+
+        cif.freeze()
+    }
+
+    pub(crate) fn extension(&self, kind: u16) -> Option<&[u8]> {
+        self.extensions
+            .iter()
+            .find(|(k, _)| *k == kind)
+            .map(|(_, content)| &content[..])
+    }
+
+    /// The TSBPD delay a caller asks for in its HSREQ extension, or a
+    /// listener agrees to in its HSRSP extension, in milliseconds, whether
+    /// it sends or receives.
+    pub(crate) fn delay_ms(&self) -> Option<u16> {
+        let hsreq = self
+            .extension(EXTENSION_HSREQ)
+            .or_else(|| self.extension(EXTENSION_HSRSP))?;
+
+        // the receiver delay is in the upper half, the sender delay in the
+        // lower half
+        let delays = be_u32(hsreq.get(8..12)?);
+        Some(((delays >> 16) as u16).max(delays as u16))
+    }
+
+    /// The stream ID of a caller, which libsrt sends as 32-bit words in
+    /// little endian order.
+    pub(crate) fn stream_id(&self) -> Option<String> {
+        let sid = self.extension(EXTENSION_SID)?;
+
+        let bytes = sid
+            .chunks(4)
+            .flat_map(|word| word.iter().rev().copied())
+            .take_while(|b| *b != 0)
+            .collect::<Vec<_>>();
+
+        String::from_utf8(bytes).ok()
+    }
+}
+
+/// Builds the HSREQ extension of a caller which asks for a TSBPD delay of
+/// `delay_ms`, which has the same contents as an HSRSP.
+pub(crate) fn hsreq(delay_ms: u16) -> Bytes {
+    hsrsp(delay_ms)
+}
+
+/// Builds the HSRSP extension which accepts a caller with a TSBPD delay of
+/// `delay_ms`, in whichever direction it sends.
+pub(crate) fn hsrsp(delay_ms: u16) -> Bytes {
+    let mut content = BytesMut::with_capacity(12);
+    content.put_u32(SRT_VERSION);
+    content.put_u32(
+        SRT_FLAG_TSBPD_SND
+            | SRT_FLAG_TSBPD_RCV
+            | SRT_FLAG_TLPKTDROP
+            | SRT_FLAG_PERIODIC_NAK
+            | SRT_FLAG_REXMIT,
+    );
+    content.put_u16(delay_ms);
+    content.put_u16(delay_ms);
+
+    content.freeze()
+}
+
+#[cfg(test)]
+fn test_handshake() -> Handshake {
+    Handshake {
+        version: 5,
+        encryption: 0,
+        extension: FLAG_HANDSHAKE_EXTENSION,
+        initial_seq: 1000,
+        mtu: 1500,
+        flow_window: 8192,
+        kind: TYPE_CONCLUSION,
+        socket_id: 42,
+        cookie: 7,
+        peer_ip: [0; 16],
+        extensions: Vec::new(),
+    }
+}
+
+#[test]
+fn parses_extensions() {
+    let mut handshake = test_handshake();
+    handshake.extensions = vec![
+        (EXTENSION_HSREQ, hsreq(120)),
+        // "live/a" as libsrt sends it
+        (EXTENSION_SID, Bytes::from_static(b"evil\0\0a/")),
+    ];
+
+    let parsed = Handshake::parse(&handshake.to_bytes()).unwrap();
+    assert_eq!(parsed.socket_id, 42);
+    assert_eq!(parsed.kind, TYPE_CONCLUSION);
+    assert_eq!(parsed.delay_ms(), Some(120));
+    assert_eq!(parsed.stream_id().as_deref(), Some("live/a"));
+}
+
+#[test]
+fn truncated_handshakes_are_rejected() {
+    let bytes = test_handshake().to_bytes();
+    assert!(Handshake::parse(&bytes[..HANDSHAKE_SIZE - 1]).is_none());
+
+    // an extension claiming more words than there are
+    let mut handshake = test_handshake();
+    handshake.extensions = vec![(EXTENSION_HSREQ, hsreq(120))];
+    let bytes = handshake.to_bytes();
+    assert!(Handshake::parse(&bytes[..bytes.len() - 4]).is_none());
+
+    // bytes after the last extension which can't be one are ignored
+    let mut padded = bytes.to_vec();
+    padded.extend_from_slice(&[0; 3]);
+    assert_eq!(Handshake::parse(&padded).unwrap().extensions.len(), 1);
+}
+
+#[test]
+fn short_extensions_have_no_values() {
+    let mut handshake = test_handshake();
+    handshake.extensions = vec![
+        (EXTENSION_HSREQ, Bytes::from_static(&[0; 8])),
+        (EXTENSION_SID, Bytes::from_static(&[0x00, 0x00, 0xfe, 0xff])),
+    ];
+
+    let parsed = Handshake::parse(&handshake.to_bytes()).unwrap();
+    assert_eq!(parsed.delay_ms(), None);
+    assert_eq!(parsed.stream_id(), None);
+}
@@ -0,0 +1,280 @@
+use bytes::Bytes;
+use tokio::{
+    net::UdpSocket,
+    time::{Duration, Instant},
+};
+use tracing::*;
+
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hash, Hasher},
+    net::SocketAddr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+mod caller;
+mod connection;
+mod handshake;
+mod packet;
+
+pub use caller::*;
+pub use connection::*;
+
+use handshake::*;
+use packet::*;
+
+/// The most connections waiting to be accepted.
+const BACKLOG: usize = 16;
+
+/// The most packets queued for a connection before new ones are dropped,
+/// to be requested again once the connection catches up.
+const CONNECTION_QUEUE: usize = 4096;
+
+/// A listener for SRT callers sending MPEG-TS, such as OBS and ffmpeg, or
+/// receiving it, such as production tools pulling a feed.
+///
+/// Every connection shares the UDP socket of the listener, and packets are
+/// routed to connections by their destination socket ID. Encryption is not
+/// supported, so callers with a passphrase are rejected.
+pub struct SrtListener {
+    addr: SocketAddr,
+    incoming: async_channel::Receiver<SrtConnection>,
+}
+
+impl SrtListener {
+    /// Binds a listener which buffers at least `latency` of packets to
+    /// recover lost ones, or more if a caller asks for it.
+    pub async fn bind(addr: SocketAddr, latency: Duration) -> anyhow::Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let (tx, incoming) = async_channel::bounded(BACKLOG);
+
+        let mut demuxer = Demuxer::new(socket, latency, tx);
+        tokio::spawn(async move {
+            if let Err(e) = demuxer.run().await {
+                error!("SRT listener at {} failed: {:?}", addr, e);
+            }
+        });
+
+        Ok(SrtListener { addr, incoming })
+    }
+
+    /// Waits for a caller to complete its handshake.
+    pub async fn accept(&self) -> anyhow::Result<SrtConnection> {
+        self.incoming
+            .recv()
+            .await
+            .map_err(|_| anyhow::anyhow!("SRT listener at {} stopped", self.addr))
+    }
+}
+
+/// Answers handshakes and routes the packets of every connection to it.
+struct Demuxer {
+    socket: Arc<UdpSocket>,
+    latency: Duration,
+    incoming: async_channel::Sender<SrtConnection>,
+    secret: RandomState,
+    next_socket_id: u32,
+    connections: HashMap<u32, async_channel::Sender<Bytes>>,
+    /// The conclusion responses sent to callers by their address and
+    /// socket ID, which are sent again if a caller repeats its conclusion.
+    accepted: HashMap<(SocketAddr, u32), (u32, Bytes)>,
+}
+
+impl Demuxer {
+    fn new(
+        socket: Arc<UdpSocket>,
+        latency: Duration,
+        incoming: async_channel::Sender<SrtConnection>,
+    ) -> Self {
+        let secret = RandomState::new();
+        let next_socket_id = secret.build_hasher().finish() as u32 | 1;
+
+        Demuxer {
+            socket,
+            latency,
+            incoming,
+            secret,
+            next_socket_id,
+            connections: HashMap::new(),
+            accepted: HashMap::new(),
+        }
+    }
+
+    async fn run(&mut self) -> anyhow::Result<()> {
+        let mut buf = vec![0; 2048];
+
+        loop {
+            let (len, addr) = self.socket.recv_from(&mut buf).await?;
+            let datagram = &buf[..len];
+
+            let dest = match Packet::parse(datagram) {
+                Some(Packet::Control {
+                    kind: CONTROL_HANDSHAKE,
+                    cif,
+                    ..
+                }) => {
+                    match Handshake::parse(cif) {
+                        Some(handshake) => self.handshake(handshake, addr).await?,
+                        None => debug!("Skipping a malformed SRT handshake from {}", addr),
+                    }
+                    continue;
+                }
+                Some(Packet::Data { dest, .. }) | Some(Packet::Control { dest, .. }) => dest,
+                None => {
+                    debug!("Skipping a malformed SRT packet from {}", addr);
+                    continue;
+                }
+            };
+
+            let closed = match self.connections.get(&dest) {
+                Some(connection) => match connection.try_send(Bytes::copy_from_slice(datagram)) {
+                    Err(async_channel::TrySendError::Closed(_)) => true,
+                    // lost packets are requested again
+                    Err(async_channel::TrySendError::Full(_)) | Ok(()) => false,
+                },
+                None => false,
+            };
+
+            if closed {
+                self.connections.remove(&dest);
+                self.accepted.retain(|_, (socket_id, _)| *socket_id != dest);
+            }
+        }
+    }
+
+    async fn handshake(&mut self, request: Handshake, addr: SocketAddr) -> anyhow::Result<()> {
+        let response = match request.kind {
+            TYPE_INDUCTION => Handshake {
+                version: 5,
+                encryption: 0,
+                extension: SRT_MAGIC,
+                kind: TYPE_INDUCTION,
+                socket_id: 0,
+                cookie: self.cookie(addr, 0),
+                extensions: Vec::new(),
+                ..request.clone()
+            },
+            TYPE_CONCLUSION => {
+                if let Some((_, response)) = self.accepted.get(&(addr, request.socket_id)) {
+                    self.socket.send_to(response, addr).await?;
+                    return Ok(());
+                }
+
+                if request.cookie != self.cookie(addr, 0) && request.cookie != self.cookie(addr, 1)
+                {
+                    debug!(
+                        "Ignoring an SRT conclusion with a stale cookie from {}",
+                        addr
+                    );
+                    return Ok(());
+                }
+
+                match self.conclude(&request, addr) {
+                    Ok(response) => response,
+                    Err(reason) => Handshake {
+                        kind: reason,
+                        extensions: Vec::new(),
+                        ..request.clone()
+                    },
+                }
+            }
+            kind => {
+                debug!(
+                    "Ignoring an SRT handshake of type {:#x} from {}",
+                    kind, addr
+                );
+                return Ok(());
+            }
+        };
+
+        let packet = control(
+            CONTROL_HANDSHAKE,
+            0,
+            0,
+            request.socket_id,
+            &response.to_bytes(),
+        );
+
+        if request.kind == TYPE_CONCLUSION && response.kind == TYPE_CONCLUSION {
+            self.accepted.insert(
+                (addr, request.socket_id),
+                (response.socket_id, packet.clone()),
+            );
+        }
+
+        self.socket.send_to(&packet, addr).await?;
+
+        Ok(())
+    }
+
+    /// Accepts a caller, returning the conclusion response or the reason
+    /// it was rejected.
+    fn conclude(&mut self, request: &Handshake, addr: SocketAddr) -> Result<Handshake, u32> {
+        if request.version < 5 {
+            warn!("Rejecting an SRT caller at {} with an old version", addr);
+            return Err(REJECT_VERSION);
+        }
+
+        if request.encryption != 0 || request.extension(EXTENSION_KMREQ).is_some() {
+            warn!("Rejecting an encrypted SRT caller at {}", addr);
+            return Err(REJECT_UNSECURE);
+        }
+
+        let latency = request
+            .delay_ms()
+            .map(|ms| Duration::from_millis(ms as u64))
+            .unwrap_or_default()
+            .max(self.latency);
+
+        let socket_id = self.next_socket_id;
+        self.next_socket_id = self.next_socket_id.wrapping_add(1).max(1);
+
+        let (tx, rx) = async_channel::bounded(CONNECTION_QUEUE);
+        let connection = SrtConnection {
+            socket: Arc::clone(&self.socket),
+            peer: addr,
+            stream_id: request.stream_id(),
+            peer_socket_id: request.socket_id,
+            initial_seq: request.initial_seq,
+            latency,
+            packets: rx,
+            started: Instant::now(),
+        };
+
+        if self.incoming.try_send(connection).is_err() {
+            warn!("Rejecting an SRT caller at {}, too many are waiting", addr);
+            return Err(REJECT_BACKLOG);
+        }
+
+        self.connections.insert(socket_id, tx);
+
+        Ok(Handshake {
+            version: 5,
+            encryption: 0,
+            extension: FLAG_HANDSHAKE_EXTENSION,
+            kind: TYPE_CONCLUSION,
+            extensions: vec![(
+                EXTENSION_HSRSP,
+                hsrsp(latency.as_millis().min(u16::MAX as u128) as u16),
+            )],
+            ..request.clone()
+        })
+    }
+
+    /// A cookie for a caller which is only valid for a minute or two, so
+    /// callers have to go through the induction before concluding.
+    fn cookie(&self, addr: SocketAddr, minutes_ago: u64) -> u32 {
+        let minute = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            / 60;
+
+        let mut hasher = self.secret.build_hasher();
+        addr.hash(&mut hasher);
+        (minute - minutes_ago).hash(&mut hasher);
+
+        hasher.finish() as u32
+    }
+}
@@ -0,0 +1,178 @@
+use bytes::{BufMut, Bytes, BytesMut};
+
+pub(crate) const HEADER_SIZE: usize = 16;
+
+pub(crate) const CONTROL_HANDSHAKE: u16 = 0x0000;
+pub(crate) const CONTROL_ACK: u16 = 0x0002;
+pub(crate) const CONTROL_NAK: u16 = 0x0003;
+pub(crate) const CONTROL_SHUTDOWN: u16 = 0x0005;
+pub(crate) const CONTROL_ACKACK: u16 = 0x0006;
+
+/// Sequence numbers are 31 bits and wrap around.
+pub(crate) const SEQUENCE_MODULO: u64 = 1 << 31;
+
+/// An SRT packet, borrowing from the datagram it was received in.
+pub(crate) enum Packet<'a> {
+    Data {
+        seq: u32,
+        /// Whether the payload is encrypted, with either the even or odd
+        /// key.
+        encrypted: bool,
+        dest: u32,
+        payload: &'a [u8],
+    },
+    Control {
+        kind: u16,
+        /// The type-specific information, e.g. the number of an ACK.
+        info: u32,
+        dest: u32,
+        cif: &'a [u8],
+    },
+}
+
+impl<'a> Packet<'a> {
+    pub(crate) fn parse(datagram: &'a [u8]) -> Option<Self> {
+        if datagram.len() < HEADER_SIZE {
+            return None;
+        }
+
+        let word = |i: usize| be_u32(&datagram[i * 4..]);
+        let dest = word(3);
+
+        if word(0) & 0x8000_0000 == 0 {
+            Some(Packet::Data {
+                seq: word(0),
+                // the KK bits of the message number field
+                encrypted: (word(1) >> 27) & 0b11 != 0,
+                dest,
+                payload: &datagram[HEADER_SIZE..],
+            })
+        } else {
+            Some(Packet::Control {
+                kind: ((word(0) >> 16) & 0x7fff) as u16,
+                info: word(1),
+                dest,
+                cif: &datagram[HEADER_SIZE..],
+            })
+        }
+    }
+}
+
+/// Builds a control packet.
+pub(crate) fn control(kind: u16, info: u32, timestamp: u32, dest: u32, cif: &[u8]) -> Bytes {
+    let mut packet = BytesMut::with_capacity(HEADER_SIZE + cif.len());
+    packet.put_u32(0x8000_0000 | ((kind as u32) << 16));
+    packet.put_u32(info);
+    packet.put_u32(timestamp);
+    packet.put_u32(dest);
+    packet.put_slice(cif);
+
+    packet.freeze()
+}
+
+/// Builds a data packet carrying a whole message, which is how live mode
+/// sends every payload.
+pub(crate) fn data(
+    seq: u32,
+    message: u32,
+    retransmitted: bool,
+    timestamp: u32,
+    dest: u32,
+    payload: &[u8],
+) -> Bytes {
+    let mut packet = BytesMut::with_capacity(HEADER_SIZE + payload.len());
+    packet.put_u32(seq & 0x7fff_ffff);
+    // the first and last packet of its message, in no particular order
+    // and unencrypted
+    packet.put_u32(0xc000_0000 | ((retransmitted as u32) << 26) | (message & 0x03ff_ffff));
+    packet.put_u32(timestamp);
+    packet.put_u32(dest);
+    packet.put_slice(payload);
+
+    packet.freeze()
+}
+
+/// Extends a 31-bit sequence number to the 64-bit sequence number closest
+/// to `reference`.
+pub(crate) fn extend_sequence(reference: u64, seq: u32) -> u64 {
+    let half = SEQUENCE_MODULO / 2;
+    let candidate = (reference & !(SEQUENCE_MODULO - 1)) | seq as u64;
+
+    if candidate + half < reference {
+        candidate + SEQUENCE_MODULO
+    } else if candidate > reference + half && candidate >= SEQUENCE_MODULO {
+        candidate - SEQUENCE_MODULO
+    } else {
+        candidate
+    }
+}
+
+pub(crate) fn be_u32(b: &[u8]) -> u32 {
+    u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+}
+
+#[test]
+fn short_datagrams_are_rejected() {
+    assert!(Packet::parse(&[]).is_none());
+    assert!(Packet::parse(&[0x80; HEADER_SIZE - 1]).is_none());
+    assert!(Packet::parse(&[0x80; HEADER_SIZE]).is_some());
+}
+
+#[test]
+fn parses_data_and_control_packets() {
+    let packet = data(0x8000_0005, 7, true, 1000, 42, b"payload");
+    match Packet::parse(&packet) {
+        Some(Packet::Data {
+            seq,
+            encrypted,
+            dest,
+            payload,
+        }) => {
+            // the highest bit marks control packets, so it isn't sent
+            assert_eq!(seq, 5);
+            assert!(!encrypted);
+            assert_eq!(dest, 42);
+            assert_eq!(payload, b"payload");
+        }
+        _ => panic!("not a data packet"),
+    }
+
+    let mut encrypted = packet.to_vec();
+    encrypted[4] |= 0x10;
+    assert!(matches!(
+        Packet::parse(&encrypted),
+        Some(Packet::Data {
+            encrypted: true,
+            ..
+        })
+    ));
+
+    let packet = control(CONTROL_ACK, 3, 1000, 42, &[]);
+    match Packet::parse(&packet) {
+        Some(Packet::Control {
+            kind,
+            info,
+            dest,
+            cif,
+        }) => {
+            assert_eq!(kind, CONTROL_ACK);
+            assert_eq!(info, 3);
+            assert_eq!(dest, 42);
+            assert!(cif.is_empty());
+        }
+        _ => panic!("not a control packet"),
+    }
+}
+
+#[test]
+fn extends_sequence_numbers_across_the_wrap() {
+    let end = SEQUENCE_MODULO - 10;
+
+    assert_eq!(extend_sequence(end, 5), SEQUENCE_MODULO + 5);
+    assert_eq!(
+        extend_sequence(SEQUENCE_MODULO + 5, (end + 2) as u32),
+        end + 2
+    );
+    assert_eq!(extend_sequence(5, (end + 2) as u32), end + 2);
+    assert_eq!(extend_sequence(100, 90), 90);
+}
@@ -0,0 +1,107 @@
+use bytes::BufMut;
+use sh_media::{
+    AudioCodecInfo, AudioCodecSpecificInfo, ByteWriteFilter2, CodecTypeInfo, Frame,
+    FrameWriteFilter, Muxer, Stream,
+};
+use tracing::*;
+
+/// Puts an ADTS header in front of a raw AAC frame, built from the
+/// AudioSpecificConfig of its stream.
+pub(crate) fn adts_frame(stream: &Stream, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let config = match &stream.codec.properties {
+        CodecTypeInfo::Audio(AudioCodecInfo {
+            extra: AudioCodecSpecificInfo::Aac { extra },
+            ..
+        }) => extra,
+        CodecTypeInfo::Audio(_) => anyhow::bail!("Only AAC can be written as ADTS"),
+        _ => anyhow::bail!("Not an audio stream"),
+    };
+
+    if config.len() < 2 {
+        anyhow::bail!("AAC stream without an AudioSpecificConfig");
+    }
+
+    let object_type = config[0] >> 3;
+    let frequency_index = ((config[0] & 0x07) << 1) | (config[1] >> 7);
+    let channels = (config[1] >> 3) & 0x0f;
+
+    let len = 7 + data.len();
+    if len > 0x1fff {
+        anyhow::bail!("AAC frame of {} bytes is too long for ADTS", data.len());
+    }
+
+    let mut frame = Vec::with_capacity(len);
+    // MPEG-4, without a CRC
+    frame.put_u16(0xfff1);
+    frame.put_u8((object_type.saturating_sub(1) << 6) | (frequency_index << 2) | (channels >> 2));
+    frame.put_u8(((channels & 0x03) << 6) | (len >> 11) as u8);
+    frame.put_u8((len >> 3) as u8);
+    // the low bits of the length, and a variable bitrate buffer fullness
+    frame.put_u8(((len as u8 & 0x07) << 5) | 0x1f);
+    frame.put_u8(0xfc);
+    frame.put_slice(data);
+
+    Ok(frame)
+}
+
+/// A push filter which writes the frames of the first AAC stream as ADTS,
+/// like the streams of web radios. Other streams are skipped.
+pub struct AdtsWriteFilter {
+    target: Box<dyn ByteWriteFilter2 + Send + Unpin>,
+    audio: Option<u32>,
+}
+
+impl AdtsWriteFilter {
+    pub fn new(target: Box<dyn ByteWriteFilter2 + Send + Unpin>) -> Self {
+        AdtsWriteFilter {
+            target,
+            audio: None,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl FrameWriteFilter for AdtsWriteFilter {
+    async fn start(&mut self, streams: Vec<Stream>) -> anyhow::Result<()> {
+        let audio = streams
+            .iter()
+            .find(|s| s.is_aac())
+            .ok_or_else(|| anyhow::anyhow!("ADTS output needs an AAC stream"))?;
+
+        debug!("Writing {:?} as ADTS", audio);
+
+        self.audio = Some(audio.id);
+        self.target.start().await
+    }
+
+    async fn write(&mut self, frame: Frame) -> anyhow::Result<()> {
+        if self.audio != Some(frame.stream.id) {
+            return Ok(());
+        }
+
+        let data = adts_frame(&frame.stream, &frame.buffer)?;
+
+        self.target.write(data.into()).await
+    }
+}
+
+/// A [`Muxer`] for raw AAC in ADTS, see [`AdtsWriteFilter`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AdtsMuxer;
+
+impl Muxer for AdtsMuxer {
+    fn content_type(&self) -> &'static str {
+        "audio/aac"
+    }
+
+    fn extension(&self) -> &'static str {
+        "aac"
+    }
+
+    fn mux(
+        &self,
+        output: Box<dyn ByteWriteFilter2 + Send + Unpin>,
+    ) -> Box<dyn FrameWriteFilter + Send + Unpin> {
+        Box::new(AdtsWriteFilter::new(output))
+    }
+}
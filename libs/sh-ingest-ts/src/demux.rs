@@ -0,0 +1,682 @@
+use bytes::{Bytes, BytesMut};
+use tracing::*;
+
+use std::{collections::HashMap, str::FromStr};
+
+/// The size of a single MPEG-TS packet.
+pub const TS_PACKET_SIZE: usize = 188;
+
+pub const STREAM_TYPE_AAC_ADTS: u8 = 0x0f;
+pub const STREAM_TYPE_H264: u8 = 0x1b;
+/// PES packets with private data, identified by their descriptors.
+pub const STREAM_TYPE_PRIVATE_PES: u8 = 0x06;
+
+pub const DESCRIPTOR_TELETEXT: u8 = 0x56;
+pub const DESCRIPTOR_DVB_SUBTITLING: u8 = 0x59;
+
+pub(crate) const SYNC_BYTE: u8 = 0x47;
+const PAT_PID: u16 = 0x0000;
+
+/// The longest PAT or PMT section, from its table id to the end of its CRC.
+const MAX_PSI_SECTION_SIZE: usize = 1024;
+
+/// Selects which program of a multi-program transport stream is demuxed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProgramSelector {
+    /// The first program listed in the program association table.
+    #[default]
+    First,
+    /// The program with the given program number, also known as the
+    /// service ID.
+    ServiceId(u16),
+    /// The program whose map table is carried on the given PID.
+    PmtPid(u16),
+}
+
+impl FromStr for ProgramSelector {
+    type Err = std::num::ParseIntError;
+
+    /// Parses `first`, a service ID such as `101` or a PMT PID such as
+    /// `pid:0x100`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn parse_number(s: &str) -> Result<u16, std::num::ParseIntError> {
+            match s.strip_prefix("0x") {
+                Some(hex) => u16::from_str_radix(hex, 16),
+                None => s.parse(),
+            }
+        }
+
+        if s.is_empty() || s == "first" {
+            Ok(ProgramSelector::First)
+        } else if let Some(pid) = s.strip_prefix("pid:") {
+            Ok(ProgramSelector::PmtPid(parse_number(pid)?))
+        } else {
+            Ok(ProgramSelector::ServiceId(parse_number(s)?))
+        }
+    }
+}
+
+/// A program announced in the program association table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    pub number: u16,
+    pub pmt_pid: u16,
+}
+
+/// An elementary stream announced in the program map table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElementaryStream {
+    pub pid: u16,
+    pub stream_type: u8,
+    /// The tags of the descriptors in the ES info loop.
+    pub descriptor_tags: Vec<u8>,
+}
+
+impl ElementaryStream {
+    pub fn is_teletext(&self) -> bool {
+        self.stream_type == STREAM_TYPE_PRIVATE_PES
+            && self.descriptor_tags.contains(&DESCRIPTOR_TELETEXT)
+    }
+
+    pub fn is_dvb_subtitle(&self) -> bool {
+        self.stream_type == STREAM_TYPE_PRIVATE_PES
+            && self.descriptor_tags.contains(&DESCRIPTOR_DVB_SUBTITLING)
+    }
+}
+
+/// A complete PES packet of an elementary stream, with timestamps in
+/// 90 kHz units.
+#[derive(Debug, Clone)]
+pub struct PesPacket {
+    pub pid: u16,
+    pub stream_type: u8,
+    pub pts: Option<u64>,
+    pub dts: Option<u64>,
+    pub data: Bytes,
+}
+
+/// A demuxer which extracts the PES packets of a single program in an
+/// MPEG transport stream.
+#[derive(Default)]
+pub struct TsDemuxer {
+    program: ProgramSelector,
+    programs: Vec<Program>,
+    pmt_pid: Option<u16>,
+    streams: Vec<ElementaryStream>,
+    pes: HashMap<u16, BytesMut>,
+    /// PSI sections which span several packets, by PID.
+    sections: HashMap<u16, BytesMut>,
+    continuity: HashMap<u16, u8>,
+}
+
+impl TsDemuxer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a demuxer for the selected program.
+    pub fn with_program(program: ProgramSelector) -> Self {
+        TsDemuxer {
+            program,
+            ..Self::default()
+        }
+    }
+
+    /// Every program in the transport stream, empty until the program
+    /// association table has been received.
+    pub fn programs(&self) -> &[Program] {
+        &self.programs
+    }
+
+    /// The elementary streams of the program, empty until the program map
+    /// table has been received.
+    pub fn streams(&self) -> &[ElementaryStream] {
+        &self.streams
+    }
+
+    /// Demuxes a buffer of whole TS packets, returning the PES packets
+    /// which were completed by it.
+    pub fn push(&mut self, buffer: &[u8]) -> Vec<PesPacket> {
+        let mut packets = Vec::new();
+
+        for packet in buffer.chunks(TS_PACKET_SIZE) {
+            if packet.len() != TS_PACKET_SIZE || packet[0] != SYNC_BYTE {
+                debug!("Skipping a malformed TS packet");
+                continue;
+            }
+
+            self.push_packet(packet, &mut packets);
+        }
+
+        packets
+    }
+
+    /// Returns the PES packets which are still being assembled, e.g. when
+    /// the input ends.
+    pub fn flush(&mut self) -> Vec<PesPacket> {
+        let pes = std::mem::take(&mut self.pes);
+
+        pes.into_iter()
+            .filter_map(|(pid, buffer)| self.parse_pes(pid, buffer))
+            .collect()
+    }
+
+    fn push_packet(&mut self, packet: &[u8], packets: &mut Vec<PesPacket>) {
+        let payload_start = packet[1] & 0x40 != 0;
+        let pid = ((packet[1] as u16 & 0x1f) << 8) | packet[2] as u16;
+        let adaptation_field = (packet[3] >> 4) & 0x03;
+        let counter = packet[3] & 0x0f;
+
+        let mut offset = 4;
+        if adaptation_field & 0x02 != 0 {
+            offset += 1 + packet[4] as usize;
+        }
+        if adaptation_field & 0x01 == 0 || offset >= TS_PACKET_SIZE {
+            return;
+        }
+
+        let payload = &packet[offset..];
+
+        if pid == PAT_PID || Some(pid) == self.pmt_pid {
+            let lost = self.lost_packets(pid, counter);
+
+            for section in self.push_psi(pid, payload_start, lost, payload) {
+                if pid == PAT_PID {
+                    self.parse_pat(&section);
+                } else {
+                    self.parse_pmt(&section);
+                }
+            }
+            return;
+        }
+
+        if !self.streams.iter().any(|s| s.pid == pid) {
+            return;
+        }
+
+        let lost = self.lost_packets(pid, counter);
+
+        if payload_start {
+            if let Some(buffer) = self.pes.remove(&pid) {
+                packets.extend(self.parse_pes(pid, buffer));
+            }
+
+            self.pes.insert(pid, BytesMut::from(payload));
+        } else if lost {
+            warn!("Lost TS packets on PID {}, dropping PES packet", pid);
+            self.pes.remove(&pid);
+        } else if let Some(buffer) = self.pes.get_mut(&pid) {
+            buffer.extend_from_slice(payload);
+        }
+
+        // emit packets with a known length as soon as they are complete
+        // instead of waiting for the next one
+        let complete = self
+            .pes
+            .get(&pid)
+            .and_then(|buffer| pes_length(buffer).map(|len| buffer.len() >= len))
+            .unwrap_or(false);
+
+        if complete {
+            if let Some(buffer) = self.pes.remove(&pid) {
+                packets.extend(self.parse_pes(pid, buffer));
+            }
+        }
+    }
+
+    /// Checks the continuity counter of a packet on the PID.
+    fn lost_packets(&mut self, pid: u16, counter: u8) -> bool {
+        let previous = self.continuity.insert(pid, counter);
+
+        previous.is_some_and(|p| (p + 1) & 0x0f != counter)
+    }
+
+    /// Adds the payload of a packet to the PSI sections on the PID,
+    /// returning the sections which were completed by it.
+    fn push_psi(
+        &mut self,
+        pid: u16,
+        payload_start: bool,
+        lost: bool,
+        payload: &[u8],
+    ) -> Vec<Bytes> {
+        if lost && self.sections.remove(&pid).is_some() {
+            warn!("Lost TS packets on PID {}, dropping PSI section", pid);
+        }
+
+        let mut sections = Vec::new();
+
+        if payload_start {
+            // the pointer field gives the length of the end of the previous
+            // section, which comes before the new one
+            let start = 1 + payload[0] as usize;
+            if start > payload.len() {
+                debug!(
+                    "Skipping a PSI packet with an invalid pointer on PID {}",
+                    pid
+                );
+                self.sections.remove(&pid);
+                return sections;
+            }
+
+            if let Some(buffer) = self.sections.get_mut(&pid) {
+                buffer.extend_from_slice(&payload[1..start]);
+                sections.extend(self.complete_sections(pid));
+            }
+
+            self.sections.insert(pid, BytesMut::from(&payload[start..]));
+        } else if let Some(buffer) = self.sections.get_mut(&pid) {
+            buffer.extend_from_slice(payload);
+        }
+
+        sections.extend(self.complete_sections(pid));
+        sections
+    }
+
+    /// Splits the complete sections off the buffer of the PID. The buffer
+    /// is dropped once no section is left in progress, as only a packet
+    /// with a pointer field can start a new one.
+    fn complete_sections(&mut self, pid: u16) -> Vec<Bytes> {
+        let mut sections = Vec::new();
+
+        let buffer = match self.sections.get_mut(&pid) {
+            Some(buffer) => buffer,
+            None => return sections,
+        };
+
+        // stuffing bytes end the sections of a packet
+        while buffer.len() >= 3 && buffer[0] != 0xff {
+            let length = 3 + (((buffer[1] as usize & 0x0f) << 8) | buffer[2] as usize);
+            if length > MAX_PSI_SECTION_SIZE {
+                debug!("Dropping a PSI section of {} bytes on PID {}", length, pid);
+                buffer.clear();
+                break;
+            }
+
+            if buffer.len() < length {
+                return sections;
+            }
+
+            sections.push(buffer.split_to(length).freeze());
+        }
+
+        if buffer.is_empty() || buffer[0] == 0xff {
+            self.sections.remove(&pid);
+        }
+
+        sections
+    }
+
+    fn parse_pat(&mut self, section: &[u8]) {
+        let section = match psi_table(section, 0x00) {
+            Some(section) => section,
+            None => return,
+        };
+
+        // program number 0 refers to the network information table
+        self.programs = section[8..]
+            .chunks_exact(4)
+            .map(|p| Program {
+                number: u16::from_be_bytes([p[0], p[1]]),
+                pmt_pid: ((p[2] as u16 & 0x1f) << 8) | p[3] as u16,
+            })
+            .filter(|p| p.number != 0)
+            .collect();
+
+        let pmt_pid = self
+            .programs
+            .iter()
+            .find(|p| match self.program {
+                ProgramSelector::First => true,
+                ProgramSelector::ServiceId(number) => p.number == number,
+                ProgramSelector::PmtPid(pid) => p.pmt_pid == pid,
+            })
+            .map(|p| p.pmt_pid);
+
+        if pmt_pid.is_none() && self.pmt_pid.is_none() {
+            debug!(
+                "Selected program {:?} is not in {:?}",
+                self.program, self.programs
+            );
+        }
+
+        if pmt_pid.is_some() && pmt_pid != self.pmt_pid {
+            debug!("Found PMT at PID {:?}", pmt_pid);
+            self.pmt_pid = pmt_pid;
+        }
+    }
+
+    fn parse_pmt(&mut self, section: &[u8]) {
+        let section = match psi_table(section, 0x02) {
+            Some(section) => section,
+            None => return,
+        };
+
+        if section.len() < 12 {
+            return;
+        }
+
+        let program_info_length = ((section[10] as usize & 0x0f) << 8) | section[11] as usize;
+
+        let mut streams = Vec::new();
+        let mut i = 12 + program_info_length;
+        while i + 5 <= section.len() {
+            let stream_type = section[i];
+            let pid = ((section[i + 1] as u16 & 0x1f) << 8) | section[i + 2] as u16;
+            let es_info_length = ((section[i + 3] as usize & 0x0f) << 8) | section[i + 4] as usize;
+
+            let descriptors = section
+                .get(i + 5..i + 5 + es_info_length)
+                .unwrap_or_default();
+
+            let mut descriptor_tags = Vec::new();
+            let mut j = 0;
+            while j + 2 <= descriptors.len() {
+                descriptor_tags.push(descriptors[j]);
+                j += 2 + descriptors[j + 1] as usize;
+            }
+
+            streams.push(ElementaryStream {
+                pid,
+                stream_type,
+                descriptor_tags,
+            });
+
+            i += 5 + es_info_length;
+        }
+
+        if streams != self.streams {
+            debug!("Program contains streams {:?}", streams);
+            self.streams = streams;
+        }
+    }
+
+    fn parse_pes(&self, pid: u16, buffer: BytesMut) -> Option<PesPacket> {
+        let stream_type = self.streams.iter().find(|s| s.pid == pid)?.stream_type;
+
+        if buffer.len() < 9 || buffer[..3] != [0x00, 0x00, 0x01] {
+            debug!("Skipping a PES packet without a start code on PID {}", pid);
+            return None;
+        }
+
+        let flags = buffer[7];
+        let header_length = 9 + buffer[8] as usize;
+        if buffer.len() < header_length {
+            return None;
+        }
+
+        let pts = (flags & 0x80 != 0 && header_length >= 14).then(|| pes_timestamp(&buffer[9..]));
+        let dts = (flags & 0x40 != 0 && header_length >= 19).then(|| pes_timestamp(&buffer[14..]));
+
+        let mut data = buffer.freeze();
+        if let Some(len) = pes_length(&data) {
+            if len < header_length {
+                debug!(
+                    "Skipping a PES packet shorter than its header on PID {}",
+                    pid
+                );
+                return None;
+            }
+
+            data.truncate(len);
+        }
+
+        Some(PesPacket {
+            pid,
+            stream_type,
+            pts,
+            dts,
+            data: data.slice(header_length..),
+        })
+    }
+}
+
+/// Returns the contents of a complete PSI section with the given table id,
+/// without the trailing CRC.
+fn psi_table(section: &[u8], table_id: u8) -> Option<&[u8]> {
+    // the syntax section and CRC take up at least 9 bytes
+    if section.len() < 12 || section[0] != table_id {
+        return None;
+    }
+
+    Some(&section[..section.len() - 4])
+}
+
+/// Returns the total length of a PES packet, if it is given in its header.
+fn pes_length(buffer: &[u8]) -> Option<usize> {
+    if buffer.len() < 6 {
+        return None;
+    }
+
+    match u16::from_be_bytes([buffer[4], buffer[5]]) {
+        0 => None,
+        len => Some(6 + len as usize),
+    }
+}
+
+fn pes_timestamp(b: &[u8]) -> u64 {
+    ((b[0] as u64 >> 1) & 0x07) << 30
+        | (b[1] as u64) << 22
+        | (b[2] as u64 >> 1) << 15
+        | (b[3] as u64) << 7
+        | (b[4] as u64 >> 1)
+}
+
+#[cfg(test)]
+fn test_packet(pid: u16, payload_start: bool, counter: u8, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![
+        SYNC_BYTE,
+        ((payload_start as u8) << 6) | (pid >> 8) as u8,
+        pid as u8,
+        0x10 | counter,
+    ];
+    packet.extend_from_slice(payload);
+    packet.resize(TS_PACKET_SIZE, 0xff);
+
+    packet
+}
+
+/// A PSI section with a zeroed CRC, which isn't checked.
+#[cfg(test)]
+fn test_section(table_id: u8, body: &[u8]) -> Vec<u8> {
+    let length = 5 + body.len() + 4;
+
+    let mut section = vec![table_id, 0xb0 | (length >> 8) as u8, length as u8];
+    section.extend_from_slice(&[0x00, 0x01, 0xc1, 0x00, 0x00]);
+    section.extend_from_slice(body);
+    section.extend_from_slice(&[0; 4]);
+
+    section
+}
+
+/// A PAT with program 1 at PID 0x1000, and a PMT with a video and an
+/// audio stream whose program descriptors make it span two packets.
+#[cfg(test)]
+fn test_tables() -> (Vec<u8>, Vec<u8>) {
+    let pat = test_section(0x00, &[0x00, 0x01, 0xf0, 0x00]);
+
+    let mut body = vec![0xe1, 0x00, 0xf0, 200, 0x05, 198];
+    body.resize(4 + 200, 0x00);
+    body.extend_from_slice(&[STREAM_TYPE_H264, 0xe1, 0x00, 0xf0, 0x00]);
+    body.extend_from_slice(&[STREAM_TYPE_AAC_ADTS, 0xe1, 0x01, 0xf0, 0x00]);
+
+    (pat, test_section(0x02, &body))
+}
+
+#[test]
+fn pmt_spanning_packets_is_reassembled() {
+    let (pat, pmt) = test_tables();
+    let mut demuxer = TsDemuxer::new();
+
+    demuxer.push(&test_packet(
+        PAT_PID,
+        true,
+        0,
+        &[&[0x00], &pat[..]].concat(),
+    ));
+    demuxer.push(&test_packet(
+        0x1000,
+        true,
+        0,
+        &[&[0x00], &pmt[..183]].concat(),
+    ));
+    assert!(demuxer.streams().is_empty());
+
+    demuxer.push(&test_packet(0x1000, false, 1, &pmt[183..]));
+    let pids = demuxer.streams().iter().map(|s| s.pid).collect::<Vec<_>>();
+    assert_eq!(pids, [0x100, 0x101]);
+}
+
+#[test]
+fn pmt_with_lost_packets_is_dropped() {
+    let (pat, pmt) = test_tables();
+    let mut demuxer = TsDemuxer::new();
+
+    demuxer.push(&test_packet(
+        PAT_PID,
+        true,
+        0,
+        &[&[0x00], &pat[..]].concat(),
+    ));
+    demuxer.push(&test_packet(
+        0x1000,
+        true,
+        0,
+        &[&[0x00], &pmt[..183]].concat(),
+    ));
+    demuxer.push(&test_packet(0x1000, false, 2, &pmt[183..]));
+    assert!(demuxer.streams().is_empty());
+
+    // the next section starts over
+    demuxer.push(&test_packet(
+        0x1000,
+        true,
+        3,
+        &[&[0x00], &pmt[..183]].concat(),
+    ));
+    demuxer.push(&test_packet(0x1000, false, 4, &pmt[183..]));
+    assert_eq!(demuxer.streams().len(), 2);
+}
+
+/// A PES packet with a PTS, in a single TS packet.
+#[cfg(test)]
+fn test_pes(pes_length: u16, header_length: u8, pts: u64) -> Vec<u8> {
+    let mut pes = vec![0x00, 0x00, 0x01, 0xe0];
+    pes.extend_from_slice(&pes_length.to_be_bytes());
+    pes.extend_from_slice(&[0x80, 0x80, header_length]);
+    pes.extend_from_slice(&[
+        0x21 | ((pts >> 29) & 0x0e) as u8,
+        (pts >> 22) as u8,
+        0x01 | (pts >> 14) as u8,
+        (pts >> 7) as u8,
+        0x01 | (pts << 1) as u8,
+    ]);
+    pes.extend_from_slice(&[0x00, 0x00, 0x00, 0x01, 0x09, 0xf0]);
+
+    pes
+}
+
+#[cfg(test)]
+fn test_demuxer() -> TsDemuxer {
+    let (pat, pmt) = test_tables();
+    let mut demuxer = TsDemuxer::new();
+
+    demuxer.push(&test_packet(
+        PAT_PID,
+        true,
+        0,
+        &[&[0x00], &pat[..]].concat(),
+    ));
+    demuxer.push(&test_packet(
+        0x1000,
+        true,
+        0,
+        &[&[0x00], &pmt[..183]].concat(),
+    ));
+    demuxer.push(&test_packet(0x1000, false, 1, &pmt[183..]));
+    assert_eq!(demuxer.streams().len(), 2);
+
+    demuxer
+}
+
+#[test]
+fn truncated_and_unsynced_packets_are_skipped() {
+    let (pat, _) = test_tables();
+    let mut demuxer = TsDemuxer::new();
+
+    let packet = test_packet(PAT_PID, true, 0, &[&[0x00], &pat[..]].concat());
+    assert!(demuxer.push(&packet[..100]).is_empty());
+
+    let mut unsynced = packet.clone();
+    unsynced[0] = 0x00;
+    assert!(demuxer.push(&unsynced).is_empty());
+
+    // an adaptation field longer than the packet
+    let mut adaptation = packet;
+    adaptation[3] |= 0x20;
+    adaptation[4] = 200;
+    assert!(demuxer.push(&adaptation).is_empty());
+
+    assert!(demuxer.programs().is_empty());
+}
+
+#[test]
+fn malformed_psi_sections_are_skipped() {
+    let (pat, _) = test_tables();
+    let mut demuxer = TsDemuxer::new();
+
+    // a pointer past the end of the packet
+    demuxer.push(&test_packet(PAT_PID, true, 0, &[&[250], &pat[..]].concat()));
+    assert!(demuxer.programs().is_empty());
+
+    // a section longer than a PAT may be
+    let mut oversized = pat.clone();
+    oversized[1] |= 0x0f;
+    demuxer.push(&test_packet(
+        PAT_PID,
+        true,
+        1,
+        &[&[0x00], &oversized[..]].concat(),
+    ));
+    assert!(demuxer.programs().is_empty());
+
+    // a section too short for its syntax fields
+    let short = [0x00, 0xb0, 0x04, 0x00, 0x01, 0xc1, 0x00];
+    demuxer.push(&test_packet(
+        PAT_PID,
+        true,
+        2,
+        &[&[0x00], &short[..]].concat(),
+    ));
+    assert!(demuxer.programs().is_empty());
+
+    // a table following the end of a previous section
+    let start = [&[3, 0xaa, 0xbb, 0xcc], &pat[..]].concat();
+    demuxer.push(&test_packet(PAT_PID, true, 3, &start));
+    assert_eq!(demuxer.programs().len(), 1);
+}
+
+#[test]
+fn malformed_pes_packets_are_dropped() {
+    let mut demuxer = test_demuxer();
+
+    // no start code
+    let mut pes = test_pes(0, 5, 9000);
+    pes[2] = 0x02;
+    demuxer.push(&test_packet(0x100, true, 0, &pes));
+    assert!(demuxer.flush().is_empty());
+
+    // a header longer than the packet
+    demuxer.push(&test_packet(0x100, true, 1, &test_pes(0, 200, 9000)));
+    assert!(demuxer.flush().is_empty());
+
+    // a packet length shorter than the header
+    assert!(demuxer
+        .push(&test_packet(0x100, true, 2, &test_pes(4, 5, 9000)))
+        .is_empty());
+
+    let packets = demuxer.push(&test_packet(0x100, true, 3, &test_pes(14, 5, 9000)));
+    assert_eq!(packets.len(), 1);
+    assert_eq!(packets[0].pts, Some(9000));
+    assert_eq!(&packets[0].data[..], [0x00, 0x00, 0x00, 0x01, 0x09, 0xf0]);
+}
@@ -0,0 +1,120 @@
+use std::{collections::HashMap, io::Read};
+
+use crate::{PesPacket, TsDemuxer, STREAM_TYPE_H264, TS_PACKET_SIZE};
+
+const NAL_UNIT_TYPE_IDR: u8 = 5;
+
+/// The keyframes of an MPEG transport stream file, where it can be split
+/// into segments which start decoding cleanly.
+#[derive(Debug, Clone, Default)]
+pub struct TsIndex {
+    pub keyframes: Vec<TsKeyframe>,
+    /// The last video timestamp in the file, in 90 kHz units.
+    pub last_pts: Option<u64>,
+    /// The size of the file, in whole TS packets.
+    pub size: u64,
+    /// The size of the start of the file up to and including the first
+    /// program map table, which has to be read before any keyframe.
+    pub header_size: u64,
+}
+
+/// A video keyframe, located by the first TS packet of its PES packet.
+#[derive(Debug, Clone, Copy)]
+pub struct TsKeyframe {
+    pub offset: u64,
+    /// The end of the last TS packet of its PES packet.
+    pub end: u64,
+    /// The presentation time in 90 kHz units.
+    pub pts: u64,
+}
+
+impl TsIndex {
+    /// Reads a transport stream file and finds the keyframes of the first
+    /// H.264 stream of its first program.
+    pub fn read<R: Read>(reader: &mut R) -> anyhow::Result<Self> {
+        let mut index = TsIndex::default();
+        let mut demuxer = TsDemuxer::new();
+        // where the PES packet currently being assembled on a PID started
+        let mut starts = HashMap::new();
+        let mut packet = [0; TS_PACKET_SIZE];
+
+        loop {
+            match reader.read_exact(&mut packet) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+
+            let offset = index.size;
+            index.size += TS_PACKET_SIZE as u64;
+
+            let pid = ((packet[1] as u16 & 0x1f) << 8) | packet[2] as u16;
+            let mut previous_start = None;
+            if packet[1] & 0x40 != 0 {
+                previous_start = starts.insert(pid, offset);
+            }
+
+            let packets = demuxer.push(&packet);
+            if index.header_size == 0 && !demuxer.streams().is_empty() {
+                index.header_size = index.size;
+            }
+
+            for pes in packets {
+                // a PES packet ends either when the next one on its PID
+                // starts, or in this packet if its length is known
+                let start = if pes.pid == pid {
+                    previous_start.take()
+                } else {
+                    None
+                };
+                let end = if start.is_some() { offset } else { index.size };
+
+                index.add(
+                    &demuxer,
+                    &pes,
+                    start.or_else(|| starts.get(&pes.pid).copied()),
+                    end,
+                );
+            }
+        }
+
+        for pes in demuxer.flush() {
+            let start = starts.get(&pes.pid).copied();
+            index.add(&demuxer, &pes, start, index.size);
+        }
+
+        if index.last_pts.is_none() {
+            anyhow::bail!("file has no H.264 video");
+        }
+
+        Ok(index)
+    }
+
+    fn add(&mut self, demuxer: &TsDemuxer, pes: &PesPacket, start: Option<u64>, end: u64) {
+        let video_pid = demuxer
+            .streams()
+            .iter()
+            .find(|s| s.stream_type == STREAM_TYPE_H264)
+            .map(|s| s.pid);
+        if Some(pes.pid) != video_pid {
+            return;
+        }
+
+        let pts = match pes.pts {
+            Some(pts) => pts,
+            None => return,
+        };
+
+        self.last_pts = Some(self.last_pts.map_or(pts, |last| last.max(pts)));
+
+        if let (Some(offset), true) = (start, has_idr(&pes.data)) {
+            self.keyframes.push(TsKeyframe { offset, end, pts });
+        }
+    }
+}
+
+/// Whether an Annex B access unit contains an IDR slice.
+fn has_idr(data: &[u8]) -> bool {
+    data.windows(4)
+        .any(|w| w[..3] == [0x00, 0x00, 0x01] && w[3] & 0x1f == NAL_UNIT_TYPE_IDR)
+}
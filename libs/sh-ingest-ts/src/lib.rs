@@ -0,0 +1,468 @@
+use anyhow::Context;
+use bytes::Bytes;
+use h264_reader::{
+    nal::{sps::SeqParameterSet, UnitType},
+    rbsp::decode_nal,
+};
+use sh_media::{
+    frame_nal_units, nut_header, parse_adts_header, parse_bitstream, AudioCodecInfo,
+    AudioCodecSpecificInfo, BitstreamFraming, CodecInfo, CodecTypeInfo, Fraction, Frame,
+    FrameDependency, FrameReadFilter, MediaTime, SoundType, Stream, VideoCodecInfo,
+    VideoCodecSpecificInfo,
+};
+use tracing::*;
+
+use std::{collections::VecDeque, sync::Arc, time::Instant};
+
+mod adts;
+mod demux;
+mod index;
+mod mux;
+mod rist;
+mod sap;
+mod teletext;
+mod udp;
+
+pub use adts::{AdtsMuxer, AdtsWriteFilter};
+pub use demux::*;
+pub use index::*;
+pub use mux::*;
+pub use rist::*;
+pub use sap::*;
+pub use teletext::*;
+pub use udp::*;
+
+/// The timebase of all MPEG-TS timestamps.
+const TS_TIMEBASE: Fraction = Fraction::new(1, 90000);
+
+/// The most PES packets buffered while waiting for codec parameters.
+const MAX_PENDING_PES: usize = 2048;
+
+const AAC_SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+/// A pull filter which reads H.264 video and AAC audio from an MPEG
+/// transport stream, received as buffers of whole TS packets from a
+/// transport such as RIST or UDP.
+pub struct TsReadFilter {
+    input: async_channel::Receiver<Bytes>,
+    demuxer: TsDemuxer,
+    ready: VecDeque<PesPacket>,
+    pending: VecDeque<PesPacket>,
+    frames: VecDeque<Frame>,
+
+    video_stream: Option<Stream>,
+    audio_stream: Option<Stream>,
+
+    clock: TsClock,
+
+    started: bool,
+    teletext: Option<(TeletextDecoder, async_channel::Sender<Caption>)>,
+    warned_dvb_subtitles: bool,
+}
+
+impl TsReadFilter {
+    /// Creates a filter which reads the first program of the input.
+    pub fn new(input: async_channel::Receiver<Bytes>) -> Self {
+        Self::with_program(input, ProgramSelector::First)
+    }
+
+    /// Creates a filter which reads the selected program of the input.
+    pub fn with_program(input: async_channel::Receiver<Bytes>, program: ProgramSelector) -> Self {
+        TsReadFilter {
+            input,
+            demuxer: TsDemuxer::with_program(program),
+            ready: VecDeque::new(),
+            pending: VecDeque::new(),
+            frames: VecDeque::new(),
+            video_stream: None,
+            audio_stream: None,
+            clock: TsClock::default(),
+            started: false,
+            teletext: None,
+            warned_dvb_subtitles: false,
+        }
+    }
+
+    /// Extracts the captions of a teletext page, e.g. 888, from the
+    /// program. Captions which are not read in time are dropped.
+    pub fn captions(&mut self, page: u16) -> async_channel::Receiver<Caption> {
+        let (tx, rx) = async_channel::bounded(64);
+        self.teletext = Some((TeletextDecoder::new(page), tx));
+
+        rx
+    }
+
+    async fn next_pes(&mut self) -> anyhow::Result<PesPacket> {
+        loop {
+            if let Some(pes) = self.ready.pop_front() {
+                return Ok(pes);
+            }
+
+            let buffer = self.input.recv().await.context("TS input ended")?;
+            self.ready.extend(self.demuxer.push(&buffer));
+        }
+    }
+
+    fn has_streams(&self) -> bool {
+        let has_audio = self
+            .demuxer
+            .streams()
+            .iter()
+            .any(|s| s.stream_type == STREAM_TYPE_AAC_ADTS);
+
+        self.video_stream.is_some() && (self.audio_stream.is_some() || !has_audio)
+    }
+
+    fn add_pes(&mut self, pes: PesPacket) -> anyhow::Result<()> {
+        let pts = match pes.pts.or(pes.dts) {
+            Some(pts) => pts,
+            None => {
+                debug!("Skipping a PES packet without timestamps");
+                return Ok(());
+            }
+        };
+
+        // the decode time goes first, so the timeline starts at the first
+        // frame to be decoded rather than the first to be shown
+        let dts = pes.dts.map(|dts| self.clock.time(dts));
+        let time = self.clock.time(pts);
+
+        match pes.stream_type {
+            STREAM_TYPE_H264 => self.add_video_frame(pes.data, time, dts),
+            STREAM_TYPE_AAC_ADTS => {
+                // a corrupt packet loses its frames rather than the ingest
+                if let Err(e) = self.add_audio_frames(pes.data, time) {
+                    warn!("Skipping the rest of an AAC PES packet: {:?}", e);
+                }
+                Ok(())
+            }
+            STREAM_TYPE_PRIVATE_PES => {
+                self.add_subtitles(pes.pid, pes.data, time);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn add_subtitles(&mut self, pid: u16, data: Bytes, time: u64) {
+        // captions seen while probing are decoded again once the pending
+        // packets are read
+        if !self.started {
+            return;
+        }
+
+        let stream = match self.demuxer.streams().iter().find(|s| s.pid == pid) {
+            Some(stream) => stream,
+            None => return,
+        };
+
+        if stream.is_dvb_subtitle() && !self.warned_dvb_subtitles {
+            // DVB subtitles are bitmaps, which would need OCR to become text
+            warn!("Ignoring DVB bitmap subtitles on PID {}", pid);
+            self.warned_dvb_subtitles = true;
+        }
+
+        if !stream.is_teletext() {
+            return;
+        }
+
+        if let Some((decoder, captions)) = &mut self.teletext {
+            for caption in decoder.push(&data, time) {
+                if captions.try_send(caption).is_err() {
+                    debug!("Dropping a teletext caption");
+                }
+            }
+        }
+    }
+
+    fn add_video_frame(&mut self, data: Bytes, pts: u64, dts: Option<u64>) -> anyhow::Result<()> {
+        let nal_units = parse_bitstream(data, BitstreamFraming::FourByteStartCode)
+            .into_iter()
+            .filter(|nal| !nal.is_empty())
+            .filter(|nal| nut_header(nal) != Some(UnitType::AccessUnitDelimiter))
+            .collect::<Vec<_>>();
+
+        if let Some(codec) = get_video_codec_info(&nal_units) {
+            let changed = self
+                .video_stream
+                .as_ref()
+                .map(|s| s.parameter_sets() != codec.video().and_then(|v| v.parameter_sets()))
+                .unwrap_or(true);
+
+            if changed {
+                debug!("Got video parameters {:?}", codec);
+                self.video_stream = Some(Stream {
+                    id: 0,
+                    codec: Arc::new(codec),
+                    timebase: TS_TIMEBASE,
+                });
+            }
+        }
+
+        let stream = match &self.video_stream {
+            Some(stream) => stream.clone(),
+            None => return Ok(()),
+        };
+
+        let is_keyframe = nal_units
+            .iter()
+            .any(|nal| nut_header(nal) == Some(UnitType::SliceLayerWithoutPartitioningIdr));
+
+        self.frames.push_back(Frame {
+            time: MediaTime {
+                pts,
+                dts: dts.filter(|&dts| dts != pts),
+                timebase: TS_TIMEBASE,
+            },
+            dependency: if is_keyframe {
+                FrameDependency::None
+            } else {
+                FrameDependency::Backwards
+            },
+            buffer: frame_nal_units(&nal_units[..], BitstreamFraming::FourByteLength).freeze(),
+            stream,
+            received: Instant::now(),
+            metadata: None,
+        });
+
+        Ok(())
+    }
+
+    fn add_audio_frames(&mut self, mut data: Bytes, time: u64) -> anyhow::Result<()> {
+        let mut offset = 0;
+
+        while !data.is_empty() {
+            let (header_len, frame_len) = parse_adts_header(&data)?;
+
+            if self.audio_stream.is_none() {
+                let codec = get_audio_codec_info(&data)?;
+                debug!("Got audio parameters {:?}", codec);
+
+                let sample_rate = codec.audio().map(|a| a.sample_rate).unwrap_or(48000);
+                self.audio_stream = Some(Stream {
+                    id: 1,
+                    codec: Arc::new(codec),
+                    timebase: Fraction::new(1, sample_rate),
+                });
+            }
+
+            let stream = self.audio_stream.clone().unwrap();
+            let timebase = stream.timebase;
+
+            let mut frame = data.split_to(frame_len);
+            let buffer = frame.split_off(header_len);
+
+            let time = MediaTime {
+                pts: time,
+                dts: None,
+                timebase: TS_TIMEBASE,
+            }
+            .in_base(timebase);
+
+            self.frames.push_back(Frame {
+                time: MediaTime {
+                    pts: time.pts + offset,
+                    dts: None,
+                    timebase,
+                },
+                dependency: FrameDependency::None,
+                buffer,
+                stream,
+                received: Instant::now(),
+                metadata: None,
+            });
+
+            offset += 1024;
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl FrameReadFilter for TsReadFilter {
+    async fn start(&mut self) -> anyhow::Result<Vec<Stream>> {
+        while !self.has_streams() {
+            let pes = self.next_pes().await?;
+
+            self.add_pes(pes.clone())?;
+            self.frames.clear();
+
+            if self.pending.len() >= MAX_PENDING_PES {
+                anyhow::bail!("Did not find codec parameters in the transport stream");
+            }
+            self.pending.push_back(pes);
+        }
+
+        // forget the timestamps seen while probing, they are added again
+        // when the pending packets are read
+        self.clock = TsClock::default();
+        self.started = true;
+
+        Ok(self
+            .video_stream
+            .iter()
+            .chain(self.audio_stream.iter())
+            .cloned()
+            .collect())
+    }
+
+    async fn read(&mut self) -> anyhow::Result<Frame> {
+        loop {
+            if let Some(frame) = self.frames.pop_front() {
+                return Ok(frame);
+            }
+
+            let pes = match self.pending.pop_front() {
+                Some(pes) => pes,
+                None => self.next_pes().await?,
+            };
+
+            self.add_pes(pes)?;
+        }
+    }
+}
+
+/// Converts 33-bit MPEG-TS timestamps to a timeline which starts at zero
+/// and does not wrap around.
+///
+/// The timestamps don't have to be in order, as decode and presentation
+/// times of reordered frames aren't.
+#[derive(Default)]
+struct TsClock {
+    origin: Option<u64>,
+    /// The last timestamp, after unwrapping it.
+    last: Option<u64>,
+}
+
+impl TsClock {
+    const WRAP: u64 = 1 << 33;
+
+    fn time(&mut self, ts: u64) -> u64 {
+        // timestamps are never half a wrap apart, so a bigger jump means
+        // they wrapped around, or that one is from before they did
+        let ts = match self.last {
+            Some(last) => {
+                let ts = last - last % Self::WRAP + ts;
+
+                if ts + Self::WRAP / 2 < last {
+                    ts + Self::WRAP
+                } else if ts > last + Self::WRAP / 2 && ts >= Self::WRAP {
+                    ts - Self::WRAP
+                } else {
+                    ts
+                }
+            }
+            None => ts,
+        };
+        self.last = Some(ts);
+
+        let origin = *self.origin.get_or_insert(ts);
+
+        ts.saturating_sub(origin)
+    }
+}
+
+fn get_video_codec_info(nal_units: &[Bytes]) -> Option<CodecInfo> {
+    let sps = nal_units
+        .iter()
+        .find(|nal| nut_header(nal) == Some(UnitType::SeqParameterSet))?;
+    let pps = nal_units
+        .iter()
+        .find(|nal| nut_header(nal) == Some(UnitType::PicParameterSet))?;
+
+    let parsed = SeqParameterSet::from_bytes(&decode_nal(&sps[1..])).ok()?;
+    let (width, height) = parsed.pixel_dimensions().ok()?;
+
+    Some(CodecInfo {
+        name: "h264",
+        properties: CodecTypeInfo::Video(VideoCodecInfo {
+            width,
+            height,
+            extra: VideoCodecSpecificInfo::H264 {
+                bitstream_format: BitstreamFraming::FourByteLength,
+                profile_indication: parsed.profile_idc.into(),
+                profile_compatibility: parsed.constraint_flags.into(),
+                level_indication: parsed.level_idc,
+                sps: Arc::new(sps.to_vec()),
+                pps: Arc::new(pps.to_vec()),
+            },
+        }),
+    })
+}
+
+/// Builds the codec info of an AAC stream from an ADTS header.
+fn get_audio_codec_info(header: &[u8]) -> anyhow::Result<CodecInfo> {
+    let profile = (header[2] >> 6) & 0x03;
+    let frequency_index = (header[2] >> 2) & 0x0f;
+    let channels = ((header[2] & 0x01) << 2) | (header[3] >> 6);
+
+    let sample_rate = *AAC_SAMPLE_RATES
+        .get(frequency_index as usize)
+        .context("Invalid AAC sampling frequency index")?;
+
+    // AudioSpecificConfig, with the object type being the ADTS profile + 1
+    let extra = vec![
+        ((profile + 1) << 3) | (frequency_index >> 1),
+        ((frequency_index & 0x01) << 7) | (channels << 3),
+    ];
+
+    Ok(CodecInfo {
+        name: "AAC",
+        properties: CodecTypeInfo::Audio(AudioCodecInfo {
+            sample_rate,
+            sample_bpp: 16,
+            sound_type: if channels == 1 {
+                SoundType::Mono
+            } else {
+                SoundType::Stereo
+            },
+            extra: AudioCodecSpecificInfo::Aac { extra },
+        }),
+    })
+}
+
+#[test]
+fn clock_unwraps_reordered_timestamps() {
+    let mut clock = TsClock::default();
+    let end = TsClock::WRAP - 3000;
+
+    // decode and presentation times of reordered frames around a wrap
+    assert_eq!(clock.time(end - 3000), 0);
+    assert_eq!(clock.time(end + 3000), 6000);
+    assert_eq!(clock.time(end), 3000);
+    assert_eq!(clock.time(3000), 9000);
+    assert_eq!(clock.time(end + 1500), 4500);
+    assert_eq!(clock.time(6000), 12000);
+}
+
+#[test]
+fn corrupt_adts_packets_are_skipped() {
+    let (_tx, rx) = async_channel::bounded(1);
+    let mut filter = TsReadFilter::new(rx);
+
+    // a valid frame of 9 bytes followed by a truncated header
+    let adts = [
+        0xff, 0xf1, 0x50, 0x80, 0x01, 0x3f, 0xfc, 0xaa, 0xbb, 0xff, 0xf1, 0x50,
+    ];
+    let pes = PesPacket {
+        pid: 0x101,
+        stream_type: STREAM_TYPE_AAC_ADTS,
+        pts: Some(0),
+        dts: None,
+        data: Bytes::copy_from_slice(&adts),
+    };
+
+    filter.add_pes(pes.clone()).unwrap();
+    assert_eq!(filter.frames.len(), 1);
+    assert_eq!(&filter.frames[0].buffer[..], [0xaa, 0xbb]);
+
+    let invalid = PesPacket {
+        data: Bytes::from_static(&[0x00; 16]),
+        ..pes
+    };
+    filter.add_pes(invalid).unwrap();
+    assert_eq!(filter.frames.len(), 1);
+}
@@ -0,0 +1,391 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use h264_reader::nal::UnitType;
+use sh_media::{
+    frame_nal_units, nut_header, parse_bitstream, BitstreamFraming, ByteWriteFilter2, Frame,
+    FrameWriteFilter, Muxer, Stream,
+};
+use tracing::*;
+
+use std::collections::HashMap;
+
+use crate::{adts::adts_frame, demux::SYNC_BYTE, TS_PACKET_SIZE, TS_TIMEBASE};
+use crate::{STREAM_TYPE_AAC_ADTS, STREAM_TYPE_H264};
+
+const PAT_PID: u16 = 0x0000;
+const PMT_PID: u16 = 0x1000;
+const VIDEO_PID: u16 = 0x0100;
+const AUDIO_PID: u16 = 0x0101;
+const PROGRAM_NUMBER: u16 = 1;
+
+/// How far the PCR runs behind the timestamps of the frames, so decoders
+/// have time to buffer them. This is the default delay of FFmpeg.
+const MUX_DELAY: u64 = 63000;
+
+/// How many audio frames are written between the PAT and PMT of a stream
+/// without video keyframes to repeat them at.
+const PSI_INTERVAL: u32 = 40;
+
+/// The access unit delimiter put before every video frame, which some
+/// hardware decoders need to find the frames in a transport stream.
+const ACCESS_UNIT_DELIMITER: [u8; 2] = [0x09, 0xf0];
+
+/// Muxes H.264 and AAC frames into a single program MPEG transport stream.
+///
+/// The PAT and PMT are repeated before every video keyframe, so receivers
+/// which join late can start decoding there.
+pub struct TsMuxer {
+    video: Option<Stream>,
+    audio: Option<Stream>,
+    /// The next continuity counter of every PID.
+    continuity: HashMap<u16, u8>,
+    frames_since_psi: u32,
+}
+
+impl TsMuxer {
+    pub fn new(streams: &[Stream]) -> Self {
+        TsMuxer {
+            // only H.264 and AAC are carried in transport streams
+            video: streams.iter().find(|s| s.is_h264()).cloned(),
+            audio: streams.iter().find(|s| s.is_aac()).cloned(),
+            continuity: HashMap::new(),
+            frames_since_psi: PSI_INTERVAL,
+        }
+    }
+
+    /// Muxes a frame into whole TS packets. Frames of other streams are
+    /// skipped.
+    pub fn mux(&mut self, frame: &Frame) -> anyhow::Result<Bytes> {
+        let mut out = BytesMut::new();
+
+        let time = frame.time.in_base(TS_TIMEBASE);
+        let pts = time.pts + MUX_DELAY;
+        let dts = time
+            .dts
+            .map(|dts| dts + MUX_DELAY)
+            .filter(|&dts| dts != pts);
+
+        if self.video.as_ref().map(|s| s.id) == Some(frame.stream.id) {
+            let keyframe = frame.is_keyframe();
+            if keyframe {
+                self.write_psi(&mut out);
+            }
+
+            let data = self.annex_b(frame, keyframe);
+            let pes = pes_packet(0xe0, pts, dts, &data, false);
+            let pcr = dts.unwrap_or(pts) - MUX_DELAY;
+
+            self.write_packets(&mut out, VIDEO_PID, &pes, Some(pcr), keyframe);
+        } else if self.audio.as_ref().map(|s| s.id) == Some(frame.stream.id) {
+            if self.video.is_none() && self.frames_since_psi >= PSI_INTERVAL {
+                self.write_psi(&mut out);
+            }
+            self.frames_since_psi += 1;
+
+            let data = adts_frame(&frame.stream, &frame.buffer)?;
+            let pes = pes_packet(0xc0, pts, None, &data, true);
+            let pcr = self.video.is_none().then(|| pts - MUX_DELAY);
+
+            self.write_packets(&mut out, AUDIO_PID, &pes, pcr, true);
+        }
+
+        Ok(out.freeze())
+    }
+
+    /// Converts a video frame to Annex B, with an access unit delimiter and
+    /// the parameter sets in front of keyframes which don't have them.
+    fn annex_b(&self, frame: &Frame, keyframe: bool) -> Vec<u8> {
+        let framing = frame
+            .stream
+            .bitstream_format()
+            .unwrap_or(BitstreamFraming::FourByteLength);
+        let units = parse_bitstream(frame.buffer.clone(), framing);
+
+        let mut nal_units = vec![Bytes::from_static(&ACCESS_UNIT_DELIMITER)];
+
+        let has_sps = units
+            .iter()
+            .any(|nal| nut_header(nal) == Some(UnitType::SeqParameterSet));
+        if keyframe && !has_sps {
+            if let Some(parameter_sets) = frame.stream.parameter_sets() {
+                nal_units.extend(parse_bitstream(
+                    parameter_sets.into(),
+                    BitstreamFraming::FourByteLength,
+                ));
+            }
+        }
+
+        nal_units.extend(
+            units
+                .into_iter()
+                .filter(|nal| !nal.is_empty())
+                .filter(|nal| nut_header(nal) != Some(UnitType::AccessUnitDelimiter)),
+        );
+
+        frame_nal_units(&nal_units[..], BitstreamFraming::FourByteStartCode).to_vec()
+    }
+
+    fn write_psi(&mut self, out: &mut BytesMut) {
+        self.frames_since_psi = 0;
+
+        let pat = psi_section(0x00, 1, &{
+            let mut program = Vec::new();
+            program.put_u16(PROGRAM_NUMBER);
+            program.put_u16(0xe000 | PMT_PID);
+            program
+        });
+        self.write_section(out, PAT_PID, &pat);
+
+        let pcr_pid = if self.video.is_some() {
+            VIDEO_PID
+        } else {
+            AUDIO_PID
+        };
+
+        let mut program = Vec::new();
+        program.put_u16(0xe000 | pcr_pid);
+        // no program descriptors
+        program.put_u16(0xf000);
+
+        let streams = [
+            (self.video.is_some(), STREAM_TYPE_H264, VIDEO_PID),
+            (self.audio.is_some(), STREAM_TYPE_AAC_ADTS, AUDIO_PID),
+        ];
+        for (_, stream_type, pid) in streams.iter().filter(|(present, ..)| *present) {
+            program.put_u8(*stream_type);
+            program.put_u16(0xe000 | pid);
+            program.put_u16(0xf000);
+        }
+
+        let pmt = psi_section(0x02, PROGRAM_NUMBER, &program);
+        self.write_section(out, PMT_PID, &pmt);
+    }
+
+    /// Writes a PSI section into a single packet, which is padded with
+    /// `0xff` bytes.
+    fn write_section(&mut self, out: &mut BytesMut, pid: u16, section: &[u8]) {
+        let start = out.len();
+
+        self.write_header(out, pid, true, false);
+        // pointer field
+        out.put_u8(0);
+        out.put_slice(section);
+        out.resize(start + TS_PACKET_SIZE, 0xff);
+    }
+
+    fn write_header(&mut self, out: &mut BytesMut, pid: u16, start: bool, adaptation: bool) {
+        let continuity = self.continuity.entry(pid).or_default();
+
+        out.put_u8(SYNC_BYTE);
+        out.put_u16((if start { 0x4000 } else { 0 }) | pid);
+        out.put_u8((if adaptation { 0x30 } else { 0x10 }) | *continuity);
+
+        *continuity = (*continuity + 1) & 0x0f;
+    }
+
+    /// Splits a PES packet into TS packets, with the PCR and random access
+    /// indicator in the first of them, and stuffing in the last.
+    fn write_packets(
+        &mut self,
+        out: &mut BytesMut,
+        pid: u16,
+        mut payload: &[u8],
+        pcr: Option<u64>,
+        random_access: bool,
+    ) {
+        let mut first = true;
+
+        while !payload.is_empty() {
+            // the adaptation field, without its length byte
+            let mut adaptation = Vec::new();
+            if first && (pcr.is_some() || random_access) {
+                let mut flags = 0;
+                if random_access {
+                    flags |= 0x40;
+                }
+                if pcr.is_some() {
+                    flags |= 0x10;
+                }
+                adaptation.push(flags);
+
+                if let Some(pcr) = pcr {
+                    let base = pcr & 0x1_ffff_ffff;
+                    adaptation.put_u32((base >> 1) as u32);
+                    // the low bit of the base, reserved bits and a zero
+                    // extension
+                    adaptation.put_u16((((base & 1) as u16) << 15) | 0x7e00);
+                }
+            }
+
+            let mut field_len = if adaptation.is_empty() {
+                0
+            } else {
+                adaptation.len() + 1
+            };
+            let take = payload.len().min(TS_PACKET_SIZE - 4 - field_len);
+
+            let mut stuffing = TS_PACKET_SIZE - 4 - field_len - take;
+            if stuffing > 0 {
+                if adaptation.is_empty() {
+                    // the length byte of an empty field is the first byte
+                    // of stuffing, and its flags the second
+                    stuffing -= 1;
+                    if stuffing > 0 {
+                        adaptation.push(0);
+                        stuffing -= 1;
+                    }
+                }
+                adaptation.resize(adaptation.len() + stuffing, 0xff);
+                field_len = adaptation.len() + 1;
+            }
+
+            self.write_header(out, pid, first, field_len > 0);
+            if field_len > 0 {
+                out.put_u8(adaptation.len() as u8);
+                out.put_slice(&adaptation);
+            }
+
+            out.put_slice(&payload[..take]);
+            payload = &payload[take..];
+            first = false;
+        }
+    }
+}
+
+/// Builds a PES packet. Video packets are left unbounded, since they may
+/// not fit in the 16-bit length.
+fn pes_packet(stream_id: u8, pts: u64, dts: Option<u64>, data: &[u8], bounded: bool) -> Vec<u8> {
+    let header_len = if dts.is_some() { 10 } else { 5 };
+
+    let mut pes = Vec::with_capacity(9 + header_len + data.len());
+    pes.put_slice(&[0x00, 0x00, 0x01, stream_id]);
+
+    let length = 3 + header_len + data.len();
+    pes.put_u16(if bounded && length <= 0xffff {
+        length as u16
+    } else {
+        0
+    });
+
+    pes.put_u8(0x80);
+    pes.put_u8(if dts.is_some() { 0xc0 } else { 0x80 });
+    pes.put_u8(header_len as u8);
+
+    match dts {
+        Some(dts) => {
+            put_timestamp(&mut pes, 0x3, pts);
+            put_timestamp(&mut pes, 0x1, dts);
+        }
+        None => put_timestamp(&mut pes, 0x2, pts),
+    }
+
+    pes.put_slice(data);
+
+    pes
+}
+
+/// Writes a 33-bit timestamp with marker bits, as in PES headers.
+fn put_timestamp(buf: &mut Vec<u8>, prefix: u8, ts: u64) {
+    let ts = ts & 0x1_ffff_ffff;
+
+    buf.put_u8((prefix << 4) | (((ts >> 30) as u8 & 0x07) << 1) | 1);
+    buf.put_u16((((ts >> 15) as u16 & 0x7fff) << 1) | 1);
+    buf.put_u16(((ts as u16 & 0x7fff) << 1) | 1);
+}
+
+/// Builds a PSI section with a single section number, ending with its CRC.
+fn psi_section(table_id: u8, table_id_extension: u16, data: &[u8]) -> Vec<u8> {
+    let mut section = Vec::with_capacity(12 + data.len());
+
+    section.put_u8(table_id);
+    // the rest of the header, the data and the CRC
+    section.put_u16(0xb000 | (5 + data.len() + 4) as u16);
+    section.put_u16(table_id_extension);
+    // version 0, current
+    section.put_u8(0xc1);
+    // section number and last section number
+    section.put_u16(0);
+    section.put_slice(data);
+
+    let crc = crc32_mpeg2(&section);
+    section.put_u32(crc);
+
+    section
+}
+
+fn crc32_mpeg2(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+
+    for &byte in data {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+/// A push filter which muxes frames into an MPEG transport stream and writes
+/// it to a [`ByteWriteFilter2`].
+pub struct TsWriteFilter {
+    target: Box<dyn ByteWriteFilter2 + Send + Unpin>,
+    muxer: Option<TsMuxer>,
+}
+
+impl TsWriteFilter {
+    pub fn new(target: Box<dyn ByteWriteFilter2 + Send + Unpin>) -> Self {
+        TsWriteFilter {
+            target,
+            muxer: None,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl FrameWriteFilter for TsWriteFilter {
+    async fn start(&mut self, streams: Vec<Stream>) -> anyhow::Result<()> {
+        debug!("Muxing {:?} to MPEG-TS", streams);
+
+        self.muxer = Some(TsMuxer::new(&streams));
+        self.target.start().await
+    }
+
+    async fn write(&mut self, frame: Frame) -> anyhow::Result<()> {
+        let muxer = self
+            .muxer
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("MPEG-TS muxer was not started"))?;
+
+        let packets = muxer.mux(&frame)?;
+        if packets.is_empty() {
+            return Ok(());
+        }
+
+        self.target.write(packets).await
+    }
+}
+
+/// A [`Muxer`] for MPEG transport streams, see [`TsWriteFilter`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MpegTsMuxer;
+
+impl Muxer for MpegTsMuxer {
+    fn content_type(&self) -> &'static str {
+        "video/mp2t"
+    }
+
+    fn extension(&self) -> &'static str {
+        "ts"
+    }
+
+    fn mux(
+        &self,
+        output: Box<dyn ByteWriteFilter2 + Send + Unpin>,
+    ) -> Box<dyn FrameWriteFilter + Send + Unpin> {
+        Box::new(TsWriteFilter::new(output))
+    }
+}
@@ -0,0 +1,225 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use sh_media::ReorderBuffer;
+use tokio::{
+    net::UdpSocket,
+    time::{interval, Duration, Instant},
+};
+use tracing::*;
+
+use std::net::SocketAddr;
+
+use crate::udp::{wait_for_sender, IDLE_TIMEOUT};
+
+/// How often missing packets are requested again.
+const NACK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The most packets requested in a single NACK.
+const MAX_NACKED_PACKETS: usize = 256;
+
+const RTP_VERSION: u8 = 2;
+const RTCP_RTPFB: u8 = 205;
+const RTCP_GENERIC_NACK: u8 = 1;
+
+/// A listener for MPEG-TS sent with the RIST Simple Profile.
+///
+/// RTP packets are received on an even port and RTCP on the port after it.
+/// Packets are put back in order, and missing packets are requested again
+/// with RTCP NACKs until they are older than the receive buffer.
+pub struct RistListener {
+    rtp: UdpSocket,
+    rtcp: UdpSocket,
+    buffer: Duration,
+}
+
+impl RistListener {
+    pub async fn bind(addr: SocketAddr, buffer: Duration) -> anyhow::Result<Self> {
+        if !addr.port().is_multiple_of(2) {
+            anyhow::bail!("RIST listeners must use an even port, got {}", addr.port());
+        }
+
+        let mut rtcp_addr = addr;
+        rtcp_addr.set_port(addr.port() + 1);
+
+        Ok(RistListener {
+            rtp: UdpSocket::bind(addr).await?,
+            rtcp: UdpSocket::bind(rtcp_addr).await?,
+            buffer,
+        })
+    }
+
+    /// Waits until a sender starts sending RTP and returns its address.
+    pub async fn wait_for_sender(&self) -> anyhow::Result<SocketAddr> {
+        wait_for_sender(&self.rtp).await
+    }
+
+    /// Receives the TS payloads of the current sender in order into
+    /// `output`, until the sender goes idle or `output` is closed.
+    pub async fn receive(&self, output: async_channel::Sender<Bytes>) -> anyhow::Result<()> {
+        let mut session = RistSession::new(self.buffer);
+
+        let mut rtp_buf = vec![0; 2048];
+        let mut rtcp_buf = vec![0; 2048];
+        let mut ticker = interval(NACK_INTERVAL);
+
+        loop {
+            tokio::select! {
+                result = self.rtp.recv_from(&mut rtp_buf) => {
+                    let (len, addr) = result?;
+                    session.add_rtp(&rtp_buf[..len], addr);
+                }
+                result = self.rtcp.recv_from(&mut rtcp_buf) => {
+                    let (_, addr) = result?;
+                    session.rtcp_peer = Some(addr);
+                }
+                _ = ticker.tick() => {
+                    if session.last_packet.elapsed() > IDLE_TIMEOUT {
+                        info!("RIST sender went idle");
+                        return Ok(());
+                    }
+
+                    if let Some((addr, nack)) = session.nack() {
+                        self.rtcp.send_to(&nack, addr).await?;
+                    }
+                }
+            }
+
+            while let Some(payload) = session.packets.next_payload() {
+                if output.send(payload).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+struct RistSession {
+    packets: ReorderBuffer,
+    ssrc: u32,
+    rtp_peer: Option<SocketAddr>,
+    rtcp_peer: Option<SocketAddr>,
+    last_packet: Instant,
+}
+
+impl RistSession {
+    fn new(buffer: Duration) -> Self {
+        RistSession {
+            packets: ReorderBuffer::new("RIST", buffer),
+            ssrc: 0,
+            rtp_peer: None,
+            rtcp_peer: None,
+            last_packet: Instant::now(),
+        }
+    }
+
+    fn add_rtp(&mut self, packet: &[u8], addr: SocketAddr) {
+        let (seq, ssrc, payload) = match parse_rtp(packet) {
+            Some(rtp) => rtp,
+            None => {
+                debug!("Skipping a malformed RTP packet from {}", addr);
+                return;
+            }
+        };
+
+        self.last_packet = Instant::now();
+        self.rtp_peer = Some(addr);
+        self.ssrc = ssrc;
+
+        let seq = match self.packets.highest() {
+            Some(highest) => extend_sequence(highest, seq),
+            None => seq as u64,
+        };
+        self.packets.insert(seq, payload);
+    }
+
+    /// Builds a generic NACK for the missing packets which have not been
+    /// requested recently, along with where to send it.
+    fn nack(&mut self) -> Option<(SocketAddr, Vec<u8>)> {
+        let missing = self.packets.request_missing(MAX_NACKED_PACKETS);
+        if missing.is_empty() {
+            return None;
+        }
+
+        // RTCP goes to the sender's RTCP port, which is assumed to be the
+        // port after its RTP port until it sends us a report
+        let addr = self.rtcp_peer.or_else(|| {
+            self.rtp_peer.map(|mut addr| {
+                addr.set_port(addr.port() + 1);
+                addr
+            })
+        })?;
+
+        Some((addr, generic_nack(self.ssrc, &missing)))
+    }
+}
+
+/// Returns the sequence number, SSRC and payload of an RTP packet.
+pub(crate) fn parse_rtp(packet: &[u8]) -> Option<(u16, u32, &[u8])> {
+    if packet.len() < 12 || packet[0] >> 6 != RTP_VERSION {
+        return None;
+    }
+
+    let padding = packet[0] & 0x20 != 0;
+    let extension = packet[0] & 0x10 != 0;
+    let csrc_count = (packet[0] & 0x0f) as usize;
+
+    let seq = u16::from_be_bytes([packet[2], packet[3]]);
+    let ssrc = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+
+    let mut start = 12 + 4 * csrc_count;
+    if extension {
+        let header = packet.get(start..start + 4)?;
+        let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        start += 4 + 4 * len;
+    }
+
+    let mut end = packet.len();
+    if padding {
+        end = end.checked_sub(*packet.last()? as usize)?;
+    }
+
+    Some((seq, ssrc, packet.get(start..end)?))
+}
+
+/// Extends a 16-bit sequence number to the 64-bit sequence number closest
+/// to `reference`.
+fn extend_sequence(reference: u64, seq: u16) -> u64 {
+    let candidate = (reference & !0xffff) | seq as u64;
+
+    if candidate + 0x8000 < reference {
+        candidate + 0x10000
+    } else if candidate > reference + 0x8000 && candidate >= 0x10000 {
+        candidate - 0x10000
+    } else {
+        candidate
+    }
+}
+
+/// Builds an RTCP generic NACK (RFC 4585) for a sorted list of sequence
+/// numbers.
+fn generic_nack(media_ssrc: u32, missing: &[u64]) -> Vec<u8> {
+    // each entry requests a packet and a bitmask of the 16 following it
+    let mut fci = Vec::<(u64, u16)>::new();
+
+    for &seq in missing {
+        match fci.last_mut() {
+            Some((pid, blp)) if seq > *pid && seq - *pid <= 16 => {
+                *blp |= 1 << (seq - *pid - 1);
+            }
+            _ => fci.push((seq, 0)),
+        }
+    }
+
+    let mut packet = BytesMut::with_capacity(12 + 4 * fci.len());
+    packet.put_u8(0x80 | RTCP_GENERIC_NACK);
+    packet.put_u8(RTCP_RTPFB);
+    packet.put_u16(2 + fci.len() as u16);
+    packet.put_u32(0);
+    packet.put_u32(media_ssrc);
+
+    for (pid, blp) in fci {
+        packet.put_u16(pid as u16);
+        packet.put_u16(blp);
+    }
+
+    packet.to_vec()
+}
@@ -0,0 +1,136 @@
+use bytes::{BufMut, BytesMut};
+use tokio::{net::UdpSocket, time::interval};
+use tracing::*;
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The SAP group of administratively scoped IPv4 sessions, which is where
+/// VLC and most decoders listen for announcements.
+const SAP_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 255);
+const SAP_PORT: u16 = 9875;
+
+/// Announces a multicast MPEG-TS session with SAP, so receivers on the
+/// network like VLC list it without being told its address.
+pub struct SapAnnouncer {
+    socket: UdpSocket,
+    announcement: Vec<u8>,
+    deletion: Vec<u8>,
+}
+
+impl SapAnnouncer {
+    /// Creates the announcer of the session `name`, which is sent to
+    /// `group` with the time to live `ttl`.
+    pub async fn new(name: &str, group: SocketAddrV4, ttl: u32) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        socket.set_multicast_ttl_v4(ttl)?;
+
+        // the address the announcements are sent from, which receivers
+        // expect as the origin of the session
+        socket.connect((SAP_GROUP, SAP_PORT)).await?;
+        let origin = match socket.local_addr()?.ip() {
+            IpAddr::V4(ip) if !ip.is_unspecified() => ip,
+            _ => anyhow::bail!("No IPv4 address to announce sessions from"),
+        };
+
+        let mut hasher = DefaultHasher::new();
+        (name, group).hash(&mut hasher);
+        let session_id = hasher.finish() & 0xffff_ffff;
+        let version = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let sdp = session_description(name, group, ttl, origin, session_id, version);
+        let hash = (session_id ^ version) as u16;
+
+        Ok(SapAnnouncer {
+            socket,
+            announcement: sap_packet(false, hash, origin, &sdp),
+            deletion: sap_packet(true, hash, origin, &sdp),
+        })
+    }
+
+    /// Announces the session every `period`, until the future is dropped.
+    pub async fn announce(&self, period: Duration) {
+        let mut ticks = interval(period);
+
+        loop {
+            ticks.tick().await;
+
+            if let Err(e) = self.socket.send(&self.announcement).await {
+                debug!("Failed to send SAP announcement: {:?}", e);
+            }
+        }
+    }
+
+    /// Tells receivers that the session has ended, so they stop listing it.
+    pub async fn delete(&self) {
+        if let Err(e) = self.socket.send(&self.deletion).await {
+            debug!("Failed to send SAP deletion: {:?}", e);
+        }
+    }
+}
+
+/// Describes an MPEG-TS session in plain UDP, the way VLC describes the
+/// sessions it announces.
+fn session_description(
+    name: &str,
+    group: SocketAddrV4,
+    ttl: u32,
+    origin: Ipv4Addr,
+    session_id: u64,
+    version: u64,
+) -> String {
+    // line breaks would end the session name early
+    let name = name.replace(['\r', '\n'], " ");
+
+    format!(
+        "v=0\r\n\
+         o=- {} {} IN IP4 {}\r\n\
+         s={}\r\n\
+         c=IN IP4 {}/{}\r\n\
+         t=0 0\r\n\
+         a=tool:streamhead\r\n\
+         a=type:broadcast\r\n\
+         a=recvonly\r\n\
+         m=video {} udp mpeg\r\n",
+        session_id,
+        version,
+        origin,
+        name,
+        group.ip(),
+        ttl,
+        group.port()
+    )
+}
+
+/// Builds a SAP packet without authentication or encryption, carrying an
+/// SDP payload.
+fn sap_packet(deletion: bool, hash: u16, origin: Ipv4Addr, sdp: &str) -> Vec<u8> {
+    let mut packet = BytesMut::new();
+
+    // version 1, IPv4 origin, announcement or deletion
+    packet.put_u8(if deletion { 0x24 } else { 0x20 });
+    // no authentication data
+    packet.put_u8(0);
+    packet.put_u16(hash);
+    packet.put_slice(&origin.octets());
+    packet.put_slice(b"application/sdp\0");
+    packet.put_slice(sdp.as_bytes());
+
+    packet.to_vec()
+}
+
+/// Returns the group of sessions sent to `addr`, if they can be announced
+/// with SAP, which is only done for IPv4 multicast groups.
+pub fn announceable_group(addr: SocketAddr) -> Option<SocketAddrV4> {
+    match addr {
+        SocketAddr::V4(addr) if addr.ip().is_multicast() => Some(addr),
+        _ => None,
+    }
+}
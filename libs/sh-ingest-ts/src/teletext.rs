@@ -0,0 +1,152 @@
+use tracing::*;
+
+/// The length of an EBU teletext data unit in a PES packet.
+const DATA_UNIT_LENGTH: usize = 44;
+
+const DATA_UNIT_TELETEXT: u8 = 0x02;
+const DATA_UNIT_TELETEXT_SUBTITLE: u8 = 0x03;
+
+/// A teletext subtitle page which was put on screen, with its time in the
+/// 90 kHz timebase of the transport stream. An empty text clears the
+/// screen.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Caption {
+    pub time: u64,
+    pub text: String,
+}
+
+/// Decodes a single teletext page, such as the common subtitle page 888,
+/// from EBU teletext PES packets (ETSI EN 300 472).
+pub struct TeletextDecoder {
+    /// The page number in the hexadecimal form teletext uses, e.g. 0x888.
+    page: u16,
+    receiving: bool,
+    rows: Vec<String>,
+    shown: Option<String>,
+}
+
+impl TeletextDecoder {
+    /// Creates a decoder for a page given as it is written, e.g. 888.
+    pub fn new(page: u16) -> Self {
+        // 888 is written as 0x888 in the page header
+        let page = ((page / 100) << 8) | ((page / 10 % 10) << 4) | (page % 10);
+
+        TeletextDecoder {
+            page,
+            receiving: false,
+            rows: vec![String::new(); 24],
+            shown: None,
+        }
+    }
+
+    /// Decodes the payload of a teletext PES packet, returning the captions
+    /// which were completed by it.
+    pub fn push(&mut self, data: &[u8], time: u64) -> Vec<Caption> {
+        let mut captions = Vec::new();
+
+        // data_identifier, 0x10 to 0x1f for EBU data
+        if !matches!(data.first(), Some(0x10..=0x1f)) {
+            return captions;
+        }
+
+        let mut units = &data[1..];
+        while units.len() >= 2 {
+            let id = units[0];
+            let len = units[1] as usize;
+
+            let unit = match units.get(2..2 + len) {
+                Some(unit) => unit,
+                None => break,
+            };
+
+            if (id == DATA_UNIT_TELETEXT || id == DATA_UNIT_TELETEXT_SUBTITLE)
+                && len == DATA_UNIT_LENGTH
+            {
+                // skip the line offset and the framing code
+                let packet = unit[2..]
+                    .iter()
+                    .map(|b| b.reverse_bits())
+                    .collect::<Vec<_>>();
+                captions.extend(self.push_packet(&packet, time));
+            }
+
+            units = &units[2 + len..];
+        }
+
+        captions
+    }
+
+    fn push_packet(&mut self, packet: &[u8], time: u64) -> Option<Caption> {
+        let address = unham84(packet[0]) | unham84(packet[1]) << 4;
+        let magazine = match address & 0x07 {
+            0 => 8,
+            m => m as u16,
+        };
+        let row = (address >> 3) as usize;
+        let data = &packet[2..];
+
+        if row == 0 {
+            let page = magazine << 8 | (unham84(data[1]) as u16) << 4 | unham84(data[0]) as u16;
+
+            if magazine != self.page >> 8 {
+                return None;
+            }
+
+            // a new header ends the page which was being received, and
+            // puts it on screen
+            let caption = if self.receiving {
+                self.show(time)
+            } else {
+                None
+            };
+
+            self.receiving = page == self.page;
+            if self.receiving {
+                self.rows.iter_mut().for_each(|row| row.clear());
+            }
+
+            return caption;
+        }
+
+        if self.receiving && magazine == self.page >> 8 && row < self.rows.len() {
+            self.rows[row] = decode_row(data);
+        }
+
+        None
+    }
+
+    fn show(&mut self, time: u64) -> Option<Caption> {
+        let text = self
+            .rows
+            .iter()
+            .map(|row| row.trim())
+            .filter(|row| !row.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        if self.shown.as_ref() == Some(&text) {
+            return None;
+        }
+
+        debug!("Teletext page {:x} shows {:?}", self.page, text);
+        self.shown = Some(text.clone());
+
+        Some(Caption { time, text })
+    }
+}
+
+/// Decodes a Hamming 8/4 protected nibble, without correcting errors.
+fn unham84(b: u8) -> u8 {
+    (b >> 1) & 0x01 | (b >> 2) & 0x02 | (b >> 3) & 0x04 | (b >> 4) & 0x08
+}
+
+/// Decodes a row of odd parity characters. Control characters are shown
+/// as spaces and the Latin G0 set is approximated with ASCII.
+fn decode_row(data: &[u8]) -> String {
+    data.iter()
+        .map(|b| match b & 0x7f {
+            c @ 0x20..=0x7e => c as char,
+            _ => ' ',
+        })
+        .collect()
+}
@@ -0,0 +1,162 @@
+use bytes::{Bytes, BytesMut};
+use sh_media::ByteWriteFilter2;
+use tokio::{
+    net::UdpSocket,
+    time::{timeout, Duration},
+};
+use tracing::*;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::{demux::SYNC_BYTE, rist::parse_rtp, TS_PACKET_SIZE};
+
+/// A sender is considered gone after not sending anything for this long.
+pub(crate) const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many TS packets are sent in every datagram, the most which fit in
+/// an Ethernet frame.
+const PACKETS_PER_DATAGRAM: usize = 7;
+
+/// A listener for MPEG-TS sent in plain UDP datagrams, or wrapped in RTP,
+/// which is what most hardware encoders send.
+///
+/// Listening on a multicast address joins the group on every interface.
+/// There is no retransmission, lost datagrams are lost.
+pub struct UdpTsListener {
+    socket: UdpSocket,
+}
+
+impl UdpTsListener {
+    pub async fn bind(addr: SocketAddr) -> anyhow::Result<Self> {
+        let socket = match addr.ip() {
+            IpAddr::V4(group) if group.is_multicast() => {
+                let socket =
+                    UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), addr.port()))
+                        .await?;
+                socket.join_multicast_v4(group, Ipv4Addr::UNSPECIFIED)?;
+                socket
+            }
+            IpAddr::V6(group) if group.is_multicast() => {
+                let socket =
+                    UdpSocket::bind(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), addr.port()))
+                        .await?;
+                socket.join_multicast_v6(&group, 0)?;
+                socket
+            }
+            _ => UdpSocket::bind(addr).await?,
+        };
+
+        Ok(UdpTsListener { socket })
+    }
+
+    /// Waits until a sender starts sending and returns its address.
+    pub async fn wait_for_sender(&self) -> anyhow::Result<SocketAddr> {
+        wait_for_sender(&self.socket).await
+    }
+
+    /// Receives the TS packets of every datagram into `output`, until the
+    /// sender goes idle or `output` is closed.
+    pub async fn receive(&self, output: async_channel::Sender<Bytes>) -> anyhow::Result<()> {
+        let mut buf = vec![0; 65536];
+
+        loop {
+            let (len, addr) = match timeout(IDLE_TIMEOUT, self.socket.recv_from(&mut buf)).await {
+                Ok(result) => result?,
+                Err(_) => {
+                    info!("UDP sender went idle");
+                    return Ok(());
+                }
+            };
+
+            let payload = match ts_payload(&buf[..len]) {
+                Some(payload) => payload,
+                None => {
+                    debug!("Skipping a datagram without TS packets from {}", addr);
+                    continue;
+                }
+            };
+
+            if output.send(Bytes::copy_from_slice(payload)).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// A sender of MPEG-TS in plain UDP datagrams of seven TS packets, e.g. to
+/// a multicast group which local decoders have joined.
+pub struct UdpTsSender {
+    socket: UdpSocket,
+    target: SocketAddr,
+    /// The packets which don't fill a datagram yet.
+    pending: BytesMut,
+}
+
+impl UdpTsSender {
+    /// Creates a sender to `target`, where multicast datagrams are sent with
+    /// the time to live `ttl`, i.e. how many routers they may cross.
+    pub async fn connect(target: SocketAddr, ttl: u32) -> anyhow::Result<Self> {
+        let socket = match target {
+            SocketAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?,
+            SocketAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await?,
+        };
+
+        match target.ip() {
+            IpAddr::V4(group) if group.is_multicast() => socket.set_multicast_ttl_v4(ttl)?,
+            IpAddr::V4(_) => socket.set_ttl(ttl)?,
+            // the hop limit of IPv6 multicast is left to the system
+            IpAddr::V6(_) => {}
+        }
+
+        Ok(UdpTsSender {
+            socket,
+            target,
+            pending: BytesMut::new(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl ByteWriteFilter2 for UdpTsSender {
+    async fn start(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn write(&mut self, bytes: Bytes) -> anyhow::Result<()> {
+        const DATAGRAM_SIZE: usize = PACKETS_PER_DATAGRAM * TS_PACKET_SIZE;
+
+        self.pending.extend_from_slice(&bytes);
+
+        while self.pending.len() >= DATAGRAM_SIZE {
+            let datagram = self.pending.split_to(DATAGRAM_SIZE);
+            self.socket.send_to(&datagram, self.target).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Waits for a datagram on `socket` without receiving it, and returns the
+/// address it came from.
+pub(crate) async fn wait_for_sender(socket: &UdpSocket) -> anyhow::Result<SocketAddr> {
+    let mut buf = [0; 1];
+    let (_, addr) = socket.peek_from(&mut buf).await?;
+
+    Ok(addr)
+}
+
+/// Returns the whole TS packets of a datagram, which may be an RTP packet.
+fn ts_payload(datagram: &[u8]) -> Option<&[u8]> {
+    let payload = if datagram.first() == Some(&SYNC_BYTE) {
+        datagram
+    } else {
+        let (_, _, payload) = parse_rtp(datagram)?;
+        payload
+    };
+
+    if payload.first() != Some(&SYNC_BYTE) {
+        return None;
+    }
+
+    Some(&payload[..payload.len() - payload.len() % TS_PACKET_SIZE])
+}
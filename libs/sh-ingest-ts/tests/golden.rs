@@ -0,0 +1,196 @@
+//! Muxes known frame sequences into MPEG-TS and compares the output with
+//! golden files in `tests/golden`, besides checking its packet structure.
+//!
+//! A missing golden file fails the test like a different one does. Run the
+//! tests with `UPDATE_GOLDEN=1` to write the golden files after an intended
+//! change to the output, and review the difference before committing them.
+
+use sh_ingest_ts::TsMuxer;
+use sh_media::{
+    testing::{audio_frame, audio_stream, video_stream, VIDEO_TIMEBASE},
+    Frame, Stream,
+};
+
+use std::collections::HashMap;
+
+/// About how large the video frames are, long enough to span a few TS
+/// packets.
+const VIDEO_FRAME_SIZE: usize = 400;
+
+const PACKET_SIZE: usize = 188;
+const PAT_PID: u16 = 0x0000;
+const PMT_PID: u16 = 0x1000;
+const VIDEO_PID: u16 = 0x0100;
+const AUDIO_PID: u16 = 0x0101;
+
+/// How far the timestamps of the muxer run ahead of its PCR.
+const MUX_DELAY: u64 = 63000;
+
+fn mux(streams: &[Stream], frames: &[Frame]) -> Vec<u8> {
+    let mut muxer = TsMuxer::new(streams);
+
+    frames
+        .iter()
+        .flat_map(|frame| muxer.mux(frame).unwrap())
+        .collect()
+}
+
+fn video_frames(stream: &Stream) -> Vec<Frame> {
+    sh_media::testing::video_frames(stream, VIDEO_FRAME_SIZE)
+}
+
+fn interleaved_frames(video: &Stream, audio: &Stream) -> Vec<Frame> {
+    sh_media::testing::interleaved_frames(video, audio, VIDEO_FRAME_SIZE)
+}
+
+fn assert_golden(name: &str, muxed: &[u8]) {
+    sh_media::testing::assert_golden(
+        concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden"),
+        name,
+        muxed,
+    );
+}
+
+/// The start of a PES packet in the transport stream.
+#[derive(Debug, PartialEq, Eq)]
+struct PesStart {
+    pid: u16,
+    pts: u64,
+    dts: Option<u64>,
+    pcr: Option<u64>,
+}
+
+fn read_timestamp(data: &[u8]) -> u64 {
+    (((data[0] >> 1) & 0x07) as u64) << 30
+        | (data[1] as u64) << 22
+        | ((data[2] >> 1) as u64) << 15
+        | (data[3] as u64) << 7
+        | (data[4] >> 1) as u64
+}
+
+/// Checks the packets of a transport stream, and returns the PIDs of its
+/// packets and the PES packets which start in them.
+fn read_packets(muxed: &[u8]) -> (Vec<u16>, Vec<PesStart>) {
+    assert_eq!(muxed.len() % PACKET_SIZE, 0);
+
+    let mut pids = Vec::new();
+    let mut starts = Vec::new();
+    let mut continuity = HashMap::new();
+
+    for packet in muxed.chunks(PACKET_SIZE) {
+        assert_eq!(packet[0], 0x47);
+
+        let pid = u16::from_be_bytes([packet[1] & 0x1f, packet[2]]);
+        let unit_start = packet[1] & 0x40 != 0;
+        let has_adaptation = packet[3] & 0x20 != 0;
+        let counter = packet[3] & 0x0f;
+        pids.push(pid);
+
+        // every packet carries a payload, so the counter always advances
+        if let Some(last) = continuity.insert(pid, counter) {
+            assert_eq!(counter, (last + 1) & 0x0f, "continuity of PID {:#x}", pid);
+        }
+
+        let mut payload = &packet[4..];
+        let mut pcr = None;
+        if has_adaptation {
+            let len = payload[0] as usize;
+            if len > 0 && payload[1] & 0x10 != 0 {
+                let base = u32::from_be_bytes([payload[2], payload[3], payload[4], payload[5]]);
+                pcr = Some((base as u64) << 1 | (payload[6] >> 7) as u64);
+            }
+            payload = &payload[1 + len..];
+        }
+
+        if unit_start && (pid == VIDEO_PID || pid == AUDIO_PID) {
+            assert_eq!(&payload[..3], &[0x00, 0x00, 0x01]);
+
+            let flags = payload[7] >> 6;
+            starts.push(PesStart {
+                pid,
+                pts: read_timestamp(&payload[9..]),
+                dts: (flags == 0b11).then(|| read_timestamp(&payload[14..])),
+                pcr,
+            });
+        }
+    }
+
+    (pids, starts)
+}
+
+#[test]
+fn video_with_reordered_frames() {
+    let video = video_stream();
+    let frames = video_frames(&video);
+
+    let muxed = mux(&[video], &frames);
+    let (pids, starts) = read_packets(&muxed);
+
+    // receivers can start at every keyframe
+    assert_eq!(&pids[..2], &[PAT_PID, PMT_PID]);
+    assert_eq!(pids.iter().filter(|&&pid| pid == PAT_PID).count(), 2);
+
+    assert_eq!(starts.len(), frames.len());
+    for (start, frame) in starts.iter().zip(&frames) {
+        let pts = frame.time.pts + MUX_DELAY;
+        let dts = frame.time.decode_time() + MUX_DELAY;
+
+        assert_eq!(
+            start,
+            &PesStart {
+                pid: VIDEO_PID,
+                pts,
+                dts: Some(dts).filter(|&dts| dts != pts),
+                pcr: Some(dts - MUX_DELAY),
+            }
+        );
+    }
+
+    assert_golden("video_with_reordered_frames.ts", &muxed);
+}
+
+#[test]
+fn video_and_audio() {
+    let (video, audio) = (video_stream(), audio_stream());
+    let frames = interleaved_frames(&video, &audio);
+
+    let muxed = mux(&[video, audio], &frames);
+    let (_, starts) = read_packets(&muxed);
+
+    assert_eq!(starts.len(), frames.len());
+    for (start, frame) in starts.iter().zip(&frames) {
+        let pts = frame.time.in_base(VIDEO_TIMEBASE).pts + MUX_DELAY;
+        assert_eq!(start.pts, pts);
+
+        if frame.stream.is_video() {
+            assert_eq!(start.pid, VIDEO_PID);
+        } else {
+            // the PCR is carried by the video
+            assert_eq!(start.pid, AUDIO_PID);
+            assert_eq!(start.dts, None);
+            assert_eq!(start.pcr, None);
+        }
+    }
+
+    assert_golden("video_and_audio.ts", &muxed);
+}
+
+#[test]
+fn audio_only() {
+    let audio = audio_stream();
+    let frames = (0..50).map(|i| audio_frame(&audio, i)).collect::<Vec<_>>();
+
+    let muxed = mux(&[audio], &frames);
+    let (pids, starts) = read_packets(&muxed);
+
+    // without keyframes the tables are repeated every few frames
+    assert_eq!(&pids[..2], &[PAT_PID, PMT_PID]);
+    assert!(pids.iter().filter(|&&pid| pid == PAT_PID).count() > 1);
+
+    for (start, frame) in starts.iter().zip(&frames) {
+        let pts = frame.time.in_base(VIDEO_TIMEBASE).pts;
+        assert_eq!(start.pcr, Some(pts));
+    }
+
+    assert_golden("audio_only.ts", &muxed);
+}
@@ -0,0 +1,317 @@
+use bytes::Bytes;
+use h264_reader::{
+    nal::{sps::SeqParameterSet, UnitType},
+    rbsp::decode_nal,
+};
+use sh_media::{
+    frame_nal_units, nut_header, parse_bitstream, BitstreamFraming, CodecInfo, CodecTypeInfo,
+    Fraction, Frame, FrameDependency, FrameReadFilter, MediaTime, OpusHeader, Stream,
+    VideoCodecInfo, VideoCodecSpecificInfo, Vp9FrameHeader, OPUS_SAMPLE_RATE,
+};
+use tokio::sync::{mpsc, watch};
+use tracing::*;
+use webrtc::peer_connection::RTCPeerConnection;
+
+use std::{sync::Arc, time::Instant};
+
+use crate::VIDEO_CLOCK_RATE;
+
+const VIDEO_TIMEBASE: Fraction = Fraction::new(1, VIDEO_CLOCK_RATE);
+const AUDIO_TIMEBASE: Fraction = Fraction::new(1, OPUS_SAMPLE_RATE);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SampleCodec {
+    H264,
+    Vp9,
+    /// Opus with a number of channels.
+    Opus(u8),
+}
+
+/// An access unit of the video, in Annex B format for H.264, or an Opus
+/// packet, with the RTP timestamp of its packets.
+pub(crate) struct Sample {
+    pub(crate) codec: SampleCodec,
+    pub(crate) timestamp: u32,
+    pub(crate) data: Bytes,
+}
+
+/// A pull filter which reads the H.264 or VP9 video and Opus audio of a
+/// WHIP publisher.
+pub struct WhipReadFilter {
+    samples: mpsc::Receiver<Sample>,
+    closed: watch::Receiver<bool>,
+    /// Closed when the filter is dropped, so the publisher notices that the
+    /// stream has ended.
+    peer: Arc<RTCPeerConnection>,
+
+    video_stream: Option<Stream>,
+    audio_stream: Option<Stream>,
+
+    /// When the first sample arrived, which the stream starts at.
+    start: Option<Instant>,
+    video_clock: RtpClock,
+    audio_clock: RtpClock,
+}
+
+impl WhipReadFilter {
+    pub(crate) fn new(
+        samples: mpsc::Receiver<Sample>,
+        closed: watch::Receiver<bool>,
+        peer: Arc<RTCPeerConnection>,
+    ) -> Self {
+        WhipReadFilter {
+            samples,
+            closed,
+            peer,
+            video_stream: None,
+            audio_stream: None,
+            start: None,
+            video_clock: RtpClock::new(VIDEO_TIMEBASE),
+            audio_clock: RtpClock::new(AUDIO_TIMEBASE),
+        }
+    }
+
+    async fn next_sample(&mut self) -> anyhow::Result<Sample> {
+        tokio::select! {
+            sample = self.samples.recv() => {
+                sample.ok_or_else(|| anyhow::anyhow!("WHIP session has no video"))
+            }
+            _ = self.closed.changed() => anyhow::bail!("WHIP session closed"),
+        }
+    }
+
+    fn frame(&mut self, sample: Sample) -> Option<Frame> {
+        let start = *self.start.get_or_insert_with(Instant::now);
+
+        match sample.codec {
+            SampleCodec::Opus(channels) => {
+                let time = self.audio_clock.time(sample.timestamp, start);
+                self.audio_frame(channels, time, sample.data)
+            }
+            _ => {
+                let time = self.video_clock.time(sample.timestamp, start);
+                self.video_frame(sample.codec, time, sample.data)
+            }
+        }
+    }
+
+    fn video_frame(&mut self, codec: SampleCodec, time: u64, data: Bytes) -> Option<Frame> {
+        let (is_keyframe, buffer) = match codec {
+            SampleCodec::Vp9 => self.vp9_frame(data)?,
+            _ => self.h264_frame(data),
+        };
+        let stream = self.video_stream.clone()?;
+
+        Some(Frame {
+            time: MediaTime {
+                pts: time,
+                dts: None,
+                timebase: VIDEO_TIMEBASE,
+            },
+            dependency: if is_keyframe {
+                FrameDependency::None
+            } else {
+                FrameDependency::Backwards
+            },
+            buffer,
+            stream,
+            received: Instant::now(),
+            metadata: None,
+        })
+    }
+
+    /// Makes a frame of an Opus packet. Audio which starts after the video
+    /// is dropped, since the streams can't change once they are started.
+    fn audio_frame(&mut self, channels: u8, time: u64, buffer: Bytes) -> Option<Frame> {
+        if self.audio_stream.is_none() && self.video_stream.is_none() {
+            let codec = OpusHeader::new(channels).codec_info();
+            debug!("Got audio parameters {:?}", codec);
+
+            self.audio_stream = Some(Stream {
+                id: 1,
+                codec: Arc::new(codec),
+                timebase: AUDIO_TIMEBASE,
+            });
+        }
+
+        Some(Frame {
+            time: MediaTime {
+                pts: time,
+                dts: None,
+                timebase: AUDIO_TIMEBASE,
+            },
+            dependency: FrameDependency::None,
+            buffer,
+            stream: self.audio_stream.clone()?,
+            received: Instant::now(),
+            metadata: None,
+        })
+    }
+
+    /// Converts an H.264 access unit to NAL units prefixed by 4 byte
+    /// lengths, returning whether it is a keyframe.
+    fn h264_frame(&mut self, data: Bytes) -> (bool, Bytes) {
+        let nal_units = parse_bitstream(data, BitstreamFraming::FourByteStartCode)
+            .into_iter()
+            .filter(|nal| !nal.is_empty())
+            .filter(|nal| nut_header(nal) != Some(UnitType::AccessUnitDelimiter))
+            .collect::<Vec<_>>();
+
+        if let Some(codec) = get_video_codec_info(&nal_units) {
+            let changed = self
+                .video_stream
+                .as_ref()
+                .map(|s| s.parameter_sets() != codec.video().and_then(|v| v.parameter_sets()))
+                .unwrap_or(true);
+
+            if changed {
+                debug!("Got video parameters {:?}", codec);
+                self.video_stream = Some(Stream {
+                    id: 0,
+                    codec: Arc::new(codec),
+                    timebase: VIDEO_TIMEBASE,
+                });
+            }
+        }
+
+        let is_keyframe = nal_units
+            .iter()
+            .any(|nal| nut_header(nal) == Some(UnitType::SliceLayerWithoutPartitioningIdr));
+
+        (
+            is_keyframe,
+            frame_nal_units(&nal_units[..], BitstreamFraming::FourByteLength).freeze(),
+        )
+    }
+
+    /// Reads the format of a VP9 frame from its header, returning whether it
+    /// is a keyframe. Frames with a broken header are dropped.
+    fn vp9_frame(&mut self, data: Bytes) -> Option<(bool, Bytes)> {
+        let header = Vp9FrameHeader::parse(&data)?;
+
+        if let Some(codec) = header.codec_info() {
+            let stream = Stream {
+                id: 0,
+                codec: Arc::new(codec),
+                timebase: VIDEO_TIMEBASE,
+            };
+
+            if !matches!(&self.video_stream, Some(s) if s.is_compatible_with(&stream)) {
+                debug!("Got video parameters {:?}", stream.codec);
+                self.video_stream = Some(stream);
+            }
+        }
+
+        Some((header.keyframe.is_some(), data))
+    }
+}
+
+#[async_trait::async_trait]
+impl FrameReadFilter for WhipReadFilter {
+    async fn start(&mut self) -> anyhow::Result<Vec<Stream>> {
+        // browsers send the parameter sets, or the VP9 format, along with
+        // every keyframe
+        while self.video_stream.is_none() {
+            let sample = self.next_sample().await?;
+            self.frame(sample);
+        }
+
+        Ok(self
+            .video_stream
+            .iter()
+            .chain(self.audio_stream.iter())
+            .cloned()
+            .collect())
+    }
+
+    async fn read(&mut self) -> anyhow::Result<Frame> {
+        loop {
+            let sample = self.next_sample().await?;
+
+            if let Some(frame) = self.frame(sample) {
+                return Ok(frame);
+            }
+        }
+    }
+}
+
+impl Drop for WhipReadFilter {
+    fn drop(&mut self) {
+        let peer = self.peer.clone();
+        tokio::spawn(async move {
+            if let Err(e) = peer.close().await {
+                debug!("Failed to close WHIP peer connection: {:?}", e);
+            }
+        });
+    }
+}
+
+/// Converts the RTP timestamps of a track to a timeline which doesn't wrap
+/// around, and starts at the time the first sample of any track arrived,
+/// since the tracks have unrelated RTP timestamps.
+struct RtpClock {
+    timebase: Fraction,
+    /// The RTP timestamp which the stream would have started at.
+    origin: Option<u64>,
+    last_timestamp: Option<u32>,
+    /// Added to the RTP timestamps for each time they have wrapped around.
+    offset: u64,
+}
+
+impl RtpClock {
+    fn new(timebase: Fraction) -> Self {
+        RtpClock {
+            timebase,
+            origin: None,
+            last_timestamp: None,
+            offset: 0,
+        }
+    }
+
+    fn time(&mut self, timestamp: u32, start: Instant) -> u64 {
+        if let Some(last) = self.last_timestamp {
+            if last > timestamp && last - timestamp > u32::MAX / 2 {
+                self.offset += 1 << 32;
+            }
+        }
+        self.last_timestamp = Some(timestamp);
+
+        let timestamp = timestamp as u64 + self.offset;
+        let rate = self.timebase.denominator as u64;
+        let origin = *self.origin.get_or_insert_with(|| {
+            let since_start = start.elapsed().as_micros() as u64 * rate / 1_000_000;
+
+            timestamp.saturating_sub(since_start)
+        });
+
+        timestamp.saturating_sub(origin)
+    }
+}
+
+fn get_video_codec_info(nal_units: &[Bytes]) -> Option<CodecInfo> {
+    let sps = nal_units
+        .iter()
+        .find(|nal| nut_header(nal) == Some(UnitType::SeqParameterSet))?;
+    let pps = nal_units
+        .iter()
+        .find(|nal| nut_header(nal) == Some(UnitType::PicParameterSet))?;
+
+    let parsed = SeqParameterSet::from_bytes(&decode_nal(&sps[1..])).ok()?;
+    let (width, height) = parsed.pixel_dimensions().ok()?;
+
+    Some(CodecInfo {
+        name: "h264",
+        properties: CodecTypeInfo::Video(VideoCodecInfo {
+            width,
+            height,
+            extra: VideoCodecSpecificInfo::H264 {
+                bitstream_format: BitstreamFraming::FourByteLength,
+                profile_indication: parsed.profile_idc.into(),
+                profile_compatibility: parsed.constraint_flags.into(),
+                level_indication: parsed.level_idc,
+                sps: Arc::new(sps.to_vec()),
+                pps: Arc::new(pps.to_vec()),
+            },
+        }),
+    })
+}
@@ -0,0 +1,352 @@
+use sh_media::OPUS_SAMPLE_RATE;
+use tokio::sync::{mpsc, watch};
+use tracing::*;
+use webrtc::{
+    api::{
+        interceptor_registry::register_default_interceptors,
+        media_engine::{MediaEngine, MIME_TYPE_H264, MIME_TYPE_OPUS, MIME_TYPE_VP9},
+        APIBuilder, API,
+    },
+    interceptor::registry::Registry,
+    media::io::sample_builder::SampleBuilder,
+    peer_connection::{
+        configuration::RTCConfiguration, peer_connection_state::RTCPeerConnectionState,
+        sdp::session_description::RTCSessionDescription, RTCPeerConnection,
+    },
+    rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication,
+    rtp::{
+        codecs::{h264::H264Packet, opus::OpusPacket, vp9::Vp9Packet},
+        packetizer::Depacketizer,
+    },
+    rtp_transceiver::{
+        rtp_codec::{RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType},
+        RTCPFeedback,
+    },
+    track::track_remote::TrackRemote,
+};
+
+use std::{
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+mod filter;
+
+pub use filter::*;
+
+/// How often the publisher is asked for a keyframe. Browsers only send
+/// keyframes when they are asked for one, but viewers can only join a
+/// stream at a keyframe.
+const KEYFRAME_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How many packets a video sample waits for its late packets.
+const MAX_LATE_PACKETS: u16 = 256;
+
+const VIDEO_CLOCK_RATE: u32 = 90000;
+
+/// A WebRTC session of a publisher, set up from the SDP offer of a WHIP
+/// request.
+pub struct WhipSession {
+    peer: Arc<RTCPeerConnection>,
+}
+
+impl WhipSession {
+    /// Answers the SDP offer of a publisher, returning the session, the SDP
+    /// answer and a filter which reads the published video and audio.
+    ///
+    /// Only H.264 or VP9 video and Opus audio are negotiated.
+    pub async fn accept(offer: String) -> anyhow::Result<(Self, String, WhipReadFilter)> {
+        let peer = Arc::new(
+            build_api()?
+                .new_peer_connection(RTCConfiguration::default())
+                .await?,
+        );
+
+        let (samples, samples_recv) = mpsc::channel(256);
+        let (closed, closed_recv) = watch::channel(false);
+
+        peer.on_peer_connection_state_change(Box::new(move |state| {
+            debug!("WHIP peer connection is {}", state);
+
+            if matches!(
+                state,
+                RTCPeerConnectionState::Disconnected
+                    | RTCPeerConnectionState::Failed
+                    | RTCPeerConnectionState::Closed
+            ) {
+                let _ = closed.send(true);
+            }
+
+            Box::pin(async {})
+        }))
+        .await;
+
+        let weak_peer = Arc::downgrade(&peer);
+        peer.on_track(Box::new(move |track, _receiver| {
+            if let Some(track) = track {
+                tokio::spawn(read_track(track, weak_peer.clone(), samples.clone()));
+            }
+
+            Box::pin(async {})
+        }))
+        .await;
+
+        peer.set_remote_description(RTCSessionDescription::offer(offer)?)
+            .await?;
+        let answer = peer.create_answer(None).await?;
+
+        // the answer is sent once every ICE candidate is known, WHIP clients
+        // don't have to support trickling candidates
+        let mut gathered = peer.gathering_complete_promise().await;
+        peer.set_local_description(answer).await?;
+        let _ = gathered.recv().await;
+
+        let answer = peer
+            .local_description()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("WHIP session has no local description"))?;
+
+        let filter = WhipReadFilter::new(samples_recv, closed_recv, peer.clone());
+
+        Ok((WhipSession { peer }, answer.sdp, filter))
+    }
+
+    /// Ends the session, which ends the stream it publishes.
+    pub async fn close(&self) {
+        if let Err(e) = self.peer.close().await {
+            warn!("Failed to close WHIP session: {:?}", e);
+        }
+    }
+}
+
+/// Builds a WebRTC API which only negotiates the codecs that can be
+/// ingested.
+fn build_api() -> anyhow::Result<API> {
+    let mut media = MediaEngine::default();
+
+    let feedback = vec![
+        RTCPFeedback {
+            typ: "nack".into(),
+            parameter: "".into(),
+        },
+        RTCPFeedback {
+            typ: "nack".into(),
+            parameter: "pli".into(),
+        },
+    ];
+
+    media.register_codec(
+        RTCRtpCodecParameters {
+            capability: RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_H264.into(),
+                clock_rate: VIDEO_CLOCK_RATE,
+                channels: 0,
+                sdp_fmtp_line:
+                    "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f".into(),
+                rtcp_feedback: feedback.clone(),
+            },
+            payload_type: 102,
+            ..Default::default()
+        },
+        RTPCodecType::Video,
+    )?;
+    media.register_codec(
+        RTCRtpCodecParameters {
+            capability: RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_VP9.into(),
+                clock_rate: VIDEO_CLOCK_RATE,
+                channels: 0,
+                sdp_fmtp_line: "profile-id=0".into(),
+                rtcp_feedback: feedback,
+            },
+            payload_type: 98,
+            ..Default::default()
+        },
+        RTPCodecType::Video,
+    )?;
+    media.register_codec(
+        RTCRtpCodecParameters {
+            capability: RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_OPUS.into(),
+                clock_rate: OPUS_SAMPLE_RATE,
+                channels: 2,
+                sdp_fmtp_line: "minptime=10;useinbandfec=1".into(),
+                rtcp_feedback: vec![],
+            },
+            payload_type: 111,
+            ..Default::default()
+        },
+        RTPCodecType::Audio,
+    )?;
+
+    let registry = register_default_interceptors(Registry::new(), &mut media)?;
+
+    Ok(APIBuilder::new()
+        .with_media_engine(media)
+        .with_interceptor_registry(registry)
+        .build())
+}
+
+/// Reads the RTP packets of a track, sending the access units of a video
+/// track or the packets of an audio track to the filter.
+async fn read_track(
+    track: Arc<TrackRemote>,
+    peer: Weak<RTCPeerConnection>,
+    samples: mpsc::Sender<Sample>,
+) {
+    if track.kind() != RTPCodecType::Video {
+        // Opus is the only audio codec which is negotiated
+        let channels = track.codec().await.capability.channels.clamp(1, 2) as u8;
+        let builder = SampleBuilder::new(MAX_LATE_PACKETS, OpusPacket::default(), OPUS_SAMPLE_RATE);
+        read_samples(&track, builder, SampleCodec::Opus(channels), &samples).await;
+        return;
+    }
+
+    let media_ssrc = track.ssrc();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(KEYFRAME_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let peer = match peer.upgrade() {
+                Some(peer) => peer,
+                None => break,
+            };
+            let pli = PictureLossIndication {
+                sender_ssrc: 0,
+                media_ssrc,
+            };
+            if peer.write_rtcp(&[Box::new(pli)]).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mime_type = track.codec().await.capability.mime_type;
+    if mime_type.eq_ignore_ascii_case(MIME_TYPE_VP9) {
+        let builder = SampleBuilder::new(MAX_LATE_PACKETS, Vp9Packet::default(), VIDEO_CLOCK_RATE);
+        read_samples(&track, builder, SampleCodec::Vp9, &samples).await;
+    } else {
+        let builder = SampleBuilder::new(MAX_LATE_PACKETS, H264Packet::default(), VIDEO_CLOCK_RATE);
+        read_samples(&track, builder, SampleCodec::H264, &samples).await;
+    }
+}
+
+/// Depacketizes the RTP packets of a track into samples until the track or
+/// the filter ends.
+async fn read_samples<T: Depacketizer>(
+    track: &TrackRemote,
+    mut builder: SampleBuilder<T>,
+    codec: SampleCodec,
+    samples: &mpsc::Sender<Sample>,
+) {
+    while let Ok((packet, _)) = track.read_rtp().await {
+        builder.push(packet);
+
+        while let Some(sample) = builder.pop() {
+            let sample = Sample {
+                codec,
+                timestamp: sample.packet_timestamp,
+                data: sample.data,
+            };
+
+            if samples.send(sample).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+const TEST_OFFER: &[&str] = &[
+    "v=0",
+    "o=- 4215775240449105457 2 IN IP4 127.0.0.1",
+    "s=-",
+    "t=0 0",
+    "a=group:BUNDLE 0 1",
+    "a=fingerprint:sha-256 0F:74:31:25:CB:A2:13:EC:28:6F:6D:2C:61:FF:5D:C2:\
+     BC:B9:DB:3D:98:14:8D:1A:BB:EA:33:0C:A4:60:A8:8E",
+    "m=audio 9 UDP/TLS/RTP/SAVPF 111",
+    "c=IN IP4 0.0.0.0",
+    "a=ice-ufrag:EsAw",
+    "a=ice-pwd:bP+XJMM09aR8AiX1jdukzR6Y",
+    "a=setup:actpass",
+    "a=mid:0",
+    "a=sendonly",
+    "a=rtcp-mux",
+    "a=rtpmap:111 opus/48000/2",
+    "m=video 9 UDP/TLS/RTP/SAVPF 98",
+    "c=IN IP4 0.0.0.0",
+    "a=ice-ufrag:EsAw",
+    "a=ice-pwd:bP+XJMM09aR8AiX1jdukzR6Y",
+    "a=setup:actpass",
+    "a=mid:1",
+    "a=sendonly",
+    "a=rtcp-mux",
+    "a=rtpmap:98 VP9/90000",
+];
+
+#[cfg(test)]
+fn test_offer(lines: &[&str]) -> String {
+    lines.iter().map(|line| format!("{}\r\n", line)).collect()
+}
+
+#[tokio::test]
+async fn answers_offers() {
+    let (session, answer, _filter) = WhipSession::accept(test_offer(TEST_OFFER)).await.unwrap();
+
+    assert!(answer.contains("opus/48000"));
+    assert!(answer.contains("VP9/90000"));
+
+    session.close().await;
+}
+
+#[tokio::test]
+async fn truncated_offers_are_rejected() {
+    let offer = test_offer(TEST_OFFER);
+
+    let truncated = [
+        String::new(),
+        offer[..offer.find("s=-").unwrap()].to_string(),
+        offer[..offer.find("m=audio").unwrap() + 7].to_string(),
+    ];
+
+    for offer in truncated {
+        assert!(
+            WhipSession::accept(offer.clone()).await.is_err(),
+            "{:?}",
+            offer
+        );
+    }
+}
+
+#[tokio::test]
+async fn malformed_offers_are_rejected() {
+    let without = |prefix: &str| {
+        let lines: Vec<&str> = TEST_OFFER
+            .iter()
+            .copied()
+            .filter(|line| !line.starts_with(prefix))
+            .collect();
+        test_offer(&lines)
+    };
+
+    let malformed = [
+        "hello".to_string(),
+        test_offer(&["v=0", "this is not sdp"]),
+        test_offer(TEST_OFFER).replace("m=audio 9", "m=audio nine"),
+        test_offer(TEST_OFFER).replace("v=0", "v=1"),
+        // DTLS and ICE can't be set up without these
+        without("a=fingerprint"),
+        without("a=ice-ufrag"),
+    ];
+
+    for offer in malformed {
+        assert!(
+            WhipSession::accept(offer.clone()).await.is_err(),
+            "{:?}",
+            offer
+        );
+    }
+}
@@ -0,0 +1,222 @@
+use std::sync::Arc;
+
+use crate::{bits::BitReader, CodecInfo, CodecTypeInfo, VideoCodecInfo, VideoCodecSpecificInfo};
+
+const OBU_SEQUENCE_HEADER: u8 = 1;
+
+/// The `AV1CodecConfigurationRecord` of an AV1 stream, which is the content
+/// of the `av1C` box and what Enhanced RTMP starts a stream with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Av1CodecConfiguration {
+    pub seq_profile: u8,
+    pub seq_level_idx_0: u8,
+    pub seq_tier_0: bool,
+    pub high_bitdepth: bool,
+    pub twelve_bit: bool,
+    pub monochrome: bool,
+    pub chroma_subsampling_x: bool,
+    pub chroma_subsampling_y: bool,
+    pub chroma_sample_position: u8,
+    pub initial_presentation_delay_minus_one: Option<u8>,
+    /// The OBUs which configure the decoder, where the first is the
+    /// sequence header.
+    pub config_obus: Vec<u8>,
+}
+
+impl Av1CodecConfiguration {
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        if data.len() < 4 {
+            anyhow::bail!("AV1 codec configuration is too short");
+        }
+        if data[0] != 0x81 {
+            anyhow::bail!(
+                "Unsupported AV1 codec configuration version {}",
+                data[0] & 0x7f
+            );
+        }
+
+        let initial_presentation_delay_minus_one = if data[3] & 0x10 != 0 {
+            Some(data[3] & 0x0f)
+        } else {
+            None
+        };
+
+        Ok(Av1CodecConfiguration {
+            seq_profile: data[1] >> 5,
+            seq_level_idx_0: data[1] & 0x1f,
+            seq_tier_0: data[2] & 0x80 != 0,
+            high_bitdepth: data[2] & 0x40 != 0,
+            twelve_bit: data[2] & 0x20 != 0,
+            monochrome: data[2] & 0x10 != 0,
+            chroma_subsampling_x: data[2] & 0x08 != 0,
+            chroma_subsampling_y: data[2] & 0x04 != 0,
+            chroma_sample_position: data[2] & 0x03,
+            initial_presentation_delay_minus_one,
+            config_obus: data[4..].to_vec(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(4 + self.config_obus.len());
+
+        // marker and version 1
+        data.push(0x81);
+        data.push((self.seq_profile << 5) | self.seq_level_idx_0);
+        data.push(
+            ((self.seq_tier_0 as u8) << 7)
+                | ((self.high_bitdepth as u8) << 6)
+                | ((self.twelve_bit as u8) << 5)
+                | ((self.monochrome as u8) << 4)
+                | ((self.chroma_subsampling_x as u8) << 3)
+                | ((self.chroma_subsampling_y as u8) << 2)
+                | self.chroma_sample_position,
+        );
+        data.push(match self.initial_presentation_delay_minus_one {
+            Some(delay) => 0x10 | delay,
+            None => 0,
+        });
+        data.extend_from_slice(&self.config_obus);
+
+        data
+    }
+
+    pub fn bit_depth(&self) -> u8 {
+        match (self.high_bitdepth, self.twelve_bit) {
+            (true, true) => 12,
+            (true, false) => 10,
+            _ => 8,
+        }
+    }
+
+    /// Returns the codec string of the stream, like `av01.0.08M.08`, as in
+    /// the AV1 codec ISO media file format binding.
+    pub fn codec_string(&self) -> String {
+        format!(
+            "av01.{}.{:02}{}.{:02}",
+            self.seq_profile,
+            self.seq_level_idx_0,
+            if self.seq_tier_0 { 'H' } else { 'M' },
+            self.bit_depth()
+        )
+    }
+
+    /// Makes the codec info of the stream, with the size of the video from
+    /// its sequence header.
+    pub fn codec_info(self) -> anyhow::Result<CodecInfo> {
+        let sequence_header = obus(&self.config_obus)
+            .find(|(obu_type, _)| *obu_type == OBU_SEQUENCE_HEADER)
+            .ok_or_else(|| anyhow::anyhow!("AV1 codec configuration has no sequence header"))?;
+        let (width, height) = max_frame_size(sequence_header.1)
+            .ok_or_else(|| anyhow::anyhow!("Invalid AV1 sequence header"))?;
+
+        Ok(CodecInfo {
+            name: "av1",
+            properties: CodecTypeInfo::Video(VideoCodecInfo {
+                width,
+                height,
+                extra: VideoCodecSpecificInfo::Av1 {
+                    config: Arc::new(self),
+                },
+            }),
+        })
+    }
+}
+
+/// Returns the `av1C` content of a stream.
+pub fn av1_decoder_configuration(extra: &VideoCodecSpecificInfo) -> Option<Vec<u8>> {
+    match extra {
+        VideoCodecSpecificInfo::Av1 { config } => Some(config.to_bytes()),
+        _ => None,
+    }
+}
+
+/// Returns the types and payloads of the OBUs in the low overhead bitstream
+/// format, where every OBU has its size.
+fn obus(mut data: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    std::iter::from_fn(move || {
+        let header = *data.first()?;
+        let has_extension = header & 0x04 != 0;
+        let has_size = header & 0x02 != 0;
+        if !has_size {
+            return None;
+        }
+
+        let mut pos = if has_extension { 2 } else { 1 };
+        let mut size = 0usize;
+        for i in 0..8 {
+            let byte = *data.get(pos)?;
+            pos += 1;
+
+            size |= ((byte & 0x7f) as usize) << (i * 7);
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+
+        let payload = data.get(pos..pos.checked_add(size)?)?;
+        data = &data[pos + size..];
+
+        Some(((header >> 3) & 0x0f, payload))
+    })
+}
+
+/// Reads the largest width and height of the frames from a sequence header
+/// OBU, which is the size of the video.
+fn max_frame_size(sequence_header: &[u8]) -> Option<(u32, u32)> {
+    let mut r = BitReader::new(sequence_header);
+
+    let _seq_profile = r.read(3)?;
+    let _still_picture = r.read(1)?;
+    let reduced_still_picture_header = r.read(1)? == 1;
+
+    if reduced_still_picture_header {
+        let _seq_level_idx = r.read(5)?;
+    } else {
+        let timing_info_present = r.read(1)? == 1;
+        let mut buffer_delay_length = 0;
+        let mut decoder_model_info_present = false;
+
+        if timing_info_present {
+            let _num_units_in_display_tick = r.read(32)?;
+            let _time_scale = r.read(32)?;
+            if r.read(1)? == 1 {
+                let _num_ticks_per_picture_minus_1 = r.read_uvlc()?;
+            }
+
+            decoder_model_info_present = r.read(1)? == 1;
+            if decoder_model_info_present {
+                buffer_delay_length = r.read(5)? as usize + 1;
+                let _num_units_in_decoding_tick = r.read(32)?;
+                let _buffer_removal_time_length_minus_1 = r.read(5)?;
+                let _frame_presentation_time_length_minus_1 = r.read(5)?;
+            }
+        }
+
+        let initial_display_delay_present = r.read(1)? == 1;
+        let operating_points = r.read(5)? + 1;
+        for _ in 0..operating_points {
+            let _operating_point_idc = r.read(12)?;
+            let seq_level_idx = r.read(5)?;
+            if seq_level_idx > 7 {
+                let _seq_tier = r.read(1)?;
+            }
+
+            if decoder_model_info_present && r.read(1)? == 1 {
+                let _decoder_buffer_delay = r.read(buffer_delay_length)?;
+                let _encoder_buffer_delay = r.read(buffer_delay_length)?;
+                let _low_delay_mode_flag = r.read(1)?;
+            }
+
+            if initial_display_delay_present && r.read(1)? == 1 {
+                let _initial_display_delay_minus_1 = r.read(4)?;
+            }
+        }
+    }
+
+    let width_bits = r.read(4)? as usize + 1;
+    let height_bits = r.read(4)? as usize + 1;
+    let width = r.read(width_bits)? + 1;
+    let height = r.read(height_bits)? + 1;
+
+    Some((width, height))
+}
@@ -0,0 +1,124 @@
+/// Reads the bits of codec headers, most significant bit first.
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    /// The position in bits.
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        BitReader { data, pos: 0 }
+    }
+
+    /// Reads a number of up to 32 bits.
+    pub fn read(&mut self, bits: usize) -> Option<u32> {
+        let mut value: u64 = 0;
+
+        for _ in 0..bits {
+            let byte = self.data.get(self.pos / 8)?;
+            let bit = (byte >> (7 - self.pos % 8)) & 1;
+
+            value = (value << 1) | bit as u64;
+            self.pos += 1;
+        }
+
+        u32::try_from(value).ok()
+    }
+
+    pub fn skip(&mut self, bits: usize) -> Option<()> {
+        let pos = self.pos.checked_add(bits)?;
+
+        if pos <= self.data.len() * 8 {
+            self.pos = pos;
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    /// Reads an unsigned Exp-Golomb code, as in `ue(v)` of the H.264 and
+    /// H.265 specifications.
+    pub fn read_ue(&mut self) -> Option<u32> {
+        let mut leading_zeros = 0;
+        while self.read(1)? == 0 {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return None;
+            }
+        }
+
+        Some(((1u64 << leading_zeros) - 1 + self.read(leading_zeros)? as u64) as u32)
+    }
+
+    /// Reads a variable length unsigned number, as in `uvlc()` of the AV1
+    /// specification, which saturates rather than failing.
+    pub fn read_uvlc(&mut self) -> Option<u32> {
+        let mut leading_zeros = 0;
+        while self.read(1)? == 0 {
+            leading_zeros += 1;
+            if leading_zeros >= 32 {
+                return Some(u32::MAX);
+            }
+        }
+
+        Some(((1u64 << leading_zeros) - 1 + self.read(leading_zeros)? as u64) as u32)
+    }
+}
+
+/// Reads the payload type or size of a SEI message, which is coded as a
+/// run of 0xff bytes added to the byte after them.
+pub(crate) fn read_sei_value(data: &mut &[u8]) -> Option<u32> {
+    let mut value = 0u32;
+
+    loop {
+        let (&byte, rest) = data.split_first()?;
+        *data = rest;
+        value = value.checked_add(byte as u32)?;
+
+        if byte != 0xff {
+            return Some(value);
+        }
+    }
+}
+
+/// Removes the bytes which keep start codes out of a H.264 or H.265 NAL
+/// unit, which leaves its RBSP.
+pub(crate) fn remove_emulation_prevention(data: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+
+    for &byte in data {
+        if zeros >= 2 && byte == 3 {
+            zeros = 0;
+            continue;
+        }
+
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+
+    rbsp
+}
+
+#[test]
+fn reads_bits_and_exp_golomb_codes() {
+    // 101, then ue(v) 00101 = 4 and 1 = 0
+    let mut r = BitReader::new(&[0b1010_0101, 0b1000_0000]);
+    assert_eq!(r.read(3), Some(0b101));
+    assert_eq!(r.read_ue(), Some(4));
+    assert_eq!(r.read_ue(), Some(0));
+
+    assert_eq!(r.skip(7), Some(()));
+    assert_eq!(r.read(1), None);
+    assert_eq!(r.skip(usize::MAX), None);
+}
+
+#[test]
+fn removes_emulation_prevention_bytes() {
+    let rbsp = remove_emulation_prevention(&[0x00, 0x00, 0x03, 0x01, 0x00, 0x00, 0x03]);
+    assert_eq!(rbsp, [0x00, 0x00, 0x01, 0x00, 0x00]);
+
+    let mut data = &[0xff, 0xff, 0x02, 0x07][..];
+    assert_eq!(read_sei_value(&mut data), Some(512));
+    assert_eq!(data, [0x07]);
+}
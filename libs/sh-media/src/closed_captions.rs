@@ -0,0 +1,678 @@
+use std::collections::BTreeMap;
+
+use crate::Frame;
+
+/// The number of rows of the caption screen of CEA-608.
+const ROWS: usize = 15;
+
+/// The number of columns of the caption screen of CEA-608.
+const COLUMNS: usize = 32;
+
+/// How many frames of caption data are held back to be put in presentation
+/// order at most, so a stream with broken timestamps can't grow it.
+const MAX_PENDING: usize = 64;
+
+/// The most lines a CEA-708 caption is shown with, like roll-up captions.
+const MAX_708_LINES: usize = 4;
+
+/// A `cc_data` triplet of ATSC A/53, which carries two bytes of CEA-608 or
+/// CEA-708 caption data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CcData {
+    /// 0 and 1 are CEA-608 data of the first and second field, 2 and 3 are
+    /// the continuation and start of a CEA-708 DTVCC packet.
+    pub cc_type: u8,
+    pub data: [u8; 2],
+}
+
+/// The captions on screen from `pts` on, in the timebase of the video they
+/// were carried in. An empty text clears the screen.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClosedCaption {
+    pub pts: u64,
+    pub text: String,
+}
+
+/// Decodes the closed captions of a video stream from the caption data of
+/// its frames, which is in decode order.
+pub(crate) struct CaptionExtractor {
+    /// The caption data of frames by their presentation time, until every
+    /// frame before them was decoded.
+    pending: BTreeMap<u64, Vec<CcData>>,
+    decoder: ClosedCaptionDecoder,
+}
+
+impl CaptionExtractor {
+    pub(crate) fn new() -> Self {
+        CaptionExtractor {
+            pending: BTreeMap::new(),
+            decoder: ClosedCaptionDecoder::default(),
+        }
+    }
+
+    /// Adds the caption data of a frame, and returns the captions of the
+    /// frames which are now known to be next in presentation order.
+    pub(crate) fn push(&mut self, frame: &Frame, data: Vec<CcData>) -> Vec<ClosedCaption> {
+        if !data.is_empty() {
+            self.pending.insert(frame.time.pts, data);
+        }
+
+        // no frame after this one is shown before it is decoded
+        let decoded = frame.time.decode_time();
+        let mut captions = Vec::new();
+
+        while let Some(pts) = self.pending.keys().next().copied() {
+            if pts > decoded && self.pending.len() <= MAX_PENDING {
+                break;
+            }
+
+            let data = self.pending.remove(&pts).unwrap_or_default();
+            if let Some(text) = self.decoder.push(&data) {
+                captions.push(ClosedCaption { pts, text });
+            }
+        }
+
+        captions
+    }
+}
+
+/// Decodes the first service of CEA-708, or the first channel of CEA-608
+/// for streams which only carry that.
+#[derive(Default)]
+pub struct ClosedCaptionDecoder {
+    cea608: Cea608Decoder,
+    cea708: Cea708Decoder,
+    /// Whether the stream has CEA-708 captions, which are preferred over
+    /// the CEA-608 ones they are carried next to.
+    has_708: bool,
+}
+
+impl ClosedCaptionDecoder {
+    /// Decodes the caption data of a frame, returning the text on screen
+    /// when it changed.
+    pub fn push(&mut self, data: &[CcData]) -> Option<String> {
+        let mut text = None;
+
+        for cc in data {
+            match cc.cc_type {
+                0 => {
+                    if let Some(changed) = self.cea608.push(cc.data) {
+                        if !self.has_708 {
+                            text = Some(changed);
+                        }
+                    }
+                }
+                2 | 3 => {
+                    if let Some(changed) = self.cea708.push(cc.cc_type == 3, cc.data) {
+                        self.has_708 = true;
+                        text = Some(changed);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        text
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    PopOn,
+    RollUp(usize),
+    PaintOn,
+}
+
+type Screen = [[char; COLUMNS]; ROWS];
+
+/// Decodes the CC1 channel of CEA-608 captions from the first field.
+struct Cea608Decoder {
+    mode: Mode,
+    displayed: Screen,
+    /// Where pop-on captions are loaded before they are shown.
+    non_displayed: Screen,
+    row: usize,
+    column: usize,
+    /// Whether text goes to CC1, rather than CC2 of the same field.
+    selected: bool,
+    /// The last control code, which encoders send twice in a row.
+    last_control: Option<(u8, u8)>,
+    /// The text last shown, which changes are told against.
+    shown: String,
+}
+
+impl Default for Cea608Decoder {
+    fn default() -> Self {
+        Cea608Decoder {
+            mode: Mode::PopOn,
+            displayed: [[' '; COLUMNS]; ROWS],
+            non_displayed: [[' '; COLUMNS]; ROWS],
+            row: ROWS - 1,
+            column: 0,
+            selected: true,
+            last_control: None,
+            shown: String::new(),
+        }
+    }
+}
+
+impl Cea608Decoder {
+    /// Decodes a byte pair, returning the text on screen when it changed.
+    fn push(&mut self, data: [u8; 2]) -> Option<String> {
+        let (b1, b2) = (data[0] & 0x7f, data[1] & 0x7f);
+
+        if b1 == 0 && b2 == 0 {
+            return None;
+        }
+
+        if !(0x10..=0x1f).contains(&b1) {
+            self.last_control = None;
+
+            if self.selected {
+                self.put(basic_char(b1));
+                if b2 >= 0x20 {
+                    self.put(basic_char(b2));
+                }
+            }
+
+            // paint-on and roll-up text is shown once the line is done
+            return None;
+        }
+
+        if self.last_control.take() == Some((b1, b2)) {
+            return None;
+        }
+        self.last_control = Some((b1, b2));
+
+        // codes of the second channel have the 0x08 bit set
+        self.selected = b1 & 0x08 == 0;
+        if !self.selected {
+            return None;
+        }
+
+        let b1 = b1 & !0x08;
+        match (b1, b2) {
+            (0x14, 0x20..=0x2f) => self.command(b2),
+            (0x17, 0x21..=0x23) => {
+                self.column = (self.column + (b2 - 0x20) as usize).min(COLUMNS - 1);
+                None
+            }
+            // special characters
+            (0x11, 0x30..=0x3f) => {
+                self.put(SPECIAL_CHARS[(b2 - 0x30) as usize]);
+                None
+            }
+            // mid-row style changes, which show as a space
+            (0x11, 0x20..=0x2f) => {
+                self.put(' ');
+                None
+            }
+            // extended characters, which replace the standard character
+            // sent before them for decoders which don't know them
+            (0x12 | 0x13, 0x20..=0x3f) => {
+                let chars = if b1 == 0x12 {
+                    &EXTENDED_CHARS_12
+                } else {
+                    &EXTENDED_CHARS_13
+                };
+
+                self.backspace();
+                self.put(chars[(b2 - 0x20) as usize]);
+                None
+            }
+            (_, 0x40..=0x7f) => {
+                self.preamble(b1, b2);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn command(&mut self, code: u8) -> Option<String> {
+        match code {
+            // resume caption loading
+            0x20 => self.mode = Mode::PopOn,
+            // backspace
+            0x21 => self.backspace(),
+            // delete to end of row
+            0x24 => {
+                let (row, column) = (self.row, self.column);
+                self.screen()[row][column..].fill(' ');
+            }
+            // roll-up with 2, 3 or 4 rows
+            0x25..=0x27 => {
+                let rows = (code - 0x23) as usize;
+                if !matches!(self.mode, Mode::RollUp(_)) {
+                    self.displayed = [[' '; COLUMNS]; ROWS];
+                    self.row = ROWS - 1;
+                }
+
+                self.mode = Mode::RollUp(rows);
+                self.column = 0;
+            }
+            // resume direct captioning
+            0x29 => self.mode = Mode::PaintOn,
+            // erase displayed memory
+            0x2c => self.displayed = [[' '; COLUMNS]; ROWS],
+            // carriage return
+            0x2d => {
+                if let Mode::RollUp(rows) = self.mode {
+                    self.roll_up(rows);
+                }
+            }
+            // erase non-displayed memory
+            0x2e => self.non_displayed = [[' '; COLUMNS]; ROWS],
+            // end of caption
+            0x2f => {
+                std::mem::swap(&mut self.displayed, &mut self.non_displayed);
+                self.mode = Mode::PopOn;
+            }
+            _ => {}
+        }
+
+        self.changed()
+    }
+
+    /// Moves the cursor to the row and indent of a preamble address code.
+    fn preamble(&mut self, b1: u8, b2: u8) {
+        let second = b2 & 0x20 != 0;
+        let row = match (b1, second) {
+            (0x11, false) => 1,
+            (0x11, true) => 2,
+            (0x12, false) => 3,
+            (0x12, true) => 4,
+            (0x15, false) => 5,
+            (0x15, true) => 6,
+            (0x16, false) => 7,
+            (0x16, true) => 8,
+            (0x17, false) => 9,
+            (0x17, true) => 10,
+            (0x10, _) => 11,
+            (0x13, false) => 12,
+            (0x13, true) => 13,
+            (0x14, false) => 14,
+            (0x14, true) => 15,
+            _ => return,
+        } - 1;
+
+        if let Mode::RollUp(rows) = self.mode {
+            // the rows of a roll-up window move along with its base row
+            if row != self.row {
+                let mut moved = [[' '; COLUMNS]; ROWS];
+                for offset in 0..rows.min(row + 1).min(self.row + 1) {
+                    moved[row - offset] = self.displayed[self.row - offset];
+                }
+                self.displayed = moved;
+            }
+        }
+
+        self.row = row;
+        self.column = if b2 & 0x10 != 0 {
+            ((b2 & 0x0e) >> 1) as usize * 4
+        } else {
+            0
+        };
+    }
+
+    fn screen(&mut self) -> &mut Screen {
+        match self.mode {
+            Mode::PopOn => &mut self.non_displayed,
+            Mode::RollUp(_) | Mode::PaintOn => &mut self.displayed,
+        }
+    }
+
+    fn put(&mut self, c: char) {
+        let (row, column) = (self.row, self.column);
+        self.screen()[row][column] = c;
+        self.column = (column + 1).min(COLUMNS - 1);
+    }
+
+    fn backspace(&mut self) {
+        self.column = self.column.saturating_sub(1);
+        let (row, column) = (self.row, self.column);
+        self.screen()[row][column] = ' ';
+    }
+
+    fn roll_up(&mut self, rows: usize) {
+        let top = (self.row + 1).saturating_sub(rows);
+
+        for row in top..self.row {
+            self.displayed[row] = self.displayed[row + 1];
+        }
+        self.displayed[self.row] = [' '; COLUMNS];
+        for row in self.displayed[..top].iter_mut() {
+            *row = [' '; COLUMNS];
+        }
+
+        self.column = 0;
+    }
+
+    fn changed(&mut self) -> Option<String> {
+        let text = screen_text(&self.displayed);
+        if text == self.shown {
+            return None;
+        }
+
+        self.shown = text.clone();
+        Some(text)
+    }
+}
+
+/// Returns the rows of a screen which have text, trimmed, one per line.
+fn screen_text(screen: &Screen) -> String {
+    screen
+        .iter()
+        .map(|row| row.iter().collect::<String>())
+        .map(|row| row.trim().to_string())
+        .filter(|row| !row.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Maps the standard characters of CEA-608, which are ASCII but for a few.
+fn basic_char(b: u8) -> char {
+    match b {
+        0x2a => 'á',
+        0x5c => 'é',
+        0x5e => 'í',
+        0x5f => 'ó',
+        0x60 => 'ú',
+        0x7b => 'ç',
+        0x7c => '÷',
+        0x7d => 'Ñ',
+        0x7e => 'ñ',
+        0x7f => '█',
+        b => b as char,
+    }
+}
+
+const SPECIAL_CHARS: [char; 16] = [
+    '®', '°', '½', '¿', '™', '¢', '£', '♪', 'à', ' ', 'è', 'â', 'ê', 'î', 'ô', 'û',
+];
+
+const EXTENDED_CHARS_12: [char; 32] = [
+    'Á', 'É', 'Ó', 'Ú', 'Ü', 'ü', '‘', '¡', '*', '’', '—', '©', '℠', '•', '“', '”', 'À', 'Â', 'Ç',
+    'È', 'Ê', 'Ë', 'ë', 'Î', 'Ï', 'ï', 'Ô', 'Ù', 'ù', 'Û', '«', '»',
+];
+
+const EXTENDED_CHARS_13: [char; 32] = [
+    'Ã', 'ã', 'Í', 'Ì', 'ì', 'Ò', 'ò', 'Õ', 'õ', '{', '}', '\\', '^', '_', '|', '~', 'Ä', 'ä', 'Ö',
+    'ö', 'ß', '¥', '¤', '│', 'Å', 'å', 'Ø', 'ø', '┌', '┐', '└', '┘',
+];
+
+/// Decodes the text of the first service of CEA-708 captions.
+///
+/// Windows and pens aren't kept apart, the text of the service is shown as
+/// the last few lines it wrote, like roll-up captions.
+#[derive(Default)]
+struct Cea708Decoder {
+    packet: Vec<u8>,
+    /// The size of the DTVCC packet being collected, with its header.
+    packet_size: usize,
+    lines: Vec<String>,
+    /// The text last shown, which changes are told against.
+    shown: String,
+}
+
+impl Cea708Decoder {
+    fn push(&mut self, start: bool, data: [u8; 2]) -> Option<String> {
+        if start {
+            self.packet.clear();
+            self.packet_size = match data[0] & 0x3f {
+                0 => 128,
+                code => code as usize * 2,
+            };
+        } else if self.packet.is_empty() {
+            // the start of the packet was lost
+            return None;
+        }
+
+        self.packet.extend_from_slice(&data);
+        if self.packet.len() < self.packet_size {
+            return None;
+        }
+
+        let packet = std::mem::take(&mut self.packet);
+        self.service_blocks(&packet[1..self.packet_size])
+    }
+
+    fn service_blocks(&mut self, mut data: &[u8]) -> Option<String> {
+        let mut changed = None;
+
+        while let Some((&header, rest)) = data.split_first() {
+            let mut service = header >> 5;
+            let size = (header & 0x1f) as usize;
+            data = rest;
+
+            if service == 0 {
+                break;
+            }
+            if service == 7 {
+                let (&extended, rest) = data.split_first()?;
+                service = extended & 0x3f;
+                data = rest;
+            }
+
+            let block = data.get(..size)?;
+            data = &data[size..];
+
+            if service == 1 {
+                if let Some(text) = self.service(block) {
+                    changed = Some(text);
+                }
+            }
+        }
+
+        changed
+    }
+
+    /// Decodes a service block of the first service.
+    fn service(&mut self, mut block: &[u8]) -> Option<String> {
+        let mut changed = None;
+
+        while let Some((&code, rest)) = block.split_first() {
+            block = rest;
+
+            let skip = match code {
+                // ETX, which ends a caption
+                0x03 => {
+                    changed = self.changed().or(changed);
+                    0
+                }
+                0x08 => {
+                    self.line().pop();
+                    0
+                }
+                // form feed, which clears the window
+                0x0c => {
+                    self.lines.clear();
+                    changed = self.changed().or(changed);
+                    0
+                }
+                0x0d => {
+                    changed = self.changed().or(changed);
+                    self.new_line();
+                    0
+                }
+                0x0e => {
+                    self.line().clear();
+                    0
+                }
+                0x10 => match block.split_first() {
+                    Some((&extended, rest)) => {
+                        block = rest;
+                        match extended {
+                            0x00..=0x07 => 0,
+                            0x08..=0x0f => 1,
+                            0x10..=0x17 => 2,
+                            0x18..=0x1f => 3,
+                            0x20..=0x7f => {
+                                if let Some(c) = g2_char(extended) {
+                                    self.line().push(c);
+                                }
+                                0
+                            }
+                            0x80..=0x87 => 4,
+                            0x88..=0x8f => 5,
+                            // variable length codes, which this decoder
+                            // can't skip
+                            0x90..=0x9f => return changed,
+                            0xa0..=0xff => 0,
+                        }
+                    }
+                    None => 0,
+                },
+                0x00..=0x0f => 0,
+                0x11..=0x17 => 1,
+                0x18..=0x1f => 2,
+                0x20..=0x7e => {
+                    self.line().push(code as char);
+                    0
+                }
+                0x7f => {
+                    self.line().push('♪');
+                    0
+                }
+                // CLW, DLW and RST, which clear windows
+                0x88 | 0x8c | 0x8f => {
+                    self.lines.clear();
+                    changed = self.changed().or(changed);
+                    if code == 0x8f {
+                        0
+                    } else {
+                        1
+                    }
+                }
+                // DSW and HDW, which show and hide windows
+                0x89 | 0x8a => {
+                    changed = self.changed().or(changed);
+                    1
+                }
+                0x80..=0x87 | 0x8e | 0x93..=0x96 => 0,
+                0x8b | 0x8d => 1,
+                0x90 | 0x92 => 2,
+                0x91 => 3,
+                0x97 => 4,
+                0x98..=0x9f => 6,
+                // Latin-1
+                0xa0..=0xff => {
+                    self.line().push(code as char);
+                    0
+                }
+            };
+
+            block = block.get(skip..).unwrap_or_default();
+        }
+
+        changed
+    }
+
+    fn line(&mut self) -> &mut String {
+        if self.lines.is_empty() {
+            self.lines.push(String::new());
+        }
+
+        self.lines.last_mut().unwrap()
+    }
+
+    fn new_line(&mut self) {
+        self.lines.push(String::new());
+
+        if self.lines.len() > MAX_708_LINES {
+            self.lines.remove(0);
+        }
+    }
+
+    fn changed(&mut self) -> Option<String> {
+        let text = self
+            .lines
+            .iter()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+        if text == self.shown {
+            return None;
+        }
+
+        self.shown = text.clone();
+        Some(text)
+    }
+}
+
+/// Maps the characters of the G2 set of CEA-708 which have a Unicode
+/// equivalent.
+fn g2_char(code: u8) -> Option<char> {
+    Some(match code {
+        0x20 | 0x21 => ' ',
+        0x25 => '…',
+        0x2a => 'Š',
+        0x2c => 'Œ',
+        0x30 => '█',
+        0x31 => '‘',
+        0x32 => '’',
+        0x33 => '“',
+        0x34 => '”',
+        0x35 => '•',
+        0x39 => '™',
+        0x3a => 'š',
+        0x3c => 'œ',
+        0x3d => '℠',
+        0x3f => 'Ÿ',
+        _ => return None,
+    })
+}
+
+#[test]
+fn decodes_pop_on_captions() {
+    fn cc(data: [u8; 2]) -> CcData {
+        CcData { cc_type: 0, data }
+    }
+
+    let mut decoder = ClosedCaptionDecoder::default();
+
+    // RCL, a preamble address code for row 15, "HI", then EOC, with every
+    // control code sent twice
+    let loading = [
+        cc([0x14, 0x20]),
+        cc([0x14, 0x20]),
+        cc([0x14, 0x70]),
+        cc([0x14, 0x70]),
+        cc([b'H', b'I']),
+    ];
+    assert_eq!(decoder.push(&loading), None);
+
+    let shown = [cc([0x14, 0x2f]), cc([0x14, 0x2f])];
+    assert_eq!(decoder.push(&shown), Some("HI".to_string()));
+
+    // EDM
+    assert_eq!(decoder.push(&[cc([0x14, 0x2c])]), Some(String::new()));
+}
+
+#[test]
+fn decodes_708_service_blocks() {
+    let mut decoder = ClosedCaptionDecoder::default();
+
+    // a packet of 8 bytes: its header, a block of service 1 with "Hi" and
+    // a carriage return, and padding
+    let data = [
+        CcData {
+            cc_type: 3,
+            data: [0x04, 0x23],
+        },
+        CcData {
+            cc_type: 2,
+            data: [b'H', b'i'],
+        },
+        CcData {
+            cc_type: 2,
+            data: [0x0d, 0x00],
+        },
+        CcData {
+            cc_type: 2,
+            data: [0x00, 0x00],
+        },
+    ];
+
+    assert_eq!(decoder.push(&data), Some("Hi".to_string()));
+}
@@ -3,11 +3,32 @@ use tokio::fs::File;
 
 pub struct FileWriteFilter {
     file: File,
+    sync: bool,
 }
 
 impl FileWriteFilter {
     pub fn new(file: File) -> Self {
-        Self { file }
+        Self { file, sync: false }
+    }
+
+    /// Makes every write reach the disk before it completes, so nothing
+    /// that was written is lost in a crash.
+    pub fn with_sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    async fn write_bytes(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        self.file.write_all(bytes).await?;
+        self.file.flush().await?;
+
+        if self.sync {
+            self.file.sync_data().await?;
+        }
+
+        Ok(())
     }
 }
 
@@ -17,12 +38,7 @@ impl ByteWriteFilter2 for FileWriteFilter {
         Ok(())
     }
     async fn write(&mut self, bytes: bytes::Bytes) -> anyhow::Result<()> {
-        use tokio::io::AsyncWriteExt;
-
-        self.file.write_all(&bytes).await?;
-        self.file.flush().await?;
-
-        Ok(())
+        self.write_bytes(&bytes).await
     }
 }
 
@@ -32,13 +48,8 @@ impl<T: Send + Unpin + 'static> ByteWriteFilter<T> for FileWriteFilter {
         Ok(())
     }
     async fn write(&mut self, bytes: anyhow::Result<(bytes::Bytes, T)>) -> anyhow::Result<()> {
-        use tokio::io::AsyncWriteExt;
-
         let bytes = bytes?.0;
 
-        self.file.write_all(&bytes).await?;
-        self.file.flush().await?;
-
-        Ok(())
+        self.write_bytes(&bytes).await
     }
 }
@@ -0,0 +1,780 @@
+use tokio::sync::{mpsc, oneshot};
+use tracing::*;
+
+use std::{
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use crate::{Frame, FrameReadFilter, FrameWriteFilter, Stream, TeeWriteFilter};
+
+type ReadFilter = Box<dyn FrameReadFilter + Unpin + Send>;
+type WriteFilter = Box<dyn FrameWriteFilter + Unpin + Send>;
+type InlineFilter = Box<dyn FrameFilter + Unpin + Send>;
+
+/// A filter which is inserted into a running [`FilterGraph`], between its
+/// filters and its sinks. Unlike a [`FrameReadFilter`] it doesn't own the
+/// nodes before it, so it can be removed again, but it can't change the
+/// streams.
+#[async_trait::async_trait]
+pub trait FrameFilter {
+    async fn start(&mut self, streams: &[Stream]) -> anyhow::Result<()>;
+
+    /// Changes a frame before it reaches the sinks, returning whether it
+    /// should reach them at all.
+    async fn filter(&mut self, frame: &mut Frame) -> anyhow::Result<bool>;
+}
+
+/// What a node of a [`FilterGraph`] does with frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    /// Where frames are read from.
+    Source,
+    /// Reads the frames of the node before it, and passes them on.
+    Filter,
+    /// Where frames are written to, which the graph fails along with.
+    Sink,
+    /// Where frames are written to, until it fails.
+    Branch,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GraphNode {
+    pub name: String,
+    pub kind: NodeKind,
+}
+
+/// The nodes of a [`FilterGraph`], from its source through its filters to
+/// its sinks.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Topology {
+    nodes: Vec<GraphNode>,
+}
+
+impl Topology {
+    pub fn nodes(&self) -> &[GraphNode] {
+        &self.nodes
+    }
+
+    pub fn node(&self, name: &str) -> Option<&GraphNode> {
+        self.nodes.iter().find(|node| node.name == name)
+    }
+
+    /// The nodes frames pass through before they reach the sinks, in the
+    /// order they pass through them.
+    pub fn chain(&self) -> impl Iterator<Item = &GraphNode> {
+        self.nodes
+            .iter()
+            .filter(|node| matches!(node.kind, NodeKind::Source | NodeKind::Filter))
+    }
+
+    pub fn sinks(&self) -> impl Iterator<Item = &GraphNode> {
+        self.nodes
+            .iter()
+            .filter(|node| matches!(node.kind, NodeKind::Sink | NodeKind::Branch))
+    }
+}
+
+/// Shows the graph like `rtmp -> sei -> [queue, recorder?]`, where branches
+/// are marked with a `?`.
+impl fmt::Display for Topology {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for node in self.chain() {
+            write!(f, "{} -> ", node.name)?;
+        }
+
+        write!(f, "[")?;
+        for (i, node) in self.sinks().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", node.name)?;
+            if node.kind == NodeKind::Branch {
+                write!(f, "?")?;
+            }
+        }
+        write!(f, "]")
+    }
+}
+
+/// Builds a [`FilterGraph`] out of named nodes, e.g.
+///
+/// ```ignore
+/// let graph = FilterGraph::builder()
+///     .source("rtmp", read)
+///     .filter("sei", |read| Box::new(SeiReadFilter::new(read)))
+///     .sink("queue", Box::new(queue.clone()))
+///     .branch("recorder", recorder)
+///     .build()?;
+/// ```
+#[derive(Default)]
+pub struct FilterGraphBuilder {
+    read: Option<ReadFilter>,
+    tee: TeeWriteFilter,
+    topology: Topology,
+    /// The first mistake made while building, which is returned by
+    /// [`FilterGraphBuilder::build`].
+    error: Option<String>,
+}
+
+impl FilterGraphBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push(&mut self, name: &str, kind: NodeKind) {
+        if self.error.is_none() && self.topology.node(name).is_some() {
+            self.error = Some(format!("Filter graph has two nodes named '{}'", name));
+        }
+
+        self.topology.nodes.push(GraphNode {
+            name: name.to_string(),
+            kind,
+        });
+    }
+
+    /// Sets where the frames of the graph are read from.
+    pub fn source(mut self, name: &str, read: ReadFilter) -> Self {
+        if self.read.is_some() && self.error.is_none() {
+            self.error = Some(format!("Filter graph has a second source '{}'", name));
+        }
+
+        self.push(name, NodeKind::Source);
+        self.read = Some(read);
+        self
+    }
+
+    /// Passes the frames read so far through a filter, which is created
+    /// around the nodes before it.
+    pub fn filter<F>(mut self, name: &str, wrap: F) -> Self
+    where
+        F: FnOnce(ReadFilter) -> ReadFilter,
+    {
+        match self.read.take() {
+            Some(read) => self.read = Some(wrap(read)),
+            None if self.error.is_none() => {
+                self.error = Some(format!("Filter '{}' comes before any source", name));
+            }
+            None => {}
+        }
+
+        self.push(name, NodeKind::Filter);
+        self
+    }
+
+    /// Adds a sink which the graph fails along with.
+    pub fn sink(mut self, name: &str, write: WriteFilter) -> Self {
+        self.push(name, NodeKind::Sink);
+        self.tee.add(name.to_string(), write, true);
+        self
+    }
+
+    /// Adds a sink which is dropped when it fails, so the graph goes on
+    /// without it.
+    pub fn branch(mut self, name: &str, write: WriteFilter) -> Self {
+        self.push(name, NodeKind::Branch);
+        self.tee.add(name.to_string(), write, false);
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<FilterGraph> {
+        if let Some(error) = self.error {
+            anyhow::bail!(error);
+        }
+
+        let read = self
+            .read
+            .ok_or_else(|| anyhow::anyhow!("Filter graph has no source"))?;
+        if self.tee.is_empty() {
+            anyhow::bail!("Filter graph has no sinks");
+        }
+
+        let chain = self.topology.chain().cloned().collect();
+
+        Ok(FilterGraph {
+            read,
+            sinks: GraphSinks {
+                tee: self.tee,
+                filters: Vec::new(),
+                chain,
+                topology: Arc::new(Mutex::new(self.topology)),
+            },
+            streams: None,
+        })
+    }
+}
+
+pub struct FilterGraph {
+    read: ReadFilter,
+    sinks: GraphSinks,
+    /// The streams of the source, once the graph has started.
+    streams: Option<Vec<Stream>>,
+}
+
+impl FilterGraph {
+    pub fn new(read: ReadFilter, write: WriteFilter) -> Self {
+        Self::builder()
+            .source("source", read)
+            .sink("sink", write)
+            .build()
+            .expect("a graph of a source and a sink is complete")
+    }
+
+    pub fn builder() -> FilterGraphBuilder {
+        FilterGraphBuilder::new()
+    }
+
+    /// Creates a graph which writes every frame of `read` to all of
+    /// `sinks`, and fails when any of them does.
+    pub fn with_sinks(read: ReadFilter, sinks: Vec<WriteFilter>) -> anyhow::Result<Self> {
+        sinks
+            .into_iter()
+            .enumerate()
+            .fold(
+                Self::builder().source("source", read),
+                |builder, (i, sink)| builder.sink(&format!("sink{}", i), sink),
+            )
+            .build()
+    }
+
+    /// The nodes of the graph, including the filters and sinks added or
+    /// removed while it runs.
+    pub fn topology(&self) -> Topology {
+        self.sinks.topology.lock().unwrap().clone()
+    }
+
+    /// Starts the source and sinks of the graph, returning the streams of
+    /// the source. Graphs which haven't started when they run are started
+    /// then.
+    pub async fn start(&mut self) -> anyhow::Result<Vec<Stream>> {
+        let streams = self.read.start().await?;
+
+        self.sinks.tee.start(streams.clone()).await?;
+        self.sinks.changed();
+        self.streams = Some(streams.clone());
+
+        Ok(streams)
+    }
+
+    /// Runs the graph until its source or a sink fails, returning a handle
+    /// which adds and removes filters and sinks while it runs, like a
+    /// recorder.
+    ///
+    /// Sinks added while the graph runs start at the next frame, so ones
+    /// which need to start at a keyframe should be wrapped in a
+    /// [`crate::WaitForSyncFrameFilter`]. Filters are inserted after the
+    /// filters the graph was built with, which can't be removed since every
+    /// one of them owns the ones before it.
+    pub fn run(
+        self,
+    ) -> (
+        GraphControl,
+        impl Future<Output = anyhow::Result<()>> + Send,
+    ) {
+        let (send, commands) = mpsc::unbounded_channel();
+        let control = GraphControl {
+            commands: send,
+            topology: self.sinks.topology.clone(),
+        };
+
+        (control, self.drive(commands))
+    }
+
+    async fn drive(
+        mut self,
+        mut commands: mpsc::UnboundedReceiver<GraphCommand>,
+    ) -> anyhow::Result<()> {
+        let streams = match self.streams.take() {
+            Some(streams) => streams,
+            None => self.start().await?,
+        };
+
+        loop {
+            // the read is kept while commands are handled, since reads
+            // can't be cancelled without losing data
+            let mut read = self.read.read();
+            let frame = loop {
+                tokio::select! {
+                    frame = &mut read => break frame?,
+                    Some(command) = commands.recv() => {
+                        self.sinks.handle(command, &streams).await;
+                    }
+                }
+            };
+
+            let frame = match self.sinks.filter(frame).await {
+                Some(frame) => frame,
+                None => continue,
+            };
+
+            let sinks = self.sinks.tee.len();
+            self.sinks.tee.write(frame).await?;
+
+            if self.sinks.tee.len() != sinks {
+                self.sinks.changed();
+            }
+        }
+    }
+}
+
+/// The inserted filters and the sinks of a [`FilterGraph`], which change
+/// while it runs.
+struct GraphSinks {
+    tee: TeeWriteFilter,
+    /// The filters inserted into the running graph, in the order frames
+    /// pass through them.
+    filters: Vec<(String, InlineFilter)>,
+    /// The source and filters of the graph, which stay the same while it
+    /// runs.
+    chain: Vec<GraphNode>,
+    topology: Arc<Mutex<Topology>>,
+}
+
+impl GraphSinks {
+    /// Updates the topology after the filters or sinks changed.
+    fn changed(&self) {
+        let filters = self.filters.iter().map(|(name, _)| GraphNode {
+            name: name.clone(),
+            kind: NodeKind::Filter,
+        });
+        let sinks = self.tee.names().map(|(name, required)| GraphNode {
+            name: name.to_string(),
+            kind: if required {
+                NodeKind::Sink
+            } else {
+                NodeKind::Branch
+            },
+        });
+
+        self.topology.lock().unwrap().nodes = self
+            .chain
+            .iter()
+            .cloned()
+            .chain(filters)
+            .chain(sinks)
+            .collect();
+    }
+
+    /// Passes a frame through the inserted filters, unless one of them
+    /// drops it. A filter which fails is removed, and the frame goes on
+    /// without it.
+    async fn filter(&mut self, mut frame: Frame) -> Option<Frame> {
+        let mut i = 0;
+        while let Some((name, filter)) = self.filters.get_mut(i) {
+            match filter.filter(&mut frame).await {
+                Ok(true) => i += 1,
+                Ok(false) => return None,
+                Err(e) => {
+                    warn!(
+                        "Removed failed filter '{}' from a filter graph: {:?}",
+                        name, e
+                    );
+                    self.filters.remove(i);
+                    self.changed();
+                }
+            }
+        }
+
+        Some(frame)
+    }
+
+    fn has_node(&self, name: &str) -> bool {
+        self.topology.lock().unwrap().node(name).is_some()
+    }
+
+    async fn handle(&mut self, command: GraphCommand, streams: &[Stream]) {
+        match command {
+            GraphCommand::Add {
+                name,
+                write,
+                required,
+                reply,
+            } => {
+                let result = if self.has_node(&name) {
+                    Err(anyhow::anyhow!("Filter graph has a node named '{}'", name))
+                } else {
+                    let added = name.clone();
+                    self.tee
+                        .add_started(added, write, required, streams.to_vec())
+                        .await
+                };
+
+                if result.is_ok() {
+                    debug!("Added sink '{}' to a running filter graph", name);
+                    self.changed();
+                }
+                let _ = reply.send(result);
+            }
+            GraphCommand::Insert {
+                name,
+                mut filter,
+                reply,
+            } => {
+                let result = if self.has_node(&name) {
+                    Err(anyhow::anyhow!("Filter graph has a node named '{}'", name))
+                } else {
+                    filter.start(streams).await
+                };
+
+                if result.is_ok() {
+                    debug!("Inserted filter '{}' into a running filter graph", name);
+                    self.filters.push((name, filter));
+                    self.changed();
+                }
+                let _ = reply.send(result);
+            }
+            GraphCommand::Remove { name, reply } => {
+                let filter = self.filters.iter().position(|(filter, _)| *filter == name);
+                let removed = match filter {
+                    Some(i) => {
+                        self.filters.remove(i);
+                        true
+                    }
+                    None => self.tee.remove(&name),
+                };
+
+                if removed {
+                    debug!("Removed '{}' from a running filter graph", name);
+                    self.changed();
+                }
+                let _ = reply.send(removed);
+            }
+        }
+    }
+}
+
+enum GraphCommand {
+    Add {
+        name: String,
+        write: WriteFilter,
+        required: bool,
+        reply: oneshot::Sender<anyhow::Result<()>>,
+    },
+    Insert {
+        name: String,
+        filter: InlineFilter,
+        reply: oneshot::Sender<anyhow::Result<()>>,
+    },
+    Remove {
+        name: String,
+        reply: oneshot::Sender<bool>,
+    },
+}
+
+/// Changes the inserted filters and the sinks of a running [`FilterGraph`].
+#[derive(Clone)]
+pub struct GraphControl {
+    commands: mpsc::UnboundedSender<GraphCommand>,
+    topology: Arc<Mutex<Topology>>,
+}
+
+impl GraphControl {
+    /// Adds a sink which the graph fails along with, once it is started.
+    pub async fn add_sink(&self, name: &str, write: WriteFilter) -> anyhow::Result<()> {
+        self.add(name, write, true).await
+    }
+
+    /// Adds a sink which is dropped when it fails, once it is started.
+    pub async fn add_branch(&self, name: &str, write: WriteFilter) -> anyhow::Result<()> {
+        self.add(name, write, false).await
+    }
+
+    async fn add(&self, name: &str, write: WriteFilter, required: bool) -> anyhow::Result<()> {
+        let (reply, result) = oneshot::channel();
+        let command = GraphCommand::Add {
+            name: name.to_string(),
+            write,
+            required,
+            reply,
+        };
+
+        self.commands
+            .send(command)
+            .map_err(|_| anyhow::anyhow!("Filter graph has stopped"))?;
+        result
+            .await
+            .map_err(|_| anyhow::anyhow!("Filter graph has stopped"))?
+    }
+
+    /// Inserts a filter after the others, once it is started with the
+    /// streams of the graph.
+    pub async fn insert_filter(&self, name: &str, filter: InlineFilter) -> anyhow::Result<()> {
+        let (reply, result) = oneshot::channel();
+        let command = GraphCommand::Insert {
+            name: name.to_string(),
+            filter,
+            reply,
+        };
+
+        self.commands
+            .send(command)
+            .map_err(|_| anyhow::anyhow!("Filter graph has stopped"))?;
+        result
+            .await
+            .map_err(|_| anyhow::anyhow!("Filter graph has stopped"))?
+    }
+
+    /// Removes an inserted filter, a sink or a branch, returning whether
+    /// the graph had one by that name.
+    pub async fn remove(&self, name: &str) -> bool {
+        let (reply, removed) = oneshot::channel();
+        let command = GraphCommand::Remove {
+            name: name.to_string(),
+            reply,
+        };
+
+        if self.commands.send(command).is_err() {
+            return false;
+        }
+        removed.await.unwrap_or(false)
+    }
+
+    /// The nodes of the graph as it runs.
+    pub fn topology(&self) -> Topology {
+        self.topology.lock().unwrap().clone()
+    }
+}
+
+#[test]
+fn builder_records_topology() {
+    use crate::Frame;
+
+    struct Nothing;
+
+    #[async_trait::async_trait]
+    impl FrameReadFilter for Nothing {
+        async fn start(&mut self) -> anyhow::Result<Vec<Stream>> {
+            Ok(Vec::new())
+        }
+
+        async fn read(&mut self) -> anyhow::Result<Frame> {
+            anyhow::bail!("Nothing to read")
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl FrameWriteFilter for Nothing {
+        async fn start(&mut self, _streams: Vec<Stream>) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn write(&mut self, _frame: Frame) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    let graph = FilterGraph::builder()
+        .source("input", Box::new(Nothing))
+        .filter("pass", |read| read)
+        .sink("output", Box::new(Nothing))
+        .branch("copy", Box::new(Nothing))
+        .build()
+        .unwrap();
+
+    assert_eq!(
+        graph.topology().to_string(),
+        "input -> pass -> [output, copy?]"
+    );
+    assert_eq!(
+        graph.topology().node("pass").unwrap().kind,
+        NodeKind::Filter
+    );
+
+    let twice = FilterGraph::builder()
+        .source("input", Box::new(Nothing))
+        .sink("output", Box::new(Nothing))
+        .sink("output", Box::new(Nothing))
+        .build();
+    assert!(twice.is_err());
+
+    let unsourced = FilterGraph::builder()
+        .filter("pass", |read| read)
+        .sink("output", Box::new(Nothing))
+        .build();
+    assert!(unsourced.is_err());
+}
+
+#[tokio::test]
+async fn graphs_pass_frames_through_their_filters() {
+    use crate::{test_frame, Frame, TestSink, TestSource};
+
+    /// Moves every frame a second later.
+    struct Delay(ReadFilter);
+
+    #[async_trait::async_trait]
+    impl FrameReadFilter for Delay {
+        async fn start(&mut self) -> anyhow::Result<Vec<Stream>> {
+            self.0.start().await
+        }
+
+        async fn read(&mut self) -> anyhow::Result<Frame> {
+            let mut frame = self.0.read().await?;
+            frame.time.pts += 1000;
+
+            Ok(frame)
+        }
+    }
+
+    let (input, frames) = mpsc::unbounded_channel();
+    let (output, mut written) = mpsc::unbounded_channel();
+    let (copy, mut copied) = mpsc::unbounded_channel();
+
+    let mut graph = FilterGraph::builder()
+        .source("input", Box::new(TestSource(frames)))
+        .filter("delay", |read| Box::new(Delay(read)))
+        .sink("output", Box::new(TestSink::Collect(output)))
+        .branch("copy", Box::new(TestSink::Collect(copy)))
+        .build()
+        .unwrap();
+
+    let streams = graph.start().await.unwrap();
+    assert_eq!(streams.len(), 1);
+
+    let (_control, run) = graph.run();
+    let run = tokio::spawn(run);
+
+    for pts in 0..3 {
+        input.send(test_frame(pts, pts == 0)).unwrap();
+    }
+    for pts in 0..3 {
+        assert_eq!(written.recv().await, Some(pts + 1000));
+        assert_eq!(copied.recv().await, Some(pts + 1000));
+    }
+
+    // the graph stops when its source does
+    drop(input);
+    assert!(run.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn sinks_are_added_and_removed_while_running() {
+    use crate::{test_frame, TestSink, TestSource};
+    use std::time::Duration;
+
+    let (input, frames) = mpsc::unbounded_channel();
+    let (output, mut written) = mpsc::unbounded_channel();
+    let (copy, mut copied) = mpsc::unbounded_channel();
+
+    let graph = FilterGraph::builder()
+        .source("input", Box::new(TestSource(frames)))
+        .sink("output", Box::new(TestSink::Collect(output)))
+        .build()
+        .unwrap();
+
+    let (control, run) = graph.run();
+    tokio::spawn(run);
+
+    input.send(test_frame(0, true)).unwrap();
+    assert_eq!(written.recv().await, Some(0));
+
+    // the source has nothing to read, which doesn't hold back the sink
+    let add = control.add_branch("copy", Box::new(TestSink::Collect(copy)));
+    tokio::time::timeout(Duration::from_secs(5), add)
+        .await
+        .expect("sink is added while the source waits")
+        .unwrap();
+    assert_eq!(control.topology().to_string(), "input -> [output, copy?]");
+
+    input.send(test_frame(1, false)).unwrap();
+    assert_eq!(written.recv().await, Some(1));
+    assert_eq!(copied.recv().await, Some(1));
+
+    assert!(control.remove("copy").await);
+    assert!(!control.remove("copy").await);
+    assert_eq!(control.topology().to_string(), "input -> [output]");
+
+    input.send(test_frame(2, false)).unwrap();
+    assert_eq!(written.recv().await, Some(2));
+    assert_eq!(copied.recv().await, None);
+}
+
+#[tokio::test]
+async fn filters_are_inserted_and_removed_while_running() {
+    use crate::{test_frame, TestSink, TestSource};
+
+    /// Moves every frame a second later, and drops those at odd seconds.
+    struct Delay;
+
+    #[async_trait::async_trait]
+    impl FrameFilter for Delay {
+        async fn start(&mut self, streams: &[Stream]) -> anyhow::Result<()> {
+            anyhow::ensure!(!streams.is_empty(), "No streams to delay");
+            Ok(())
+        }
+
+        async fn filter(&mut self, frame: &mut Frame) -> anyhow::Result<bool> {
+            frame.time.pts += 1000;
+            Ok(frame.time.pts & 1 == 0)
+        }
+    }
+
+    struct Broken;
+
+    #[async_trait::async_trait]
+    impl FrameFilter for Broken {
+        async fn start(&mut self, _streams: &[Stream]) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn filter(&mut self, _frame: &mut Frame) -> anyhow::Result<bool> {
+            anyhow::bail!("Broken filter")
+        }
+    }
+
+    let (input, frames) = mpsc::unbounded_channel();
+    let (output, mut written) = mpsc::unbounded_channel();
+
+    let graph = FilterGraph::builder()
+        .source("input", Box::new(TestSource(frames)))
+        .filter("pass", |read| read)
+        .sink("output", Box::new(TestSink::Collect(output)))
+        .build()
+        .unwrap();
+
+    let (control, run) = graph.run();
+    tokio::spawn(run);
+
+    input.send(test_frame(0, true)).unwrap();
+    assert_eq!(written.recv().await, Some(0));
+
+    control
+        .insert_filter("delay", Box::new(Delay))
+        .await
+        .unwrap();
+    assert!(control
+        .insert_filter("pass", Box::new(Delay))
+        .await
+        .is_err());
+    assert!(control
+        .insert_filter("output", Box::new(Delay))
+        .await
+        .is_err());
+    assert_eq!(
+        control.topology().to_string(),
+        "input -> pass -> delay -> [output]"
+    );
+
+    for pts in 1..=4 {
+        input.send(test_frame(pts, false)).unwrap();
+    }
+    assert_eq!(written.recv().await, Some(1002));
+    assert_eq!(written.recv().await, Some(1004));
+
+    // filters the graph was built with own the ones before them
+    assert!(!control.remove("pass").await);
+    assert!(control.remove("delay").await);
+    assert_eq!(control.topology().to_string(), "input -> pass -> [output]");
+
+    input.send(test_frame(5, false)).unwrap();
+    assert_eq!(written.recv().await, Some(5));
+
+    // a filter which fails is taken out, rather than failing the graph
+    control
+        .insert_filter("broken", Box::new(Broken))
+        .await
+        .unwrap();
+    input.send(test_frame(6, false)).unwrap();
+    assert_eq!(written.recv().await, Some(6));
+    assert_eq!(control.topology().to_string(), "input -> pass -> [output]");
+}
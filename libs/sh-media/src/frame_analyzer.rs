@@ -1,4 +1,5 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, RwLock};
 use std::time::Instant;
 
 use super::{Frame, FrameReadFilter, FrameWriteFilter, MediaTime, Stream};
@@ -26,12 +27,71 @@ impl ReadOrWriteFilter {
     }
 }
 
+/// How many seconds of media the rolling stats are measured over.
+const STATS_WINDOW: f64 = 5.0;
+
+/// How many seconds of media pass between updates of the published stats.
+const STATS_INTERVAL: f64 = 1.0;
+
+/// The rolling stats of a stream, as measured by a [`FrameAnalyzerFilter`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct StreamStats {
+    /// In kbit/s.
+    pub bitrate: f64,
+    /// In frames per second.
+    pub frame_rate: f64,
+    /// How many frames the last complete GOP of a video stream had.
+    pub gop_length: Option<u32>,
+    /// How many seconds passed between the last two keyframes of a video
+    /// stream.
+    pub keyframe_interval: Option<f64>,
+}
+
+/// The stats of the streams which went through a [`FrameAnalyzerFilter`]
+/// by stream ID, shared with whatever reads them.
+#[derive(Clone, Default)]
+pub struct StatsRegistry {
+    streams: Arc<RwLock<HashMap<u32, StreamStats>>>,
+}
+
+impl StatsRegistry {
+    pub fn get(&self, stream: u32) -> Option<StreamStats> {
+        self.streams.read().unwrap().get(&stream).copied()
+    }
+
+    /// The stats of every stream, ordered by stream ID.
+    pub fn all(&self) -> Vec<(u32, StreamStats)> {
+        let mut streams: Vec<_> = self
+            .streams
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(id, stats)| (*id, *stats))
+            .collect();
+        streams.sort_by_key(|(id, _)| *id);
+
+        streams
+    }
+
+    fn publish(&self, stream: u32, stats: StreamStats) {
+        self.streams.write().unwrap().insert(stream, stats);
+    }
+}
+
 pub struct StreamMetrics {
     buffer: e_ring::Ring<f32, 1000>,
     index: u16,
     data_size: u64,
     last_time: Option<MediaTime>,
     last_report: Instant,
+    /// The decode time in seconds and size of the frames of the last
+    /// [`STATS_WINDOW`].
+    window: VecDeque<(f64, usize)>,
+    window_size: usize,
+    frames_since_keyframe: u32,
+    last_keyframe: Option<f64>,
+    last_published: Option<f64>,
+    stats: StreamStats,
 }
 
 pub struct MetricsReport {
@@ -48,6 +108,66 @@ impl StreamMetrics {
             data_size: 0,
             last_time: None,
             last_report: Instant::now(),
+            window: VecDeque::new(),
+            window_size: 0,
+            frames_since_keyframe: 0,
+            last_keyframe: None,
+            last_published: None,
+            stats: StreamStats::default(),
+        }
+    }
+
+    /// Adds a frame to the rolling stats, and returns them when they are
+    /// due to be published.
+    fn add_to_stats(&mut self, frame: &Frame) -> Option<StreamStats> {
+        let timebase = frame.stream.timebase;
+        let time = frame.time.decode_time() as f64 * timebase.numerator as f64
+            / timebase.denominator as f64;
+
+        // a jump back, like after a reconnect, starts the window over
+        if self.window.back().is_some_and(|(last, _)| time < *last) {
+            self.window.clear();
+            self.window_size = 0;
+            self.last_keyframe = None;
+            self.last_published = None;
+        }
+
+        self.window.push_back((time, frame.buffer.len()));
+        self.window_size += frame.buffer.len();
+        while let Some((first, size)) = self.window.front().copied() {
+            if time - first <= STATS_WINDOW {
+                break;
+            }
+            self.window.pop_front();
+            self.window_size -= size;
+        }
+
+        if frame.stream.is_video() {
+            if frame.is_keyframe() {
+                if let Some(last) = self.last_keyframe {
+                    self.stats.gop_length = Some(self.frames_since_keyframe);
+                    self.stats.keyframe_interval = Some(time - last);
+                }
+                self.last_keyframe = Some(time);
+                self.frames_since_keyframe = 0;
+            }
+            self.frames_since_keyframe += 1;
+        }
+
+        let span = time - self.window.front().map_or(time, |(first, _)| *first);
+        if span > 0.0 {
+            // the last frame is only counted once its duration has passed
+            let (_, last_size) = self.window.back().copied().unwrap_or_default();
+            self.stats.bitrate = (self.window_size - last_size) as f64 * 8.0 / 1000.0 / span;
+            self.stats.frame_rate = (self.window.len() - 1) as f64 / span;
+        }
+
+        match self.last_published {
+            Some(last) if time - last < STATS_INTERVAL => None,
+            _ => {
+                self.last_published = Some(time);
+                Some(self.stats)
+            }
         }
     }
 
@@ -88,6 +208,8 @@ pub struct FrameAnalyzerFilter {
     streams: Vec<Stream>,
     filter: ReadOrWriteFilter,
     metrics: HashMap<u32, StreamMetrics>,
+    /// Where the rolling stats of every stream are published, if anywhere.
+    registry: Option<StatsRegistry>,
 }
 
 impl FrameAnalyzerFilter {
@@ -96,6 +218,7 @@ impl FrameAnalyzerFilter {
             streams: Vec::new(),
             filter: ReadOrWriteFilter::Read(target),
             metrics: HashMap::new(),
+            registry: None,
         }
     }
 
@@ -104,18 +227,31 @@ impl FrameAnalyzerFilter {
             streams: Vec::new(),
             filter: ReadOrWriteFilter::Write(target),
             metrics: HashMap::new(),
+            registry: None,
         }
     }
 
+    /// Publishes the bitrate, frame rate and GOP structure of every stream
+    /// to `registry` about once a second of media.
+    pub fn with_registry(mut self, registry: StatsRegistry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
     fn report(&mut self, frame: &Frame) {
         let stream_id = frame.stream.id;
-
-        if let Some(_report) = self
+        let metrics = self
             .metrics
             .entry(stream_id)
-            .or_insert_with(StreamMetrics::new)
-            .add(frame)
-        {
+            .or_insert_with(StreamMetrics::new);
+
+        if let Some(registry) = &self.registry {
+            if let Some(stats) = metrics.add_to_stats(frame) {
+                registry.publish(stream_id, stats);
+            }
+        }
+
+        if let Some(_report) = metrics.add(frame) {
             if self.streams.iter().any(|s| s.id == stream_id) {
                 let _action = if matches!(self.filter, ReadOrWriteFilter::Read(_)) {
                     "reading"
@@ -0,0 +1,180 @@
+use super::{
+    frame_nal_units, nut_header, parse_bitstream, BitstreamFraming, Frame, FrameDependency,
+    FrameReadFilter, MediaTime, Stream,
+};
+
+use anyhow::Context;
+use bytes::Bytes;
+use h264_reader::nal::UnitType;
+use std::time::Instant;
+
+/// Creates a connected [`FrameInjector`] and [`InjectReadFilter`] for the
+/// given streams.
+///
+/// Frames pushed into the injector are read from the filter in the order
+/// they were pushed, which allows application code to act as a publisher
+/// without going through a network protocol.
+pub fn frame_injector(streams: Vec<Stream>) -> (FrameInjector, InjectReadFilter) {
+    let (tx, rx) = async_channel::bounded(1024);
+
+    let injector = FrameInjector {
+        streams: streams.clone(),
+        tx,
+    };
+    let filter = InjectReadFilter { streams, rx };
+
+    (injector, filter)
+}
+
+/// The sending half of a [`frame_injector`].
+#[derive(Clone)]
+pub struct FrameInjector {
+    streams: Vec<Stream>,
+    tx: async_channel::Sender<Frame>,
+}
+
+impl FrameInjector {
+    pub fn streams(&self) -> &[Stream] {
+        &self.streams
+    }
+
+    fn stream(&self, stream_id: u32) -> anyhow::Result<&Stream> {
+        self.streams
+            .iter()
+            .find(|s| s.id == stream_id)
+            .ok_or_else(|| anyhow::anyhow!("No stream with id {}", stream_id))
+    }
+
+    /// Pushes a [`Frame`] as-is into the stream.
+    pub async fn push(&self, frame: Frame) -> anyhow::Result<()> {
+        self.tx
+            .send(frame)
+            .await
+            .context("injected stream was closed")?;
+
+        Ok(())
+    }
+
+    /// Pushes an access unit framed in Annex B format (start codes). The
+    /// NAL units are reframed to the bitstream format of the stream.
+    pub async fn push_annexb(
+        &self,
+        stream_id: u32,
+        time: MediaTime,
+        buffer: Bytes,
+    ) -> anyhow::Result<()> {
+        let stream = self.stream(stream_id)?.clone();
+        let framing = stream
+            .bitstream_format()
+            .unwrap_or(BitstreamFraming::FourByteLength);
+
+        let nal_units = parse_bitstream(buffer, BitstreamFraming::FourByteStartCode);
+        let is_keyframe = nal_units.iter().any(|nal| {
+            matches!(
+                nut_header(nal),
+                Some(UnitType::SliceLayerWithoutPartitioningIdr)
+            )
+        });
+
+        let frame = Frame {
+            time,
+            dependency: if is_keyframe {
+                FrameDependency::None
+            } else {
+                FrameDependency::Backwards
+            },
+            buffer: frame_nal_units(&nal_units[..], framing).freeze(),
+            stream,
+            received: Instant::now(),
+            metadata: None,
+        };
+
+        self.push(frame).await
+    }
+
+    /// Pushes one or more AAC frames with ADTS headers. Each raw frame
+    /// following the first is offset by 1024 samples.
+    pub async fn push_adts(
+        &self,
+        stream_id: u32,
+        time: MediaTime,
+        mut buffer: Bytes,
+    ) -> anyhow::Result<()> {
+        let stream = self.stream(stream_id)?.clone();
+        let sample_rate = stream
+            .codec
+            .audio()
+            .map(|a| a.sample_rate)
+            .context("ADTS frames pushed to a non-audio stream")?;
+        let frame_duration = 1024 * time.timebase.denominator as u64 / sample_rate as u64;
+
+        let mut pts = time.pts;
+        while !buffer.is_empty() {
+            let (header_len, frame_len) = parse_adts_header(&buffer)?;
+
+            let mut data = buffer.split_to(frame_len);
+            let data = data.split_off(header_len);
+
+            let frame = Frame {
+                time: MediaTime {
+                    pts,
+                    dts: None,
+                    timebase: time.timebase,
+                },
+                dependency: FrameDependency::None,
+                buffer: data,
+                stream: stream.clone(),
+                received: Instant::now(),
+                metadata: None,
+            };
+
+            self.push(frame).await?;
+
+            pts += frame_duration;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the header length and the total frame length of an ADTS frame.
+pub fn parse_adts_header(buffer: &[u8]) -> anyhow::Result<(usize, usize)> {
+    if buffer.len() < 7 || buffer[0] != 0xff || buffer[1] & 0xf0 != 0xf0 {
+        anyhow::bail!("Invalid ADTS header");
+    }
+
+    let protection_absent = buffer[1] & 0x01 == 1;
+    let header_len = if protection_absent { 7 } else { 9 };
+    let frame_len = (((buffer[3] & 0x03) as usize) << 11)
+        | ((buffer[4] as usize) << 3)
+        | ((buffer[5] as usize) >> 5);
+
+    if frame_len < header_len || frame_len > buffer.len() {
+        anyhow::bail!("Invalid ADTS frame length {}", frame_len);
+    }
+
+    Ok((header_len, frame_len))
+}
+
+/// A pull filter which reads [`Frame`]s pushed through a [`FrameInjector`].
+pub struct InjectReadFilter {
+    streams: Vec<Stream>,
+    rx: async_channel::Receiver<Frame>,
+}
+
+#[async_trait::async_trait]
+impl FrameReadFilter for InjectReadFilter {
+    async fn start(&mut self) -> anyhow::Result<Vec<Stream>> {
+        Ok(self.streams.clone())
+    }
+
+    async fn read(&mut self) -> anyhow::Result<Frame> {
+        let frame = self
+            .rx
+            .recv()
+            .await
+            .context("all frame injectors were dropped")?;
+
+        Ok(frame)
+    }
+}
@@ -0,0 +1,298 @@
+use tracing::*;
+
+use std::{collections::VecDeque, str::FromStr};
+
+use crate::{
+    parse_bitstream, BitstreamFraming, Fraction, Frame, FrameDependency, FrameReadFilter, Stream,
+    VideoCodecSpecificInfo,
+};
+
+/// How many intervals between video frames the frame rate is judged by.
+const WINDOW: usize = 60;
+
+/// How much the intervals between video frames may deviate from their mean,
+/// relative to it, before the frame rate is taken to be variable.
+const VARIABLE_DEVIATION: f64 = 0.15;
+
+/// The most frames which fill a single gap, so a stream which stalls isn't
+/// followed by a burst of repeated frames.
+const MAX_REPEATS: u64 = 60;
+
+/// What a [`FrameRateFilter`] does with the timestamps of video, for
+/// outputs which need a constant frame rate, like MPEG-TS to TVs.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum FrameRatePolicy {
+    /// Leaves the timestamps as they are.
+    #[default]
+    Passthrough,
+    /// Moves every frame to the nearest free slot of the frame rate, so the
+    /// intervals between frames are whole frame durations. Frames are never
+    /// dropped, so a faster source still comes out faster.
+    Smooth(Fraction),
+    /// Smooths the timestamps, and fills the gaps between frames with
+    /// repeats of the frame before, as long as no later frame refers to it.
+    Duplicate(Fraction),
+}
+
+impl FromStr for FrameRatePolicy {
+    type Err = anyhow::Error;
+
+    /// Parses `passthrough`, `smooth:<fps>` or `duplicate:<fps>`, where the
+    /// frame rate is like `30` or `30000/1001`.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        if s.is_empty() || s == "passthrough" {
+            return Ok(FrameRatePolicy::Passthrough);
+        }
+
+        let (policy, rate) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Frame rate policy '{}' has no frame rate", s))?;
+        let rate = match rate.split_once('/') {
+            Some((numerator, denominator)) => {
+                Fraction::new(numerator.trim().parse()?, denominator.trim().parse()?)
+            }
+            None => Fraction::new(rate.trim().parse()?, 1),
+        };
+        if rate.numerator == 0 || rate.denominator == 0 {
+            anyhow::bail!("Frame rate policy '{}' has no frame rate", s);
+        }
+
+        match policy {
+            "smooth" => Ok(FrameRatePolicy::Smooth(rate)),
+            "duplicate" => Ok(FrameRatePolicy::Duplicate(rate)),
+            _ => anyhow::bail!("Unknown frame rate policy '{}'", policy),
+        }
+    }
+}
+
+/// The slots of a constant frame rate, from the first video frame.
+struct Grid {
+    /// The decode time of the first slot.
+    origin: u64,
+    /// The length of a slot in the timebase of the video.
+    slot: f64,
+    last_slot: Option<u64>,
+    /// The latest frame, if it can be repeated.
+    repeatable: Option<Frame>,
+}
+
+impl Grid {
+    fn time(&self, slot: u64) -> u64 {
+        self.origin + (slot as f64 * self.slot).round() as u64
+    }
+}
+
+/// Detects video which has a variable frame rate, like screen captures and
+/// phones, and moves its timestamps to a constant frame rate by its
+/// [`FrameRatePolicy`].
+///
+/// Audio is passed through as is, and stays in sync within half a frame.
+pub struct FrameRateFilter {
+    target: Box<dyn FrameReadFilter + Send + Unpin>,
+    policy: FrameRatePolicy,
+    /// The video stream which is retimed, the first one.
+    video: Option<u32>,
+    /// The intervals between the latest video frames, in seconds.
+    intervals: VecDeque<f64>,
+    last_pts: Option<u64>,
+    variable: bool,
+    grid: Option<Grid>,
+    /// The frames which were retimed or repeated but not read yet.
+    pending: VecDeque<Frame>,
+}
+
+impl FrameRateFilter {
+    pub fn new(target: Box<dyn FrameReadFilter + Send + Unpin>, policy: FrameRatePolicy) -> Self {
+        Self {
+            target,
+            policy,
+            video: None,
+            intervals: VecDeque::with_capacity(WINDOW),
+            last_pts: None,
+            variable: false,
+            grid: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Whether the intervals between the latest video frames vary.
+    pub fn is_variable(&self) -> bool {
+        self.variable
+    }
+
+    fn detect(&mut self, frame: &Frame) {
+        let timebase = frame.time.timebase;
+
+        if let Some(last) = self.last_pts.replace(frame.time.pts) {
+            let ticks = frame.time.pts.saturating_sub(last) as f64;
+            let interval = ticks * timebase.numerator as f64 / timebase.denominator as f64;
+
+            if self.intervals.len() == WINDOW {
+                self.intervals.pop_front();
+            }
+            self.intervals.push_back(interval);
+        }
+
+        if self.intervals.len() < WINDOW {
+            return;
+        }
+
+        let mean = self.intervals.iter().sum::<f64>() / WINDOW as f64;
+        let variance = self
+            .intervals
+            .iter()
+            .map(|i| (i - mean).powi(2))
+            .sum::<f64>()
+            / WINDOW as f64;
+        let variable = mean > 0.0 && variance.sqrt() / mean > VARIABLE_DEVIATION;
+
+        if variable != self.variable {
+            if variable {
+                info!("Stream {} has a variable frame rate", frame.stream.id);
+            } else {
+                info!("Stream {} has a constant frame rate", frame.stream.id);
+            }
+            self.variable = variable;
+        }
+    }
+
+    /// Moves a video frame to its slot, after the repeats which fill the
+    /// gap before it.
+    fn retime(&mut self, mut frame: Frame, rate: Fraction, repeat: bool) {
+        let time = frame.time.decode_time();
+        let timebase = frame.time.timebase;
+
+        let grid = self.grid.get_or_insert_with(|| Grid {
+            origin: time,
+            slot: (timebase.denominator as f64 * rate.denominator as f64)
+                / (timebase.numerator as f64 * rate.numerator as f64),
+            last_slot: None,
+            repeatable: None,
+        });
+
+        let nearest = (time.saturating_sub(grid.origin) as f64 / grid.slot).round() as u64;
+        let first = grid.last_slot.map(|last| last + 1).unwrap_or(0);
+        let slot = nearest.max(first);
+
+        if let Some(previous) = grid.repeatable.take() {
+            let last = slot.min(first + MAX_REPEATS);
+
+            for repeated in first..last {
+                let mut copy = previous.clone();
+                set_decode_time(&mut copy, grid.time(repeated));
+                copy.dependency = FrameDependency::Backwards;
+                self.pending.push_back(copy);
+            }
+        }
+
+        set_decode_time(&mut frame, grid.time(slot));
+        grid.last_slot = Some(slot);
+        grid.repeatable = (repeat && is_unreferenced(&frame)).then(|| frame.clone());
+
+        self.pending.push_back(frame);
+    }
+}
+
+/// Moves the decode time of a frame, and its presentation time along with
+/// it.
+fn set_decode_time(frame: &mut Frame, time: u64) {
+    let delta = time as i64 - frame.time.decode_time() as i64;
+
+    frame.time.pts = (frame.time.pts as i64 + delta).max(0) as u64;
+    if frame.time.dts.is_some() {
+        frame.time.dts = Some(time);
+    }
+}
+
+/// Whether no other frame refers to a frame, so decoding it again gives the
+/// same picture.
+fn is_unreferenced(frame: &Frame) -> bool {
+    if frame.is_keyframe() {
+        return true;
+    }
+
+    let video = match frame.stream.codec.video() {
+        Some(video) => video,
+        None => return false,
+    };
+    let framing = frame
+        .stream
+        .bitstream_format()
+        .unwrap_or(BitstreamFraming::FourByteLength);
+    let nal_units = parse_bitstream(frame.buffer.clone(), framing);
+
+    match video.extra {
+        // slices with a nal_ref_idc of 0
+        VideoCodecSpecificInfo::H264 { .. } => nal_units
+            .iter()
+            .filter_map(|nal| nal.first())
+            .filter(|h| matches!(*h & 0x1f, 1..=5))
+            .all(|h| h & 0x60 == 0),
+        // sub-layer non-reference pictures, which have even types up to 14
+        VideoCodecSpecificInfo::H265 { .. } => nal_units
+            .iter()
+            .filter_map(|nal| nal.first())
+            .map(|h| (h >> 1) & 0x3f)
+            .filter(|nal_type| *nal_type < 32)
+            .all(|nal_type| nal_type <= 14 && nal_type % 2 == 0),
+        _ => false,
+    }
+}
+
+#[async_trait::async_trait]
+impl FrameReadFilter for FrameRateFilter {
+    async fn start(&mut self) -> anyhow::Result<Vec<Stream>> {
+        let streams = self.target.start().await?;
+
+        self.video = streams.iter().find(|s| s.is_video()).map(|s| s.id);
+        self.intervals.clear();
+        self.last_pts = None;
+        self.grid = None;
+        self.pending.clear();
+
+        Ok(streams)
+    }
+
+    async fn read(&mut self) -> anyhow::Result<Frame> {
+        loop {
+            if let Some(frame) = self.pending.pop_front() {
+                return Ok(frame);
+            }
+
+            let frame = self.target.read().await?;
+            if Some(frame.stream.id) != self.video {
+                return Ok(frame);
+            }
+
+            self.detect(&frame);
+
+            match self.policy {
+                FrameRatePolicy::Passthrough => return Ok(frame),
+                FrameRatePolicy::Smooth(rate) => self.retime(frame, rate, false),
+                FrameRatePolicy::Duplicate(rate) => self.retime(frame, rate, true),
+            }
+        }
+    }
+}
+
+#[test]
+fn parses_policies() {
+    assert!(matches!("".parse(), Ok(FrameRatePolicy::Passthrough)));
+    assert!(matches!(
+        "smooth:30".parse(),
+        Ok(FrameRatePolicy::Smooth(Fraction {
+            numerator: 30,
+            denominator: 1
+        }))
+    ));
+    assert!(matches!(
+        "duplicate:30000/1001".parse(),
+        Ok(FrameRatePolicy::Duplicate(Fraction {
+            numerator: 30000,
+            denominator: 1001
+        }))
+    ));
+    assert!("smooth:0".parse::<FrameRatePolicy>().is_err());
+    assert!("drop:30".parse::<FrameRatePolicy>().is_err());
+}
@@ -0,0 +1,478 @@
+use std::{fmt::Write, sync::Arc};
+
+use crate::{
+    bits::{read_sei_value, remove_emulation_prevention, BitReader},
+    BitstreamFraming, CodecInfo, CodecTypeInfo, VideoCodecInfo, VideoCodecSpecificInfo,
+};
+
+const NAL_UNIT_TYPE_VPS: u8 = 32;
+const NAL_UNIT_TYPE_SPS: u8 = 33;
+const NAL_UNIT_TYPE_PPS: u8 = 34;
+const NAL_UNIT_TYPE_PREFIX_SEI: u8 = 39;
+
+const PAYLOAD_TYPE_MASTERING_DISPLAY_COLOUR_VOLUME: u32 = 137;
+const PAYLOAD_TYPE_CONTENT_LIGHT_LEVEL_INFO: u32 = 144;
+
+/// The size of the fixed part of the record, before its NAL unit arrays.
+const RECORD_HEADER_SIZE: usize = 23;
+
+/// The `HEVCDecoderConfigurationRecord` of a H.265 stream, which is the
+/// content of the `hvcC` box and what Enhanced RTMP starts a stream with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HevcDecoderConfiguration {
+    pub general_profile_space: u8,
+    pub general_tier_flag: bool,
+    pub general_profile_idc: u8,
+    pub general_profile_compatibility_flags: u32,
+    /// The 48 bits of the general constraint indicator flags.
+    pub general_constraint_indicator_flags: u64,
+    pub general_level_idc: u8,
+    pub min_spatial_segmentation_idc: u16,
+    pub parallelism_type: u8,
+    pub chroma_format_idc: u8,
+    pub bit_depth_luma: u8,
+    pub bit_depth_chroma: u8,
+    pub avg_frame_rate: u16,
+    pub constant_frame_rate: u8,
+    pub num_temporal_layers: u8,
+    pub temporal_id_nested: bool,
+    /// How many bytes the length of each NAL unit of a frame takes.
+    pub length_size: u8,
+    pub arrays: Vec<HevcNalArray>,
+}
+
+/// The NAL units of one type in a [`HevcDecoderConfiguration`], like its
+/// parameter sets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HevcNalArray {
+    /// Whether every NAL unit of the type is in the array, rather than some
+    /// also being in the frames.
+    pub array_completeness: bool,
+    pub nal_unit_type: u8,
+    pub nal_units: Vec<Vec<u8>>,
+}
+
+/// The HDR metadata of a stream, which players need to map its colours to
+/// the display.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HdrMetadata {
+    pub mastering_display: Option<MasteringDisplay>,
+    pub content_light_level: Option<ContentLightLevel>,
+}
+
+/// The colour volume of the display the content was mastered on, as in the
+/// `mastering_display_colour_volume` SEI message and the `mdcv` box.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MasteringDisplay {
+    /// The x and y of the green, blue and red primaries in units of 0.00002.
+    pub display_primaries: [(u16, u16); 3],
+    pub white_point: (u16, u16),
+    /// In units of 0.0001 cd/m².
+    pub max_luminance: u32,
+    pub min_luminance: u32,
+}
+
+impl MasteringDisplay {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let u16_at = |i: usize| Some(u16::from_be_bytes([*data.get(i)?, *data.get(i + 1)?]));
+        let u32_at = |i: usize| Some(u32::from_be_bytes(data.get(i..i + 4)?.try_into().ok()?));
+
+        Some(MasteringDisplay {
+            display_primaries: [
+                (u16_at(0)?, u16_at(2)?),
+                (u16_at(4)?, u16_at(6)?),
+                (u16_at(8)?, u16_at(10)?),
+            ],
+            white_point: (u16_at(12)?, u16_at(14)?),
+            max_luminance: u32_at(16)?,
+            min_luminance: u32_at(20)?,
+        })
+    }
+
+    /// Returns the content of a `mdcv` box, which has the same layout as
+    /// the SEI message.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(24);
+
+        for (x, y) in self.display_primaries {
+            data.extend_from_slice(&x.to_be_bytes());
+            data.extend_from_slice(&y.to_be_bytes());
+        }
+        data.extend_from_slice(&self.white_point.0.to_be_bytes());
+        data.extend_from_slice(&self.white_point.1.to_be_bytes());
+        data.extend_from_slice(&self.max_luminance.to_be_bytes());
+        data.extend_from_slice(&self.min_luminance.to_be_bytes());
+
+        data
+    }
+}
+
+/// The light level of the content in cd/m², as in the
+/// `content_light_level_info` SEI message and the `clli` box.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentLightLevel {
+    pub max_content_light_level: u16,
+    pub max_pic_average_light_level: u16,
+}
+
+impl ContentLightLevel {
+    fn parse(data: &[u8]) -> Option<Self> {
+        match data {
+            [a, b, c, d, ..] => Some(ContentLightLevel {
+                max_content_light_level: u16::from_be_bytes([*a, *b]),
+                max_pic_average_light_level: u16::from_be_bytes([*c, *d]),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Returns the content of a `clli` box.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(4);
+        data.extend_from_slice(&self.max_content_light_level.to_be_bytes());
+        data.extend_from_slice(&self.max_pic_average_light_level.to_be_bytes());
+
+        data
+    }
+}
+
+impl HdrMetadata {
+    /// Reads the metadata from the content of `mdcv` and `clli` boxes.
+    pub fn from_boxes(mdcv: Option<&[u8]>, clli: Option<&[u8]>) -> Option<Self> {
+        let hdr = HdrMetadata {
+            mastering_display: mdcv.and_then(MasteringDisplay::parse),
+            content_light_level: clli.and_then(ContentLightLevel::parse),
+        };
+
+        hdr.is_present().then_some(hdr)
+    }
+
+    fn is_present(&self) -> bool {
+        self.mastering_display.is_some() || self.content_light_level.is_some()
+    }
+
+    /// Adds the HDR messages of a prefix SEI NAL unit to the metadata.
+    fn parse_sei(&mut self, nal_unit: &[u8]) -> Option<()> {
+        // the NAL unit header is two bytes
+        let rbsp = remove_emulation_prevention(nal_unit.get(2..)?);
+        let mut rest = &rbsp[..];
+
+        // the last byte is the RBSP trailing bits
+        while rest.len() > 1 {
+            let payload_type = read_sei_value(&mut rest)?;
+            let payload_size = read_sei_value(&mut rest)? as usize;
+            let payload = rest.get(..payload_size)?;
+            rest = &rest[payload_size..];
+
+            match payload_type {
+                PAYLOAD_TYPE_MASTERING_DISPLAY_COLOUR_VOLUME => {
+                    self.mastering_display = MasteringDisplay::parse(payload);
+                }
+                PAYLOAD_TYPE_CONTENT_LIGHT_LEVEL_INFO => {
+                    self.content_light_level = ContentLightLevel::parse(payload);
+                }
+                _ => {}
+            }
+        }
+
+        Some(())
+    }
+}
+
+impl HevcDecoderConfiguration {
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        if data.len() < RECORD_HEADER_SIZE {
+            anyhow::bail!("HEVC decoder configuration is too short");
+        }
+        if data[0] != 1 {
+            anyhow::bail!("Unsupported HEVC decoder configuration version {}", data[0]);
+        }
+
+        let mut arrays = Vec::new();
+        let mut rest = &data[RECORD_HEADER_SIZE..];
+        for _ in 0..data[22] {
+            let (header, count) = match rest {
+                [header, a, b, tail @ ..] => {
+                    rest = tail;
+                    (*header, u16::from_be_bytes([*a, *b]))
+                }
+                _ => anyhow::bail!("HEVC decoder configuration ends in an array header"),
+            };
+
+            let mut nal_units = Vec::new();
+            for _ in 0..count {
+                let len = match rest {
+                    [a, b, tail @ ..] => {
+                        rest = tail;
+                        u16::from_be_bytes([*a, *b]) as usize
+                    }
+                    _ => anyhow::bail!("HEVC decoder configuration ends in a NAL unit length"),
+                };
+                if rest.len() < len {
+                    anyhow::bail!("HEVC decoder configuration ends in a NAL unit");
+                }
+
+                nal_units.push(rest[..len].to_vec());
+                rest = &rest[len..];
+            }
+
+            arrays.push(HevcNalArray {
+                array_completeness: header & 0x80 != 0,
+                nal_unit_type: header & 0x3f,
+                nal_units,
+            });
+        }
+
+        let mut constraint_flags = [0; 8];
+        constraint_flags[2..].copy_from_slice(&data[6..12]);
+
+        Ok(HevcDecoderConfiguration {
+            general_profile_space: data[1] >> 6,
+            general_tier_flag: data[1] & 0x20 != 0,
+            general_profile_idc: data[1] & 0x1f,
+            general_profile_compatibility_flags: u32::from_be_bytes([
+                data[2], data[3], data[4], data[5],
+            ]),
+            general_constraint_indicator_flags: u64::from_be_bytes(constraint_flags),
+            general_level_idc: data[12],
+            min_spatial_segmentation_idc: u16::from_be_bytes([data[13], data[14]]) & 0x0fff,
+            parallelism_type: data[15] & 0x03,
+            chroma_format_idc: data[16] & 0x03,
+            bit_depth_luma: (data[17] & 0x07) + 8,
+            bit_depth_chroma: (data[18] & 0x07) + 8,
+            avg_frame_rate: u16::from_be_bytes([data[19], data[20]]),
+            constant_frame_rate: data[21] >> 6,
+            num_temporal_layers: (data[21] >> 3) & 0x07,
+            temporal_id_nested: data[21] & 0x04 != 0,
+            length_size: (data[21] & 0x03) + 1,
+            arrays,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(RECORD_HEADER_SIZE);
+
+        data.push(1);
+        data.push(
+            (self.general_profile_space << 6)
+                | ((self.general_tier_flag as u8) << 5)
+                | self.general_profile_idc,
+        );
+        data.extend_from_slice(&self.general_profile_compatibility_flags.to_be_bytes());
+        data.extend_from_slice(&self.general_constraint_indicator_flags.to_be_bytes()[2..]);
+        data.push(self.general_level_idc);
+        data.extend_from_slice(&(0xf000 | self.min_spatial_segmentation_idc).to_be_bytes());
+        data.push(0xfc | self.parallelism_type);
+        data.push(0xfc | self.chroma_format_idc);
+        data.push(0xf8 | (self.bit_depth_luma - 8));
+        data.push(0xf8 | (self.bit_depth_chroma - 8));
+        data.extend_from_slice(&self.avg_frame_rate.to_be_bytes());
+        data.push(
+            (self.constant_frame_rate << 6)
+                | (self.num_temporal_layers << 3)
+                | ((self.temporal_id_nested as u8) << 2)
+                | (self.length_size - 1),
+        );
+        data.push(self.arrays.len() as u8);
+
+        for array in &self.arrays {
+            data.push(((array.array_completeness as u8) << 7) | array.nal_unit_type);
+            data.extend_from_slice(&(array.nal_units.len() as u16).to_be_bytes());
+
+            for nal_unit in &array.nal_units {
+                data.extend_from_slice(&(nal_unit.len() as u16).to_be_bytes());
+                data.extend_from_slice(nal_unit);
+            }
+        }
+
+        data
+    }
+
+    /// Returns the first NAL unit of a type, like a parameter set.
+    fn nal_unit(&self, nal_unit_type: u8) -> Option<&[u8]> {
+        self.arrays
+            .iter()
+            .filter(|array| array.nal_unit_type == nal_unit_type)
+            .find_map(|array| array.nal_units.first())
+            .map(|nal_unit| nal_unit.as_slice())
+    }
+
+    /// Returns the VPS, SPS and PPS of the stream.
+    pub fn parameter_sets(&self) -> Vec<&[u8]> {
+        [NAL_UNIT_TYPE_VPS, NAL_UNIT_TYPE_SPS, NAL_UNIT_TYPE_PPS]
+            .iter()
+            .filter_map(|&nal_unit_type| self.nal_unit(nal_unit_type))
+            .collect()
+    }
+
+    /// Returns the RFC 6381 codec string of the stream, like
+    /// `hvc1.1.6.L93.B0`, as in annex E of ISO/IEC 14496-15.
+    pub fn codec_string(&self) -> String {
+        let mut codec = String::from("hvc1.");
+
+        if self.general_profile_space > 0 {
+            codec.push((b'A' + self.general_profile_space - 1) as char);
+        }
+        // writing to a String can't fail
+        let _ = write!(
+            codec,
+            "{}.{:X}.{}{}",
+            self.general_profile_idc,
+            self.general_profile_compatibility_flags.reverse_bits(),
+            if self.general_tier_flag { 'H' } else { 'L' },
+            self.general_level_idc
+        );
+
+        // trailing bytes of constraint flags which are zero are left out
+        let constraints = &self.general_constraint_indicator_flags.to_be_bytes()[2..];
+        let len = constraints
+            .iter()
+            .rposition(|&b| b != 0)
+            .map_or(0, |last| last + 1);
+        for byte in &constraints[..len] {
+            let _ = write!(codec, ".{:X}", byte);
+        }
+
+        codec
+    }
+
+    /// Reads the HDR metadata from the prefix SEI NAL units of the record,
+    /// if it has any.
+    pub fn hdr_metadata(&self) -> Option<HdrMetadata> {
+        let mut hdr = HdrMetadata::default();
+
+        let sei = self
+            .arrays
+            .iter()
+            .filter(|array| array.nal_unit_type == NAL_UNIT_TYPE_PREFIX_SEI)
+            .flat_map(|array| &array.nal_units);
+        for nal_unit in sei {
+            // the messages before a malformed one are kept
+            let _ = hdr.parse_sei(nal_unit);
+        }
+
+        hdr.is_present().then_some(hdr)
+    }
+
+    /// Makes the codec info of the stream, with the size of the video from
+    /// its SPS and the HDR metadata of its SEI.
+    pub fn codec_info(self) -> anyhow::Result<CodecInfo> {
+        let sps = self
+            .nal_unit(NAL_UNIT_TYPE_SPS)
+            .ok_or_else(|| anyhow::anyhow!("HEVC decoder configuration has no SPS"))?;
+        let (width, height) =
+            sps_dimensions(sps).ok_or_else(|| anyhow::anyhow!("Invalid HEVC SPS"))?;
+
+        let bitstream_format = match self.length_size {
+            2 => BitstreamFraming::TwoByteLength,
+            4 => BitstreamFraming::FourByteLength,
+            size => anyhow::bail!("Unsupported HEVC NAL unit length size {}", size),
+        };
+
+        Ok(CodecInfo {
+            name: "h265",
+            properties: CodecTypeInfo::Video(VideoCodecInfo {
+                width,
+                height,
+                extra: VideoCodecSpecificInfo::H265 {
+                    bitstream_format,
+                    hdr: self.hdr_metadata().map(Arc::new),
+                    config: Arc::new(self),
+                },
+            }),
+        })
+    }
+}
+
+/// Returns the `hvcC` content of a stream, with the NAL unit length size of
+/// its current bitstream format.
+pub fn hevc_decoder_configuration(extra: &VideoCodecSpecificInfo) -> Option<Vec<u8>> {
+    let (bitstream_format, config) = match extra {
+        VideoCodecSpecificInfo::H265 {
+            bitstream_format,
+            config,
+            ..
+        } => (bitstream_format, config),
+        _ => return None,
+    };
+
+    let length_size = match bitstream_format {
+        BitstreamFraming::TwoByteLength => 2,
+        _ => 4,
+    };
+
+    Some(
+        HevcDecoderConfiguration {
+            length_size,
+            ..(**config).clone()
+        }
+        .to_bytes(),
+    )
+}
+
+/// Reads the width and height of the video from a SPS NAL unit, cropped to
+/// its conformance window.
+fn sps_dimensions(sps: &[u8]) -> Option<(u32, u32)> {
+    // the NAL unit header is two bytes
+    let rbsp = remove_emulation_prevention(sps.get(2..)?);
+    let mut r = BitReader::new(&rbsp);
+
+    let _sps_video_parameter_set_id = r.read(4)?;
+    let max_sub_layers_minus1 = r.read(3)?;
+    let _sps_temporal_id_nesting_flag = r.read(1)?;
+
+    // profile_tier_level, where the general profile and level are 96 bits
+    r.skip(96)?;
+    let mut sub_layers = Vec::new();
+    for _ in 0..max_sub_layers_minus1 {
+        let profile_present = r.read(1)? == 1;
+        let level_present = r.read(1)? == 1;
+        sub_layers.push((profile_present, level_present));
+    }
+    if max_sub_layers_minus1 > 0 {
+        r.skip(2 * (8 - max_sub_layers_minus1 as usize))?;
+    }
+    for (profile_present, level_present) in sub_layers {
+        if profile_present {
+            r.skip(88)?;
+        }
+        if level_present {
+            r.skip(8)?;
+        }
+    }
+
+    let _sps_seq_parameter_set_id = r.read_ue()?;
+    let chroma_format_idc = r.read_ue()?;
+    if chroma_format_idc == 3 {
+        let _separate_colour_plane_flag = r.read(1)?;
+    }
+    let width = r.read_ue()?;
+    let height = r.read_ue()?;
+
+    if r.read(1)? == 0 {
+        return Some((width, height));
+    }
+
+    let (sub_width, sub_height) = match chroma_format_idc {
+        1 => (2, 2),
+        2 => (2, 1),
+        _ => (1, 1),
+    };
+    let left = r.read_ue()?;
+    let right = r.read_ue()?;
+    let top = r.read_ue()?;
+    let bottom = r.read_ue()?;
+
+    Some((
+        width.checked_sub(sub_width * (left + right))?,
+        height.checked_sub(sub_height * (top + bottom))?,
+    ))
+}
+
+/// Returns the HDR metadata of a H.265 stream, if it has any.
+pub fn hdr_metadata(extra: &VideoCodecSpecificInfo) -> Option<&HdrMetadata> {
+    match extra {
+        VideoCodecSpecificInfo::H265 { hdr, .. } => hdr.as_deref(),
+        _ => None,
+    }
+}
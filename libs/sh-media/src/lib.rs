@@ -6,18 +6,48 @@ use std::{fmt, sync::Arc, time::Instant};
 
 use bytes::Bytes;
 
+mod av1;
+mod bits;
 mod bitstream_framer;
+mod closed_captions;
 mod file_writer;
+mod filter_graph;
 mod frame_analyzer;
+mod frame_injector;
+mod frame_rate;
+mod hevc;
 mod media_frame_queue;
+mod mp3;
+mod muxer;
+mod opus;
+mod reorder;
+mod sei;
 mod tcp;
+mod tee;
+#[cfg(feature = "test-support")]
+pub mod testing;
+mod vp9;
 mod wait_for_sync_frame;
 
+pub use av1::*;
+pub use bits::BitReader;
 pub use bitstream_framer::*;
+pub use closed_captions::{CcData, ClosedCaption, ClosedCaptionDecoder};
 pub use file_writer::*;
+pub use filter_graph::*;
 pub use frame_analyzer::*;
+pub use frame_injector::*;
+pub use frame_rate::*;
+pub use hevc::*;
 pub use media_frame_queue::*;
+pub use mp3::*;
+pub use muxer::*;
+pub use opus::*;
+pub use reorder::ReorderBuffer;
+pub use sei::*;
 pub use tcp::*;
+pub use tee::*;
+pub use vp9::*;
 pub use wait_for_sync_frame::*;
 
 #[derive(Copy, Clone)]
@@ -94,6 +124,23 @@ pub enum VideoCodecSpecificInfo {
         sps: Arc<Vec<u8>>,
         pps: Arc<Vec<u8>>,
     },
+    Vp9 {
+        profile: u8,
+        level: u8,
+        bit_depth: u8,
+        /// As in the `vpcC` box, 1 for 4:2:0, 2 for 4:2:2 and 3 for 4:4:4.
+        chroma_subsampling: u8,
+        full_range: bool,
+    },
+    H265 {
+        bitstream_format: BitstreamFraming,
+        config: Arc<HevcDecoderConfiguration>,
+        /// The mastering display and content light level of a HDR stream.
+        hdr: Option<Arc<HdrMetadata>>,
+    },
+    Av1 {
+        config: Arc<Av1CodecConfiguration>,
+    },
 }
 
 #[derive(Clone)]
@@ -105,9 +152,11 @@ pub struct VideoCodecInfo {
 
 impl VideoCodecInfo {
     pub fn parameter_sets(&self) -> Option<Vec<u8>> {
-        let VideoCodecSpecificInfo::H264 { sps, pps, .. } = &self.extra;
-
-        let nuts = [sps.as_slice(), pps.as_slice()];
+        let nuts = match &self.extra {
+            VideoCodecSpecificInfo::H264 { sps, pps, .. } => vec![sps.as_slice(), pps.as_slice()],
+            VideoCodecSpecificInfo::H265 { config, .. } => config.parameter_sets(),
+            VideoCodecSpecificInfo::Vp9 { .. } | VideoCodecSpecificInfo::Av1 { .. } => return None,
+        };
 
         Some(frame_nal_units(&nuts[..], BitstreamFraming::FourByteLength).to_vec())
     }
@@ -117,9 +166,11 @@ impl fmt::Debug for VideoCodecInfo {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match &self.extra {
             VideoCodecSpecificInfo::H264 { sps, .. } => {
-                use h264_reader::{nal::sps::SeqParameterSet, rbsp::decode_nal};
+                use h264_reader::nal::sps::SeqParameterSet;
 
-                let sps = SeqParameterSet::from_bytes(&decode_nal(&sps[1..])).unwrap();
+                let sps =
+                    SeqParameterSet::from_bytes(&bits::remove_emulation_prevention(&sps[1..]))
+                        .unwrap();
 
                 //dbg!(&sps);
 
@@ -165,6 +216,32 @@ impl fmt::Debug for VideoCodecInfo {
 
                 Ok(())
             }
+            VideoCodecSpecificInfo::Vp9 {
+                profile, bit_depth, ..
+            } => write!(
+                f,
+                "VP9 (profile {}, {}-bit) {}x{}",
+                profile, bit_depth, self.width, self.height
+            ),
+            VideoCodecSpecificInfo::H265 { config, hdr, .. } => write!(
+                f,
+                "H265 (profile {}, level {}, {}-bit{}) {}x{}",
+                config.general_profile_idc,
+                config.general_level_idc,
+                config.bit_depth_luma,
+                if hdr.is_some() { ", HDR" } else { "" },
+                self.width,
+                self.height
+            ),
+            VideoCodecSpecificInfo::Av1 { config } => write!(
+                f,
+                "AV1 (profile {}, level {}, {}-bit) {}x{}",
+                config.seq_profile,
+                config.seq_level_idx_0,
+                config.bit_depth(),
+                self.width,
+                self.height
+            ),
         }
     }
 }
@@ -173,12 +250,16 @@ impl fmt::Debug for VideoCodecInfo {
 #[derive(Debug, Clone)]
 pub enum AudioCodecSpecificInfo {
     Aac { extra: Vec<u8> },
+    Opus { header: OpusHeader },
+    Mp3,
 }
 
 impl AudioCodecSpecificInfo {
+    /// Returns the `AudioSpecificConfig` of AAC.
     pub fn decoder_specific_data(&self) -> Option<Vec<u8>> {
         match self {
             Self::Aac { extra } => Some(extra.clone()),
+            Self::Opus { .. } | Self::Mp3 => None,
         }
     }
 }
@@ -279,6 +360,10 @@ impl Stream {
                 VideoCodecSpecificInfo::H264 {
                     ref mut bitstream_format,
                     ..
+                }
+                | VideoCodecSpecificInfo::H265 {
+                    ref mut bitstream_format,
+                    ..
                 },
             ..
         }) = Arc::make_mut(&mut self.codec).properties
@@ -289,9 +374,13 @@ impl Stream {
 
     pub fn bitstream_format(&self) -> Option<BitstreamFraming> {
         if let CodecTypeInfo::Video(VideoCodecInfo {
-            extra: VideoCodecSpecificInfo::H264 {
-                bitstream_format, ..
-            },
+            extra:
+                VideoCodecSpecificInfo::H264 {
+                    bitstream_format, ..
+                }
+                | VideoCodecSpecificInfo::H265 {
+                    bitstream_format, ..
+                },
             ..
         }) = self.codec.properties
         {
@@ -301,10 +390,103 @@ impl Stream {
         }
     }
 
+    /// Returns whether frames of `other` can be stored in a container which
+    /// was set up for this stream, i.e. the codec configuration is the same.
+    pub fn is_compatible_with(&self, other: &Stream) -> bool {
+        if self.timebase.denominator != other.timebase.denominator
+            || self.timebase.numerator != other.timebase.numerator
+        {
+            return false;
+        }
+
+        if Arc::ptr_eq(&self.codec, &other.codec) {
+            return true;
+        }
+
+        match (&self.codec.properties, &other.codec.properties) {
+            (CodecTypeInfo::Video(a), CodecTypeInfo::Video(b)) => {
+                a.width == b.width
+                    && a.height == b.height
+                    && std::mem::discriminant(&a.extra) == std::mem::discriminant(&b.extra)
+                    && a.parameter_sets() == b.parameter_sets()
+                    && vp9_decoder_configuration(&a.extra) == vp9_decoder_configuration(&b.extra)
+                    && av1_decoder_configuration(&a.extra) == av1_decoder_configuration(&b.extra)
+                    && hdr_metadata(&a.extra) == hdr_metadata(&b.extra)
+            }
+            (CodecTypeInfo::Audio(a), CodecTypeInfo::Audio(b)) => {
+                a.sample_rate == b.sample_rate
+                    && std::mem::discriminant(&a.extra) == std::mem::discriminant(&b.extra)
+                    && a.extra.decoder_specific_data() == b.extra.decoder_specific_data()
+                    && opus_header(&a.extra) == opus_header(&b.extra)
+            }
+            _ => false,
+        }
+    }
+
     pub fn is_video(&self) -> bool {
         matches!(self.codec.properties, CodecTypeInfo::Video(_))
     }
 
+    pub fn is_h264(&self) -> bool {
+        matches!(
+            &self.codec.properties,
+            CodecTypeInfo::Video(VideoCodecInfo {
+                extra: VideoCodecSpecificInfo::H264 { .. },
+                ..
+            })
+        )
+    }
+
+    pub fn is_h265(&self) -> bool {
+        matches!(
+            &self.codec.properties,
+            CodecTypeInfo::Video(VideoCodecInfo {
+                extra: VideoCodecSpecificInfo::H265 { .. },
+                ..
+            })
+        )
+    }
+
+    pub fn is_av1(&self) -> bool {
+        matches!(
+            &self.codec.properties,
+            CodecTypeInfo::Video(VideoCodecInfo {
+                extra: VideoCodecSpecificInfo::Av1 { .. },
+                ..
+            })
+        )
+    }
+
+    pub fn is_aac(&self) -> bool {
+        matches!(
+            &self.codec.properties,
+            CodecTypeInfo::Audio(AudioCodecInfo {
+                extra: AudioCodecSpecificInfo::Aac { .. },
+                ..
+            })
+        )
+    }
+
+    pub fn is_opus(&self) -> bool {
+        matches!(
+            &self.codec.properties,
+            CodecTypeInfo::Audio(AudioCodecInfo {
+                extra: AudioCodecSpecificInfo::Opus { .. },
+                ..
+            })
+        )
+    }
+
+    pub fn is_mp3(&self) -> bool {
+        matches!(
+            &self.codec.properties,
+            CodecTypeInfo::Audio(AudioCodecInfo {
+                extra: AudioCodecSpecificInfo::Mp3,
+                ..
+            })
+        )
+    }
+
     pub fn is_audio(&self) -> bool {
         matches!(self.codec.properties, CodecTypeInfo::Audio(_))
     }
@@ -326,6 +508,19 @@ pub struct Frame {
     pub stream: Stream,
 
     pub received: Instant,
+
+    /// What was parsed from the frame, like its SEI messages, which is
+    /// shared by every clone of the frame.
+    pub metadata: Option<Arc<FrameMetadata>>,
+}
+
+/// Information carried in a frame besides its picture or samples.
+#[derive(Debug, Clone, Default)]
+pub struct FrameMetadata {
+    pub sei: Vec<SeiMessage>,
+    /// The closed captions which changed with the frame, in the order they
+    /// are shown.
+    pub captions: Vec<ClosedCaption>,
 }
 
 impl Frame {
@@ -418,6 +613,12 @@ impl MediaTime {
         }
     }
 
+    /// When the frame is decoded, which is when it is shown for streams
+    /// without reordered frames.
+    pub fn decode_time(&self) -> u64 {
+        self.dts.unwrap_or(self.pts)
+    }
+
     pub fn in_base(&self, new_timebase: Fraction) -> MediaTime {
         let pts = convert_timebase(self.pts, self.timebase, new_timebase);
         let dts = self
@@ -444,28 +645,136 @@ fn con_test() {
     );
 }
 
-pub struct FilterGraph {
-    read: Box<dyn FrameReadFilter + Unpin + Send>,
-    write: Box<dyn FrameWriteFilter + Unpin + Send>,
+#[test]
+fn in_base_converts_pts_and_dts() {
+    let time = MediaTime {
+        pts: 3000,
+        dts: Some(1500),
+        timebase: Fraction::new(1, 1000),
+    };
+
+    let converted = time.in_base(Fraction::new(1, 90000));
+    assert_eq!(converted.pts, 270000);
+    assert_eq!(converted.dts, Some(135000));
+    assert_eq!(converted.timebase.denominator, 90000);
 }
 
-impl FilterGraph {
-    pub fn new(
-        read: Box<dyn FrameReadFilter + Unpin + Send>,
-        write: Box<dyn FrameWriteFilter + Unpin + Send>,
-    ) -> Self {
-        Self { read, write }
+#[test]
+fn decode_time_defaults_to_pts() {
+    let mut time = MediaTime {
+        pts: 3000,
+        dts: None,
+        timebase: Fraction::new(1, 90000),
+    };
+    assert_eq!(time.decode_time(), 3000);
+
+    time.dts = Some(0);
+    assert_eq!(time.decode_time(), 0);
+}
+
+#[test]
+fn since_is_signed() {
+    let earlier = MediaTime {
+        pts: 1000,
+        dts: None,
+        timebase: Fraction::new(1, 1000),
+    };
+    let later = MediaTime {
+        pts: 1500,
+        ..earlier.clone()
+    };
+
+    assert_eq!(later.since(&earlier).duration, 500);
+    assert_eq!(earlier.since(&later).duration, -500);
+}
+
+/// A frame of a VP9 stream with a millisecond timebase, for tests.
+#[cfg(test)]
+pub(crate) fn test_frame(pts: u64, keyframe: bool) -> Frame {
+    let stream = Stream {
+        id: 0,
+        codec: Arc::new(CodecInfo {
+            name: "vp9",
+            properties: CodecTypeInfo::Video(VideoCodecInfo {
+                width: 1280,
+                height: 720,
+                extra: VideoCodecSpecificInfo::Vp9 {
+                    profile: 0,
+                    level: 31,
+                    bit_depth: 8,
+                    chroma_subsampling: 1,
+                    full_range: false,
+                },
+            }),
+        }),
+        timebase: Fraction::new(1, 1000),
+    };
+
+    Frame {
+        time: MediaTime {
+            pts,
+            dts: None,
+            timebase: stream.timebase,
+        },
+        dependency: if keyframe {
+            FrameDependency::None
+        } else {
+            FrameDependency::Backwards
+        },
+        buffer: Bytes::from_static(&[0; 16]),
+        stream,
+        received: Instant::now(),
+        metadata: None,
     }
+}
 
-    pub async fn run(&mut self) -> anyhow::Result<()> {
-        let streams = self.read.start().await?;
+/// A write filter for tests, which does what its variant says with the
+/// frames written to it.
+#[cfg(test)]
+pub(crate) enum TestSink {
+    /// Sends the PTS of every frame.
+    Collect(tokio::sync::mpsc::UnboundedSender<u64>),
+    /// Fails every write.
+    Fail,
+    /// Never finishes a write, like a sink which is stuck for good.
+    Stall,
+}
+
+/// A read filter for tests, which reads the frames sent to it until its
+/// sender is dropped.
+#[cfg(test)]
+pub(crate) struct TestSource(pub tokio::sync::mpsc::UnboundedReceiver<Frame>);
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl FrameReadFilter for TestSource {
+    async fn start(&mut self) -> anyhow::Result<Vec<Stream>> {
+        Ok(vec![test_frame(0, true).stream])
+    }
 
-        self.write.start(streams).await?;
+    async fn read(&mut self) -> anyhow::Result<Frame> {
+        self.0
+            .recv()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Test source ended"))
+    }
+}
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl FrameWriteFilter for TestSink {
+    async fn start(&mut self, _streams: Vec<Stream>) -> anyhow::Result<()> {
+        Ok(())
+    }
 
-        loop {
-            let frame = self.read.read().await?;
-            // dbg!(&frame);
-            self.write.write(frame).await?;
+    async fn write(&mut self, frame: Frame) -> anyhow::Result<()> {
+        match self {
+            TestSink::Collect(frames) => {
+                let _ = frames.send(frame.time.pts);
+                Ok(())
+            }
+            TestSink::Fail => anyhow::bail!("Test sink failed"),
+            TestSink::Stall => std::future::pending().await,
         }
     }
 }
@@ -500,6 +809,25 @@ pub trait ByteReadFilter {
     async fn read(&mut self) -> anyhow::Result<Bytes>;
 }
 
+/// Reads from `read` until at least `size` bytes are in `buffer`, for
+/// demuxers which need a whole header or box before they can parse it.
+pub async fn fill_buffer(
+    read: &mut (dyn ByteReadFilter + Send + Unpin),
+    buffer: &mut bytes::BytesMut,
+    size: usize,
+) -> anyhow::Result<()> {
+    while buffer.len() < size {
+        let bytes = read.read().await?;
+        if bytes.is_empty() {
+            anyhow::bail!("Stream ended {} bytes short", size - buffer.len());
+        }
+
+        buffer.extend_from_slice(&bytes);
+    }
+
+    Ok(())
+}
+
 pub struct ByteStreamWriteFilter {
     tx: Sender<anyhow::Result<bytes::Bytes>>,
 }
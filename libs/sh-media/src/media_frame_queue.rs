@@ -1,15 +1,39 @@
 use super::{Frame, FrameReadFilter, FrameWriteFilter, Stream};
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
 
 use anyhow::Context;
 use tracing::*;
 
+/// The most frames kept in the GOP cache, about 10 seconds of 60 fps video
+/// along with its audio. Longer GOPs are not cached.
+const MAX_GOP_FRAMES: usize = 2048;
+
 /// A queue which broadcasts [`Frame`] to multiple readers.
 #[derive(Clone, Default)]
 pub struct MediaFrameQueue {
-    // FIXME: alternative to mutex here?
-    targets: Arc<Mutex<Vec<async_channel::Sender<Frame>>>>,
+    targets: Arc<QueueTargets>,
     streams: Arc<Mutex<Vec<Stream>>>,
+    /// Every frame since the latest video keyframe.
+    gop: Arc<Mutex<Vec<Frame>>>,
+    /// How long frames are kept for readers which start behind live.
+    dvr_window: Option<Duration>,
+    dvr: Arc<Mutex<DvrBuffer>>,
+}
+
+#[derive(Default)]
+struct DvrBuffer {
+    /// Every frame received within the DVR window, starting at a video
+    /// keyframe.
+    frames: VecDeque<Frame>,
+    /// How many video keyframes have left the window.
+    dropped_keyframes: u64,
 }
 
 impl MediaFrameQueue {
@@ -17,8 +41,22 @@ impl MediaFrameQueue {
         Self::default()
     }
 
+    /// Creates a queue which keeps the frames of the last `window`, so
+    /// readers can start behind live.
+    pub fn with_dvr_window(window: Duration) -> Self {
+        MediaFrameQueue {
+            dvr_window: Some(window),
+            ..Self::default()
+        }
+    }
+
     pub fn push(&self, frame: Frame) {
-        let mut targets = self.targets.lock().unwrap();
+        // taken before the frame is cached, like when a receiver is added,
+        // so a new receiver gets the frame either from the cache or live
+        let mut targets = self.targets.lock();
+
+        self.cache_frame(&frame);
+        self.keep_frame(&frame);
 
         /*let lens = targets
             .iter()
@@ -49,6 +87,8 @@ impl MediaFrameQueue {
 
             targets.remove(idx);
         }
+
+        self.targets.update_readers(&targets);
     }
 
     pub fn get_streams(&self) -> Vec<Stream> {
@@ -58,17 +98,289 @@ impl MediaFrameQueue {
     }
 
     pub fn get_receiver(&self) -> MediaFrameQueueReceiver {
-        let (send, recv) = async_channel::bounded(1024);
+        let mut targets = self.targets.lock();
+        let (send, recv) = self.targets.bounded(1024);
 
         debug!("Adding frame queue target");
 
-        let mut targets = self.targets.lock().unwrap();
         targets.push(send);
+        self.targets.update_readers(&targets);
 
         let streams = &*self.streams.lock().unwrap();
 
-        MediaFrameQueueReceiver::new(streams.clone(), recv)
+        recv.with_streams(streams.clone())
+    }
+
+    /// Returns a receiver which starts with the frames of the current GOP,
+    /// so that the first frame it reads is a video keyframe and players can
+    /// render right away instead of waiting for the next one.
+    pub fn get_receiver_from_keyframe(&self) -> MediaFrameQueueReceiver {
+        // hold the targets lock so no frame is cached or pushed in between
+        // the cached GOP and the live frames
+        let mut targets = self.targets.lock();
+        let gop = self.gop.lock().unwrap();
+
+        // the channel fits the cached GOP on top of the usual live buffer
+        let (send, recv) = self.targets.bounded(1024 + gop.len());
+
+        for frame in gop.iter() {
+            let _ = send.try_send(frame.clone());
+        }
+
+        debug!(
+            "Adding frame queue target with {} cached frames",
+            send.len()
+        );
+
+        targets.push(send);
+        self.targets.update_readers(&targets);
+
+        let streams = &*self.streams.lock().unwrap();
+
+        recv.with_streams(streams.clone())
+    }
+
+    /// Returns a receiver which starts at the latest video keyframe that
+    /// was received at least `behind` ago, as far back as the DVR window
+    /// allows.
+    ///
+    /// Without a DVR window this is the same as
+    /// [`MediaFrameQueue::get_receiver_from_keyframe`].
+    pub fn get_receiver_behind(&self, behind: Duration) -> MediaFrameQueueReceiver {
+        if self.dvr_window.is_none() {
+            return self.get_receiver_from_keyframe();
+        }
+
+        let mut targets = self.targets.lock();
+        let dvr = &self.dvr.lock().unwrap().frames;
+
+        let start = Instant::now().checked_sub(behind);
+        let position = dvr
+            .iter()
+            .rposition(|frame| {
+                is_video_keyframe(frame) && start.is_none_or(|start| frame.received <= start)
+            })
+            .unwrap_or(0);
+
+        // the channel fits the frames from the DVR window on top of the
+        // usual live buffer
+        let backlog = dvr.len() - position;
+        let (send, recv) = self.targets.bounded(1024 + backlog);
+
+        for frame in dvr.iter().skip(position) {
+            let _ = send.try_send(frame.clone());
+        }
+
+        debug!(
+            "Adding frame queue target with {} frames behind live",
+            backlog
+        );
+
+        targets.push(send);
+        self.targets.update_readers(&targets);
+
+        let streams = &*self.streams.lock().unwrap();
+
+        recv.with_streams(streams.clone())
+    }
+
+    /// Returns a receiver which starts at the cached video keyframe with the
+    /// time `pts`, e.g. to continue where a reader left off, or `None` if
+    /// it has left both the GOP cache and the DVR window.
+    pub fn get_receiver_at_keyframe(&self, pts: u64) -> Option<MediaFrameQueueReceiver> {
+        let mut targets = self.targets.lock();
+        let dvr = &self.dvr.lock().unwrap().frames;
+        let gop = self.gop.lock().unwrap();
+
+        let is_start = |frame: &Frame| is_video_keyframe(frame) && frame.time.pts == pts;
+        let frames: Vec<&Frame> = if let Some(position) = dvr.iter().position(is_start) {
+            dvr.iter().skip(position).collect()
+        } else if gop.first().is_some_and(is_start) {
+            gop.iter().collect()
+        } else {
+            return None;
+        };
+
+        let (send, recv) = self.targets.bounded(1024 + frames.len());
+        for frame in frames {
+            let _ = send.try_send(frame.clone());
+        }
+
+        debug!(
+            "Adding frame queue target resuming with {} cached frames",
+            send.len()
+        );
+
+        targets.push(send);
+        self.targets.update_readers(&targets);
+
+        let streams = &*self.streams.lock().unwrap();
+
+        Some(recv.with_streams(streams.clone()))
+    }
+
+    /// Returns how many receivers read from the queue.
+    pub fn readers(&self) -> usize {
+        self.targets.readers.load(Ordering::Relaxed)
+    }
+
+    fn keep_frame(&self, frame: &Frame) {
+        let window = match self.dvr_window {
+            Some(window) => window,
+            None => return,
+        };
+
+        let mut dvr = self.dvr.lock().unwrap();
+        let DvrBuffer {
+            frames,
+            dropped_keyframes,
+        } = &mut *dvr;
+
+        if frames.is_empty() && !is_video_keyframe(frame) {
+            return;
+        }
+        frames.push_back(frame.clone());
+
+        // drop the frames which are too old, and then the rest of their GOP
+        // so the window starts at a keyframe
+        let oldest = match Instant::now().checked_sub(window) {
+            Some(oldest) => oldest,
+            None => return,
+        };
+        while frames.front().is_some_and(|f| f.received < oldest) {
+            if frames.pop_front().is_some_and(|f| is_video_keyframe(&f)) {
+                *dropped_keyframes += 1;
+            }
+        }
+        while frames.front().is_some_and(|f| !is_video_keyframe(f)) {
+            frames.pop_front();
+        }
+    }
+
+    /// Returns the video keyframes in the DVR window, along with how many
+    /// keyframes have left the window before the first of them.
+    pub fn dvr_keyframes(&self) -> (u64, Vec<Frame>) {
+        let dvr = self.dvr.lock().unwrap();
+
+        let keyframes = dvr
+            .frames
+            .iter()
+            .filter(|frame| is_video_keyframe(frame))
+            .cloned()
+            .collect();
+
+        (dvr.dropped_keyframes, keyframes)
     }
+
+    fn cache_frame(&self, frame: &Frame) {
+        let mut gop = self.gop.lock().unwrap();
+
+        if frame.stream.is_video() && frame.is_keyframe() {
+            gop.clear();
+        } else if gop.is_empty() || gop.len() >= MAX_GOP_FRAMES {
+            gop.clear();
+            return;
+        }
+
+        gop.push(frame.clone());
+    }
+}
+
+/// The readers of a queue, which remove themselves when they are dropped
+/// so their buffered frames are freed right away instead of at the next
+/// frame.
+#[derive(Default)]
+struct QueueTargets {
+    list: Mutex<Vec<QueueTarget>>,
+    next_id: AtomicU64,
+    readers: AtomicUsize,
+}
+
+impl QueueTargets {
+    fn lock(&self) -> MutexGuard<'_, Vec<QueueTarget>> {
+        self.list.lock().unwrap()
+    }
+
+    /// Creates a reader, which has to be added to the targets.
+    fn bounded(self: &Arc<Self>, capacity: usize) -> (QueueTarget, MediaFrameQueueReceiver) {
+        let (send, recv) = async_channel::bounded(capacity);
+        let backlog = QueueBacklog::default();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+        let target = QueueTarget {
+            id,
+            send,
+            backlog: backlog.clone(),
+        };
+        let receiver = MediaFrameQueueReceiver {
+            streams: Vec::new(),
+            recv,
+            backlog,
+            detach: Some((id, self.clone())),
+        };
+
+        (target, receiver)
+    }
+
+    fn update_readers(&self, targets: &[QueueTarget]) {
+        self.readers.store(targets.len(), Ordering::Relaxed);
+    }
+
+    fn remove(&self, id: u64) {
+        let mut targets = self.lock();
+        targets.retain(|target| target.id != id);
+
+        self.update_readers(&targets);
+    }
+}
+
+/// The sending end of a reader of a queue.
+struct QueueTarget {
+    id: u64,
+    send: async_channel::Sender<Frame>,
+    backlog: QueueBacklog,
+}
+
+impl QueueTarget {
+    /// Sends a frame to the reader, returning why it couldn't without the
+    /// frame, which the queue has no use for.
+    fn try_send(&self, frame: Frame) -> Result<(), async_channel::TrySendError<()>> {
+        use async_channel::TrySendError;
+
+        let len = frame.buffer.len();
+
+        // counted before it is sent, so a reader never takes away more than
+        // was added
+        self.backlog.0.fetch_add(len, Ordering::Relaxed);
+        let result = self.send.try_send(frame);
+        if result.is_err() {
+            self.backlog.0.fetch_sub(len, Ordering::Relaxed);
+        }
+
+        result.map_err(|e| match e {
+            TrySendError::Full(_) => TrySendError::Full(()),
+            TrySendError::Closed(_) => TrySendError::Closed(()),
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.send.len()
+    }
+}
+
+/// How many bytes of frames a [`MediaFrameQueueReceiver`] has yet to read,
+/// which can be checked from elsewhere while it is read from.
+#[derive(Debug, Clone, Default)]
+pub struct QueueBacklog(Arc<AtomicUsize>);
+
+impl QueueBacklog {
+    pub fn bytes(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+fn is_video_keyframe(frame: &Frame) -> bool {
+    frame.stream.is_video() && frame.is_keyframe()
 }
 
 #[async_trait::async_trait]
@@ -87,14 +399,34 @@ impl FrameWriteFilter for MediaFrameQueue {
 }
 
 /// A pull filter which reads [`MediaFrame`]s from a [`MediaFrameQueue`].
+///
+/// The receiver stops reading from the queue when it is dropped, also when
+/// the task reading it panics.
 pub struct MediaFrameQueueReceiver {
     streams: Vec<Stream>,
     recv: async_channel::Receiver<Frame>,
+    backlog: QueueBacklog,
+    /// The target of the receiver in the queue, which is removed on drop.
+    detach: Option<(u64, Arc<QueueTargets>)>,
+}
+
+impl Drop for MediaFrameQueueReceiver {
+    fn drop(&mut self) {
+        if let Some((id, targets)) = self.detach.take() {
+            targets.remove(id);
+        }
+    }
 }
 
 impl MediaFrameQueueReceiver {
-    fn new(streams: Vec<Stream>, recv: async_channel::Receiver<Frame>) -> Self {
-        MediaFrameQueueReceiver { streams, recv }
+    fn with_streams(mut self, streams: Vec<Stream>) -> Self {
+        self.streams = streams;
+        self
+    }
+
+    /// Returns how far behind the queue the receiver is.
+    pub fn backlog(&self) -> QueueBacklog {
+        self.backlog.clone()
     }
 }
 
@@ -113,7 +445,49 @@ impl FrameReadFilter for MediaFrameQueueReceiver {
             .recv()
             .await
             .context("failed to read frame from queue")?;
+        self.backlog
+            .0
+            .fetch_sub(frame.buffer.len(), Ordering::Relaxed);
 
         Ok(frame)
     }
 }
+
+#[test]
+fn joining_mid_push_gets_every_frame_once() {
+    let queue = MediaFrameQueue::new();
+
+    let pusher = {
+        let queue = queue.clone();
+        std::thread::spawn(move || {
+            for pts in 0..20000 {
+                queue.push(crate::test_frame(pts, pts % 50 == 0));
+            }
+        })
+    };
+
+    let check = |receiver: MediaFrameQueueReceiver| {
+        let pts = std::iter::from_fn(|| receiver.recv.try_recv().ok())
+            .map(|frame| frame.time.pts)
+            .collect::<Vec<_>>();
+
+        assert!(
+            pts.windows(2).all(|pair| pair[1] == pair[0] + 1),
+            "frames were skipped or repeated: {:?}",
+            pts
+        );
+    };
+
+    // the receivers are checked a while after they joined, when they got
+    // both cached and live frames
+    let mut receivers = VecDeque::new();
+    while !pusher.is_finished() {
+        receivers.push_back(queue.get_receiver_from_keyframe());
+        if receivers.len() > 16 {
+            check(receivers.pop_front().unwrap());
+        }
+    }
+    pusher.join().unwrap();
+
+    receivers.into_iter().for_each(check);
+}
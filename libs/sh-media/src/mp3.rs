@@ -0,0 +1,69 @@
+use crate::{AudioCodecInfo, AudioCodecSpecificInfo, CodecInfo, CodecTypeInfo, SoundType};
+
+/// The sample rates of MPEG-1 audio, which are halved for MPEG-2 and
+/// quartered for MPEG-2.5.
+const SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+
+/// The header of an MPEG audio layer III frame. MP3 has no decoder
+/// configuration, every frame starts with the format of the audio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mp3FrameHeader {
+    pub sample_rate: u32,
+    pub channels: u8,
+}
+
+impl Mp3FrameHeader {
+    /// Parses the header at the start of a frame, or returns `None` if the
+    /// frame is not MPEG audio layer III.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let header = data.get(..4)?;
+
+        // 11 bits of frame sync
+        if header[0] != 0xff || header[1] & 0xe0 != 0xe0 {
+            return None;
+        }
+
+        let version = (header[1] >> 3) & 0x03;
+        let layer = (header[1] >> 1) & 0x03;
+        if layer != 0b01 {
+            return None;
+        }
+
+        let sample_rate = *SAMPLE_RATES.get(((header[2] >> 2) & 0x03) as usize)?;
+        let sample_rate = match version {
+            0b11 => sample_rate,
+            0b10 => sample_rate / 2,
+            0b00 => sample_rate / 4,
+            _ => return None,
+        };
+
+        // the last channel mode is single channel
+        let channels = if header[3] >> 6 == 0b11 { 1 } else { 2 };
+
+        Some(Mp3FrameHeader {
+            sample_rate,
+            channels,
+        })
+    }
+
+    pub fn codec_info(&self) -> CodecInfo {
+        mp3_codec_info(self.sample_rate, self.channels)
+    }
+}
+
+/// Makes the codec info of an MP3 stream.
+pub fn mp3_codec_info(sample_rate: u32, channels: u8) -> CodecInfo {
+    CodecInfo {
+        name: "mp3",
+        properties: CodecTypeInfo::Audio(AudioCodecInfo {
+            sample_rate,
+            sample_bpp: 16,
+            sound_type: if channels == 1 {
+                SoundType::Mono
+            } else {
+                SoundType::Stereo
+            },
+            extra: AudioCodecSpecificInfo::Mp3,
+        }),
+    }
+}
@@ -0,0 +1,21 @@
+use crate::{ByteWriteFilter2, FrameWriteFilter};
+
+/// A container format which frames are muxed into, apart from how the
+/// muxed bytes are delivered.
+///
+/// Transports pair a muxer with a delivery layer, like a file, a socket or
+/// an HTTP body, instead of muxing on their own.
+pub trait Muxer: Send + Sync {
+    /// The MIME type of the muxed bytes, e.g. for HTTP responses.
+    fn content_type(&self) -> &'static str;
+
+    /// The extension of files in the format, without the dot.
+    fn extension(&self) -> &'static str;
+
+    /// Creates the filter which muxes frames into `output`, which should be
+    /// started at a keyframe.
+    fn mux(
+        &self,
+        output: Box<dyn ByteWriteFilter2 + Send + Unpin>,
+    ) -> Box<dyn FrameWriteFilter + Send + Unpin>;
+}
@@ -0,0 +1,127 @@
+use crate::{AudioCodecInfo, AudioCodecSpecificInfo, CodecInfo, CodecTypeInfo, SoundType};
+
+/// The sample rate Opus is always decoded at.
+pub const OPUS_SAMPLE_RATE: u32 = 48000;
+
+/// The parameters of an Opus stream which the decoder needs, as in the
+/// `OpusHead` identification header of Ogg Opus, which is also what
+/// Matroska stores, and the `dOps` box of the Opus ISO media file format
+/// binding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpusHeader {
+    pub channels: u8,
+    /// The samples at 48 kHz to skip at the start of the stream.
+    pub pre_skip: u16,
+    /// The sample rate of the source, which is only informational.
+    pub input_sample_rate: u32,
+    /// The gain in Q7.8 dB to apply when decoding.
+    pub output_gain: i16,
+}
+
+impl OpusHeader {
+    /// A header of a stream with `channels` channels, as WebRTC sends, which
+    /// has no pre-skip because it is not encoded with one.
+    pub fn new(channels: u8) -> Self {
+        OpusHeader {
+            channels,
+            pre_skip: 0,
+            input_sample_rate: OPUS_SAMPLE_RATE,
+            output_gain: 0,
+        }
+    }
+
+    /// Parses an `OpusHead` identification header.
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        if data.len() < 19 || &data[..8] != b"OpusHead" {
+            anyhow::bail!("Invalid Opus identification header");
+        }
+        if data[18] != 0 {
+            anyhow::bail!("Unsupported Opus channel mapping family {}", data[18]);
+        }
+
+        Ok(OpusHeader {
+            channels: data[9],
+            pre_skip: u16::from_le_bytes([data[10], data[11]]),
+            input_sample_rate: u32::from_le_bytes([data[12], data[13], data[14], data[15]]),
+            output_gain: i16::from_le_bytes([data[16], data[17]]),
+        })
+    }
+
+    /// Parses the content of a `dOps` box.
+    pub fn parse_dops(data: &[u8]) -> anyhow::Result<Self> {
+        if data.len() < 11 {
+            anyhow::bail!("dOps box is too short");
+        }
+        if data[0] != 0 {
+            anyhow::bail!("Unsupported dOps version {}", data[0]);
+        }
+        if data[10] != 0 {
+            anyhow::bail!("Unsupported Opus channel mapping family {}", data[10]);
+        }
+
+        Ok(OpusHeader {
+            channels: data[1],
+            pre_skip: u16::from_be_bytes([data[2], data[3]]),
+            input_sample_rate: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+            output_gain: i16::from_be_bytes([data[8], data[9]]),
+        })
+    }
+
+    /// Returns the `OpusHead` identification header, which is little
+    /// endian.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(19);
+
+        data.extend_from_slice(b"OpusHead");
+        // version 1
+        data.push(1);
+        data.push(self.channels);
+        data.extend_from_slice(&self.pre_skip.to_le_bytes());
+        data.extend_from_slice(&self.input_sample_rate.to_le_bytes());
+        data.extend_from_slice(&self.output_gain.to_le_bytes());
+        // mono or stereo, without a channel mapping table
+        data.push(0);
+
+        data
+    }
+
+    /// Returns the content of a `dOps` box, which has the fields of the
+    /// identification header in big endian.
+    pub fn to_dops(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(11);
+
+        // version 0
+        data.push(0);
+        data.push(self.channels);
+        data.extend_from_slice(&self.pre_skip.to_be_bytes());
+        data.extend_from_slice(&self.input_sample_rate.to_be_bytes());
+        data.extend_from_slice(&self.output_gain.to_be_bytes());
+        data.push(0);
+
+        data
+    }
+
+    pub fn codec_info(self) -> CodecInfo {
+        CodecInfo {
+            name: "opus",
+            properties: CodecTypeInfo::Audio(AudioCodecInfo {
+                sample_rate: OPUS_SAMPLE_RATE,
+                sample_bpp: 16,
+                sound_type: if self.channels == 1 {
+                    SoundType::Mono
+                } else {
+                    SoundType::Stereo
+                },
+                extra: AudioCodecSpecificInfo::Opus { header: self },
+            }),
+        }
+    }
+}
+
+/// Returns the `OpusHead` identification header of a stream.
+pub fn opus_header(extra: &AudioCodecSpecificInfo) -> Option<&OpusHeader> {
+    match extra {
+        AudioCodecSpecificInfo::Opus { header } => Some(header),
+        _ => None,
+    }
+}
@@ -0,0 +1,160 @@
+use bytes::Bytes;
+use tokio::time::{Duration, Instant};
+use tracing::*;
+
+use std::collections::{BTreeMap, HashMap};
+
+/// Puts the packets of a transport which retransmits lost packets, like SRT
+/// or RIST, back in order, and tells which of them to request again.
+///
+/// Sequence numbers are extended to 64 bits by the transport, so they don't
+/// wrap around. A missing packet is given up on once a later packet has
+/// waited for the latency.
+pub struct ReorderBuffer {
+    /// The name of the transport, for logs.
+    transport: &'static str,
+    latency: Duration,
+    packets: BTreeMap<u64, (Instant, Bytes)>,
+    /// The sequence number of the next packet in order, once it is known.
+    next: Option<u64>,
+    highest: Option<u64>,
+    /// When missing packets were last requested again.
+    requested: HashMap<u64, Instant>,
+}
+
+impl ReorderBuffer {
+    /// Creates a buffer which starts at the first packet added.
+    pub fn new(transport: &'static str, latency: Duration) -> Self {
+        ReorderBuffer {
+            transport,
+            latency,
+            packets: BTreeMap::new(),
+            next: None,
+            highest: None,
+            requested: HashMap::new(),
+        }
+    }
+
+    /// Creates a buffer which starts at `seq`, for transports which agree
+    /// on the first sequence number beforehand.
+    pub fn starting_at(transport: &'static str, seq: u64, latency: Duration) -> Self {
+        ReorderBuffer {
+            next: Some(seq),
+            ..ReorderBuffer::new(transport, latency)
+        }
+    }
+
+    /// The sequence number of the next packet in order.
+    pub fn next(&self) -> Option<u64> {
+        self.next
+    }
+
+    /// The highest sequence number added.
+    pub fn highest(&self) -> Option<u64> {
+        self.highest
+    }
+
+    /// How many packets are waiting for the ones before them.
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    pub fn insert(&mut self, seq: u64, payload: &[u8]) {
+        let next = *self.next.get_or_insert(seq);
+        if seq < next {
+            // a retransmission which arrived too late, or a duplicate
+            return;
+        }
+
+        self.highest = Some(self.highest.map_or(seq, |h| h.max(seq)));
+        self.requested.remove(&seq);
+        self.packets
+            .entry(seq)
+            .or_insert_with(|| (Instant::now(), Bytes::copy_from_slice(payload)));
+    }
+
+    /// Returns the next payload in sequence order, skipping missing packets
+    /// once a later packet has waited for the length of the latency.
+    pub fn next_payload(&mut self) -> Option<Bytes> {
+        let next = self.next?;
+
+        if let Some((_, payload)) = self.packets.remove(&next) {
+            self.next = Some(next + 1);
+            return Some(payload);
+        }
+
+        let (&first, (received, _)) = self.packets.iter().next()?;
+        if received.elapsed() < self.latency {
+            return None;
+        }
+
+        warn!("Lost {} packets {} to {}", self.transport, next, first - 1);
+
+        self.requested.retain(|&seq, _| seq >= first);
+        self.next = Some(first);
+        self.next_payload()
+    }
+
+    /// Returns up to `max` missing packets which have not been requested
+    /// again within a quarter of the latency, as requested now.
+    pub fn request_missing(&mut self, max: usize) -> Vec<u64> {
+        let (next, highest) = match (self.next, self.highest) {
+            (Some(next), Some(highest)) => (next, highest),
+            _ => return Vec::new(),
+        };
+
+        let now = Instant::now();
+        let missing = (next..highest)
+            .filter(|seq| !self.packets.contains_key(seq))
+            .filter(|seq| {
+                self.requested
+                    .get(seq)
+                    .map(|at| now.duration_since(*at) >= self.latency / 4)
+                    .unwrap_or(true)
+            })
+            .take(max)
+            .collect::<Vec<_>>();
+
+        for seq in &missing {
+            self.requested.insert(*seq, now);
+        }
+
+        missing
+    }
+}
+
+#[test]
+fn reorders_packets() {
+    let mut buffer = ReorderBuffer::new("test", Duration::from_secs(60));
+    buffer.insert(10, b"a");
+    buffer.insert(12, b"c");
+    buffer.insert(14, b"e");
+
+    assert_eq!(buffer.next_payload().as_deref(), Some(&b"a"[..]));
+    assert_eq!(buffer.next_payload(), None);
+    assert_eq!(buffer.request_missing(16), [11, 13]);
+    // requested too recently to be requested again
+    assert!(buffer.request_missing(16).is_empty());
+
+    buffer.insert(11, b"b");
+    // too late
+    buffer.insert(9, b"z");
+    assert_eq!(buffer.next_payload().as_deref(), Some(&b"b"[..]));
+    assert_eq!(buffer.next_payload().as_deref(), Some(&b"c"[..]));
+    assert_eq!(buffer.next_payload(), None);
+    assert_eq!(buffer.len(), 1);
+}
+
+#[test]
+fn skips_lost_packets() {
+    let mut buffer = ReorderBuffer::starting_at("test", 5, Duration::ZERO);
+    buffer.insert(7, b"c");
+
+    assert_eq!(buffer.next_payload().as_deref(), Some(&b"c"[..]));
+    assert_eq!(buffer.next(), Some(8));
+    assert!(buffer.is_empty());
+}
@@ -0,0 +1,352 @@
+use std::{collections::HashMap, sync::Arc};
+
+use bytes::Bytes;
+use h264_reader::nal::sps::SeqParameterSet;
+use tracing::*;
+
+use crate::{
+    bits::{read_sei_value, remove_emulation_prevention, BitReader},
+    closed_captions::CaptionExtractor,
+    parse_bitstream, BitstreamFraming, CcData, CodecTypeInfo, Frame, FrameReadFilter, Stream,
+    VideoCodecInfo, VideoCodecSpecificInfo,
+};
+
+const NAL_UNIT_TYPE_SEI: u8 = 6;
+
+const PAYLOAD_TYPE_PIC_TIMING: u32 = 1;
+const PAYLOAD_TYPE_USER_DATA_REGISTERED: u32 = 4;
+const PAYLOAD_TYPE_USER_DATA_UNREGISTERED: u32 = 5;
+
+/// A SEI message of a H.264 frame which is of use after ingest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SeiMessage {
+    PicTiming(PicTiming),
+    /// Data identified by a UUID, which encoders use for things like their
+    /// settings or their own timecodes.
+    UserDataUnregistered {
+        uuid: [u8; 16],
+        payload: Bytes,
+    },
+    /// The CEA-608 and CEA-708 caption data of ATSC A/53, which is carried
+    /// in registered user data.
+    CaptionData(Vec<CcData>),
+}
+
+/// The `pic_timing` SEI message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PicTiming {
+    pub cpb_removal_delay: Option<u32>,
+    pub dpb_output_delay: Option<u32>,
+    /// Whether the picture is a frame, a field or a repeated frame or field,
+    /// as in table D-1 of H.264.
+    pub pic_struct: Option<u8>,
+    /// The timecode of each field or frame of the picture which has one.
+    pub timecodes: Vec<Timecode>,
+}
+
+/// A clock timestamp of a picture, with the parts the encoder left out
+/// carried over from the previous one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Timecode {
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+    pub frames: u8,
+    /// Whether frame numbers were skipped, as in drop-frame timecode.
+    pub dropped_frames: bool,
+    pub discontinuity: bool,
+    /// The offset from the timecode in units of the SPS clock.
+    pub time_offset: i32,
+}
+
+/// What a `pic_timing` message holds, which is decided by the SPS.
+#[derive(Debug, Clone, Default)]
+struct PicTimingLayout {
+    /// The lengths of the CPB removal and DPB output delays, if present.
+    delay_lengths: Option<(usize, usize)>,
+    pic_struct_present: bool,
+    time_offset_length: usize,
+}
+
+impl PicTimingLayout {
+    fn from_sps(sps: &[u8]) -> Option<Self> {
+        let sps = SeqParameterSet::from_bytes(&remove_emulation_prevention(sps.get(1..)?)).ok()?;
+        let vui = sps.vui_parameters?;
+        let hrd = vui
+            .nal_hrd_parameters
+            .as_ref()
+            .or(vui.vcl_hrd_parameters.as_ref());
+
+        Some(PicTimingLayout {
+            delay_lengths: hrd.map(|hrd| {
+                (
+                    hrd.cpb_removal_delay_length_minus1 as usize + 1,
+                    hrd.dpb_output_delay_length_minus1 as usize + 1,
+                )
+            }),
+            pic_struct_present: vui.pic_struct_present_flag,
+            time_offset_length: hrd.map(|hrd| hrd.time_offset_length as usize).unwrap_or(24),
+        })
+    }
+}
+
+/// Parses the SEI messages of a H.264 stream.
+struct SeiParser {
+    framing: BitstreamFraming,
+    layout: Option<PicTimingLayout>,
+    last_timecode: Timecode,
+    captions: CaptionExtractor,
+}
+
+impl SeiParser {
+    fn new(video: &VideoCodecInfo) -> Option<Self> {
+        let (framing, sps) = match &video.extra {
+            VideoCodecSpecificInfo::H264 {
+                bitstream_format,
+                sps,
+                ..
+            } => (*bitstream_format, sps),
+            _ => return None,
+        };
+
+        let layout = PicTimingLayout::from_sps(sps);
+        if layout.is_none() {
+            debug!("Could not read the SPS, pic_timing messages are skipped");
+        }
+
+        Some(SeiParser {
+            framing,
+            layout,
+            last_timecode: Timecode::default(),
+            captions: CaptionExtractor::new(),
+        })
+    }
+
+    fn parse(&mut self, frame: &Frame) -> Vec<SeiMessage> {
+        let mut messages = Vec::new();
+
+        for nal in parse_bitstream(frame.buffer.clone(), self.framing) {
+            if nal.first().map(|h| h & 0x1f) != Some(NAL_UNIT_TYPE_SEI) {
+                continue;
+            }
+
+            let rbsp = remove_emulation_prevention(&nal[1..]);
+            if self.parse_nal(&rbsp, &mut messages).is_none() {
+                trace!("Skipped the rest of a malformed SEI NAL");
+            }
+        }
+
+        messages
+    }
+
+    /// Parses the messages of a SEI NAL unit without its header.
+    fn parse_nal(&mut self, mut rbsp: &[u8], messages: &mut Vec<SeiMessage>) -> Option<()> {
+        // the last byte is the RBSP trailing bits
+        while rbsp.len() > 1 {
+            let payload_type = read_sei_value(&mut rbsp)?;
+            let payload_size = read_sei_value(&mut rbsp)? as usize;
+            let payload = rbsp.get(..payload_size)?;
+            rbsp = &rbsp[payload_size..];
+
+            let message = match payload_type {
+                PAYLOAD_TYPE_PIC_TIMING => self.parse_pic_timing(payload),
+                PAYLOAD_TYPE_USER_DATA_REGISTERED => parse_caption_data(payload),
+                PAYLOAD_TYPE_USER_DATA_UNREGISTERED if payload.len() >= 16 => {
+                    let mut uuid = [0; 16];
+                    uuid.copy_from_slice(&payload[..16]);
+
+                    Some(SeiMessage::UserDataUnregistered {
+                        uuid,
+                        payload: Bytes::copy_from_slice(&payload[16..]),
+                    })
+                }
+                _ => None,
+            };
+
+            messages.extend(message);
+        }
+
+        Some(())
+    }
+
+    fn parse_pic_timing(&mut self, payload: &[u8]) -> Option<SeiMessage> {
+        let layout = self.layout.as_ref()?;
+        let mut reader = BitReader::new(payload);
+
+        let (cpb_removal_delay, dpb_output_delay) = match layout.delay_lengths {
+            Some((cpb, dpb)) => (Some(reader.read(cpb)?), Some(reader.read(dpb)?)),
+            None => (None, None),
+        };
+
+        let mut timing = PicTiming {
+            cpb_removal_delay,
+            dpb_output_delay,
+            pic_struct: None,
+            timecodes: Vec::new(),
+        };
+
+        if layout.pic_struct_present {
+            let pic_struct = reader.read(4)? as u8;
+            let clock_timestamps = match pic_struct {
+                0..=2 => 1,
+                3 | 4 | 7 => 2,
+                5 | 6 | 8 => 3,
+                _ => 0,
+            };
+
+            for _ in 0..clock_timestamps {
+                if reader.read(1)? == 1 {
+                    let timecode = read_clock_timestamp(
+                        &mut reader,
+                        &self.last_timecode,
+                        layout.time_offset_length,
+                    )?;
+
+                    self.last_timecode = timecode.clone();
+                    timing.timecodes.push(timecode);
+                }
+            }
+
+            timing.pic_struct = Some(pic_struct);
+        }
+
+        Some(SeiMessage::PicTiming(timing))
+    }
+}
+
+/// Parses the `cc_data` of ATSC A/53 from registered user data, skipping
+/// the triplets which aren't valid.
+fn parse_caption_data(payload: &[u8]) -> Option<SeiMessage> {
+    // the country code of the United States, the provider code of ATSC,
+    // the GA94 identifier and the type code of `cc_data`
+    let cc_data = payload.strip_prefix(b"\xb5\x00\x31GA94\x03")?;
+
+    let (&flags, rest) = cc_data.split_first()?;
+    let process_cc_data = flags & 0x40 != 0;
+    let cc_count = (flags & 0x1f) as usize;
+    if !process_cc_data {
+        return None;
+    }
+
+    // after the em_data byte
+    let triplets = rest.get(1..1 + cc_count * 3)?;
+    let data = triplets
+        .chunks_exact(3)
+        .filter(|triplet| triplet[0] & 0x04 != 0)
+        .map(|triplet| CcData {
+            cc_type: triplet[0] & 0x03,
+            data: [triplet[1], triplet[2]],
+        })
+        .collect::<Vec<_>>();
+
+    Some(SeiMessage::CaptionData(data))
+}
+
+fn read_clock_timestamp(
+    reader: &mut BitReader,
+    last: &Timecode,
+    time_offset_length: usize,
+) -> Option<Timecode> {
+    // ct_type, nuit_field_based_flag and counting_type
+    reader.skip(2 + 1 + 5)?;
+    let full_timestamp = reader.read(1)? == 1;
+    let discontinuity = reader.read(1)? == 1;
+    let dropped_frames = reader.read(1)? == 1;
+    let frames = reader.read(8)? as u8;
+
+    let mut timecode = Timecode {
+        frames,
+        dropped_frames,
+        discontinuity,
+        ..last.clone()
+    };
+
+    if full_timestamp {
+        timecode.seconds = reader.read(6)? as u8;
+        timecode.minutes = reader.read(6)? as u8;
+        timecode.hours = reader.read(5)? as u8;
+    } else if reader.read(1)? == 1 {
+        timecode.seconds = reader.read(6)? as u8;
+        if reader.read(1)? == 1 {
+            timecode.minutes = reader.read(6)? as u8;
+            if reader.read(1)? == 1 {
+                timecode.hours = reader.read(5)? as u8;
+            }
+        }
+    }
+
+    timecode.time_offset = match time_offset_length {
+        0 => 0,
+        length => {
+            let offset = reader.read(length)? as i32;
+            // sign extend the two's complement value
+            (offset << (32 - length)) >> (32 - length)
+        }
+    };
+
+    Some(timecode)
+}
+
+/// Parses the SEI messages of H.264 frames and adds them to the metadata
+/// of the frames, for consumers like captions and timecode overlays.
+///
+/// The closed captions of the frames are decoded as well, and added to the
+/// metadata of the frame they are known at, which can be after the frame
+/// they belong to when frames are reordered.
+pub struct SeiReadFilter {
+    target: Box<dyn FrameReadFilter + Send + Unpin>,
+    parsers: HashMap<u32, SeiParser>,
+}
+
+impl SeiReadFilter {
+    pub fn new(target: Box<dyn FrameReadFilter + Send + Unpin>) -> Self {
+        Self {
+            target,
+            parsers: HashMap::new(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl FrameReadFilter for SeiReadFilter {
+    async fn start(&mut self) -> anyhow::Result<Vec<Stream>> {
+        let streams = self.target.start().await?;
+
+        self.parsers = streams
+            .iter()
+            .filter_map(|stream| match &stream.codec.properties {
+                CodecTypeInfo::Video(video) => Some((stream.id, SeiParser::new(video)?)),
+                CodecTypeInfo::Audio(_) => None,
+            })
+            .collect();
+
+        Ok(streams)
+    }
+
+    async fn read(&mut self) -> anyhow::Result<Frame> {
+        let mut frame = self.target.read().await?;
+
+        if let Some(parser) = self.parsers.get_mut(&frame.stream.id) {
+            let sei = parser.parse(&frame);
+
+            let caption_data = sei
+                .iter()
+                .filter_map(|message| match message {
+                    SeiMessage::CaptionData(data) => Some(data.iter().copied()),
+                    _ => None,
+                })
+                .flatten()
+                .collect();
+            let captions = parser.captions.push(&frame, caption_data);
+
+            if !sei.is_empty() || !captions.is_empty() {
+                let mut metadata = frame.metadata.as_deref().cloned().unwrap_or_default();
+                metadata.sei = sei;
+                metadata.captions = captions;
+                frame.metadata = Some(Arc::new(metadata));
+            }
+        }
+
+        Ok(frame)
+    }
+}
@@ -0,0 +1,279 @@
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::*;
+
+use crate::{Frame, FrameWriteFilter, Stream};
+
+type WriteFilter = Box<dyn FrameWriteFilter + Unpin + Send>;
+
+/// How many frames a branch of a tee can fall behind its sinks before it is
+/// dropped.
+pub const BRANCH_QUEUE_LEN: usize = 256;
+
+struct TeeSink {
+    /// What the sink is called in the topology of a filter graph.
+    name: String,
+    output: TeeOutput,
+}
+
+enum TeeOutput {
+    /// Written by the tee, which fails along with it.
+    Sink(WriteFilter),
+    /// A branch which hasn't started yet.
+    Idle(WriteFilter),
+    /// A started branch, which a task of its own writes the frames sent to
+    /// it, so it can't hold back the sinks.
+    Branch(mpsc::Sender<Frame>),
+}
+
+/// A write filter which fans frames out to several sinks, e.g. so one
+/// ingest can feed its live queue, a recorder and a relay at once.
+///
+/// Frames are cloned for every sink, which shares their buffers rather
+/// than copying them. Sinks are written in the order they were added, so
+/// a sink which blocks holds back the ones after it. Branches are written
+/// on tasks of their own, and are dropped when they fail or fall
+/// [`BRANCH_QUEUE_LEN`] frames behind.
+#[derive(Default)]
+pub struct TeeWriteFilter {
+    sinks: Vec<TeeSink>,
+}
+
+impl TeeWriteFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a sink which the tee fails along with.
+    pub fn sink(mut self, write: WriteFilter) -> Self {
+        self.add(String::new(), write, true);
+        self
+    }
+
+    /// Adds a sink which is dropped when it fails or falls behind, so the
+    /// other sinks keep going without it.
+    pub fn branch(mut self, write: WriteFilter) -> Self {
+        self.add(String::new(), write, false);
+        self
+    }
+
+    pub(crate) fn add(&mut self, name: String, write: WriteFilter, required: bool) {
+        let output = if required {
+            TeeOutput::Sink(write)
+        } else {
+            TeeOutput::Idle(write)
+        };
+
+        self.sinks.push(TeeSink { name, output });
+    }
+
+    /// Adds a sink to a tee which has started, starting the sink.
+    pub(crate) async fn add_started(
+        &mut self,
+        name: String,
+        write: WriteFilter,
+        required: bool,
+        streams: Vec<Stream>,
+    ) -> anyhow::Result<()> {
+        self.add(name, write, required);
+
+        let index = self.sinks.len() - 1;
+        if let Err(e) = start_sink(&mut self.sinks[index], streams).await {
+            self.sinks.pop();
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Removes the sink with the given name, returning whether there was
+    /// one.
+    pub(crate) fn remove(&mut self, name: &str) -> bool {
+        let before = self.sinks.len();
+        self.sinks.retain(|sink| sink.name != name);
+
+        self.sinks.len() != before
+    }
+
+    /// The names of the sinks, and whether the tee fails along with them.
+    pub(crate) fn names(&self) -> impl Iterator<Item = (&str, bool)> {
+        self.sinks.iter().map(|sink| {
+            (
+                sink.name.as_str(),
+                matches!(sink.output, TeeOutput::Sink(_)),
+            )
+        })
+    }
+
+    /// Returns how many sinks are still written to.
+    pub fn len(&self) -> usize {
+        self.sinks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+}
+
+/// Starts a sink, and the task which writes it if it is a branch.
+async fn start_sink(sink: &mut TeeSink, streams: Vec<Stream>) -> anyhow::Result<()> {
+    match &mut sink.output {
+        TeeOutput::Sink(write) => write.start(streams).await,
+        TeeOutput::Idle(write) => {
+            write.start(streams).await?;
+
+            let (frames, recv) = mpsc::channel(BRANCH_QUEUE_LEN);
+            let idle = std::mem::replace(&mut sink.output, TeeOutput::Branch(frames));
+            if let TeeOutput::Idle(write) = idle {
+                tokio::spawn(write_branch(sink.name.clone(), write, recv));
+            }
+
+            Ok(())
+        }
+        TeeOutput::Branch(_) => Ok(()),
+    }
+}
+
+/// Writes the frames sent to a branch until it fails, or the tee drops it.
+async fn write_branch(name: String, mut write: WriteFilter, mut frames: mpsc::Receiver<Frame>) {
+    while let Some(frame) = frames.recv().await {
+        if let Err(e) = write.write(frame).await {
+            warn!("Dropping branch '{}' of a tee which failed: {:?}", name, e);
+            return;
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl FrameWriteFilter for TeeWriteFilter {
+    async fn start(&mut self, streams: Vec<Stream>) -> anyhow::Result<()> {
+        let mut index = 0;
+
+        while index < self.sinks.len() {
+            let sink = &mut self.sinks[index];
+
+            match start_sink(sink, streams.clone()).await {
+                Ok(()) => index += 1,
+                Err(e) if !matches!(sink.output, TeeOutput::Sink(_)) => {
+                    warn!(
+                        "Dropping branch '{}' of a tee which failed to start: {:?}",
+                        sink.name, e
+                    );
+                    self.sinks.remove(index);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn write(&mut self, frame: Frame) -> anyhow::Result<()> {
+        let mut index = 0;
+
+        while index < self.sinks.len() {
+            let sink = &mut self.sinks[index];
+
+            let keep = match &mut sink.output {
+                TeeOutput::Sink(write) => {
+                    write.write(frame.clone()).await?;
+                    true
+                }
+                TeeOutput::Idle(_) => anyhow::bail!("Tee was written before it started"),
+                TeeOutput::Branch(frames) => match frames.try_send(frame.clone()) {
+                    Ok(()) => true,
+                    Err(TrySendError::Full(_)) => {
+                        warn!("Dropping branch '{}' of a tee which fell behind", sink.name);
+                        false
+                    }
+                    // the branch failed, which it warned about
+                    Err(TrySendError::Closed(_)) => false,
+                },
+            };
+
+            if keep {
+                index += 1;
+            } else {
+                self.sinks.remove(index);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn fans_out_to_every_sink() {
+    use crate::{test_frame, TestSink};
+
+    let (first, mut first_frames) = mpsc::unbounded_channel();
+    let (second, mut second_frames) = mpsc::unbounded_channel();
+    let (branch, mut branch_frames) = mpsc::unbounded_channel();
+
+    let mut tee = TeeWriteFilter::new()
+        .sink(Box::new(TestSink::Collect(first)))
+        .sink(Box::new(TestSink::Collect(second)))
+        .branch(Box::new(TestSink::Collect(branch)));
+
+    tee.start(vec![test_frame(0, true).stream]).await.unwrap();
+    for pts in 0..3 {
+        tee.write(test_frame(pts, pts == 0)).await.unwrap();
+    }
+
+    for pts in 0..3 {
+        assert_eq!(first_frames.try_recv().ok(), Some(pts));
+        assert_eq!(second_frames.try_recv().ok(), Some(pts));
+        assert_eq!(branch_frames.recv().await, Some(pts));
+    }
+    assert_eq!(tee.len(), 3);
+}
+
+#[tokio::test]
+async fn failing_branches_are_dropped() {
+    use crate::{test_frame, TestSink};
+
+    let (sink, mut frames) = mpsc::unbounded_channel();
+    let mut tee = TeeWriteFilter::new()
+        .branch(Box::new(TestSink::Fail))
+        .sink(Box::new(TestSink::Collect(sink)));
+
+    tee.start(vec![test_frame(0, true).stream]).await.unwrap();
+    tee.write(test_frame(0, true)).await.unwrap();
+
+    // the branch fails on its own task, and is noticed at the next write
+    tokio::task::yield_now().await;
+    tee.write(test_frame(1, false)).await.unwrap();
+
+    assert_eq!(tee.len(), 1);
+    assert_eq!(frames.try_recv().ok(), Some(0));
+    assert_eq!(frames.try_recv().ok(), Some(1));
+
+    // a failing sink fails the tee
+    let mut tee = TeeWriteFilter::new().sink(Box::new(TestSink::Fail));
+    tee.start(vec![test_frame(0, true).stream]).await.unwrap();
+    assert!(tee.write(test_frame(0, true)).await.is_err());
+}
+
+#[tokio::test]
+async fn branches_which_fall_behind_are_dropped() {
+    use crate::{test_frame, TestSink};
+
+    let (sink, mut frames) = mpsc::unbounded_channel();
+    let mut tee = TeeWriteFilter::new()
+        .branch(Box::new(TestSink::Stall))
+        .sink(Box::new(TestSink::Collect(sink)));
+
+    tee.start(vec![test_frame(0, true).stream]).await.unwrap();
+
+    // the branch takes one frame it never finishes writing, and queues the
+    // rest until it is full
+    let count = BRANCH_QUEUE_LEN as u64 + 2;
+    for pts in 0..count {
+        tee.write(test_frame(pts, pts == 0)).await.unwrap();
+        tokio::task::yield_now().await;
+    }
+
+    assert_eq!(tee.len(), 1);
+    for pts in 0..count {
+        assert_eq!(frames.try_recv().ok(), Some(pts));
+    }
+}
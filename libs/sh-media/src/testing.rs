@@ -0,0 +1,181 @@
+//! Known streams and frames for the tests of muxers, and the golden files
+//! their output is compared with. Enabled by the `test-support` feature,
+//! which crates only use as a dev-dependency.
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use std::{fs, path::Path, sync::Arc, time::Instant};
+
+use crate::{
+    AudioCodecInfo, AudioCodecSpecificInfo, BitstreamFraming, CodecInfo, CodecTypeInfo, Fraction,
+    Frame, FrameDependency, MediaTime, SoundType, Stream, VideoCodecInfo, VideoCodecSpecificInfo,
+};
+
+/// A 320x240 baseline profile SPS.
+pub const SPS: [u8; 8] = [0x67, 0x42, 0xc0, 0x1e, 0xf4, 0x0a, 0x0f, 0xc8];
+pub const PPS: [u8; 4] = [0x68, 0xce, 0x3c, 0x80];
+
+/// AAC LC at 44.1 kHz in stereo.
+pub const AUDIO_SPECIFIC_CONFIG: [u8; 2] = [0x12, 0x10];
+
+pub const VIDEO_TIMEBASE: Fraction = Fraction::new(1, 90000);
+pub const AUDIO_TIMEBASE: Fraction = Fraction::new(1, 44100);
+
+/// 30 frames per second.
+pub const FRAME_DURATION: u64 = 3000;
+pub const AAC_FRAME_SAMPLES: u64 = 1024;
+
+/// A H.264 stream of the [`SPS`] and [`PPS`].
+pub fn video_stream() -> Stream {
+    Stream {
+        id: 0,
+        codec: Arc::new(CodecInfo {
+            name: "h264",
+            properties: CodecTypeInfo::Video(VideoCodecInfo {
+                width: 320,
+                height: 240,
+                extra: VideoCodecSpecificInfo::H264 {
+                    bitstream_format: BitstreamFraming::FourByteLength,
+                    profile_indication: SPS[1],
+                    profile_compatibility: SPS[2],
+                    level_indication: SPS[3],
+                    sps: Arc::new(SPS.to_vec()),
+                    pps: Arc::new(PPS.to_vec()),
+                },
+            }),
+        }),
+        timebase: VIDEO_TIMEBASE,
+    }
+}
+
+/// An AAC stream of the [`AUDIO_SPECIFIC_CONFIG`].
+pub fn audio_stream() -> Stream {
+    Stream {
+        id: 1,
+        codec: Arc::new(CodecInfo {
+            name: "aac",
+            properties: CodecTypeInfo::Audio(AudioCodecInfo {
+                sample_rate: 44100,
+                sample_bpp: 16,
+                sound_type: SoundType::Stereo,
+                extra: AudioCodecSpecificInfo::Aac {
+                    extra: AUDIO_SPECIFIC_CONFIG.to_vec(),
+                },
+            }),
+        }),
+        timebase: AUDIO_TIMEBASE,
+    }
+}
+
+/// A frame of a single NAL unit with a recognizable payload, which is
+/// `size` bytes and a few more for each frame before it.
+pub fn video_frame(
+    stream: &Stream,
+    index: u64,
+    pts: u64,
+    dts: u64,
+    keyframe: bool,
+    size: usize,
+) -> Frame {
+    let header = if keyframe { 0x65 } else { 0x41 };
+    let len = size + index as usize * 7;
+
+    let mut buffer = BytesMut::new();
+    buffer.put_u32(len as u32 + 1);
+    buffer.put_u8(header);
+    buffer.extend((0..len).map(|i| (i as u64 + index) as u8));
+
+    Frame {
+        time: MediaTime {
+            pts,
+            dts: Some(dts),
+            timebase: VIDEO_TIMEBASE,
+        },
+        dependency: if keyframe {
+            FrameDependency::None
+        } else {
+            FrameDependency::Backwards
+        },
+        buffer: buffer.freeze(),
+        stream: stream.clone(),
+        received: Instant::now(),
+        metadata: None,
+    }
+}
+
+pub fn audio_frame(stream: &Stream, index: u64) -> Frame {
+    Frame {
+        time: MediaTime {
+            pts: index * AAC_FRAME_SAMPLES,
+            dts: None,
+            timebase: AUDIO_TIMEBASE,
+        },
+        dependency: FrameDependency::None,
+        buffer: Bytes::from(vec![0x21; 20 + index as usize]),
+        stream: stream.clone(),
+        received: Instant::now(),
+        metadata: None,
+    }
+}
+
+/// Two groups of pictures of an I, P, B, B pattern in decode order, where
+/// the presentation times are reordered. See [`video_frame`] for `size`.
+pub fn video_frames(stream: &Stream, size: usize) -> Vec<Frame> {
+    // the presentation order of each frame in its group
+    let order = [1, 4, 2, 3];
+
+    (0..8)
+        .map(|i| {
+            let group = i / 4 * 4;
+            let dts = i * FRAME_DURATION;
+            let pts = (group + order[i as usize % 4]) * FRAME_DURATION;
+
+            video_frame(stream, i, pts, dts, i % 4 == 0, size)
+        })
+        .collect()
+}
+
+/// The video frames with the audio frames of the same time in between.
+pub fn interleaved_frames(video: &Stream, audio: &Stream, size: usize) -> Vec<Frame> {
+    let mut frames = Vec::new();
+    let mut audio_index = 0;
+
+    for frame in video_frames(video, size) {
+        let seconds = frame.time.decode_time() as f64 / 90000.0;
+        while (audio_index * AAC_FRAME_SAMPLES) as f64 / 44100.0 <= seconds {
+            frames.push(audio_frame(audio, audio_index));
+            audio_index += 1;
+        }
+
+        frames.push(frame);
+    }
+
+    frames
+}
+
+/// Compares `muxed` with the golden file `name` in `dir`.
+///
+/// A missing golden file fails like a different one does. With
+/// `UPDATE_GOLDEN=1` the golden file is written instead, after an intended
+/// change to the output.
+pub fn assert_golden(dir: impl AsRef<Path>, name: &str, muxed: &[u8]) {
+    let path = dir.as_ref().join(name);
+
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::create_dir_all(dir.as_ref()).unwrap();
+        fs::write(&path, muxed).unwrap();
+        return;
+    }
+
+    let golden = fs::read(&path).unwrap_or_else(|e| {
+        panic!(
+            "{} has no golden file ({}), run with UPDATE_GOLDEN=1 to write it",
+            name, e
+        )
+    });
+    assert!(
+        golden == muxed,
+        "{} differs from its golden file, run with UPDATE_GOLDEN=1 if that is intended",
+        name
+    );
+}
@@ -0,0 +1,182 @@
+use crate::{bits::BitReader, CodecInfo, CodecTypeInfo, VideoCodecInfo, VideoCodecSpecificInfo};
+
+/// The color space which has no chroma subsampling or color range.
+const CS_RGB: u32 = 7;
+
+/// The luma picture sizes up to which the levels of VP9 go, without
+/// looking at the sample rate.
+const LEVELS: [(u32, u8); 9] = [
+    (36864, 10),
+    (73728, 11),
+    (122880, 20),
+    (245760, 21),
+    (552960, 30),
+    (983040, 31),
+    (2228224, 40),
+    (8912896, 50),
+    (35651584, 60),
+];
+
+/// What the uncompressed header of a VP9 frame says about it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vp9FrameHeader {
+    pub profile: u8,
+    /// The format of the video, which only keyframes have.
+    pub keyframe: Option<Vp9Format>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vp9Format {
+    pub width: u32,
+    pub height: u32,
+    pub bit_depth: u8,
+    /// The chroma subsampling as in the `vpcC` box, 1 for 4:2:0, 2 for
+    /// 4:2:2 and 3 for 4:4:4.
+    pub chroma_subsampling: u8,
+    pub full_range: bool,
+}
+
+impl Vp9FrameHeader {
+    /// Parses the start of the uncompressed header of a frame, or of the
+    /// first frame of a superframe.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let mut r = BitReader::new(data);
+
+        if r.read(2)? != 2 {
+            return None;
+        }
+
+        let profile_low = r.read(1)?;
+        let profile = ((r.read(1)? << 1) | profile_low) as u8;
+        if profile == 3 {
+            r.read(1)?;
+        }
+
+        // a frame which was decoded before, which is never a keyframe
+        let show_existing_frame = r.read(1)? == 1;
+        if show_existing_frame {
+            return Some(Vp9FrameHeader {
+                profile,
+                keyframe: None,
+            });
+        }
+
+        let frame_type = r.read(1)?;
+        let _show_frame = r.read(1)?;
+        let _error_resilient_mode = r.read(1)?;
+        if frame_type != 0 {
+            return Some(Vp9FrameHeader {
+                profile,
+                keyframe: None,
+            });
+        }
+
+        if r.read(24)? != 0x498342 {
+            return None;
+        }
+
+        let bit_depth = match profile {
+            2 | 3 if r.read(1)? == 1 => 12,
+            2 | 3 => 10,
+            _ => 8,
+        };
+
+        let color_space = r.read(3)?;
+        let (full_range, chroma_subsampling) = if color_space != CS_RGB {
+            let full_range = r.read(1)? == 1;
+            let subsampling = if profile == 1 || profile == 3 {
+                let (x, y) = (r.read(1)?, r.read(1)?);
+                r.read(1)?;
+
+                match (x, y) {
+                    (1, 1) => 1,
+                    (1, 0) => 2,
+                    _ => 3,
+                }
+            } else {
+                1
+            };
+
+            (full_range, subsampling)
+        } else {
+            if profile == 1 || profile == 3 {
+                r.read(1)?;
+            }
+
+            (true, 3)
+        };
+
+        let width = r.read(16)? + 1;
+        let height = r.read(16)? + 1;
+
+        Some(Vp9FrameHeader {
+            profile,
+            keyframe: Some(Vp9Format {
+                width,
+                height,
+                bit_depth,
+                chroma_subsampling,
+                full_range,
+            }),
+        })
+    }
+
+    /// Describes the video of a keyframe.
+    pub fn codec_info(&self) -> Option<CodecInfo> {
+        let format = self.keyframe?;
+
+        Some(CodecInfo {
+            name: "vp9",
+            properties: CodecTypeInfo::Video(VideoCodecInfo {
+                width: format.width,
+                height: format.height,
+                extra: VideoCodecSpecificInfo::Vp9 {
+                    profile: self.profile,
+                    level: vp9_level(format.width, format.height),
+                    bit_depth: format.bit_depth,
+                    chroma_subsampling: format.chroma_subsampling,
+                    full_range: format.full_range,
+                },
+            }),
+        })
+    }
+}
+
+/// Returns the lowest level which allows pictures of a size.
+pub fn vp9_level(width: u32, height: u32) -> u8 {
+    let samples = width * height;
+
+    LEVELS
+        .iter()
+        .find(|(max, _)| samples <= *max)
+        .map_or(62, |(_, level)| *level)
+}
+
+/// Makes the `VPCodecConfigurationRecord` of a stream, the content of the
+/// `vpcC` box after its version and flags.
+pub fn vp9_decoder_configuration(extra: &VideoCodecSpecificInfo) -> Option<Vec<u8>> {
+    let (profile, level, bit_depth, chroma_subsampling, full_range) = match extra {
+        VideoCodecSpecificInfo::Vp9 {
+            profile,
+            level,
+            bit_depth,
+            chroma_subsampling,
+            full_range,
+        } => (profile, level, bit_depth, chroma_subsampling, full_range),
+        _ => return None,
+    };
+
+    Some(vec![
+        *profile,
+        *level,
+        (bit_depth << 4) | (chroma_subsampling << 1) | *full_range as u8,
+        // unspecified color primaries, transfer characteristics and matrix
+        // coefficients
+        2,
+        2,
+        2,
+        // no codec initialization data
+        0,
+        0,
+    ])
+}
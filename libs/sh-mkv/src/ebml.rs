@@ -0,0 +1,95 @@
+use bytes::{BufMut, BytesMut};
+
+pub const EBML: u32 = 0x1a45dfa3;
+pub const EBML_VERSION: u32 = 0x4286;
+pub const EBML_READ_VERSION: u32 = 0x42f7;
+pub const EBML_MAX_ID_LENGTH: u32 = 0x42f2;
+pub const EBML_MAX_SIZE_LENGTH: u32 = 0x42f3;
+pub const DOC_TYPE: u32 = 0x4282;
+pub const DOC_TYPE_VERSION: u32 = 0x4287;
+pub const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+
+pub const SEGMENT: u32 = 0x18538067;
+
+pub const INFO: u32 = 0x1549a966;
+pub const TIMESTAMP_SCALE: u32 = 0x2ad7b1;
+pub const MUXING_APP: u32 = 0x4d80;
+pub const WRITING_APP: u32 = 0x5741;
+
+pub const TRACKS: u32 = 0x1654ae6b;
+pub const TRACK_ENTRY: u32 = 0xae;
+pub const TRACK_NUMBER: u32 = 0xd7;
+pub const TRACK_UID: u32 = 0x73c5;
+pub const TRACK_TYPE: u32 = 0x83;
+pub const FLAG_LACING: u32 = 0x9c;
+pub const CODEC_ID: u32 = 0x86;
+pub const CODEC_PRIVATE: u32 = 0x63a2;
+pub const CODEC_DELAY: u32 = 0x56aa;
+pub const SEEK_PRE_ROLL: u32 = 0x56bb;
+pub const VIDEO: u32 = 0xe0;
+pub const PIXEL_WIDTH: u32 = 0xb0;
+pub const PIXEL_HEIGHT: u32 = 0xba;
+pub const AUDIO: u32 = 0xe1;
+pub const SAMPLING_FREQUENCY: u32 = 0xb5;
+pub const CHANNELS: u32 = 0x9f;
+
+pub const CLUSTER: u32 = 0x1f43b675;
+pub const TIMESTAMP: u32 = 0xe7;
+pub const SIMPLE_BLOCK: u32 = 0xa3;
+
+/// The size of an element which is written before its children are known,
+/// like a live segment.
+const UNKNOWN_SIZE: [u8; 8] = [0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+
+/// Appends an element ID, which already has the length marker of a
+/// variable size integer.
+fn put_id(out: &mut BytesMut, id: u32) {
+    let bytes = id.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&b| b == 0).count().min(3);
+
+    out.put_slice(&bytes[skip..]);
+}
+
+/// Appends a size as a variable size integer of 8 bytes, which fits every
+/// size without knowing it up front.
+fn put_size(out: &mut BytesMut, size: usize) {
+    out.put_u64((1 << 56) | size as u64);
+}
+
+/// Appends an element with its children already encoded.
+pub fn put_element(out: &mut BytesMut, id: u32, data: &[u8]) {
+    put_id(out, id);
+    put_size(out, data.len());
+    out.put_slice(data);
+}
+
+/// Appends the header of an element of unknown size, which ends where an
+/// element that can't be its child starts.
+pub fn put_unknown_size(out: &mut BytesMut, id: u32) {
+    put_id(out, id);
+    out.put_slice(&UNKNOWN_SIZE);
+}
+
+/// Appends an unsigned integer element with as few bytes as needed.
+pub fn put_uint(out: &mut BytesMut, id: u32, value: u64) {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&b| b == 0).count().min(7);
+
+    put_element(out, id, &bytes[skip..]);
+}
+
+pub fn put_float(out: &mut BytesMut, id: u32, value: f64) {
+    put_element(out, id, &value.to_be_bytes());
+}
+
+pub fn put_string(out: &mut BytesMut, id: u32, value: &str) {
+    put_element(out, id, value.as_bytes());
+}
+
+/// Appends an element with children written by `f`.
+pub fn put_master(out: &mut BytesMut, id: u32, f: impl FnOnce(&mut BytesMut)) {
+    let mut children = BytesMut::new();
+    f(&mut children);
+
+    put_element(out, id, &children);
+}
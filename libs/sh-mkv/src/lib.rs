@@ -0,0 +1,247 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use sh_media::{
+    av1_decoder_configuration, hevc_decoder_configuration, AudioCodecSpecificInfo,
+    BitstreamFramerFilter, BitstreamFraming, ByteWriteFilter2, CodecTypeInfo, Fraction, Frame,
+    FrameWriteFilter, Muxer, SoundType, Stream, VideoCodecSpecificInfo, OPUS_SAMPLE_RATE,
+};
+use tracing::*;
+
+mod ebml;
+
+use ebml::*;
+
+/// The timebase of block timestamps, with a timestamp scale of 1ms.
+const MKV_TIMEBASE: Fraction = Fraction::new(1, 1000);
+
+/// How far from the start of a cluster its blocks can be, which is well
+/// within the 16-bit relative timestamps of blocks.
+const MAX_CLUSTER_OFFSET: i64 = 30_000;
+
+const TRACK_TYPE_VIDEO: u64 = 1;
+const TRACK_TYPE_AUDIO: u64 = 2;
+
+/// A push filter which muxes H.264, H.265, VP9 or AV1 and AAC, Opus or MP3
+/// frames into a live Matroska stream and writes it to a [`ByteWriteFilter2`].
+///
+/// The segment and its clusters have unknown sizes so they can be written
+/// as the frames arrive, which players handle like a file being recorded.
+/// Clusters start at video keyframes. H.264 and H.265 frames have to be
+/// framed with 4 byte lengths, see [`MatroskaMuxer`].
+pub struct MatroskaWriteFilter {
+    target: Box<dyn ByteWriteFilter2 + Send + Unpin>,
+    /// The stream ID of every track, where the track number is the index
+    /// plus one.
+    tracks: Vec<u32>,
+    /// The first presentation time, which the timestamps start at.
+    origin: Option<u64>,
+    /// The timestamp of the current cluster.
+    cluster: Option<i64>,
+}
+
+impl MatroskaWriteFilter {
+    pub fn new(target: Box<dyn ByteWriteFilter2 + Send + Unpin>) -> Self {
+        MatroskaWriteFilter {
+            target,
+            tracks: Vec::new(),
+            origin: None,
+            cluster: None,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl FrameWriteFilter for MatroskaWriteFilter {
+    async fn start(&mut self, streams: Vec<Stream>) -> anyhow::Result<()> {
+        debug!("Muxing {:?} to Matroska", streams);
+
+        self.target.start().await?;
+
+        let video = streams.iter().find(|s| s.is_video());
+        let audio = streams.iter().find(|s| s.is_audio());
+        self.tracks = video.iter().chain(audio.iter()).map(|s| s.id).collect();
+
+        let mut out = BytesMut::new();
+        put_master(&mut out, EBML, |out| {
+            put_uint(out, EBML_VERSION, 1);
+            put_uint(out, EBML_READ_VERSION, 1);
+            put_uint(out, EBML_MAX_ID_LENGTH, 4);
+            put_uint(out, EBML_MAX_SIZE_LENGTH, 8);
+            put_string(out, DOC_TYPE, "matroska");
+            put_uint(out, DOC_TYPE_VERSION, 4);
+            put_uint(out, DOC_TYPE_READ_VERSION, 2);
+        });
+
+        put_unknown_size(&mut out, SEGMENT);
+        put_master(&mut out, INFO, |out| {
+            put_uint(out, TIMESTAMP_SCALE, 1_000_000);
+            put_string(out, MUXING_APP, "streamhead");
+            put_string(out, WRITING_APP, "streamhead");
+        });
+        put_master(&mut out, TRACKS, |out| {
+            for (number, stream) in video.iter().chain(audio.iter()).enumerate() {
+                put_master(out, TRACK_ENTRY, |out| {
+                    put_track(out, number as u64 + 1, stream)
+                });
+            }
+        });
+
+        self.target.write(out.freeze()).await
+    }
+
+    async fn write(&mut self, frame: Frame) -> anyhow::Result<()> {
+        let track = match self.tracks.iter().position(|&id| id == frame.stream.id) {
+            Some(index) => index as u64 + 1,
+            None => return Ok(()),
+        };
+
+        let pts = frame.time.in_base(MKV_TIMEBASE).pts;
+        let origin = *self.origin.get_or_insert(pts);
+        let timestamp = (pts as i64 - origin as i64).max(0);
+
+        let is_keyframe = frame.is_keyframe();
+        let mut out = BytesMut::new();
+
+        let starts_cluster = match self.cluster {
+            Some(cluster) => {
+                (frame.stream.is_video() && is_keyframe)
+                    || (timestamp - cluster).abs() > MAX_CLUSTER_OFFSET
+            }
+            None => true,
+        };
+        if starts_cluster {
+            put_unknown_size(&mut out, CLUSTER);
+            put_uint(&mut out, TIMESTAMP, timestamp as u64);
+            self.cluster = Some(timestamp);
+        }
+        let offset = timestamp - self.cluster.unwrap_or(timestamp);
+
+        let mut block = BytesMut::with_capacity(4 + frame.buffer.len());
+        // the track number as a 1 byte variable size integer
+        block.put_u8(0x80 | track as u8);
+        block.put_i16(offset as i16);
+        block.put_u8(if is_keyframe { 0x80 } else { 0 });
+        block.put_slice(&frame.buffer);
+        put_element(&mut out, SIMPLE_BLOCK, &block);
+
+        self.target.write(out.freeze()).await
+    }
+}
+
+/// Appends the children of the track entry of a stream.
+fn put_track(out: &mut BytesMut, number: u64, stream: &Stream) {
+    put_uint(out, TRACK_NUMBER, number);
+    put_uint(out, TRACK_UID, number);
+    put_uint(out, FLAG_LACING, 0);
+
+    match &stream.codec.properties {
+        CodecTypeInfo::Video(video) => {
+            put_uint(out, TRACK_TYPE, TRACK_TYPE_VIDEO);
+            if let Some(record) = avc_decoder_configuration(&video.extra) {
+                put_string(out, CODEC_ID, "V_MPEG4/ISO/AVC");
+                put_element(out, CODEC_PRIVATE, &record);
+            } else if let Some(record) = hevc_decoder_configuration(&video.extra) {
+                put_string(out, CODEC_ID, "V_MPEGH/ISO/HEVC");
+                put_element(out, CODEC_PRIVATE, &record);
+            } else if let Some(record) = av1_decoder_configuration(&video.extra) {
+                put_string(out, CODEC_ID, "V_AV1");
+                put_element(out, CODEC_PRIVATE, &record);
+            } else {
+                // VP9 decoders configure themselves from the keyframes
+                put_string(out, CODEC_ID, "V_VP9");
+            }
+            put_master(out, VIDEO, |out| {
+                put_uint(out, PIXEL_WIDTH, video.width as u64);
+                put_uint(out, PIXEL_HEIGHT, video.height as u64);
+            });
+        }
+        CodecTypeInfo::Audio(audio) => {
+            let channels = match audio.sound_type {
+                SoundType::Mono => 1,
+                SoundType::Stereo => 2,
+            };
+
+            put_uint(out, TRACK_TYPE, TRACK_TYPE_AUDIO);
+            match &audio.extra {
+                AudioCodecSpecificInfo::Aac { extra } => {
+                    put_string(out, CODEC_ID, "A_AAC");
+                    put_element(out, CODEC_PRIVATE, extra);
+                }
+                AudioCodecSpecificInfo::Opus { header } => {
+                    put_string(out, CODEC_ID, "A_OPUS");
+                    put_element(out, CODEC_PRIVATE, &header.to_bytes());
+                    // in nanoseconds, where Opus needs 80ms of audio before
+                    // a seek to decode properly
+                    let delay = header.pre_skip as u64 * 1_000_000_000 / OPUS_SAMPLE_RATE as u64;
+                    put_uint(out, CODEC_DELAY, delay);
+                    put_uint(out, SEEK_PRE_ROLL, 80_000_000);
+                }
+                AudioCodecSpecificInfo::Mp3 => put_string(out, CODEC_ID, "A_MPEG/L3"),
+            }
+            put_master(out, AUDIO, |out| {
+                put_float(out, SAMPLING_FREQUENCY, audio.sample_rate as f64);
+                put_uint(out, CHANNELS, channels);
+            });
+        }
+    }
+}
+
+/// Makes the AVCDecoderConfigurationRecord of an H.264 stream, for NAL
+/// units prefixed by 4 byte lengths.
+fn avc_decoder_configuration(extra: &VideoCodecSpecificInfo) -> Option<Bytes> {
+    let (profile_indication, profile_compatibility, level_indication, sps, pps) = match extra {
+        VideoCodecSpecificInfo::H264 {
+            profile_indication,
+            profile_compatibility,
+            level_indication,
+            sps,
+            pps,
+            ..
+        } => (
+            profile_indication,
+            profile_compatibility,
+            level_indication,
+            sps,
+            pps,
+        ),
+        _ => return None,
+    };
+
+    let mut record = BytesMut::new();
+    record.put_u8(1);
+    record.put_u8(*profile_indication);
+    record.put_u8(*profile_compatibility);
+    record.put_u8(*level_indication);
+    record.put_u8(0xff);
+    record.put_u8(0xe1);
+    record.put_u16(sps.len() as u16);
+    record.put_slice(sps);
+    record.put_u8(1);
+    record.put_u16(pps.len() as u16);
+    record.put_slice(pps);
+
+    Some(record.freeze())
+}
+
+/// A [`Muxer`] for Matroska, see [`MatroskaWriteFilter`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MatroskaMuxer;
+
+impl Muxer for MatroskaMuxer {
+    fn content_type(&self) -> &'static str {
+        "video/x-matroska"
+    }
+
+    fn extension(&self) -> &'static str {
+        "mkv"
+    }
+
+    fn mux(
+        &self,
+        output: Box<dyn ByteWriteFilter2 + Send + Unpin>,
+    ) -> Box<dyn FrameWriteFilter + Send + Unpin> {
+        Box::new(BitstreamFramerFilter::new(
+            BitstreamFraming::FourByteLength,
+            Box::new(MatroskaWriteFilter::new(output)),
+        ))
+    }
+}
@@ -0,0 +1,316 @@
+//! The protocol which viewers play streams over with MSE, shared by the
+//! server and players.
+//!
+//! A viewer connects with a WebSocket, and is first offered the renditions
+//! of the stream as a [`ServerMessage::Offer`], which it answers with a
+//! [`ClientMessage::Rendition`]. It is then sent, as text messages:
+//!
+//! 1. The codecs of the rendition, as in the `codecs` parameter of a MSE
+//!    source buffer, which is the only message that isn't JSON.
+//! 2. Optionally, [`ReconnectHints`].
+//! 3. A [`ResumeMessage`] before every video keyframe.
+//! 4. A [`CaptionMessage`] whenever the closed captions of the stream
+//!    change.
+//!
+//! Binary messages are the fragmented MP4 of the stream, starting with its
+//! initialization segment. While playing, the viewer can send the other
+//! [`ClientMessage`]s.
+//!
+//! The crate only depends on what builds for `wasm32`, so players written
+//! in Rust can use it as is.
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use std::{fmt, str::FromStr};
+
+/// Sent by a viewer to stop receiving media, e.g. when its tab is hidden.
+pub const PAUSE_MESSAGE: &str = "pause";
+
+/// Sent by a paused viewer to receive media again, from the next keyframe.
+pub const RESUME_MESSAGE: &str = "resume";
+
+/// Sent by a viewer behind live along with a rate, e.g. `rate:1.5`, to
+/// receive media faster than realtime until it catches up.
+pub const RATE_MESSAGE_PREFIX: &str = "rate:";
+
+/// The fastest rate a viewer can catch up to live at.
+pub const MAX_CATCH_UP_RATE: f64 = 2.0;
+
+/// Sent by a viewer along with the name of the rendition it picked from an
+/// offer, e.g. `rendition:source`, or with no name if it can't decode any.
+pub const RENDITION_MESSAGE_PREFIX: &str = "rendition:";
+
+/// A text message from a viewer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientMessage {
+    Pause,
+    Resume,
+    /// How many times faster than realtime to be sent media.
+    Rate(f64),
+    /// The name of the picked rendition, or `None` if none of them can be
+    /// decoded.
+    Rendition(Option<String>),
+}
+
+impl ClientMessage {
+    /// Parses a text message, where `None` is a message which isn't part of
+    /// the protocol or has an invalid value.
+    pub fn parse(text: &str) -> Option<Self> {
+        if text == PAUSE_MESSAGE {
+            Some(ClientMessage::Pause)
+        } else if text == RESUME_MESSAGE {
+            Some(ClientMessage::Resume)
+        } else if let Some(rate) = text.strip_prefix(RATE_MESSAGE_PREFIX) {
+            clamp_rate(rate.trim().parse().ok()?).map(ClientMessage::Rate)
+        } else if let Some(name) = text.strip_prefix(RENDITION_MESSAGE_PREFIX) {
+            let name = name.trim();
+            Some(ClientMessage::Rendition(
+                (!name.is_empty()).then(|| name.to_string()),
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for ClientMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientMessage::Pause => f.write_str(PAUSE_MESSAGE),
+            ClientMessage::Resume => f.write_str(RESUME_MESSAGE),
+            ClientMessage::Rate(rate) => write!(f, "{}{}", RATE_MESSAGE_PREFIX, rate),
+            ClientMessage::Rendition(name) => write!(
+                f,
+                "{}{}",
+                RENDITION_MESSAGE_PREFIX,
+                name.as_deref().unwrap_or_default()
+            ),
+        }
+    }
+}
+
+/// Limits the rate a viewer asked for to what it can be sent at, or returns
+/// `None` if it isn't a number.
+pub fn clamp_rate(rate: f64) -> Option<f64> {
+    if !rate.is_finite() {
+        return None;
+    }
+
+    Some(rate.clamp(1.0, MAX_CATCH_UP_RATE))
+}
+
+/// A text message to a viewer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerMessage {
+    /// The RFC 6381 codecs of the video and audio, e.g.
+    /// `avc1.64001f,mp4a.40.2`.
+    Codecs(String),
+    Offer(OfferMessage),
+    ReconnectHints(ReconnectHints),
+    Resume(ResumeMessage),
+    Caption(CaptionMessage),
+}
+
+/// The messages which are sent as JSON, told apart by their fields.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonMessage {
+    Offer(OfferMessage),
+    ReconnectHints(ReconnectHints),
+    Resume(ResumeMessage),
+    Caption(CaptionMessage),
+}
+
+impl ServerMessage {
+    /// Parses a text message, where anything which isn't a JSON object is
+    /// taken to be codecs.
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        if !text.trim_start().starts_with('{') {
+            return Ok(ServerMessage::Codecs(text.to_string()));
+        }
+
+        let message = match serde_json::from_str(text).context("unknown message")? {
+            JsonMessage::Offer(offer) => ServerMessage::Offer(offer),
+            JsonMessage::ReconnectHints(hints) => ServerMessage::ReconnectHints(hints),
+            JsonMessage::Resume(resume) => ServerMessage::Resume(resume),
+            JsonMessage::Caption(caption) => ServerMessage::Caption(caption),
+        };
+
+        Ok(message)
+    }
+
+    /// Returns the text message to send.
+    pub fn to_text(&self) -> anyhow::Result<String> {
+        let text = match self {
+            ServerMessage::Codecs(codecs) => codecs.clone(),
+            ServerMessage::Offer(offer) => serde_json::to_string(offer)?,
+            ServerMessage::ReconnectHints(hints) => serde_json::to_string(hints)?,
+            ServerMessage::Resume(resume) => serde_json::to_string(resume)?,
+            ServerMessage::Caption(caption) => serde_json::to_string(caption)?,
+        };
+
+        Ok(text)
+    }
+}
+
+/// A version of a stream which a viewer can pick, by the codecs it has to
+/// be able to decode.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "typescript", derive(ts_rs::TS))]
+pub struct RenditionOffer {
+    pub name: String,
+    pub codecs: String,
+}
+
+/// The renditions of a stream, as
+/// `{"renditions":[{"name":"source","codecs":"avc1.64001f"}]}`.
+///
+/// Viewers check the offers with `MediaSource.isTypeSupported`, and answer
+/// with [`ClientMessage::Rendition`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "typescript", derive(ts_rs::TS))]
+pub struct OfferMessage {
+    pub renditions: Vec<RenditionOffer>,
+}
+
+/// Server-suggested reconnect behavior, sent to players after the codec
+/// parameters so they back off sensibly during restarts and failovers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "typescript", derive(ts_rs::TS))]
+#[serde(rename_all = "camelCase")]
+pub struct ReconnectHints {
+    /// Delay before the first reconnect attempt.
+    pub initial_delay_ms: u32,
+    /// Upper bound for the exponentially growing reconnect delay.
+    pub max_delay_ms: u32,
+    /// Other URLs the same stream can be played from.
+    pub alternatives: Vec<String>,
+}
+
+/// Sent before every video keyframe, as `{"resumeToken":"<token>"}`, for
+/// the viewer to pass back if it reconnects.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "typescript", derive(ts_rs::TS))]
+#[serde(rename_all = "camelCase")]
+pub struct ResumeMessage {
+    pub resume_token: String,
+}
+
+/// The closed captions on screen from a point in the stream, as
+/// `{"caption":{"time":<seconds>,"text":"<text>"}}`, sent before the
+/// fragment of the frame they were found in.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CaptionMessage {
+    pub caption: CaptionCue,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CaptionCue {
+    /// Seconds since the start of the timestamps the viewer was sent.
+    pub time: f64,
+    /// What is on screen, where an empty text clears it.
+    pub text: String,
+}
+
+/// Where a viewer is in a stream, which it can continue from if its
+/// WebSocket drops.
+///
+/// Players should treat the token as opaque, it is only parsed by the
+/// server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResumeToken {
+    /// The stream session the viewer watched.
+    pub session: i32,
+    /// The time of the first video frame the viewer was sent, which the
+    /// timestamps it got are relative to.
+    pub start: u64,
+    /// The time of the latest video keyframe the viewer was sent.
+    pub keyframe: u64,
+}
+
+impl fmt::Display for ResumeToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.session, self.start, self.keyframe)
+    }
+}
+
+impl FromStr for ResumeToken {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, '.');
+        let mut next = || parts.next().context("resume token is too short");
+
+        Ok(ResumeToken {
+            session: next()?.parse()?,
+            start: next()?.parse()?,
+            keyframe: next()?.parse()?,
+        })
+    }
+}
+
+#[test]
+fn client_messages_round_trip() {
+    let messages = [
+        ClientMessage::Pause,
+        ClientMessage::Resume,
+        ClientMessage::Rate(1.5),
+        ClientMessage::Rendition(Some("source".to_string())),
+        ClientMessage::Rendition(None),
+    ];
+
+    for message in messages {
+        assert_eq!(ClientMessage::parse(&message.to_string()), Some(message));
+    }
+
+    assert_eq!(
+        ClientMessage::parse("rate:9"),
+        Some(ClientMessage::Rate(MAX_CATCH_UP_RATE))
+    );
+    assert_eq!(ClientMessage::parse("rate:fast"), None);
+}
+
+#[test]
+fn server_messages_round_trip() {
+    let messages = [
+        ServerMessage::Codecs("avc1.64001f,mp4a.40.2".to_string()),
+        ServerMessage::Offer(OfferMessage {
+            renditions: vec![RenditionOffer {
+                name: "source".to_string(),
+                codecs: "avc1.64001f".to_string(),
+            }],
+        }),
+        ServerMessage::ReconnectHints(ReconnectHints {
+            initial_delay_ms: 1000,
+            max_delay_ms: 30000,
+            alternatives: Vec::new(),
+        }),
+        ServerMessage::Resume(ResumeMessage {
+            resume_token: "1.2.3".to_string(),
+        }),
+        ServerMessage::Caption(CaptionMessage {
+            caption: CaptionCue {
+                time: 1.5,
+                text: "HELLO".to_string(),
+            },
+        }),
+    ];
+
+    for message in messages {
+        let text = message.to_text().unwrap();
+        assert_eq!(ServerMessage::parse(&text).unwrap(), message);
+    }
+}
+
+#[test]
+fn resume_token_round_trip() {
+    let token = ResumeToken {
+        session: 12,
+        start: 3000,
+        keyframe: 93000,
+    };
+
+    assert_eq!(token.to_string().parse::<ResumeToken>().unwrap(), token);
+    assert!("12.3000".parse::<ResumeToken>().is_err());
+}
@@ -0,0 +1,467 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    ptr,
+    sync::Arc,
+    time::Instant,
+};
+
+use bytes::Bytes;
+use ffmpeg_next::{
+    codec::{self, Id},
+    decoder, encoder, ffi,
+    format::{sample::Type, Sample},
+    frame,
+    software::resampling,
+    ChannelLayout, Packet,
+};
+use sh_media::{
+    AudioCodecInfo, AudioCodecSpecificInfo, CodecInfo, CodecTypeInfo, Fraction, Frame,
+    FrameDependency, FrameReadFilter, FrameWriteFilter, MediaTime, OpusHeader, SoundType, Stream,
+    OPUS_SAMPLE_RATE,
+};
+use tracing::*;
+
+use crate::{filter::ReadOrWriteFilter, AudioCodec, AudioGain, AudioTranscodeOptions};
+
+/// The sample rates of AAC by their sampling frequency index.
+const AAC_SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+/// The audio object type of AAC-LC.
+const AAC_LC: u8 = 2;
+
+/// The samples of an encoded frame for encoders which take any number.
+const DEFAULT_FRAME_SIZE: usize = 1024;
+
+/// Decodes the frames of an AAC or Opus stream and encodes them as the
+/// other codec, or as the same codec with a gain.
+struct AudioTranscoder {
+    /// The stream of the encoded frames.
+    stream: Stream,
+    decoder: decoder::Audio,
+    resampler: Option<resampling::Context>,
+    encoder: encoder::audio::Encoder,
+    format: Sample,
+    layout: ChannelLayout,
+    rate: u32,
+    /// The samples the encoder takes at a time.
+    frame_size: usize,
+    /// The bytes of one sample in each plane of the encoder format.
+    sample_size: usize,
+    /// The resampled audio which doesn't fill a frame of the encoder yet, by
+    /// plane.
+    buffer: Vec<Vec<u8>>,
+    /// The time of the first sample in the buffer at the output rate.
+    next_pts: Option<i64>,
+    gain: Option<AudioGain>,
+    /// The factor of the gain the last samples were multiplied by.
+    factor: f32,
+}
+
+// the libav contexts are only ever used by the filter which owns them, one
+// call at a time, but the resampler isn't marked as `Send` by the bindings
+unsafe impl Send for AudioTranscoder {}
+
+impl AudioTranscoder {
+    fn new(stream: &Stream, options: &AudioTranscodeOptions) -> anyhow::Result<Option<Self>> {
+        let audio = match stream.codec.audio() {
+            Some(audio) => audio,
+            None => return Ok(None),
+        };
+
+        let (source, id, extradata) = match &audio.extra {
+            AudioCodecSpecificInfo::Aac { extra } => (AudioCodec::Aac, Id::AAC, extra.clone()),
+            AudioCodecSpecificInfo::Opus { header } => {
+                (AudioCodec::Opus, Id::OPUS, header.to_bytes())
+            }
+            AudioCodecSpecificInfo::Mp3 => {
+                warn!(
+                    "Can only transcode AAC and Opus, stream {} is left as is",
+                    stream.id
+                );
+                return Ok(None);
+            }
+        };
+
+        let target = options.codec.unwrap_or(source);
+        if target == source && options.gain.is_none() {
+            return Ok(None);
+        }
+
+        let channels = match audio.sound_type {
+            SoundType::Mono => 1,
+            SoundType::Stereo => 2,
+        };
+        let layout = ChannelLayout::default(channels as i32);
+
+        let codec = decoder::find(id)
+            .ok_or_else(|| anyhow::anyhow!("ffmpeg was built without a {:?} decoder", id))?;
+        let mut context = codec::Context::new();
+        set_extradata(&mut context, &extradata)?;
+        let decoder = context.decoder().open_as(codec)?.audio()?;
+
+        // AAC is encoded at the rate of the source, and Opus always at 48 kHz
+        let (codec, format, rate) = match target {
+            AudioCodec::Aac => (
+                encoder::find(Id::AAC),
+                Sample::F32(Type::Planar),
+                audio.sample_rate,
+            ),
+            AudioCodec::Opus => (
+                encoder::find_by_name("libopus"),
+                Sample::F32(Type::Packed),
+                OPUS_SAMPLE_RATE,
+            ),
+        };
+        let codec = codec
+            .ok_or_else(|| anyhow::anyhow!("ffmpeg was built without a {:?} encoder", target))?;
+
+        let mut encoder = codec::Context::new().encoder().audio()?;
+        encoder.set_rate(rate as i32);
+        encoder.set_channel_layout(layout);
+        encoder.set_format(format);
+        encoder.set_bit_rate(options.bitrate);
+        encoder.set_time_base((1, rate as i32));
+        let encoder = encoder.open_as(codec)?;
+
+        let info = match target {
+            AudioCodec::Aac => aac_codec_info(rate, channels)?,
+            AudioCodec::Opus => OpusHeader::new(channels).codec_info(),
+        };
+        let stream = Stream {
+            id: stream.id,
+            codec: Arc::new(info),
+            timebase: Fraction::new(1, rate),
+        };
+
+        let (planes, sample_size) = if format.is_planar() {
+            (channels as usize, format.bytes())
+        } else {
+            (1, format.bytes() * channels as usize)
+        };
+
+        Ok(Some(AudioTranscoder {
+            stream,
+            decoder,
+            resampler: None,
+            frame_size: match encoder.frame_size() {
+                0 => DEFAULT_FRAME_SIZE,
+                size => size as usize,
+            },
+            encoder,
+            format,
+            layout,
+            rate,
+            sample_size,
+            buffer: vec![Vec::new(); planes],
+            next_pts: None,
+            factor: options.gain.as_ref().map_or(1.0, AudioGain::factor),
+            gain: options.gain.clone(),
+        }))
+    }
+
+    fn transcode(&mut self, frame: &Frame) -> anyhow::Result<Vec<Frame>> {
+        if self.next_pts.is_none() {
+            let time = frame.time.in_base(self.stream.timebase);
+            self.next_pts = Some(time.pts as i64);
+        }
+
+        let mut packet = Packet::copy(&frame.buffer);
+        packet.set_pts(Some(frame.time.pts as i64));
+        self.decoder.send_packet(&packet)?;
+
+        let mut decoded = frame::Audio::empty();
+        while self.decoder.receive_frame(&mut decoded).is_ok() {
+            self.resample(&decoded)?;
+        }
+
+        let mut frames = Vec::new();
+        while let Some(input) = self.next_input() {
+            self.encoder.send_frame(&input)?;
+
+            let mut packet = Packet::empty();
+            while self.encoder.receive_packet(&mut packet).is_ok() {
+                frames.push(self.encoded_frame(&packet));
+            }
+        }
+
+        Ok(frames)
+    }
+
+    /// Resamples decoded audio to the format of the encoder and adds it to
+    /// the buffer, making a resampler whenever the input changes format.
+    fn resample(&mut self, decoded: &frame::Audio) -> anyhow::Result<()> {
+        let layout = match decoded.channel_layout() {
+            layout if layout.is_empty() => ChannelLayout::default(decoded.channels() as i32),
+            layout => layout,
+        };
+        let input = (decoded.format(), layout, decoded.rate());
+        let current = self.resampler.as_ref().map(|resampler| {
+            let definition = resampler.input();
+            (
+                definition.format,
+                definition.channel_layout,
+                definition.rate,
+            )
+        });
+
+        let resampler = match &mut self.resampler {
+            Some(resampler) if current == Some(input) => resampler,
+            resampler => resampler.insert(resampling::Context::get(
+                input.0,
+                input.1,
+                input.2,
+                self.format,
+                self.layout,
+                self.rate,
+            )?),
+        };
+
+        let mut resampled = frame::Audio::empty();
+        resampler.run(decoded, &mut resampled)?;
+
+        // the planes can be padded past the samples
+        let len = resampled.samples() * self.sample_size;
+        let factor = self.gain.as_ref().map(AudioGain::factor);
+        for (plane, buffer) in self.buffer.iter_mut().enumerate() {
+            let start = buffer.len();
+            buffer.extend_from_slice(&resampled.data(plane)[..len]);
+
+            if let Some(factor) = factor {
+                apply_gain(&mut buffer[start..], self.factor, factor);
+            }
+        }
+        self.factor = factor.unwrap_or(1.0);
+
+        Ok(())
+    }
+
+    /// Takes a frame of the size the encoder takes from the buffer, if it
+    /// has enough audio.
+    fn next_input(&mut self) -> Option<frame::Audio> {
+        let len = self.frame_size * self.sample_size;
+        if self.buffer.first()?.len() < len {
+            return None;
+        }
+
+        let mut input = frame::Audio::new(self.format, self.frame_size, self.layout);
+        for (plane, buffer) in self.buffer.iter_mut().enumerate() {
+            input.data_mut(plane)[..len].copy_from_slice(&buffer[..len]);
+            buffer.drain(..len);
+        }
+
+        let pts = self.next_pts?;
+        input.set_rate(self.rate);
+        input.set_pts(Some(pts));
+        self.next_pts = Some(pts + self.frame_size as i64);
+
+        Some(input)
+    }
+
+    fn encoded_frame(&self, packet: &Packet) -> Frame {
+        Frame {
+            time: MediaTime {
+                pts: packet.pts().unwrap_or_default().max(0) as u64,
+                dts: None,
+                timebase: self.stream.timebase,
+            },
+            dependency: FrameDependency::None,
+            buffer: Bytes::copy_from_slice(packet.data().unwrap_or_default()),
+            stream: self.stream.clone(),
+            received: Instant::now(),
+            metadata: None,
+        }
+    }
+}
+
+/// Multiplies samples in the 32-bit float format of the encoders by a gain
+/// which moves from `from` to `to` over them, so the gain changing doesn't
+/// click.
+fn apply_gain(samples: &mut [u8], from: f32, to: f32) {
+    let count = (samples.len() / 4).max(1) as f32;
+
+    for (i, sample) in samples.chunks_exact_mut(4).enumerate() {
+        let factor = from + (to - from) * (i + 1) as f32 / count;
+        let value = f32::from_ne_bytes([sample[0], sample[1], sample[2], sample[3]]) * factor;
+
+        sample.copy_from_slice(&value.clamp(-1.0, 1.0).to_ne_bytes());
+    }
+}
+
+/// Sets the codec specific data of a decoder, like the AudioSpecificConfig
+/// of AAC, which the bindings have no setter for.
+fn set_extradata(context: &mut codec::Context, data: &[u8]) -> anyhow::Result<()> {
+    unsafe {
+        // libav reads past the end of the data, so it has to be padded
+        let size = data.len() + ffi::AV_INPUT_BUFFER_PADDING_SIZE as usize;
+        let extradata = ffi::av_mallocz(size) as *mut u8;
+        if extradata.is_null() {
+            anyhow::bail!("Failed to allocate {} bytes of extradata", size);
+        }
+        ptr::copy_nonoverlapping(data.as_ptr(), extradata, data.len());
+
+        let context = context.as_mut_ptr();
+        (*context).extradata = extradata;
+        (*context).extradata_size = data.len() as i32;
+    }
+
+    Ok(())
+}
+
+/// Makes the codec info of an AAC-LC stream, with the AudioSpecificConfig
+/// which the encoder encodes with.
+fn aac_codec_info(sample_rate: u32, channels: u8) -> anyhow::Result<CodecInfo> {
+    let frequency_index = AAC_SAMPLE_RATES
+        .iter()
+        .position(|&rate| rate == sample_rate)
+        .ok_or_else(|| anyhow::anyhow!("AAC has no sample rate of {} Hz", sample_rate))?
+        as u8;
+
+    let config = vec![
+        (AAC_LC << 3) | (frequency_index >> 1),
+        ((frequency_index & 1) << 7) | (channels << 3),
+    ];
+
+    Ok(CodecInfo {
+        name: "AAC",
+        properties: CodecTypeInfo::Audio(AudioCodecInfo {
+            sample_rate,
+            sample_bpp: 16,
+            sound_type: if channels == 1 {
+                SoundType::Mono
+            } else {
+                SoundType::Stereo
+            },
+            extra: AudioCodecSpecificInfo::Aac { extra: config },
+        }),
+    })
+}
+
+/// A filter which re-encodes the AAC and Opus streams which it reads or
+/// writes to the other codec, and passes video through, so a stream can be
+/// served to clients which only decode one of them without transcoding its
+/// video. With a gain, the audio is re-encoded with it applied, e.g. to
+/// normalize its loudness.
+///
+/// Like [`crate::TranscodeFilter`], it needs the multi-threaded Tokio
+/// runtime.
+pub struct AudioTranscodeFilter {
+    filter: ReadOrWriteFilter,
+    options: AudioTranscodeOptions,
+    transcoders: HashMap<u32, AudioTranscoder>,
+    /// The frames which were encoded but not read yet.
+    pending: VecDeque<Frame>,
+}
+
+impl AudioTranscodeFilter {
+    pub fn read(
+        target: Box<dyn FrameReadFilter + Send + Unpin>,
+        options: AudioTranscodeOptions,
+    ) -> Self {
+        Self::new(ReadOrWriteFilter::Read(target), options)
+    }
+
+    pub fn write(
+        target: Box<dyn FrameWriteFilter + Send + Unpin>,
+        options: AudioTranscodeOptions,
+    ) -> Self {
+        Self::new(ReadOrWriteFilter::Write(target), options)
+    }
+
+    fn new(filter: ReadOrWriteFilter, options: AudioTranscodeOptions) -> Self {
+        Self {
+            filter,
+            options,
+            transcoders: HashMap::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Makes a transcoder for each audio stream which is re-encoded, and
+    /// returns the streams with the codecs they are encoded to.
+    fn start_transcoders(&mut self, streams: Vec<Stream>) -> anyhow::Result<Vec<Stream>> {
+        ffmpeg_next::init()?;
+
+        self.transcoders.clear();
+        self.pending.clear();
+
+        streams
+            .into_iter()
+            .map(
+                |stream| match AudioTranscoder::new(&stream, &self.options)? {
+                    Some(transcoder) => {
+                        debug!(
+                            "Transcoding stream {} from {:?} to {:?}",
+                            stream.id, stream.codec, transcoder.stream.codec
+                        );
+
+                        let output = transcoder.stream.clone();
+                        self.transcoders.insert(stream.id, transcoder);
+
+                        Ok(output)
+                    }
+                    None => Ok(stream),
+                },
+            )
+            .collect()
+    }
+
+    fn transcode(&mut self, frame: Frame) -> anyhow::Result<Vec<Frame>> {
+        match self.transcoders.get_mut(&frame.stream.id) {
+            Some(transcoder) => tokio::task::block_in_place(|| transcoder.transcode(&frame)),
+            None => Ok(vec![frame]),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl FrameReadFilter for AudioTranscodeFilter {
+    async fn start(&mut self) -> anyhow::Result<Vec<Stream>> {
+        let streams = match &mut self.filter {
+            ReadOrWriteFilter::Read(read) => read.start().await?,
+            ReadOrWriteFilter::Write(_) => panic!("Tried to read from a write transcoder"),
+        };
+
+        self.start_transcoders(streams)
+    }
+
+    async fn read(&mut self) -> anyhow::Result<Frame> {
+        loop {
+            if let Some(frame) = self.pending.pop_front() {
+                return Ok(frame);
+            }
+
+            let frame = match &mut self.filter {
+                ReadOrWriteFilter::Read(read) => read.read().await?,
+                ReadOrWriteFilter::Write(_) => panic!("Tried to read from a write transcoder"),
+            };
+
+            let frames = self.transcode(frame)?;
+            self.pending.extend(frames);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl FrameWriteFilter for AudioTranscodeFilter {
+    async fn start(&mut self, streams: Vec<Stream>) -> anyhow::Result<()> {
+        let streams = self.start_transcoders(streams)?;
+
+        match &mut self.filter {
+            ReadOrWriteFilter::Write(write) => write.start(streams).await,
+            ReadOrWriteFilter::Read(_) => panic!("Tried to write to a read transcoder"),
+        }
+    }
+
+    async fn write(&mut self, frame: Frame) -> anyhow::Result<()> {
+        for frame in self.transcode(frame)? {
+            match &mut self.filter {
+                ReadOrWriteFilter::Write(write) => write.write(frame).await?,
+                ReadOrWriteFilter::Read(_) => panic!("Tried to write to a read transcoder"),
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+use ffmpeg_next::{format::Pixel, frame};
+use tracing::*;
+
+use crate::{
+    filter::{Scaler, VideoDecoder},
+    pip::{PipOverlay, PipPlacement},
+};
+
+/// Puts the latest picture of the picture-in-picture stream of an overlay
+/// on top of the pictures of a stream before they are encoded.
+pub(crate) struct Compositor {
+    overlay: PipOverlay,
+    /// The generation of the source which is decoded.
+    generation: u64,
+    decoder: Option<VideoDecoder>,
+    scaler: Option<Scaler>,
+    /// The size the secondary pictures are scaled to.
+    size: (u32, u32),
+    /// The latest secondary picture, at the size it is shown at.
+    picture: Option<frame::Video>,
+}
+
+impl Compositor {
+    pub(crate) fn new(overlay: PipOverlay) -> Self {
+        Compositor {
+            overlay,
+            generation: 0,
+            decoder: None,
+            scaler: None,
+            size: (0, 0),
+            picture: None,
+        }
+    }
+
+    /// Draws the secondary picture onto a picture of the main stream, which
+    /// is in YUV 4:2:0.
+    pub(crate) fn composite(&mut self, main: &mut frame::Video) {
+        let placement = match self.update(main.width(), main.height()) {
+            Some(placement) => placement,
+            None => return,
+        };
+
+        if let Some(picture) = &self.picture {
+            blend(main, picture, placement);
+        }
+    }
+
+    /// Decodes the frames the secondary stream sent since the last picture,
+    /// returning where it is shown if it is.
+    fn update(&mut self, width: u32, height: u32) -> Option<PipPlacement> {
+        let overlay = self.overlay.clone();
+        let mut state = overlay.state.lock().unwrap();
+
+        let source = match &mut state.source {
+            Some(source) => source,
+            None => {
+                self.reset(0);
+                return None;
+            }
+        };
+        if source.generation != self.generation {
+            self.reset(source.generation);
+        }
+
+        let placement = source.placement;
+        while let Ok(frame) = source.frames.try_recv() {
+            if let Err(e) = self.decode(&frame, placement, (width, height)) {
+                // a broken secondary stream mustn't break the main one, so
+                // it is decoded anew from its next keyframe
+                warn!("Failed to decode picture-in-picture stream: {:?}", e);
+                self.decoder = None;
+            }
+        }
+
+        Some(placement)
+    }
+
+    fn reset(&mut self, generation: u64) {
+        self.generation = generation;
+        self.decoder = None;
+        self.picture = None;
+    }
+
+    fn decode(
+        &mut self,
+        frame: &sh_media::Frame,
+        placement: PipPlacement,
+        main: (u32, u32),
+    ) -> anyhow::Result<()> {
+        if !frame.stream.is_video() {
+            return Ok(());
+        }
+
+        let decoder = match &mut self.decoder {
+            Some(decoder) => decoder,
+            // decoding can only start at a keyframe
+            None if !frame.is_keyframe() => return Ok(()),
+            decoder => match VideoDecoder::new(&frame.stream)? {
+                Some(new) => decoder.insert(new),
+                None => return Ok(()),
+            },
+        };
+        decoder.send(frame)?;
+
+        let mut decoded = frame::Video::empty();
+        while decoder.receive(&mut decoded) {
+            let size = pip_size(&decoded, placement, main);
+            if self.scaler.is_none() || self.size != size {
+                self.scaler = Some(Scaler::new(Pixel::YUV420P, size));
+                self.size = size;
+            }
+
+            if let Some(scaler) = &mut self.scaler {
+                self.picture = Some(scaler.run(&decoded)?);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The size a secondary picture is shown at, which keeps its aspect ratio
+/// and fits in the main picture from where it is placed. It is rounded to
+/// even numbers, like its position, so the chroma planes line up.
+fn pip_size(decoded: &frame::Video, placement: PipPlacement, main: (u32, u32)) -> (u32, u32) {
+    let (x, y) = position(placement, main);
+    let width = ((main.0 as f32 * placement.width) as u32).min(main.0 - x);
+    let height = (decoded.height() as u64 * width as u64 / decoded.width().max(1) as u64) as u32;
+
+    ((width & !1).max(2), (height.min(main.1 - y) & !1).max(2))
+}
+
+fn position(placement: PipPlacement, main: (u32, u32)) -> (u32, u32) {
+    let x = (main.0 as f32 * placement.x.clamp(0.0, 1.0)) as u32 & !1;
+    let y = (main.1 as f32 * placement.y.clamp(0.0, 1.0)) as u32 & !1;
+
+    (
+        x.min(main.0.saturating_sub(2)),
+        y.min(main.1.saturating_sub(2)),
+    )
+}
+
+/// Copies the planes of the secondary picture into the main picture, where
+/// the chroma planes have half the size of the luma plane.
+fn blend(main: &mut frame::Video, picture: &frame::Video, placement: PipPlacement) {
+    let (x, y) = position(placement, (main.width(), main.height()));
+
+    for plane in 0..3 {
+        let shift = if plane == 0 { 0 } else { 1 };
+        let (x, y) = ((x >> shift) as usize, (y >> shift) as usize);
+        let main_width = (main.width() >> shift) as usize;
+        let main_height = (main.height() >> shift) as usize;
+
+        let width = ((picture.width() >> shift) as usize).min(main_width.saturating_sub(x));
+        let height = ((picture.height() >> shift) as usize).min(main_height.saturating_sub(y));
+
+        let (src_stride, dst_stride) = (picture.stride(plane), main.stride(plane));
+        let src = picture.data(plane);
+        let dst = main.data_mut(plane);
+
+        for row in 0..height {
+            let from = row * src_stride;
+            let to = (y + row) * dst_stride + x;
+
+            dst[to..to + width].copy_from_slice(&src[from..from + width]);
+        }
+    }
+}
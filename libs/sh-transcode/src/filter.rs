@@ -0,0 +1,496 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::Instant,
+};
+
+use bytes::Bytes;
+use ffmpeg_next::{
+    codec::{self, Id},
+    decoder, encoder,
+    format::Pixel,
+    frame, picture,
+    software::scaling::{self, Flags},
+    Dictionary, Packet, Rational,
+};
+use sh_media::{
+    frame_nal_units, parse_bitstream, BitstreamFraming, CodecInfo, CodecTypeInfo, Frame,
+    FrameDependency, FrameReadFilter, FrameWriteFilter, MediaTime, Stream, VideoCodecInfo,
+    VideoCodecSpecificInfo,
+};
+use tracing::*;
+
+use crate::{compose::Compositor, PipOverlay, TranscodeOptions};
+
+const NAL_UNIT_TYPE_SPS: u8 = 7;
+const NAL_UNIT_TYPE_PPS: u8 = 8;
+
+pub(crate) enum ReadOrWriteFilter {
+    Read(Box<dyn FrameReadFilter + Send + Unpin>),
+    Write(Box<dyn FrameWriteFilter + Send + Unpin>),
+}
+
+/// A decoder of a H.264 or H.265 stream, which is configured in-band by
+/// putting the parameter sets of the stream before its keyframes.
+pub(crate) struct VideoDecoder {
+    framing: BitstreamFraming,
+    parameter_sets: Bytes,
+    decoder: decoder::Video,
+}
+
+impl VideoDecoder {
+    /// Makes a decoder for a stream, or returns `None` if it isn't H.264 or
+    /// H.265.
+    pub(crate) fn new(stream: &Stream) -> anyhow::Result<Option<Self>> {
+        let video = match stream.codec.video() {
+            Some(video) => video,
+            None => return Ok(None),
+        };
+
+        let id = match &video.extra {
+            VideoCodecSpecificInfo::H264 { .. } => Id::H264,
+            VideoCodecSpecificInfo::H265 { .. } => Id::HEVC,
+            _ => {
+                warn!(
+                    "Can only decode H.264 and H.265, stream {} is left as is",
+                    stream.id
+                );
+                return Ok(None);
+            }
+        };
+
+        let framing = stream
+            .bitstream_format()
+            .unwrap_or(BitstreamFraming::FourByteLength);
+        let parameter_sets = video
+            .parameter_sets()
+            .map(|sets| {
+                let nal_units = parse_bitstream(sets.into(), BitstreamFraming::FourByteLength);
+                frame_nal_units(&nal_units, BitstreamFraming::FourByteStartCode).freeze()
+            })
+            .unwrap_or_default();
+
+        let codec = decoder::find(id)
+            .ok_or_else(|| anyhow::anyhow!("ffmpeg was built without a {:?} decoder", id))?;
+        let decoder = codec::Context::new().decoder().open_as(codec)?.video()?;
+
+        Ok(Some(VideoDecoder {
+            framing,
+            parameter_sets,
+            decoder,
+        }))
+    }
+
+    pub(crate) fn send(&mut self, frame: &Frame) -> anyhow::Result<()> {
+        let nal_units = parse_bitstream(frame.buffer.clone(), self.framing);
+        let mut data = Vec::with_capacity(self.parameter_sets.len() + frame.buffer.len());
+        if frame.is_keyframe() {
+            data.extend_from_slice(&self.parameter_sets);
+        }
+        data.extend_from_slice(&frame_nal_units(
+            &nal_units,
+            BitstreamFraming::FourByteStartCode,
+        ));
+
+        let mut packet = Packet::copy(&data);
+        packet.set_pts(Some(frame.time.pts as i64));
+        packet.set_dts(frame.time.dts.map(|dts| dts as i64));
+        self.decoder.send_packet(&packet)?;
+
+        Ok(())
+    }
+
+    /// Tells the decoder there are no more frames, so it returns the
+    /// pictures it holds back.
+    pub(crate) fn finish(&mut self) -> anyhow::Result<()> {
+        Ok(self.decoder.send_eof()?)
+    }
+
+    /// Takes the next decoded picture, if there is one.
+    pub(crate) fn receive(&mut self, decoded: &mut frame::Video) -> bool {
+        self.decoder.receive_frame(decoded).is_ok()
+    }
+}
+
+/// Scales decoded pictures to a size and pixel format, making a scaler
+/// whenever the input changes size or format.
+pub(crate) struct Scaler {
+    format: Pixel,
+    size: (u32, u32),
+    context: Option<scaling::Context>,
+}
+
+impl Scaler {
+    pub(crate) fn new(format: Pixel, size: (u32, u32)) -> Self {
+        Scaler {
+            format,
+            size,
+            context: None,
+        }
+    }
+
+    pub(crate) fn run(&mut self, decoded: &frame::Video) -> anyhow::Result<frame::Video> {
+        let input = (decoded.format(), decoded.width(), decoded.height());
+        let current = self.context.as_ref().map(|context| {
+            let definition = context.input();
+            (definition.format, definition.width, definition.height)
+        });
+
+        let context = match &mut self.context {
+            Some(context) if current == Some(input) => context,
+            context => context.insert(scaling::Context::get(
+                input.0,
+                input.1,
+                input.2,
+                self.format,
+                self.size.0,
+                self.size.1,
+                Flags::BILINEAR,
+            )?),
+        };
+
+        let mut scaled = frame::Video::empty();
+        context.run(decoded, &mut scaled)?;
+        scaled.set_pts(decoded.timestamp());
+
+        Ok(scaled)
+    }
+}
+
+/// Decodes the frames of a H.264 or H.265 stream, scales them and encodes
+/// them as H.264 again.
+struct VideoTranscoder {
+    /// The stream of the encoded frames.
+    stream: Stream,
+    decoder: VideoDecoder,
+    scaler: Scaler,
+    /// Draws a picture-in-picture stream over the pictures, if any.
+    compositor: Option<Compositor>,
+    encoder: encoder::video::Encoder,
+    /// The times of the keyframes of the input which weren't decoded yet,
+    /// which are encoded as keyframes too so the output can be segmented
+    /// at the same times as the input.
+    keyframes: VecDeque<u64>,
+}
+
+// the libav contexts are only ever used by the filter which owns them, one
+// call at a time, but the scaler isn't marked as `Send` by the bindings
+unsafe impl Send for VideoTranscoder {}
+
+impl VideoTranscoder {
+    fn new(
+        stream: &Stream,
+        options: &TranscodeOptions,
+        overlay: Option<&PipOverlay>,
+    ) -> anyhow::Result<Option<Self>> {
+        let (video, decoder) = match (stream.codec.video(), VideoDecoder::new(stream)?) {
+            (Some(video), Some(decoder)) => (video, decoder),
+            _ => return Ok(None),
+        };
+
+        let (width, height) = options.output_size(video.width, video.height);
+        let timebase = Rational::new(
+            stream.timebase.numerator as i32,
+            stream.timebase.denominator as i32,
+        );
+
+        let codec = encoder::find(Id::H264)
+            .ok_or_else(|| anyhow::anyhow!("ffmpeg was built without a H.264 encoder"))?;
+        let mut encoder = codec::Context::new().encoder().video()?;
+        encoder.set_width(width);
+        encoder.set_height(height);
+        encoder.set_format(Pixel::YUV420P);
+        encoder.set_time_base(timebase);
+        encoder.set_bit_rate(options.bitrate);
+        encoder.set_max_bit_rate(options.bitrate);
+        encoder.set_gop(options.keyframe_interval);
+        // the parameter sets are needed for the codec info before the first
+        // frame is encoded
+        encoder.set_flags(codec::Flags::GLOBAL_HEADER);
+
+        let mut settings = Dictionary::new();
+        settings.set("preset", "veryfast");
+        // no B-frames nor lookahead, which would delay every frame
+        settings.set("tune", "zerolatency");
+        // pictures which are forced to be keyframes are IDR pictures, which
+        // segments can start at
+        settings.set("forced-idr", "1");
+
+        let encoder = encoder.open_as_with(codec, settings)?;
+
+        let stream = Stream {
+            id: stream.id,
+            codec: Arc::new(encoded_codec_info(&encoder, width, height)?),
+            timebase: stream.timebase,
+        };
+
+        Ok(Some(VideoTranscoder {
+            stream,
+            decoder,
+            scaler: Scaler::new(Pixel::YUV420P, (width, height)),
+            compositor: overlay.cloned().map(Compositor::new),
+            encoder,
+            keyframes: VecDeque::new(),
+        }))
+    }
+
+    fn transcode(&mut self, frame: &Frame) -> anyhow::Result<Vec<Frame>> {
+        if frame.is_keyframe() {
+            self.keyframes.push_back(frame.time.pts);
+        }
+        self.decoder.send(frame)?;
+
+        let mut decoded = frame::Video::empty();
+        while self.decoder.receive(&mut decoded) {
+            let mut scaled = self.scaler.run(&decoded)?;
+            if let Some(compositor) = &mut self.compositor {
+                compositor.composite(&mut scaled);
+            }
+            if self.is_keyframe(decoded.timestamp()) {
+                scaled.set_kind(picture::Type::I);
+            }
+            self.encoder.send_frame(&scaled)?;
+        }
+
+        let mut frames = Vec::new();
+        let mut packet = Packet::empty();
+        while self.encoder.receive_packet(&mut packet).is_ok() {
+            frames.push(self.encoded_frame(&packet));
+        }
+
+        Ok(frames)
+    }
+
+    /// Whether a decoded picture was a keyframe of the input, forgetting
+    /// the keyframes before it.
+    fn is_keyframe(&mut self, pts: Option<i64>) -> bool {
+        let pts = match pts {
+            Some(pts) => pts.max(0) as u64,
+            None => return false,
+        };
+
+        while let Some(&keyframe) = self.keyframes.front() {
+            if keyframe > pts {
+                break;
+            }
+
+            self.keyframes.pop_front();
+            if keyframe == pts {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    fn encoded_frame(&self, packet: &Packet) -> Frame {
+        let nal_units = parse_bitstream(
+            Bytes::copy_from_slice(packet.data().unwrap_or_default()),
+            BitstreamFraming::FourByteStartCode,
+        );
+        let pts = packet.pts().unwrap_or_default().max(0) as u64;
+
+        Frame {
+            time: MediaTime {
+                pts,
+                dts: packet.dts().map(|dts| dts.max(0) as u64),
+                timebase: self.stream.timebase,
+            },
+            dependency: if packet.is_key() {
+                FrameDependency::None
+            } else {
+                FrameDependency::Backwards
+            },
+            buffer: frame_nal_units(&nal_units, BitstreamFraming::FourByteLength).freeze(),
+            stream: self.stream.clone(),
+            received: Instant::now(),
+            metadata: None,
+        }
+    }
+}
+
+/// Makes the codec info of the H.264 stream of an encoder from the
+/// parameter sets in its extradata.
+fn encoded_codec_info(
+    encoder: &encoder::video::Encoder,
+    width: u32,
+    height: u32,
+) -> anyhow::Result<CodecInfo> {
+    // the bindings have no accessor for the extradata, which is Annex B
+    // parameter sets for H.264 with global headers
+    let extradata = unsafe {
+        let context = encoder.as_ptr();
+        if (*context).extradata.is_null() {
+            anyhow::bail!("The H.264 encoder has no global headers");
+        }
+
+        Bytes::copy_from_slice(std::slice::from_raw_parts(
+            (*context).extradata,
+            (*context).extradata_size as usize,
+        ))
+    };
+
+    let nal_units = parse_bitstream(extradata, BitstreamFraming::FourByteStartCode);
+    let find = |nal_unit_type| {
+        nal_units
+            .iter()
+            .find(|nal| nal.first().map(|h| h & 0x1f) == Some(nal_unit_type))
+    };
+    let sps = find(NAL_UNIT_TYPE_SPS)
+        .filter(|sps| sps.len() >= 4)
+        .ok_or_else(|| anyhow::anyhow!("The H.264 encoder has no SPS"))?;
+    let pps =
+        find(NAL_UNIT_TYPE_PPS).ok_or_else(|| anyhow::anyhow!("The H.264 encoder has no PPS"))?;
+
+    Ok(CodecInfo {
+        name: "h264",
+        properties: CodecTypeInfo::Video(VideoCodecInfo {
+            width,
+            height,
+            extra: VideoCodecSpecificInfo::H264 {
+                bitstream_format: BitstreamFraming::FourByteLength,
+                profile_indication: sps[1],
+                profile_compatibility: sps[2],
+                level_indication: sps[3],
+                sps: Arc::new(sps.to_vec()),
+                pps: Arc::new(pps.to_vec()),
+            },
+        }),
+    })
+}
+
+/// A filter which re-encodes the H.264 and H.265 streams which it reads or
+/// writes to H.264 of a given size and bitrate, and passes other streams
+/// through. With an overlay, a picture-in-picture stream is composited
+/// over the pictures while they are re-encoded.
+///
+/// Encoding takes a lot of CPU time, so it is done outside of the async
+/// workers and needs the multi-threaded Tokio runtime.
+pub struct TranscodeFilter {
+    filter: ReadOrWriteFilter,
+    options: TranscodeOptions,
+    overlay: Option<PipOverlay>,
+    transcoders: HashMap<u32, VideoTranscoder>,
+    /// The frames which were encoded but not read yet.
+    pending: VecDeque<Frame>,
+}
+
+impl TranscodeFilter {
+    pub fn read(
+        target: Box<dyn FrameReadFilter + Send + Unpin>,
+        options: TranscodeOptions,
+    ) -> Self {
+        Self::new(ReadOrWriteFilter::Read(target), options)
+    }
+
+    pub fn write(
+        target: Box<dyn FrameWriteFilter + Send + Unpin>,
+        options: TranscodeOptions,
+    ) -> Self {
+        Self::new(ReadOrWriteFilter::Write(target), options)
+    }
+
+    fn new(filter: ReadOrWriteFilter, options: TranscodeOptions) -> Self {
+        Self {
+            filter,
+            options,
+            overlay: None,
+            transcoders: HashMap::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Composites the picture-in-picture stream of `overlay` over the
+    /// video, whenever it shows one.
+    pub fn with_overlay(mut self, overlay: PipOverlay) -> Self {
+        self.overlay = Some(overlay);
+        self
+    }
+
+    /// Makes a transcoder for each video stream, and returns the streams
+    /// with the codecs they are encoded to.
+    fn start_transcoders(&mut self, streams: Vec<Stream>) -> anyhow::Result<Vec<Stream>> {
+        ffmpeg_next::init()?;
+
+        self.transcoders.clear();
+        self.pending.clear();
+
+        streams
+            .into_iter()
+            .map(|stream| {
+                match VideoTranscoder::new(&stream, &self.options, self.overlay.as_ref())? {
+                    Some(transcoder) => {
+                        debug!(
+                            "Transcoding stream {} from {:?} to {:?}",
+                            stream.id, stream.codec, transcoder.stream.codec
+                        );
+
+                        let output = transcoder.stream.clone();
+                        self.transcoders.insert(stream.id, transcoder);
+
+                        Ok(output)
+                    }
+                    None => Ok(stream),
+                }
+            })
+            .collect()
+    }
+
+    fn transcode(&mut self, frame: Frame) -> anyhow::Result<Vec<Frame>> {
+        match self.transcoders.get_mut(&frame.stream.id) {
+            Some(transcoder) => tokio::task::block_in_place(|| transcoder.transcode(&frame)),
+            None => Ok(vec![frame]),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl FrameReadFilter for TranscodeFilter {
+    async fn start(&mut self) -> anyhow::Result<Vec<Stream>> {
+        let streams = match &mut self.filter {
+            ReadOrWriteFilter::Read(read) => read.start().await?,
+            ReadOrWriteFilter::Write(_) => panic!("Tried to read from a write transcoder"),
+        };
+
+        self.start_transcoders(streams)
+    }
+
+    async fn read(&mut self) -> anyhow::Result<Frame> {
+        loop {
+            if let Some(frame) = self.pending.pop_front() {
+                return Ok(frame);
+            }
+
+            let frame = match &mut self.filter {
+                ReadOrWriteFilter::Read(read) => read.read().await?,
+                ReadOrWriteFilter::Write(_) => panic!("Tried to read from a write transcoder"),
+            };
+
+            let frames = self.transcode(frame)?;
+            self.pending.extend(frames);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl FrameWriteFilter for TranscodeFilter {
+    async fn start(&mut self, streams: Vec<Stream>) -> anyhow::Result<()> {
+        let streams = self.start_transcoders(streams)?;
+
+        match &mut self.filter {
+            ReadOrWriteFilter::Write(write) => write.start(streams).await,
+            ReadOrWriteFilter::Read(_) => panic!("Tried to write to a read transcoder"),
+        }
+    }
+
+    async fn write(&mut self, frame: Frame) -> anyhow::Result<()> {
+        for frame in self.transcode(frame)? {
+            match &mut self.filter {
+                ReadOrWriteFilter::Write(write) => write.write(frame).await?,
+                ReadOrWriteFilter::Read(_) => panic!("Tried to write to a read transcoder"),
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+//! Re-encodes video streams to a target resolution and bitrate, so a stream
+//! can be offered in qualities other than the one it was published in, and
+//! audio streams to another codec, so a stream can be served to clients
+//! which can't decode the one it was published with, or with a gain which
+//! normalizes its loudness. Video can have a picture-in-picture stream
+//! composited over it while it is re-encoded, and keyframes can be encoded
+//! as JPEG pictures, for animated previews of streams.
+//!
+//! The filters themselves need the `ffmpeg` feature, which decodes and
+//! encodes with the libav libraries of ffmpeg.
+
+#[cfg(feature = "ffmpeg")]
+mod audio;
+#[cfg(feature = "ffmpeg")]
+mod compose;
+#[cfg(feature = "ffmpeg")]
+mod filter;
+mod pip;
+#[cfg(feature = "ffmpeg")]
+mod preview;
+
+#[cfg(feature = "ffmpeg")]
+pub use audio::*;
+#[cfg(feature = "ffmpeg")]
+pub use filter::*;
+pub use pip::*;
+#[cfg(feature = "ffmpeg")]
+pub use preview::*;
+
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+/// What the video streams are re-encoded to.
+#[derive(Debug, Clone)]
+pub struct TranscodeOptions {
+    /// The width of the output, or 0 to follow the aspect ratio of the input.
+    pub width: u32,
+    /// The height of the output, or 0 to follow the aspect ratio of the
+    /// input.
+    pub height: u32,
+    /// The average bitrate in bits per second.
+    pub bitrate: usize,
+    /// The most frames from one keyframe to the next. The keyframes of the
+    /// input are keyframes of the output as well.
+    pub keyframe_interval: u32,
+}
+
+impl TranscodeOptions {
+    /// Returns the size of the output for an input of `width` by `height`,
+    /// rounded to even numbers as 4:2:0 chroma needs.
+    pub fn output_size(&self, width: u32, height: u32) -> (u32, u32) {
+        let scale = |size: u64, from: u64, to: u64| (size * to / from.max(1)) as u32;
+
+        let (out_width, out_height) = match (self.width, self.height) {
+            (0, 0) => (width, height),
+            (0, h) => (scale(width as u64, height as u64, h as u64), h),
+            (w, 0) => (w, scale(height as u64, width as u64, w as u64)),
+            (w, h) => (w, h),
+        };
+
+        ((out_width & !1).max(2), (out_height & !1).max(2))
+    }
+}
+
+/// The codecs audio can be transcoded to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioCodec {
+    /// AAC-LC, which HLS clients and RTMP need.
+    Aac,
+    /// Opus, which WebRTC needs.
+    Opus,
+}
+
+/// What the audio streams are re-encoded to.
+#[derive(Debug, Clone)]
+pub struct AudioTranscodeOptions {
+    /// The codec to encode to, or `None` to keep the codec of each stream.
+    pub codec: Option<AudioCodec>,
+    /// The average bitrate in bits per second.
+    pub bitrate: usize,
+    /// A gain which is applied to the audio, which makes streams be
+    /// re-encoded even when they already have the codec.
+    pub gain: Option<AudioGain>,
+}
+
+/// A gain in dB which can be changed while audio is encoded with it, e.g.
+/// by a loudness meter of the stream.
+#[derive(Debug, Clone, Default)]
+pub struct AudioGain(Arc<AtomicU32>);
+
+impl AudioGain {
+    pub fn set_db(&self, db: f32) {
+        self.0.store(db.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn db(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    /// The factor samples are multiplied by.
+    pub fn factor(&self) -> f32 {
+        10f32.powf(self.db() / 20.0)
+    }
+}
@@ -0,0 +1,86 @@
+use sh_media::Frame;
+use tokio::sync::mpsc;
+
+use std::sync::{Arc, Mutex};
+
+/// How many frames of a picture-in-picture stream can wait to be
+/// composited before the stream they are sent from has to wait.
+const PIP_QUEUE_LEN: usize = 64;
+
+/// Where a picture-in-picture stream is shown over the main picture, as
+/// fractions of its width and height.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PipPlacement {
+    /// The left edge of the secondary picture.
+    pub x: f32,
+    /// The top edge of the secondary picture.
+    pub y: f32,
+    /// The width of the secondary picture, whose height follows its aspect
+    /// ratio.
+    pub width: f32,
+}
+
+// only the compositor reads the source, which needs ffmpeg
+#[cfg_attr(not(feature = "ffmpeg"), allow(dead_code))]
+pub(crate) struct PipSource {
+    pub(crate) placement: PipPlacement,
+    pub(crate) frames: mpsc::Receiver<Frame>,
+    /// Tells the sources apart, so the compositor starts decoding anew when
+    /// it changes.
+    pub(crate) generation: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct PipState {
+    pub(crate) source: Option<PipSource>,
+    generations: u64,
+}
+
+/// The picture-in-picture stream of a compositing [`crate::TranscodeFilter`],
+/// which is shown, moved and hidden while it runs.
+#[derive(Clone, Default)]
+pub struct PipOverlay {
+    pub(crate) state: Arc<Mutex<PipState>>,
+}
+
+impl PipOverlay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Shows the frames sent to the returned sender at `placement`, instead
+    /// of the stream shown before. The frames have to start at a keyframe.
+    pub fn show(&self, placement: PipPlacement) -> mpsc::Sender<Frame> {
+        let (send, frames) = mpsc::channel(PIP_QUEUE_LEN);
+
+        let mut state = self.state.lock().unwrap();
+        state.generations += 1;
+        state.source = Some(PipSource {
+            placement,
+            frames,
+            generation: state.generations,
+        });
+
+        send
+    }
+
+    /// Moves the stream which is shown, returning whether there is one.
+    pub fn place(&self, placement: PipPlacement) -> bool {
+        match &mut self.state.lock().unwrap().source {
+            Some(source) => {
+                source.placement = placement;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stops showing a stream, which closes its sender.
+    pub fn hide(&self) {
+        self.state.lock().unwrap().source = None;
+    }
+
+    pub fn is_shown(&self) -> bool {
+        self.state.lock().unwrap().source.is_some()
+    }
+}
@@ -0,0 +1,70 @@
+use bytes::Bytes;
+use ffmpeg_next::{
+    codec::{self, Id},
+    encoder,
+    format::Pixel,
+    frame, Packet,
+};
+use sh_media::Frame;
+
+use crate::filter::{Scaler, VideoDecoder};
+
+/// Decodes keyframes and encodes each of them as a JPEG picture `width`
+/// pixels wide, like the frames of an animated MJPEG preview of a stream.
+///
+/// Keyframes of codecs which can't be decoded are left out. This takes a
+/// while, so it should be called outside of the async workers.
+pub fn encode_jpegs(keyframes: &[Frame], width: u32) -> anyhow::Result<Vec<Bytes>> {
+    ffmpeg_next::init()?;
+
+    let mut pictures = Vec::with_capacity(keyframes.len());
+    for keyframe in keyframes {
+        if let Some(decoded) = decode_keyframe(keyframe)? {
+            pictures.push(encode_jpeg(&decoded, width)?);
+        }
+    }
+
+    Ok(pictures)
+}
+
+/// Decodes a keyframe on its own, with a decoder for the stream it is of
+/// since keyframes may be of streams which changed in between.
+fn decode_keyframe(keyframe: &Frame) -> anyhow::Result<Option<frame::Video>> {
+    let mut decoder = match VideoDecoder::new(&keyframe.stream)? {
+        Some(decoder) => decoder,
+        None => return Ok(None),
+    };
+
+    decoder.send(keyframe)?;
+    decoder.finish()?;
+
+    let mut decoded = frame::Video::empty();
+    Ok(decoder.receive(&mut decoded).then_some(decoded))
+}
+
+fn encode_jpeg(decoded: &frame::Video, width: u32) -> anyhow::Result<Bytes> {
+    // keeps the aspect ratio, rounded to even numbers as 4:2:0 chroma needs
+    let height = (decoded.height() as u64 * width as u64 / decoded.width().max(1) as u64) as u32;
+    let size = (width & !1, (height & !1).max(2));
+
+    // JPEG has full range luma, which the scaler converts to
+    let mut scaled = Scaler::new(Pixel::YUVJ420P, size).run(decoded)?;
+    scaled.set_pts(Some(0));
+
+    let codec = encoder::find(Id::MJPEG)
+        .ok_or_else(|| anyhow::anyhow!("ffmpeg was built without a JPEG encoder"))?;
+    let mut encoder = codec::Context::new().encoder().video()?;
+    encoder.set_width(size.0);
+    encoder.set_height(size.1);
+    encoder.set_format(Pixel::YUVJ420P);
+    encoder.set_time_base((1, 1));
+    let mut encoder = encoder.open_as(codec)?;
+
+    encoder.send_frame(&scaled)?;
+    encoder.send_eof()?;
+
+    let mut packet = Packet::empty();
+    encoder.receive_packet(&mut packet)?;
+
+    Ok(Bytes::copy_from_slice(packet.data().unwrap_or_default()))
+}
@@ -0,0 +1,123 @@
+use chrono::{SecondsFormat, Utc};
+
+use std::fmt::Write;
+
+use crate::HlsPlaylist;
+
+impl HlsPlaylist {
+    /// Writes a live DASH manifest of the same segments as the playlist, or
+    /// `None` before the first segment is done.
+    ///
+    /// Segments are referred to as `segments/<sequence>` relative to the
+    /// manifest, and the initialization segment as `init.mp4`. With chunked
+    /// segments, the next segment is announced as available once it starts,
+    /// so players request it while it is written.
+    pub fn dash_manifest(&self) -> Option<String> {
+        dash_manifest(&[(self, "")])
+    }
+}
+
+/// Writes a live DASH manifest with the playlists as representations of one
+/// adaptation set, which players adapt between by bandwidth, or `None`
+/// before the first playlist has a segment.
+///
+/// The segments of each playlist are referred to relative to its base URL,
+/// like `../app_720p/`, as [`HlsPlaylist::dash_manifest`] does. The timing
+/// of the manifest is that of the first playlist, which the others have to
+/// be segmented in line with. Playlists without segments are left out.
+pub fn dash_manifest<U: AsRef<str>>(representations: &[(&HlsPlaylist, U)]) -> Option<String> {
+    let (main, _) = representations.first()?;
+    main.segments.front()?;
+    let started = main.started?;
+
+    let target_duration = main.target_duration.max(1);
+    let depth = main.segments.iter().map(|s| s.duration).sum::<f64>();
+
+    let mut mpd = String::new();
+
+    // writing to a String can't fail
+    let _ = writeln!(mpd, r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    let _ = writeln!(
+        mpd,
+        r#"<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" profiles="urn:mpeg:dash:profile:isoff-live:2011" type="dynamic" availabilityStartTime="{}" publishTime="{}" minimumUpdatePeriod="PT{}S" timeShiftBufferDepth="PT{:.3}S" suggestedPresentationDelay="PT{}S" minBufferTime="PT{}S">"#,
+        started.to_rfc3339_opts(SecondsFormat::Millis, true),
+        Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        target_duration,
+        depth,
+        3 * target_duration,
+        target_duration
+    );
+    let _ = writeln!(mpd, r#"  <Period id="0" start="PT0S">"#);
+    let _ = writeln!(
+        mpd,
+        r#"    <AdaptationSet mimeType="video/mp4" segmentAlignment="true" startWithSAP="1">"#
+    );
+    for (id, (playlist, base)) in representations.iter().enumerate() {
+        write_representation(&mut mpd, id, playlist, base.as_ref(), target_duration);
+    }
+    let _ = writeln!(mpd, r#"    </AdaptationSet>"#);
+    let _ = writeln!(mpd, r#"  </Period>"#);
+    let _ = writeln!(mpd, r#"</MPD>"#);
+
+    Some(mpd)
+}
+
+fn write_representation(
+    mpd: &mut String,
+    id: usize,
+    playlist: &HlsPlaylist,
+    base: &str,
+    target_duration: u64,
+) {
+    let first = match playlist.segments.front() {
+        Some(first) => first,
+        None => return,
+    };
+
+    let depth = playlist.segments.iter().map(|s| s.duration).sum::<f64>();
+    let bytes = playlist
+        .segments
+        .iter()
+        .map(|s| s.data.len())
+        .sum::<usize>();
+    let bandwidth = (bytes as f64 * 8.0 / depth.max(0.001)) as u64;
+
+    let _ = write!(
+        mpd,
+        r#"      <Representation id="{}" bandwidth="{}""#,
+        id, bandwidth
+    );
+    if let Some(codecs) = &playlist.codecs {
+        let _ = write!(mpd, r#" codecs="{}""#, codecs);
+    }
+    if let Some((width, height)) = playlist.resolution {
+        let _ = write!(mpd, r#" width="{}" height="{}""#, width, height);
+    }
+    let _ = writeln!(mpd, ">");
+
+    let chunked = if playlist.chunked {
+        format!(
+            r#" availabilityTimeOffset="{}" availabilityTimeComplete="false""#,
+            target_duration
+        )
+    } else {
+        String::new()
+    };
+    let _ = writeln!(
+        mpd,
+        r#"        <SegmentTemplate timescale="1000" initialization="{0}init.mp4" media="{0}segments/$Number$" startNumber="{1}"{2}>"#,
+        base, first.sequence, chunked
+    );
+    let _ = writeln!(mpd, r#"          <SegmentTimeline>"#);
+    for segment in &playlist.segments {
+        let _ = writeln!(
+            mpd,
+            r#"            <S t="{}" d="{}"/>"#,
+            (segment.offset * 1000.0).round() as u64,
+            (segment.duration * 1000.0).round() as u64
+        );
+    }
+    let _ = writeln!(mpd, r#"          </SegmentTimeline>"#);
+    let _ = writeln!(mpd, r#"        </SegmentTemplate>"#);
+    let _ = writeln!(mpd, r#"      </Representation>"#);
+}
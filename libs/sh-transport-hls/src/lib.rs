@@ -0,0 +1,980 @@
+use sh_fmp4::{CmafMuxer, Mp4Index};
+use sh_media::*;
+
+use anyhow::Context;
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, SecondsFormat, Utc};
+use parking_lot::{Mutex, RwLock};
+use tokio::sync::Notify;
+use tracing::*;
+
+use std::{collections::VecDeque, fmt::Write, io::Cursor, sync::Arc, time::Duration};
+
+mod dash;
+
+pub use dash::dash_manifest;
+
+/// How a stream is segmented for HLS.
+#[derive(Debug, Clone, Copy)]
+pub struct HlsConfig {
+    /// How long segments are at least, since they can only be cut at video
+    /// keyframes.
+    pub segment_duration: Duration,
+    /// How long the partial segments of low-latency HLS are at most, if
+    /// segments are split into parts.
+    pub part_duration: Option<Duration>,
+    /// How many segments are kept in the playlist.
+    pub window: usize,
+    /// Whether segments can be requested while they are written, and sent
+    /// a CMAF chunk per frame, for low-latency DASH.
+    pub chunked: bool,
+}
+
+struct Part {
+    /// The length of the part in seconds.
+    duration: f64,
+    /// Whether the part starts with a video keyframe.
+    independent: bool,
+    data: Bytes,
+}
+
+struct Segment {
+    sequence: u64,
+    /// The publisher session the segment is from, whose initialization
+    /// segment it needs.
+    session: u64,
+    /// Whether the segment is the first after the publisher reconnected.
+    discontinuity: bool,
+    /// Whether the segment stands in for time without a publisher, and has
+    /// no media.
+    gap: bool,
+    /// The wall clock time the segment starts at.
+    date: DateTime<Utc>,
+    /// The time of the video keyframe the segment starts with.
+    start: MediaTime,
+    /// How many seconds into the output the segment starts.
+    offset: f64,
+    /// The length of the segment in seconds.
+    duration: f64,
+    data: Bytes,
+    /// The parts of the segment, which share its data.
+    parts: Vec<Part>,
+}
+
+/// The live HLS playlist of a stream, with the CMAF segments in it.
+#[derive(Default)]
+pub struct HlsPlaylist {
+    /// The initialization segments of the publisher sessions which segments
+    /// in the playlist are from, the latest last.
+    inits: VecDeque<(u64, Bytes)>,
+    /// How many discontinuities have left the playlist.
+    discontinuity_sequence: u64,
+    /// Whether the next segment is the first after the publisher
+    /// reconnected.
+    discontinuity: bool,
+    /// When the first frame of the output was written.
+    started: Option<DateTime<Utc>>,
+    /// The codecs of the tracks, as in the `codecs` attribute of DASH.
+    codecs: Option<String>,
+    /// The width and height of the video.
+    resolution: Option<(u32, u32)>,
+    /// The `EXT-X-TARGETDURATION` of the playlist in seconds, which players
+    /// expect not to change while it is live.
+    target_duration: u64,
+    /// How many seconds of segments have been written.
+    elapsed: f64,
+    segments: VecDeque<Segment>,
+    next_sequence: u64,
+    /// The parts of the segment which is being written.
+    parts: Vec<Part>,
+    /// What has been written of the segment which is being written, a CMAF
+    /// chunk per frame, and how many of them are in its parts.
+    chunks: Vec<Bytes>,
+    parted_chunks: usize,
+    /// Whether the segment which is being written can be requested.
+    chunked: bool,
+    /// How long parts are at most in seconds, if the playlist has parts.
+    part_target: Option<f64>,
+    /// Notified whenever a part or segment is added.
+    updated: Arc<Notify>,
+}
+
+/// A media playlist as a variant of a multivariant playlist, with what
+/// players pick between variants by.
+#[derive(Debug, Clone)]
+pub struct HlsVariant {
+    /// The peak bit rate of the segments, in bits per second.
+    pub bandwidth: u64,
+    /// The bit rate over all the segments, in bits per second.
+    pub average_bandwidth: u64,
+    /// The codecs of the tracks, as in the `CODECS` attribute of HLS.
+    pub codecs: Option<String>,
+    /// The width and height of the video.
+    pub resolution: Option<(u32, u32)>,
+}
+
+/// Writes a multivariant playlist of media playlists at the given URIs,
+/// which players start with the first of and adapt between by bandwidth.
+pub fn multivariant_playlist<U: AsRef<str>>(variants: &[(HlsVariant, U)]) -> String {
+    let mut playlist = String::new();
+
+    // writing to a String can't fail
+    let _ = writeln!(playlist, "#EXTM3U");
+    let _ = writeln!(playlist, "#EXT-X-VERSION:7");
+    let _ = writeln!(playlist, "#EXT-X-INDEPENDENT-SEGMENTS");
+
+    for (variant, uri) in variants {
+        let _ = write!(
+            playlist,
+            "#EXT-X-STREAM-INF:BANDWIDTH={},AVERAGE-BANDWIDTH={}",
+            variant.bandwidth, variant.average_bandwidth
+        );
+        if let Some(codecs) = &variant.codecs {
+            let _ = write!(playlist, ",CODECS=\"{}\"", codecs);
+        }
+        if let Some((width, height)) = variant.resolution {
+            let _ = write!(playlist, ",RESOLUTION={}x{}", width, height);
+        }
+        let _ = writeln!(playlist);
+        let _ = writeln!(playlist, "{}", uri.as_ref());
+    }
+
+    playlist
+}
+
+impl HlsPlaylist {
+    /// Returns the initialization segment, once the stream has started.
+    pub fn init(&self) -> Option<Bytes> {
+        self.inits.back().map(|(_, init)| init.clone())
+    }
+
+    /// Returns the initialization segment of a publisher session, as the
+    /// playlist refers to it, or the latest one without a session.
+    pub fn session_init(&self, session: Option<u64>) -> Option<Bytes> {
+        match session {
+            Some(session) => self
+                .inits
+                .iter()
+                .find(|(s, _)| *s == session)
+                .map(|(_, init)| init.clone()),
+            None => self.init(),
+        }
+    }
+
+    /// Returns a segment by its media sequence number, if it is still in
+    /// the playlist and isn't a gap.
+    pub fn segment(&self, sequence: u64) -> Option<Bytes> {
+        let first = self.segments.front()?.sequence;
+        let index = sequence.checked_sub(first)? as usize;
+
+        self.segments
+            .get(index)
+            .filter(|s| !s.gap)
+            .map(|s| s.data.clone())
+    }
+
+    /// Returns the bytes of a segment from `offset`, and whether the
+    /// segment is complete, or `None` if it isn't in the playlist. With
+    /// chunked segments this includes the segment which is being written.
+    pub fn segment_from(&self, sequence: u64, offset: usize) -> Option<(Bytes, bool)> {
+        if self.chunked && sequence == self.next_sequence {
+            let mut data = BytesMut::new();
+            let mut skip = offset;
+
+            for chunk in &self.chunks {
+                let from = skip.min(chunk.len());
+                skip -= from;
+                data.extend_from_slice(&chunk[from..]);
+            }
+
+            return Some((data.freeze(), false));
+        }
+
+        let segment = self.segment(sequence)?;
+        let from = offset.min(segment.len());
+
+        Some((segment.slice(from..), true))
+    }
+
+    /// Whether segments can be requested while they are written.
+    pub fn is_chunked(&self) -> bool {
+        self.chunked
+    }
+
+    /// Returns a part of a segment, which may still be being written.
+    pub fn part(&self, sequence: u64, part: usize) -> Option<Bytes> {
+        if sequence == self.next_sequence {
+            return self.parts.get(part).map(|p| p.data.clone());
+        }
+
+        let first = self.segments.front()?.sequence;
+        let index = sequence.checked_sub(first)? as usize;
+
+        self.segments
+            .get(index)?
+            .parts
+            .get(part)
+            .map(|p| p.data.clone())
+    }
+
+    /// Whether the playlist has gotten to the part `part` of a segment, or
+    /// to the end of the segment without a part.
+    pub fn has_part(&self, sequence: u64, part: Option<usize>) -> bool {
+        sequence < self.next_sequence
+            || (sequence == self.next_sequence && part.is_some_and(|p| p < self.parts.len()))
+    }
+
+    /// Returns the time of the video keyframe a segment starts with, e.g.
+    /// for a viewer to continue from it over another transport.
+    pub fn segment_start(&self, sequence: u64) -> Option<u64> {
+        let first = self.segments.front()?.sequence;
+        let index = sequence.checked_sub(first)? as usize;
+
+        self.segments
+            .get(index)
+            .filter(|s| !s.gap)
+            .map(|s| s.start.pts)
+    }
+
+    /// Returns how many seconds into the playlist the video frame with the
+    /// time `pts` is, if it is in one of the segments.
+    pub fn offset_of(&self, pts: u64) -> Option<f64> {
+        let mut offset = 0.0;
+
+        for segment in &self.segments {
+            if !segment.gap && pts >= segment.start.pts {
+                let time = MediaTime {
+                    pts,
+                    dts: None,
+                    timebase: segment.start.timebase,
+                };
+                let into: Duration = (&time - &segment.start).into();
+
+                if into.as_secs_f64() < segment.duration {
+                    return Some(offset + into.as_secs_f64());
+                }
+            }
+
+            offset += segment.duration;
+        }
+
+        None
+    }
+
+    /// Writes the media playlist, or `None` before the first segment is
+    /// done. Players start `start` seconds into the playlist if it is set,
+    /// instead of near live.
+    ///
+    /// With `skip`, the playlist is a delta update which leaves out the
+    /// segments the player already has from earlier reloads, as far as
+    /// `CAN-SKIP-UNTIL` allows.
+    ///
+    /// Segments are referred to as `segments/<sequence>` relative to the
+    /// playlist, and the initialization segment of each publisher session
+    /// as `init.mp4?session=<session>`.
+    ///
+    /// Every segment has its wall clock time. The first segment after a
+    /// reconnect is marked as a discontinuity, after gaps for the time
+    /// without a publisher.
+    pub fn playlist(&self, start: Option<f64>, skip: bool) -> Option<String> {
+        let first = self.segments.front()?.sequence;
+        let target_duration = self.target_duration.max(1);
+
+        // segments which end further than this from the end of the playlist
+        // can be skipped
+        let can_skip_until = 6.0 * target_duration as f64;
+        let skipped = if skip {
+            let mut end_to_end: f64 = self.segments.iter().map(|s| s.duration).sum();
+
+            self.segments
+                .iter()
+                .take_while(|segment| {
+                    end_to_end -= segment.duration;
+                    end_to_end >= can_skip_until
+                })
+                .count()
+        } else {
+            0
+        };
+
+        let mut playlist = String::new();
+
+        // writing to a String can't fail
+        let _ = writeln!(playlist, "#EXTM3U");
+        // skipping segments needs a newer version
+        let _ = writeln!(
+            playlist,
+            "#EXT-X-VERSION:{}",
+            if skipped > 0 { 9 } else { 7 }
+        );
+        let _ = writeln!(playlist, "#EXT-X-TARGETDURATION:{}", target_duration);
+        let _ = write!(
+            playlist,
+            "#EXT-X-SERVER-CONTROL:CAN-SKIP-UNTIL={:.3}",
+            can_skip_until
+        );
+        if let Some(part_target) = self.part_target {
+            let _ = write!(
+                playlist,
+                ",CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK={:.3}",
+                3.0 * part_target
+            );
+        }
+        let _ = writeln!(playlist);
+        if let Some(part_target) = self.part_target {
+            let _ = writeln!(playlist, "#EXT-X-PART-INF:PART-TARGET={:.3}", part_target);
+        }
+        let _ = writeln!(playlist, "#EXT-X-MEDIA-SEQUENCE:{}", first);
+        if self.discontinuity_sequence > 0 {
+            let _ = writeln!(
+                playlist,
+                "#EXT-X-DISCONTINUITY-SEQUENCE:{}",
+                self.discontinuity_sequence
+            );
+        }
+        let _ = writeln!(playlist, "#EXT-X-INDEPENDENT-SEGMENTS");
+        if let Some(start) = start {
+            let _ = writeln!(
+                playlist,
+                "#EXT-X-START:TIME-OFFSET={:.3},PRECISE=YES",
+                start
+            );
+        }
+        if skipped > 0 {
+            let _ = writeln!(playlist, "#EXT-X-SKIP:SKIPPED-SEGMENTS={}", skipped);
+        }
+
+        // parts are only listed for the last three target durations
+        let mut parts_from = self.segments.len();
+        let mut remaining = 3.0 * target_duration as f64;
+        while parts_from > 0 && remaining > 0.0 {
+            parts_from -= 1;
+            remaining -= self.segments[parts_from].duration;
+        }
+
+        // the session of the last segment written, whose initialization
+        // segment applies
+        let mut session = None;
+
+        for (i, segment) in self.segments.iter().enumerate().skip(skipped) {
+            if segment.discontinuity {
+                let _ = writeln!(playlist, "#EXT-X-DISCONTINUITY");
+            }
+            if session != Some(segment.session) {
+                write_map(&mut playlist, segment.session);
+                session = Some(segment.session);
+            }
+            let _ = writeln!(
+                playlist,
+                "#EXT-X-PROGRAM-DATE-TIME:{}",
+                segment.date.to_rfc3339_opts(SecondsFormat::Millis, true)
+            );
+            if segment.gap {
+                let _ = writeln!(playlist, "#EXT-X-GAP");
+            }
+
+            if self.part_target.is_some() && i >= parts_from {
+                write_parts(&mut playlist, segment.sequence, &segment.parts);
+            }
+
+            let _ = writeln!(playlist, "#EXTINF:{:.3},", segment.duration);
+            let _ = writeln!(playlist, "segments/{}", segment.sequence);
+        }
+
+        if self.part_target.is_some() {
+            // the parts of the first segment after a reconnect
+            if let Some((current, _)) = self.inits.back() {
+                if !self.parts.is_empty() && session != Some(*current) {
+                    if self.discontinuity {
+                        let _ = writeln!(playlist, "#EXT-X-DISCONTINUITY");
+                    }
+                    write_map(&mut playlist, *current);
+                }
+            }
+
+            write_parts(&mut playlist, self.next_sequence, &self.parts);
+
+            let _ = writeln!(
+                playlist,
+                "#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"segments/{}/parts/{}\"",
+                self.next_sequence,
+                self.parts.len()
+            );
+        }
+
+        Some(playlist)
+    }
+
+    /// Describes the media playlist as a variant of a multivariant
+    /// playlist, or `None` before the first segment is done.
+    ///
+    /// Chromecast and AirPlay receivers pick the decoder up front from the
+    /// `CODECS` and `RESOLUTION` of the variant, so they play a multivariant
+    /// playlist rather than the media playlist.
+    pub fn variant(&self) -> Option<HlsVariant> {
+        let segments = || self.segments.iter().filter(|s| !s.gap);
+        let depth = segments().map(|s| s.duration).sum::<f64>();
+        let bytes = segments().map(|s| s.data.len()).sum::<usize>();
+        let average = (bytes as f64 * 8.0 / depth.max(0.001)) as u64;
+        let peak = segments()
+            .map(|s| (s.data.len() as f64 * 8.0 / s.duration.max(0.001)) as u64)
+            .max()?;
+
+        Some(HlsVariant {
+            bandwidth: peak,
+            average_bandwidth: average,
+            codecs: self.codecs.clone(),
+            resolution: self.resolution,
+        })
+    }
+
+    /// The codecs of the tracks, as in the `CODECS` attribute of HLS.
+    pub fn codecs(&self) -> Option<&str> {
+        self.codecs.as_deref()
+    }
+
+    /// Whether the default receiver of Chromecast plays the stream, which
+    /// is H.264 in the Baseline, Main or High profile up to level 4.2 and
+    /// 1080p, with AAC-LC audio if any.
+    pub fn is_cast_compatible(&self) -> bool {
+        let codecs = match &self.codecs {
+            Some(codecs) => codecs,
+            None => return false,
+        };
+
+        let compatible_codecs = codecs.split(',').all(|codec| {
+            if let Some(avc) = codec
+                .strip_prefix("avc1.")
+                .or_else(|| codec.strip_prefix("avc3."))
+            {
+                let profile = avc.get(0..2).and_then(|p| u8::from_str_radix(p, 16).ok());
+                let level = avc.get(4..6).and_then(|l| u8::from_str_radix(l, 16).ok());
+
+                matches!(profile, Some(66 | 77 | 100)) && level.is_some_and(|l| l <= 42)
+            } else {
+                codec == "mp4a.40.2"
+            }
+        });
+        let compatible_resolution = self
+            .resolution
+            .is_some_and(|(width, height)| width <= 1920 && height <= 1080);
+
+        compatible_codecs && compatible_resolution
+    }
+
+    /// Starts the segments of a publisher session with its initialization
+    /// segment, at `now`.
+    ///
+    /// A playlist with segments of an earlier session, from before the
+    /// publisher reconnected, continues with gaps for the time without a
+    /// publisher, as much of it as fits in the window, and a discontinuity.
+    fn start_session(&mut self, init: Bytes, now: DateTime<Utc>, config: &HlsConfig) {
+        let session = self.inits.back().map_or(0, |(session, _)| session + 1);
+        self.inits.push_back((session, init));
+        self.started.get_or_insert(now);
+        self.target_duration = self
+            .target_duration
+            .max(config.segment_duration.as_secs_f64().ceil() as u64);
+
+        // what was written of the unfinished segment is lost
+        self.parts.clear();
+        self.chunks.clear();
+        self.parted_chunks = 0;
+
+        let end = match self.segments.back() {
+            Some(last) => last.date + seconds(last.duration),
+            None => return,
+        };
+        self.discontinuity = true;
+
+        let target = (config.segment_duration.as_millis() as u64).max(1);
+        let missing =
+            ((now - end).num_milliseconds().max(0) as u64).min(target * config.window as u64);
+        let gaps = missing.div_ceil(target);
+
+        for i in 0..gaps {
+            // the first gap is the shortest, so the rest line up with now
+            let until = missing - (gaps - 1 - i) * target;
+            let from = until.saturating_sub(target);
+            let duration = (until - from) as f64 / 1000.0;
+
+            self.segments.push_back(Segment {
+                sequence: self.next_sequence,
+                session,
+                discontinuity: std::mem::take(&mut self.discontinuity),
+                gap: true,
+                date: now - chrono::Duration::milliseconds((missing - from) as i64),
+                start: MediaTime {
+                    pts: 0,
+                    dts: None,
+                    timebase: Fraction::new(1, 1000),
+                },
+                offset: self.elapsed,
+                duration,
+                data: Bytes::new(),
+                parts: Vec::new(),
+            });
+            self.next_sequence += 1;
+            self.elapsed += duration;
+        }
+
+        self.trim(config.window);
+        self.updated.notify_waiters();
+    }
+
+    /// Adds what was written for a frame to the segment which is being
+    /// written.
+    fn push_chunk(&mut self, chunk: Bytes) {
+        if chunk.is_empty() {
+            return;
+        }
+
+        self.chunks.push(chunk);
+
+        if self.chunked {
+            self.updated.notify_waiters();
+        }
+    }
+
+    /// Ends a part with the chunks written since the last one.
+    fn push_part(&mut self, duration: f64, independent: bool) {
+        let mut data = BytesMut::new();
+        for chunk in &self.chunks[self.parted_chunks..] {
+            data.extend_from_slice(chunk);
+        }
+        let data = data.freeze();
+        self.parted_chunks = self.chunks.len();
+
+        self.parts.push(Part {
+            duration,
+            independent,
+            data,
+        });
+
+        self.updated.notify_waiters();
+    }
+
+    /// Ends the segment which is being written with the parts written so
+    /// far, which started at the wall clock time `date`.
+    fn push_segment(&mut self, start: MediaTime, date: DateTime<Utc>, window: usize) {
+        let parts = std::mem::take(&mut self.parts);
+        self.chunks.clear();
+        self.parted_chunks = 0;
+
+        let mut data = BytesMut::new();
+        for part in &parts {
+            data.extend_from_slice(&part.data);
+        }
+        let data = data.freeze();
+
+        let mut offset = 0;
+        let parts = parts
+            .into_iter()
+            .map(|part| {
+                let end = offset + part.data.len();
+                let data = data.slice(offset..end);
+                offset = end;
+
+                Part { data, ..part }
+            })
+            .collect::<Vec<_>>();
+
+        let duration = parts_duration(&parts);
+        if duration.ceil() as u64 > self.target_duration {
+            // segments are only cut at keyframes, which the publisher may
+            // send further apart than the configured segment duration
+            warn!(
+                "HLS segment of {:.3}s is longer than the target duration of {}s",
+                duration, self.target_duration
+            );
+            self.target_duration = duration.ceil() as u64;
+        }
+
+        self.segments.push_back(Segment {
+            sequence: self.next_sequence,
+            session: self.inits.back().map_or(0, |(session, _)| *session),
+            discontinuity: std::mem::take(&mut self.discontinuity),
+            gap: false,
+            date,
+            start,
+            offset: self.elapsed,
+            duration,
+            data,
+            parts,
+        });
+        self.next_sequence += 1;
+        self.elapsed += duration;
+
+        self.trim(window);
+        self.updated.notify_waiters();
+    }
+
+    /// Drops the oldest segments past the window, and the initialization
+    /// segments which none of the rest need.
+    fn trim(&mut self, window: usize) {
+        while self.segments.len() > window.max(1) {
+            if let Some(segment) = self.segments.pop_front() {
+                if segment.discontinuity {
+                    self.discontinuity_sequence += 1;
+                }
+            }
+        }
+
+        if let Some(first) = self.segments.front() {
+            while self.inits.len() > 1 && self.inits[0].0 < first.session {
+                self.inits.pop_front();
+            }
+        }
+    }
+}
+
+fn seconds(seconds: f64) -> chrono::Duration {
+    chrono::Duration::milliseconds((seconds * 1000.0).round() as i64)
+}
+
+fn write_map(playlist: &mut String, session: u64) {
+    let _ = writeln!(playlist, "#EXT-X-MAP:URI=\"init.mp4?session={}\"", session);
+}
+
+fn parts_duration(parts: &[Part]) -> f64 {
+    parts.iter().map(|p| p.duration).sum()
+}
+
+fn write_parts(playlist: &mut String, sequence: u64, parts: &[Part]) {
+    for (i, part) in parts.iter().enumerate() {
+        let _ = write!(
+            playlist,
+            "#EXT-X-PART:DURATION={:.3},URI=\"segments/{}/parts/{}\"",
+            part.duration, sequence, i
+        );
+        if part.independent {
+            let _ = write!(playlist, ",INDEPENDENT=YES");
+        }
+        let _ = writeln!(playlist);
+    }
+}
+
+/// Waits until `playlist` has gotten to the part `part` of a segment, or to
+/// the end of the segment without a part, e.g. for blocking playlist
+/// reloads. Returns whether it did within `timeout`.
+pub async fn wait_for_part(
+    playlist: &RwLock<HlsPlaylist>,
+    sequence: u64,
+    part: Option<usize>,
+    timeout: Duration,
+) -> bool {
+    let wait = async {
+        loop {
+            let updated = playlist.read().updated.clone();
+
+            // waiters are notified of every update after they are created
+            let notified = updated.notified();
+            if playlist.read().has_part(sequence, part) {
+                return;
+            }
+
+            notified.await;
+        }
+    };
+
+    tokio::time::timeout(timeout, wait).await.is_ok()
+}
+
+/// Writes a segment to `output`, and with chunked segments the rest of it
+/// as it is written, so players get every CMAF chunk as soon as it is
+/// muxed. Fails if the segment isn't in the playlist, or if nothing was
+/// added to it within `timeout`.
+pub async fn write_segment(
+    playlist: &RwLock<HlsPlaylist>,
+    sequence: u64,
+    timeout: Duration,
+    output: &mut (dyn ByteWriteFilter2 + Send + Unpin),
+) -> anyhow::Result<()> {
+    output.start().await?;
+
+    let mut offset = 0;
+
+    loop {
+        let updated = playlist.read().updated.clone();
+
+        // waiters are notified of every update after they are created
+        let notified = updated.notified();
+        let next = playlist.read().segment_from(sequence, offset);
+        let (data, complete) = match next {
+            Some(next) => next,
+            None => anyhow::bail!("Segment {} is not in the playlist", sequence),
+        };
+
+        if !data.is_empty() {
+            offset += data.len();
+            output.write(data).await?;
+        }
+        if complete {
+            return Ok(());
+        }
+
+        tokio::time::timeout(timeout, notified)
+            .await
+            .context("waiting for the rest of the segment")?;
+    }
+}
+
+/// Collects what the fMP4 writer writes until it is taken, after the
+/// initialization segment and after every frame.
+#[derive(Clone, Default)]
+struct SegmentBuffer(Arc<Mutex<BytesMut>>);
+
+impl SegmentBuffer {
+    fn take(&self) -> Bytes {
+        self.0.lock().split().freeze()
+    }
+}
+
+#[async_trait::async_trait]
+impl ByteWriteFilter2 for SegmentBuffer {
+    async fn start(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn write(&mut self, bytes: Bytes) -> anyhow::Result<()> {
+        self.0.lock().extend_from_slice(&bytes);
+
+        Ok(())
+    }
+}
+
+/// Segments the frames of `read` into CMAF fragments for `playlist`,
+/// starting at the first video keyframe.
+///
+/// A segment is cut at the first video keyframe after it has lasted
+/// `config.segment_duration`, and split into parts of at most
+/// `config.part_duration` if it is set. Returns when reading fails, e.g.
+/// when the stream ends.
+pub async fn run_hls_output(
+    mut read: MediaFrameQueueReceiver,
+    playlist: Arc<RwLock<HlsPlaylist>>,
+    config: HlsConfig,
+) -> anyhow::Result<()> {
+    let streams = read.start().await?;
+    if !streams.iter().any(|s| s.is_video()) {
+        anyhow::bail!("HLS output needs a video stream");
+    }
+
+    let resolution = streams
+        .iter()
+        .find_map(|stream| match &stream.codec.properties {
+            CodecTypeInfo::Video(info) => Some((info.width, info.height)),
+            _ => None,
+        });
+
+    let buffer = SegmentBuffer::default();
+    let mut write = CmafMuxer::aligned().mux(Box::new(buffer.clone()));
+
+    let first_frame = wait_for_sync_frame(&mut read)
+        .await
+        .context("waiting for first sync frame")?;
+    write.start(streams).await.context("starting to write")?;
+    // segments are dated by how far into the session they start
+    let session_start = Utc::now();
+    {
+        let init = buffer.take();
+        let codecs = Mp4Index::read(&mut Cursor::new(&init[..]))
+            .map(|index| index.codecs_string())
+            .ok()
+            .filter(|codecs| !codecs.is_empty());
+
+        let mut playlist = playlist.write();
+        playlist.start_session(init, session_start, &config);
+        playlist.codecs = codecs;
+        playlist.resolution = resolution;
+        playlist.part_target = config.part_duration.map(|d| d.as_secs_f64());
+        playlist.chunked = config.chunked;
+    }
+
+    let first_time = first_frame.time.clone();
+    let date_of = |time: &MediaTime| {
+        let since: Duration = (time - &first_time).into();
+        session_start + chrono::Duration::from_std(since).unwrap_or_else(|_| seconds(0.0))
+    };
+
+    let mut segment_start = first_frame.time.clone();
+    let mut part_start = first_frame.time.clone();
+    let mut part_independent = true;
+    // the time between the last two video frames, which the next frame is
+    // expected after
+    let mut last_video = first_frame.time.clone();
+    let mut frame_interval = Duration::ZERO;
+
+    write
+        .write(first_frame)
+        .await
+        .context("writing first frame")?;
+    playlist.write().push_chunk(buffer.take());
+
+    loop {
+        let frame = read.read().await.context("reading frame")?;
+
+        if frame.stream.is_video() {
+            let keyframe = frame.is_keyframe();
+            let segment_duration: Duration = (&frame.time - &segment_start).into();
+            let part_duration: Duration = (&frame.time - &part_start).into();
+
+            // parts end before the frame which would make them too long
+            let cut_segment = keyframe && segment_duration >= config.segment_duration;
+            let cut_part = config
+                .part_duration
+                .is_some_and(|target| part_duration + frame_interval > target);
+
+            if cut_segment || cut_part {
+                let mut playlist = playlist.write();
+                playlist.push_part(part_duration.as_secs_f64(), part_independent);
+
+                if cut_segment {
+                    trace!("Cutting a {:?} HLS segment", segment_duration);
+
+                    let date = date_of(&segment_start);
+                    playlist.push_segment(segment_start, date, config.window);
+                    segment_start = frame.time.clone();
+                }
+
+                part_start = frame.time.clone();
+                part_independent = keyframe;
+            }
+
+            frame_interval = (&frame.time - &last_video).into();
+            last_video = frame.time.clone();
+        }
+
+        write.write(frame).await.context("writing frame")?;
+        playlist.write().push_chunk(buffer.take());
+    }
+}
+
+#[test]
+fn reconnects_continue_the_playlist() {
+    let config = HlsConfig {
+        segment_duration: Duration::from_secs(2),
+        part_duration: None,
+        window: 6,
+        chunked: false,
+    };
+    let started = DateTime::parse_from_rfc3339("2022-01-01T00:00:00Z")
+        .unwrap()
+        .with_timezone(&Utc);
+    let time = |pts| MediaTime {
+        pts,
+        dts: None,
+        timebase: Fraction::new(1, 1000),
+    };
+
+    let mut playlist = HlsPlaylist::default();
+    playlist.start_session(Bytes::from_static(b"first"), started, &config);
+    for i in 0..2 {
+        playlist.push_chunk(Bytes::from_static(b"segment"));
+        playlist.push_part(2.0, true);
+        playlist.push_segment(time(i * 2000), started + seconds(i as f64 * 2.0), 6);
+    }
+
+    // the publisher was away for 5 seconds after the last segment
+    let reconnected = started + seconds(9.0);
+    playlist.start_session(Bytes::from_static(b"second"), reconnected, &config);
+    playlist.push_chunk(Bytes::from_static(b"segment"));
+    playlist.push_part(2.0, true);
+    playlist.push_segment(time(0), reconnected, 6);
+
+    let text = playlist.playlist(None, false).unwrap();
+    let lines: Vec<&str> = text
+        .lines()
+        .skip_while(|l| !l.starts_with("#EXT-X-MAP"))
+        .collect();
+    assert_eq!(
+        lines,
+        [
+            "#EXT-X-MAP:URI=\"init.mp4?session=0\"",
+            "#EXT-X-PROGRAM-DATE-TIME:2022-01-01T00:00:00.000Z",
+            "#EXTINF:2.000,",
+            "segments/0",
+            "#EXT-X-PROGRAM-DATE-TIME:2022-01-01T00:00:02.000Z",
+            "#EXTINF:2.000,",
+            "segments/1",
+            "#EXT-X-DISCONTINUITY",
+            "#EXT-X-MAP:URI=\"init.mp4?session=1\"",
+            "#EXT-X-PROGRAM-DATE-TIME:2022-01-01T00:00:04.000Z",
+            "#EXT-X-GAP",
+            "#EXTINF:1.000,",
+            "segments/2",
+            "#EXT-X-PROGRAM-DATE-TIME:2022-01-01T00:00:05.000Z",
+            "#EXT-X-GAP",
+            "#EXTINF:2.000,",
+            "segments/3",
+            "#EXT-X-PROGRAM-DATE-TIME:2022-01-01T00:00:07.000Z",
+            "#EXT-X-GAP",
+            "#EXTINF:2.000,",
+            "segments/4",
+            "#EXT-X-PROGRAM-DATE-TIME:2022-01-01T00:00:09.000Z",
+            "#EXTINF:2.000,",
+            "segments/5",
+        ]
+    );
+
+    assert!(playlist.segment(3).is_none());
+    assert!(playlist.segment(5).is_some());
+    assert_eq!(playlist.session_init(Some(0)).unwrap(), "first");
+    assert_eq!(playlist.init().unwrap(), "second");
+
+    // the discontinuity is counted once it leaves the window
+    for i in 1..6 {
+        playlist.push_chunk(Bytes::from_static(b"segment"));
+        playlist.push_part(2.0, true);
+        playlist.push_segment(time(i * 2000), reconnected + seconds(i as f64 * 2.0), 6);
+    }
+    let text = playlist.playlist(None, false).unwrap();
+    assert!(text.contains("#EXT-X-DISCONTINUITY-SEQUENCE:1\n"));
+    assert!(!text.contains("#EXT-X-DISCONTINUITY\n"));
+    assert!(playlist.session_init(Some(0)).is_none());
+}
+
+#[test]
+fn target_duration_stays_fixed() {
+    let config = HlsConfig {
+        segment_duration: Duration::from_millis(1500),
+        part_duration: None,
+        window: 2,
+        chunked: false,
+    };
+    let started = Utc::now();
+    let time = |pts| MediaTime {
+        pts,
+        dts: None,
+        timebase: Fraction::new(1, 1000),
+    };
+    let target_duration = |playlist: &HlsPlaylist| {
+        let text = playlist.playlist(None, false).unwrap();
+        text.lines()
+            .find_map(|l| l.strip_prefix("#EXT-X-TARGETDURATION:"))
+            .map(str::to_owned)
+    };
+
+    let mut playlist = HlsPlaylist::default();
+    playlist.start_session(Bytes::from_static(b"init"), started, &config);
+
+    // the configured duration is used even when segments are shorter
+    playlist.push_chunk(Bytes::from_static(b"segment"));
+    playlist.push_part(0.5, true);
+    playlist.push_segment(time(0), started, 2);
+    assert_eq!(target_duration(&playlist).as_deref(), Some("2"));
+
+    // a longer segment raises it, and it doesn't drop once that segment
+    // has left the window
+    playlist.push_chunk(Bytes::from_static(b"segment"));
+    playlist.push_part(4.2, true);
+    playlist.push_segment(time(500), started + seconds(0.5), 2);
+    assert_eq!(target_duration(&playlist).as_deref(), Some("5"));
+
+    for i in 0..2 {
+        playlist.push_chunk(Bytes::from_static(b"segment"));
+        playlist.push_part(1.5, true);
+        playlist.push_segment(time(4700 + i * 1500), started + seconds(4.7), 2);
+    }
+    assert_eq!(target_duration(&playlist).as_deref(), Some("5"));
+}
@@ -5,14 +5,167 @@ use sh_media::{BitstreamFramerFilter, BitstreamFraming};
 use anyhow::Context;
 use axum::extract::ws::{Message, WebSocket};
 use futures::{stream::SplitSink, SinkExt, StreamExt};
+use sh_protocol::{
+    clamp_rate, CaptionCue, CaptionMessage, ClientMessage, OfferMessage, ResumeMessage,
+    ServerMessage, RATE_MESSAGE_PREFIX,
+};
+use tracing::*;
+
+pub use sh_protocol::{ReconnectHints, RenditionOffer, ResumeToken};
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// How long a viewer has to pick one of the renditions it was offered.
+const NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often viewers are pinged to measure the round trip time to them.
+const PING_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How delivery to a viewer is going, which is updated while it is sent
+/// media and can be read from elsewhere.
+#[derive(Debug, Default)]
+pub struct DeliveryStats {
+    bytes_sent: AtomicU64,
+    /// Frames the viewer was not sent while it waited for a keyframe.
+    dropped_frames: AtomicU64,
+    /// The round trip time of the latest ping in microseconds, or 0 before
+    /// the first pong.
+    rtt_micros: AtomicU64,
+    join: Mutex<JoinClock>,
+}
+
+/// How long a viewer took to reach each stage of starting to play, from
+/// when it connected.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JoinTimes {
+    /// When the init segment was sent.
+    pub init_segment: Option<Duration>,
+    /// When the keyframe the viewer starts at was read from the stream.
+    pub first_keyframe: Option<Duration>,
+    /// When the first fragment was sent, which the viewer can render.
+    pub first_fragment: Option<Duration>,
+}
+
+#[derive(Debug, Default)]
+struct JoinClock {
+    connected: Option<Instant>,
+    times: JoinTimes,
+}
+
+impl JoinClock {
+    fn elapsed(&self) -> Option<Duration> {
+        self.connected.map(|connected| connected.elapsed())
+    }
+}
+
+impl DeliveryStats {
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped_frames.load(Ordering::Relaxed)
+    }
+
+    /// The round trip time to the viewer, once it answered a ping.
+    pub fn rtt(&self) -> Option<Duration> {
+        match self.rtt_micros.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+
+    /// Sets when the viewer connected, which its join times are measured
+    /// from. Nothing is measured until it is set.
+    pub fn set_connected(&self, at: Instant) {
+        self.join.lock().unwrap().connected = Some(at);
+    }
+
+    pub fn join_times(&self) -> JoinTimes {
+        self.join.lock().unwrap().times
+    }
+
+    fn reached_keyframe(&self) {
+        let mut join = self.join.lock().unwrap();
+        join.times.first_keyframe = join.elapsed();
+    }
+
+    /// Counts a sent segment, the first of which is the init segment and
+    /// the second the first fragment.
+    fn sent_segment(&self) {
+        let mut join = self.join.lock().unwrap();
+
+        if join.times.init_segment.is_none() {
+            join.times.init_segment = join.elapsed();
+        } else if join.times.first_fragment.is_none() {
+            join.times.first_fragment = join.elapsed();
+        }
+    }
+}
 
 struct WebSocketWriteFilter {
     sink: SplitSink<WebSocket, Message>,
+    /// Text messages which are sent before the next binary message.
+    pending: Arc<Mutex<Vec<String>>>,
+    stats: Arc<DeliveryStats>,
+    /// What ping payloads are relative to, which is the time in
+    /// microseconds since then.
+    epoch: Instant,
+    last_ping: Option<Instant>,
 }
 
 impl WebSocketWriteFilter {
-    pub fn new(sink: SplitSink<WebSocket, Message>) -> Self {
-        Self { sink }
+    pub fn new(
+        sink: SplitSink<WebSocket, Message>,
+        pending: Arc<Mutex<Vec<String>>>,
+        stats: Arc<DeliveryStats>,
+        epoch: Instant,
+    ) -> Self {
+        Self {
+            sink,
+            pending,
+            stats,
+            epoch,
+            last_ping: None,
+        }
+    }
+
+    async fn ping(&mut self) -> anyhow::Result<()> {
+        let now = Instant::now();
+        if self
+            .last_ping
+            .is_some_and(|last| now - last < PING_INTERVAL)
+        {
+            return Ok(());
+        }
+
+        let sent = (now - self.epoch).as_micros() as u64;
+        self.sink
+            .send(Message::Ping(sent.to_be_bytes().to_vec()))
+            .await?;
+        self.last_ping = Some(now);
+
+        Ok(())
+    }
+}
+
+/// Measures the round trip time of a ping from the pong a viewer answered
+/// it with.
+fn record_pong(stats: &DeliveryStats, epoch: Instant, payload: &[u8]) {
+    let sent = match <[u8; 8]>::try_from(payload) {
+        Ok(sent) => Duration::from_micros(u64::from_be_bytes(sent)),
+        Err(_) => return,
+    };
+
+    if let Some(rtt) = epoch.elapsed().checked_sub(sent) {
+        let micros = (rtt.as_micros() as u64).max(1);
+        stats.rtt_micros.store(micros, Ordering::Relaxed);
     }
 }
 
@@ -23,14 +176,57 @@ impl ByteWriteFilter2 for WebSocketWriteFilter {
     }
 
     async fn write(&mut self, bytes: bytes::Bytes) -> anyhow::Result<()> {
+        self.ping().await?;
+
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        for text in pending {
+            self.sink.send(Message::Text(text)).await?;
+        }
+
+        let len = bytes.len() as u64;
         self.sink.send(Message::Binary(bytes.to_vec())).await?;
         self.sink.flush().await?;
+        self.stats.bytes_sent.fetch_add(len, Ordering::Relaxed);
+        self.stats.sent_segment();
 
         Ok(())
     }
 }
 
-fn get_codec_from_stream(stream: &Stream) -> anyhow::Result<rfc6381_codec::Codec> {
+/// Reads the binary messages of a WebSocket, e.g. fragmented MP4 from a
+/// browser which publishes with `MediaRecorder`.
+///
+/// Other messages are ignored, and the stream ends when the socket closes.
+pub struct WebSocketReadFilter {
+    socket: WebSocket,
+}
+
+impl WebSocketReadFilter {
+    pub fn new(socket: WebSocket) -> Self {
+        Self { socket }
+    }
+}
+
+#[async_trait::async_trait]
+impl ByteReadFilter for WebSocketReadFilter {
+    async fn start(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn read(&mut self) -> anyhow::Result<bytes::Bytes> {
+        loop {
+            match self.socket.recv().await {
+                Some(Ok(Message::Binary(bytes))) => return Ok(bytes.into()),
+                Some(Ok(Message::Close(_))) | None => return Ok(bytes::Bytes::new()),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// Returns the RFC 6381 codec string of a stream.
+fn get_codec_from_stream(stream: &Stream) -> anyhow::Result<String> {
     use mpeg4_audio_const::AudioObjectType;
     use rfc6381_codec::{Codec, Mp4a};
 
@@ -45,7 +241,28 @@ fn get_codec_from_stream(stream: &Stream) -> anyhow::Result<rfc6381_codec::Codec
             *profile_indication,
             *profile_compatibility,
             *level_indication,
-        ))
+        )
+        .to_string())
+    } else if let Some(VideoCodecSpecificInfo::Vp9 {
+        profile,
+        level,
+        bit_depth,
+        ..
+    }) = stream.codec.video().map(|v| &v.extra)
+    {
+        Ok(format!("vp09.{:02}.{:02}.{:02}", profile, level, bit_depth))
+    } else if let Some(VideoCodecSpecificInfo::H265 { config, .. }) =
+        stream.codec.video().map(|v| &v.extra)
+    {
+        Ok(config.codec_string())
+    } else if let Some(VideoCodecSpecificInfo::Av1 { config }) =
+        stream.codec.video().map(|v| &v.extra)
+    {
+        Ok(config.codec_string())
+    } else if stream.is_opus() {
+        Ok("opus".to_string())
+    } else if stream.is_mp3() {
+        Ok("mp3".to_string())
     } else if let Some(audio_specific) = stream
         .codec
         .audio()
@@ -54,49 +271,246 @@ fn get_codec_from_stream(stream: &Stream) -> anyhow::Result<rfc6381_codec::Codec
         let audio_object_type = audio_specific[0] >> 3;
         Ok(Codec::Mp4a(Mp4a::Mpeg4Audio {
             audio_object_type: Some(AudioObjectType::try_from(audio_object_type).unwrap()),
-        }))
+        })
+        .to_string())
     } else {
-        unimplemented!()
+        anyhow::bail!("No MSE codec for {}", stream.codec.name)
+    }
+}
+
+/// Returns the codecs of the video and audio of a stream, as in the
+/// `codecs` parameter of an MSE source buffer.
+pub fn codec_string(streams: &[Stream]) -> anyhow::Result<String> {
+    let video = streams
+        .iter()
+        .find(|s| s.is_video())
+        .context("stream has no video")?;
+    let video_codec = get_codec_from_stream(video)?;
+
+    // streams published without audio, e.g. over WHIP, only have video
+    match streams.iter().find(|s| s.is_audio()) {
+        Some(audio) => Ok(format!("{},{}", video_codec, get_codec_from_stream(audio)?)),
+        None => Ok(video_codec),
+    }
+}
+
+/// Offers the renditions of a stream to a viewer, see [`OfferMessage`], and
+/// returns the name of the one it picked.
+///
+/// `None` is returned when the viewer can't decode any of them, and has to
+/// play the stream some other way.
+pub async fn negotiate_rendition(
+    socket: &mut WebSocket,
+    offers: &[RenditionOffer],
+) -> anyhow::Result<Option<String>> {
+    let offer = ServerMessage::Offer(OfferMessage {
+        renditions: offers.to_vec(),
+    });
+    socket.send(Message::Text(offer.to_text()?)).await?;
+
+    let answer = tokio::time::timeout(NEGOTIATION_TIMEOUT, async {
+        loop {
+            match socket.recv().await {
+                Some(Ok(Message::Text(text))) => {
+                    if let Some(ClientMessage::Rendition(name)) = ClientMessage::parse(&text) {
+                        return Ok(name);
+                    }
+                }
+                Some(Ok(Message::Close(_))) | None => {
+                    anyhow::bail!("viewer left before picking a rendition")
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+            }
+        }
+    })
+    .await
+    .context("viewer did not pick a rendition in time")??;
+
+    if let Some(name) = &answer {
+        if !offers.iter().any(|offer| &offer.name == name) {
+            anyhow::bail!("viewer picked unknown rendition '{}'", name);
+        }
+    }
+
+    Ok(answer)
+}
+
+/// Paces frames by when they were received, so frames which a viewer is
+/// behind on are sent at a rate relative to realtime.
+///
+/// Live frames are never held back, since they can't arrive any earlier
+/// than their pace.
+#[derive(Default)]
+struct Pacer {
+    /// When a frame was sent, and when that frame was received.
+    anchor: Option<(Instant, Instant)>,
+    rate: f64,
+}
+
+impl Pacer {
+    async fn wait(&mut self, frame: &Frame, rate: f64) {
+        let now = Instant::now();
+
+        let (sent, received) = match self.anchor {
+            Some(anchor) if self.rate == rate => anchor,
+            _ => {
+                self.anchor = Some((now, frame.received));
+                self.rate = rate;
+                return;
+            }
+        };
+
+        let elapsed = frame.received.saturating_duration_since(received);
+        let due = sent + Duration::from_secs_f64(elapsed.as_secs_f64() / rate);
+
+        if due > now {
+            tokio::time::sleep_until(due.into()).await;
+        } else {
+            // late frames start a new pace, so the viewer doesn't get a
+            // burst of frames after a stall
+            self.anchor = Some((now, frame.received));
+        }
     }
 }
 
+/// Sends the frames of `read` to a viewer over a WebSocket.
+///
+/// Frames are sent at most `rate` times faster than they were received,
+/// which lets a viewer that started behind live catch up to it. The viewer
+/// can change the rate with [`ClientMessage::Rate`].
+///
+/// A viewer which continues with a `resume` token is sent timestamps which
+/// follow on from its earlier session of `session`, so `read` has to start
+/// at the keyframe of the token.
+///
+/// How delivery goes is kept in `stats`, with the round trip time measured
+/// by pinging the viewer.
+///
+/// The closed captions of the stream are sent as text messages, along with
+/// the fragments of the frames they were found in.
 pub async fn start_websocket_filters(
     socket: WebSocket,
     read: &mut (dyn FrameReadFilter + Unpin + Send),
+    hints: Option<&ReconnectHints>,
+    rate: f64,
+    session: i32,
+    resume: Option<ResumeToken>,
+    stats: Arc<DeliveryStats>,
 ) -> anyhow::Result<()> {
     let streams = read.start().await?;
-    let video_codec = get_codec_from_stream(streams.iter().find(|s| s.is_video()).unwrap())?;
-
-    let audio_codec = get_codec_from_stream(streams.iter().find(|s| s.is_audio()).unwrap())?;
+    let codecs = codec_string(&streams)?;
 
     let (mut sender, mut receiver) = socket.split();
-    sender
-        .send(Message::Text(format!("{},{}", video_codec, audio_codec)))
-        .await?;
+    let codecs = ServerMessage::Codecs(codecs);
+    sender.send(Message::Text(codecs.to_text()?)).await?;
+
+    if let Some(hints) = hints {
+        let hints = ServerMessage::ReconnectHints(hints.clone());
+        sender.send(Message::Text(hints.to_text()?)).await?;
+    }
+
+    let first_frame = wait_for_sync_frame(read)
+        .await
+        .context("waiting for first sync frame")?;
+    stats.reached_keyframe();
+
+    // a resumed viewer continues the timeline of its earlier session
+    let start = MediaTime {
+        pts: resume.map_or(first_frame.time.pts, |r| r.start),
+        dts: None,
+        timebase: first_frame.time.timebase,
+    };
+    let resume_message = |keyframe: &Frame| {
+        let token = ResumeToken {
+            session,
+            start: start.pts,
+            keyframe: keyframe.time.pts,
+        };
+
+        ServerMessage::Resume(ResumeMessage {
+            resume_token: token.to_string(),
+        })
+        .to_text()
+    };
+    let caption_messages = |frame: &Frame| -> anyhow::Result<Vec<String>> {
+        let captions = match &frame.metadata {
+            Some(metadata) => &metadata.captions,
+            None => return Ok(Vec::new()),
+        };
+
+        let timebase = frame.time.timebase;
+        captions
+            .iter()
+            .map(|caption| {
+                let ticks = caption.pts.saturating_sub(start.pts) as f64;
 
-    let output_filter = WebSocketWriteFilter::new(sender);
-    let fmp4_filter = Box::new(FragmentedMp4WriteFilter::new(Box::new(output_filter)));
+                ServerMessage::Caption(CaptionMessage {
+                    caption: CaptionCue {
+                        time: ticks * timebase.numerator as f64 / timebase.denominator as f64,
+                        text: caption.text.clone(),
+                    },
+                })
+                .to_text()
+            })
+            .collect()
+    };
+
+    let epoch = Instant::now();
+    let mut first_messages = vec![resume_message(&first_frame)?];
+    first_messages.extend(caption_messages(&first_frame)?);
+    let pending = Arc::new(Mutex::new(first_messages));
+    let output_filter = WebSocketWriteFilter::new(sender, pending.clone(), stats.clone(), epoch);
+    let fmp4_filter = Box::new(FragmentedMp4WriteFilter::aligned_at(
+        Box::new(output_filter),
+        start.clone(),
+    ));
     let write_analyzer = Box::new(FrameAnalyzerFilter::write(fmp4_filter));
     let mut write = Box::new(BitstreamFramerFilter::new(
         BitstreamFraming::FourByteLength,
         write_analyzer,
     ));
 
-    let first_frame = wait_for_sync_frame(read)
-        .await
-        .context("waiting for first sync frame")?;
     write.start(streams).await.context("starting to write")?;
     write
         .write(first_frame)
         .await
         .context("writing first frame")?;
 
+    // whether the viewer asked us to stop sending, and whether a keyframe
+    // is needed before sending again
+    let paused = AtomicBool::new(false);
+    let needs_keyframe = AtomicBool::new(false);
+    let rate = AtomicU64::new(clamp_rate(rate).unwrap_or(1.0).to_bits());
+    let mut pacer = Pacer::default();
+
     tokio::select! {
         res = async {
             loop {
                 let frame = read.read()
                     .await
                     .context("reading frame")?;
+
+                // frames are still read while paused so the viewer doesn't
+                // fall behind the live queue
+                if paused.load(Ordering::Relaxed) {
+                    continue;
+                }
+                if needs_keyframe.load(Ordering::Relaxed) {
+                    if !(frame.stream.is_video() && frame.is_keyframe()) {
+                        stats.dropped_frames.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                    needs_keyframe.store(false, Ordering::Relaxed);
+                }
+
+                pacer.wait(&frame, f64::from_bits(rate.load(Ordering::Relaxed))).await;
+
+                if frame.stream.is_video() && frame.is_keyframe() {
+                    pending.lock().unwrap().push(resume_message(&frame)?);
+                }
+                pending.lock().unwrap().extend(caption_messages(&frame)?);
+
                 write.write(frame)
                     .await
                     .context("writing frame")?;
@@ -104,8 +518,24 @@ pub async fn start_websocket_filters(
         } => res,
         res = async {
             loop {
-                if let Some(msg) = receiver.next().await {
-                    break Err(anyhow::anyhow!("WebSocket closed, got message: {:?}", msg));
+                match receiver.next().await {
+                    Some(Ok(Message::Text(text))) => match ClientMessage::parse(&text) {
+                        Some(ClientMessage::Pause) => paused.store(true, Ordering::Relaxed),
+                        Some(ClientMessage::Resume) => {
+                            needs_keyframe.store(true, Ordering::Relaxed);
+                            paused.store(false, Ordering::Relaxed);
+                        }
+                        Some(ClientMessage::Rate(new_rate)) => {
+                            rate.store(new_rate.to_bits(), Ordering::Relaxed)
+                        }
+                        None if text.starts_with(RATE_MESSAGE_PREFIX) => {
+                            debug!("Ignoring invalid rate message: {}", text)
+                        }
+                        _ => break Err(anyhow::anyhow!("WebSocket closed, got message: {:?}", text)),
+                    },
+                    Some(Ok(Message::Pong(payload))) => record_pong(&stats, epoch, &payload),
+                    Some(Ok(Message::Ping(_))) => {}
+                    msg => break Err(anyhow::anyhow!("WebSocket closed, got message: {:?}", msg)),
                 }
             }
         } => res
@@ -0,0 +1,289 @@
+use bytes::Bytes;
+use h264_reader::nal::UnitType;
+use sh_media::{
+    frame_nal_units, nut_header, parse_bitstream, wait_for_sync_frame, BitstreamFraming,
+    CodecTypeInfo, Fraction, Frame, FrameReadFilter, MediaTime, Stream, VideoCodecSpecificInfo,
+};
+use tokio::sync::watch;
+use tracing::*;
+use webrtc::{
+    api::{
+        interceptor_registry::register_default_interceptors,
+        media_engine::{MediaEngine, MIME_TYPE_H264},
+        APIBuilder, API,
+    },
+    interceptor::registry::Registry,
+    media::Sample,
+    peer_connection::{
+        configuration::RTCConfiguration, peer_connection_state::RTCPeerConnectionState,
+        sdp::session_description::RTCSessionDescription, RTCPeerConnection,
+    },
+    rtp_transceiver::{
+        rtp_codec::{RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType},
+        RTCPFeedback,
+    },
+    track::track_local::{track_local_static_sample::TrackLocalStaticSample, TrackLocal},
+};
+
+use std::{sync::Arc, time::Duration};
+
+const VIDEO_CLOCK_RATE: u32 = 90000;
+
+/// A WebRTC session of a viewer, set up from the SDP offer of a WHEP
+/// request.
+pub struct WhepSession {
+    peer: Arc<RTCPeerConnection>,
+    video: Arc<TrackLocalStaticSample>,
+    state: watch::Receiver<RTCPeerConnectionState>,
+}
+
+impl WhepSession {
+    /// Answers the SDP offer of a viewer of a stream with `streams`,
+    /// returning the session and the SDP answer.
+    ///
+    /// Only the H.264 video is sent. Browsers can't decode AAC over WebRTC,
+    /// and the audio would have to be transcoded to Opus first.
+    pub async fn accept(offer: String, streams: &[Stream]) -> anyhow::Result<(Self, String)> {
+        let codec = streams
+            .iter()
+            .find_map(h264_capability)
+            .ok_or_else(|| anyhow::anyhow!("Stream has no H.264 video"))?;
+
+        let peer = Arc::new(
+            build_api(&codec)?
+                .new_peer_connection(RTCConfiguration::default())
+                .await?,
+        );
+
+        let (state, state_recv) = watch::channel(RTCPeerConnectionState::New);
+        peer.on_peer_connection_state_change(Box::new(move |new_state| {
+            debug!("WHEP peer connection is {}", new_state);
+            let _ = state.send(new_state);
+
+            Box::pin(async {})
+        }))
+        .await;
+
+        let video = Arc::new(TrackLocalStaticSample::new(
+            codec,
+            "video".into(),
+            "streamhead".into(),
+        ));
+        let sender = peer
+            .add_track(video.clone() as Arc<dyn TrackLocal + Send + Sync>)
+            .await?;
+
+        // RTCP from the viewer has to be read for the interceptors, e.g. to
+        // answer NACKs
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1500];
+            while sender.read(&mut buf).await.is_ok() {}
+        });
+
+        peer.set_remote_description(RTCSessionDescription::offer(offer)?)
+            .await?;
+        let answer = peer.create_answer(None).await?;
+
+        // the answer is sent once every ICE candidate is known, WHEP clients
+        // don't have to support trickling candidates
+        let mut gathered = peer.gathering_complete_promise().await;
+        peer.set_local_description(answer).await?;
+        let _ = gathered.recv().await;
+
+        let answer = peer
+            .local_description()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("WHEP session has no local description"))?;
+
+        let session = WhepSession {
+            peer,
+            video,
+            state: state_recv,
+        };
+
+        Ok((session, answer.sdp))
+    }
+
+    /// Waits until the viewer is connected, failing if it never connects.
+    pub async fn connected(&self) -> anyhow::Result<()> {
+        let mut state = self.state.clone();
+
+        loop {
+            match *state.borrow() {
+                RTCPeerConnectionState::Connected => return Ok(()),
+                RTCPeerConnectionState::Disconnected
+                | RTCPeerConnectionState::Failed
+                | RTCPeerConnectionState::Closed => {
+                    anyhow::bail!("WHEP viewer did not connect")
+                }
+                _ => {}
+            }
+
+            state.changed().await?;
+        }
+    }
+
+    /// Sends the video of `read` from its next keyframe, until the viewer
+    /// leaves or the stream ends.
+    pub async fn send(
+        &self,
+        read: &mut (dyn FrameReadFilter + Send + Unpin),
+    ) -> anyhow::Result<()> {
+        let mut state = self.state.clone();
+
+        read.start().await?;
+
+        let mut writer = SampleWriter::default();
+        writer
+            .write(&self.video, wait_for_sync_frame(read).await?)
+            .await?;
+
+        loop {
+            tokio::select! {
+                frame = read.read() => {
+                    let frame = frame?;
+                    if frame.stream.is_video() {
+                        writer.write(&self.video, frame).await?;
+                    }
+                }
+                changed = state.changed() => {
+                    changed?;
+
+                    if matches!(
+                        *state.borrow(),
+                        RTCPeerConnectionState::Disconnected
+                            | RTCPeerConnectionState::Failed
+                            | RTCPeerConnectionState::Closed
+                    ) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Ends the session, which stops sending to the viewer.
+    pub async fn close(&self) {
+        if let Err(e) = self.peer.close().await {
+            warn!("Failed to close WHEP session: {:?}", e);
+        }
+    }
+}
+
+/// Writes video frames as samples, each held back until the next one so its
+/// duration is known.
+#[derive(Default)]
+struct SampleWriter {
+    pending: Option<(MediaTime, Bytes)>,
+}
+
+impl SampleWriter {
+    async fn write(&mut self, track: &TrackLocalStaticSample, frame: Frame) -> anyhow::Result<()> {
+        let time = frame.time.in_base(Fraction::new(1, VIDEO_CLOCK_RATE));
+        let data = annex_b(&frame);
+
+        if let Some((last, data)) = self.pending.replace((time.clone(), data)) {
+            let ticks = time
+                .dts
+                .unwrap_or(time.pts)
+                .saturating_sub(last.dts.unwrap_or(last.pts));
+
+            track
+                .write_sample(&Sample {
+                    data,
+                    duration: Duration::from_secs_f64(ticks as f64 / VIDEO_CLOCK_RATE as f64),
+                    ..Default::default()
+                })
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Converts a video frame to Annex B, with the parameter sets in front of
+/// keyframes which don't have them, since viewers can't be sent them out of
+/// band.
+fn annex_b(frame: &Frame) -> Bytes {
+    let framing = frame
+        .stream
+        .bitstream_format()
+        .unwrap_or(BitstreamFraming::FourByteLength);
+    let units = parse_bitstream(frame.buffer.clone(), framing);
+
+    let has_sps = units
+        .iter()
+        .any(|nal| nut_header(nal) == Some(UnitType::SeqParameterSet));
+
+    let mut nal_units = Vec::with_capacity(units.len() + 2);
+    if frame.is_keyframe() && !has_sps {
+        if let Some(parameter_sets) = frame.stream.parameter_sets() {
+            nal_units.extend(parse_bitstream(
+                parameter_sets.into(),
+                BitstreamFraming::FourByteLength,
+            ));
+        }
+    }
+    nal_units.extend(units.into_iter().filter(|nal| !nal.is_empty()));
+
+    frame_nal_units(&nal_units[..], BitstreamFraming::FourByteStartCode).freeze()
+}
+
+/// Describes the H.264 video of a stream as an RTP codec, with the profile
+/// and level of the stream so the viewer's decoder can check them.
+fn h264_capability(stream: &Stream) -> Option<RTCRtpCodecCapability> {
+    let video = match &stream.codec.properties {
+        CodecTypeInfo::Video(video) => video,
+        _ => return None,
+    };
+    let (profile_indication, profile_compatibility, level_indication) = match &video.extra {
+        VideoCodecSpecificInfo::H264 {
+            profile_indication,
+            profile_compatibility,
+            level_indication,
+            ..
+        } => (profile_indication, profile_compatibility, level_indication),
+        _ => return None,
+    };
+
+    Some(RTCRtpCodecCapability {
+        mime_type: MIME_TYPE_H264.into(),
+        clock_rate: VIDEO_CLOCK_RATE,
+        channels: 0,
+        sdp_fmtp_line: format!(
+            "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id={:02x}{:02x}{:02x}",
+            profile_indication, profile_compatibility, level_indication
+        ),
+        rtcp_feedback: vec![
+            RTCPFeedback {
+                typ: "nack".into(),
+                parameter: "".into(),
+            },
+            RTCPFeedback {
+                typ: "nack".into(),
+                parameter: "pli".into(),
+            },
+        ],
+    })
+}
+
+/// Builds a WebRTC API which only negotiates the video codec of the stream.
+fn build_api(codec: &RTCRtpCodecCapability) -> anyhow::Result<API> {
+    let mut media = MediaEngine::default();
+
+    media.register_codec(
+        RTCRtpCodecParameters {
+            capability: codec.clone(),
+            payload_type: 102,
+            ..Default::default()
+        },
+        RTPCodecType::Video,
+    )?;
+
+    let registry = register_default_interceptors(Registry::new(), &mut media)?;
+
+    Ok(APIBuilder::new()
+        .with_media_engine(media)
+        .with_interceptor_registry(registry)
+        .build())
+}
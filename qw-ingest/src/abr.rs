@@ -0,0 +1,155 @@
+use sh_media::{FrameReadFilter, FrameWriteFilter, MediaFrameQueue, Stream};
+use sh_transcode::TranscodeOptions;
+use tracing::*;
+
+use std::sync::Arc;
+
+use crate::AppData;
+
+/// The most frames from one keyframe to the next in renditions, which
+/// otherwise have their keyframes where the stream has them.
+pub(crate) const MAX_KEYFRAME_INTERVAL: u32 = 300;
+
+/// A quality which streams are transcoded to, next to the source.
+#[derive(Debug, Clone)]
+pub struct Rung {
+    /// The name of the rendition, like `720p`.
+    pub name: String,
+    pub height: u32,
+    pub options: TranscodeOptions,
+}
+
+/// Parses a ladder like `1080p:6000,720p:3000,480p:1200`, of the heights of
+/// renditions along with their bitrates in kbit/s.
+pub fn parse_ladder(ladder: &str) -> anyhow::Result<Vec<Rung>> {
+    ladder
+        .split(',')
+        .map(str::trim)
+        .filter(|rung| !rung.is_empty())
+        .map(|rung| {
+            let (name, kbps) = rung
+                .split_once(':')
+                .ok_or_else(|| anyhow::anyhow!("{} is not like 720p:3000", rung))?;
+            let height = name.trim_end_matches('p').parse()?;
+            let kbps: usize = kbps.parse()?;
+
+            Ok(Rung {
+                name: name.to_string(),
+                height,
+                options: TranscodeOptions {
+                    width: 0,
+                    height,
+                    bitrate: kbps * 1000,
+                    keyframe_interval: MAX_KEYFRAME_INTERVAL,
+                },
+            })
+        })
+        .collect()
+}
+
+/// Transcodes a stream to the rungs of the ABR ladder below its resolution,
+/// as renditions of the stream which viewers can pick and which are served
+/// as variants of its HLS and DASH manifests.
+///
+/// Renditions are removed from the stream when their transcoding fails,
+/// and end along with the stream.
+pub fn spawn_ladder(stream_session_id: i32, source: &[Stream], data: &Arc<AppData>) {
+    let height = match source.iter().find_map(|s| s.codec.video()) {
+        Some(video) => video.height,
+        None => return,
+    };
+
+    for rung in data.abr_ladder.iter().filter(|rung| rung.height < height) {
+        let rung = rung.clone();
+        let data = data.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = transcode_rendition(stream_session_id, &rung, &data).await {
+                debug!("Rendition {} stopped: {:?}", rung.name, e);
+            }
+
+            let mut repo = data.stream_repo.write();
+            if let Some(state) = repo.streams.get_mut(&stream_session_id) {
+                state.renditions.remove(&rung.name);
+                state.rendition_hls.remove(&rung.name);
+            }
+        });
+    }
+}
+
+async fn transcode_rendition(
+    stream_session_id: i32,
+    rung: &Rung,
+    data: &AppData,
+) -> anyhow::Result<()> {
+    let read = {
+        let repo = data.stream_repo.read();
+
+        match repo.streams.get(&stream_session_id) {
+            Some(state) if state.is_live() => state.queue.get_receiver_from_keyframe(),
+            _ => return Ok(()),
+        }
+    };
+
+    let mut transcoder = transcoder(Box::new(read), rung.options.clone())?;
+    let streams = transcoder.start().await?;
+
+    let mut queue = match data.dvr_window {
+        Some(window) => MediaFrameQueue::with_dvr_window(window),
+        None => MediaFrameQueue::new(),
+    };
+    queue.start(streams).await?;
+
+    {
+        let mut repo = data.stream_repo.write();
+        let name = match repo.streams.get(&stream_session_id) {
+            Some(state) => state.name.clone(),
+            None => return Ok(()),
+        };
+        let hls = data
+            .hls
+            .map(|config| (config, repo.hls_playlist(&name, &rung.name)));
+        let state = match repo.streams.get_mut(&stream_session_id) {
+            Some(state) => state,
+            None => return Ok(()),
+        };
+
+        info!("Transcoding '{}' to {}", state.name, rung.name);
+        state.renditions.insert(rung.name.clone(), queue.clone());
+
+        if let Some((config, playlist)) = hls {
+            state
+                .rendition_hls
+                .insert(rung.name.clone(), playlist.clone());
+
+            let read = queue.get_receiver_from_keyframe();
+            let name = format!("{}_{}", state.name, rung.name);
+            tokio::spawn(async move {
+                if let Err(e) = sh_transport_hls::run_hls_output(read, playlist, config).await {
+                    debug!("HLS output of '{}' stopped: {:?}", name, e);
+                }
+            });
+        }
+    }
+
+    loop {
+        let frame = transcoder.read().await?;
+        queue.write(frame).await?;
+    }
+}
+
+#[cfg(feature = "transcode")]
+fn transcoder(
+    read: Box<dyn FrameReadFilter + Send + Unpin>,
+    options: TranscodeOptions,
+) -> anyhow::Result<Box<dyn FrameReadFilter + Send + Unpin>> {
+    Ok(Box::new(sh_transcode::TranscodeFilter::read(read, options)))
+}
+
+#[cfg(not(feature = "transcode"))]
+fn transcoder(
+    _read: Box<dyn FrameReadFilter + Send + Unpin>,
+    _options: TranscodeOptions,
+) -> anyhow::Result<Box<dyn FrameReadFilter + Send + Unpin>> {
+    anyhow::bail!("qwer-ingest was built without the transcode feature")
+}
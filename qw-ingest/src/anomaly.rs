@@ -0,0 +1,281 @@
+use parking_lot::Mutex;
+use qw_proto::stream_info::stream_reply::StreamAnomaly;
+use serde::Serialize;
+use tokio::sync::broadcast::Sender;
+use tracing::*;
+use ts_rs::TS;
+
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use sh_media::{Frame, FrameReadFilter, MediaTime, Stream};
+
+/// How long the bitrate and frame rate are averaged over for each sample.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The weight of a new sample in the moving average and variance of a
+/// metric, so the baseline follows slow changes over about a minute.
+const EWMA_ALPHA: f64 = 0.05;
+
+/// How many samples a baseline needs before samples are compared to it.
+const WARMUP_SAMPLES: u32 = 20;
+
+/// How many standard deviations off the baseline a sample is anomalous at.
+const Z_THRESHOLD: f64 = 4.0;
+
+/// How many standard deviations off the baseline a metric has to come back
+/// within before it can be anomalous again, so a metric which hovers
+/// around the threshold doesn't raise an anomaly every other sample.
+const Z_RECOVERED: f64 = 2.0;
+
+/// The smallest deviation relative to the baseline, since a metric as
+/// steady as the frame rate of most encoders has next to no variance.
+const MIN_RELATIVE_DEVIATION: f64 = 0.05;
+
+/// How long anomalies are shown by the health check.
+const RECENT_ANOMALIES: Duration = Duration::from_secs(300);
+
+/// A time series of an ingest which anomalies are detected in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, TS)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    /// In kbit/s.
+    Bitrate,
+    /// Video frames per second.
+    FrameRate,
+    /// Seconds between video keyframes.
+    KeyframeInterval,
+}
+
+impl Metric {
+    fn name(self) -> &'static str {
+        match self {
+            Metric::Bitrate => "bitrate",
+            Metric::FrameRate => "frame_rate",
+            Metric::KeyframeInterval => "keyframe_interval",
+        }
+    }
+}
+
+/// A sample of a metric which is far off from what the metric has been.
+#[derive(Debug, Clone, Serialize, TS)]
+pub struct Anomaly {
+    pub metric: Metric,
+    pub value: f64,
+    /// The moving average of the metric before the sample.
+    pub expected: f64,
+    pub z_score: f64,
+    /// Unix timestamp of when the anomaly started.
+    #[ts(type = "number")]
+    pub at: u64,
+}
+
+/// The anomalies of a stream over the last few minutes.
+#[derive(Default)]
+pub struct Anomalies {
+    recent: VecDeque<(Instant, Anomaly)>,
+}
+
+impl Anomalies {
+    fn push(&mut self, anomaly: Anomaly) {
+        self.forget_old();
+        self.recent.push_back((Instant::now(), anomaly));
+    }
+
+    pub fn recent(&mut self) -> Vec<Anomaly> {
+        self.forget_old();
+        self.recent.iter().map(|(_, a)| a.clone()).collect()
+    }
+
+    fn forget_old(&mut self) {
+        while self
+            .recent
+            .front()
+            .is_some_and(|(at, _)| at.elapsed() > RECENT_ANOMALIES)
+        {
+            self.recent.pop_front();
+        }
+    }
+}
+
+/// The exponentially weighted moving average and variance of a metric.
+#[derive(Default)]
+struct Baseline {
+    mean: f64,
+    variance: f64,
+    samples: u32,
+    /// Whether the metric is off the baseline, until it comes back.
+    anomalous: bool,
+}
+
+impl Baseline {
+    /// Adds a sample to the baseline, and returns what was expected and the
+    /// z-score of the sample if it starts an anomaly.
+    fn add(&mut self, value: f64) -> Option<(f64, f64)> {
+        if self.samples == 0 {
+            self.mean = value;
+            self.samples = 1;
+            return None;
+        }
+
+        let expected = self.mean;
+        let deviation = self
+            .variance
+            .sqrt()
+            .max(expected.abs() * MIN_RELATIVE_DEVIATION)
+            .max(f64::EPSILON);
+        let z_score = (value - expected) / deviation;
+
+        let diff = value - self.mean;
+        self.mean += EWMA_ALPHA * diff;
+        self.variance = (1.0 - EWMA_ALPHA) * (self.variance + EWMA_ALPHA * diff * diff);
+        self.samples = self.samples.saturating_add(1);
+
+        if self.samples <= WARMUP_SAMPLES {
+            return None;
+        }
+
+        if self.anomalous {
+            self.anomalous = z_score.abs() > Z_RECOVERED;
+            None
+        } else if z_score.abs() > Z_THRESHOLD {
+            self.anomalous = true;
+            Some((expected, z_score))
+        } else {
+            None
+        }
+    }
+}
+
+/// A filter which watches the bitrate, frame rate and keyframe interval of
+/// an ingest, and raises an anomaly when one of them suddenly changes.
+///
+/// Encoders which run out of CPU or bandwidth usually drop frames or
+/// bitrate well before the stream fails, so anomalies are a warning that
+/// comes in time to do something about it.
+pub struct AnomalyFilter {
+    filter: Box<dyn FrameReadFilter + Send + Unpin>,
+    send: Sender<StreamAnomaly>,
+    anomalies: Arc<Mutex<Anomalies>>,
+    stream_id: i32,
+    sample_start: Instant,
+    bytes: u64,
+    video_frames: u32,
+    last_keyframe: Option<MediaTime>,
+    bitrate: Baseline,
+    frame_rate: Baseline,
+    keyframe_interval: Baseline,
+}
+
+impl AnomalyFilter {
+    pub fn new(
+        filter: Box<dyn FrameReadFilter + Send + Unpin>,
+        stream_id: i32,
+        send: Sender<StreamAnomaly>,
+        anomalies: Arc<Mutex<Anomalies>>,
+    ) -> Self {
+        AnomalyFilter {
+            filter,
+            send,
+            anomalies,
+            stream_id,
+            sample_start: Instant::now(),
+            bytes: 0,
+            video_frames: 0,
+            last_keyframe: None,
+            bitrate: Baseline::default(),
+            frame_rate: Baseline::default(),
+            keyframe_interval: Baseline::default(),
+        }
+    }
+
+    fn analyze(&mut self, frame: &Frame) {
+        self.bytes += frame.buffer.len() as u64;
+
+        if frame.stream.is_video() {
+            self.video_frames += 1;
+
+            if frame.is_keyframe() {
+                if let Some(last) = &self.last_keyframe {
+                    let interval = Duration::from(frame.time.since(last)).as_secs_f64();
+                    if interval > 0.0 {
+                        self.sample(Metric::KeyframeInterval, interval);
+                    }
+                }
+                self.last_keyframe = Some(frame.time.clone());
+            }
+        }
+
+        let elapsed = self.sample_start.elapsed();
+        if elapsed >= SAMPLE_INTERVAL {
+            let seconds = elapsed.as_secs_f64();
+            let bitrate = self.bytes as f64 * 8.0 / 1000.0 / seconds;
+            let frame_rate = self.video_frames as f64 / seconds;
+
+            self.sample(Metric::Bitrate, bitrate);
+            self.sample(Metric::FrameRate, frame_rate);
+
+            self.bytes = 0;
+            self.video_frames = 0;
+            self.sample_start = Instant::now();
+        }
+    }
+
+    fn sample(&mut self, metric: Metric, value: f64) {
+        let baseline = match metric {
+            Metric::Bitrate => &mut self.bitrate,
+            Metric::FrameRate => &mut self.frame_rate,
+            Metric::KeyframeInterval => &mut self.keyframe_interval,
+        };
+        let (expected, z_score) = match baseline.add(value) {
+            Some(anomaly) => anomaly,
+            None => return,
+        };
+
+        warn!(
+            "Anomalous {} of stream {}: {:.2}, expected around {:.2}",
+            metric.name(),
+            self.stream_id,
+            value,
+            expected
+        );
+
+        let _ = self.send.send(StreamAnomaly {
+            stream_session_id: self.stream_id,
+            metric: metric.name().to_string(),
+            value,
+            expected,
+            z_score,
+        });
+
+        let at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.anomalies.lock().push(Anomaly {
+            metric,
+            value,
+            expected,
+            z_score,
+            at,
+        });
+    }
+}
+
+#[async_trait::async_trait]
+impl FrameReadFilter for AnomalyFilter {
+    async fn start(&mut self) -> anyhow::Result<Vec<Stream>> {
+        self.filter.start().await
+    }
+
+    async fn read(&mut self) -> anyhow::Result<Frame> {
+        let frame = self.filter.read().await?;
+
+        self.analyze(&frame);
+
+        Ok(frame)
+    }
+}
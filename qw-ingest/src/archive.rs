@@ -0,0 +1,741 @@
+use axum::{
+    body,
+    extract::{ConnectInfo, Extension, Path, Query},
+    http::HeaderMap,
+    response::IntoResponse,
+};
+use hyper::{Response, StatusCode};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sh_fmp4::CmafMuxer;
+use sh_media::Muxer;
+use sha2::{Digest, Sha256};
+use tracing::*;
+use ts_rs::TS;
+
+use std::{
+    io::Read,
+    net::SocketAddr,
+    path::{Path as FsPath, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    diagnostics::is_admin,
+    entitlement::{self, PlaybackParams},
+    naming::{NameTemplate, NameValues},
+    packaging::{self, FileFormat, SegmentedFile},
+    AppData,
+};
+
+/// Describes the files of a recording, along with checksums of the files
+/// which have been completely written.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, TS)]
+pub struct Manifest {
+    pub stream: String,
+    pub files: Vec<ArchivedFile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+pub struct ArchivedFile {
+    /// File name, relative to the recording directory.
+    pub name: String,
+    #[ts(type = "number")]
+    pub started_at: u64,
+    #[ts(type = "number | null")]
+    pub finished_at: Option<u64>,
+    #[ts(type = "number | null")]
+    pub size: Option<u64>,
+    /// Hex encoded SHA-256 of the file contents.
+    pub sha256: Option<String>,
+}
+
+/// The result of checking a single file against its manifest entry.
+#[derive(Debug, Serialize, TS)]
+pub struct FileVerification {
+    pub name: String,
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+/// When written files are flushed to disk, trading write load for how much
+/// is lost in a crash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Leave it to the OS.
+    Never,
+    /// Once a file is complete, before it is published under its name.
+    Finish,
+    /// After every fragment, and once a file is complete.
+    Fragment,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(FsyncPolicy::Never),
+            "finish" => Ok(FsyncPolicy::Finish),
+            "fragment" => Ok(FsyncPolicy::Fragment),
+            _ => anyhow::bail!("unknown fsync policy '{}'", s),
+        }
+    }
+}
+
+/// The files of a single recording in the recording directory, named
+/// `<name>.mp4`, `<name>-1.mp4`, ... unless a template is set, with a
+/// `<name>.manifest.json`. The extension is that of the muxer the files are
+/// written with.
+///
+/// Files are written with a `.part` suffix and only renamed once they are
+/// complete, so a crash never leaves a half-written file under the name in
+/// the manifest.
+pub struct Archive {
+    dir: PathBuf,
+    name: String,
+    template: Option<(NameTemplate, String)>,
+    fsync: FsyncPolicy,
+    muxer: Arc<dyn Muxer>,
+    manifest: Mutex<Manifest>,
+}
+
+impl Archive {
+    pub fn new(dir: PathBuf, name: String, stream: String) -> Self {
+        Archive {
+            dir,
+            name,
+            template: None,
+            fsync: FsyncPolicy::Never,
+            muxer: Arc::new(CmafMuxer::aligned()),
+            manifest: Mutex::new(Manifest {
+                stream,
+                files: Vec::new(),
+            }),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The name of the recorded stream.
+    pub fn stream(&self) -> String {
+        self.manifest.lock().stream.clone()
+    }
+
+    /// Names the files of a rendition of the stream after `template`,
+    /// which may place them in subdirectories.
+    pub fn with_template(mut self, template: NameTemplate, rendition: String) -> Self {
+        self.template = Some((template, rendition));
+        self
+    }
+
+    pub fn with_fsync(mut self, fsync: FsyncPolicy) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
+    /// Writes the files with `muxer` instead of as fragmented MP4.
+    pub fn with_muxer(mut self, muxer: Arc<dyn Muxer>) -> Self {
+        self.muxer = muxer;
+        self
+    }
+
+    pub fn muxer(&self) -> &dyn Muxer {
+        &*self.muxer
+    }
+
+    /// Whether every fragment written to the files has to reach the disk.
+    pub fn syncs_fragments(&self) -> bool {
+        self.fsync == FsyncPolicy::Fragment
+    }
+
+    /// The files written so far, in order.
+    pub fn files(&self) -> Vec<PathBuf> {
+        let manifest = self.manifest.lock();
+
+        manifest
+            .files
+            .iter()
+            .map(|f| self.dir.join(&f.name))
+            .collect()
+    }
+
+    /// Finishes the current file and creates the next one.
+    pub async fn create_file(&self) -> anyhow::Result<(PathBuf, tokio::fs::File)> {
+        self.finish().await?;
+
+        let name = {
+            let mut manifest = self.manifest.lock();
+
+            let part = manifest.files.len();
+            let started_at = unix_time();
+            let name = match &self.template {
+                Some((template, rendition)) => template.render(&NameValues {
+                    stream: &manifest.stream,
+                    rendition,
+                    name: &self.name,
+                    seq: part,
+                    time: started_at,
+                }),
+                None if part == 0 => format!("{}.{}", self.name, self.muxer.extension()),
+                None => format!("{}-{}.{}", self.name, part, self.muxer.extension()),
+            };
+
+            manifest.files.push(ArchivedFile {
+                name: name.clone(),
+                started_at,
+                finished_at: None,
+                size: None,
+                sha256: None,
+            });
+
+            name
+        };
+
+        let path = self.dir.join(name);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let file = tokio::fs::File::create(part_path(&path)).await?;
+
+        self.save().await?;
+
+        Ok((path, file))
+    }
+
+    /// Computes the checksum of the current file, if it has not been
+    /// finished yet, moves it to its final name and updates the manifest.
+    pub async fn finish(&self) -> anyhow::Result<()> {
+        let name = {
+            let manifest = self.manifest.lock();
+
+            match manifest.files.last() {
+                Some(file) if file.finished_at.is_none() => file.name.clone(),
+                _ => return Ok(()),
+            }
+        };
+
+        let path = self.dir.join(&name);
+        let part = part_path(&path);
+
+        let (size, sha256) = checksum_file(part.clone()).await?;
+
+        if self.fsync != FsyncPolicy::Never {
+            sync_file(part.clone()).await?;
+        }
+        tokio::fs::rename(&part, &path).await?;
+        if self.fsync != FsyncPolicy::Never {
+            if let Some(parent) = path.parent() {
+                sync_file(parent.to_path_buf()).await?;
+            }
+        }
+
+        {
+            let mut manifest = self.manifest.lock();
+
+            if let Some(file) = manifest.files.last_mut() {
+                file.finished_at = Some(unix_time());
+                file.size = Some(size);
+                file.sha256 = Some(sha256);
+            }
+        }
+
+        self.save().await
+    }
+
+    async fn save(&self) -> anyhow::Result<()> {
+        let json = serde_json::to_vec_pretty(&*self.manifest.lock())?;
+
+        write_atomically(
+            &manifest_path(&self.dir, &self.name),
+            &json,
+            self.fsync != FsyncPolicy::Never,
+        )
+        .await
+    }
+}
+
+/// Replaces the contents of `path` so readers see either the old or the new
+/// contents, never a mix. With `sync`, the new contents are also on disk
+/// once this returns.
+pub async fn write_atomically(path: &FsPath, contents: &[u8], sync: bool) -> anyhow::Result<()> {
+    // write the new file next to the old one, which keeps it on the same
+    // file system for the rename
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    tokio::fs::write(&tmp, contents).await?;
+    if sync {
+        sync_file(tmp.clone()).await?;
+    }
+
+    tokio::fs::rename(&tmp, path).await?;
+    if sync {
+        if let Some(parent) = path.parent() {
+            sync_file(parent.to_path_buf()).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Flushes a file or directory to disk. Syncing a directory persists the
+/// files created in or renamed into it.
+async fn sync_file(path: PathBuf) -> anyhow::Result<()> {
+    tokio::task::spawn_blocking(move || Ok(std::fs::File::open(path)?.sync_all()?)).await?
+}
+
+/// The name a file has while it is being written.
+fn part_path(path: &FsPath) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+
+    PathBuf::from(part)
+}
+
+fn manifest_path(dir: &FsPath, name: &str) -> PathBuf {
+    dir.join(format!("{}.manifest.json", name))
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Returns the size and hex encoded SHA-256 of a file.
+pub async fn checksum_file(path: PathBuf) -> anyhow::Result<(u64, String)> {
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; 64 * 1024];
+        let mut size = 0;
+
+        loop {
+            let read = file.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+
+            hasher.update(&buf[..read]);
+            size += read as u64;
+        }
+
+        Ok((size, format!("{:x}", hasher.finalize())))
+    })
+    .await?
+}
+
+async fn read_manifest(dir: &FsPath, name: &str) -> anyhow::Result<Manifest> {
+    let json = tokio::fs::read(manifest_path(dir, name)).await?;
+
+    Ok(serde_json::from_slice(&json)?)
+}
+
+/// Finds the directory a recording is stored in, since recordings of
+/// different renditions may be stored in different places.
+fn recording_dir<'a>(data: &'a AppData, name: &str) -> &'a FsPath {
+    std::iter::once(&data.recording_dir)
+        .chain(data.recording_targets.values())
+        .find(|dir| manifest_path(dir, name).exists())
+        .unwrap_or(&data.recording_dir)
+}
+
+/// Checks every finished file of a recording against its manifest.
+pub async fn verify(dir: &FsPath, name: &str) -> anyhow::Result<Vec<FileVerification>> {
+    let manifest = read_manifest(dir, name).await?;
+
+    let mut results = Vec::new();
+
+    for file in manifest.files {
+        let expected = match (file.size, file.sha256) {
+            (Some(size), Some(sha256)) => (size, sha256),
+            _ => {
+                results.push(FileVerification {
+                    name: file.name,
+                    ok: false,
+                    error: Some("File was not finished".into()),
+                });
+                continue;
+            }
+        };
+
+        let error = match checksum_file(dir.join(&file.name)).await {
+            Ok(actual) if actual == expected => None,
+            Ok((size, _)) if size != expected.0 => Some(format!(
+                "Size is {} bytes, expected {} bytes",
+                size, expected.0
+            )),
+            Ok(_) => Some("Checksum mismatch".into()),
+            Err(e) => Some(e.to_string()),
+        };
+
+        if let Some(e) = &error {
+            warn!("Archived file {} failed verification: {}", file.name, e);
+        }
+
+        results.push(FileVerification {
+            name: file.name,
+            ok: error.is_none(),
+            error,
+        });
+    }
+
+    Ok(results)
+}
+
+pub(crate) fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Checks that a viewer may watch the stream a recording was made of, the
+/// same way as viewers of the live stream, and returns the status and
+/// reason to reject the request with otherwise.
+pub(crate) async fn authorize_recording(
+    data: &AppData,
+    recording: &str,
+    client: SocketAddr,
+    headers: &HeaderMap,
+    params: &PlaybackParams,
+) -> Result<(), (StatusCode, &'static str)> {
+    if !is_valid_name(recording) {
+        return Err((StatusCode::BAD_REQUEST, "Invalid recording name"));
+    }
+
+    let manifest = read_manifest(recording_dir(data, recording), recording)
+        .await
+        .map_err(|_| (StatusCode::NOT_FOUND, "No such recording"))?;
+
+    entitlement::authorize_request(data, &manifest.stream, client, headers, params)
+        .await
+        .map_err(|status| (status, "Not allowed to watch the recording"))
+}
+
+fn rejected((status, reason): (StatusCode, &'static str)) -> Response<body::Full<bytes::Bytes>> {
+    Response::builder()
+        .status(status)
+        .body(body::Full::from(reason))
+        .unwrap()
+}
+
+/// Verifies the files of `recording` against its manifest, returning a
+/// JSON list with the result for each file.
+pub async fn verify_archive(
+    Path(recording): Path<String>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    if !is_admin(&data, &headers) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body::Full::from("Missing or invalid admin token"))
+            .unwrap();
+    }
+
+    if !is_valid_name(&recording) {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(body::Full::from("Invalid recording name"))
+            .unwrap();
+    }
+
+    match verify(recording_dir(&data, &recording), &recording).await {
+        Ok(results) => {
+            let status = if results.iter().all(|r| r.ok) {
+                StatusCode::OK
+            } else {
+                StatusCode::CONFLICT
+            };
+
+            Response::builder()
+                .header("Content-Type", "application/json")
+                .status(status)
+                .body(body::Full::from(serde_json::to_vec(&results).unwrap()))
+                .unwrap()
+        }
+        Err(e) => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(body::Full::from(format!(
+                "Failed to read manifest: {:?}",
+                e
+            )))
+            .unwrap(),
+    }
+}
+
+/// The finished files of a recording which can be played, in order.
+pub(crate) async fn playable_files(
+    data: &AppData,
+    recording: &str,
+) -> anyhow::Result<Vec<PathBuf>> {
+    Ok(recorded_files(data, recording).await?.0)
+}
+
+/// The finished files of a recording which can be played, in order, and
+/// the file which is still being written, if any, under the name it has
+/// while it is written.
+async fn recorded_files(
+    data: &AppData,
+    recording: &str,
+) -> anyhow::Result<(Vec<PathBuf>, Option<PathBuf>)> {
+    let dir = recording_dir(data, recording);
+    let manifest = read_manifest(dir, recording).await?;
+
+    let growing = manifest
+        .files
+        .last()
+        .filter(|file| file.finished_at.is_none())
+        // only fragmented MP4 can be read while it is written
+        .filter(|file| FileFormat::from_path(FsPath::new(&file.name)) == Some(FileFormat::Mp4))
+        .map(|file| part_path(&dir.join(&file.name)))
+        .filter(|path| path.exists());
+    let finished = manifest
+        .files
+        .into_iter()
+        .filter(|file| file.finished_at.is_some())
+        .map(|file| dir.join(file.name))
+        .filter(|path| FileFormat::from_path(path).is_some())
+        .collect();
+
+    Ok((finished, growing))
+}
+
+/// Segments every playable file of a recording, with the URI each file is
+/// served at relative to the manifests. Returns whether the recording has
+/// ended, which it hasn't if a file is still being written.
+///
+/// With `growing`, the file which is still being written is segmented as
+/// far as it has been written, so the recording can be watched while it
+/// is recorded with a single file per rendition.
+async fn segmented_files(
+    data: &AppData,
+    recording: &str,
+    growing: bool,
+) -> anyhow::Result<(Vec<(String, Arc<SegmentedFile>)>, bool)> {
+    let mut files = Vec::new();
+
+    let (finished, growing_path) = recorded_files(data, recording).await?;
+    for (i, path) in finished.iter().enumerate() {
+        let format = FileFormat::from_path(path).unwrap_or(FileFormat::Mp4);
+        let segments = data.packaging_cache.segments(path, format).await?;
+
+        files.push((format!("files/{}", i), segments));
+    }
+
+    let ended = growing_path.is_none();
+    if let Some(path) = growing_path.filter(|_| growing) {
+        // the file may not have a complete header yet, or may have just
+        // been finished and renamed
+        match data.packaging_cache.growing_segments(&path).await {
+            Ok(segments) => files.push((format!("files/{}", finished.len()), segments)),
+            Err(e) => debug!("Not packaging {} yet: {:?}", path.display(), e),
+        }
+    }
+
+    if files.is_empty() {
+        anyhow::bail!("recording has no playable files");
+    }
+
+    Ok((files, ended || !growing))
+}
+
+#[derive(Clone, Copy)]
+enum ManifestFormat {
+    Hls,
+    HlsIFrames,
+    HlsMaster,
+    Dash,
+}
+
+/// The viewer a recording is packaged for.
+struct Viewer {
+    client: SocketAddr,
+    headers: HeaderMap,
+    params: PlaybackParams,
+}
+
+async fn packaged_recording(
+    recording: String,
+    data: Arc<AppData>,
+    viewer: Viewer,
+    format: ManifestFormat,
+) -> Response<body::Full<bytes::Bytes>> {
+    let Viewer {
+        client,
+        headers,
+        params,
+    } = viewer;
+    let authorized = authorize_recording(&data, &recording, client, &headers, &params).await;
+    if let Err(rejection) = authorized {
+        return rejected(rejection);
+    }
+
+    // DASH manifests of recordings are static, so they only have the
+    // finished files
+    let growing = !matches!(format, ManifestFormat::Dash);
+
+    let manifest = segmented_files(&data, &recording, growing)
+        .await
+        .and_then(|(files, ended)| match format {
+            ManifestFormat::Hls => Ok(packaging::hls_playlist(&files, ended)),
+            ManifestFormat::HlsIFrames => Ok(packaging::hls_iframe_playlist(&files, ended)),
+            ManifestFormat::HlsMaster => Ok(packaging::hls_master_playlist(
+                &files,
+                "index.m3u8",
+                "iframes.m3u8",
+            )),
+            ManifestFormat::Dash => packaging::dash_manifest(&files),
+        });
+
+    let content_type = match format {
+        ManifestFormat::Hls | ManifestFormat::HlsIFrames | ManifestFormat::HlsMaster => {
+            "application/vnd.apple.mpegurl"
+        }
+        ManifestFormat::Dash => "application/dash+xml",
+    };
+
+    match manifest {
+        Ok(manifest) => Response::builder()
+            .header("Content-Type", content_type)
+            .header("Access-Control-Allow-Origin", "*")
+            .status(StatusCode::OK)
+            .body(body::Full::from(manifest))
+            .unwrap(),
+        Err(e) => {
+            warn!("Failed to package recording {}: {:?}", recording, e);
+
+            Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(body::Full::from(format!(
+                    "Failed to package recording: {}",
+                    e
+                )))
+                .unwrap()
+        }
+    }
+}
+
+/// Returns an HLS playlist of a recording, packaged from its files when it
+/// is requested instead of being stored.
+///
+/// While the recording is still being written, this is an event playlist
+/// of byte ranges of the growing file, so the recording doubles as a DVR
+/// with a single file per rendition.
+pub async fn recording_playlist(
+    Path(recording): Path<String>,
+    Query(params): Query<PlaybackParams>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    let viewer = Viewer {
+        client,
+        headers,
+        params,
+    };
+    packaged_recording(recording, data, viewer, ManifestFormat::Hls).await
+}
+
+/// Returns an HLS I-frame playlist of a recording, where every segment is a
+/// keyframe, for thumbnails while scrubbing.
+pub async fn recording_iframe_playlist(
+    Path(recording): Path<String>,
+    Query(params): Query<PlaybackParams>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    let viewer = Viewer {
+        client,
+        headers,
+        params,
+    };
+    packaged_recording(recording, data, viewer, ManifestFormat::HlsIFrames).await
+}
+
+/// Returns an HLS master playlist of a recording, which points players to
+/// its playlist and its I-frame playlist.
+pub async fn recording_master_playlist(
+    Path(recording): Path<String>,
+    Query(params): Query<PlaybackParams>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    let viewer = Viewer {
+        client,
+        headers,
+        params,
+    };
+    packaged_recording(recording, data, viewer, ManifestFormat::HlsMaster).await
+}
+
+/// Returns a DASH manifest of a recording, packaged like its HLS playlist.
+pub async fn recording_manifest(
+    Path(recording): Path<String>,
+    Query(params): Query<PlaybackParams>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    let viewer = Viewer {
+        client,
+        headers,
+        params,
+    };
+    packaged_recording(recording, data, viewer, ManifestFormat::Dash).await
+}
+
+/// Serves a file of a recording, usually in the byte ranges of its
+/// manifests, including the file which is still being written.
+pub async fn recording_file(
+    Path((recording, part)): Path<(String, usize)>,
+    Query(params): Query<PlaybackParams>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    let authorized = authorize_recording(&data, &recording, client, &headers, &params).await;
+    if let Err(rejection) = authorized {
+        return rejected(rejection);
+    }
+
+    // the file which is being written comes after the finished ones
+    let path = match recorded_files(&data, &recording).await {
+        Ok((finished, growing)) => finished.into_iter().chain(growing).nth(part),
+        Err(_) => None,
+    };
+
+    let path = match path {
+        Some(path) => path,
+        None => {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(body::Full::from("No such file in recording"))
+                .unwrap()
+        }
+    };
+
+    let format = FileFormat::from_path(&path).unwrap_or(FileFormat::Mp4);
+
+    match packaging::serve_file(&path, format.content_type(), &headers).await {
+        Ok(response) => response,
+        Err(e) => {
+            warn!("Failed to serve {}: {:?}", path.display(), e);
+
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(body::Full::from("Failed to read file"))
+                .unwrap()
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use axum::{
+    body::{boxed, BoxBody, Empty, StreamBody},
+    extract::{ConnectInfo, Extension, Path, Query},
+    http::{HeaderMap, HeaderValue},
+};
+use hyper::{Response, StatusCode};
+use sh_ingest_ts::AdtsMuxer;
+use sh_media::{ByteStreamWriteFilter, ByteWriteFilter2, Frame, FrameReadFilter, Muxer, Stream};
+use tokio::task;
+use tracing::*;
+
+use std::{net::SocketAddr, sync::Arc};
+
+use crate::{
+    bandwidth_analyzer::BandwidthAnalyzerFilter,
+    entitlement::{self, PlaybackParams, PlaybackSession},
+    AppData, ViewGuard,
+};
+
+/// Streams the audio of a stream as ADTS AAC over chunked HTTP, like an
+/// Icecast mount, so web radio players can listen without the video.
+///
+/// The `icy-*` headers describe the stream, but no metadata is interleaved
+/// with the audio, so players asking for it with `Icy-MetaData: 1` get the
+/// plain stream.
+pub async fn audio_stream(
+    Path(stream): Path<String>,
+    Query(params): Query<PlaybackParams>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> Response<BoxBody> {
+    debug!("Received audio request for '{}' from {}", stream, client);
+
+    let session =
+        match entitlement::authorize_playback(&data, &stream, client, &headers, &params).await {
+            Ok(session) => session,
+            Err(status) => {
+                return Response::builder()
+                    .status(status)
+                    .body(boxed(Empty::new()))
+                    .unwrap();
+            }
+        };
+
+    let (receiver, guard) = match ViewGuard::attach(stream.clone(), &data, None) {
+        Some(attached) => attached,
+        None => {
+            debug!("Did not find a stream at {}", stream);
+
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(boxed(Empty::new()))
+                .unwrap();
+        }
+    };
+
+    let mut read = AudioReadFilter {
+        read: Box::new(receiver),
+        audio: None,
+    };
+    let streams = match read.start().await {
+        Ok(streams) if !streams.is_empty() => streams,
+        _ => {
+            debug!("Stream '{}' has no AAC audio", stream);
+
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(boxed(Empty::new()))
+                .unwrap();
+        }
+    };
+
+    let sender = data.stream_stat_sender.clone();
+    let read = BandwidthAnalyzerFilter::new(Box::new(read), guard.0, false, sender);
+    let (output, bytes_rx) = ByteStreamWriteFilter::new();
+
+    task::spawn(async move {
+        let _guard = guard;
+
+        if let Err(e) = stream_audio(read, streams, Box::new(output), session).await {
+            debug!("Stopped streaming audio: {:?}", e);
+        }
+    });
+
+    // stream names come from the URL, which may not be a valid header
+    let name = HeaderValue::from_str(&stream).unwrap_or(HeaderValue::from_static("streamhead"));
+
+    // the response never ends while the stream is live, so it must not be
+    // cached
+    Response::builder()
+        .header("Content-Type", AdtsMuxer.content_type())
+        .header("Cache-Control", "no-store")
+        .header("Access-Control-Allow-Origin", "*")
+        .header("icy-name", name)
+        .header("icy-pub", "0")
+        .body(boxed(StreamBody::new(bytes_rx)))
+        .unwrap()
+}
+
+async fn stream_audio(
+    mut read: BandwidthAnalyzerFilter,
+    streams: Vec<Stream>,
+    output: Box<dyn ByteWriteFilter2 + Send + Unpin>,
+    mut session: Option<PlaybackSession>,
+) -> anyhow::Result<()> {
+    let mut write = AdtsMuxer.mux(output);
+    write.start(streams).await?;
+
+    let forward = async {
+        loop {
+            let frame = read.read().await?;
+            write.write(frame).await?;
+        }
+    };
+
+    tokio::select! {
+        res = forward => res,
+        _ = entitlement::wait_for_kick(session.as_mut()) => {
+            anyhow::bail!("session was replaced by a newer one")
+        }
+    }
+}
+
+/// A pull filter which only reads the first audio stream, so viewers of the
+/// audio aren't counted for the bandwidth of the video they don't get.
+struct AudioReadFilter {
+    read: Box<dyn FrameReadFilter + Send + Unpin>,
+    audio: Option<u32>,
+}
+
+#[async_trait::async_trait]
+impl FrameReadFilter for AudioReadFilter {
+    async fn start(&mut self) -> anyhow::Result<Vec<Stream>> {
+        let streams = self.read.start().await?;
+        // the audio is sent as ADTS, which only carries AAC
+        let audio = streams.into_iter().find(|s| s.is_aac());
+        self.audio = audio.as_ref().map(|s| s.id);
+
+        Ok(audio.into_iter().collect())
+    }
+
+    async fn read(&mut self) -> anyhow::Result<Frame> {
+        loop {
+            let frame = self.read.read().await?;
+
+            if Some(frame.stream.id) == self.audio {
+                return Ok(frame);
+            }
+        }
+    }
+}
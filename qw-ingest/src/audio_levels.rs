@@ -0,0 +1,205 @@
+use qw_proto::stream_info::stream_reply::StreamAudioLevels;
+use tokio::sync::broadcast::Sender;
+
+use std::time::{Duration, Instant};
+
+use sh_media::{AudioCodecSpecificInfo, BitReader, Frame, FrameReadFilter, Stream};
+
+/// How often levels are reported, fast enough for a VU meter.
+const REPORT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The lowest level reported, used for silence.
+const MIN_DB: f32 = -96.0;
+
+/// The AAC global gain of roughly full scale audio. The global gain scales
+/// the spectrum in steps of 1.5 dB, so levels are relative to this.
+const FULL_SCALE_GAIN: f32 = 190.0;
+
+const ID_SCE: u32 = 0;
+const ID_CPE: u32 = 1;
+const ID_LFE: u32 = 3;
+const EIGHT_SHORT_SEQUENCE: u32 = 2;
+
+/// A filter which estimates a single, approximate audio level of an ingest
+/// and reports it to stream info listeners.
+///
+/// The audio is not decoded. Instead the level of each AAC frame is taken
+/// from the global gain of its first channel element, which follows the
+/// loudness closely enough for metering. Only the first channel has its
+/// gain before the spectral data, so there are no levels per channel.
+pub struct AudioLevelFilter {
+    filter: Box<dyn FrameReadFilter + Send + Unpin>,
+    send: Sender<StreamAudioLevels>,
+    last_report: Instant,
+    stream_id: i32,
+    peak: f32,
+    sum_squares: f32,
+    frames: u32,
+}
+
+impl AudioLevelFilter {
+    pub fn new(
+        filter: Box<dyn FrameReadFilter + Send + Unpin>,
+        stream_id: i32,
+        send: Sender<StreamAudioLevels>,
+    ) -> Self {
+        AudioLevelFilter {
+            filter,
+            send,
+            last_report: Instant::now(),
+            stream_id,
+            peak: 0.0,
+            sum_squares: 0.0,
+            frames: 0,
+        }
+    }
+
+    fn analyze(&mut self, frame: &Frame) {
+        if let Some(amplitude) = frame_amplitude(frame) {
+            self.peak = self.peak.max(amplitude);
+            self.sum_squares += amplitude * amplitude;
+            self.frames += 1;
+        }
+
+        let now = Instant::now();
+        if now - self.last_report > REPORT_INTERVAL && self.frames > 0 {
+            let frames = self.frames as f32;
+
+            let _ = self.send.send(StreamAudioLevels {
+                stream_session_id: self.stream_id,
+                approx_peak_db: to_db(self.peak),
+                approx_rms_db: to_db((self.sum_squares / frames).sqrt()),
+            });
+
+            self.peak = 0.0;
+            self.sum_squares = 0.0;
+            self.frames = 0;
+            self.last_report = now;
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl FrameReadFilter for AudioLevelFilter {
+    async fn start(&mut self) -> anyhow::Result<Vec<Stream>> {
+        self.filter.start().await
+    }
+
+    async fn read(&mut self) -> anyhow::Result<Frame> {
+        let frame = self.filter.read().await?;
+
+        self.analyze(&frame);
+
+        Ok(frame)
+    }
+}
+
+/// Estimates the amplitude of an AAC frame, where 1.0 is full scale.
+pub(crate) fn frame_amplitude(frame: &Frame) -> Option<f32> {
+    let is_aac = matches!(
+        frame.stream.codec.audio().map(|a| &a.extra),
+        Some(AudioCodecSpecificInfo::Aac { .. })
+    );
+    if !is_aac {
+        return None;
+    }
+
+    Some(gain_amplitude(first_element_gain(&frame.buffer)?))
+}
+
+fn gain_amplitude(gain: u8) -> f32 {
+    if gain == 0 {
+        0.0
+    } else {
+        2f32.powf(0.25 * (gain as f32 - FULL_SCALE_GAIN)).min(1.0)
+    }
+}
+
+pub(crate) fn to_db(amplitude: f32) -> f32 {
+    (20.0 * amplitude.log10()).max(MIN_DB)
+}
+
+/// Returns the global gain of the first channel of a raw AAC frame.
+fn first_element_gain(frame: &[u8]) -> Option<u8> {
+    let mut bits = BitReader::new(frame);
+
+    let id = bits.read(3)?;
+    let _element_instance_tag = bits.read(4)?;
+
+    match id {
+        ID_SCE | ID_LFE => Some(bits.read(8)? as u8),
+        ID_CPE => {
+            // both channels share the ICS info, which comes before the gain
+            // of the first channel
+            if bits.read(1)? == 1 {
+                let _ics_reserved_bit = bits.read(1)?;
+                let window_sequence = bits.read(2)?;
+                let _window_shape = bits.read(1)?;
+
+                let (max_sfb, window_groups) = if window_sequence == EIGHT_SHORT_SEQUENCE {
+                    let max_sfb = bits.read(4)?;
+                    let grouping = bits.read(7)?;
+                    (max_sfb, 1 + (!grouping & 0x7f).count_ones())
+                } else {
+                    let max_sfb = bits.read(6)?;
+                    if bits.read(1)? == 1 {
+                        // prediction is only used by the Main profile
+                        return None;
+                    }
+                    (max_sfb, 1)
+                };
+
+                if bits.read(2)? == 1 {
+                    bits.skip((max_sfb * window_groups) as usize)?;
+                }
+            }
+
+            Some(bits.read(8)? as u8)
+        }
+        _ => None,
+    }
+}
+
+#[test]
+fn gain_of_single_channel_element() {
+    // an SCE with instance tag 0 and a global gain of 190
+    assert_eq!(first_element_gain(&[0x01, 0x7c, 0x00]), Some(190));
+    assert_eq!(first_element_gain(&[0x00]), None);
+}
+
+#[test]
+fn gain_of_channel_pair_element() {
+    // a CPE without a common window, where the gain follows the tag
+    assert_eq!(first_element_gain(&[0x20, 0xa0]), Some(160));
+
+    // a CPE with a common long window, 2 scale factor bands and an M/S mask
+    // which comes before the gain of the first channel
+    //
+    // id 001, tag 0000, common window 1, reserved 0, long window 00,
+    // shape 0, max_sfb 000010, no prediction 0, ms_mask_present 01,
+    // 2 mask bits 11, gain 10100000
+    let frame = [0b0010_0001, 0b0000_0000, 0b1000_1111, 0b0100_0000];
+    assert_eq!(first_element_gain(&frame), Some(160));
+
+    // prediction is not supported
+    let frame = [0b0010_0001, 0b0000_0000, 0b1010_0000, 0b0000_0000];
+    assert_eq!(first_element_gain(&frame), None);
+}
+
+#[test]
+fn other_elements_have_no_gain() {
+    // a fill element
+    assert_eq!(first_element_gain(&[0xc0, 0x00]), None);
+}
+
+#[test]
+fn gain_maps_to_levels() {
+    assert_eq!(gain_amplitude(0), 0.0);
+    assert_eq!(gain_amplitude(190), 1.0);
+    assert_eq!(gain_amplitude(255), 1.0);
+
+    // 4 steps of 1.5 dB halve the amplitude
+    assert!((to_db(gain_amplitude(186)) + 6.02).abs() < 0.01);
+    assert!((to_db(gain_amplitude(150)) + 60.2).abs() < 0.1);
+    assert_eq!(to_db(0.0), MIN_DB);
+}
@@ -0,0 +1,202 @@
+use axum::{body, extract::Extension, response::IntoResponse};
+use hyper::{Response, StatusCode};
+use serde::Serialize;
+use sh_media::{ByteStreamWriteFilter, MediaFrameQueue};
+use tokio::time::{sleep, timeout};
+use tracing::*;
+use ts_rs::TS;
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{anomaly::Anomaly, stream_fmp4, AppData};
+
+/// How long a canary viewer waits for the init segment and first fragment.
+const CANARY_DEADLINE: Duration = Duration::from_secs(10);
+
+/// The outcome of the latest canary check of a stream.
+#[derive(Clone, Serialize, TS)]
+pub struct CanaryResult {
+    pub ok: bool,
+    /// Unix timestamp of when the check finished.
+    #[ts(type = "number")]
+    pub checked_at: u64,
+    /// Time until the first media fragment arrived.
+    #[ts(type = "number | null")]
+    pub latency_ms: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// Periodically attaches a simulated viewer to every stream through the
+/// fragmented MP4 transport and records whether media arrived in time.
+///
+/// Canary viewers read directly from the frame queue, so they are not
+/// counted as viewers of the stream.
+pub fn spawn_canary(data: Arc<AppData>, interval: Duration) {
+    tokio::spawn(async move {
+        loop {
+            sleep(interval).await;
+
+            let streams = {
+                let repo = data.stream_repo.read();
+
+                repo.stream_mapping
+                    .iter()
+                    .filter_map(|(name, id)| {
+                        repo.streams
+                            .get(id)
+                            .map(|s| (name.clone(), s.queue.clone()))
+                    })
+                    .collect::<Vec<_>>()
+            };
+
+            for (name, queue) in streams {
+                let data = data.clone();
+                tokio::spawn(async move {
+                    let result = check_stream(queue).await;
+
+                    if let Some(e) = &result.error {
+                        warn!("Canary viewer failed for '{}': {}", name, e);
+                    }
+
+                    data.canary_results.write().insert(name, result);
+                });
+            }
+
+            // forget streams which are no longer live
+            {
+                let repo = data.stream_repo.read();
+                data.canary_results
+                    .write()
+                    .retain(|name, _| repo.stream_mapping.contains_key(name));
+            }
+        }
+    });
+}
+
+async fn check_stream(queue: MediaFrameQueue) -> CanaryResult {
+    let start = Instant::now();
+
+    let (output, bytes_rx) = ByteStreamWriteFilter::new();
+    let task = tokio::spawn(stream_fmp4(
+        Box::new(queue.get_receiver()),
+        Box::new(output),
+    ));
+
+    let result = timeout(CANARY_DEADLINE, async {
+        let init = bytes_rx.recv().await??;
+        if !is_box(&init, b"ftyp") {
+            anyhow::bail!("Stream did not start with an init segment");
+        }
+
+        let fragment = bytes_rx.recv().await??;
+        if !is_box(&fragment, b"moof") {
+            anyhow::bail!("Init segment was not followed by a media fragment");
+        }
+
+        Ok(start.elapsed())
+    })
+    .await;
+
+    task.abort();
+
+    let checked_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    match result {
+        Ok(Ok(latency)) => CanaryResult {
+            ok: true,
+            checked_at,
+            latency_ms: Some(latency.as_millis() as u64),
+            error: None,
+        },
+        Ok(Err(e)) => CanaryResult {
+            ok: false,
+            checked_at,
+            latency_ms: None,
+            error: Some(e.to_string()),
+        },
+        Err(_) => CanaryResult {
+            ok: false,
+            checked_at,
+            latency_ms: None,
+            error: Some(format!("No media within {:?}", CANARY_DEADLINE)),
+        },
+    }
+}
+
+fn is_box(bytes: &[u8], ty: &[u8; 4]) -> bool {
+    bytes.len() >= 8 && &bytes[4..8] == ty
+}
+
+/// How a live stream is doing, by its canary check and its ingest.
+#[derive(Serialize)]
+struct StreamHealth {
+    #[serde(flatten)]
+    canary: Option<CanaryResult>,
+    /// Sudden changes of the ingest over the last few minutes, which often
+    /// come before the stream fails.
+    anomalies: Vec<Anomaly>,
+}
+
+/// Reports the latest canary results and recent ingest anomalies of every
+/// stream as JSON, with a 503 status if any stream failed its check.
+///
+/// Anomalies are warnings, so they don't make a stream unhealthy by
+/// themselves.
+pub async fn health(Extension(data): Extension<Arc<AppData>>) -> impl IntoResponse {
+    let mut results = data
+        .canary_results
+        .read()
+        .iter()
+        .map(|(name, result)| {
+            let health = StreamHealth {
+                canary: Some(result.clone()),
+                anomalies: Vec::new(),
+            };
+
+            (name.clone(), health)
+        })
+        .collect::<HashMap<_, _>>();
+
+    {
+        let repo = data.stream_repo.read();
+        for (name, id) in &repo.stream_mapping {
+            let anomalies = match repo.streams.get(id) {
+                Some(state) => state.anomalies.lock().recent(),
+                None => continue,
+            };
+
+            if !anomalies.is_empty() {
+                results
+                    .entry(name.clone())
+                    .or_insert(StreamHealth {
+                        canary: None,
+                        anomalies: Vec::new(),
+                    })
+                    .anomalies = anomalies;
+            }
+        }
+    }
+
+    let healthy = results
+        .values()
+        .all(|r| r.canary.as_ref().is_none_or(|c| c.ok));
+
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .status(status)
+        .body(body::Full::from(serde_json::to_vec(&results).unwrap()))
+        .unwrap()
+}
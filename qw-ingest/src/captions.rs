@@ -0,0 +1,189 @@
+use axum::{
+    body,
+    extract::{ConnectInfo, Extension, Path, Query},
+    http::HeaderMap,
+    response::IntoResponse,
+};
+use hyper::{Response, StatusCode};
+use parking_lot::RwLock;
+use sh_ingest_ts::Caption;
+use sh_media::{Fraction, Frame, FrameReadFilter, Stream};
+use tracing::*;
+
+use std::{collections::VecDeque, fmt::Write, net::SocketAddr, sync::Arc};
+
+use crate::{
+    entitlement::{self, PlaybackParams},
+    AppData,
+};
+
+/// The number of captions kept for each stream.
+const MAX_CAPTIONS: usize = 256;
+
+/// How long the caption which is still on screen is announced for, in the
+/// 90 kHz timebase of the captions.
+const OPEN_CUE_DURATION: u64 = 10 * 90000;
+
+/// The latest captions of a stream, such as teletext subtitles from a
+/// transport stream or the closed captions of its video, oldest first.
+#[derive(Default)]
+pub struct Captions {
+    recent: VecDeque<Caption>,
+}
+
+impl Captions {
+    fn add(&mut self, caption: Caption) {
+        if self.recent.len() == MAX_CAPTIONS {
+            self.recent.pop_front();
+        }
+
+        self.recent.push_back(caption);
+    }
+
+    /// Renders the captions as a WebVTT document, where each caption lasts
+    /// until the one after it and times are relative to the stream start.
+    pub fn to_webvtt(&self) -> String {
+        let mut vtt = String::from("WEBVTT\n");
+
+        let ends = self
+            .recent
+            .iter()
+            .skip(1)
+            .map(|c| Some(c.time))
+            .chain(std::iter::once(None));
+
+        for (caption, end) in self.recent.iter().zip(ends) {
+            if caption.text.is_empty() {
+                continue;
+            }
+
+            let end = end.unwrap_or(caption.time + OPEN_CUE_DURATION);
+            let _ = write!(
+                vtt,
+                "\n{} --> {}\n{}\n",
+                timestamp(caption.time),
+                timestamp(end),
+                escape(&caption.text)
+            );
+        }
+
+        vtt
+    }
+}
+
+/// Collects the captions of a stream until the source ends.
+pub fn spawn_caption_reader(
+    captions: async_channel::Receiver<Caption>,
+    target: Arc<RwLock<Captions>>,
+) {
+    tokio::spawn(async move {
+        while let Ok(caption) = captions.recv().await {
+            target.write().add(caption);
+        }
+    });
+}
+
+/// Collects the closed captions which the SEI parser found in the frames
+/// of a stream.
+pub struct ClosedCaptionFilter {
+    filter: Box<dyn FrameReadFilter + Send + Unpin>,
+    captions: Arc<RwLock<Captions>>,
+}
+
+impl ClosedCaptionFilter {
+    pub fn new(
+        filter: Box<dyn FrameReadFilter + Send + Unpin>,
+        captions: Arc<RwLock<Captions>>,
+    ) -> Self {
+        ClosedCaptionFilter { filter, captions }
+    }
+}
+
+#[async_trait::async_trait]
+impl FrameReadFilter for ClosedCaptionFilter {
+    async fn start(&mut self) -> anyhow::Result<Vec<Stream>> {
+        self.filter.start().await
+    }
+
+    async fn read(&mut self) -> anyhow::Result<Frame> {
+        let frame = self.filter.read().await?;
+
+        if let Some(metadata) = &frame.metadata {
+            if !metadata.captions.is_empty() {
+                let mut captions = self.captions.write();
+
+                for caption in &metadata.captions {
+                    let mut time = frame.time.clone();
+                    time.pts = caption.pts;
+
+                    captions.add(Caption {
+                        time: time.in_base(Fraction::new(1, 90000)).pts,
+                        text: caption.text.clone(),
+                    });
+                }
+            }
+        }
+
+        Ok(frame)
+    }
+}
+
+pub async fn captions(
+    Path(stream): Path<String>,
+    Query(params): Query<PlaybackParams>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    debug!("Received captions request for '{}'", stream);
+
+    if let Err(status) =
+        entitlement::authorize_request(&data, &stream, client, &headers, &params).await
+    {
+        return Response::builder()
+            .status(status)
+            .body(body::Full::from("Not allowed to watch the stream"))
+            .unwrap();
+    }
+
+    let repo = data.stream_repo.read();
+
+    let stream_id = repo.stream_mapping.get(&stream);
+    let meta = stream_id.and_then(|id| repo.streams.get(id));
+
+    if let Some(meta) = meta {
+        let vtt = meta.captions.read().to_webvtt();
+
+        Response::builder()
+            .header("Content-Type", "text/vtt")
+            .header("Access-Control-Allow-Origin", "*")
+            .header("Cache-Control", "no-cache")
+            .status(StatusCode::OK)
+            .body(body::Full::from(vtt))
+            .unwrap()
+    } else {
+        Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(body::Full::from("Failed to find stream"))
+            .unwrap()
+    }
+}
+
+/// Formats a time in the 90 kHz timebase as a WebVTT timestamp.
+fn timestamp(time: u64) -> String {
+    let ms = time / 90;
+
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
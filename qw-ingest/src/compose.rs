@@ -0,0 +1,238 @@
+use axum::{
+    body,
+    extract::{Extension, Path, Query},
+    http::HeaderMap,
+    response::IntoResponse,
+};
+use hyper::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sh_media::FrameReadFilter;
+use sh_transcode::{PipOverlay, PipPlacement, TranscodeOptions};
+use tracing::*;
+use ts_rs::TS;
+
+use std::sync::Arc;
+
+use crate::{diagnostics::is_admin, AppData, StreamRepository};
+
+/// A secondary stream shown as picture-in-picture over a main stream, such
+/// as a sign-language interpreter.
+///
+/// The secondary picture is composited into the video of the main stream,
+/// which is re-encoded for it, so only the streams in
+/// `INGEST_COMPOSITED_STREAMS` can have one.
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+pub struct PipLayout {
+    /// The name of the secondary stream.
+    pub source: String,
+    /// The left edge of the secondary picture, as a fraction of the width
+    /// of the main picture.
+    #[serde(default = "default_position")]
+    pub x: f32,
+    /// The top edge of the secondary picture, as a fraction of the height
+    /// of the main picture.
+    #[serde(default = "default_position")]
+    pub y: f32,
+    /// The width of the secondary picture, as a fraction of the width of
+    /// the main picture.
+    #[serde(default = "default_width")]
+    pub width: f32,
+}
+
+fn default_position() -> f32 {
+    0.7
+}
+
+fn default_width() -> f32 {
+    0.25
+}
+
+impl PipLayout {
+    fn is_valid(&self) -> bool {
+        let in_range = |v: f32| (0.0..=1.0).contains(&v);
+
+        !self.source.is_empty()
+            && in_range(self.x)
+            && in_range(self.y)
+            && self.width > 0.0
+            && in_range(self.x + self.width)
+    }
+
+    fn placement(&self) -> PipPlacement {
+        PipPlacement {
+            x: self.x,
+            y: self.y,
+            width: self.width,
+        }
+    }
+}
+
+/// Re-encodes the video of a composited stream, with the picture-in-picture
+/// stream of `overlay` drawn over it whenever there is one.
+#[cfg(feature = "transcode")]
+pub fn compositor(
+    read: Box<dyn FrameReadFilter + Send + Unpin>,
+    options: TranscodeOptions,
+    overlay: PipOverlay,
+) -> Box<dyn FrameReadFilter + Send + Unpin> {
+    Box::new(sh_transcode::TranscodeFilter::read(read, options).with_overlay(overlay))
+}
+
+#[cfg(not(feature = "transcode"))]
+pub fn compositor(
+    read: Box<dyn FrameReadFilter + Send + Unpin>,
+    _options: TranscodeOptions,
+    _overlay: PipOverlay,
+) -> Box<dyn FrameReadFilter + Send + Unpin> {
+    // no stream is composited without the transcode feature
+    read
+}
+
+/// Shows the picture-in-picture streams which involve a stream which just
+/// went live, whether it is the main or the secondary stream.
+pub(crate) fn attach_pips(repo: &StreamRepository, stream: &str) {
+    let mains = repo
+        .pip
+        .iter()
+        .filter(|(main, layout)| *main == stream || layout.source == stream)
+        .map(|(main, _)| main);
+
+    for main in mains {
+        show_pip(repo, main);
+    }
+}
+
+/// Starts compositing the secondary stream of `main` over it, if both are
+/// live.
+fn show_pip(repo: &StreamRepository, main: &str) {
+    let overlay = match overlay(repo, main) {
+        Some(overlay) => overlay,
+        None => return,
+    };
+    let layout = match repo.pip.get(main) {
+        Some(layout) => layout,
+        None => return,
+    };
+    let secondary = repo
+        .stream_mapping
+        .get(&layout.source)
+        .and_then(|id| repo.streams.get(id));
+    let mut read = match secondary {
+        Some(secondary) => secondary.queue.get_receiver_from_keyframe(),
+        None => return,
+    };
+
+    debug!("Compositing '{}' over '{}'", layout.source, main);
+
+    let frames = overlay.show(layout.placement());
+    tokio::spawn(async move {
+        // until the secondary stream ends, or another one is shown instead
+        while let Ok(frame) = read.read().await {
+            if frames.send(frame).await.is_err() {
+                return;
+            }
+        }
+
+        // rather than freezing on its last picture
+        overlay.hide();
+    });
+}
+
+/// The overlay of a live, composited stream.
+fn overlay(repo: &StreamRepository, stream: &str) -> Option<PipOverlay> {
+    let id = repo.stream_mapping.get(stream)?;
+    repo.streams.get(id)?.pip.clone()
+}
+
+/// Returns the picture-in-picture layout of a stream.
+pub async fn get_composition(
+    Path(stream): Path<String>,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    let pip = data.stream_repo.read().pip.get(&stream).cloned();
+
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .header("Access-Control-Allow-Origin", "*")
+        .status(StatusCode::OK)
+        .body(body::Full::from(json!({ "pip": pip }).to_string()))
+        .unwrap()
+}
+
+/// Shows `source` as picture-in-picture over `stream`, or moves it.
+pub async fn set_pip(
+    Path(stream): Path<String>,
+    Query(layout): Query<PipLayout>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    if !is_admin(&data, &headers) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body::Full::from("Missing or invalid admin token"))
+            .unwrap();
+    }
+
+    if !layout.is_valid() || layout.source == stream {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(body::Full::from("Invalid picture-in-picture layout"))
+            .unwrap();
+    }
+
+    if !data.composited_streams.contains(&stream) {
+        return Response::builder()
+            .status(StatusCode::CONFLICT)
+            .body(body::Full::from("Stream is not composited"))
+            .unwrap();
+    }
+
+    let mut repo = data.stream_repo.write();
+
+    // the same secondary stream is only moved, rather than decoded anew
+    let placement = layout.placement();
+    let same_source = repo
+        .pip
+        .insert(stream.clone(), layout.clone())
+        .is_some_and(|previous| previous.source == layout.source);
+    let moved = same_source && overlay(&repo, &stream).is_some_and(|o| o.place(placement));
+    if !moved {
+        show_pip(&repo, &stream);
+    }
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .body(body::Full::from("Picture-in-picture enabled"))
+        .unwrap()
+}
+
+/// Stops showing a picture-in-picture stream over `stream`.
+pub async fn clear_pip(
+    Path(stream): Path<String>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    if !is_admin(&data, &headers) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body::Full::from("Missing or invalid admin token"))
+            .unwrap();
+    }
+
+    let mut repo = data.stream_repo.write();
+    if let Some(overlay) = overlay(&repo, &stream) {
+        overlay.hide();
+    }
+
+    match repo.pip.remove(&stream) {
+        Some(_) => Response::builder()
+            .status(StatusCode::OK)
+            .body(body::Full::from("Picture-in-picture disabled"))
+            .unwrap(),
+        None => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(body::Full::from("Stream has no picture-in-picture"))
+            .unwrap(),
+    }
+}
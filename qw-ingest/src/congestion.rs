@@ -0,0 +1,238 @@
+use serde::Serialize;
+use sh_ingest_rtmp::RtmpFeedback;
+use tokio::sync::broadcast::Sender;
+use tracing::*;
+use ts_rs::TS;
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use sh_media::{Frame, FrameReadFilter, MediaTime, Stream};
+
+/// How long the input is measured over for each sample.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many samples starvation is judged by.
+const STARVATION_WINDOW: usize = 10;
+
+/// How far the media of an ingest may fall behind the clock over the
+/// starvation window before its publisher is taken to be starved.
+const STARVED_LAG: f64 = 2.0;
+
+/// The weight of a new sample in the moving average of the bitrate.
+const EWMA_ALPHA: f64 = 0.05;
+
+/// How many samples the average bitrate needs before it is compared to.
+const WARMUP_SAMPLES: u32 = 20;
+
+/// The share of the average bitrate below which the bitrate has collapsed.
+const COLLAPSE_RATIO: f64 = 0.25;
+
+/// How many samples in a row the bitrate has to stay collapsed, so a few
+/// static frames don't count.
+const COLLAPSE_SAMPLES: u32 = 3;
+
+/// How often a congested publisher is told about it at most.
+const NOTIFY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Why the network of a publisher is taken to be congested.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, TS)]
+#[serde(rename_all = "snake_case")]
+pub enum CongestionCause {
+    /// Media arrives slower than it plays.
+    Starvation,
+    /// The bitrate dropped far below what it has been.
+    BitrateCollapse,
+}
+
+/// The network of a publisher can't keep up with its stream.
+#[derive(Debug, Clone)]
+pub struct Congestion {
+    pub stream_session_id: i32,
+    pub cause: CongestionCause,
+    /// In kbit/s, over the last sample.
+    pub bitrate: f64,
+    /// The moving average of the bitrate in kbit/s.
+    pub expected: f64,
+    /// How many seconds the media fell behind the clock over the last
+    /// samples.
+    pub behind: f64,
+}
+
+/// A filter which watches for the input of an ingest starving or its
+/// bitrate collapsing, which is how a congested network shows, and tells
+/// the publisher to lower its bitrate if it can be told.
+pub struct CongestionFilter {
+    filter: Box<dyn FrameReadFilter + Send + Unpin>,
+    stream_id: i32,
+    feedback: Option<RtmpFeedback>,
+    send: Sender<Congestion>,
+    /// The stream whose timestamps are compared to the clock, the video if
+    /// there is any.
+    clock: Option<u32>,
+    last_time: Option<MediaTime>,
+    sample_start: Instant,
+    /// How many seconds of media were received during the sample.
+    media: f64,
+    bytes: u64,
+    /// How far the media fell behind the clock in each recent sample.
+    lag: VecDeque<f64>,
+    bitrate: f64,
+    samples: u32,
+    collapsed: u32,
+    last_notified: Option<Instant>,
+}
+
+impl CongestionFilter {
+    pub fn new(
+        filter: Box<dyn FrameReadFilter + Send + Unpin>,
+        stream_id: i32,
+        feedback: Option<RtmpFeedback>,
+        send: Sender<Congestion>,
+    ) -> Self {
+        CongestionFilter {
+            filter,
+            stream_id,
+            feedback,
+            send,
+            clock: None,
+            last_time: None,
+            sample_start: Instant::now(),
+            media: 0.0,
+            bytes: 0,
+            lag: VecDeque::with_capacity(STARVATION_WINDOW),
+            bitrate: 0.0,
+            samples: 0,
+            collapsed: 0,
+            last_notified: None,
+        }
+    }
+
+    fn analyze(&mut self, frame: &Frame) {
+        self.bytes += frame.buffer.len() as u64;
+
+        if Some(frame.stream.id) == self.clock {
+            if let Some(last) = &self.last_time {
+                self.media += Duration::from(frame.time.since(last)).as_secs_f64();
+            }
+            self.last_time = Some(frame.time.clone());
+        }
+
+        let elapsed = self.sample_start.elapsed();
+        if elapsed < SAMPLE_INTERVAL {
+            return;
+        }
+
+        let seconds = elapsed.as_secs_f64();
+        let bitrate = self.bytes as f64 * 8.0 / 1000.0 / seconds;
+
+        if self.lag.len() == STARVATION_WINDOW {
+            self.lag.pop_front();
+        }
+        self.lag.push_back(seconds - self.media);
+
+        self.media = 0.0;
+        self.bytes = 0;
+        self.sample_start = Instant::now();
+
+        let behind: f64 = self.lag.iter().sum();
+        let cause = if self.lag.len() == STARVATION_WINDOW && behind > STARVED_LAG {
+            Some(CongestionCause::Starvation)
+        } else if self.sample_bitrate(bitrate) {
+            Some(CongestionCause::BitrateCollapse)
+        } else {
+            None
+        };
+
+        if let Some(cause) = cause {
+            self.notify(Congestion {
+                stream_session_id: self.stream_id,
+                cause,
+                bitrate,
+                expected: self.bitrate,
+                behind,
+            });
+        }
+    }
+
+    /// Adds a sample to the average bitrate, and returns whether the
+    /// bitrate has collapsed.
+    fn sample_bitrate(&mut self, bitrate: f64) -> bool {
+        if self.samples >= WARMUP_SAMPLES && bitrate < self.bitrate * COLLAPSE_RATIO {
+            // the average is kept from before the collapse
+            self.collapsed += 1;
+            return self.collapsed >= COLLAPSE_SAMPLES;
+        }
+
+        self.collapsed = 0;
+        self.bitrate = match self.samples {
+            0 => bitrate,
+            _ => self.bitrate + EWMA_ALPHA * (bitrate - self.bitrate),
+        };
+        self.samples = self.samples.saturating_add(1);
+
+        false
+    }
+
+    fn notify(&mut self, congestion: Congestion) {
+        if self
+            .last_notified
+            .is_some_and(|at| at.elapsed() < NOTIFY_INTERVAL)
+        {
+            return;
+        }
+        self.last_notified = Some(Instant::now());
+
+        warn!(
+            "Publisher of stream {} is congested ({:?}): {:.0} kbit/s, expected around {:.0}, \
+             {:.1}s behind",
+            self.stream_id,
+            congestion.cause,
+            congestion.bitrate,
+            congestion.expected,
+            congestion.behind
+        );
+
+        if let Some(feedback) = &self.feedback {
+            let description = match congestion.cause {
+                CongestionCause::Starvation => "Stream is arriving slower than real time",
+                CongestionCause::BitrateCollapse => "Stream bitrate dropped sharply",
+            };
+
+            if let Err(e) = feedback.slow_network(description) {
+                debug!(
+                    "Failed to tell publisher of stream {}: {:?}",
+                    self.stream_id, e
+                );
+            }
+        }
+
+        let _ = self.send.send(congestion);
+    }
+}
+
+#[async_trait::async_trait]
+impl FrameReadFilter for CongestionFilter {
+    async fn start(&mut self) -> anyhow::Result<Vec<Stream>> {
+        let streams = self.filter.start().await?;
+
+        self.clock = streams
+            .iter()
+            .find(|s| s.is_video())
+            .or_else(|| streams.first())
+            .map(|s| s.id);
+        self.sample_start = Instant::now();
+
+        Ok(streams)
+    }
+
+    async fn read(&mut self) -> anyhow::Result<Frame> {
+        let frame = self.filter.read().await?;
+
+        self.analyze(&frame);
+
+        Ok(frame)
+    }
+}
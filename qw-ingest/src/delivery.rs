@@ -0,0 +1,419 @@
+use axum::{
+    body::{self, boxed, BoxBody},
+    extract::{Extension, Path, Query},
+    http::HeaderMap,
+};
+use hyper::{Response, StatusCode};
+use parking_lot::Mutex;
+use qw_proto::stream_info::stream_reply::{ViewerDelivery, ViewerStats};
+use serde::{Deserialize, Serialize};
+use sh_media::QueueBacklog;
+use sh_transport_mse::{DeliveryStats, JoinTimes};
+use tokio::time::interval;
+use ts_rs::TS;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{diagnostics::is_admin, AppData};
+
+/// How often the throughput of viewers is measured and sent as events.
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How many of the latest viewers the percentiles of join times are taken
+/// over.
+const JOIN_SAMPLES: usize = 1000;
+
+/// A viewer which is sent a stream over a WebSocket.
+pub struct Viewer {
+    pub stream: String,
+    /// The stream session the viewer watches.
+    pub session: i32,
+    pub rendition: String,
+    /// Who the viewer is logged in as, if playback requires a login.
+    pub user: Option<String>,
+    pub user_agent: Option<String>,
+    /// The address of the viewer, as told by the proxy in front of the
+    /// server if there is one.
+    pub client: SocketAddr,
+    pub stats: Arc<DeliveryStats>,
+    /// The frames the viewer has yet to be sent.
+    pub backlog: QueueBacklog,
+}
+
+struct TrackedViewer {
+    viewer: Viewer,
+    /// Seconds since the UNIX epoch of when the viewer started watching.
+    started: u64,
+    /// How many bytes had been sent when the throughput was last measured.
+    measured_bytes: u64,
+    measured_at: Instant,
+    bytes_per_second: u64,
+    /// Whether the join times of the viewer were added to the samples.
+    join_recorded: bool,
+}
+
+/// The viewers of every stream with how delivery to them is going, so
+/// support can look into a viewer for whom a stream keeps buffering.
+#[derive(Default)]
+pub struct Deliveries {
+    viewers: Mutex<HashMap<u64, TrackedViewer>>,
+    next_id: AtomicU64,
+    join_samples: Mutex<JoinSamples>,
+}
+
+/// The join times of the latest viewers, by stage.
+#[derive(Default)]
+struct JoinSamples {
+    init_segment: VecDeque<Duration>,
+    first_keyframe: VecDeque<Duration>,
+    first_fragment: VecDeque<Duration>,
+}
+
+impl JoinSamples {
+    fn add(&mut self, times: JoinTimes) {
+        let stages = [
+            (&mut self.init_segment, times.init_segment),
+            (&mut self.first_keyframe, times.first_keyframe),
+            (&mut self.first_fragment, times.first_fragment),
+        ];
+
+        for (samples, time) in stages {
+            if let Some(time) = time {
+                if samples.len() == JOIN_SAMPLES {
+                    samples.pop_front();
+                }
+                samples.push_back(time);
+            }
+        }
+    }
+}
+
+/// Removes a viewer from the deliveries when it is dropped.
+pub struct DeliveryGuard {
+    id: u64,
+    deliveries: Arc<Deliveries>,
+}
+
+impl Drop for DeliveryGuard {
+    fn drop(&mut self) {
+        let tracked = self.deliveries.viewers.lock().remove(&self.id);
+
+        // viewers which left before they could play still count for the
+        // stages they got to
+        if let Some(tracked) = tracked.filter(|tracked| !tracked.join_recorded) {
+            let times = tracked.viewer.stats.join_times();
+            self.deliveries.join_samples.lock().add(times);
+        }
+    }
+}
+
+impl Deliveries {
+    /// Tracks the delivery to a viewer until the guard is dropped.
+    pub fn track(self: &Arc<Self>, viewer: Viewer) -> DeliveryGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let tracked = TrackedViewer {
+            measured_bytes: viewer.stats.bytes_sent(),
+            viewer,
+            started,
+            measured_at: Instant::now(),
+            bytes_per_second: 0,
+            join_recorded: false,
+        };
+        self.viewers.lock().insert(id, tracked);
+
+        DeliveryGuard {
+            id,
+            deliveries: self.clone(),
+        }
+    }
+
+    /// The throughput to every viewer together, as last measured.
+    pub fn bytes_per_second(&self) -> u64 {
+        self.viewers
+            .lock()
+            .values()
+            .map(|tracked| tracked.bytes_per_second)
+            .sum()
+    }
+
+    /// Measures the throughput of every viewer since it was last measured,
+    /// and returns how delivery is going by stream session.
+    fn measure(&self) -> HashMap<i32, Vec<ViewerDelivery>> {
+        let now = Instant::now();
+        let mut viewers = self.viewers.lock();
+        let mut sessions = HashMap::<i32, Vec<ViewerDelivery>>::new();
+
+        for (id, tracked) in viewers.iter_mut() {
+            let bytes = tracked.viewer.stats.bytes_sent();
+            let elapsed = (now - tracked.measured_at).as_secs_f64();
+            if elapsed > 0.0 {
+                tracked.bytes_per_second =
+                    ((bytes - tracked.measured_bytes) as f64 / elapsed) as u64;
+            }
+            tracked.measured_bytes = bytes;
+            tracked.measured_at = now;
+
+            if !tracked.join_recorded {
+                let times = tracked.viewer.stats.join_times();
+                if times.first_fragment.is_some() {
+                    self.join_samples.lock().add(times);
+                    tracked.join_recorded = true;
+                }
+            }
+
+            let delivery = info(*id, tracked);
+            sessions
+                .entry(tracked.viewer.session)
+                .or_default()
+                .push(ViewerDelivery {
+                    viewer_id: delivery.id,
+                    user: delivery.user,
+                    bytes_per_second: delivery.bytes_per_second,
+                    queued_bytes: delivery.queued_bytes,
+                    dropped_frames: delivery.dropped_frames,
+                    rtt_ms: delivery.rtt_ms,
+                });
+        }
+
+        sessions
+    }
+}
+
+/// Sends how delivery to the viewers of each stream is going to the
+/// events channel, every few seconds.
+pub fn spawn_delivery_reporter(data: Arc<AppData>) {
+    tokio::spawn(async move {
+        let mut ticks = interval(REPORT_INTERVAL);
+
+        loop {
+            ticks.tick().await;
+
+            for (stream_session_id, viewers) in data.deliveries.measure() {
+                let _ = data.viewer_stat_sender.send(ViewerStats {
+                    stream_session_id,
+                    viewers,
+                });
+            }
+        }
+    });
+}
+
+/// How delivery to a viewer is going, as shown by the API.
+#[derive(Debug, Clone, Serialize, TS)]
+pub struct ViewerInfo {
+    #[ts(type = "number")]
+    pub id: u64,
+    pub stream: String,
+    pub rendition: String,
+    pub user: Option<String>,
+    pub user_agent: Option<String>,
+    #[ts(type = "string")]
+    pub client: SocketAddr,
+    /// Seconds since the UNIX epoch of when the viewer started watching.
+    #[ts(type = "number")]
+    pub started: u64,
+    #[ts(type = "number")]
+    pub bytes_sent: u64,
+    /// The throughput to the viewer over the last few seconds.
+    #[ts(type = "number")]
+    pub bytes_per_second: u64,
+    /// How many bytes of frames are waiting to be sent to the viewer,
+    /// which grows when its connection can't keep up.
+    #[ts(type = "number")]
+    pub queued_bytes: u64,
+    #[ts(type = "number")]
+    pub dropped_frames: u64,
+    pub rtt_ms: Option<u32>,
+}
+
+fn info(id: u64, tracked: &TrackedViewer) -> ViewerInfo {
+    let viewer = &tracked.viewer;
+
+    ViewerInfo {
+        id,
+        stream: viewer.stream.clone(),
+        rendition: viewer.rendition.clone(),
+        user: viewer.user.clone(),
+        user_agent: viewer.user_agent.clone(),
+        client: viewer.client,
+        started: tracked.started,
+        bytes_sent: viewer.stats.bytes_sent(),
+        bytes_per_second: tracked.bytes_per_second,
+        queued_bytes: viewer.backlog.bytes() as u64,
+        dropped_frames: viewer.stats.dropped_frames(),
+        rtt_ms: viewer
+            .stats
+            .rtt()
+            .map(|rtt| rtt.as_millis().min(u32::MAX as u128) as u32),
+    }
+}
+
+/// Which viewers to list.
+#[derive(Deserialize)]
+pub struct ViewerFilter {
+    pub stream: Option<String>,
+    pub user: Option<String>,
+}
+
+fn json<T: Serialize>(value: &T) -> Response<BoxBody> {
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .status(StatusCode::OK)
+        .body(boxed(body::Full::from(serde_json::to_vec(value).unwrap())))
+        .unwrap()
+}
+
+fn error(status: StatusCode, message: &'static str) -> Response<BoxBody> {
+    Response::builder()
+        .status(status)
+        .body(boxed(body::Full::from(message)))
+        .unwrap()
+}
+
+/// Lists the WebSocket viewers with how delivery to them is going, of a
+/// single stream or user if asked to.
+pub async fn list_viewers(
+    Query(filter): Query<ViewerFilter>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> Response<BoxBody> {
+    if !is_admin(&data, &headers) {
+        return error(StatusCode::UNAUTHORIZED, "Missing or invalid admin token");
+    }
+
+    let mut viewers = {
+        let viewers = data.deliveries.viewers.lock();
+
+        viewers
+            .iter()
+            .filter(|(_, tracked)| {
+                let viewer = &tracked.viewer;
+
+                filter.stream.as_ref().is_none_or(|s| *s == viewer.stream)
+                    && filter
+                        .user
+                        .as_ref()
+                        .is_none_or(|u| viewer.user.as_ref() == Some(u))
+            })
+            .map(|(id, tracked)| info(*id, tracked))
+            .collect::<Vec<_>>()
+    };
+    viewers.sort_by_key(|viewer| viewer.id);
+
+    json(&viewers)
+}
+
+/// Shows how delivery to a single WebSocket viewer is going.
+pub async fn get_viewer(
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> Response<BoxBody> {
+    if !is_admin(&data, &headers) {
+        return error(StatusCode::UNAUTHORIZED, "Missing or invalid admin token");
+    }
+
+    let viewer = data
+        .deliveries
+        .viewers
+        .lock()
+        .get(&id)
+        .map(|tracked| info(id, tracked));
+
+    match viewer {
+        Some(viewer) => json(&viewer),
+        None => error(StatusCode::NOT_FOUND, "No such viewer"),
+    }
+}
+
+/// A stage of a viewer starting to play, from when it connected.
+#[derive(Debug, Clone, Copy, Serialize, TS)]
+#[serde(rename_all = "snake_case")]
+pub enum JoinStage {
+    InitSegment,
+    FirstKeyframe,
+    FirstFragment,
+}
+
+/// The percentiles of how long the latest viewers took to reach a stage of
+/// starting to play, in milliseconds.
+#[derive(Debug, Clone, Serialize, TS)]
+pub struct JoinPercentiles {
+    pub stage: JoinStage,
+    #[ts(type = "number")]
+    pub samples: usize,
+    #[ts(type = "number | null")]
+    pub p50: Option<u64>,
+    #[ts(type = "number | null")]
+    pub p90: Option<u64>,
+    #[ts(type = "number | null")]
+    pub p99: Option<u64>,
+}
+
+fn percentiles(stage: JoinStage, samples: &VecDeque<Duration>) -> JoinPercentiles {
+    let mut sorted = samples.iter().copied().collect::<Vec<_>>();
+    sorted.sort_unstable();
+
+    let percentile = |p: usize| {
+        let index = (sorted.len() * p / 100).min(sorted.len().checked_sub(1)?);
+        Some(sorted[index].as_millis() as u64)
+    };
+
+    JoinPercentiles {
+        stage,
+        samples: sorted.len(),
+        p50: percentile(50),
+        p90: percentile(90),
+        p99: percentile(99),
+    }
+}
+
+/// Shows how long the latest WebSocket viewers took to start playing, by
+/// stage, so regressions in join time show up.
+pub async fn join_times(
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> Response<BoxBody> {
+    if !is_admin(&data, &headers) {
+        return error(StatusCode::UNAUTHORIZED, "Missing or invalid admin token");
+    }
+
+    let stages = {
+        let samples = data.deliveries.join_samples.lock();
+
+        [
+            percentiles(JoinStage::InitSegment, &samples.init_segment),
+            percentiles(JoinStage::FirstKeyframe, &samples.first_keyframe),
+            percentiles(JoinStage::FirstFragment, &samples.first_fragment),
+        ]
+    };
+
+    json(&stages)
+}
+
+#[test]
+fn percentiles_of_join_times() {
+    let samples = (1..=100).map(Duration::from_millis).collect();
+    let stage = percentiles(JoinStage::FirstFragment, &samples);
+
+    assert_eq!(stage.samples, 100);
+    assert_eq!(stage.p50, Some(51));
+    assert_eq!(stage.p90, Some(91));
+    assert_eq!(stage.p99, Some(100));
+
+    let empty = percentiles(JoinStage::FirstFragment, &VecDeque::new());
+    assert_eq!(empty.p50, None);
+}
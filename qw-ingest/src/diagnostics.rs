@@ -0,0 +1,462 @@
+use axum::{
+    body,
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Extension, Path, Query,
+    },
+    http::{header::AUTHORIZATION, HeaderMap},
+    response::IntoResponse,
+};
+use hyper::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use sh_ingest_ts::AdtsMuxer;
+use sh_media::{
+    frame_nal_units, parse_bitstream, BitstreamFraming, FileWriteFilter, Frame, FrameReadFilter,
+    FrameWriteFilter, MediaFrameQueueReceiver, Muxer,
+};
+use tokio::{fs::File, io::AsyncWriteExt};
+use tracing::*;
+use ts_rs::TS;
+
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use crate::AppData;
+
+/// The longest time a single capture is allowed to run.
+const MAX_CAPTURE_DURATION: Duration = Duration::from_secs(300);
+
+/// Returns whether the request carries the configured admin token.
+pub fn is_admin(data: &AppData, headers: &HeaderMap) -> bool {
+    let token = match &data.admin_token {
+        Some(token) => token,
+        None => return false,
+    };
+
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(|h| h == token)
+        .unwrap_or(false)
+}
+
+#[derive(Deserialize)]
+pub struct CaptureParams {
+    seconds: Option<u64>,
+}
+
+/// Starts dumping the raw bytes and events of the RTMP session publishing
+/// `stream` to the capture directory.
+pub async fn start_capture(
+    Path(stream): Path<String>,
+    Query(params): Query<CaptureParams>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    if !is_admin(&data, &headers) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body::Full::from("Missing or invalid admin token"))
+            .unwrap();
+    }
+
+    let repo = data.stream_repo.read();
+
+    let capture = repo
+        .stream_mapping
+        .get(&stream)
+        .and_then(|id| repo.streams.get(id))
+        .and_then(|s| s.capture.clone());
+
+    let capture = match capture {
+        Some(capture) => capture,
+        None => {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(body::Full::from("No RTMP session for stream"))
+                .unwrap()
+        }
+    };
+
+    let duration = Duration::from_secs(params.seconds.unwrap_or(30)).min(MAX_CAPTURE_DURATION);
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let path = data.capture_dir.join(format!("{}-{}", stream, timestamp));
+
+    match capture.start(&path, duration) {
+        Ok(()) => {
+            info!("Started a {:?} capture of '{}'", duration, stream);
+
+            Response::builder()
+                .status(StatusCode::OK)
+                .body(body::Full::from(path.display().to_string()))
+                .unwrap()
+        }
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Full::from(format!(
+                "Failed to start capture: {:?}",
+                e
+            )))
+            .unwrap(),
+    }
+}
+
+/// Starts writing the video and audio of `stream` as raw elementary
+/// streams to the capture directory, as Annex B H.264 or H.265 and as AAC
+/// in ADTS, which tools like ffprobe read as they are.
+///
+/// The dump starts at the last keyframe, so the video can be decoded from
+/// its start. Returns the paths of the files, one per line.
+pub async fn dump_bitstream(
+    Path(stream): Path<String>,
+    Query(params): Query<CaptureParams>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    if !is_admin(&data, &headers) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body::Full::from(
+                "Missing or invalid admin token".to_string(),
+            ))
+            .unwrap();
+    }
+
+    let read = {
+        let repo = data.stream_repo.read();
+
+        repo.stream_mapping
+            .get(&stream)
+            .and_then(|id| repo.streams.get(id))
+            .map(|entry| entry.queue.get_receiver_from_keyframe())
+    };
+
+    let read = match read {
+        Some(read) => read,
+        None => {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(body::Full::from("No live stream by that name".to_string()))
+                .unwrap()
+        }
+    };
+
+    let duration = Duration::from_secs(params.seconds.unwrap_or(10)).min(MAX_CAPTURE_DURATION);
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let base = format!("{}-{}", stream, timestamp);
+
+    match start_dump(read, &data.capture_dir, &base, duration).await {
+        Ok(paths) => {
+            info!("Started a {:?} bitstream dump of '{}'", duration, stream);
+
+            let paths: Vec<_> = paths.iter().map(|p| p.display().to_string()).collect();
+            Response::builder()
+                .status(StatusCode::OK)
+                .body(body::Full::from(paths.join("\n")))
+                .unwrap()
+        }
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Full::from(format!(
+                "Failed to start bitstream dump: {:?}",
+                e
+            )))
+            .unwrap(),
+    }
+}
+
+/// Creates the files of a bitstream dump, and writes the frames of `read`
+/// to them for `duration` in the background.
+async fn start_dump(
+    mut read: MediaFrameQueueReceiver,
+    dir: &std::path::Path,
+    base: &str,
+    duration: Duration,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let streams = read.start().await?;
+    let video = streams.iter().find(|s| s.is_h264() || s.is_h265());
+    let audio = streams.iter().find(|s| s.is_aac());
+
+    if video.is_none() && audio.is_none() {
+        anyhow::bail!("Stream has no H.264, H.265 or AAC to dump");
+    }
+
+    tokio::fs::create_dir_all(dir).await?;
+    let mut paths = Vec::new();
+
+    let video = match video {
+        Some(stream) => {
+            let extension = if stream.is_h265() { "h265" } else { "h264" };
+            let path = dir.join(format!("{}.{}", base, extension));
+            let file = File::create(&path).await?;
+            paths.push(path);
+
+            Some((stream.id, tokio::io::BufWriter::new(file)))
+        }
+        None => None,
+    };
+
+    let audio = match audio {
+        Some(stream) => {
+            let path = dir.join(format!("{}.aac", base));
+            let file = File::create(&path).await?;
+            paths.push(path);
+
+            let mut write = AdtsMuxer.mux(Box::new(FileWriteFilter::new(file)));
+            write.start(streams.clone()).await?;
+
+            Some((stream.id, write))
+        }
+        None => None,
+    };
+
+    tokio::spawn(async move {
+        if let Err(e) = dump(read, video, audio, duration).await {
+            warn!("Bitstream dump failed: {:?}", e);
+        }
+    });
+
+    Ok(paths)
+}
+
+async fn dump(
+    mut read: MediaFrameQueueReceiver,
+    mut video: Option<(u32, tokio::io::BufWriter<File>)>,
+    mut audio: Option<(u32, Box<dyn FrameWriteFilter + Send + Unpin>)>,
+    duration: Duration,
+) -> anyhow::Result<()> {
+    let deadline = tokio::time::Instant::now() + duration;
+
+    while let Ok(frame) = tokio::time::timeout_at(deadline, read.read()).await {
+        // the queue closes when the stream ends, which ends the dump early
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(_) => break,
+        };
+
+        match (&mut video, &mut audio) {
+            (Some((id, file)), _) if *id == frame.stream.id => {
+                file.write_all(&annex_b(&frame)).await?;
+            }
+            (_, Some((id, write))) if *id == frame.stream.id => {
+                write.write(frame).await?;
+            }
+            _ => {}
+        }
+    }
+
+    if let Some((_, mut file)) = video {
+        file.flush().await?;
+    }
+
+    Ok(())
+}
+
+/// Converts a H.264 or H.265 frame to Annex B, with the parameter sets in
+/// front of keyframes which don't have them, so every keyframe can be
+/// decoded on its own.
+fn annex_b(frame: &Frame) -> Vec<u8> {
+    let framing = frame
+        .stream
+        .bitstream_format()
+        .unwrap_or(BitstreamFraming::FourByteLength);
+    let units = parse_bitstream(frame.buffer.clone(), framing);
+
+    let hevc = frame.stream.is_h265();
+    let has_sps = units.iter().filter_map(|nal| nal.first()).any(|h| {
+        if hevc {
+            (h >> 1) & 0x3f == 33
+        } else {
+            h & 0x1f == 7
+        }
+    });
+
+    let mut nal_units = Vec::with_capacity(units.len() + 3);
+    if frame.is_keyframe() && !has_sps {
+        if let Some(parameter_sets) = frame.stream.parameter_sets() {
+            nal_units.extend(parse_bitstream(
+                parameter_sets.into(),
+                BitstreamFraming::FourByteLength,
+            ));
+        }
+    }
+    nal_units.extend(units.into_iter().filter(|nal| !nal.is_empty()));
+
+    frame_nal_units(&nal_units[..], BitstreamFraming::FourByteStartCode).to_vec()
+}
+
+/// Returns the conformance report of the latest RTMP session of `stream` as
+/// JSON.
+pub async fn conformance_report(
+    Path(stream): Path<String>,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    let report = data.stream_repo.read().reports.get(&stream).cloned();
+
+    let report = match report {
+        Some(report) => report.lock().clone(),
+        None => {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(body::Full::from("No report for stream"))
+                .unwrap()
+        }
+    };
+
+    match serde_json::to_vec_pretty(&report) {
+        Ok(json) => Response::builder()
+            .header("Content-Type", "application/json")
+            .header("Access-Control-Allow-Origin", "*")
+            .status(StatusCode::OK)
+            .body(body::Full::from(json))
+            .unwrap(),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Full::from(format!(
+                "Failed to serialize report: {:?}",
+                e
+            )))
+            .unwrap(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct FrameParams {
+    /// The admin token, for clients like browsers which can't set headers
+    /// on WebSocket requests.
+    token: Option<String>,
+}
+
+/// A frame as shown to tooling, without its data.
+#[derive(Debug, Serialize, TS)]
+pub struct FrameSummary {
+    #[ts(type = "number")]
+    pub stream: u32,
+    /// Whether the frame is `video` or `audio`.
+    pub kind: &'static str,
+    pub codec: &'static str,
+    #[ts(type = "number")]
+    pub size: usize,
+    #[ts(type = "number")]
+    pub pts: u64,
+    #[ts(type = "number | null")]
+    pub dts: Option<u64>,
+    /// The timebase of `pts` and `dts`, like `1/90000`.
+    pub timebase: String,
+    pub keyframe: bool,
+    /// The types of the NAL units of H.264 and H.265 frames.
+    pub nal_types: Option<Vec<u8>>,
+}
+
+impl FrameSummary {
+    fn new(frame: &Frame) -> Self {
+        let stream = &frame.stream;
+        let nal_types = match stream.bitstream_format() {
+            Some(framing) if stream.is_h264() || stream.is_h265() => {
+                Some(nal_types(frame, framing, stream.is_h265()))
+            }
+            _ => None,
+        };
+
+        FrameSummary {
+            stream: stream.id,
+            kind: if stream.is_video() { "video" } else { "audio" },
+            codec: stream.codec.name,
+            size: frame.buffer.len(),
+            pts: frame.time.pts,
+            dts: frame.time.dts,
+            timebase: format!(
+                "{}/{}",
+                stream.timebase.numerator, stream.timebase.denominator
+            ),
+            keyframe: frame.is_keyframe(),
+            nal_types,
+        }
+    }
+}
+
+fn nal_types(frame: &Frame, framing: BitstreamFraming, hevc: bool) -> Vec<u8> {
+    parse_bitstream(frame.buffer.clone(), framing)
+        .iter()
+        .filter_map(|nal| nal.first())
+        .map(|h| if hevc { (h >> 1) & 0x3f } else { h & 0x1f })
+        .collect()
+}
+
+/// Streams a JSON summary of every frame of `stream` as a text message,
+/// so the pipeline can be inspected live with a browser or `websocat`.
+pub async fn frames(
+    ws: WebSocketUpgrade,
+    Path(stream): Path<String>,
+    Query(params): Query<FrameParams>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    let token_matches = match (&data.admin_token, &params.token) {
+        (Some(admin), Some(token)) => admin == token,
+        _ => false,
+    };
+    if !is_admin(&data, &headers) && !token_matches {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body::Full::from("Missing or invalid admin token"))
+            .unwrap()
+            .into_response();
+    }
+
+    let read = {
+        let repo = data.stream_repo.read();
+
+        repo.stream_mapping
+            .get(&stream)
+            .and_then(|id| repo.streams.get(id))
+            .map(|entry| entry.queue.get_receiver())
+    };
+
+    let read = match read {
+        Some(read) => read,
+        None => {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(body::Full::from("No live stream by that name"))
+                .unwrap()
+                .into_response()
+        }
+    };
+
+    info!("Inspecting the frames of '{}'", stream);
+
+    ws.on_upgrade(move |socket| async move {
+        if let Err(e) = send_frames(socket, read).await {
+            debug!("Stopped inspecting the frames of '{}': {:?}", stream, e);
+        }
+    })
+    .into_response()
+}
+
+async fn send_frames(
+    mut socket: WebSocket,
+    mut read: MediaFrameQueueReceiver,
+) -> anyhow::Result<()> {
+    read.start().await?;
+
+    loop {
+        tokio::select! {
+            // the queue closes when the stream ends
+            frame = read.read() => {
+                let summary = FrameSummary::new(&frame?);
+                socket.send(Message::Text(serde_json::to_string(&summary)?)).await?;
+            }
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return Ok(()),
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
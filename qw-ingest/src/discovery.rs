@@ -0,0 +1,90 @@
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use tracing::*;
+
+/// The service type of the server, which companion apps browse for.
+const SERVER_SERVICE: &str = "_streamhead._tcp.local.";
+
+/// The service type of live streams, with an instance per stream.
+const STREAM_SERVICE: &str = "_streamhead-live._tcp.local.";
+
+/// Advertises the server and its live streams with mDNS/DNS-SD, so apps and
+/// displays on the local network find them without being configured.
+///
+/// Streams are advertised as instances named after them, with the paths
+/// they can be played from in their TXT records.
+pub struct Discovery {
+    daemon: ServiceDaemon,
+    host_name: String,
+    port: u16,
+}
+
+impl Discovery {
+    /// Starts advertising the server as `name`, with its HTTP endpoint on
+    /// `port` of every local address.
+    pub fn new(name: &str, port: u16) -> anyhow::Result<Self> {
+        let daemon = ServiceDaemon::new()?;
+
+        let host_name = format!(
+            "{}.local.",
+            name.chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+                .collect::<String>()
+        );
+
+        let server = ServiceInfo::new(
+            SERVER_SERVICE,
+            name,
+            &host_name,
+            "",
+            port,
+            &[("version", env!("CARGO_PKG_VERSION")), ("vod", "/vod")][..],
+        )?
+        .enable_addr_auto();
+        daemon.register(server)?;
+
+        info!("Advertising the server as '{}' with mDNS", name);
+
+        Ok(Discovery {
+            daemon,
+            host_name,
+            port,
+        })
+    }
+
+    /// Advertises a stream which just went live.
+    pub fn advertise_stream(&self, stream: &str) {
+        let mse = format!("/transport/mse/{}", stream);
+        let hls = format!("/hls/{}/playlist.m3u8", stream);
+        let whep = format!("/whep/{}", stream);
+        let cast = format!("/cast/{}", stream);
+        let properties = [
+            ("mse", mse.as_str()),
+            ("hls", hls.as_str()),
+            ("whep", whep.as_str()),
+            ("cast", cast.as_str()),
+        ];
+
+        let result = ServiceInfo::new(
+            STREAM_SERVICE,
+            stream,
+            &self.host_name,
+            "",
+            self.port,
+            &properties[..],
+        )
+        .and_then(|info| self.daemon.register(info.enable_addr_auto()));
+
+        if let Err(e) = result {
+            warn!("Failed to advertise '{}' with mDNS: {:?}", stream, e);
+        }
+    }
+
+    /// Stops advertising a stream which has ended.
+    pub fn withdraw_stream(&self, stream: &str) {
+        let fullname = format!("{}.{}", stream, STREAM_SERVICE);
+
+        if let Err(e) = self.daemon.unregister(&fullname) {
+            debug!("Failed to withdraw '{}' from mDNS: {:?}", stream, e);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use qw_proto::stream_info::stream_reply::{DiskFull, StreamType};
+use tokio::time::interval;
+use tracing::*;
+
+use std::{
+    collections::HashSet,
+    ffi::CString,
+    io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::AppData;
+
+/// How often the disks recordings are written to are checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How much of a full disk has to be freed before it is reported as full
+/// again, so a disk hovering at the limit doesn't raise an alert a minute.
+const RECOVERY_MARGIN: f64 = 0.05;
+
+/// The size and free space of a filesystem, in bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct DiskUsage {
+    pub available: u64,
+    pub total: u64,
+}
+
+impl DiskUsage {
+    /// Reads the usage of the filesystem `path` is on.
+    // the fields are narrower than 64 bits on some platforms
+    #[allow(clippy::unnecessary_cast)]
+    pub fn of(path: &Path) -> io::Result<Self> {
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+        let result = unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let stat = unsafe { stat.assume_init() };
+
+        let block_size = stat.f_frsize as u64;
+        Ok(DiskUsage {
+            // what is left for unprivileged users, which the ingest is
+            available: stat.f_bavail as u64 * block_size,
+            total: stat.f_blocks as u64 * block_size,
+        })
+    }
+
+    /// The fraction of the disk in use.
+    fn used(&self) -> f64 {
+        if self.total == 0 {
+            return 0.0;
+        }
+
+        1.0 - self.available as f64 / self.total as f64
+    }
+}
+
+/// Tells which disks became full, remembering those which already were so
+/// each is reported once until it has room again.
+#[derive(Debug)]
+pub struct DiskWatch {
+    /// The fraction of a disk in use at which it is full.
+    limit: f64,
+    full: HashSet<PathBuf>,
+}
+
+impl DiskWatch {
+    pub fn new(limit: f64) -> Self {
+        DiskWatch {
+            limit,
+            full: HashSet::new(),
+        }
+    }
+
+    /// Returns whether the disk of `path` just became full.
+    pub fn check(&mut self, path: &Path, usage: DiskUsage) -> bool {
+        let used = usage.used();
+
+        if used >= self.limit {
+            self.full.insert(path.to_path_buf())
+        } else {
+            if used < self.limit - RECOVERY_MARGIN {
+                self.full.remove(path);
+            }
+
+            false
+        }
+    }
+}
+
+/// Checks the disks of the recording directories every minute, sending a
+/// [`DiskFull`] event when one of them fills up past `limit`.
+pub fn spawn_disk_checks(data: Arc<AppData>, limit: f64) {
+    let mut dirs = vec![data.recording_dir.clone()];
+    dirs.extend(data.recording_targets.values().cloned());
+    dirs.sort();
+    dirs.dedup();
+
+    tokio::spawn(async move {
+        let mut watch = DiskWatch::new(limit);
+        let mut interval = interval(CHECK_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            for dir in &dirs {
+                let usage = match disk_usage(dir.clone()).await {
+                    Ok(usage) => usage,
+                    Err(e) => {
+                        debug!("Failed to check the disk of {}: {:?}", dir.display(), e);
+                        continue;
+                    }
+                };
+
+                if !watch.check(dir, usage) {
+                    continue;
+                }
+
+                warn!(
+                    "The disk of {} is full, with {} MiB left",
+                    dir.display(),
+                    usage.available / (1024 * 1024)
+                );
+
+                let event = StreamType::DiskFull(DiskFull {
+                    path: dir.display().to_string(),
+                    available_bytes: usage.available,
+                    total_bytes: usage.total,
+                });
+                let events = data.stream_repo.read().event_sender();
+                let _ = events.send(event);
+            }
+        }
+    });
+}
+
+async fn disk_usage(dir: PathBuf) -> anyhow::Result<DiskUsage> {
+    Ok(tokio::task::spawn_blocking(move || DiskUsage::of(&dir)).await??)
+}
+
+#[test]
+fn reports_full_disks_once() {
+    let mut watch = DiskWatch::new(0.9);
+    let path = Path::new("/recordings");
+    let usage = |available| DiskUsage {
+        available,
+        total: 100,
+    };
+
+    assert!(!watch.check(path, usage(50)));
+    assert!(watch.check(path, usage(5)));
+    assert!(!watch.check(path, usage(3)));
+
+    // hovering just below the limit isn't enough room
+    assert!(!watch.check(path, usage(12)));
+    assert!(!watch.check(path, usage(8)));
+
+    assert!(!watch.check(path, usage(20)));
+    assert!(watch.check(path, usage(8)));
+}
+
+#[test]
+fn reads_disk_usage() {
+    let usage = DiskUsage::of(&std::env::temp_dir()).unwrap();
+    assert!(usage.available <= usage.total);
+}
@@ -0,0 +1,167 @@
+use axum::{
+    body::{self, boxed, BoxBody, StreamBody},
+    extract::{ConnectInfo, Extension, Path, Query},
+    http::HeaderMap,
+};
+use bytes::Bytes;
+use hyper::{Response, StatusCode};
+use serde::Deserialize;
+use sh_fmp4::{Mp4Index, ProgressiveMp4};
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt},
+    sync::mpsc,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::*;
+
+use std::{io::SeekFrom, net::SocketAddr, path::PathBuf, sync::Arc};
+
+use crate::{
+    archive::{authorize_recording, playable_files},
+    entitlement::PlaybackParams,
+    packaging::FileFormat,
+    AppData,
+};
+
+/// How much media data is read from the recorded files at a time.
+pub(crate) const READ_SIZE: usize = 256 * 1024;
+
+#[derive(Deserialize)]
+pub struct DownloadParams {
+    /// Downloads a single file of the recording instead of all of them,
+    /// for recordings whose codec parameters changed between files.
+    file: Option<usize>,
+}
+
+/// Serves a recording as a single progressive MP4 file with the `moov` box
+/// first, remuxed from its fragmented files while it is downloaded.
+pub async fn download_recording(
+    Path(id): Path<String>,
+    Query(params): Query<DownloadParams>,
+    Query(playback): Query<PlaybackParams>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> Response<BoxBody> {
+    let authorized = authorize_recording(&data, &id, client, &headers, &playback).await;
+    if let Err((status, reason)) = authorized {
+        return error(status, reason.into());
+    }
+
+    let files = mp4_files(&data, &id).await;
+    let files = match params.file {
+        Some(i) => files.into_iter().nth(i).into_iter().collect(),
+        None => files,
+    };
+
+    if files.is_empty() {
+        return error(StatusCode::NOT_FOUND, "No such recording".into());
+    }
+
+    let mp4 = match remux(files.clone()).await {
+        Ok(mp4) => mp4,
+        Err(e) => {
+            warn!("Failed to remux recording {}: {:?}", id, e);
+
+            return error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("Failed to remux recording: {}", e),
+            );
+        }
+    };
+
+    let size = mp4.size();
+    let (tx, rx) = mpsc::channel(4);
+
+    tokio::spawn(async move {
+        if let Err(e) = write_file(&mp4, &files, &tx).await {
+            warn!("Failed to send recording download: {:?}", e);
+
+            let _ = tx
+                .send(Err(std::io::Error::new(std::io::ErrorKind::Other, e)))
+                .await;
+        }
+    });
+
+    Response::builder()
+        .header("Content-Type", "video/mp4")
+        .header("Content-Length", size)
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}.mp4\"", id),
+        )
+        .status(StatusCode::OK)
+        .body(boxed(StreamBody::new(ReceiverStream::new(rx))))
+        .unwrap()
+}
+
+/// The fragmented MP4 files of a recording in order, or none if there is
+/// no such recording.
+pub(crate) async fn mp4_files(data: &AppData, id: &str) -> Vec<PathBuf> {
+    match playable_files(data, id).await {
+        Ok(files) => files
+            .into_iter()
+            .filter(|path| FileFormat::from_path(path) == Some(FileFormat::Mp4))
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Reads the fragments of the files, which takes a pass over their boxes.
+pub(crate) async fn read_indexes(files: Vec<PathBuf>) -> anyhow::Result<Vec<Mp4Index>> {
+    tokio::task::spawn_blocking(move || {
+        files
+            .into_iter()
+            .map(|path| {
+                let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+                Mp4Index::read(&mut file)
+            })
+            .collect()
+    })
+    .await?
+}
+
+async fn remux(files: Vec<PathBuf>) -> anyhow::Result<ProgressiveMp4> {
+    let indexes = read_indexes(files).await?;
+
+    tokio::task::spawn_blocking(move || ProgressiveMp4::remux(&indexes)).await?
+}
+
+/// Sends the header of the remuxed file and then its media data, copied
+/// from the recorded files.
+async fn write_file(
+    mp4: &ProgressiveMp4,
+    files: &[PathBuf],
+    tx: &mpsc::Sender<Result<Bytes, std::io::Error>>,
+) -> anyhow::Result<()> {
+    tx.send(Ok(Bytes::from(mp4.header.clone()))).await?;
+
+    let mut open = None;
+
+    for (i, range) in &mp4.chunks {
+        if open.as_ref().map(|(current, _)| current) != Some(i) {
+            open = Some((*i, tokio::fs::File::open(&files[*i]).await?));
+        }
+        let (_, file) = open.as_mut().unwrap();
+
+        file.seek(SeekFrom::Start(range.start)).await?;
+
+        let mut remaining = range.end - range.start;
+        while remaining > 0 {
+            let mut buf = vec![0; remaining.min(READ_SIZE as u64) as usize];
+            file.read_exact(&mut buf).await?;
+            remaining -= buf.len() as u64;
+
+            tx.send(Ok(Bytes::from(buf))).await?;
+        }
+    }
+
+    Ok(())
+}
+
+fn error(status: StatusCode, message: String) -> Response<BoxBody> {
+    Response::builder()
+        .status(status)
+        .body(boxed(body::Full::from(message)))
+        .unwrap()
+}
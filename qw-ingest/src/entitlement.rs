@@ -0,0 +1,255 @@
+use anyhow::Context;
+use axum::http::{header::USER_AGENT, HeaderMap};
+use hyper::{
+    client::HttpConnector, header::CONTENT_TYPE, Body, Client, Method, Request, StatusCode,
+};
+use hyper_rustls::HttpsConnector;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::oneshot, time::timeout};
+use tracing::*;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{
+    viewer_auth::{self, ViewerClaims},
+    AppData,
+};
+
+/// How long the entitlement service has to answer before playback is
+/// denied.
+const CALLBACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Query parameters of playback requests.
+#[derive(Default, Deserialize)]
+pub struct PlaybackParams {
+    /// An identifier of the viewer's device, passed on to the entitlement
+    /// service.
+    pub device: Option<String>,
+    /// How many seconds behind live to start watching, within the DVR
+    /// window.
+    pub behind: Option<f64>,
+    /// How much faster than realtime to send frames until the viewer
+    /// catches up to live, e.g. `1.5`.
+    pub rate: Option<f64>,
+    /// A token from an earlier session of the viewer, to continue where it
+    /// left off instead of at live.
+    pub resume: Option<String>,
+    /// The media sequence number of an HLS segment of the stream to start
+    /// at, when a viewer switches over from HLS.
+    pub segment: Option<u64>,
+    /// Whether the viewer picks a rendition it can decode before the
+    /// stream starts, see [`sh_transport_mse::negotiate_rendition`].
+    pub negotiate: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct EntitlementRequest<'a> {
+    stream: &'a str,
+    user: Option<&'a str>,
+    device: Option<&'a str>,
+    user_agent: Option<&'a str>,
+    /// The address of the viewer, as told by the proxy in front of the
+    /// server if there is one.
+    ip: IpAddr,
+}
+
+/// Decides whether viewers may play a stream, by asking an external
+/// entitlement service and by limiting how many streams each user may play
+/// at once.
+pub struct Entitlements {
+    client: Client<HttpsConnector<HttpConnector>>,
+    /// The service which is asked about every play request, if any.
+    url: Option<String>,
+    /// The most concurrent sessions per user, where the oldest session is
+    /// stopped to make room for a new one. Zero means no limit.
+    max_sessions_per_user: usize,
+    sessions: Mutex<HashMap<String, VecDeque<(u64, oneshot::Sender<()>)>>>,
+    next_session_id: AtomicU64,
+}
+
+impl Entitlements {
+    pub fn new(url: Option<String>, max_sessions_per_user: usize) -> Self {
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+
+        Entitlements {
+            client: Client::builder().build(connector),
+            url,
+            max_sessions_per_user,
+            sessions: Mutex::new(HashMap::new()),
+            next_session_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Checks that the viewer may play `stream` and starts a session for
+    /// them, which has to be kept for as long as they are watching.
+    pub async fn authorize(
+        self: &Arc<Self>,
+        stream: &str,
+        viewer: Option<&ViewerClaims>,
+        client: IpAddr,
+        headers: &HeaderMap,
+        params: &PlaybackParams,
+    ) -> anyhow::Result<Option<PlaybackSession>> {
+        let user = viewer.map(|v| v.sub.as_str());
+
+        if let Some(url) = &self.url {
+            let request = EntitlementRequest {
+                stream,
+                user,
+                device: params.device.as_deref(),
+                user_agent: headers.get(USER_AGENT).and_then(|h| h.to_str().ok()),
+                ip: client,
+            };
+
+            timeout(CALLBACK_TIMEOUT, self.ask(url, &request))
+                .await
+                .context("entitlement service timed out")??;
+        }
+
+        Ok(user.map(|user| self.start_session(user)))
+    }
+
+    async fn ask(&self, url: &str, request: &EntitlementRequest<'_>) -> anyhow::Result<()> {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_vec(request)?))?;
+
+        let response = self.client.request(request).await?;
+
+        if !response.status().is_success() {
+            anyhow::bail!("entitlement service responded with {}", response.status());
+        }
+
+        Ok(())
+    }
+
+    fn start_session(self: &Arc<Self>, user: &str) -> PlaybackSession {
+        let id = self.next_session_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, kicked) = oneshot::channel();
+
+        let mut sessions = self.sessions.lock();
+        let user_sessions = sessions.entry(user.to_string()).or_default();
+
+        if self.max_sessions_per_user > 0 {
+            while user_sessions.len() >= self.max_sessions_per_user {
+                if let Some((_, oldest)) = user_sessions.pop_front() {
+                    info!("Stopping the oldest session of {} to make room", user);
+                    let _ = oldest.send(());
+                }
+            }
+        }
+
+        user_sessions.push_back((id, tx));
+
+        PlaybackSession {
+            entitlements: self.clone(),
+            user: user.to_string(),
+            id,
+            kicked,
+        }
+    }
+}
+
+/// A viewer's playback of a stream, counting towards the session limit of
+/// the user until it is dropped.
+pub struct PlaybackSession {
+    entitlements: Arc<Entitlements>,
+    user: String,
+    id: u64,
+    kicked: oneshot::Receiver<()>,
+}
+
+impl PlaybackSession {
+    /// The user the session counts towards.
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    /// Waits until the session is stopped to make room for a newer session
+    /// of the same user.
+    pub async fn kicked(&mut self) {
+        let _ = (&mut self.kicked).await;
+    }
+}
+
+impl Drop for PlaybackSession {
+    fn drop(&mut self) {
+        let mut sessions = self.entitlements.sessions.lock();
+
+        if let Some(user_sessions) = sessions.get_mut(&self.user) {
+            user_sessions.retain(|(id, _)| *id != self.id);
+
+            if user_sessions.is_empty() {
+                sessions.remove(&self.user);
+            }
+        }
+    }
+}
+
+/// Waits until `session` is kicked, or forever if there is no session.
+pub async fn wait_for_kick(session: Option<&mut PlaybackSession>) {
+    match session {
+        Some(session) => session.kicked().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Checks the session and entitlements of a viewer before playback, and
+/// returns the status to reject the request with otherwise.
+pub async fn authorize_playback(
+    data: &AppData,
+    stream: &str,
+    client: SocketAddr,
+    headers: &HeaderMap,
+    params: &PlaybackParams,
+) -> Result<Option<PlaybackSession>, StatusCode> {
+    let viewer = match viewer_auth::authorize_viewer(data, headers).await {
+        Ok(viewer) => viewer,
+        Err(e) => {
+            debug!("Rejected viewer of '{}': {:?}", stream, e);
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+    };
+
+    match data
+        .entitlements
+        .authorize(stream, viewer.as_ref(), client.ip(), headers, params)
+        .await
+    {
+        Ok(session) => Ok(session),
+        Err(e) => {
+            debug!("Viewer of '{}' is not entitled: {:?}", stream, e);
+            Err(StatusCode::FORBIDDEN)
+        }
+    }
+}
+
+/// Checks a viewer like [`authorize_playback`], for requests which are
+/// answered at once, like playlists, segments and files, so no session is
+/// kept after the check.
+pub async fn authorize_request(
+    data: &AppData,
+    stream: &str,
+    client: SocketAddr,
+    headers: &HeaderMap,
+    params: &PlaybackParams,
+) -> Result<(), StatusCode> {
+    authorize_playback(data, stream, client, headers, params)
+        .await
+        .map(drop)
+}
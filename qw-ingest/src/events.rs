@@ -0,0 +1,531 @@
+use anyhow::Context;
+use axum::{
+    body::{self, boxed, BoxBody, StreamBody},
+    extract::Extension,
+    http::HeaderMap,
+};
+use bytes::Bytes;
+use hyper::{Response, StatusCode};
+use parking_lot::Mutex;
+use qw_proto::stream_info::stream_reply::{StreamAudioLevels, StreamType};
+use serde::Serialize;
+use tokio::{
+    sync::{broadcast, mpsc},
+    task,
+    time::timeout,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::*;
+use ts_rs::TS;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    congestion::{Congestion, CongestionCause},
+    diagnostics::is_admin,
+    AppData, StreamChange, StreamPhase,
+};
+
+/// How many events are kept for consumers which reconnect with a
+/// `Last-Event-ID`.
+const HISTORY_LEN: usize = 256;
+
+/// How long a consumer may not read before it is disconnected, so consumers
+/// which went away without closing their connection don't pile up.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long browsers wait before reconnecting.
+const RETRY_MS: u64 = 3000;
+
+/// How often the audio levels of a stream are sent, often enough for a
+/// meter on a dashboard.
+const LEVELS_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+struct FeedEvent {
+    /// Zero for live events, which are not kept in the history.
+    id: u64,
+    name: &'static str,
+    data: Arc<str>,
+}
+
+impl FeedEvent {
+    fn encode(&self) -> String {
+        // consumers keep their `Last-Event-ID` for events without an ID
+        if self.id == 0 {
+            return format!("event: {}\ndata: {}\n\n", self.name, self.data);
+        }
+
+        format!(
+            "id: {}\nevent: {}\ndata: {}\n\n",
+            self.id, self.name, self.data
+        )
+    }
+}
+
+struct History {
+    events: VecDeque<FeedEvent>,
+    next_id: u64,
+}
+
+/// Sends the events of streams starting and stopping, viewers joining and
+/// leaving, anomalies, congested publishers and audio levels to dashboards
+/// as server-sent events.
+///
+/// Consumers which reconnect with a `Last-Event-ID` get the events they
+/// missed, as long as they are still in the history.
+pub struct EventFeed {
+    history: Mutex<History>,
+    send: broadcast::Sender<FeedEvent>,
+    /// How long a consumer goes without events before it is sent a comment,
+    /// so proxies don't close the connection as idle.
+    keep_alive: Duration,
+    max_consumers: usize,
+    consumers: AtomicUsize,
+}
+
+/// Counts a consumer of the feed until it is dropped.
+struct ConsumerGuard(Arc<EventFeed>);
+
+impl Drop for ConsumerGuard {
+    fn drop(&mut self) {
+        self.0.consumers.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl EventFeed {
+    pub fn new(keep_alive: Duration, max_consumers: usize) -> Self {
+        let (send, _) = broadcast::channel(HISTORY_LEN);
+
+        EventFeed {
+            history: Mutex::new(History {
+                events: VecDeque::with_capacity(HISTORY_LEN),
+                next_id: 1,
+            }),
+            send,
+            keep_alive,
+            max_consumers,
+            consumers: AtomicUsize::new(0),
+        }
+    }
+
+    fn publish(&self, name: &'static str, data: serde_json::Value) {
+        let mut history = self.history.lock();
+
+        let event = FeedEvent {
+            id: history.next_id,
+            name,
+            data: data.to_string().into(),
+        };
+        history.next_id += 1;
+
+        if history.events.len() == HISTORY_LEN {
+            history.events.pop_front();
+        }
+        history.events.push_back(event.clone());
+
+        let _ = self.send.send(event);
+    }
+
+    /// Sends an event to the current consumers without keeping it, for
+    /// events which are soon outdated and would crowd out the others.
+    fn publish_live(&self, name: &'static str, data: serde_json::Value) {
+        let _ = self.send.send(FeedEvent {
+            id: 0,
+            name,
+            data: data.to_string().into(),
+        });
+    }
+
+    fn join(self: &Arc<Self>) -> Option<ConsumerGuard> {
+        let consumers = self.consumers.fetch_add(1, Ordering::Relaxed);
+        let guard = ConsumerGuard(self.clone());
+
+        if consumers >= self.max_consumers {
+            return None;
+        }
+
+        Some(guard)
+    }
+
+    /// Subscribes to new events, and returns the events after `last_id`,
+    /// or `None` if some of them are no longer kept.
+    fn subscribe(
+        &self,
+        last_id: Option<u64>,
+    ) -> (Option<Vec<FeedEvent>>, broadcast::Receiver<FeedEvent>) {
+        // events are published while the history is locked, so none are
+        // missed or sent twice between the two
+        let history = self.history.lock();
+        let receiver = self.send.subscribe();
+
+        let missed = match last_id {
+            None => Some(Vec::new()),
+            Some(id) if id + 1 == history.next_id => Some(Vec::new()),
+            Some(id) => match history.events.front() {
+                Some(oldest) if id + 1 >= oldest.id && id < history.next_id => Some(
+                    history
+                        .events
+                        .iter()
+                        .filter(|e| e.id > id)
+                        .cloned()
+                        .collect(),
+                ),
+                // the consumer missed too much, or the ID is from before
+                // the server restarted
+                _ => None,
+            },
+        };
+
+        (missed, receiver)
+    }
+}
+
+/// The data of a `stream-started` event.
+#[derive(Serialize, TS)]
+pub(crate) struct StreamStartedEvent {
+    session: i32,
+    video_codec: Option<String>,
+    audio_codec: Option<String>,
+    video_bitrate_kbps: Option<u32>,
+    video_encoder: Option<String>,
+}
+
+/// The data of a `stream-stopped` event.
+#[derive(Serialize, TS)]
+pub(crate) struct StreamStoppedEvent {
+    session: i32,
+    /// Why the stream ended, if its pipeline failed unexpectedly.
+    error: Option<String>,
+}
+
+/// The data of a `viewer-joined` or `viewer-left` event.
+#[derive(Serialize, TS)]
+pub(crate) struct ViewerEvent {
+    session: i32,
+}
+
+/// The data of an `anomaly` event.
+#[derive(Serialize, TS)]
+pub(crate) struct AnomalyEvent {
+    session: i32,
+    /// `bitrate`, `frame_rate` or `keyframe_interval`.
+    metric: String,
+    value: f64,
+    expected: f64,
+    z_score: f64,
+}
+
+/// The data of a `recording-failed` event.
+#[derive(Serialize, TS)]
+pub(crate) struct RecordingFailedEvent {
+    /// The name of the recorded stream.
+    stream: String,
+    recording: String,
+    error: String,
+}
+
+/// The data of a `disk-full` event, about the disk recordings are written
+/// to.
+#[derive(Serialize, TS)]
+pub(crate) struct DiskFullEvent {
+    path: String,
+    available_bytes: u64,
+    total_bytes: u64,
+}
+
+/// The data of a `congestion` event.
+#[derive(Serialize, TS)]
+pub(crate) struct CongestionEvent {
+    session: i32,
+    cause: CongestionCause,
+    /// In kbit/s.
+    bitrate: f64,
+    expected: f64,
+    /// How many seconds the media fell behind the clock.
+    behind: f64,
+}
+
+/// The data of a `stream-phase` event.
+#[derive(Serialize, TS)]
+pub(crate) struct StreamPhaseEvent {
+    session: i32,
+    name: String,
+    phase: StreamPhase,
+    /// Why the stream changed phase, like the error its publisher went away
+    /// with.
+    reason: Option<String>,
+}
+
+/// The data of an `audio-levels` event, in dBFS. The levels are a single
+/// approximation for all channels, as they are estimated without decoding
+/// the audio.
+#[derive(Serialize, TS)]
+pub(crate) struct AudioLevelsEvent {
+    session: i32,
+    approx_peak_db: f32,
+    approx_rms_db: f32,
+}
+
+/// Names an event and describes it as JSON, if it is sent to the feed.
+/// Stats are sent too often for dashboards to keep up over HTTP.
+fn feed_event(event: &StreamType) -> Option<(&'static str, serde_json::Value)> {
+    let event = match event {
+        StreamType::StreamStarted(started) => {
+            let meta = started.meta.clone().unwrap_or_default();
+
+            (
+                "stream-started",
+                to_json(StreamStartedEvent {
+                    session: started.stream_session_id,
+                    video_codec: meta.video_codec,
+                    audio_codec: meta.audio_codec,
+                    video_bitrate_kbps: meta.video_bitrate_kbps,
+                    video_encoder: meta.video_encoder,
+                }),
+            )
+        }
+        StreamType::StreamStopped(stopped) => (
+            "stream-stopped",
+            to_json(StreamStoppedEvent {
+                session: stopped.stream_session_id,
+                error: stopped.error.clone(),
+            }),
+        ),
+        StreamType::ViewerJoin(join) => (
+            "viewer-joined",
+            to_json(ViewerEvent {
+                session: join.stream_session_id,
+            }),
+        ),
+        StreamType::ViewerLeave(leave) => (
+            "viewer-left",
+            to_json(ViewerEvent {
+                session: leave.stream_session_id,
+            }),
+        ),
+        StreamType::StreamAnomaly(anomaly) => (
+            "anomaly",
+            to_json(AnomalyEvent {
+                session: anomaly.stream_session_id,
+                metric: anomaly.metric.clone(),
+                value: anomaly.value,
+                expected: anomaly.expected,
+                z_score: anomaly.z_score,
+            }),
+        ),
+        StreamType::RecordingFailed(failed) => (
+            "recording-failed",
+            to_json(RecordingFailedEvent {
+                stream: failed.stream.clone(),
+                recording: failed.recording.clone(),
+                error: failed.error.clone(),
+            }),
+        ),
+        StreamType::DiskFull(full) => (
+            "disk-full",
+            to_json(DiskFullEvent {
+                path: full.path.clone(),
+                available_bytes: full.available_bytes,
+                total_bytes: full.total_bytes,
+            }),
+        ),
+        _ => return None,
+    };
+
+    Some(event)
+}
+
+fn phase_event(change: &StreamChange) -> (&'static str, serde_json::Value) {
+    (
+        "stream-phase",
+        to_json(StreamPhaseEvent {
+            session: change.stream_session_id,
+            name: change.name.clone(),
+            phase: change.phase,
+            reason: change.reason.clone(),
+        }),
+    )
+}
+
+fn congestion_event(congestion: &Congestion) -> (&'static str, serde_json::Value) {
+    (
+        "congestion",
+        to_json(CongestionEvent {
+            session: congestion.stream_session_id,
+            cause: congestion.cause,
+            bitrate: congestion.bitrate,
+            expected: congestion.expected,
+            behind: congestion.behind,
+        }),
+    )
+}
+
+fn levels_event(levels: &StreamAudioLevels) -> (&'static str, serde_json::Value) {
+    (
+        "audio-levels",
+        to_json(AudioLevelsEvent {
+            session: levels.stream_session_id,
+            approx_peak_db: levels.approx_peak_db,
+            approx_rms_db: levels.approx_rms_db,
+        }),
+    )
+}
+
+fn to_json(event: impl Serialize) -> serde_json::Value {
+    // the events only have fields which can't fail to serialize
+    serde_json::to_value(event).unwrap()
+}
+
+/// Publishes the events of streams, their phases, their anomalies, the
+/// congestion of their publishers and their audio levels to the feed.
+pub fn spawn_event_feed(data: Arc<AppData>) {
+    tokio::spawn(async move {
+        let (mut events, mut changes) = {
+            let repo = data.stream_repo.read();
+            (repo.events(), repo.changes())
+        };
+        let mut anomalies = data.anomaly_sender.subscribe();
+        let mut congestion = data.congestion_sender.subscribe();
+        let mut audio_levels = data.audio_level_sender.subscribe();
+        let mut levels_sent: HashMap<i32, Instant> = HashMap::new();
+
+        loop {
+            let event = tokio::select! {
+                event = events.recv() => {
+                    if let Ok(StreamType::StreamStopped(stopped)) = &event {
+                        levels_sent.remove(&stopped.stream_session_id);
+                    }
+                    event.map(|e| feed_event(&e))
+                }
+                change = changes.recv() => change.map(|c| Some(phase_event(&c))),
+                anomaly = anomalies.recv() => {
+                    anomaly.map(|a| feed_event(&StreamType::StreamAnomaly(a)))
+                }
+                congested = congestion.recv() => congested.map(|c| Some(congestion_event(&c))),
+                levels = audio_levels.recv() => {
+                    // levels are outdated by the next ones, so missing some
+                    // is fine
+                    if let Ok(levels) = levels {
+                        let now = Instant::now();
+                        let due = levels_sent
+                            .get(&levels.stream_session_id)
+                            .is_none_or(|sent| now - *sent >= LEVELS_INTERVAL);
+
+                        if due {
+                            levels_sent.insert(levels.stream_session_id, now);
+
+                            let (name, json) = levels_event(&levels);
+                            data.events.publish_live(name, json);
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            match event {
+                Ok(Some((name, json))) => data.events.publish(name, json),
+                Ok(None) => {}
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Event feed missed {} events", missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Sends the event feed as server-sent events, starting after the
+/// `Last-Event-ID` of a reconnecting consumer.
+pub async fn events(
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> Response<BoxBody> {
+    if !is_admin(&data, &headers) {
+        return error(StatusCode::UNAUTHORIZED, "Missing or invalid admin token");
+    }
+
+    let consumer = match data.events.join() {
+        Some(consumer) => consumer,
+        None => return error(StatusCode::SERVICE_UNAVAILABLE, "Too many event consumers"),
+    };
+
+    let last_id = headers
+        .get("Last-Event-ID")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|id| id.trim().parse().ok());
+    let (missed, receiver) = data.events.subscribe(last_id);
+
+    let (tx, rx) = mpsc::channel(16);
+    task::spawn(async move {
+        if let Err(e) = consume(consumer, missed, receiver, tx).await {
+            debug!("Stopped sending events: {:?}", e);
+        }
+    });
+
+    // proxies like nginx buffer responses unless told not to, which would
+    // hold events back until the buffer fills
+    Response::builder()
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-store")
+        .header("X-Accel-Buffering", "no")
+        .body(boxed(StreamBody::new(ReceiverStream::new(rx))))
+        .unwrap()
+}
+
+async fn consume(
+    consumer: ConsumerGuard,
+    missed: Option<Vec<FeedEvent>>,
+    mut receiver: broadcast::Receiver<FeedEvent>,
+    tx: mpsc::Sender<Result<Bytes, Infallible>>,
+) -> anyhow::Result<()> {
+    let keep_alive = consumer.0.keep_alive;
+
+    send(&tx, format!("retry: {}\n\n", RETRY_MS)).await?;
+    match missed {
+        Some(missed) => {
+            for event in missed {
+                send(&tx, event.encode()).await?;
+            }
+        }
+        // the consumer has to start over from the current state
+        None => send(&tx, "event: reset\ndata: {}\n\n".to_string()).await?,
+    }
+
+    loop {
+        // the keep-alive is only sent when there were no events for a while
+        let message = match timeout(keep_alive, receiver.recv()).await {
+            Ok(Ok(event)) => event.encode(),
+            Ok(Err(broadcast::error::RecvError::Lagged(missed))) => {
+                anyhow::bail!("consumer fell {} events behind", missed)
+            }
+            Ok(Err(broadcast::error::RecvError::Closed)) => return Ok(()),
+            Err(_) => ": keep-alive\n\n".to_string(),
+        };
+
+        send(&tx, message).await?;
+    }
+}
+
+/// Sends a message to a consumer, which has to take it in time.
+async fn send(tx: &mpsc::Sender<Result<Bytes, Infallible>>, message: String) -> anyhow::Result<()> {
+    timeout(IDLE_TIMEOUT, tx.send(Ok(message.into())))
+        .await
+        .context("consumer stopped reading")?
+        .map_err(|_| anyhow::anyhow!("consumer disconnected"))
+}
+
+fn error(status: StatusCode, message: &'static str) -> Response<BoxBody> {
+    Response::builder()
+        .status(status)
+        .body(boxed(body::Full::from(message)))
+        .unwrap()
+}
@@ -0,0 +1,234 @@
+use axum::{
+    body::{self, boxed, BoxBody},
+    extract::{Extension, Path},
+};
+use bytes::Bytes;
+use hyper::{Response, StatusCode};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use sh_media::Stream;
+use tracing::*;
+use ts_rs::TS;
+
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use crate::{archive::write_atomically, AppData};
+
+/// How many seconds players are told to wait before asking for a stream
+/// which isn't live yet again.
+const RETRY_AFTER_SECS: u32 = 5;
+
+/// What a player is told about an expected stream which isn't live yet, so
+/// it can show that the stream is coming and set up its decoders before it
+/// is.
+#[derive(Debug, Clone, Serialize, TS)]
+pub struct WaitingPage {
+    pub stream: String,
+    pub live: bool,
+    /// The codecs of the last session of the stream, as in the `codecs`
+    /// parameter of an MSE source buffer, if it was ever live.
+    pub codecs: Option<String>,
+    #[ts(type = "number")]
+    pub retry_after: u32,
+}
+
+/// A stream which is expected to be published, with what was last known
+/// about it.
+struct ExpectedStream {
+    codecs: Option<String>,
+    /// The waiting page, serialized ahead of time since players poll it
+    /// until the stream starts.
+    waiting: Bytes,
+}
+
+impl ExpectedStream {
+    fn new(name: &str, codecs: Option<String>) -> Self {
+        let page = WaitingPage {
+            stream: name.to_string(),
+            live: false,
+            codecs: codecs.clone(),
+            retry_after: RETRY_AFTER_SECS,
+        };
+
+        ExpectedStream {
+            codecs,
+            waiting: serde_json::to_vec(&page).unwrap_or_default().into(),
+        }
+    }
+}
+
+/// The last known codecs of expected streams, as kept in the cache file.
+#[derive(Default, Serialize, Deserialize)]
+struct CodecCache {
+    codecs: HashMap<String, String>,
+}
+
+/// The streams which are expected to be published, like those of the
+/// encoders of a venue, which are set up before their publishers connect.
+///
+/// The codecs of every session are kept in a JSON file, so players can be
+/// told them before the stream starts, also after a restart.
+pub struct ExpectedStreams {
+    streams: RwLock<HashMap<String, ExpectedStream>>,
+    /// Where the last known codecs are persisted, if anywhere.
+    path: Option<PathBuf>,
+    sync: bool,
+    /// Serializes writes of the cache file, so an older snapshot never
+    /// replaces a newer one.
+    persisting: tokio::sync::Mutex<()>,
+}
+
+impl ExpectedStreams {
+    pub fn new(names: &[String], path: Option<PathBuf>, sync: bool) -> Self {
+        let streams = names
+            .iter()
+            .map(|name| (name.clone(), ExpectedStream::new(name, None)))
+            .collect();
+
+        ExpectedStreams {
+            streams: RwLock::new(streams),
+            path,
+            sync,
+            persisting: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Reads the last known codecs of the expected streams from the cache
+    /// file, if there is one.
+    pub async fn load(&self) -> anyhow::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let json = match tokio::fs::read(path).await {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let cache: CodecCache = serde_json::from_slice(&json)?;
+
+        let mut streams = self.streams.write();
+        for (name, codecs) in cache.codecs {
+            if let Some(stream) = streams.get_mut(&name) {
+                *stream = ExpectedStream::new(&name, Some(codecs));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn is_expected(&self, name: &str) -> bool {
+        self.streams.read().contains_key(name)
+    }
+
+    /// The codecs of the last session of an expected stream.
+    pub fn codecs(&self, name: &str) -> Option<String> {
+        self.streams.read().get(name)?.codecs.clone()
+    }
+
+    fn waiting_page(&self, name: &str) -> Option<Bytes> {
+        Some(self.streams.read().get(name)?.waiting.clone())
+    }
+
+    /// Keeps the codecs of a new session of an expected stream, if they
+    /// changed since the last one.
+    pub async fn remember(&self, name: &str, source: &[Stream]) {
+        let codecs = match sh_transport_mse::codec_string(source) {
+            Ok(codecs) => codecs,
+            Err(_) => return,
+        };
+
+        {
+            let mut streams = self.streams.write();
+            let stream = match streams.get_mut(name) {
+                Some(stream) if stream.codecs.as_ref() != Some(&codecs) => stream,
+                _ => return,
+            };
+
+            debug!("Codecs of expected stream '{}' are now {}", name, codecs);
+            *stream = ExpectedStream::new(name, Some(codecs));
+        }
+
+        self.persist().await;
+    }
+
+    async fn persist(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+
+        let _persisting = self.persisting.lock().await;
+        let json = {
+            let streams = self.streams.read();
+            let codecs = streams
+                .iter()
+                .filter_map(|(name, s)| Some((name.clone(), s.codecs.clone()?)))
+                .collect();
+
+            serde_json::to_vec_pretty(&CodecCache { codecs })
+        };
+
+        let result = match json {
+            Ok(json) => write_atomically(path, &json, self.sync).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            warn!("Failed to persist codecs to {}: {:?}", path.display(), e);
+        }
+    }
+}
+
+/// Tells a player whether an expected stream is live yet, along with the
+/// codecs it was last published with, so it can poll until the stream
+/// starts.
+pub async fn waiting_page(
+    Path(stream): Path<String>,
+    Extension(data): Extension<Arc<AppData>>,
+) -> Response<BoxBody> {
+    let waiting = match data.expected.waiting_page(&stream) {
+        Some(waiting) => waiting,
+        None => {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(boxed(body::Full::from(
+                    "No stream is expected by that name",
+                )))
+                .unwrap()
+        }
+    };
+
+    let live = {
+        let repo = data.stream_repo.read();
+
+        repo.stream_mapping
+            .get(&stream)
+            .and_then(|id| repo.streams.get(id))
+            .filter(|entry| entry.is_live())
+            .map(|entry| sh_transport_mse::codec_string(&entry.streams).ok())
+    };
+
+    let response = Response::builder()
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-store")
+        .header("Access-Control-Allow-Origin", "*");
+
+    match live {
+        Some(codecs) => {
+            let page = WaitingPage {
+                stream,
+                live: true,
+                codecs,
+                retry_after: 0,
+            };
+            let json = serde_json::to_vec(&page).unwrap_or_default();
+
+            response.body(boxed(body::Full::from(json))).unwrap()
+        }
+        None => response
+            .header("Retry-After", RETRY_AFTER_SECS.to_string())
+            .body(boxed(body::Full::from(waiting)))
+            .unwrap(),
+    }
+}
@@ -0,0 +1,237 @@
+use axum::{
+    body::{self, boxed, BoxBody, StreamBody},
+    extract::{Extension, Path, Query},
+    http::HeaderMap,
+};
+use bytes::Bytes;
+use hyper::{Response, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use sh_fmp4::ProgressiveMp4;
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::mpsc,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::*;
+
+use std::{io::SeekFrom, ops::Range, path::PathBuf, sync::Arc};
+
+use crate::{
+    archive::is_valid_name,
+    diagnostics::is_admin,
+    download::{mp4_files, read_indexes, READ_SIZE},
+    jobs::{self, JobKind, JobProgress, JobState},
+    AppData,
+};
+
+#[derive(Deserialize)]
+pub struct ExportParams {
+    /// Seconds from the start of the recording.
+    start: f64,
+    end: f64,
+    /// Cuts exactly at `start` instead of at the keyframe before it, which
+    /// needs the start to be transcoded.
+    #[serde(default)]
+    accurate: bool,
+}
+
+/// Starts exporting the part of a recording between two timestamps as a
+/// new progressive MP4 file, returning the ID of the export job.
+pub async fn start_export(
+    Path(id): Path<String>,
+    Query(params): Query<ExportParams>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> Response<BoxBody> {
+    if !is_admin(&data, &headers) {
+        return error(StatusCode::UNAUTHORIZED, "Missing or invalid admin token");
+    }
+
+    if !is_valid_name(&id) {
+        return error(StatusCode::BAD_REQUEST, "Invalid recording name");
+    }
+
+    if !(params.start >= 0.0 && params.end > params.start) {
+        return error(StatusCode::BAD_REQUEST, "The end has to be after the start");
+    }
+
+    // there is no encoder on the server to re-encode the frames before
+    // the first keyframe with
+    if params.accurate {
+        return error(
+            StatusCode::NOT_IMPLEMENTED,
+            "Frame-accurate exports need transcoding, which is not supported",
+        );
+    }
+
+    if mp4_files(&data, &id).await.is_empty() {
+        return error(StatusCode::NOT_FOUND, "No such recording");
+    }
+
+    let job = jobs::submit(
+        &data,
+        JobKind::Export {
+            recording: id,
+            start: params.start,
+            end: params.end,
+        },
+    )
+    .await;
+
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .status(StatusCode::ACCEPTED)
+        .body(boxed(body::Full::from(json!({ "job": job }).to_string())))
+        .unwrap()
+}
+
+/// Downloads the file of a finished export job.
+pub async fn export_file(
+    Path(job): Path<String>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> Response<BoxBody> {
+    if !is_admin(&data, &headers) {
+        return error(StatusCode::UNAUTHORIZED, "Missing or invalid admin token");
+    }
+
+    let status = match data.jobs.get(&job) {
+        Some(status) if matches!(status.kind, JobKind::Export { .. }) => status,
+        _ => return error(StatusCode::NOT_FOUND, "No such export"),
+    };
+
+    if status.state != JobState::Finished {
+        return error(StatusCode::CONFLICT, "The export has not finished");
+    }
+
+    let mut file = match tokio::fs::File::open(export_path(&data, &job)).await {
+        Ok(file) => file,
+        Err(_) => return error(StatusCode::NOT_FOUND, "The exported file is gone"),
+    };
+
+    let (tx, rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        loop {
+            let mut buf = vec![0; READ_SIZE];
+            match file.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(read) => {
+                    buf.truncate(read);
+                    if tx.send(Ok(Bytes::from(buf))).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    let _ = tx.send(Err(e)).await;
+                    break;
+                }
+            }
+        }
+    });
+
+    let mut response = Response::builder()
+        .header("Content-Type", "video/mp4")
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}.mp4\"", job),
+        )
+        .status(StatusCode::OK);
+    if let Some(size) = status.result.as_ref().and_then(|r| r["size"].as_u64()) {
+        response = response.header("Content-Length", size);
+    }
+
+    response
+        .body(boxed(StreamBody::new(ReceiverStream::new(rx))))
+        .unwrap()
+}
+
+/// Exports a part of a recording for a job. The file only appears once it
+/// has been completely written.
+pub(crate) async fn run_export(
+    data: &AppData,
+    job: &str,
+    recording: &str,
+    range: Range<f64>,
+    progress: &JobProgress,
+) -> anyhow::Result<serde_json::Value> {
+    let files = mp4_files(data, recording).await;
+    if files.is_empty() {
+        anyhow::bail!("recording {} has no files", recording);
+    }
+
+    let indexes = read_indexes(files.clone()).await?;
+    let (mp4, start) =
+        tokio::task::spawn_blocking(move || ProgressiveMp4::remux_range(&indexes, range)).await??;
+
+    let size = mp4.size();
+    let path = export_path(data, job);
+    tokio::fs::create_dir_all(&data.export_dir).await?;
+
+    let partial = path.with_extension("mp4.part");
+    let mut output = tokio::fs::File::create(&partial).await?;
+    let result = write_file(&mp4, &files, &mut output, |written| {
+        progress.set(written as f64 / size as f64)
+    })
+    .await;
+
+    if let Err(e) = result {
+        let _ = tokio::fs::remove_file(&partial).await;
+        return Err(e);
+    }
+
+    output.sync_all().await?;
+    tokio::fs::rename(&partial, &path).await?;
+
+    info!("Exported {} as {}", recording, path.display());
+
+    // the start is before the requested one when it is not on a keyframe
+    Ok(json!({ "start": start, "size": size }))
+}
+
+fn export_path(data: &AppData, job: &str) -> PathBuf {
+    data.export_dir.join(format!("{}.mp4", job))
+}
+
+/// Writes the header of the remuxed file and then its media data, copied
+/// from the recorded files, reporting how many bytes have been written.
+async fn write_file(
+    mp4: &ProgressiveMp4,
+    files: &[PathBuf],
+    output: &mut tokio::fs::File,
+    progress: impl Fn(u64),
+) -> anyhow::Result<()> {
+    output.write_all(&mp4.header).await?;
+    let mut written = mp4.header.len() as u64;
+
+    let mut open = None;
+
+    for (i, range) in &mp4.chunks {
+        if open.as_ref().map(|(current, _)| current) != Some(i) {
+            open = Some((*i, tokio::fs::File::open(&files[*i]).await?));
+        }
+        let (_, file) = open.as_mut().unwrap();
+
+        file.seek(SeekFrom::Start(range.start)).await?;
+
+        let mut remaining = range.end - range.start;
+        while remaining > 0 {
+            let mut buf = vec![0; remaining.min(READ_SIZE as u64) as usize];
+            file.read_exact(&mut buf).await?;
+            remaining -= buf.len() as u64;
+
+            output.write_all(&buf).await?;
+            written += buf.len() as u64;
+            progress(written);
+        }
+    }
+
+    Ok(())
+}
+
+fn error(status: StatusCode, message: &'static str) -> Response<BoxBody> {
+    Response::builder()
+        .status(status)
+        .body(boxed(body::Full::from(message)))
+        .unwrap()
+}
@@ -0,0 +1,666 @@
+use anyhow::Context;
+use sh_media::{CodecTypeInfo, Fraction, Frame, FrameReadFilter, MediaTime, Stream};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    time::sleep,
+};
+use tracing::*;
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    audio_levels, authenticate_stream, ingest, supervisor::Backoff, AppData, IngestSource,
+};
+
+/// Audio quieter than this is considered silent.
+const SILENCE_DB: f32 = -60.0;
+
+/// How often inputs which are not live are looked up again.
+const INPUT_RETRY: Duration = Duration::from_secs(1);
+
+/// The timebase which timestamps are made continuous in when switching.
+const OUTPUT_TIMEBASE: Fraction = Fraction::new(1, 90000);
+
+/// The gap between the last frame of one input and the first frame of the
+/// next, about one video frame.
+const SWITCH_GAP: u64 = 90000 / 30;
+
+/// How many events of the inputs are queued before the frames of inputs
+/// which aren't played are dropped.
+const EVENT_QUEUE_LEN: usize = 256;
+
+/// Stored as the active input before there is one.
+const NO_INPUT: usize = usize::MAX;
+
+/// How eagerly a failover channel switches between its inputs.
+#[derive(Debug, Clone, Copy)]
+pub struct FailoverConfig {
+    /// How long video or sound has to be missing before an input is given
+    /// up on.
+    pub loss_after: Duration,
+    /// How long a preferred input has to be healthy before switching back
+    /// to it, so a flaky input doesn't cause constant switching.
+    pub recover_after: Duration,
+}
+
+/// Publishes `stream_key` as a channel which plays the first healthy stream
+/// of `inputs`, in order of preference.
+pub fn spawn_failover_channel(
+    stream_key: String,
+    inputs: Vec<String>,
+    config: FailoverConfig,
+    data: Arc<AppData>,
+) {
+    tokio::spawn(async move {
+        let mut backoff = Backoff::new(INPUT_RETRY);
+
+        loop {
+            let started = Instant::now();
+            let result = run_channel(&stream_key, &inputs, config, data.clone()).await;
+            if let Err(e) = &result {
+                error!("Failover channel with inputs {:?} failed: {:?}", inputs, e);
+            }
+
+            if !data.supervisor.should_restart(&result) {
+                break;
+            }
+
+            sleep(backoff.next(started.elapsed())).await;
+        }
+    });
+}
+
+async fn run_channel(
+    stream_key: &str,
+    inputs: &[String],
+    config: FailoverConfig,
+    data: Arc<AppData>,
+) -> anyhow::Result<()> {
+    let mut filter = FailoverReadFilter::new(inputs.to_vec(), config, data.clone());
+
+    // only go live once there is something to show
+    filter.start().await?;
+
+    let mut client = data.client.clone();
+    let (id, name) = authenticate_stream(&mut client, stream_key, false).await?;
+
+    info!(
+        "Starting failover channel {} with inputs {:?}",
+        name, inputs
+    );
+
+    ingest(id, name, IngestSource::new(Box::new(filter)), data).await
+}
+
+pub(crate) enum InputEvent {
+    Started(usize, Vec<Stream>),
+    Frame(usize, Frame),
+    Ended(usize),
+}
+
+#[derive(Default)]
+struct InputState {
+    streams: Option<Vec<Stream>>,
+    /// Whether the streams can replace the streams of the channel.
+    compatible: bool,
+    last_video: Option<Instant>,
+    last_sound: Option<Instant>,
+    healthy_since: Option<Instant>,
+}
+
+impl InputState {
+    fn start(&mut self, streams: Vec<Stream>, now: Instant) {
+        // give the audio time to prove it isn't silent
+        self.last_sound = streams.iter().any(|s| s.is_audio()).then_some(now);
+        self.streams = Some(streams);
+    }
+
+    fn observe(&mut self, frame: &Frame, now: Instant) {
+        if frame.stream.is_video() {
+            self.last_video = Some(now);
+        } else if let Some(amplitude) = audio_levels::frame_amplitude(frame) {
+            if audio_levels::to_db(amplitude) > SILENCE_DB {
+                self.last_sound = Some(now);
+            }
+        }
+    }
+
+    fn is_healthy(&self, config: &FailoverConfig, now: Instant) -> bool {
+        let has_audio = self
+            .streams
+            .iter()
+            .flatten()
+            .any(|stream| stream.is_audio());
+        let is_recent = |at: Option<Instant>| at.is_some_and(|at| now - at < config.loss_after);
+
+        self.streams.is_some()
+            && is_recent(self.last_video)
+            && (!has_audio || is_recent(self.last_sound))
+    }
+}
+
+/// A pull filter which reads frames from the first healthy input stream.
+///
+/// An input is healthy while it has video and its audio, if any, is not
+/// silent. Switches happen on a keyframe of the new input, and timestamps
+/// continue from the previous input.
+///
+/// The streams of the channel are announced once, so inputs whose codecs or
+/// parameter sets differ from the first input played are never switched to.
+pub struct FailoverReadFilter {
+    names: Vec<String>,
+    inputs: Vec<InputState>,
+    events: mpsc::Receiver<InputEvent>,
+    config: FailoverConfig,
+
+    active: Option<usize>,
+    /// The active input, shared with the tasks following the inputs.
+    active_input: Arc<AtomicUsize>,
+    pending: Option<usize>,
+    first: Option<Frame>,
+    announced: Option<Vec<Stream>>,
+
+    /// Added to the timestamps of the active input, in [`OUTPUT_TIMEBASE`].
+    offset: i64,
+    last_output: Option<u64>,
+}
+
+impl FailoverReadFilter {
+    pub fn new(names: Vec<String>, config: FailoverConfig, data: Arc<AppData>) -> Self {
+        let (tx, events) = mpsc::channel(EVENT_QUEUE_LEN);
+        let active_input = Arc::new(AtomicUsize::new(NO_INPUT));
+
+        for (index, name) in names.iter().enumerate() {
+            // only the frames of the active input have to be played, the
+            // others are just watched for their health
+            let active_input = active_input.clone();
+            let is_wanted = move |_: &Frame| active_input.load(Ordering::Relaxed) == index;
+
+            tokio::spawn(follow_input(
+                index,
+                name.clone(),
+                data.clone(),
+                tx.clone(),
+                is_wanted,
+            ));
+        }
+
+        Self::with_events(names, config, events, active_input)
+    }
+
+    fn with_events(
+        names: Vec<String>,
+        config: FailoverConfig,
+        events: mpsc::Receiver<InputEvent>,
+        active_input: Arc<AtomicUsize>,
+    ) -> Self {
+        FailoverReadFilter {
+            inputs: names.iter().map(|_| InputState::default()).collect(),
+            names,
+            events,
+            config,
+            active: None,
+            active_input,
+            pending: None,
+            first: None,
+            announced: None,
+            offset: 0,
+            last_output: None,
+        }
+    }
+
+    async fn next_frame(&mut self) -> anyhow::Result<Frame> {
+        loop {
+            let event = self
+                .events
+                .recv()
+                .await
+                .context("all failover inputs stopped")?;
+
+            if let Some(frame) = self.handle(event) {
+                return Ok(frame);
+            }
+        }
+    }
+
+    fn handle(&mut self, event: InputEvent) -> Option<Frame> {
+        let now = Instant::now();
+
+        let (index, frame) = match event {
+            InputEvent::Started(index, streams) => {
+                debug!("Failover input {} started", self.names[index]);
+
+                let input = &mut self.inputs[index];
+                input.compatible = self
+                    .announced
+                    .as_deref()
+                    .is_none_or(|announced| is_compatible(announced, &streams));
+                if !input.compatible {
+                    warn!(
+                        "Failover input {} has other codecs than the channel and won't be played",
+                        self.names[index]
+                    );
+                }
+
+                input.start(streams, now);
+                (index, None)
+            }
+            InputEvent::Ended(index) => {
+                debug!("Failover input {} ended", self.names[index]);
+                self.inputs[index] = InputState::default();
+                (index, None)
+            }
+            InputEvent::Frame(index, frame) => {
+                self.inputs[index].observe(&frame, now);
+                (index, Some(frame))
+            }
+        };
+
+        self.choose_input(now);
+
+        let frame = frame?;
+
+        if Some(index) == self.pending && frame.stream.is_video() && frame.is_keyframe() {
+            info!("Switching to failover input {}", self.names[index]);
+
+            self.active = Some(index);
+            self.active_input.store(index, Ordering::Relaxed);
+            self.pending = None;
+            self.rebase(&frame);
+
+            if self.announced.is_none() {
+                self.announce(index);
+            }
+        }
+
+        if Some(index) != self.active {
+            return None;
+        }
+
+        Some(self.retime(frame))
+    }
+
+    fn choose_input(&mut self, now: Instant) {
+        let config = self.config;
+
+        for input in &mut self.inputs {
+            if !input.compatible || !input.is_healthy(&config, now) {
+                input.healthy_since = None;
+            } else if input.healthy_since.is_none() {
+                input.healthy_since = Some(now);
+            }
+        }
+
+        let active_is_healthy = self
+            .active
+            .is_some_and(|i| self.inputs[i].healthy_since.is_some());
+
+        let candidate = if active_is_healthy {
+            // only go back to a preferred input once it has stayed healthy
+            self.inputs[..self.active.unwrap()]
+                .iter()
+                .position(|input| {
+                    input
+                        .healthy_since
+                        .is_some_and(|since| now - since >= config.recover_after)
+                })
+        } else {
+            self.inputs
+                .iter()
+                .position(|input| input.healthy_since.is_some())
+        };
+
+        let pending = candidate.filter(|&i| Some(i) != self.active);
+        if let Some(index) = pending.filter(|_| pending != self.pending) {
+            debug!(
+                "Waiting for a keyframe to switch to failover input {}",
+                self.names[index]
+            );
+        }
+
+        self.pending = pending;
+    }
+
+    /// Makes the streams of the first input played the streams of the
+    /// channel, which the other inputs have to match.
+    fn announce(&mut self, index: usize) {
+        let announced = self.inputs[index].streams.clone().unwrap_or_default();
+
+        for (input, name) in self.inputs.iter_mut().zip(&self.names) {
+            let streams = input.streams.as_deref().unwrap_or_default();
+
+            input.compatible = is_compatible(&announced, streams);
+            if input.streams.is_some() && !input.compatible {
+                warn!(
+                    "Failover input {} has other codecs than the channel and won't be played",
+                    name
+                );
+            }
+        }
+
+        self.announced = Some(announced);
+    }
+
+    /// Makes the timestamps of a newly active input continue from the
+    /// previous one, starting at `frame`.
+    fn rebase(&mut self, frame: &Frame) {
+        let time = frame.time.in_base(OUTPUT_TIMEBASE);
+        let start = self.last_output.map_or(0, |last| last + SWITCH_GAP);
+
+        self.offset = start as i64 - time.dts.unwrap_or(time.pts) as i64;
+    }
+
+    fn retime(&mut self, mut frame: Frame) -> Frame {
+        let offset = self.offset;
+        let shift = |ts: u64| (ts as i64 + offset).max(0) as u64;
+
+        let time = frame.time.in_base(OUTPUT_TIMEBASE);
+        let output = MediaTime {
+            pts: shift(time.pts),
+            dts: time.dts.map(shift),
+            timebase: OUTPUT_TIMEBASE,
+        };
+
+        let end = output.dts.unwrap_or(output.pts);
+        self.last_output = Some(self.last_output.map_or(end, |last| last.max(end)));
+
+        frame.time = output.in_base(frame.time.timebase);
+        frame
+    }
+}
+
+#[async_trait::async_trait]
+impl FrameReadFilter for FailoverReadFilter {
+    /// Waits for the first input to become healthy and returns its streams.
+    /// Calling this again after it has returned does not wait.
+    async fn start(&mut self) -> anyhow::Result<Vec<Stream>> {
+        if self.first.is_none() && self.active.is_none() {
+            let frame = self.next_frame().await?;
+            self.first = Some(frame);
+        }
+
+        self.announced
+            .clone()
+            .context("failover input stopped while starting")
+    }
+
+    async fn read(&mut self) -> anyhow::Result<Frame> {
+        if let Some(frame) = self.first.take() {
+            return Ok(frame);
+        }
+
+        self.next_frame().await
+    }
+}
+
+/// Whether frames of `streams` can follow frames of the `announced`
+/// streams, without a decoder having to be set up again.
+fn is_compatible(announced: &[Stream], streams: &[Stream]) -> bool {
+    announced.len() == streams.len()
+        && announced.iter().zip(streams).all(|(a, b)| {
+            if a.codec.name != b.codec.name {
+                return false;
+            }
+
+            match (&a.codec.properties, &b.codec.properties) {
+                (CodecTypeInfo::Video(a), CodecTypeInfo::Video(b)) => {
+                    a.width == b.width
+                        && a.height == b.height
+                        && a.parameter_sets() == b.parameter_sets()
+                }
+                (CodecTypeInfo::Audio(a), CodecTypeInfo::Audio(b)) => {
+                    a.sample_rate == b.sample_rate
+                        && a.extra.decoder_specific_data() == b.extra.decoder_specific_data()
+                }
+                _ => false,
+            }
+        })
+}
+
+/// Forwards the frames of the named stream whenever it is live, until the
+/// receiving filter is dropped.
+///
+/// Frames for which `is_wanted` returns false are dropped while the filter
+/// is behind, instead of holding up the other inputs.
+pub(crate) async fn follow_input<F>(
+    index: usize,
+    name: String,
+    data: Arc<AppData>,
+    events: mpsc::Sender<InputEvent>,
+    is_wanted: F,
+) where
+    F: Fn(&Frame) -> bool + Send + 'static,
+{
+    while !events.is_closed() {
+        let receiver = {
+            let repo = data.stream_repo.read();
+
+            repo.stream_mapping
+                .get(&name)
+                .and_then(|id| repo.streams.get(id))
+                .map(|state| state.queue.get_receiver())
+        };
+
+        if let Some(mut receiver) = receiver {
+            if let Ok(streams) = receiver.start().await {
+                if events
+                    .send(InputEvent::Started(index, streams))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+
+                while let Ok(frame) = receiver.read().await {
+                    let wanted = is_wanted(&frame);
+
+                    if !send_frame(&events, InputEvent::Frame(index, frame), wanted).await {
+                        return;
+                    }
+                }
+            }
+
+            if events.send(InputEvent::Ended(index)).await.is_err() {
+                return;
+            }
+        }
+
+        sleep(INPUT_RETRY).await;
+    }
+}
+
+/// Sends a frame event, or drops it if the channel is full and the frame
+/// isn't wanted. Returns false once the receiver is gone.
+async fn send_frame(events: &mpsc::Sender<InputEvent>, event: InputEvent, wanted: bool) -> bool {
+    match events.try_send(event) {
+        Ok(()) => true,
+        Err(TrySendError::Full(event)) if wanted => events.send(event).await.is_ok(),
+        Err(TrySendError::Full(_)) => true,
+        Err(TrySendError::Closed(_)) => false,
+    }
+}
+
+#[cfg(test)]
+fn test_stream(sps: &[u8]) -> Stream {
+    use sh_media::{BitstreamFraming, CodecInfo, VideoCodecInfo, VideoCodecSpecificInfo};
+
+    Stream {
+        id: 0,
+        codec: Arc::new(CodecInfo {
+            name: "h264",
+            properties: CodecTypeInfo::Video(VideoCodecInfo {
+                width: 320,
+                height: 240,
+                extra: VideoCodecSpecificInfo::H264 {
+                    bitstream_format: BitstreamFraming::FourByteLength,
+                    profile_indication: sps[1],
+                    profile_compatibility: sps[2],
+                    level_indication: sps[3],
+                    sps: Arc::new(sps.to_vec()),
+                    pps: Arc::new(vec![0x68, 0xce, 0x3c, 0x80]),
+                },
+            }),
+        }),
+        timebase: OUTPUT_TIMEBASE,
+    }
+}
+
+#[cfg(test)]
+fn test_frame(stream: &Stream, pts: u64, keyframe: bool) -> Frame {
+    use sh_media::FrameDependency;
+
+    Frame {
+        time: MediaTime {
+            pts,
+            dts: None,
+            timebase: stream.timebase,
+        },
+        dependency: if keyframe {
+            FrameDependency::None
+        } else {
+            FrameDependency::Backwards
+        },
+        buffer: bytes::Bytes::from_static(&[0; 16]),
+        stream: stream.clone(),
+        received: Instant::now(),
+        metadata: None,
+    }
+}
+
+#[cfg(test)]
+const TEST_SPS: [u8; 8] = [0x67, 0x42, 0xc0, 0x1e, 0xf4, 0x0a, 0x0f, 0xc8];
+
+#[cfg(test)]
+fn test_filter() -> FailoverReadFilter {
+    let (_, events) = mpsc::channel(1);
+    let config = FailoverConfig {
+        loss_after: Duration::from_secs(10),
+        recover_after: Duration::ZERO,
+    };
+
+    FailoverReadFilter::with_events(
+        vec!["main".into(), "backup".into()],
+        config,
+        events,
+        Arc::new(AtomicUsize::new(NO_INPUT)),
+    )
+}
+
+#[test]
+fn switches_back_to_preferred_input_on_keyframe() {
+    let stream = test_stream(&TEST_SPS);
+    let mut filter = test_filter();
+
+    filter.handle(InputEvent::Started(1, vec![stream.clone()]));
+    let frame = filter.handle(InputEvent::Frame(1, test_frame(&stream, 9000, true)));
+    assert_eq!(frame.map(|f| f.time.pts), Some(0));
+    assert_eq!(filter.active_input.load(Ordering::Relaxed), 1);
+
+    filter.handle(InputEvent::Started(0, vec![stream.clone()]));
+
+    // the preferred input has to reach a keyframe first
+    assert!(filter
+        .handle(InputEvent::Frame(0, test_frame(&stream, 500, false)))
+        .is_none());
+    let frame = filter.handle(InputEvent::Frame(1, test_frame(&stream, 12000, false)));
+    assert_eq!(frame.map(|f| f.time.pts), Some(3000));
+
+    let frame = filter.handle(InputEvent::Frame(0, test_frame(&stream, 600, true)));
+    assert_eq!(frame.map(|f| f.time.pts), Some(3000 + SWITCH_GAP));
+    assert_eq!(filter.active_input.load(Ordering::Relaxed), 0);
+
+    assert!(filter
+        .handle(InputEvent::Frame(1, test_frame(&stream, 15000, false)))
+        .is_none());
+}
+
+#[test]
+fn refuses_inputs_with_other_parameter_sets() {
+    let stream = test_stream(&TEST_SPS);
+    let mut filter = test_filter();
+
+    filter.handle(InputEvent::Started(1, vec![stream.clone()]));
+    filter.handle(InputEvent::Frame(1, test_frame(&stream, 0, true)));
+
+    // the same codec at another level
+    let mut sps = TEST_SPS;
+    sps[3] = 0x1f;
+    let other = test_stream(&sps);
+
+    filter.handle(InputEvent::Started(0, vec![other.clone()]));
+    assert!(filter
+        .handle(InputEvent::Frame(0, test_frame(&other, 0, true)))
+        .is_none());
+    assert_eq!(filter.active, Some(1));
+
+    // and is taken once it restarts with the parameters of the channel
+    filter.handle(InputEvent::Ended(0));
+    filter.handle(InputEvent::Started(0, vec![stream.clone()]));
+    assert!(filter
+        .handle(InputEvent::Frame(0, test_frame(&stream, 0, true)))
+        .is_some());
+    assert_eq!(filter.active, Some(0));
+}
+
+#[test]
+fn announces_the_first_input_played() {
+    let stream = test_stream(&TEST_SPS);
+    let mut sps = TEST_SPS;
+    sps[3] = 0x1f;
+    let other = test_stream(&sps);
+
+    let mut filter = test_filter();
+
+    // either could be played until one of them is
+    filter.handle(InputEvent::Started(0, vec![other.clone()]));
+    filter.handle(InputEvent::Started(1, vec![stream.clone()]));
+    assert!(filter.inputs.iter().all(|input| input.compatible));
+
+    filter.handle(InputEvent::Frame(1, test_frame(&stream, 0, true)));
+    assert_eq!(filter.active, Some(1));
+    assert!(!filter.inputs[0].compatible);
+
+    assert!(filter
+        .handle(InputEvent::Frame(0, test_frame(&other, 0, true)))
+        .is_none());
+    assert_eq!(filter.active, Some(1));
+}
+
+#[tokio::test]
+async fn drops_unwanted_frames_when_behind() {
+    let stream = test_stream(&TEST_SPS);
+    let (tx, mut rx) = mpsc::channel(2);
+
+    for pts in 0..4 {
+        let event = InputEvent::Frame(1, test_frame(&stream, pts, false));
+        assert!(send_frame(&tx, event, false).await);
+    }
+
+    // wanted frames wait for room instead
+    let wanted = send_frame(
+        &tx,
+        InputEvent::Frame(0, test_frame(&stream, 4, false)),
+        true,
+    );
+    tokio::pin!(wanted);
+    assert!(futures::poll!(&mut wanted).is_pending());
+
+    let mut received = Vec::new();
+    while let Ok(InputEvent::Frame(_, frame)) = rx.try_recv() {
+        received.push(frame.time.pts);
+        if received.len() == 2 {
+            assert!(wanted.as_mut().await);
+        }
+    }
+    assert_eq!(received, [0, 1, 4]);
+
+    drop(rx);
+    let event = InputEvent::Frame(1, test_frame(&stream, 5, false));
+    assert!(!send_frame(&tx, event, false).await);
+}
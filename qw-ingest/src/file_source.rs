@@ -0,0 +1,357 @@
+use bytes::Bytes;
+use sh_fmp4::Mp4Index;
+use sh_ingest_rtmp::FlvReadFilter;
+use sh_media::{ByteReadFilter, Frame, FrameReadFilter, MediaTime, Stream};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+    time::sleep,
+};
+use tracing::*;
+
+use std::{
+    collections::VecDeque,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{authenticate_stream, ingest, supervisor::Backoff, AppData, IngestSource};
+
+/// How long to wait before playing a looped file again after it failed.
+const FILE_RETRY: Duration = Duration::from_secs(5);
+
+/// How much of an FLV file is read at a time.
+const FLV_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The containers which can be played.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FileKind {
+    /// Fragmented MP4, as written by recordings.
+    Mp4,
+    Flv,
+}
+
+impl FileKind {
+    fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "mp4" | "m4v" => Some(FileKind::Mp4),
+            "flv" => Some(FileKind::Flv),
+            _ => None,
+        }
+    }
+}
+
+/// Publishes `stream_key` as a stream played from the file at `path`, e.g.
+/// to test players without running an encoder.
+///
+/// A looped file is played again after it fails, with a backoff while it
+/// keeps failing, otherwise the stream ends with the file.
+pub fn spawn_file_stream(stream_key: String, path: PathBuf, looped: bool, data: Arc<AppData>) {
+    tokio::spawn(async move {
+        let mut backoff = Backoff::new(FILE_RETRY);
+
+        loop {
+            let started = Instant::now();
+            let result = run_stream(&stream_key, &path, looped, data.clone()).await;
+            if let Err(e) = &result {
+                error!("Playing {} failed: {:?}", path.display(), e);
+            }
+
+            if !looped || !data.supervisor.should_restart(&result) {
+                break;
+            }
+
+            sleep(backoff.next(started.elapsed())).await;
+        }
+    });
+}
+
+async fn run_stream(
+    stream_key: &str,
+    path: &Path,
+    looped: bool,
+    data: Arc<AppData>,
+) -> anyhow::Result<()> {
+    let mut filter = FileReadFilter::new(path.to_path_buf(), looped)?;
+
+    // fail before going live if the file can't be played
+    filter.start().await?;
+
+    let mut client = data.client.clone();
+    let (id, name) = authenticate_stream(&mut client, stream_key, false).await?;
+
+    info!("Starting stream {} from {}", name, path.display());
+
+    ingest(id, name, IngestSource::new(Box::new(filter)), data).await
+}
+
+/// A pull filter which plays an MP4 or FLV file in real time, as if it was
+/// being published by an encoder.
+///
+/// A looped file starts over when it ends, with its timestamps continuing
+/// from the end of the previous pass.
+pub struct FileReadFilter {
+    path: PathBuf,
+    kind: FileKind,
+    looped: bool,
+    source: Option<FileSource>,
+    streams: Vec<Stream>,
+
+    /// When the first frame was sent, and its time in seconds.
+    epoch: Option<(Instant, f64)>,
+    /// The time of the first frame of the file, in seconds.
+    first: Option<f64>,
+    /// The time of the last frame of the current pass, in seconds.
+    last: f64,
+    /// The time between the last two frames of the current pass, which the
+    /// next pass starts after.
+    last_interval: f64,
+    /// How far the current pass is from the start of the file, in seconds.
+    offset: f64,
+}
+
+impl FileReadFilter {
+    pub fn new(path: PathBuf, looped: bool) -> anyhow::Result<Self> {
+        let kind = FileKind::from_path(&path).ok_or_else(|| {
+            anyhow::anyhow!("Can't play {}, not an MP4 or FLV file", path.display())
+        })?;
+
+        Ok(FileReadFilter {
+            path,
+            kind,
+            looped,
+            source: None,
+            streams: Vec::new(),
+            epoch: None,
+            first: None,
+            last: 0.0,
+            last_interval: 0.0,
+            offset: 0.0,
+        })
+    }
+
+    /// Reads the next frame of the file, starting over at the end of a
+    /// looped file.
+    async fn next_frame(&mut self) -> anyhow::Result<Frame> {
+        loop {
+            let mut source = match self.source.take() {
+                Some(source) => source,
+                None => FileSource::open(&self.path, self.kind).await?.0,
+            };
+
+            match source.read().await? {
+                Some(frame) => {
+                    self.source = Some(source);
+                    return Ok(frame);
+                }
+                None if self.looped => {
+                    debug!("Reached the end of {}, starting over", self.path.display());
+
+                    let first = self.first.unwrap_or(0.0);
+                    self.offset += self.last - first + self.last_interval;
+                    self.last = first;
+                }
+                None => anyhow::bail!("Reached the end of {}", self.path.display()),
+            }
+        }
+    }
+
+    /// Moves a frame of the file to the current pass, and waits until it is
+    /// due.
+    async fn pace(&mut self, mut frame: Frame) -> Frame {
+        let timebase = frame.stream.timebase;
+        let seconds =
+            |ticks: u64| ticks as f64 * timebase.numerator as f64 / timebase.denominator as f64;
+
+        let time = seconds(frame.time.dts.unwrap_or(frame.time.pts));
+        let first = *self.first.get_or_insert(time);
+
+        if time > self.last {
+            self.last_interval = time - self.last;
+            self.last = time;
+        }
+
+        let offset = self.offset - first;
+        let shift = |ticks: u64| {
+            let shifted = seconds(ticks) + offset;
+            (shifted.max(0.0) * timebase.denominator as f64 / timebase.numerator as f64) as u64
+        };
+        frame.time = MediaTime {
+            pts: shift(frame.time.pts),
+            dts: frame.time.dts.map(shift),
+            timebase,
+        };
+
+        if let Some(stream) = self.streams.iter().find(|s| s.id == frame.stream.id) {
+            frame.stream = stream.clone();
+        }
+
+        let time = time + offset;
+        let (start, start_time) = *self.epoch.get_or_insert((Instant::now(), time));
+        if let Ok(due) = Duration::try_from_secs_f64(time - start_time) {
+            tokio::time::sleep_until((start + due).into()).await;
+        }
+
+        frame.received = Instant::now();
+        frame
+    }
+}
+
+#[async_trait::async_trait]
+impl FrameReadFilter for FileReadFilter {
+    async fn start(&mut self) -> anyhow::Result<Vec<Stream>> {
+        let (source, streams) = FileSource::open(&self.path, self.kind).await?;
+
+        debug!("Playing {} with {:?}", self.path.display(), streams);
+
+        self.source = Some(source);
+        self.streams = streams.clone();
+
+        Ok(streams)
+    }
+
+    async fn read(&mut self) -> anyhow::Result<Frame> {
+        let frame = self.next_frame().await?;
+
+        Ok(self.pace(frame).await)
+    }
+}
+
+/// Reads the frames of a file as fast as possible.
+enum FileSource {
+    Mp4(Mp4Source),
+    Flv {
+        filter: FlvReadFilter,
+        ended: Arc<AtomicBool>,
+    },
+}
+
+impl FileSource {
+    async fn open(path: &Path, kind: FileKind) -> anyhow::Result<(Self, Vec<Stream>)> {
+        match kind {
+            FileKind::Mp4 => {
+                let source = Mp4Source::open(path).await?;
+                let streams = source.streams.iter().map(|(_, s)| s.clone()).collect();
+
+                Ok((FileSource::Mp4(source), streams))
+            }
+            FileKind::Flv => {
+                let ended = Arc::new(AtomicBool::new(false));
+                let mut filter = FlvReadFilter::new(Box::new(FileByteReadFilter {
+                    file: File::open(path).await?,
+                    ended: ended.clone(),
+                }));
+                let streams = filter.start().await?;
+
+                Ok((FileSource::Flv { filter, ended }, streams))
+            }
+        }
+    }
+
+    /// Reads the next frame, or `None` at the end of the file.
+    async fn read(&mut self) -> anyhow::Result<Option<Frame>> {
+        match self {
+            FileSource::Mp4(source) => source.read().await,
+            FileSource::Flv { filter, ended } => match filter.read().await {
+                Ok(frame) => Ok(Some(frame)),
+                Err(_) if ended.load(Ordering::Relaxed) => Ok(None),
+                Err(e) => Err(e),
+            },
+        }
+    }
+}
+
+/// Reads the samples of a fragmented MP4 file, one fragment at a time.
+struct Mp4Source {
+    file: File,
+    index: Mp4Index,
+    /// The streams of the played tracks, by track ID.
+    streams: Vec<(u32, Stream)>,
+    fragment: usize,
+    frames: VecDeque<Frame>,
+}
+
+impl Mp4Source {
+    async fn open(path: &Path) -> anyhow::Result<Self> {
+        let index_path = path.to_path_buf();
+        let index = tokio::task::spawn_blocking(move || {
+            let mut file = std::io::BufReader::new(std::fs::File::open(index_path)?);
+            Mp4Index::read(&mut file)
+        })
+        .await??;
+
+        Ok(Mp4Source {
+            file: File::open(path).await?,
+            streams: index.streams()?,
+            index,
+            fragment: 0,
+            frames: VecDeque::new(),
+        })
+    }
+
+    async fn read(&mut self) -> anyhow::Result<Option<Frame>> {
+        loop {
+            if let Some(frame) = self.frames.pop_front() {
+                return Ok(Some(frame));
+            }
+
+            if self.fragment >= self.index.fragments.len() {
+                return Ok(None);
+            }
+
+            self.read_fragment().await?;
+            self.fragment += 1;
+        }
+    }
+
+    /// Reads the samples of the next fragment, if it belongs to a played
+    /// track.
+    async fn read_fragment(&mut self) -> anyhow::Result<()> {
+        let fragment = &self.index.fragments[self.fragment];
+        let stream = match self.streams.iter().find(|(id, _)| *id == fragment.track_id) {
+            Some((_, stream)) => stream.clone(),
+            None => return Ok(()),
+        };
+
+        let start = fragment.bytes.start;
+        let mut bytes = vec![0; (fragment.bytes.end - start) as usize];
+        self.file.seek(SeekFrom::Start(start)).await?;
+        self.file.read_exact(&mut bytes).await?;
+
+        let frames = sh_fmp4::fragment_frames(fragment, &stream, &bytes.into(), start)?;
+        self.frames.extend(frames);
+
+        Ok(())
+    }
+}
+
+/// Reads a file in chunks, noting when it ends.
+struct FileByteReadFilter {
+    file: File,
+    ended: Arc<AtomicBool>,
+}
+
+#[async_trait::async_trait]
+impl ByteReadFilter for FileByteReadFilter {
+    async fn start(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn read(&mut self) -> anyhow::Result<Bytes> {
+        let mut buf = vec![0; FLV_CHUNK_SIZE];
+        let n = self.file.read(&mut buf).await?;
+
+        if n == 0 {
+            self.ended.store(true, Ordering::Relaxed);
+        }
+
+        buf.truncate(n);
+
+        Ok(buf.into())
+    }
+}
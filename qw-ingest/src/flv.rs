@@ -0,0 +1,75 @@
+use axum::{
+    body::{self, boxed, BoxBody},
+    extract::{Extension, Path, Query, RawBody},
+};
+use bytes::Bytes;
+use hyper::{body::HttpBody, Body, Response, StatusCode};
+use serde::Deserialize;
+use sh_ingest_rtmp::FlvReadFilter;
+use sh_media::ByteReadFilter;
+use tracing::*;
+
+use std::sync::Arc;
+
+use crate::{authenticate_stream, ingest, AppData, IngestSource};
+
+#[derive(Debug, Deserialize)]
+pub struct FlvParams {
+    /// Whether the stream is listed, like the `public` app of RTMP.
+    #[serde(default)]
+    public: bool,
+}
+
+/// Reads the body of a request as it is uploaded.
+struct BodyReadFilter {
+    body: Body,
+}
+
+#[async_trait::async_trait]
+impl ByteReadFilter for BodyReadFilter {
+    async fn start(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn read(&mut self) -> anyhow::Result<Bytes> {
+        match self.body.data().await {
+            Some(bytes) => Ok(bytes?),
+            None => Ok(Bytes::new()),
+        }
+    }
+}
+
+/// Publishes a stream from an FLV file uploaded with a chunked request, for
+/// restreaming tools which can only push HTTP-FLV.
+///
+/// `stream` is the stream key. The response is sent once the upload ends.
+pub async fn publish(
+    Path(stream): Path<String>,
+    Query(params): Query<FlvParams>,
+    Extension(data): Extension<Arc<AppData>>,
+    RawBody(upload): RawBody,
+) -> Response<BoxBody> {
+    let mut client = data.client.clone();
+    let (id, name) = match authenticate_stream(&mut client, &stream, params.public).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            warn!("Failed to authenticate HTTP-FLV publisher: {:?}", e);
+            return Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(boxed(body::Full::from("Invalid stream key")))
+                .unwrap();
+        }
+    };
+
+    info!("Got a HTTP-FLV upload for {}", name);
+
+    let filter = FlvReadFilter::new(Box::new(BodyReadFilter { body: upload }));
+    if let Err(e) = ingest(id, name, IngestSource::new(Box::new(filter)), data).await {
+        debug!("HTTP-FLV ingest ended: {:?}", e);
+    }
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .body(boxed(body::Empty::new()))
+        .unwrap()
+}
@@ -0,0 +1,84 @@
+use axum::{
+    body::{self, boxed, BoxBody},
+    extract::{Extension, Path},
+    http::HeaderMap,
+};
+use hyper::{Response, StatusCode};
+use serde::Serialize;
+use ts_rs::TS;
+
+use std::sync::Arc;
+
+use crate::{diagnostics, AppData};
+
+/// The rolling stats of one stream of a live stream session, as measured
+/// when it is ingested.
+#[derive(Debug, Serialize, TS)]
+pub struct TrackStats {
+    #[ts(type = "number")]
+    pub stream: u32,
+    pub codec: &'static str,
+    /// In kbit/s, over the last few seconds.
+    pub bitrate: f64,
+    pub frame_rate: f64,
+    /// How many frames the last GOP of a video stream had.
+    pub gop_length: Option<u32>,
+    /// How many seconds apart the last keyframes of a video stream were.
+    pub keyframe_interval: Option<f64>,
+}
+
+/// Returns the bitrate, frame rate and GOP structure of every stream of a
+/// live stream.
+pub async fn stream_stats(
+    Path(stream): Path<String>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> Response<BoxBody> {
+    if !diagnostics::is_admin(&data, &headers) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(boxed(body::Full::from("Missing or invalid admin token")))
+            .unwrap();
+    }
+
+    let tracks = {
+        let repo = data.stream_repo.read();
+
+        repo.stream_mapping
+            .get(&stream)
+            .and_then(|id| repo.streams.get(id))
+            .map(|entry| {
+                entry
+                    .frame_stats
+                    .all()
+                    .into_iter()
+                    .filter_map(|(id, stats)| {
+                        let source = entry.streams.iter().find(|s| s.id == id)?;
+
+                        Some(TrackStats {
+                            stream: id,
+                            codec: source.codec.name,
+                            bitrate: stats.bitrate,
+                            frame_rate: stats.frame_rate,
+                            gop_length: stats.gop_length,
+                            keyframe_interval: stats.keyframe_interval,
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            })
+    };
+
+    match tracks {
+        Some(tracks) => Response::builder()
+            .header("Content-Type", "application/json")
+            .header("Cache-Control", "no-cache")
+            .body(boxed(body::Full::from(
+                serde_json::to_vec(&tracks).unwrap_or_default(),
+            )))
+            .unwrap(),
+        None => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(boxed(body::Full::from("No live stream with that name")))
+            .unwrap(),
+    }
+}
@@ -0,0 +1,526 @@
+use axum::{
+    body::{self, boxed, BoxBody, StreamBody},
+    extract::{Extension, Path, Query},
+    http::{
+        header::{HOST, IF_NONE_MATCH},
+        HeaderMap,
+    },
+    response::IntoResponse,
+};
+use hyper::{http::response::Builder, Response, StatusCode};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use sh_media::ByteStreamWriteFilter;
+use sh_transport_hls::HlsPlaylist;
+use sh_transport_mse::ResumeToken;
+use tracing::*;
+use ts_rs::TS;
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{viewer_auth, AppData};
+
+/// How long playlist and part requests wait for a part which isn't there
+/// yet, before they are answered anyway.
+const BLOCKING_TIMEOUT: Duration = Duration::from_secs(6);
+
+/// Query parameters of HLS playlist requests.
+#[derive(Deserialize)]
+pub struct HlsParams {
+    /// A token from an MSE session of the viewer, to continue where it left
+    /// off when it switches over to HLS.
+    pub resume: Option<String>,
+    /// The segment a low-latency HLS player waits for the playlist to have.
+    #[serde(rename = "_HLS_msn")]
+    pub msn: Option<u64>,
+    /// The part of the `msn` segment the player waits for.
+    #[serde(rename = "_HLS_part")]
+    pub part: Option<usize>,
+    /// `YES` or `v2` for a delta update, which leaves out the segments the
+    /// player already has.
+    #[serde(rename = "_HLS_skip")]
+    pub skip: Option<String>,
+}
+
+/// Query parameters of initialization segment requests.
+#[derive(Deserialize)]
+pub struct InitParams {
+    /// The publisher session whose initialization segment the playlist
+    /// refers to, after the publisher reconnected.
+    pub session: Option<u64>,
+}
+
+/// Makes an entity tag of a response body, so players and caches can ask
+/// whether it changed with `If-None-Match`.
+fn entity_tag(body: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+/// Whether a request already has the body with the tag `etag`.
+fn is_not_modified(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == etag || tag == "*")
+}
+
+/// Finds the playlist of a stream, along with its session.
+///
+/// The renditions of the ABR ladder of a stream are found as if they were
+/// streams named `<stream>_<rendition>`, like `app_720p`, with the session
+/// of the stream.
+fn find_playlist(data: &AppData, stream: &str) -> Option<(i32, Arc<RwLock<HlsPlaylist>>)> {
+    let repo = data.stream_repo.read();
+
+    if let Some(&stream_id) = repo.stream_mapping.get(stream) {
+        let playlist = repo.streams.get(&stream_id)?.hls.clone()?;
+
+        return Some((stream_id, playlist));
+    }
+
+    let (source, rendition) = stream.rsplit_once('_')?;
+    let stream_id = *repo.stream_mapping.get(source)?;
+    let playlist = repo
+        .streams
+        .get(&stream_id)?
+        .rendition_hls
+        .get(rendition)?
+        .clone();
+
+    Some((stream_id, playlist))
+}
+
+/// Returns a token which resumes an MSE viewer at the start of an HLS
+/// segment of a stream, so it can switch over from HLS.
+pub fn segment_resume_token(data: &AppData, stream: &str, sequence: u64) -> Option<ResumeToken> {
+    let (session, playlist) = find_playlist(data, stream)?;
+    let keyframe = playlist.read().segment_start(sequence)?;
+
+    Some(ResumeToken {
+        session,
+        start: keyframe,
+        keyframe,
+    })
+}
+
+fn error(status: StatusCode, message: &'static str) -> Response<body::Full<bytes::Bytes>> {
+    // cast receivers only see why a request failed with CORS headers
+    Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .status(status)
+        .body(body::Full::from(message))
+        .unwrap()
+}
+
+/// Adds the caching headers of a response which caches may keep for
+/// `max_age` seconds. With viewer authentication a response is only for the
+/// viewer who asked for it, so neither edges nor browsers may keep it.
+fn cache_headers(response: Builder, data: &AppData, max_age: u32) -> Builder {
+    if data.viewer_auth.is_some() {
+        response
+            .header("Cache-Control", "private, no-store")
+            .header("Vary", "Cookie")
+    } else {
+        response.header("Cache-Control", format!("public, max-age={}", max_age))
+    }
+}
+
+/// Finds the playlist of a stream, if the viewer may watch it.
+async fn authorized_playlist(
+    data: &AppData,
+    stream: &str,
+    headers: &HeaderMap,
+) -> Result<(i32, Arc<RwLock<HlsPlaylist>>), Response<body::Full<bytes::Bytes>>> {
+    if let Err(e) = viewer_auth::authorize_viewer(data, headers).await {
+        debug!("Rejected HLS viewer of '{}': {:?}", stream, e);
+        return Err(error(StatusCode::UNAUTHORIZED, "Not logged in"));
+    }
+
+    find_playlist(data, stream)
+        .ok_or_else(|| error(StatusCode::NOT_FOUND, "No HLS stream with that name"))
+}
+
+/// Returns the live HLS media playlist of a stream, for players without
+/// MSE such as Safari on iOS.
+///
+/// Viewers which switch over from MSE with a resume token start at the
+/// keyframe of the token, if it is still in the playlist. Low-latency HLS
+/// players can ask for a part which isn't there yet, and get the playlist
+/// once it is.
+///
+/// Playlists can be cached by edges for a second, or for longer when they
+/// answer a blocking reload, since those are only ever of one version,
+/// unless viewers have to log in.
+pub async fn playlist(
+    Path(stream): Path<String>,
+    Query(params): Query<HlsParams>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    let (session, playlist) = match authorized_playlist(&data, &stream, &headers).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+
+    let resume = params
+        .resume
+        .as_deref()
+        .and_then(|token| token.parse::<ResumeToken>().ok())
+        .filter(|token| token.session == session);
+
+    let blocked = match params.msn {
+        Some(msn) => {
+            sh_transport_hls::wait_for_part(&playlist, msn, params.part, BLOCKING_TIMEOUT).await
+        }
+        None => false,
+    };
+    let skip = matches!(params.skip.as_deref(), Some("YES" | "v2"));
+
+    let playlist = {
+        let playlist = playlist.read();
+        let start = resume.and_then(|token| playlist.offset_of(token.keyframe));
+
+        playlist.playlist(start, skip)
+    };
+    let playlist = match playlist {
+        Some(playlist) => playlist,
+        None => return error(StatusCode::NOT_FOUND, "No segments yet"),
+    };
+
+    let etag = entity_tag(&playlist);
+    let max_age = if blocked { 60 } else { 1 };
+
+    if is_not_modified(&headers, &etag) {
+        return cache_headers(Response::builder(), &data, max_age)
+            .header("ETag", etag)
+            .header("Access-Control-Allow-Origin", "*")
+            .status(StatusCode::NOT_MODIFIED)
+            .body(body::Full::from(""))
+            .unwrap();
+    }
+
+    cache_headers(Response::builder(), &data, max_age)
+        .header("Content-Type", "application/vnd.apple.mpegurl")
+        .header("ETag", etag)
+        .header("Access-Control-Allow-Origin", "*")
+        .status(StatusCode::OK)
+        .body(body::Full::from(playlist))
+        .unwrap()
+}
+
+/// Finds the HLS playlists of the renditions of a stream, which are the
+/// live streams named after it with a suffix, like `app_720` and `app_480`
+/// of `app`, and the renditions of its ABR ladder, by the names they are
+/// played as.
+fn rendition_playlists(data: &AppData, stream: &str) -> Vec<(String, Arc<RwLock<HlsPlaylist>>)> {
+    let prefix = format!("{}_", stream);
+    let repo = data.stream_repo.read();
+
+    let mut playlists = repo
+        .stream_mapping
+        .iter()
+        .filter(|(name, _)| name.starts_with(&prefix))
+        .filter_map(|(name, id)| Some((name.clone(), repo.streams.get(id)?.hls.clone()?)))
+        .collect::<Vec<_>>();
+
+    if let Some(state) = repo
+        .stream_mapping
+        .get(stream)
+        .and_then(|id| repo.streams.get(id))
+    {
+        playlists.extend(
+            state.rendition_hls.iter().map(|(rendition, playlist)| {
+                (format!("{}_{}", stream, rendition), playlist.clone())
+            }),
+        );
+    }
+
+    playlists
+}
+
+/// Returns a multivariant playlist of the HLS playlist of a stream, which
+/// is what Chromecast and AirPlay receivers are given to play.
+///
+/// Renditions of the stream are variants of it as well, so players can
+/// adapt to their bandwidth. The stream itself comes first, since players
+/// start with the first variant, and the renditions follow from the
+/// highest bandwidth down.
+pub async fn multivariant_playlist(
+    Path(stream): Path<String>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    let (_, playlist) = match authorized_playlist(&data, &stream, &headers).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+
+    let variant = playlist.read().variant();
+    let variant = match variant {
+        Some(variant) => variant,
+        None => return error(StatusCode::NOT_FOUND, "No segments yet"),
+    };
+
+    // renditions which have no segments yet are left out until they do
+    let mut renditions = rendition_playlists(&data, &stream)
+        .into_iter()
+        .filter_map(|(name, playlist)| {
+            let variant = playlist.read().variant()?;
+
+            Some((variant, format!("../{}/playlist.m3u8", name)))
+        })
+        .collect::<Vec<_>>();
+    renditions.sort_by(|(a, _), (b, _)| b.bandwidth.cmp(&a.bandwidth));
+
+    let mut variants = vec![(variant, String::from("playlist.m3u8"))];
+    variants.extend(renditions);
+
+    cache_headers(Response::builder(), &data, 1)
+        .header("Content-Type", "application/vnd.apple.mpegurl")
+        .header("Access-Control-Allow-Origin", "*")
+        .status(StatusCode::OK)
+        .body(body::Full::from(sh_transport_hls::multivariant_playlist(
+            &variants,
+        )))
+        .unwrap()
+}
+
+/// What a sender app needs to cast a stream, in the terms of the `MediaInfo`
+/// of the Cast SDK.
+#[derive(Serialize, TS)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CastInfo {
+    /// The multivariant playlist, which receivers fetch themselves.
+    content_id: String,
+    content_type: &'static str,
+    stream_type: &'static str,
+    hls_segment_format: &'static str,
+    hls_video_segment_format: &'static str,
+    codecs: Option<String>,
+    /// Whether the default receiver of Chromecast can play the stream.
+    chromecast_compatible: bool,
+}
+
+/// Returns how to cast a stream to a Chromecast or AirPlay receiver, so
+/// sender apps don't have to know the HLS flavour streamhead serves.
+///
+/// The playlist URL is absolute, since receivers fetch it on their own. It
+/// is built from the `Host` and `X-Forwarded-Proto` headers of the request.
+pub async fn cast(
+    Path(stream): Path<String>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    let (_, playlist) = match authorized_playlist(&data, &stream, &headers).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+
+    let host = match headers.get(HOST).and_then(|h| h.to_str().ok()) {
+        Some(host) => host,
+        None => return error(StatusCode::BAD_REQUEST, "Request has no Host header"),
+    };
+    let scheme = headers
+        .get("X-Forwarded-Proto")
+        .and_then(|h| h.to_str().ok())
+        .filter(|proto| *proto == "https")
+        .unwrap_or("http");
+
+    let info = {
+        let playlist = playlist.read();
+
+        CastInfo {
+            content_id: format!("{}://{}/hls/{}/master.m3u8", scheme, host, stream),
+            content_type: "application/vnd.apple.mpegurl",
+            stream_type: "LIVE",
+            hls_segment_format: "fmp4",
+            hls_video_segment_format: "fmp4",
+            codecs: playlist.codecs().map(str::to_string),
+            chromecast_compatible: playlist.is_cast_compatible(),
+        }
+    };
+
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .header("Access-Control-Allow-Origin", "*")
+        .status(StatusCode::OK)
+        .body(body::Full::from(serde_json::to_vec(&info).unwrap()))
+        .unwrap()
+}
+
+/// Returns the initialization segment of the HLS playlist of a stream, of
+/// the latest publisher session unless another is asked for.
+pub async fn init(
+    Path(stream): Path<String>,
+    Query(params): Query<InitParams>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    let (_, playlist) = match authorized_playlist(&data, &stream, &headers).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+
+    let init = playlist.read().session_init(params.session);
+    // without a session it changes when the publisher reconnects
+    let max_age = if params.session.is_some() { 60 } else { 1 };
+    match init {
+        Some(bytes) => cache_headers(Response::builder(), &data, max_age)
+            .header("Content-Type", "video/mp4")
+            .header("Access-Control-Allow-Origin", "*")
+            .status(StatusCode::OK)
+            .body(body::Full::from(bytes))
+            .unwrap(),
+        None => error(StatusCode::NOT_FOUND, "Stream hasn't started yet"),
+    }
+}
+
+/// Returns a segment of the HLS playlist of a stream by its media sequence
+/// number. With chunked segments, the segment which is being written is
+/// sent as it is written.
+pub async fn segment(
+    Path((stream, sequence)): Path<(String, u64)>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> Response<BoxBody> {
+    let (_, playlist) = match authorized_playlist(&data, &stream, &headers).await {
+        Ok(found) => found,
+        Err(response) => return response.map(boxed),
+    };
+
+    let (segment, written) = {
+        let playlist = playlist.read();
+        (
+            playlist.segment(sequence),
+            playlist.segment_from(sequence, 0).is_some(),
+        )
+    };
+
+    if let Some(bytes) = segment {
+        return cache_headers(Response::builder(), &data, 60)
+            .header("Content-Type", "video/mp4")
+            .header("Access-Control-Allow-Origin", "*")
+            .status(StatusCode::OK)
+            .body(boxed(body::Full::from(bytes)))
+            .unwrap();
+    }
+    if !written {
+        return error(StatusCode::NOT_FOUND, "Segment has left the playlist").map(boxed);
+    }
+
+    let (mut output, bytes_rx) = ByteStreamWriteFilter::new();
+    tokio::spawn(async move {
+        let result =
+            sh_transport_hls::write_segment(&playlist, sequence, BLOCKING_TIMEOUT, &mut output)
+                .await;
+        if let Err(e) = result {
+            debug!(
+                "Stopped sending segment {} of '{}': {:?}",
+                sequence, stream, e
+            );
+        }
+    });
+
+    // the segment is incomplete until the response ends, so it must not be
+    // cached as it is now
+    Response::builder()
+        .header("Content-Type", "video/mp4")
+        .header("Cache-Control", "no-store")
+        .header("Access-Control-Allow-Origin", "*")
+        .status(StatusCode::OK)
+        .body(boxed(StreamBody::new(bytes_rx)))
+        .unwrap()
+}
+
+/// Returns a part of a segment of the HLS playlist of a stream, waiting for
+/// it if it is the next part, as hinted in the playlist.
+pub async fn part(
+    Path((stream, sequence, part)): Path<(String, u64, usize)>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    let (_, playlist) = match authorized_playlist(&data, &stream, &headers).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+
+    sh_transport_hls::wait_for_part(&playlist, sequence, Some(part), BLOCKING_TIMEOUT).await;
+
+    let part = playlist.read().part(sequence, part);
+    match part {
+        Some(bytes) => cache_headers(Response::builder(), &data, 60)
+            .header("Content-Type", "video/mp4")
+            .header("Access-Control-Allow-Origin", "*")
+            .status(StatusCode::OK)
+            .body(body::Full::from(bytes))
+            .unwrap(),
+        None => error(StatusCode::NOT_FOUND, "No such part"),
+    }
+}
+
+/// Returns a live DASH manifest of the same segments as the HLS playlist
+/// of a stream, for players which prefer DASH.
+///
+/// The renditions of the ABR ladder of the stream are representations of
+/// it as well, whose segments line up with those of the stream.
+pub async fn dash_manifest(
+    Path(stream): Path<String>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    let (_, playlist) = match authorized_playlist(&data, &stream, &headers).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+
+    let renditions = {
+        let repo = data.stream_repo.read();
+
+        repo.stream_mapping
+            .get(&stream)
+            .and_then(|id| repo.streams.get(id))
+            .map(|state| state.rendition_hls.clone())
+            .unwrap_or_default()
+    };
+
+    // sorted so representations keep their IDs between updates
+    let mut renditions = renditions.into_iter().collect::<Vec<_>>();
+    renditions.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut playlists = vec![(String::new(), playlist)];
+    playlists.extend(
+        renditions
+            .into_iter()
+            .map(|(rendition, playlist)| (format!("../{}_{}/", stream, rendition), playlist)),
+    );
+    let playlists = playlists
+        .iter()
+        .map(|(base, playlist)| (base, playlist.read()))
+        .collect::<Vec<_>>();
+    let representations = playlists
+        .iter()
+        .map(|(base, playlist)| (&**playlist, base.as_str()))
+        .collect::<Vec<_>>();
+
+    let manifest = sh_transport_hls::dash_manifest(&representations);
+    match manifest {
+        Some(manifest) => cache_headers(Response::builder(), &data, 1)
+            .header("Content-Type", "application/dash+xml")
+            .header("Access-Control-Allow-Origin", "*")
+            .status(StatusCode::OK)
+            .body(body::Full::from(manifest))
+            .unwrap(),
+        None => error(StatusCode::NOT_FOUND, "No segments yet"),
+    }
+}
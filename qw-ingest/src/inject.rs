@@ -0,0 +1,34 @@
+use sh_media::{frame_injector, FrameInjector, Stream};
+use tracing::*;
+
+use std::sync::Arc;
+
+use crate::{authenticate_stream, ingest, AppData, IngestSource};
+
+/// Starts a stream which is published by application code instead of a
+/// network protocol.
+///
+/// The stream key is authenticated the same way as for RTMP. Frames pushed
+/// into the returned [`FrameInjector`] are served to viewers, and the stream
+/// is stopped once every clone of the injector has been dropped.
+pub async fn inject_stream(
+    data: Arc<AppData>,
+    stream_key: &str,
+    is_public: bool,
+    streams: Vec<Stream>,
+) -> anyhow::Result<FrameInjector> {
+    let mut client = data.client.clone();
+    let (id, name) = authenticate_stream(&mut client, stream_key, is_public).await?;
+
+    info!("Got an injected stream for {}", name);
+
+    let (injector, read) = frame_injector(streams);
+
+    tokio::spawn(async move {
+        if let Err(e) = ingest(id, name, IngestSource::new(Box::new(read)), data).await {
+            error!("Failed to process injected stream: {:?}", e);
+        }
+    });
+
+    Ok(injector)
+}
@@ -0,0 +1,326 @@
+use axum::{
+    body::{self, boxed, BoxBody},
+    extract::{Extension, Path},
+    http::HeaderMap,
+};
+use hyper::{Response, StatusCode};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use tracing::*;
+use ts_rs::TS;
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{archive::write_atomically, diagnostics::is_admin, export, AppData};
+
+/// How long a failed job waits before its next attempt, multiplied by the
+/// number of attempts so far.
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// The work a job does, along with everything it needs to be run again
+/// after a restart.
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JobKind {
+    /// Exports the part of a recording from `start` to `end` seconds as a
+    /// progressive MP4 file.
+    Export {
+        recording: String,
+        start: f64,
+        end: f64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, TS)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    /// Waiting to run, either for the first time or to be retried.
+    Queued,
+    Running,
+    Finished,
+    /// Failed on its last attempt.
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+pub struct Job {
+    pub id: String,
+    pub kind: JobKind,
+    pub state: JobState,
+    pub attempts: u32,
+    /// How much of the current attempt is done, from 0 to 1.
+    pub progress: f64,
+    /// What the job produced once it is finished, depending on its kind.
+    #[ts(type = "unknown")]
+    pub result: Option<serde_json::Value>,
+    /// Why the last attempt failed.
+    pub error: Option<String>,
+    #[ts(type = "number")]
+    pub created_at: u64,
+    #[ts(type = "number | null")]
+    pub finished_at: Option<u64>,
+}
+
+/// Long-running work which runs in the background, like exporting clips.
+///
+/// Jobs are kept in a JSON file, so the jobs that were queued or running
+/// when the server stopped are run again once it starts. Only so many jobs
+/// run at a time, and failed jobs are retried a few times.
+pub struct JobQueue {
+    jobs: Mutex<HashMap<String, Job>>,
+    /// Where jobs are persisted, if anywhere.
+    path: Option<PathBuf>,
+    sync: bool,
+    /// Serializes writes of the jobs file, so an older snapshot never
+    /// replaces a newer one.
+    persisting: tokio::sync::Mutex<()>,
+    permits: Semaphore,
+    max_attempts: u32,
+    next_id: AtomicU64,
+}
+
+impl JobQueue {
+    pub fn new(path: Option<PathBuf>, sync: bool, concurrency: usize, max_attempts: u32) -> Self {
+        JobQueue {
+            jobs: Mutex::new(HashMap::new()),
+            path,
+            sync,
+            persisting: tokio::sync::Mutex::new(()),
+            permits: Semaphore::new(concurrency.max(1)),
+            max_attempts: max_attempts.max(1),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Reads the jobs persisted by a previous run. Jobs which were running
+    /// are queued again.
+    pub async fn load(&self) -> anyhow::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let json = match tokio::fs::read(path).await {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let jobs: Vec<Job> = serde_json::from_slice(&json)?;
+
+        let mut current = self.jobs.lock();
+        for mut job in jobs {
+            if job.state == JobState::Running {
+                job.state = JobState::Queued;
+            }
+            current.insert(job.id.clone(), job);
+        }
+
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Option<Job> {
+        self.jobs.lock().get(id).cloned()
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut Job)) {
+        if let Some(job) = self.jobs.lock().get_mut(id) {
+            f(job);
+        }
+    }
+
+    async fn persist(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+
+        let _persisting = self.persisting.lock().await;
+        let json = {
+            let jobs = self.jobs.lock();
+            let mut jobs = jobs.values().collect::<Vec<_>>();
+            jobs.sort_by(|a, b| a.created_at.cmp(&b.created_at).then(a.id.cmp(&b.id)));
+
+            serde_json::to_vec_pretty(&jobs)
+        };
+
+        let result = match json {
+            Ok(json) => write_atomically(path, &json, self.sync).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            warn!("Failed to persist jobs to {}: {:?}", path.display(), e);
+        }
+    }
+}
+
+/// Reports the progress of a running job.
+pub struct JobProgress {
+    data: Arc<AppData>,
+    id: String,
+}
+
+impl JobProgress {
+    pub fn set(&self, progress: f64) {
+        self.data
+            .jobs
+            .update(&self.id, |job| job.progress = progress.clamp(0.0, 1.0));
+    }
+}
+
+/// Queues a job, returning its ID.
+pub async fn submit(data: &Arc<AppData>, kind: JobKind) -> String {
+    let created_at = unix_time();
+    let id = format!(
+        "{}-{}",
+        created_at,
+        data.jobs.next_id.fetch_add(1, Ordering::Relaxed)
+    );
+
+    info!("Queueing job {}: {:?}", id, kind);
+
+    data.jobs.jobs.lock().insert(
+        id.clone(),
+        Job {
+            id: id.clone(),
+            kind,
+            state: JobState::Queued,
+            attempts: 0,
+            progress: 0.0,
+            result: None,
+            error: None,
+            created_at,
+            finished_at: None,
+        },
+    );
+    data.jobs.persist().await;
+
+    tokio::spawn(run(data.clone(), id.clone()));
+
+    id
+}
+
+/// Runs the jobs which were queued when the server stopped.
+pub fn resume(data: &Arc<AppData>) {
+    let queued = data
+        .jobs
+        .jobs
+        .lock()
+        .values()
+        .filter(|job| job.state == JobState::Queued)
+        .map(|job| job.id.clone())
+        .collect::<Vec<_>>();
+
+    for id in queued {
+        info!("Resuming job {}", id);
+        tokio::spawn(run(data.clone(), id));
+    }
+}
+
+async fn run(data: Arc<AppData>, id: String) {
+    loop {
+        let retry = {
+            let _permit = match data.jobs.permits.acquire().await {
+                Ok(permit) => permit,
+                Err(_) => return,
+            };
+
+            let kind = match data.jobs.get(&id) {
+                Some(job) => job.kind,
+                None => return,
+            };
+            data.jobs.update(&id, |job| {
+                job.state = JobState::Running;
+                job.attempts += 1;
+                job.progress = 0.0;
+            });
+            data.jobs.persist().await;
+
+            let progress = JobProgress {
+                data: data.clone(),
+                id: id.clone(),
+            };
+            let result = match &kind {
+                JobKind::Export {
+                    recording,
+                    start,
+                    end,
+                } => export::run_export(&data, &id, recording, *start..*end, &progress).await,
+            };
+
+            let mut retry = false;
+            data.jobs.update(&id, |job| match result {
+                Ok(result) => {
+                    job.state = JobState::Finished;
+                    job.progress = 1.0;
+                    job.result = Some(result);
+                    job.error = None;
+                    job.finished_at = Some(unix_time());
+                }
+                Err(e) => {
+                    warn!("Job {} failed on attempt {}: {:?}", id, job.attempts, e);
+
+                    retry = job.attempts < data.jobs.max_attempts;
+                    job.state = if retry {
+                        JobState::Queued
+                    } else {
+                        job.finished_at = Some(unix_time());
+                        JobState::Failed
+                    };
+                    job.error = Some(e.to_string());
+                }
+            });
+            data.jobs.persist().await;
+
+            retry
+        };
+
+        if !retry {
+            return;
+        }
+
+        let attempts = data.jobs.get(&id).map_or(1, |job| job.attempts);
+        tokio::time::sleep(RETRY_DELAY * attempts).await;
+    }
+}
+
+/// Returns the state and progress of a job.
+pub async fn job_status(
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> Response<BoxBody> {
+    if !is_admin(&data, &headers) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(boxed(body::Full::from("Missing or invalid admin token")))
+            .unwrap();
+    }
+
+    match data.jobs.get(&id) {
+        Some(job) => Response::builder()
+            .header("Content-Type", "application/json")
+            .status(StatusCode::OK)
+            .body(boxed(body::Full::from(serde_json::to_vec(&job).unwrap())))
+            .unwrap(),
+        None => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(boxed(body::Full::from("No such job")))
+            .unwrap(),
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,202 @@
+use axum::{
+    body,
+    extract::{Extension, Path},
+    response::IntoResponse,
+};
+use hyper::{Response, StatusCode};
+use parking_lot::RwLock;
+use serde::Serialize;
+use sh_media::{Frame, FrameReadFilter, Stream};
+use sh_transcode::AudioGain;
+#[cfg(feature = "transcode")]
+use sh_transcode::{AudioTranscodeFilter, AudioTranscodeOptions};
+use ts_rs::TS;
+
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{audio_levels, AppData};
+
+/// The window loudness is measured over, the short-term window of EBU R128.
+const WINDOW: Duration = Duration::from_secs(3);
+
+/// Audio below this loudness is not measured, so gain isn't raised during
+/// silence. This is the absolute gate of EBU R128.
+const GATE_LUFS: f32 = -70.0;
+
+/// The most gain applied in either direction.
+const MAX_GAIN_DB: f32 = 12.0;
+
+/// How fast the gain may change, so adjustments aren't heard as pumping.
+const GAIN_SLEW_DB_PER_SECOND: f32 = 3.0;
+
+/// How often the gain is updated.
+const UPDATE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The bitrate audio is re-encoded at once the gain is applied.
+#[cfg(feature = "transcode")]
+const NORMALIZED_BITRATE: usize = 128_000;
+
+/// The loudness of a stream and the gain which brings it to the target.
+#[derive(Debug, Default, Clone, Serialize, TS)]
+pub struct Loudness {
+    pub target_lufs: f32,
+    /// The short-term loudness, unless the stream has been quiet.
+    pub loudness_lufs: Option<f32>,
+    pub gain_db: f32,
+}
+
+/// A filter which measures the loudness of an ingest and works out the gain
+/// which brings it to a target loudness, like a slow AGC. The gain is
+/// applied by the [`normalizer`] after it.
+///
+/// The audio is not decoded, so the loudness is estimated from the same
+/// per-frame levels as the audio meters, without K-weighting.
+pub struct LoudnessFilter {
+    filter: Box<dyn FrameReadFilter + Send + Unpin>,
+    loudness: Arc<RwLock<Loudness>>,
+    gain: AudioGain,
+    target_lufs: f32,
+    powers: VecDeque<(Instant, f32)>,
+    gain_db: f32,
+    last_update: Instant,
+}
+
+impl LoudnessFilter {
+    pub fn new(
+        filter: Box<dyn FrameReadFilter + Send + Unpin>,
+        target_lufs: f32,
+        loudness: Arc<RwLock<Loudness>>,
+        gain: AudioGain,
+    ) -> Self {
+        LoudnessFilter {
+            filter,
+            loudness,
+            gain,
+            target_lufs,
+            powers: VecDeque::new(),
+            gain_db: 0.0,
+            last_update: Instant::now(),
+        }
+    }
+
+    fn measure(&mut self, frame: &Frame) {
+        let now = Instant::now();
+
+        if let Some(amplitude) = audio_levels::frame_amplitude(frame) {
+            self.powers.push_back((now, amplitude * amplitude));
+        }
+        while let Some((at, _)) = self.powers.front() {
+            if now - *at <= WINDOW {
+                break;
+            }
+            self.powers.pop_front();
+        }
+
+        let elapsed = now - self.last_update;
+        if elapsed < UPDATE_INTERVAL {
+            return;
+        }
+        self.last_update = now;
+
+        let loudness_lufs = if self.powers.is_empty() {
+            None
+        } else {
+            let mean = self.powers.iter().map(|(_, p)| p).sum::<f32>() / self.powers.len() as f32;
+            Some(-0.691 + 10.0 * mean.log10()).filter(|lufs| *lufs > GATE_LUFS)
+        };
+
+        if let Some(lufs) = loudness_lufs {
+            let wanted = (self.target_lufs - lufs).clamp(-MAX_GAIN_DB, MAX_GAIN_DB);
+            let max_step = GAIN_SLEW_DB_PER_SECOND * elapsed.as_secs_f32();
+
+            self.gain_db += (wanted - self.gain_db).clamp(-max_step, max_step);
+            self.gain.set_db(self.gain_db);
+        }
+
+        *self.loudness.write() = Loudness {
+            target_lufs: self.target_lufs,
+            loudness_lufs,
+            gain_db: self.gain_db,
+        };
+    }
+}
+
+#[async_trait::async_trait]
+impl FrameReadFilter for LoudnessFilter {
+    async fn start(&mut self) -> anyhow::Result<Vec<Stream>> {
+        self.filter.start().await
+    }
+
+    async fn read(&mut self) -> anyhow::Result<Frame> {
+        let frame = self.filter.read().await?;
+
+        if frame.stream.is_audio() {
+            self.measure(&frame);
+        }
+
+        Ok(frame)
+    }
+}
+
+/// Re-encodes the audio of a stream with the codec it has and the gain of
+/// its [`LoudnessFilter`] applied, so every output is normalized.
+#[cfg(feature = "transcode")]
+pub fn normalizer(
+    read: Box<dyn FrameReadFilter + Send + Unpin>,
+    gain: AudioGain,
+) -> Box<dyn FrameReadFilter + Send + Unpin> {
+    let options = AudioTranscodeOptions {
+        codec: None,
+        bitrate: NORMALIZED_BITRATE,
+        gain: Some(gain),
+    };
+
+    Box::new(AudioTranscodeFilter::read(read, options))
+}
+
+#[cfg(not(feature = "transcode"))]
+pub fn normalizer(
+    read: Box<dyn FrameReadFilter + Send + Unpin>,
+    _gain: AudioGain,
+) -> Box<dyn FrameReadFilter + Send + Unpin> {
+    // normalization isn't enabled without the transcode feature
+    read
+}
+
+/// Returns the loudness of a stream and the gain its audio is normalized
+/// with.
+pub async fn loudness(
+    Path(stream): Path<String>,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    let loudness = {
+        let repo = data.stream_repo.read();
+
+        repo.stream_mapping
+            .get(&stream)
+            .and_then(|id| repo.streams.get(id))
+            .and_then(|state| state.loudness.as_ref())
+            .map(|loudness| loudness.read().clone())
+    };
+
+    match loudness {
+        Some(loudness) => Response::builder()
+            .header("Content-Type", "application/json")
+            .header("Access-Control-Allow-Origin", "*")
+            .header("Cache-Control", "no-cache")
+            .status(StatusCode::OK)
+            .body(body::Full::from(serde_json::to_string(&loudness).unwrap()))
+            .unwrap(),
+        None => Response::builder()
+            .header("Access-Control-Allow-Origin", "*")
+            .status(StatusCode::NOT_FOUND)
+            .body(body::Full::from(
+                "Stream not found or loudness normalization disabled",
+            ))
+            .unwrap(),
+    }
+}
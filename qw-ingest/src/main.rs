@@ -1,17 +1,26 @@
 use axum::{
-    body::{self, boxed, Empty, StreamBody},
+    body::{self, boxed, BoxBody, Empty, StreamBody},
     extract::{
         ws::{WebSocket, WebSocketUpgrade},
-        Extension, Path,
+        ConnectInfo, Extension, Path, Query,
     },
+    http::{header::USER_AGENT, HeaderMap},
     response::IntoResponse,
-    routing::get,
+    routing::{delete, get, post, put},
     AddExtensionLayer, Router,
 };
-use futures::{future, Stream};
+use futures::{future, Future, Stream};
 use hyper::{Response, StatusCode};
-use sh_fmp4::FragmentedMp4WriteFilter;
-use sh_ingest_rtmp::RtmpRequest;
+use serde::Serialize;
+use sh_fmp4::CmafMuxer;
+use sh_ingest_rtmp::{ConformanceReport, RtmpCapture, RtmpFeedback, RtmpRequest};
+use sh_ingest_rtsp::RtspRequest;
+use sh_ingest_srt::{SrtConnection, SrtListener};
+use sh_ingest_ts::TsReadFilter;
+use sh_mkv::MatroskaMuxer;
+use sh_transcode::{AudioGain, PipOverlay, TranscodeOptions};
+use sh_transport_hls::{HlsConfig, HlsPlaylist};
+use sh_transport_mse::{DeliveryStats, ReconnectHints, RenditionOffer, ResumeToken};
 use tokio::{
     net::{TcpListener, TcpStream},
     sync::broadcast::{self, Receiver, Sender},
@@ -21,60 +30,342 @@ use tokio::{
 use tokio_stream::wrappers::BroadcastStream;
 use tonic::transport::{Channel, Endpoint};
 use tracing::*;
+use ts_rs::TS;
 
+use parking_lot::{Mutex, RwLock};
 use qw_proto::{
     stream_auth::{stream_auth_service_client::StreamAuthServiceClient, IngestRequest},
     stream_info::{
         stream_info_server::{StreamInfo, StreamInfoServer},
         stream_reply::{
-            StreamExisting, StreamStarted, StreamStats, StreamStopped, StreamType, ViewerJoin,
-            ViewerLeave,
+            StreamAnomaly, StreamAudioLevels, StreamExisting, StreamStarted, StreamStats,
+            StreamStopped, StreamType, ViewerJoin, ViewerLeave, ViewerStats,
         },
         StreamMetadata, StreamReply, StreamRequest,
     },
 };
 use sh_media::{
-    wait_for_sync_frame, BitstreamFramerFilter, BitstreamFraming, ByteStreamWriteFilter,
-    ByteWriteFilter2, Frame, FrameAnalyzerFilter, FrameReadFilter, FrameWriteFilter,
-    MediaFrameQueue, MediaFrameQueueReceiver,
+    wait_for_sync_frame, ByteStreamWriteFilter, ByteWriteFilter2, FilterGraph, Frame,
+    FrameAnalyzerFilter, FrameReadFilter, FrameWriteFilter, GraphControl, MediaFrameQueue,
+    MediaFrameQueueReceiver, Muxer, SeiReadFilter, StatsRegistry,
 };
 use tracing_subscriber::{EnvFilter, FmtSubscriber};
 
 use std::{
     collections::HashMap,
-    net::{SocketAddr, ToSocketAddrs},
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::PathBuf,
     pin::Pin,
-    sync::{Arc, RwLock},
-    time::Duration,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
-    bandwidth_analyzer::BandwidthAnalyzerFilter, snapshot_provider::SnapshotProviderFilter,
+    anomaly::{Anomalies, AnomalyFilter},
+    archive::FsyncPolicy,
+    audio_levels::AudioLevelFilter,
+    bandwidth_analyzer::BandwidthAnalyzerFilter,
+    canary::CanaryResult,
+    captions::{Captions, ClosedCaptionFilter},
+    compose::PipLayout,
+    congestion::{Congestion, CongestionFilter},
+    delivery::{Deliveries, Viewer},
+    discovery::Discovery,
+    entitlement::{Entitlements, PlaybackParams, PlaybackSession},
+    events::EventFeed,
+    expected::ExpectedStreams,
+    jobs::JobQueue,
+    loudness::{Loudness, LoudnessFilter},
+    moderation::{Moderation, ModerationFilter, ModerationState},
+    naming::NameTemplate,
+    packaging::PackagingCache,
+    proxy_protocol::{accept_proxied, ProxyProtocol},
+    recommendations::{parse_resolution, EncoderPolicy},
+    recording::{Recording, RECORDER_BRANCH},
+    relay::RelayManager,
+    rtmp_listeners::{load_rtmp_listeners, RtmpListener},
+    snapshot_provider::{SnapshotProviderFilter, Snapshots},
+    supervisor::Supervisor,
+    tcp_tuning::TcpTuning,
+    viewer_auth::{OidcConfig, OidcVerifier},
+    vod::VodLibrary,
+    whep::WhepSessions,
+    whip::WhipSessions,
 };
 
+mod abr;
+mod anomaly;
+mod archive;
+mod audio;
+mod audio_levels;
 mod bandwidth_analyzer;
+mod canary;
+mod captions;
+mod compose;
+mod congestion;
+mod delivery;
+mod diagnostics;
+mod discovery;
+mod disk;
+mod download;
+mod entitlement;
+mod events;
+mod expected;
+mod export;
+mod failover;
+mod file_source;
+mod flv;
+mod frame_stats;
+mod hls;
+mod inject;
+mod jobs;
+mod loudness;
+mod moderation;
+mod mse_ingest;
+mod multicast;
+mod naming;
+mod packaging;
+mod preview;
+mod proxy_protocol;
+mod push;
+mod recommendations;
+mod recording;
+mod relay;
+mod remap;
+mod rist;
+mod rtmp_listeners;
 mod snapshot_provider;
+mod srt_playback;
+mod supervisor;
+mod tcp_tuning;
+mod trick_play;
+mod typescript;
+mod viewer_auth;
+mod vod;
+mod whep;
+mod whip;
+
+/// The name of the rendition which is the stream as published.
+pub const SOURCE_RENDITION: &str = "source";
+
+/// Where a stream is in its life.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, TS)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamPhase {
+    /// The publisher is authenticated, but the stream isn't registered
+    /// until its codecs are known.
+    Waiting,
+    /// The stream is registered, but no frames were forwarded yet.
+    Starting,
+    Live,
+    /// The publisher went away, and the stream is kept around for a while
+    /// so a publisher which reconnects right away doesn't look like a
+    /// stopped stream.
+    Grace,
+    Ended,
+}
+
+impl StreamPhase {
+    /// Whether a stream can change from this phase to `next`. Streams only
+    /// ever move forward, and can end from any phase.
+    pub fn can_change_to(self, next: StreamPhase) -> bool {
+        use StreamPhase::*;
+
+        matches!(
+            (self, next),
+            (Waiting, Starting)
+                | (Starting, Live)
+                | (Starting | Live, Grace)
+                | (Waiting | Starting | Live | Grace, Ended)
+        )
+    }
+}
+
+/// A change of a stream to another phase.
+#[derive(Debug, Clone, Serialize, TS)]
+pub struct PhaseTransition {
+    pub phase: StreamPhase,
+    /// Seconds since the UNIX epoch of when the stream changed phase.
+    #[ts(type = "number")]
+    pub at: u64,
+    /// Why the stream changed phase, like the error its publisher went away
+    /// with.
+    pub reason: Option<String>,
+}
+
+/// The phases a stream session went through, from when its publisher was
+/// authenticated.
+#[derive(Debug, Clone)]
+pub struct Lifecycle {
+    pub stream_session_id: i32,
+    /// Never empty, starting with the change to `waiting`.
+    transitions: Vec<PhaseTransition>,
+}
+
+impl Lifecycle {
+    fn new(stream_session_id: i32) -> Self {
+        Lifecycle {
+            stream_session_id,
+            transitions: vec![PhaseTransition {
+                phase: StreamPhase::Waiting,
+                at: unix_secs(SystemTime::now()),
+                reason: None,
+            }],
+        }
+    }
+
+    pub fn phase(&self) -> StreamPhase {
+        self.transitions
+            .last()
+            .map_or(StreamPhase::Waiting, |t| t.phase)
+    }
+
+    /// Seconds since the UNIX epoch of when the stream changed to its
+    /// current phase.
+    pub fn phase_changed(&self) -> u64 {
+        self.transitions.last().map_or(0, |t| t.at)
+    }
+
+    pub fn transitions(&self) -> &[PhaseTransition] {
+        &self.transitions
+    }
+
+    /// Changes to `phase`, unless the stream can't change to it from its
+    /// current phase.
+    fn change(&mut self, phase: StreamPhase, reason: Option<String>) -> anyhow::Result<()> {
+        let current = self.phase();
+        if !current.can_change_to(phase) {
+            anyhow::bail!("can't change from {:?} to {:?}", current, phase);
+        }
+
+        self.transitions.push(PhaseTransition {
+            phase,
+            at: unix_secs(SystemTime::now()),
+            reason,
+        });
+
+        Ok(())
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// A stream which changed to another phase.
+#[derive(Debug, Clone)]
+pub struct StreamChange {
+    pub stream_session_id: i32,
+    pub name: String,
+    pub phase: StreamPhase,
+    pub reason: Option<String>,
+}
 
-pub struct StreamState {
+/// A stream session in the [`StreamRepository`].
+pub struct StreamEntry {
+    /// The name of the streamer who owns the stream, which viewers play it
+    /// by.
+    name: String,
+    /// The streams of the source, with their codecs.
+    streams: Vec<sh_media::Stream>,
+    lifecycle: Lifecycle,
+    /// When the stream was registered.
+    started: SystemTime,
     queue: MediaFrameQueue,
+    /// The source as relays read it, which the ingest drops rather than
+    /// waiting for when they fall behind.
+    relay: MediaFrameQueue,
+    /// The source as recordings read it, which the ingest only feeds while
+    /// the source is recorded.
+    recorder: MediaFrameQueue,
+    /// Adds and removes sinks of the running ingest, like the recorder.
+    graph: Option<GraphControl>,
+    /// Transcoded versions of the stream by name, next to the source.
+    renditions: HashMap<String, MediaFrameQueue>,
+    /// The live HLS playlists of the renditions by name, if enabled.
+    rendition_hls: HashMap<String, Arc<RwLock<HlsPlaylist>>>,
     viewers: u32,
-    snapshot: Arc<RwLock<Option<Frame>>>,
+    snapshots: Arc<RwLock<Snapshots>>,
+    /// The loudness of the stream and the gain it is normalized with, if
+    /// enabled.
+    loudness: Option<Arc<RwLock<Loudness>>>,
+    /// The rolling bitrate, frame rate and GOP structure of the streams.
+    frame_stats: StatsRegistry,
+    /// Whether the stream is paused by moderation, if enabled.
+    moderation: Option<Arc<ModerationState>>,
+    /// The picture-in-picture stream composited over the video, if the
+    /// stream is composited.
+    pip: Option<PipOverlay>,
+    /// The live HLS playlist of the stream, if enabled.
+    hls: Option<Arc<RwLock<HlsPlaylist>>>,
+    /// Sudden changes of the ingest over the last few minutes.
+    anomalies: Arc<Mutex<Anomalies>>,
+    capture: Option<RtmpCapture>,
     meta: StreamMetadata,
 }
 
-impl StreamState {
+impl StreamEntry {
     pub fn new(
+        name: String,
         queue: MediaFrameQueue,
-        snapshot: Arc<RwLock<Option<Frame>>>,
+        snapshots: Arc<RwLock<Snapshots>>,
+        capture: Option<RtmpCapture>,
         meta: StreamMetadata,
+        lifecycle: Lifecycle,
     ) -> Self {
-        StreamState {
+        StreamEntry {
+            name,
+            streams: Vec::new(),
+            lifecycle,
+            started: SystemTime::now(),
             queue,
+            relay: MediaFrameQueue::new(),
+            recorder: MediaFrameQueue::new(),
+            graph: None,
+            renditions: HashMap::new(),
+            rendition_hls: HashMap::new(),
             viewers: 0,
-            snapshot,
+            snapshots,
+            captions: Arc::default(),
+            loudness: None,
+            frame_stats: StatsRegistry::default(),
+            moderation: None,
+            pip: None,
+            hls: None,
+            anomalies: Arc::default(),
+            capture,
             meta,
         }
     }
+
+    /// Returns the queue of the named rendition, where `source` is the
+    /// stream as published.
+    pub fn rendition(&self, name: &str) -> Option<MediaFrameQueue> {
+        if name == SOURCE_RENDITION {
+            Some(self.queue.clone())
+        } else {
+            self.renditions.get(name).cloned()
+        }
+    }
+
+    /// Returns the queue which recordings of the named rendition read.
+    pub fn recorded(&self, name: &str) -> Option<MediaFrameQueue> {
+        if name == SOURCE_RENDITION {
+            Some(self.recorder.clone())
+        } else {
+            self.renditions.get(name).cloned()
+        }
+    }
+
+    /// Whether frames are still coming, so the stream can be played.
+    pub fn is_live(&self) -> bool {
+        matches!(
+            self.lifecycle.phase(),
+            StreamPhase::Starting | StreamPhase::Live
+        )
+    }
 }
 
 pub struct StreamInfoService {
@@ -95,12 +386,12 @@ impl StreamInfo for StreamInfoService {
 
         debug!("listen RPC call");
 
-        let (events, stream) = self
-            .data
-            .stream_repo
-            .write()
-            .unwrap()
-            .subscribe(self.data.stream_stat_sender.subscribe());
+        let (events, stream) = self.data.stream_repo.write().subscribe(
+            self.data.stream_stat_sender.subscribe(),
+            self.data.audio_level_sender.subscribe(),
+            self.data.viewer_stat_sender.subscribe(),
+            self.data.anomaly_sender.subscribe(),
+        );
 
         let event_stream = futures::stream::iter(events).map(Some);
 
@@ -117,48 +408,210 @@ impl StreamInfo for StreamInfoService {
 
 pub struct StreamRepository {
     pub stream_mapping: HashMap<String, i32>,
-    pub streams: HashMap<i32, StreamState>,
+    pub streams: HashMap<i32, StreamEntry>,
+    /// The conformance report of the latest RTMP session of each stream,
+    /// kept after the stream stops.
+    pub reports: HashMap<String, Arc<Mutex<ConformanceReport>>>,
+    /// Recordings by stream name and rendition, which continue across
+    /// publisher reconnects.
+    pub recordings: HashMap<String, HashMap<String, Recording>>,
+    /// Picture-in-picture layouts by the name of the main stream, kept
+    /// across publisher reconnects.
+    pub pip: HashMap<String, PipLayout>,
+    /// The live HLS playlists by stream name and rendition, which continue
+    /// across publisher reconnects within the grace period of a stream.
+    pub hls: HashMap<String, HashMap<String, Arc<RwLock<HlsPlaylist>>>>,
+    /// The lifecycles of the latest sessions of streams which are waiting
+    /// to be registered or have ended, by stream name. Registered streams
+    /// keep theirs in their entry.
+    pub lifecycles: HashMap<String, Lifecycle>,
     send: Sender<StreamType>,
+    changes: Sender<StreamChange>,
     // channels: Vec<Sender<StreamEvent>>,
 }
 
 impl StreamRepository {
     pub fn new() -> Self {
         let (send, _) = broadcast::channel(512);
+        let (changes, _) = broadcast::channel(512);
 
         StreamRepository {
             stream_mapping: HashMap::new(),
             streams: HashMap::new(),
+            reports: HashMap::new(),
+            recordings: HashMap::new(),
+            pip: HashMap::new(),
+            hls: HashMap::new(),
+            lifecycles: HashMap::new(),
             send,
+            changes,
         }
     }
 
+    /// Notes that the publisher of a stream is authenticated, until the
+    /// stream is registered with [`StreamRepository::start_stream`].
+    pub fn wait_for_stream(&mut self, stream_session_id: i32, stream: String) {
+        self.lifecycles
+            .insert(stream.clone(), Lifecycle::new(stream_session_id));
+        self.send_change(stream_session_id, stream, StreamPhase::Waiting, None);
+    }
+
     pub fn start_stream(
         &mut self,
         stream_session_id: i32,
         stream: String,
         queue: MediaFrameQueue,
-        snapshot: Arc<RwLock<Option<Frame>>>,
+        snapshots: Arc<RwLock<Snapshots>>,
+        capture: Option<RtmpCapture>,
         info: StreamMetadata,
     ) {
         debug!("Starting stream with id {stream_session_id}");
-        let meta = StreamState::new(queue, snapshot, info.clone());
+        let mut lifecycle = self
+            .lifecycles
+            .remove(&stream)
+            .filter(|l| l.stream_session_id == stream_session_id)
+            .unwrap_or_else(|| Lifecycle::new(stream_session_id));
+        if let Err(e) = lifecycle.change(StreamPhase::Starting, None) {
+            warn!("Stream with id {stream_session_id} {}", e);
+        }
+
+        let meta = StreamEntry::new(
+            stream.clone(),
+            queue,
+            snapshots,
+            capture,
+            info.clone(),
+            lifecycle,
+        );
+
         self.streams.insert(stream_session_id, meta);
-        self.stream_mapping.insert(stream, stream_session_id);
+        self.stream_mapping
+            .insert(stream.clone(), stream_session_id);
         self.send_event(StreamType::StreamStarted(StreamStarted {
             stream_session_id,
             meta: Some(info),
         }));
+        self.send_change(stream_session_id, stream, StreamPhase::Starting, None);
     }
 
-    pub fn stop_stream(&mut self, stream_session_id: i32) {
+    /// Continues the recordings of a stream from an earlier session with
+    /// the queues of this one, dropping those of renditions it lacks.
+    pub fn follow_recordings(&mut self, stream_session_id: i32) {
+        let state = match self.streams.get(&stream_session_id) {
+            Some(state) => state,
+            None => return,
+        };
+
+        if let Some(recordings) = self.recordings.get_mut(&state.name) {
+            recordings.retain(|rendition, recording| {
+                state
+                    .recorded(rendition)
+                    .map(|queue| recording.follow(queue))
+                    .unwrap_or(false)
+            });
+        }
+    }
+
+    /// Removes a stream, with why it ended, and the `error` it ended with
+    /// if its pipeline failed unexpectedly.
+    ///
+    /// A stream which was never registered ends while it waits.
+    pub fn stop_stream(
+        &mut self,
+        stream_session_id: i32,
+        reason: Option<String>,
+        error: Option<String>,
+    ) {
         debug!("Stopping stream with id {stream_session_id}");
-        self.streams.remove(&stream_session_id);
+        let reason = reason.or_else(|| error.clone());
+
+        match self.streams.remove(&stream_session_id) {
+            Some(mut entry) => {
+                if entry
+                    .lifecycle
+                    .change(StreamPhase::Ended, reason.clone())
+                    .is_ok()
+                {
+                    self.send_change(
+                        stream_session_id,
+                        entry.name.clone(),
+                        StreamPhase::Ended,
+                        reason,
+                    );
+                }
+                // a publisher which reconnected has taken over the playlists
+                if self.stream_mapping.get(&entry.name) == Some(&stream_session_id) {
+                    self.hls.remove(&entry.name);
+                }
+                self.lifecycles.insert(entry.name, entry.lifecycle);
+            }
+            None => self.stop_waiting(stream_session_id, reason),
+        }
+
         self.send_event(StreamType::StreamStopped(StreamStopped {
             stream_session_id,
+            error,
         }));
     }
 
+    /// Ends a stream which is waiting to be registered, like when its
+    /// publisher goes away before it tells its codecs.
+    pub fn stop_waiting(&mut self, stream_session_id: i32, reason: Option<String>) {
+        let waiting = self.lifecycles.iter_mut().find(|(_, l)| {
+            l.stream_session_id == stream_session_id && l.phase() == StreamPhase::Waiting
+        });
+
+        if let Some((name, lifecycle)) = waiting {
+            if lifecycle.change(StreamPhase::Ended, reason.clone()).is_ok() {
+                let name = name.clone();
+                self.send_change(stream_session_id, name, StreamPhase::Ended, reason);
+            }
+        }
+    }
+
+    /// Moves a stream to another phase, before it is stopped, unless it
+    /// can't change to it from its current phase.
+    pub fn set_phase(
+        &mut self,
+        stream_session_id: i32,
+        phase: StreamPhase,
+        reason: Option<String>,
+    ) {
+        let entry = match self.streams.get_mut(&stream_session_id) {
+            Some(entry) if entry.lifecycle.phase() != phase => entry,
+            _ => return,
+        };
+
+        if let Err(e) = entry.lifecycle.change(phase, reason.clone()) {
+            warn!("Stream with id {stream_session_id} {}", e);
+            return;
+        }
+        debug!("Stream with id {stream_session_id} is now {:?}", phase);
+
+        let name = entry.name.clone();
+        self.send_change(stream_session_id, name, phase, reason);
+    }
+
+    /// Returns the HLS playlist of a rendition of a stream, which is that of
+    /// the previous session if the publisher reconnected.
+    pub fn hls_playlist(&mut self, stream: &str, rendition: &str) -> Arc<RwLock<HlsPlaylist>> {
+        self.hls
+            .entry(stream.to_string())
+            .or_default()
+            .entry(rendition.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Finds the lifecycle of the latest session of a stream.
+    pub fn lifecycle(&self, stream: &str) -> Option<&Lifecycle> {
+        self.stream_mapping
+            .get(stream)
+            .and_then(|id| self.streams.get(id))
+            .map(|entry| &entry.lifecycle)
+            .or_else(|| self.lifecycles.get(stream))
+    }
+
     pub fn viewer_join(&mut self, stream_session_id: i32) {
         if let Some(meta) = self.streams.get_mut(&stream_session_id) {
             meta.viewers += 1;
@@ -176,6 +629,9 @@ impl StreamRepository {
     pub fn subscribe(
         &mut self,
         stream_stats: Receiver<StreamStats>,
+        audio_levels: Receiver<StreamAudioLevels>,
+        viewer_stats: Receiver<ViewerStats>,
+        anomalies: Receiver<StreamAnomaly>,
     ) -> (Vec<StreamType>, impl Stream<Item = Option<StreamType>>) {
         use futures::StreamExt;
 
@@ -196,11 +652,52 @@ impl StreamRepository {
         let event_stream = BroadcastStream::new(recv).map(|r| r.ok());
         let stream_stats =
             BroadcastStream::new(stream_stats).map(|s| s.map(StreamType::StreamStats).ok());
+        let audio_levels =
+            BroadcastStream::new(audio_levels).map(|l| l.map(StreamType::StreamAudioLevels).ok());
         let merged_stream = tokio_stream::StreamExt::merge(event_stream, stream_stats);
+        let viewer_stats =
+            BroadcastStream::new(viewer_stats).map(|s| s.map(StreamType::ViewerStats).ok());
+        let merged_stream = tokio_stream::StreamExt::merge(merged_stream, audio_levels);
+        let anomalies =
+            BroadcastStream::new(anomalies).map(|a| a.map(StreamType::StreamAnomaly).ok());
+        let merged_stream = tokio_stream::StreamExt::merge(merged_stream, viewer_stats);
+        let merged_stream = tokio_stream::StreamExt::merge(merged_stream, anomalies);
 
         (events, merged_stream)
     }
 
+    /// Receives the events of streams starting and stopping, and of viewers
+    /// joining and leaving them.
+    pub fn events(&self) -> Receiver<StreamType> {
+        self.send.subscribe()
+    }
+
+    /// Sends events which come from outside of the repository, like failed
+    /// recordings, next to those of streams.
+    pub fn event_sender(&self) -> Sender<StreamType> {
+        self.send.clone()
+    }
+
+    /// Receives the phases streams change to.
+    pub fn changes(&self) -> Receiver<StreamChange> {
+        self.changes.subscribe()
+    }
+
+    fn send_change(
+        &self,
+        stream_session_id: i32,
+        name: String,
+        phase: StreamPhase,
+        reason: Option<String>,
+    ) {
+        let _ = self.changes.send(StreamChange {
+            stream_session_id,
+            name,
+            phase,
+            reason,
+        });
+    }
+
     fn send_event(&self, event: StreamType) {
         debug!("Sending event: {:?}", event);
         let _ = self.send.send(event);
@@ -218,66 +715,448 @@ pub struct AppData {
     pub stream_repo: Arc<RwLock<StreamRepository>>,
     pub client: StreamAuthServiceClient<Channel>,
     pub stream_stat_sender: Sender<StreamStats>,
+    pub audio_level_sender: Sender<StreamAudioLevels>,
+    pub viewer_stat_sender: Sender<ViewerStats>,
+    pub anomaly_sender: Sender<StreamAnomaly>,
+    /// Publishers whose network can't keep up with their stream.
+    pub congestion_sender: Sender<Congestion>,
+    /// How delivery to each WebSocket viewer is going.
+    pub deliveries: Arc<Deliveries>,
+    /// The events of streams for dashboards, as server-sent events.
+    pub events: Arc<EventFeed>,
+    pub admin_token: Option<String>,
+    pub capture_dir: PathBuf,
+    pub recording_dir: PathBuf,
+    /// Storage directories for specific renditions, instead of
+    /// `recording_dir`.
+    pub recording_targets: HashMap<String, PathBuf>,
+    /// How recorded files are named, unless a recording asks otherwise.
+    pub recording_template: Option<NameTemplate>,
+    /// When recorded files are flushed to disk.
+    pub fsync_policy: FsyncPolicy,
+    /// The format recorded files are written in.
+    pub recording_muxer: Arc<dyn Muxer>,
+    pub canary_results: Arc<RwLock<HashMap<String, CanaryResult>>>,
+    pub reconnect_hints: ReconnectHints,
+    /// Origins which every stream is pushed to, where `{stream}` is
+    /// replaced with the stream name.
+    pub push_urls: Vec<String>,
+    /// Multicast groups which the MPEG-TS of streams is sent to.
+    pub multicast_targets: Vec<multicast::MulticastTarget>,
+    /// How often multicast outputs are announced with SAP, if at all.
+    pub sap_interval: Option<Duration>,
+    /// Advertises the server and its live streams on the local network.
+    pub discovery: Option<Arc<Discovery>>,
+    /// Validates the sessions of viewers, if playback requires a login.
+    pub viewer_auth: Option<Arc<OidcVerifier>>,
+    pub entitlements: Arc<Entitlements>,
+    /// The loudness the audio of streams is normalized to, if any.
+    pub loudness_target: Option<f32>,
+    /// Where sampled keyframes of every stream are sent for moderation.
+    pub moderation: Option<Arc<Moderation>>,
+    pub vod: Arc<RwLock<VodLibrary>>,
+    /// How recently played VOD assets and recordings are segmented.
+    pub packaging_cache: Arc<PackagingCache>,
+    /// Where exported parts of recordings are written.
+    pub export_dir: PathBuf,
+    pub jobs: Arc<JobQueue>,
+    /// The RTMP servers streams are restreamed to.
+    pub relays: Arc<RelayManager>,
+    /// The streams which are expected to be published, with their last
+    /// known codecs.
+    pub expected: Arc<ExpectedStreams>,
+    pub whip_sessions: Arc<WhipSessions>,
+    pub whep_sessions: Arc<WhepSessions>,
+    /// How far behind live viewers can start watching, if at all.
+    pub dvr_window: Option<Duration>,
+    /// How streams are segmented for HLS, if they are served as HLS.
+    pub hls: Option<HlsConfig>,
+    /// The qualities streams are transcoded to for adaptive bitrate.
+    pub abr_ladder: Vec<abr::Rung>,
+    /// The streams which are re-encoded to have picture-in-picture streams
+    /// composited over them.
+    pub composited_streams: Vec<String>,
+    /// How composited streams are re-encoded.
+    pub composite_options: TranscodeOptions,
+    /// The limits of the encoder settings streamers are recommended.
+    pub encoder_policy: EncoderPolicy,
+    /// How long streams are kept after their publisher goes away, if at
+    /// all.
+    pub stream_grace: Option<Duration>,
+    /// The load balancers which tell the address of the client at the
+    /// start of their RTMP, RTSP and HTTP connections.
+    pub proxy_protocol: ProxyProtocol,
+    /// Catches the panics of ingest pipelines.
+    pub supervisor: Arc<Supervisor>,
 }
 
 async fn rtmp_ingest(
     id: i32,
     name: String,
     request: sh_ingest_rtmp::RtmpRequest,
-    sender: Sender<StreamStats>,
-    repo: Arc<RwLock<StreamRepository>>,
+    data: Arc<AppData>,
 ) -> anyhow::Result<()> {
     use sh_ingest_rtmp::RtmpReadFilter;
 
     let session = timeout(Duration::from_secs(5), request.authenticate()).await??;
     let rtmp_meta = session.stream_metadata().clone();
 
-    let mut queue = MediaFrameQueue::new();
     let rtmp_filter = RtmpReadFilter::new(session);
-    let rtmp_analyzer = FrameAnalyzerFilter::read(Box::new(rtmp_filter));
-    let bw_analyzer = BandwidthAnalyzerFilter::new(Box::new(rtmp_analyzer), id, true, sender);
+    let capture = rtmp_filter.capture();
+    let report = rtmp_filter.conformance_report();
+    let feedback = rtmp_filter.feedback();
 
-    let snapshot = Arc::new(RwLock::new(None));
-    let mut snapshot_provider =
-        SnapshotProviderFilter::new(Box::new(bw_analyzer), snapshot.clone());
+    let source = IngestSource {
+        read: Box::new(rtmp_filter),
+        video_encoder: rtmp_meta.encoder,
+        video_bitrate_kbps: rtmp_meta.video_bitrate_kbps,
+        capture: Some(capture),
+        report: Some(report),
+        captions: None,
+        feedback: Some(feedback),
+    };
 
-    // let mut graph = FilterGraph::new(Box::new(snapshot_provider), Box::new(queue.clone()));
+    ingest(id, name, source, data).await
+}
 
-    let streams = snapshot_provider.start().await?;
+/// A source of frames for a stream, along with what is known about the
+/// publisher.
+struct IngestSource {
+    read: Box<dyn FrameReadFilter + Send + Unpin>,
+    video_encoder: Option<String>,
+    video_bitrate_kbps: Option<u32>,
+    capture: Option<RtmpCapture>,
+    report: Option<Arc<Mutex<ConformanceReport>>>,
+    captions: Option<async_channel::Receiver<sh_ingest_ts::Caption>>,
+    /// Tells the publisher to lower its bitrate when its network is
+    /// congested.
+    feedback: Option<RtmpFeedback>,
+}
 
-    let parameter_sets = streams.iter().find_map(|s| s.parameter_sets());
+impl IngestSource {
+    fn new(read: Box<dyn FrameReadFilter + Send + Unpin>) -> Self {
+        IngestSource {
+            read,
+            video_encoder: None,
+            video_bitrate_kbps: None,
+            capture: None,
+            report: None,
+            captions: None,
+            feedback: None,
+        }
+    }
+}
 
-    queue.start(streams).await?;
+/// Marks a stream as live once its first frame is read.
+struct LiveFilter {
+    read: Box<dyn FrameReadFilter + Send + Unpin>,
+    id: i32,
+    repo: Arc<RwLock<StreamRepository>>,
+    live: bool,
+}
+
+impl LiveFilter {
+    fn new(
+        read: Box<dyn FrameReadFilter + Send + Unpin>,
+        id: i32,
+        repo: Arc<RwLock<StreamRepository>>,
+    ) -> Self {
+        LiveFilter {
+            read,
+            id,
+            repo,
+            live: false,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl FrameReadFilter for LiveFilter {
+    async fn start(&mut self) -> anyhow::Result<Vec<sh_media::Stream>> {
+        self.read.start().await
+    }
+
+    async fn read(&mut self) -> anyhow::Result<Frame> {
+        let frame = self.read.read().await?;
+
+        if !self.live {
+            self.live = true;
+            self.repo
+                .write()
+                .set_phase(self.id, StreamPhase::Live, None);
+        }
+
+        Ok(frame)
+    }
+}
+
+/// Registers a stream in the [`StreamRepository`] and forwards frames from
+/// the source to its viewers until the source ends.
+///
+/// A panic in the pipeline ends the stream with an error, rather than
+/// leaving it in the repository with nothing feeding it.
+async fn ingest(
+    id: i32,
+    name: String,
+    source: IngestSource,
+    data: Arc<AppData>,
+) -> anyhow::Result<()> {
+    data.stream_repo.write().wait_for_stream(id, name.clone());
+
+    let pipeline = run_ingest(id, name.clone(), source, data.clone());
+    let result = data.supervisor.run("ingest", Some(id), pipeline).await;
+
+    if let Err(e) = &result {
+        // the locks don't poison, so the repository can be cleaned up even
+        // when the panicking task held it
+        let mut repo = data.stream_repo.write();
+
+        if supervisor::is_panic(e) {
+            repo.stop_stream(id, None, Some(e.to_string()));
+
+            if let Some(discovery) = &data.discovery {
+                discovery.withdraw_stream(&name);
+            }
+        } else {
+            // the pipeline failed before the stream was registered
+            repo.stop_waiting(id, Some(e.to_string()));
+        }
+    }
+
+    result
+}
+
+async fn run_ingest(
+    id: i32,
+    name: String,
+    source: IngestSource,
+    data: Arc<AppData>,
+) -> anyhow::Result<()> {
+    let repo = data.stream_repo.clone();
+    let sender = data.stream_stat_sender.clone();
+
+    let queue = match data.dvr_window {
+        Some(window) => MediaFrameQueue::with_dvr_window(window),
+        None => MediaFrameQueue::new(),
+    };
+    let relay = MediaFrameQueue::new();
+    let recorder = MediaFrameQueue::new();
+    // recordings which continue from an earlier session need the recorder
+    // from the start
+    let recorded = repo
+        .read()
+        .recordings
+        .get(&name)
+        .and_then(|recordings| recordings.get(SOURCE_RENDITION))
+        .is_some_and(|recording| !recording.is_finished());
+    let captions = Arc::new(RwLock::new(Captions::default()));
+    let frame_stats = StatsRegistry::default();
+    let anomalies = Arc::new(Mutex::new(Anomalies::default()));
+
+    let mut graph = FilterGraph::builder()
+        .source("source", source.read)
+        .filter("congestion", |read| {
+            let congestion_sender = data.congestion_sender.clone();
+            Box::new(CongestionFilter::new(
+                read,
+                id,
+                source.feedback,
+                congestion_sender,
+            ))
+        })
+        .filter("sei", |read| Box::new(SeiReadFilter::new(read)))
+        .filter("captions", |read| {
+            Box::new(ClosedCaptionFilter::new(read, captions.clone()))
+        })
+        .filter("frame-stats", |read| {
+            Box::new(FrameAnalyzerFilter::read(read).with_registry(frame_stats.clone()))
+        })
+        .filter("bandwidth", |read| {
+            Box::new(BandwidthAnalyzerFilter::new(read, id, true, sender))
+        })
+        .filter("audio-levels", |read| {
+            Box::new(AudioLevelFilter::new(
+                read,
+                id,
+                data.audio_level_sender.clone(),
+            ))
+        })
+        .filter("anomalies", |read| {
+            let anomaly_sender = data.anomaly_sender.clone();
+            Box::new(AnomalyFilter::new(
+                read,
+                id,
+                anomaly_sender,
+                anomalies.clone(),
+            ))
+        });
+
+    let mut loudness = None;
+    if let Some(target) = data.loudness_target {
+        let meter = Arc::new(RwLock::new(Loudness::default()));
+        let gain = AudioGain::default();
+        loudness = Some(meter.clone());
+
+        graph = graph
+            .filter("loudness", |read| {
+                Box::new(LoudnessFilter::new(read, target, meter, gain.clone()))
+            })
+            .filter("normalize", |read| loudness::normalizer(read, gain));
+    }
+
+    let mut moderation = None;
+    if let Some(moderator) = &data.moderation {
+        let state = Arc::new(ModerationState::default());
+        moderation = Some(state.clone());
+
+        graph = graph.filter("moderation", |read| {
+            let moderator = moderator.clone();
+            Box::new(ModerationFilter::new(read, name.clone(), moderator, state))
+        });
+    }
+
+    let mut pip = None;
+    if data.composited_streams.contains(&name) {
+        let overlay = PipOverlay::new();
+        pip = Some(overlay.clone());
+
+        graph = graph.filter("compose", |read| {
+            compose::compositor(read, data.composite_options.clone(), overlay)
+        });
+    }
+
+    let snapshots = Arc::new(RwLock::new(Snapshots::default()));
+    graph = graph
+        .filter("snapshots", |read| {
+            Box::new(SnapshotProviderFilter::new(read, snapshots.clone()))
+        })
+        .filter("live", |read| {
+            Box::new(LiveFilter::new(read, id, repo.clone()))
+        })
+        .sink("queue", Box::new(queue.clone()))
+        .branch("relay", Box::new(relay.clone()));
+    if recorded {
+        graph = graph.branch(RECORDER_BRANCH, Box::new(recorder.clone()));
+    }
+    let mut graph = graph.build()?;
+
+    let streams = graph.start().await?;
+    let (control, run) = graph.run();
+    let source_streams = streams.clone();
+
+    let parameter_sets = streams.iter().find_map(|s| s.parameter_sets());
+    let video_codec = streams
+        .iter()
+        .find(|s| s.is_video())
+        .map(|s| format!("{:?}", s.codec));
+    let audio_codec = streams
+        .iter()
+        .find(|s| s.is_audio())
+        .map(|s| format!("{:?}", s.codec));
 
     let meta = StreamMetadata {
-        video_encoder: rtmp_meta.encoder,
-        video_bitrate_kbps: rtmp_meta.video_bitrate_kbps,
+        video_encoder: source.video_encoder,
+        video_bitrate_kbps: source.video_bitrate_kbps,
         parameter_sets,
+        video_codec,
+        audio_codec,
     };
 
     info!("Starting a stream for {} with id {}", name, id);
 
-    repo.write()
-        .unwrap()
-        .start_stream(id, name.clone(), queue.clone(), snapshot, meta);
+    {
+        let mut repo = repo.write();
+        if let Some(report) = source.report {
+            repo.reports.insert(name.clone(), report);
+        }
+        repo.start_stream(
+            id,
+            name.clone(),
+            queue.clone(),
+            snapshots,
+            source.capture,
+            meta,
+        );
+
+        let hls = data
+            .hls
+            .map(|config| (config, repo.hls_playlist(&name, SOURCE_RENDITION)));
+
+        if let Some(state) = repo.streams.get_mut(&id) {
+            state.streams = source_streams;
+            state.loudness = loudness;
+            state.frame_stats = frame_stats;
+            state.moderation = moderation;
+            state.pip = pip;
+            state.anomalies = anomalies;
+            state.captions = captions;
+            state.relay = relay;
+            state.recorder = recorder;
+            state.graph = Some(control);
+
+            if let Some((config, playlist)) = hls {
+                state.hls = Some(playlist.clone());
+
+                let read = queue.get_receiver_from_keyframe();
+                let name = name.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = sh_transport_hls::run_hls_output(read, playlist, config).await {
+                        debug!("HLS output of '{}' stopped: {:?}", name, e);
+                    }
+                });
+            }
+
+            if let Some(captions) = source.captions {
+                captions::spawn_caption_reader(captions, state.captions.clone());
+            }
 
-    async fn stream(
-        mut queue: MediaFrameQueue,
-        mut snapshot_provider: SnapshotProviderFilter,
-    ) -> anyhow::Result<()> {
-        loop {
-            let frame = snapshot_provider.read().await?;
-            queue.write(frame).await?;
+            abr::spawn_ladder(id, &state.streams, &data);
+
+            let expected = data.expected.clone();
+            let (name, streams) = (name.clone(), state.streams.clone());
+            tokio::spawn(async move { expected.remember(&name, &streams).await });
         }
+
+        repo.follow_recordings(id);
+        compose::attach_pips(&repo, &name);
+    }
+
+    for url in &data.push_urls {
+        push::spawn_push(url.replace("{stream}", &name), id, repo.clone());
+    }
+
+    for target in data.multicast_targets.iter().filter(|t| t.stream == name) {
+        multicast::spawn_multicast(target.clone(), id, repo.clone(), data.sap_interval);
     }
 
-    if let Err(e) = stream(queue, snapshot_provider).await {
-        error!("Error while ingesting: {:?}", e);
+    if let Some(discovery) = &data.discovery {
+        discovery.advertise_stream(&name);
+    }
+
+    // why the publisher went away, which is where the stream is left
+    let mut reason = match run.await {
+        Ok(()) => None,
+        Err(e) => {
+            error!("Error while ingesting: {:?}", e);
+            Some(e.to_string())
+        }
+    };
+
+    if let Some(grace) = data.stream_grace {
+        info!("Keeping the stream at '{}' for {:?}", name, grace);
+
+        repo.write()
+            .set_phase(id, StreamPhase::Grace, reason.take());
+        tokio::time::sleep(grace).await;
     }
 
     info!("Stopping a stream at '{}'", name);
 
-    repo.write().unwrap().stop_stream(id);
+    repo.write().stop_stream(id, reason, None);
+
+    if let Some(discovery) = &data.discovery {
+        discovery.withdraw_stream(&name);
+    }
 
     Ok(())
 }
@@ -285,6 +1164,7 @@ async fn rtmp_ingest(
 async fn process_rtmp_ingest(
     socket: TcpStream,
     addr: SocketAddr,
+    listener: Arc<RtmpListener>,
     client: StreamAuthServiceClient<Channel>,
     data: Arc<AppData>,
 ) -> anyhow::Result<()> {
@@ -296,36 +1176,54 @@ async fn process_rtmp_ingest(
 
     info!("Got a RTMP session from {} with app {}", req.addr(), app);
 
-    let repo = data.stream_repo.clone();
-    let sender = data.stream_stat_sender.clone();
+    let policy = match listener.policy(&app) {
+        Some(policy) => policy,
+        None => anyhow::bail!("RTMP app {} is not served at {}", app, listener.addr),
+    };
+    let stream_key = match policy.stream_key(&key) {
+        Some(stream_key) => stream_key,
+        None => anyhow::bail!("{} is not a known stream of RTMP app {}", key, app),
+    };
+
     let mut client = client.clone();
-    let is_public = app == "public";
 
-    let (id, name) = authenticate_rtmp_stream(&mut client, &key, is_public).await?;
+    let (id, name) = authenticate_stream(&mut client, stream_key, policy.listed).await?;
 
-    rtmp_ingest(id, name, req, sender, repo).await?;
+    rtmp_ingest(id, name, req, data).await?;
 
     Ok(())
 }
 
 async fn listen_rtmp(
-    addr: SocketAddr,
+    rtmp_listener: RtmpListener,
     client: StreamAuthServiceClient<Channel>,
     data: Arc<AppData>,
 ) -> anyhow::Result<()> {
-    info!("Listening for RTMP at {}", addr);
+    info!("Listening for RTMP at {}", rtmp_listener.addr);
 
-    let listener = TcpListener::bind(addr).await?;
+    let listener = TcpListener::bind(rtmp_listener.addr).await?;
+    let rtmp_listener = Arc::new(rtmp_listener);
 
     loop {
         match listener.accept().await {
-            Ok((socket, addr)) => {
-                info!("Got a TCP connection from {}", addr);
+            Ok((mut socket, peer)) => {
+                info!("Got a TCP connection from {}", peer);
 
+                let rtmp_listener = rtmp_listener.clone();
                 let client = client.clone();
                 let data = data.clone();
                 tokio::spawn(async move {
-                    if let Err(e) = process_rtmp_ingest(socket, addr, client, data).await {
+                    let addr = match data.proxy_protocol.client_addr(&mut socket, peer).await {
+                        Ok(addr) => addr,
+                        Err(e) => {
+                            error!("Failed to read the client of {}: {:?}", peer, e);
+                            return;
+                        }
+                    };
+
+                    if let Err(e) =
+                        process_rtmp_ingest(socket, addr, rtmp_listener, client, data).await
+                    {
                         error!("Failed to process RTMP ingest: {:?}", e);
                     }
                 });
@@ -337,7 +1235,127 @@ async fn listen_rtmp(
     }
 }
 
-async fn authenticate_rtmp_stream(
+async fn process_rtsp_ingest(
+    socket: TcpStream,
+    addr: SocketAddr,
+    data: Arc<AppData>,
+) -> anyhow::Result<()> {
+    let (req, app, key) = timeout(
+        Duration::from_secs(5),
+        RtspRequest::from_socket(socket, addr),
+    )
+    .await??;
+
+    info!("Got a RTSP session from {} with app {}", req.addr(), app);
+
+    let mut client = data.client.clone();
+    let is_public = app == "public";
+
+    let (id, name) = authenticate_stream(&mut client, &key, is_public).await?;
+
+    let filter = timeout(Duration::from_secs(5), req.accept()).await??;
+    ingest(id, name, IngestSource::new(Box::new(filter)), data).await?;
+
+    Ok(())
+}
+
+async fn listen_rtsp(addr: SocketAddr, data: Arc<AppData>) -> anyhow::Result<()> {
+    info!("Listening for RTSP at {}", addr);
+
+    let listener = TcpListener::bind(addr).await?;
+
+    loop {
+        match listener.accept().await {
+            Ok((mut socket, peer)) => {
+                info!("Got a TCP connection from {}", peer);
+
+                let data = data.clone();
+                tokio::spawn(async move {
+                    let addr = match data.proxy_protocol.client_addr(&mut socket, peer).await {
+                        Ok(addr) => addr,
+                        Err(e) => {
+                            error!("Failed to read the client of {}: {:?}", peer, e);
+                            return;
+                        }
+                    };
+
+                    if let Err(e) = process_rtsp_ingest(socket, addr, data).await {
+                        error!("Failed to process RTSP ingest: {:?}", e);
+                    }
+                });
+            }
+            Err(e) => {
+                error!("Failed to accept TCP connection: {:?}", e);
+            }
+        }
+    }
+}
+
+async fn process_srt_ingest(connection: SrtConnection, data: Arc<AppData>) -> anyhow::Result<()> {
+    let (app, key) = parse_srt_stream_id(connection.stream_id().unwrap_or_default());
+
+    info!(
+        "Got a SRT session from {} with app {}",
+        connection.peer(),
+        app
+    );
+
+    let mut client = data.client.clone();
+    let is_public = app == "public";
+
+    let (id, name) = match authenticate_stream(&mut client, key, is_public).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            connection.close().await;
+            return Err(e);
+        }
+    };
+
+    let (tx, rx) = async_channel::bounded(1024);
+    let filter = TsReadFilter::new(rx);
+    let ingest = tokio::spawn(ingest(id, name, IngestSource::new(Box::new(filter)), data));
+
+    // the ingest ends once the caller leaves and the channel closes
+    connection.receive(tx).await?;
+    ingest.await??;
+
+    Ok(())
+}
+
+/// Splits an SRT stream ID into the app and stream key, like the path of
+/// an RTMP URL. Both `public/key` and the access control syntax
+/// `#!::r=public/key,m=publish` are understood, and a stream ID without an
+/// app is just a stream key.
+fn parse_srt_stream_id(stream_id: &str) -> (&str, &str) {
+    let resource = match stream_id.strip_prefix("#!::") {
+        Some(fields) => fields
+            .split(',')
+            .find_map(|field| field.strip_prefix("r="))
+            .unwrap_or_default(),
+        None => stream_id,
+    };
+
+    resource.split_once('/').unwrap_or(("", resource))
+}
+
+async fn listen_srt(addr: SocketAddr, latency: Duration, data: Arc<AppData>) -> anyhow::Result<()> {
+    let listener = SrtListener::bind(addr, latency).await?;
+
+    info!("Listening for SRT at {}", addr);
+
+    loop {
+        let connection = listener.accept().await?;
+
+        let data = data.clone();
+        tokio::spawn(async move {
+            if let Err(e) = process_srt_ingest(connection, data).await {
+                error!("Failed to process SRT ingest: {:?}", e);
+            }
+        });
+    }
+}
+
+async fn authenticate_stream(
     client: &mut StreamAuthServiceClient<Channel>,
     supplied_stream_key: &str,
     is_public_stream: bool,
@@ -350,13 +1368,75 @@ async fn authenticate_rtmp_stream(
     Ok((response.stream_session_id, response.streamer_name))
 }
 
+/// Streams a stream as a single fragmented MP4 over chunked HTTP, the
+/// initialization segment followed by fragments as they are muxed, so
+/// `<video src=…>` and players like ffplay can play it without WebSockets.
 pub async fn http_video(
     Path(stream): Path<String>,
+    Query(params): Query<PlaybackParams>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Extension(data): Extension<Arc<AppData>>,
-) -> impl IntoResponse {
-    debug!("Received HTTP request for '{}'", stream);
+) -> Response<BoxBody> {
+    http_muxed(
+        Arc::new(CmafMuxer::new()),
+        stream,
+        params,
+        client,
+        headers,
+        data,
+    )
+    .await
+}
 
-    if let Some((queue_receiver, guard)) = ViewGuard::attach(stream.clone(), &data) {
+/// Streams a stream as live Matroska over chunked HTTP, for players like
+/// mpv and VLC.
+pub async fn http_matroska(
+    Path(stream): Path<String>,
+    Query(params): Query<PlaybackParams>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> Response<BoxBody> {
+    http_muxed(
+        Arc::new(MatroskaMuxer),
+        stream,
+        params,
+        client,
+        headers,
+        data,
+    )
+    .await
+}
+
+/// Streams a stream muxed by `muxer` over chunked HTTP, from its next
+/// keyframe for as long as it is live.
+async fn http_muxed(
+    muxer: Arc<dyn Muxer>,
+    stream: String,
+    params: PlaybackParams,
+    client: SocketAddr,
+    headers: HeaderMap,
+    data: Arc<AppData>,
+) -> Response<BoxBody> {
+    debug!("Received HTTP request for '{}' from {}", stream, client);
+
+    let session =
+        match entitlement::authorize_playback(&data, &stream, client, &headers, &params).await {
+            Ok(session) => session,
+            Err(status) => {
+                return Response::builder()
+                    .status(status)
+                    .body(boxed(Empty::new()))
+                    .unwrap();
+            }
+        };
+
+    let behind = params
+        .behind
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok());
+
+    if let Some((queue_receiver, guard)) = ViewGuard::attach(stream.clone(), &data, behind) {
         debug!("Found a stream at {}", stream);
 
         let sender = data.stream_stat_sender.clone();
@@ -368,38 +1448,62 @@ pub async fn http_video(
         ));
         let (output_filter, bytes_rx) = ByteStreamWriteFilter::new();
         let output_filter = Box::new(output_filter);
+        let content_type = muxer.content_type();
 
         task::spawn(async move {
-            if let Err(e) = stream_http_video(bw_analyzer, output_filter, guard).await {
+            let muxing = stream_muxed(bw_analyzer, &*muxer, output_filter);
+            if let Err(e) = stream_http_video(muxing, guard, session).await {
                 error!("Failed to stream video: {:?}", e);
             }
         });
 
-        boxed(StreamBody::new(bytes_rx))
+        // the response never ends while the stream is live, so it must not
+        // be cached
+        Response::builder()
+            .header("Content-Type", content_type)
+            .header("Cache-Control", "no-store")
+            .header("Access-Control-Allow-Origin", "*")
+            .body(boxed(StreamBody::new(bytes_rx)))
+            .unwrap()
     } else {
         debug!("Did not find a stream at {}", stream);
 
-        boxed(
-            Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .body(Empty::new())
-                .unwrap(),
-        )
+        Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(boxed(Empty::new()))
+            .unwrap()
     }
 }
 
 async fn stream_http_video(
+    muxing: impl Future<Output = anyhow::Result<()>>,
+    _guard: ViewGuard,
+    mut session: Option<PlaybackSession>,
+) -> anyhow::Result<()> {
+    tokio::select! {
+        res = muxing => res,
+        _ = entitlement::wait_for_kick(session.as_mut()) => {
+            anyhow::bail!("session was replaced by a newer one")
+        }
+    }
+}
+
+/// Muxes frames from `read` into fragmented MP4, starting at the first
+/// keyframe.
+async fn stream_fmp4(
+    read: Box<dyn FrameReadFilter + Unpin + Send>,
+    output: Box<dyn ByteWriteFilter2 + Unpin + Send>,
+) -> anyhow::Result<()> {
+    stream_muxed(read, &CmafMuxer::new(), output).await
+}
+
+/// Muxes frames from `read` with `muxer`, starting at the first keyframe.
+async fn stream_muxed(
     mut read: Box<dyn FrameReadFilter + Unpin + Send>,
+    muxer: &dyn Muxer,
     output: Box<dyn ByteWriteFilter2 + Unpin + Send>,
-    _guard: ViewGuard,
 ) -> anyhow::Result<()> {
-    // write
-    let fmp4_filter = Box::new(FragmentedMp4WriteFilter::new(output));
-    let write_analyzer = Box::new(FrameAnalyzerFilter::write(fmp4_filter));
-    let mut write = Box::new(BitstreamFramerFilter::new(
-        BitstreamFraming::FourByteLength,
-        write_analyzer,
-    ));
+    let mut write = FrameAnalyzerFilter::write(muxer.mux(output));
 
     let streams = read.start().await?;
     let first_frame = wait_for_sync_frame(&mut *read).await?;
@@ -416,25 +1520,113 @@ async fn stream_http_video(
 pub async fn websocket_video(
     ws: WebSocketUpgrade,
     Path(stream): Path<String>,
+    Query(params): Query<PlaybackParams>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Extension(data): Extension<Arc<AppData>>,
 ) -> impl IntoResponse {
-    debug!("Received websocket request for '{}'", stream);
+    let connected = Instant::now();
+    debug!(
+        "Received websocket request for '{}' from {}",
+        stream, client
+    );
+
+    let session =
+        match entitlement::authorize_playback(&data, &stream, client, &headers, &params).await {
+            Ok(session) => session,
+            Err(status) => {
+                return Response::builder()
+                    .status(status)
+                    .body(body::Full::from("Not allowed to play this stream"))
+                    .unwrap()
+                    .into_response();
+            }
+        };
+
+    let client = ViewerClient {
+        addr: client,
+        user_agent: headers
+            .get(USER_AGENT)
+            .and_then(|h| h.to_str().ok())
+            .map(String::from),
+        connected,
+    };
 
-    ws.on_upgrade(move |socket| handle_websocket_video_response(socket, stream, data))
+    ws.on_upgrade(move |socket| {
+        handle_websocket_video_response(socket, stream, data, session, params, client)
+    })
+    .into_response()
+}
+
+/// Who a WebSocket viewer is, and when it connected.
+struct ViewerClient {
+    addr: SocketAddr,
+    user_agent: Option<String>,
+    connected: Instant,
 }
 
 struct ViewGuard(i32, Arc<AppData>);
 
 impl ViewGuard {
-    pub fn attach(stream: String, data: &Arc<AppData>) -> Option<(MediaFrameQueueReceiver, Self)> {
-        let mut repo = data.stream_repo.write().unwrap();
+    /// Starts receiving the frames of a stream, `behind` live if the
+    /// viewer asks to.
+    pub fn attach(
+        stream: String,
+        data: &Arc<AppData>,
+        behind: Option<Duration>,
+    ) -> Option<(MediaFrameQueueReceiver, Self)> {
+        Self::attach_rendition(stream, SOURCE_RENDITION, data, behind)
+    }
+
+    /// Starts receiving the frames of a rendition of a stream, `behind`
+    /// live if the viewer asks to, or else from the latest keyframe so the
+    /// viewer doesn't wait for the next one.
+    pub fn attach_rendition(
+        stream: String,
+        rendition: &str,
+        data: &Arc<AppData>,
+        behind: Option<Duration>,
+    ) -> Option<(MediaFrameQueueReceiver, Self)> {
+        let mut repo = data.stream_repo.write();
 
         let stream_id = *repo.stream_mapping.get(&stream)?;
 
+        let entry = repo.streams.get(&stream_id).filter(|e| e.is_live())?;
+        let queue = entry.rendition(rendition)?;
+        let receiver = match behind {
+            Some(behind) => queue.get_receiver_behind(behind),
+            None => queue.get_receiver_from_keyframe(),
+        };
+
+        repo.viewer_join(stream_id);
+        drop(repo);
+
+        let guard = ViewGuard(stream_id, data.clone());
+
+        Some((receiver, guard))
+    }
+
+    /// Starts receiving the frames of a stream where a viewer left off, if
+    /// its token is from the current session and its keyframe is still
+    /// cached.
+    pub fn resume(
+        stream: String,
+        data: &Arc<AppData>,
+        token: &ResumeToken,
+    ) -> Option<(MediaFrameQueueReceiver, Self)> {
+        let mut repo = data.stream_repo.write();
+
+        let stream_id = *repo.stream_mapping.get(&stream)?;
+        if stream_id != token.session {
+            return None;
+        }
+
         let receiver = repo
             .streams
             .get(&stream_id)
-            .map(|s| s.queue.get_receiver())?;
+            .filter(|e| e.is_live())?
+            .queue
+            .get_receiver_at_keyframe(token.keyframe)?;
 
         repo.viewer_join(stream_id);
         drop(repo);
@@ -447,24 +1639,254 @@ impl ViewGuard {
 
 impl Drop for ViewGuard {
     fn drop(&mut self) {
-        self.1
-            .stream_repo
-            .write()
-            .unwrap()
-            .viewer_disconnect(self.0);
+        self.1.stream_repo.write().viewer_disconnect(self.0);
+    }
+}
+
+/// A stream session as shown by the API.
+#[derive(Serialize, TS)]
+struct StreamSummary {
+    session: i32,
+    name: String,
+    phase: StreamPhase,
+    /// Seconds since the UNIX epoch of when the stream was registered.
+    #[ts(type = "number")]
+    started: u64,
+    /// Seconds since the UNIX epoch of when the stream changed to its
+    /// current phase.
+    #[ts(type = "number")]
+    phase_changed: u64,
+    viewers: u32,
+    /// How many read the source, like viewers, recordings and pushes.
+    readers: usize,
+    renditions: Vec<String>,
+    /// The codecs of the source, like `h264` and `aac`.
+    codecs: Vec<&'static str>,
+}
+
+/// Lists the stream sessions in the repository, with their phases.
+pub async fn list_streams(
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> Response<BoxBody> {
+    if !diagnostics::is_admin(&data, &headers) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(boxed(body::Full::from("Missing or invalid admin token")))
+            .unwrap();
+    }
+
+    let mut streams = {
+        let repo = data.stream_repo.read();
+
+        repo.streams
+            .iter()
+            .map(|(id, entry)| {
+                let mut renditions = entry.renditions.keys().cloned().collect::<Vec<_>>();
+                renditions.sort();
+
+                StreamSummary {
+                    session: *id,
+                    name: entry.name.clone(),
+                    phase: entry.lifecycle.phase(),
+                    started: unix_secs(entry.started),
+                    phase_changed: entry.lifecycle.phase_changed(),
+                    viewers: entry.viewers,
+                    readers: entry.queue.readers(),
+                    renditions,
+                    codecs: entry.streams.iter().map(|s| s.codec.name).collect(),
+                }
+            })
+            .collect::<Vec<_>>()
+    };
+    streams.sort_by_key(|s| s.session);
+
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(boxed(body::Full::from(
+            serde_json::to_vec(&streams).unwrap_or_default(),
+        )))
+        .unwrap()
+}
+
+/// The lifecycle of a stream session as shown by the API.
+#[derive(Serialize, TS)]
+struct StreamState {
+    session: i32,
+    name: String,
+    phase: StreamPhase,
+    /// The phases the session went through, oldest first.
+    transitions: Vec<PhaseTransition>,
+}
+
+/// Returns the lifecycle of the latest session of a stream, which is kept
+/// after the stream ends.
+pub async fn get_stream(
+    Path(stream): Path<String>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> Response<BoxBody> {
+    if !diagnostics::is_admin(&data, &headers) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(boxed(body::Full::from("Missing or invalid admin token")))
+            .unwrap();
+    }
+
+    let state = data
+        .stream_repo
+        .read()
+        .lifecycle(&stream)
+        .map(|lifecycle| StreamState {
+            session: lifecycle.stream_session_id,
+            name: stream.clone(),
+            phase: lifecycle.phase(),
+            transitions: lifecycle.transitions().to_vec(),
+        });
+
+    match state {
+        Some(state) => Response::builder()
+            .header("Content-Type", "application/json")
+            .body(boxed(body::Full::from(
+                serde_json::to_vec(&state).unwrap_or_default(),
+            )))
+            .unwrap(),
+        None => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(boxed(body::Full::from("No stream with that name")))
+            .unwrap(),
     }
 }
 
-async fn handle_websocket_video_response(socket: WebSocket, stream: String, data: Arc<AppData>) {
-    if let Some((queue_receiver, guard)) = ViewGuard::attach(stream.clone(), &data) {
+/// Returns the renditions of a stream which can be played over MSE, the
+/// source first.
+fn rendition_offers(data: &AppData, stream: &str) -> Vec<RenditionOffer> {
+    let repo = data.stream_repo.read();
+
+    let state = match repo
+        .stream_mapping
+        .get(stream)
+        .and_then(|id| repo.streams.get(id))
+    {
+        Some(state) => state,
+        None => return Vec::new(),
+    };
+
+    let mut names = state.renditions.keys().cloned().collect::<Vec<_>>();
+    names.sort();
+    names.insert(0, SOURCE_RENDITION.to_string());
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let streams = state.rendition(&name)?.get_streams();
+            let codecs = sh_transport_mse::codec_string(&streams).ok()?;
+
+            Some(RenditionOffer { name, codecs })
+        })
+        .collect()
+}
+
+async fn handle_websocket_video_response(
+    mut socket: WebSocket,
+    stream: String,
+    data: Arc<AppData>,
+    mut session: Option<PlaybackSession>,
+    params: PlaybackParams,
+    client: ViewerClient,
+) {
+    let behind = params
+        .behind
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok());
+
+    // players which can check what they decode pick a rendition, instead of
+    // being sent the source whether or not they can play it
+    let rendition = if params.negotiate.unwrap_or(false) {
+        let offers = rendition_offers(&data, &stream);
+        if offers.is_empty() {
+            debug!("Did not find a stream at {}", stream);
+            return;
+        }
+
+        match sh_transport_mse::negotiate_rendition(&mut socket, &offers).await {
+            Ok(Some(rendition)) => rendition,
+            Ok(None) => {
+                info!("A viewer of '{}' can't decode any rendition", stream);
+                return;
+            }
+            Err(e) => {
+                debug!("Failed to negotiate a rendition of '{}': {:?}", stream, e);
+                return;
+            }
+        }
+    } else {
+        SOURCE_RENDITION.to_string()
+    };
+
+    // viewers which can't resume where they left off start over, and only
+    // the source keeps the keyframes which tokens point at
+    let resume = params
+        .resume
+        .as_deref()
+        .and_then(|token| token.parse::<ResumeToken>().ok())
+        .or_else(|| {
+            params
+                .segment
+                .and_then(|sequence| hls::segment_resume_token(&data, &stream, sequence))
+        })
+        .filter(|_| rendition == SOURCE_RENDITION);
+    let resumed =
+        resume.and_then(|token| Some((ViewGuard::resume(stream.clone(), &data, &token)?, token)));
+    let (attached, resume) = match resumed {
+        Some((attached, token)) => (Some(attached), Some(token)),
+        None => (
+            ViewGuard::attach_rendition(stream.clone(), &rendition, &data, behind),
+            None,
+        ),
+    };
+
+    if let Some((queue_receiver, guard)) = attached {
         debug!("Found a stream at {}", stream);
 
+        let stats = Arc::new(DeliveryStats::default());
+        stats.set_connected(client.connected);
+        let _delivery = data.deliveries.track(Viewer {
+            stream: stream.clone(),
+            session: guard.0,
+            rendition,
+            user: session.as_ref().map(|s| s.user().to_string()),
+            user_agent: client.user_agent,
+            client: client.addr,
+            stats: stats.clone(),
+            backlog: queue_receiver.backlog(),
+        });
+
         let sender = data.stream_stat_sender.clone();
         let mut bw_analyzer =
             BandwidthAnalyzerFilter::new(Box::new(queue_receiver), guard.0, false, sender);
 
-        if let Err(e) = sh_transport_mse::start_websocket_filters(socket, &mut bw_analyzer).await {
-            error!("Failed to run WebSocket filters: {:?}", e);
+        let mut hints = data.reconnect_hints.clone();
+        for url in &mut hints.alternatives {
+            *url = url.replace("{stream}", &stream);
+        }
+
+        tokio::select! {
+            res = sh_transport_mse::start_websocket_filters(
+                socket,
+                &mut bw_analyzer,
+                Some(&hints),
+                params.rate.unwrap_or(1.0),
+                guard.0,
+                resume,
+                stats,
+            ) => {
+                if let Err(e) = res {
+                    error!("Failed to run WebSocket filters: {:?}", e);
+                }
+            }
+            _ = entitlement::wait_for_kick(session.as_mut()) => {
+                info!("Stopped a viewer of '{}' for a newer session", stream);
+            }
         }
     } else {
         debug!("Did not find a stream at {}", stream);
@@ -477,12 +1899,12 @@ pub async fn snapshot(
 ) -> impl IntoResponse {
     debug!("Received snapshot request for '{}'", stream);
 
-    let repo = data.stream_repo.write().unwrap();
+    let repo = data.stream_repo.write();
 
     let stream_id = repo.stream_mapping.get(&stream);
     let meta = stream_id.and_then(|id| repo.streams.get(id));
 
-    if let Some(frame) = meta.and_then(|m| m.snapshot.read().unwrap().clone()) {
+    if let Some(frame) = meta.and_then(|m| m.snapshots.read().latest.clone()) {
         match sh_fmp4::single_frame_fmp4(frame) {
             Ok(bytes) => Response::builder()
                 .header("Content-Type", "video/mp4")
@@ -516,6 +1938,32 @@ fn resolve_env_addr(var: &str, default: &str) -> SocketAddr {
         .unwrap_or_else(|| panic!("Failed to resolve {}", var))
 }
 
+/// Reads the programs of each transport stream listener from a list like
+/// `addr=key,addr=key@program`.
+fn parse_ts_listeners(
+    var: &str,
+    teletext_page: Option<u16>,
+) -> anyhow::Result<HashMap<SocketAddr, Vec<rist::TsProgram>>> {
+    let mut listeners = HashMap::<SocketAddr, Vec<rist::TsProgram>>::new();
+
+    for listener in env(var, "").split(',') {
+        if let Some((addr, stream_key)) = listener.trim().split_once('=') {
+            let (stream_key, program) = stream_key.split_once('@').unwrap_or((stream_key, ""));
+
+            listeners
+                .entry(addr.parse()?)
+                .or_default()
+                .push(rist::TsProgram {
+                    stream_key: stream_key.to_string(),
+                    program: program.parse()?,
+                    teletext_page,
+                });
+        }
+    }
+
+    Ok(listeners)
+}
+
 async fn start() -> anyhow::Result<()> {
     let ingest_rtmp_addr = resolve_env_addr("INGEST_RTMP_ADDR", "localhost:1935");
     let ingest_web_addr = resolve_env_addr("INGEST_WEB_ADDR", "localhost:8080");
@@ -530,18 +1978,445 @@ async fn start() -> anyhow::Result<()> {
         .connect_lazy();
     let client = StreamAuthServiceClient::new(client_endpoint);
 
+    let admin_token = std::env::var("INGEST_ADMIN_TOKEN").ok();
+    let capture_dir = PathBuf::from(env("INGEST_CAPTURE_DIR", "captures"));
+    let recording_dir = PathBuf::from(env("INGEST_RECORDING_DIR", "recordings"));
+    let export_dir = PathBuf::from(env("INGEST_EXPORT_DIR", "exports"));
+    let push_urls = env("INGEST_PUSH_URLS", "")
+        .split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(String::from)
+        .collect();
+    // e.g. "main=239.1.1.1:5000@4#smooth:30,backstage=239.1.1.2:5000", the
+    // TTL defaulting to 1 and the timestamps of video passed through unless
+    // smoothed or duplicated to a frame rate like 30 or 30000/1001
+    let multicast_targets = env("INGEST_MULTICAST_OUTPUTS", "")
+        .split(',')
+        .map(str::trim)
+        .filter(|target| !target.is_empty())
+        .map(str::parse)
+        .collect::<anyhow::Result<_>>()?;
+    // how often multicast outputs are announced to the network, 0 to disable
+    let sap_interval = match env("INGEST_SAP_INTERVAL_SECS", "5").parse()? {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    // the name the server is advertised as on the local network with mDNS,
+    // empty to disable
+    let discovery = match env("INGEST_MDNS_NAME", "streamhead") {
+        name if name.is_empty() => None,
+        name => Some(Arc::new(Discovery::new(&name, ingest_web_addr.port())?)),
+    };
+    let recording_targets = env("INGEST_RECORDING_TARGETS", "")
+        .split(',')
+        .filter_map(|target| target.split_once('='))
+        .map(|(rendition, dir)| (rendition.trim().to_string(), PathBuf::from(dir.trim())))
+        .collect();
+    // e.g. "{stream}/{date}/{seq}.mp4", empty for "<recording>-<seq>.mp4"
+    let recording_template = match env("INGEST_RECORDING_TEMPLATE", "").as_str() {
+        "" => None,
+        template => Some(template.parse()?),
+    };
+    // "never", "finish" or "fragment"
+    let fsync_policy = env("INGEST_FSYNC", "finish").parse()?;
+    // "mp4" or "mkv"
+    let recording_muxer = recording::recording_muxer(&env("INGEST_RECORDING_FORMAT", "mp4"))?;
+    let reconnect_hints = ReconnectHints {
+        initial_delay_ms: env("INGEST_RECONNECT_INITIAL_MS", "1000").parse()?,
+        max_delay_ms: env("INGEST_RECONNECT_MAX_MS", "30000").parse()?,
+        alternatives: env("INGEST_RECONNECT_ALTERNATIVES", "")
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(String::from)
+            .collect(),
+    };
+
+    // viewers need a session from this issuer to watch, if it is set
+    let viewer_auth = match env("INGEST_OIDC_ISSUER", "").as_str() {
+        "" => None,
+        issuer => Some(Arc::new(OidcVerifier::new(OidcConfig {
+            issuer: issuer.to_string(),
+            audience: env("INGEST_OIDC_AUDIENCE", ""),
+            cookie: env("INGEST_OIDC_COOKIE", "id_token"),
+        }))),
+    };
+
+    let entitlements = Arc::new(Entitlements::new(
+        Some(env("INGEST_ENTITLEMENT_URL", "")).filter(|url| !url.is_empty()),
+        env("INGEST_MAX_SESSIONS_PER_USER", "0").parse()?,
+    ));
+
+    // e.g. "-16", the loudness in LUFS the audio of streams is normalized
+    // to, which re-encodes it
+    let loudness_target = match env("INGEST_LOUDNESS_TARGET", "").as_str() {
+        "" => None,
+        target => Some(target.parse()?),
+    };
+    if loudness_target.is_some() && !cfg!(feature = "transcode") {
+        anyhow::bail!("INGEST_LOUDNESS_TARGET needs qwer-ingest built with the transcode feature");
+    }
+
+    // sampled keyframes are POSTed here every INGEST_MODERATION_INTERVAL
+    // seconds, and the response can flag, pause or kick the stream
+    let moderation = match env("INGEST_MODERATION_URL", "").as_str() {
+        "" => None,
+        url => Some(Arc::new(Moderation::new(
+            url.to_string(),
+            Duration::from_secs(env("INGEST_MODERATION_INTERVAL", "30").parse()?),
+        ))),
+    };
+
+    // how many played files are kept segmented in memory
+    let packaging_cache = Arc::new(PackagingCache::new(
+        env("INGEST_PACKAGING_CACHE_SIZE", "64").parse()?,
+    ));
+
+    // background jobs are kept in this file across restarts, if it is set
+    let job_file = env("INGEST_JOB_FILE", "jobs.json");
+    let jobs = Arc::new(JobQueue::new(
+        Some(PathBuf::from(&job_file)).filter(|_| !job_file.is_empty()),
+        fsync_policy != FsyncPolicy::Never,
+        env("INGEST_JOB_CONCURRENCY", "2").parse()?,
+        env("INGEST_JOB_ATTEMPTS", "3").parse()?,
+    ));
+    jobs.load().await?;
+
+    // the RTMP and SRT servers streams are restreamed to, kept in this file
+    // if it is set
+    let relay_file = env("INGEST_RELAY_FILE", "relays.json");
+    let relays = Arc::new(RelayManager::new(
+        Some(PathBuf::from(&relay_file)).filter(|_| !relay_file.is_empty()),
+        fsync_policy != FsyncPolicy::Never,
+    ));
+    relays.load().await?;
+
+    // the streams which are expected to be published, which players can
+    // wait for before their publishers connect
+    let expected_streams = env("INGEST_EXPECTED_STREAMS", "")
+        .split(',')
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect::<Vec<_>>();
+    // where the last known codecs of expected streams are kept, empty to
+    // forget them on restart
+    let codec_cache = env("INGEST_CODEC_CACHE", "codecs.json");
+    let expected = Arc::new(ExpectedStreams::new(
+        &expected_streams,
+        Some(PathBuf::from(&codec_cache)).filter(|_| !codec_cache.is_empty()),
+        fsync_policy != FsyncPolicy::Never,
+    ));
+    expected.load().await?;
+
+    // how many seconds a stream is kept after its publisher goes away
+    // before it is stopped, so quick reconnects don't show up as stopped
+    // streams, 0 to stop streams right away
+    let stream_grace = match env("INGEST_STREAM_GRACE_SECS", "0").parse()? {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+
+    // "false" to leave pull-based sources, like files and failover
+    // channels, stopped after their pipeline panics
+    let restart_sources = env("INGEST_RESTART_SOURCES_ON_PANIC", "true").parse()?;
+
+    // how many seconds behind live viewers can start watching, 0 to disable
+    let dvr_window = match env("INGEST_DVR_WINDOW_SECS", "0").parse()? {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+
+    // the shortest length of HLS segments in seconds, 0 to not serve HLS
+    let hls = match env("INGEST_HLS_SEGMENT_SECS", "2").parse()? {
+        0 => None,
+        secs => Some(HlsConfig {
+            segment_duration: Duration::from_secs(secs),
+            // the longest partial segments of low-latency HLS, 0 to disable
+            part_duration: match env("INGEST_HLS_PART_MS", "0").parse()? {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            window: env("INGEST_HLS_WINDOW", "6").parse()?,
+            // "true" to send segments while they are written, a CMAF chunk
+            // per frame, for low-latency DASH
+            chunked: env("INGEST_CMAF_CHUNKED", "false").parse()?,
+        }),
+    };
+
+    // the renditions streams are transcoded to, like
+    // "1080p:6000,720p:3000,480p:1200" with bitrates in kbit/s, empty to
+    // only serve streams as published
+    let abr_ladder = abr::parse_ladder(&env("INGEST_ABR_LADDER", ""))?;
+    if !abr_ladder.is_empty() && !cfg!(feature = "transcode") {
+        anyhow::bail!("INGEST_ABR_LADDER needs qwer-ingest built with the transcode feature");
+    }
+
+    // the comma separated streams which picture-in-picture streams can be
+    // composited over, which re-encodes them at this bitrate in kbit/s
+    let composited_streams = env("INGEST_COMPOSITED_STREAMS", "")
+        .split(',')
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect::<Vec<_>>();
+    let composite_options = TranscodeOptions {
+        width: 0,
+        height: 0,
+        bitrate: env("INGEST_COMPOSITE_BITRATE_KBPS", "6000").parse::<usize>()? * 1000,
+        keyframe_interval: abr::MAX_KEYFRAME_INTERVAL,
+    };
+    if !composited_streams.is_empty() && !cfg!(feature = "transcode") {
+        anyhow::bail!(
+            "INGEST_COMPOSITED_STREAMS needs qwer-ingest built with the transcode feature"
+        );
+    }
+
+    // the largest resolution, frame rate and bitrate streamers are
+    // recommended, and the egress in kbit/s which recommended bitrates are
+    // lowered towards, 0 to not look at the load
+    let (max_width, max_height) = parse_resolution(&env("INGEST_MAX_RESOLUTION", "1920x1080"))
+        .ok_or_else(|| anyhow::anyhow!("INGEST_MAX_RESOLUTION is not like 1920x1080"))?;
+    let encoder_policy = EncoderPolicy {
+        max_bitrate_kbps: env("INGEST_MAX_BITRATE_KBPS", "8000").parse()?,
+        max_width,
+        max_height,
+        max_fps: env("INGEST_MAX_FPS", "60").parse()?,
+        egress_capacity_kbps: match env("INGEST_EGRESS_CAPACITY_KBPS", "0").parse()? {
+            0 => None,
+            kbps => Some(kbps),
+        },
+    };
+
+    // load balancers at these comma separated addresses put a PROXY protocol
+    // header in front of the RTMP, RTSP and HTTP connections they forward
+    let proxy_protocol = ProxyProtocol::new(
+        env("INGEST_PROXY_PROTOCOL_FROM", "")
+            .split(',')
+            .map(str::trim)
+            .filter(|ip| !ip.is_empty())
+            .map(str::parse::<IpAddr>)
+            .collect::<Result<_, _>>()?,
+    );
+
+    // how long dashboards go without events before they are sent a
+    // keep-alive, and how many may follow the event feed at once
+    let events = Arc::new(EventFeed::new(
+        Duration::from_secs(
+            env("INGEST_EVENTS_KEEPALIVE_SECS", "15")
+                .parse::<u64>()?
+                .max(1),
+        ),
+        env("INGEST_EVENTS_MAX_CONSUMERS", "32").parse()?,
+    ));
+
     let (stream_stat_sender, _) = broadcast::channel(512);
+    let (audio_level_sender, _) = broadcast::channel(512);
+    let (viewer_stat_sender, _) = broadcast::channel(512);
+    let (anomaly_sender, _) = broadcast::channel(512);
+    let (congestion_sender, _) = broadcast::channel(512);
     let data = Arc::new(AppData {
         stream_repo,
         client: client.clone(),
         stream_stat_sender,
+        audio_level_sender,
+        viewer_stat_sender,
+        anomaly_sender,
+        congestion_sender,
+        deliveries: Default::default(),
+        events,
+        admin_token,
+        capture_dir,
+        recording_dir,
+        recording_targets,
+        recording_template,
+        fsync_policy,
+        recording_muxer,
+        canary_results: Default::default(),
+        reconnect_hints,
+        push_urls,
+        multicast_targets,
+        sap_interval,
+        discovery,
+        viewer_auth,
+        entitlements,
+        loudness_target,
+        moderation,
+        vod: Default::default(),
+        packaging_cache,
+        export_dir,
+        jobs,
+        relays,
+        expected,
+        whip_sessions: Default::default(),
+        whep_sessions: Default::default(),
+        dvr_window,
+        hls,
+        abr_ladder,
+        composited_streams,
+        composite_options,
+        encoder_policy,
+        stream_grace,
+        proxy_protocol,
+        supervisor: Arc::new(Supervisor::new(restart_sources)),
     });
 
-    {
+    jobs::resume(&data);
+
+    // completed files in this directory are served as VOD, if it is set
+    let vod_dir = env("INGEST_VOD_DIR", "");
+    if !vod_dir.is_empty() {
+        vod::spawn_vod_watcher(PathBuf::from(vod_dir), data.vod.clone());
+    }
+
+    let rist_buffer = Duration::from_millis(env("INGEST_RIST_BUFFER_MS", "1000").parse()?);
+    // the teletext page captions are extracted from, empty to disable
+    let teletext_page = match env("INGEST_TELETEXT_PAGE", "888").as_str() {
+        "" => None,
+        page => Some(page.parse()?),
+    };
+    // "addr=key" publishes the first program, "addr=key@program" selects a
+    // program by service ID or by PMT PID ("pid:0x100"), and repeating an
+    // address publishes several programs of the same transport stream
+    for (addr, programs) in parse_ts_listeners("INGEST_RIST_LISTENERS", teletext_page)? {
+        rist::spawn_rist_listener(addr, programs, rist_buffer, data.clone());
+    }
+    // plain MPEG-TS over UDP from hardware encoders, configured the same way
+    for (addr, programs) in parse_ts_listeners("INGEST_UDP_LISTENERS", teletext_page)? {
+        rist::spawn_udp_listener(addr, programs, data.clone());
+    }
+
+    // "key=primary|backup,..." publishes key as the first healthy input
+    let failover_config = failover::FailoverConfig {
+        loss_after: Duration::from_millis(env("INGEST_FAILOVER_LOSS_MS", "3000").parse()?),
+        recover_after: Duration::from_millis(env("INGEST_FAILOVER_RECOVER_MS", "10000").parse()?),
+    };
+    for channel in env("INGEST_FAILOVER_CHANNELS", "").split(',') {
+        if let Some((stream_key, inputs)) = channel.trim().split_once('=') {
+            failover::spawn_failover_channel(
+                stream_key.to_string(),
+                inputs.split('|').map(String::from).collect(),
+                failover_config,
+                data.clone(),
+            );
+        }
+    }
+
+    // "key=video+audio[@delay_ms],..." publishes key with the video of one
+    // stream and the audio of another, with the audio delayed by delay_ms
+    for remapped in env("INGEST_REMAPPED_STREAMS", "").split(',') {
+        if let Some((stream_key, sources)) = remapped.trim().split_once('=') {
+            let (sources, audio_delay_ms) = match sources.split_once('@') {
+                Some((sources, delay)) => (sources, delay.parse()?),
+                None => (sources, 0),
+            };
+            let (video_source, audio_source) = match sources.split_once('+') {
+                Some(sources) => sources,
+                None => anyhow::bail!(
+                    "remapped stream {} needs a video and an audio source",
+                    stream_key
+                ),
+            };
+
+            remap::spawn_remapped_stream(
+                stream_key.to_string(),
+                video_source.to_string(),
+                audio_source.to_string(),
+                audio_delay_ms,
+                data.clone(),
+            );
+        }
+    }
+
+    // "key=path[@loop],..." publishes key from an MP4 or FLV file in real
+    // time, starting over at the end of the file with "@loop"
+    for file_stream in env("INGEST_FILE_STREAMS", "").split(',') {
+        if let Some((stream_key, path)) = file_stream.trim().split_once('=') {
+            let (path, looped) = match path.rsplit_once('@') {
+                Some((path, "loop")) => (path, true),
+                _ => (path, false),
+            };
+
+            file_source::spawn_file_stream(
+                stream_key.to_string(),
+                PathBuf::from(path),
+                looped,
+                data.clone(),
+            );
+        }
+    }
+
+    relay::spawn_relay_manager(data.clone());
+    delivery::spawn_delivery_reporter(data.clone());
+    events::spawn_event_feed(data.clone());
+
+    // how full in percent the disks recordings are written to can get
+    // before an alert is raised, 0 to not check them
+    let disk_full_percent: u32 = env("INGEST_DISK_FULL_PERCENT", "95").parse()?;
+    if disk_full_percent > 0 {
+        disk::spawn_disk_checks(data.clone(), disk_full_percent as f64 / 100.0);
+    }
+
+    let canary_interval: u64 = env("INGEST_CANARY_INTERVAL", "60").parse()?;
+    if canary_interval > 0 {
+        canary::spawn_canary(data.clone(), Duration::from_secs(canary_interval));
+    }
+
+    // the RTMP listeners and the policies of their apps are read from this
+    // file if it is set, or else there is one listener at INGEST_RTMP_ADDR
+    let rtmp_listener_file = env("INGEST_RTMP_LISTENERS", "");
+    let rtmp_listeners = if rtmp_listener_file.is_empty() {
+        vec![RtmpListener::with_defaults(ingest_rtmp_addr)]
+    } else {
+        load_rtmp_listeners(std::path::Path::new(&rtmp_listener_file)).await?
+    };
+
+    for rtmp_listener in rtmp_listeners {
+        let client = client.clone();
+        let data = data.clone();
+        tokio::spawn(async move {
+            let addr = rtmp_listener.addr;
+            if let Err(e) = listen_rtmp(rtmp_listener, client, data).await {
+                error!("Error while listening on RTMP at {}: {:?}", addr, e);
+            }
+        });
+    }
+
+    // callers push MPEG-TS over SRT to this address, if it is set
+    let ingest_srt_addr = env("INGEST_SRT_ADDR", "");
+    if !ingest_srt_addr.is_empty() {
+        let addr = resolve_env_addr("INGEST_SRT_ADDR", "");
+        let latency = Duration::from_millis(env("INGEST_SRT_LATENCY_MS", "120").parse()?);
+
+        let data = data.clone();
+        tokio::spawn(async move {
+            if let Err(e) = listen_srt(addr, latency, data).await {
+                error!("Error while listening on SRT: {:?}", e);
+            }
+        });
+    }
+
+    // callers pull streams as MPEG-TS over SRT from this address, if it is
+    // set, e.g. with `srt://host:port?streamid=<stream>`
+    let playback_srt_addr = env("INGEST_SRT_PLAYBACK_ADDR", "");
+    if !playback_srt_addr.is_empty() {
+        let addr = resolve_env_addr("INGEST_SRT_PLAYBACK_ADDR", "");
+        let latency = Duration::from_millis(env("INGEST_SRT_LATENCY_MS", "120").parse()?);
+        // e.g. "smooth:30" or "duplicate:30000/1001" for decoders which play
+        // variable frame rate video unevenly, empty to pass it through
+        let frame_rate = env("INGEST_SRT_PLAYBACK_FRAME_RATE", "").parse()?;
+
+        srt_playback::spawn_srt_playback(addr, latency, frame_rate, data.clone());
+    }
+
+    // cameras and encoders publish over RTSP to this address, if it is set
+    let ingest_rtsp_addr = env("INGEST_RTSP_ADDR", "");
+    if !ingest_rtsp_addr.is_empty() {
+        let addr = resolve_env_addr("INGEST_RTSP_ADDR", "");
+
         let data = data.clone();
         tokio::spawn(async move {
-            if let Err(e) = listen_rtmp(ingest_rtmp_addr, client, data).await {
-                error!("Error while listening on RTMP: {:?}", e);
+            if let Err(e) = listen_rtsp(addr, data).await {
+                error!("Error while listening on RTSP: {:?}", e);
             }
         });
     }
@@ -549,14 +2424,141 @@ async fn start() -> anyhow::Result<()> {
     let app = Router::new()
         .route("/transport/mse/:stream", get(websocket_video))
         .route("/transport/http/:stream", get(http_video))
+        .route("/mp4/:stream", get(http_video))
+        .route("/mkv/:stream", get(http_matroska))
+        .route("/audio/:stream", get(audio::audio_stream))
         .route("/snapshot/:stream", get(snapshot))
+        .route("/health", get(canary::health))
+        .route("/preview/:stream", get(preview::preview))
+        .route("/captions/:stream", get(captions::captions))
+        .route("/loudness/:stream", get(loudness::loudness))
+        .route("/moderation/:stream", get(moderation::moderation_status))
+        .route(
+            "/moderation/:stream/pause",
+            delete(moderation::resume_stream),
+        )
+        .route(
+            "/diagnostics/capture/:stream",
+            post(diagnostics::start_capture),
+        )
+        .route(
+            "/diagnostics/report/:stream",
+            get(diagnostics::conformance_report),
+        )
+        .route(
+            "/diagnostics/dump/:stream",
+            post(diagnostics::dump_bitstream),
+        )
+        .route("/debug/frames/:stream", get(diagnostics::frames))
+        .route(
+            "/recordings/:stream",
+            post(recording::start_recording).delete(recording::stop_recording),
+        )
+        .route("/archive/:recording/verify", get(archive::verify_archive))
+        .route(
+            "/archive/:recording/index.m3u8",
+            get(archive::recording_playlist),
+        )
+        .route(
+            "/archive/:recording/iframes.m3u8",
+            get(archive::recording_iframe_playlist),
+        )
+        .route(
+            "/archive/:recording/master.m3u8",
+            get(archive::recording_master_playlist),
+        )
+        .route(
+            "/archive/:recording/manifest.mpd",
+            get(archive::recording_manifest),
+        )
+        .route(
+            "/archive/:recording/files/:part",
+            get(archive::recording_file),
+        )
+        .route(
+            "/api/recordings/:id/download",
+            get(download::download_recording),
+        )
+        .route("/api/recordings/:id/export", post(export::start_export))
+        .route("/api/jobs/:id", get(jobs::job_status))
+        .route(
+            "/api/relays",
+            get(relay::list_relays).post(relay::add_relay),
+        )
+        .route(
+            "/api/relays/:id",
+            get(relay::get_relay).delete(relay::remove_relay),
+        )
+        .route("/api/exports/:job/file", get(export::export_file))
+        .route("/api/waiting/:stream", get(expected::waiting_page))
+        .route("/api/events", get(events::events))
+        .route("/api/streams", get(list_streams))
+        .route("/api/streams/:name", get(get_stream))
+        .route("/api/streams/:name/stats", get(frame_stats::stream_stats))
+        .route("/api/supervisor", get(supervisor::status))
+        .route("/api/viewers", get(delivery::list_viewers))
+        .route("/api/viewers/:id", get(delivery::get_viewer))
+        .route("/api/metrics/join-times", get(delivery::join_times))
+        .route(
+            "/api/ingest/recommendations",
+            get(recommendations::recommendations),
+        )
+        .route(
+            "/dvr/:stream/iframes.m3u8",
+            get(trick_play::dvr_iframe_playlist),
+        )
+        .route(
+            "/dvr/:stream/iframe-init.mp4",
+            get(trick_play::dvr_iframe_init),
+        )
+        .route(
+            "/dvr/:stream/iframes/:sequence",
+            get(trick_play::dvr_iframe),
+        )
+        .route("/ingest/flv/:stream", post(flv::publish))
+        .route("/ingest/mse/:stream", get(mse_ingest::publish))
+        .route("/whip/:app", post(whip::publish))
+        .route("/whip/:app/:session", delete(whip::stop))
+        .route("/whep/:stream", post(whep::play))
+        .route("/whep/:stream/:session", delete(whep::stop))
+        .route("/hls/:stream/master.m3u8", get(hls::multivariant_playlist))
+        .route("/hls/:stream/playlist.m3u8", get(hls::playlist))
+        .route("/hls/:stream/init.mp4", get(hls::init))
+        .route("/hls/:stream/segments/:sequence", get(hls::segment))
+        .route(
+            "/hls/:stream/segments/:sequence/parts/:part",
+            get(hls::part),
+        )
+        .route("/dash/:stream/manifest.mpd", get(hls::dash_manifest))
+        .route("/dash/:stream/init.mp4", get(hls::init))
+        .route("/dash/:stream/segments/:sequence", get(hls::segment))
+        .route("/cast/:stream", get(hls::cast))
+        .route("/vod", get(vod::list_assets))
+        .route("/vod/:asset/index.m3u8", get(vod::asset_playlist))
+        .route("/vod/:asset/media", get(vod::asset_media))
+        .route("/compose/:stream", get(compose::get_composition))
+        .route(
+            "/compose/:stream/pip",
+            put(compose::set_pip).delete(compose::clear_pip),
+        )
         .layer(AddExtensionLayer::new(data.clone()));
 
+    // the socket buffers in bytes and congestion control of the connections
+    // streams are played over, 0 or empty for the kernel's defaults. A low
+    // TCP_NOTSENT_LOWAT keeps slow viewers from buffering far behind live
+    let tcp_tuning = TcpTuning {
+        send_buffer: Some(env("INGEST_TCP_SEND_BUFFER", "0").parse()?).filter(|&n| n > 0),
+        recv_buffer: Some(env("INGEST_TCP_RECV_BUFFER", "0").parse()?).filter(|&n| n > 0),
+        notsent_lowat: Some(env("INGEST_TCP_NOTSENT_LOWAT", "0").parse()?).filter(|&n| n > 0),
+        congestion: Some(env("INGEST_TCP_CONGESTION", "")).filter(|c| !c.is_empty()),
+    };
+
+    let proxy_protocol = data.proxy_protocol.clone();
     let ws_task = tokio::spawn(async move {
         debug!("Listening for WebSocket requests on {}", ingest_web_addr);
-        hyper::Server::bind(&ingest_web_addr)
-            .tcp_nodelay(true)
-            .serve(app.into_make_service())
+        let listener = TcpListener::bind(ingest_web_addr).await.unwrap();
+        hyper::Server::builder(accept_proxied(listener, proxy_protocol, tcp_tuning))
+            .serve(app.into_make_service_with_connect_info::<SocketAddr, _>())
             .await
             .unwrap();
 
@@ -585,6 +2587,15 @@ async fn start() -> anyhow::Result<()> {
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // writes the TypeScript definitions of the `bindings` package instead of
+    // running the ingest
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some("export-typescript") {
+        let path = args.next().unwrap_or_else(|| "bindings/index.d.ts".into());
+        std::fs::write(path, typescript::definitions())?;
+        return Ok(());
+    }
+
     let _ = dotenv::dotenv();
 
     let filter = EnvFilter::try_from_default_env()
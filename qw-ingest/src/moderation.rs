@@ -0,0 +1,343 @@
+use anyhow::Context;
+use axum::{
+    body,
+    extract::{Extension, Path},
+    http::HeaderMap,
+    response::IntoResponse,
+};
+use hyper::{
+    client::HttpConnector, header::CONTENT_TYPE, Body, Client, Method, Request, Response,
+    StatusCode,
+};
+use hyper_rustls::HttpsConnector;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sh_media::{Frame, FrameReadFilter, Stream};
+use tokio::{sync::mpsc, time::timeout};
+use tracing::*;
+use ts_rs::TS;
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{diagnostics::is_admin, AppData};
+
+/// How long the moderation service has to answer about a frame.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most flags kept for each stream.
+const MAX_FLAGS: usize = 100;
+
+/// What the moderation service wants done with a stream.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, TS)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationAction {
+    /// Nothing is wrong. A paused stream is resumed.
+    Allow,
+    /// Keeps a note about the stream for admins to review.
+    Flag,
+    /// Stops sending the stream to viewers until it is allowed again.
+    Pause,
+    /// Disconnects the publisher.
+    Kick,
+}
+
+#[derive(Debug, Deserialize)]
+struct Verdict {
+    action: ModerationAction,
+    reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, TS)]
+pub struct ModerationFlag {
+    #[ts(type = "number")]
+    pub at: u64,
+    pub action: ModerationAction,
+    pub reason: Option<String>,
+}
+
+/// Sends keyframes sampled from every stream to an external moderation
+/// service, whose response decides what happens to the stream.
+///
+/// Frames are not decoded, each sample is a keyframe in a single-frame MP4
+/// file like a snapshot.
+pub struct Moderation {
+    client: Client<HttpsConnector<HttpConnector>>,
+    url: String,
+    interval: Duration,
+}
+
+/// The moderation state of a stream, shared by its filter, the task which
+/// talks to the moderation service and the API.
+#[derive(Default)]
+pub struct ModerationState {
+    paused: AtomicBool,
+    kicked: AtomicBool,
+    flags: Mutex<Vec<ModerationFlag>>,
+}
+
+impl Moderation {
+    pub fn new(url: String, interval: Duration) -> Self {
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+
+        Moderation {
+            client: Client::builder().build(connector),
+            url,
+            interval,
+        }
+    }
+
+    async fn ask(&self, stream: &str, frame: Frame) -> anyhow::Result<Verdict> {
+        let mp4 = sh_fmp4::single_frame_fmp4(frame)?;
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(&self.url)
+            .header(CONTENT_TYPE, "video/mp4")
+            .header("X-Stream", stream)
+            .body(Body::from(mp4))?;
+
+        let response = self.client.request(request).await?;
+        if !response.status().is_success() {
+            anyhow::bail!("moderation service responded with {}", response.status());
+        }
+
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+/// A filter which samples keyframes of a stream for moderation, and holds
+/// back frames while the stream is paused.
+pub struct ModerationFilter {
+    filter: Box<dyn FrameReadFilter + Send + Unpin>,
+    state: Arc<ModerationState>,
+    samples: mpsc::Sender<Frame>,
+    interval: Duration,
+    last_sample: Option<Instant>,
+    /// Whether frames are dropped until the next keyframe, since viewers
+    /// can't decode the frames after a pause until then.
+    resuming: bool,
+}
+
+impl ModerationFilter {
+    /// Starts moderating the stream called `name`.
+    pub fn new(
+        filter: Box<dyn FrameReadFilter + Send + Unpin>,
+        name: String,
+        moderation: Arc<Moderation>,
+        state: Arc<ModerationState>,
+    ) -> Self {
+        // a sample is skipped while the previous one is being checked
+        let (samples, recv) = mpsc::channel(1);
+        let interval = moderation.interval;
+
+        tokio::spawn(moderate(moderation, name, state.clone(), recv));
+
+        ModerationFilter {
+            filter,
+            state,
+            samples,
+            interval,
+            last_sample: None,
+            resuming: false,
+        }
+    }
+
+    fn sample(&mut self, frame: &Frame) {
+        if !frame.is_keyframe() || !frame.stream.is_video() {
+            return;
+        }
+
+        let now = Instant::now();
+        if self
+            .last_sample
+            .is_some_and(|last| now - last < self.interval)
+        {
+            return;
+        }
+
+        if self.samples.try_send(frame.clone()).is_ok() {
+            self.last_sample = Some(now);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl FrameReadFilter for ModerationFilter {
+    async fn start(&mut self) -> anyhow::Result<Vec<Stream>> {
+        self.filter.start().await
+    }
+
+    async fn read(&mut self) -> anyhow::Result<Frame> {
+        loop {
+            if self.state.kicked.load(Ordering::Relaxed) {
+                anyhow::bail!("publisher was kicked by moderation");
+            }
+
+            let frame = self.filter.read().await?;
+
+            // paused streams are still sampled, so they can be allowed again
+            self.sample(&frame);
+
+            if self.state.paused.load(Ordering::Relaxed) {
+                self.resuming = true;
+                continue;
+            }
+
+            if self.resuming {
+                if !frame.is_keyframe() || !frame.stream.is_video() {
+                    continue;
+                }
+                self.resuming = false;
+            }
+
+            return Ok(frame);
+        }
+    }
+}
+
+/// Asks the moderation service about the sampled frames of a stream and
+/// acts on its verdicts, until the stream ends.
+async fn moderate(
+    moderation: Arc<Moderation>,
+    name: String,
+    state: Arc<ModerationState>,
+    mut samples: mpsc::Receiver<Frame>,
+) {
+    while let Some(frame) = samples.recv().await {
+        let verdict = timeout(REQUEST_TIMEOUT, moderation.ask(&name, frame))
+            .await
+            .context("moderation service timed out");
+
+        let verdict = match verdict {
+            Ok(Ok(verdict)) => verdict,
+            Ok(Err(e)) | Err(e) => {
+                warn!("Failed to moderate '{}': {:?}", name, e);
+                continue;
+            }
+        };
+
+        match verdict.action {
+            ModerationAction::Allow => {
+                if state.paused.swap(false, Ordering::Relaxed) {
+                    info!("Moderation resumed '{}'", name);
+                }
+                continue;
+            }
+            ModerationAction::Flag => {
+                warn!("Moderation flagged '{}': {:?}", name, verdict.reason)
+            }
+            ModerationAction::Pause => {
+                if !state.paused.swap(true, Ordering::Relaxed) {
+                    warn!("Moderation paused '{}': {:?}", name, verdict.reason);
+                }
+            }
+            ModerationAction::Kick => {
+                warn!("Moderation kicked '{}': {:?}", name, verdict.reason);
+                state.kicked.store(true, Ordering::Relaxed);
+            }
+        }
+
+        let mut flags = state.flags.lock();
+        if flags.len() == MAX_FLAGS {
+            flags.remove(0);
+        }
+        flags.push(ModerationFlag {
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            action: verdict.action,
+            reason: verdict.reason,
+        });
+    }
+}
+
+#[derive(Serialize, TS)]
+pub(crate) struct ModerationStatus {
+    paused: bool,
+    flags: Vec<ModerationFlag>,
+}
+
+fn moderation_state(data: &AppData, stream: &str) -> Option<Arc<ModerationState>> {
+    let repo = data.stream_repo.read();
+
+    repo.stream_mapping
+        .get(stream)
+        .and_then(|id| repo.streams.get(id))
+        .and_then(|state| state.moderation.clone())
+}
+
+/// Returns whether a stream is paused and what the moderation service has
+/// said about it.
+pub async fn moderation_status(
+    Path(stream): Path<String>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    if !is_admin(&data, &headers) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body::Full::from("Missing or invalid admin token"))
+            .unwrap();
+    }
+
+    match moderation_state(&data, &stream) {
+        Some(state) => {
+            let status = ModerationStatus {
+                paused: state.paused.load(Ordering::Relaxed),
+                flags: state.flags.lock().clone(),
+            };
+
+            Response::builder()
+                .header("Content-Type", "application/json")
+                .status(StatusCode::OK)
+                .body(body::Full::from(serde_json::to_string(&status).unwrap()))
+                .unwrap()
+        }
+        None => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(body::Full::from("Stream not found or moderation disabled"))
+            .unwrap(),
+    }
+}
+
+/// Resumes a stream paused by moderation, overriding the service.
+pub async fn resume_stream(
+    Path(stream): Path<String>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    if !is_admin(&data, &headers) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body::Full::from("Missing or invalid admin token"))
+            .unwrap();
+    }
+
+    match moderation_state(&data, &stream) {
+        Some(state) => {
+            state.paused.store(false, Ordering::Relaxed);
+            info!("Resumed '{}' after moderation", stream);
+
+            Response::builder()
+                .status(StatusCode::OK)
+                .body(body::Full::from("Resumed"))
+                .unwrap()
+        }
+        None => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(body::Full::from("Stream not found or moderation disabled"))
+            .unwrap(),
+    }
+}
@@ -0,0 +1,58 @@
+use axum::{
+    body,
+    extract::{ws::WebSocketUpgrade, Extension, Path, Query},
+    response::IntoResponse,
+};
+use hyper::{Response, StatusCode};
+use serde::Deserialize;
+use sh_fmp4::FragmentedMp4ReadFilter;
+use sh_transport_mse::WebSocketReadFilter;
+use tracing::*;
+
+use std::sync::Arc;
+
+use crate::{authenticate_stream, ingest, AppData, IngestSource};
+
+#[derive(Debug, Deserialize)]
+pub struct MseIngestParams {
+    /// Whether the stream is listed, like the `public` app of RTMP.
+    #[serde(default)]
+    public: bool,
+}
+
+/// Publishes a stream from fragmented MP4 sent over a WebSocket, so
+/// browsers can publish with `MediaRecorder` or WebCodecs on networks where
+/// RTMP is blocked.
+///
+/// `stream` is the stream key, and every binary message is a part of the
+/// fMP4 stream.
+pub async fn publish(
+    ws: WebSocketUpgrade,
+    Path(stream): Path<String>,
+    Query(params): Query<MseIngestParams>,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    let mut client = data.client.clone();
+    let (id, name) = match authenticate_stream(&mut client, &stream, params.public).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            warn!("Failed to authenticate WebSocket publisher: {:?}", e);
+            return Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(body::Full::from("Invalid stream key"))
+                .unwrap()
+                .into_response();
+        }
+    };
+
+    info!("Got a WebSocket fMP4 publisher for {}", name);
+
+    ws.on_upgrade(move |socket| async move {
+        let filter = FragmentedMp4ReadFilter::new(Box::new(WebSocketReadFilter::new(socket)));
+
+        if let Err(e) = ingest(id, name, IngestSource::new(Box::new(filter)), data).await {
+            debug!("WebSocket fMP4 ingest ended: {:?}", e);
+        }
+    })
+    .into_response()
+}
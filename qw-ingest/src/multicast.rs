@@ -0,0 +1,129 @@
+use parking_lot::RwLock;
+use sh_ingest_ts::{announceable_group, MpegTsMuxer, SapAnnouncer, UdpTsSender};
+use sh_media::{FrameRateFilter, FrameRatePolicy, FrameReadFilter, MediaFrameQueueReceiver, Muxer};
+use tokio::time::sleep;
+use tracing::*;
+
+use std::{net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
+
+use crate::StreamRepository;
+
+/// How long to wait before sending to a group again after a failure.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Where the MPEG-TS of a stream is sent.
+#[derive(Debug, Clone)]
+pub struct MulticastTarget {
+    pub stream: String,
+    pub addr: SocketAddr,
+    pub ttl: u32,
+    /// What is done with the timestamps of variable frame rate video, which
+    /// some decoders play back unevenly.
+    pub frame_rate: FrameRatePolicy,
+}
+
+impl FromStr for MulticastTarget {
+    type Err = anyhow::Error;
+
+    /// Parses `<stream>=<group>:<port>[@<ttl>][#<frame rate policy>]`, where
+    /// the TTL defaults to 1 so the datagrams stay on the local network, and
+    /// timestamps are passed through unless a policy like `smooth:30` is set.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (stream, target) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Multicast output '{}' has no stream", s))?;
+        let (target, frame_rate) = match target.split_once('#') {
+            Some((target, policy)) => (target, policy.parse()?),
+            None => (target, FrameRatePolicy::Passthrough),
+        };
+        let (addr, ttl) = match target.split_once('@') {
+            Some((addr, ttl)) => (addr, ttl.trim().parse()?),
+            None => (target, 1),
+        };
+
+        Ok(MulticastTarget {
+            stream: stream.trim().to_string(),
+            addr: addr.trim().parse()?,
+            ttl,
+            frame_rate,
+        })
+    }
+}
+
+/// Sends a stream as MPEG-TS over UDP as long as it is live, so a venue can
+/// feed any number of local decoders with a single copy of it.
+///
+/// The transport stream starts at a keyframe, and starts over from the
+/// next one if sending fails. Sessions in IPv4 multicast groups are
+/// announced with SAP every `sap_interval`, if it is set.
+pub fn spawn_multicast(
+    target: MulticastTarget,
+    stream_session_id: i32,
+    repo: Arc<RwLock<StreamRepository>>,
+    sap_interval: Option<Duration>,
+) {
+    tokio::spawn(async move {
+        let announcer = match (sap_interval, announceable_group(target.addr)) {
+            (Some(interval), Some(group)) => {
+                match SapAnnouncer::new(&target.stream, group, target.ttl).await {
+                    Ok(announcer) => Some((Arc::new(announcer), interval)),
+                    Err(e) => {
+                        warn!("Not announcing {} with SAP: {:?}", target.addr, e);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        let announcing = announcer.as_ref().map(|(announcer, interval)| {
+            let announcer = announcer.clone();
+            let interval = *interval;
+            tokio::spawn(async move { announcer.announce(interval).await })
+        });
+
+        loop {
+            let read = {
+                let repo = repo.read();
+
+                match repo.streams.get(&stream_session_id) {
+                    Some(state) if state.is_live() => state.queue.get_receiver_from_keyframe(),
+                    _ => break,
+                }
+            };
+
+            info!("Sending stream to {} with TTL {}", target.addr, target.ttl);
+
+            match send(&target, read).await {
+                Ok(()) => break,
+                Err(e) => warn!("Failed to send stream to {}: {:?}", target.addr, e),
+            }
+
+            sleep(RETRY_DELAY).await;
+        }
+
+        if let Some(announcing) = announcing {
+            announcing.abort();
+        }
+        if let Some((announcer, _)) = announcer {
+            announcer.delete().await;
+        }
+
+        info!("Stopped sending stream to {}", target.addr);
+    });
+}
+
+async fn send(target: &MulticastTarget, read: MediaFrameQueueReceiver) -> anyhow::Result<()> {
+    let mut read = FrameRateFilter::new(Box::new(read), target.frame_rate);
+    let streams = read.start().await?;
+
+    let sender = UdpTsSender::connect(target.addr, target.ttl).await?;
+    let mut write = MpegTsMuxer.mux(Box::new(sender));
+    write.start(streams).await?;
+
+    // the queue closes when the stream ends
+    while let Ok(frame) = read.read().await {
+        write.write(frame).await?;
+    }
+
+    Ok(())
+}
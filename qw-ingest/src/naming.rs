@@ -0,0 +1,114 @@
+use std::{
+    path::{Component, Path},
+    str::FromStr,
+};
+
+/// A template for the paths of files written for a stream, such as
+/// `{stream}/{date}/{seq}.mp4`, relative to the output directory.
+///
+/// The placeholders are:
+///
+/// * `{stream}`: the name of the stream
+/// * `{rendition}`: the rendition being written, e.g. `source`
+/// * `{name}`: the name of the recording
+/// * `{seq}`: the index of the file in the recording, starting at 0
+/// * `{date}`: the UTC date the file was started at, as `YYYY-MM-DD`
+/// * `{time}`: the UTC time the file was started at, as `HHMMSS`
+/// * `{timestamp}`: the Unix time the file was started at
+#[derive(Debug, Clone)]
+pub struct NameTemplate(String);
+
+const PLACEHOLDERS: &[&str] = &[
+    "stream",
+    "rendition",
+    "name",
+    "seq",
+    "date",
+    "time",
+    "timestamp",
+];
+
+/// The values a [`NameTemplate`] is filled in with.
+pub struct NameValues<'a> {
+    pub stream: &'a str,
+    pub rendition: &'a str,
+    pub name: &'a str,
+    pub seq: usize,
+    /// Unix time in seconds.
+    pub time: u64,
+}
+
+impl NameTemplate {
+    pub fn render(&self, values: &NameValues) -> String {
+        let (year, month, day) = civil_date(values.time / 86400);
+        let seconds = values.time % 86400;
+
+        self.0
+            .replace("{stream}", values.stream)
+            .replace("{rendition}", values.rendition)
+            .replace("{name}", values.name)
+            .replace("{seq}", &values.seq.to_string())
+            .replace("{date}", &format!("{:04}-{:02}-{:02}", year, month, day))
+            .replace(
+                "{time}",
+                &format!(
+                    "{:02}{:02}{:02}",
+                    seconds / 3600,
+                    seconds / 60 % 60,
+                    seconds % 60
+                ),
+            )
+            .replace("{timestamp}", &values.time.to_string())
+    }
+}
+
+impl FromStr for NameTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| anyhow::anyhow!("unclosed placeholder in '{}'", s))?;
+            let placeholder = &rest[start + 1..start + end];
+
+            if !PLACEHOLDERS.contains(&placeholder) {
+                anyhow::bail!("unknown placeholder {{{}}} in '{}'", placeholder, s);
+            }
+
+            rest = &rest[start + end + 1..];
+        }
+
+        // every file of a recording needs its own name
+        if !s.contains("{seq}") {
+            anyhow::bail!("'{}' has no {{seq}} placeholder", s);
+        }
+
+        let stays_inside = Path::new(s)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)));
+        if !stays_inside {
+            anyhow::bail!("'{}' has to be a relative path without '..'", s);
+        }
+
+        Ok(NameTemplate(s.to_string()))
+    }
+}
+
+/// Returns the year, month and day of a number of days since the Unix
+/// epoch.
+fn civil_date(days: u64) -> (u64, u64, u64) {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as u64;
+
+    (year, month, day)
+}
@@ -0,0 +1,634 @@
+use axum::{
+    body,
+    http::{header::RANGE, HeaderMap},
+};
+use hyper::{Response, StatusCode};
+use parking_lot::Mutex;
+use serde::Serialize;
+use sh_fmp4::Mp4Index;
+use sh_ingest_ts::TsIndex;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use ts_rs::TS;
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    io::SeekFrom,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+/// How long segments are made, as far as keyframes allow.
+const TARGET_SEGMENT_SECONDS: f64 = 6.0;
+
+/// The timebase of MPEG-TS timestamps.
+const TS_TIMESCALE: f64 = 90000.0;
+
+/// The container formats which can be packaged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, TS)]
+#[serde(rename_all = "snake_case")]
+pub enum FileFormat {
+    /// Fragmented MP4, as written by recordings.
+    Mp4,
+    TransportStream,
+}
+
+impl FileFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "mp4" | "m4v" => Some(FileFormat::Mp4),
+            "ts" => Some(FileFormat::TransportStream),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            FileFormat::Mp4 => "video/mp4",
+            FileFormat::TransportStream => "video/mp2t",
+        }
+    }
+}
+
+/// A segment of a VOD playlist, as a byte range of the packaged file.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub bytes: Range<u64>,
+    pub duration: f64,
+}
+
+/// How a file is split into segments, without rewriting it.
+#[derive(Debug, Clone)]
+pub struct SegmentedFile {
+    /// The bytes which initialize a decoder before any segment, if needed.
+    pub init: Option<Range<u64>>,
+    pub segments: Vec<Segment>,
+    pub format: FileFormat,
+    /// The codecs of the tracks, as in the `codecs` attribute of DASH.
+    pub codecs: Option<String>,
+    /// The byte range of every video keyframe, each lasting until the next,
+    /// for I-frame playlists.
+    pub keyframes: Vec<Segment>,
+}
+
+impl SegmentedFile {
+    /// Splits a fragmented MP4 file on video keyframes.
+    pub fn from_mp4(index: &Mp4Index) -> anyhow::Result<Self> {
+        let track = index
+            .video_track()
+            .or_else(|| index.fragments.first().map(|f| f.track_id))
+            .ok_or_else(|| anyhow::anyhow!("file has no fragments"))?;
+        let timescale = *index
+            .timescales
+            .get(&track)
+            .ok_or_else(|| anyhow::anyhow!("track {} has no timescale", track))?
+            as f64;
+
+        let mut segments = Vec::new();
+        let mut start = index.fragments[0].bytes.start;
+        let mut start_time = None;
+        let mut end_time = 0;
+
+        for fragment in index.fragments.iter().filter(|f| f.track_id == track) {
+            if let Some(time) = start_time {
+                let duration = fragment.decode_time.saturating_sub(time) as f64 / timescale;
+
+                if fragment.keyframe && duration >= TARGET_SEGMENT_SECONDS {
+                    segments.push(Segment {
+                        bytes: start..fragment.bytes.start,
+                        duration,
+                    });
+                    start = fragment.bytes.start;
+                    start_time = Some(fragment.decode_time);
+                }
+            } else {
+                start_time = Some(fragment.decode_time);
+            }
+
+            end_time = fragment.decode_time + fragment.duration;
+        }
+
+        let end = index.fragments.last().map_or(start, |f| f.bytes.end);
+        segments.push(Segment {
+            bytes: start..end,
+            duration: end_time.saturating_sub(start_time.unwrap_or(0)) as f64 / timescale,
+        });
+
+        // a keyframe is its moof box and the sample data up to its end
+        let keyframes = index
+            .fragments
+            .iter()
+            .filter(|f| f.track_id == track && f.keyframe)
+            .filter_map(|f| {
+                let sample = f.samples.first()?;
+                let end = sample.offset + sample.size as u64;
+
+                Some((f.bytes.start..end, f.decode_time))
+            });
+
+        Ok(SegmentedFile {
+            init: Some(index.init.clone()),
+            segments,
+            format: FileFormat::Mp4,
+            codecs: Some(index.codecs_string()).filter(|c| !c.is_empty()),
+            keyframes: keyframe_segments(keyframes, end_time, timescale),
+        })
+    }
+
+    /// Splits an MPEG transport stream file on video keyframes.
+    pub fn from_ts(index: &TsIndex) -> anyhow::Result<Self> {
+        let first = index
+            .keyframes
+            .first()
+            .ok_or_else(|| anyhow::anyhow!("file has no keyframes"))?;
+
+        let mut segments = Vec::new();
+        let mut start = *first;
+
+        for keyframe in &index.keyframes[1..] {
+            let duration = keyframe.pts.saturating_sub(start.pts) as f64 / TS_TIMESCALE;
+
+            if duration >= TARGET_SEGMENT_SECONDS {
+                segments.push(Segment {
+                    bytes: start.offset..keyframe.offset,
+                    duration,
+                });
+                start = *keyframe;
+            }
+        }
+
+        let last_pts = index.last_pts.unwrap_or(start.pts);
+        segments.push(Segment {
+            bytes: start.offset..index.size,
+            duration: last_pts.saturating_sub(start.pts) as f64 / TS_TIMESCALE,
+        });
+
+        let keyframes = index.keyframes.iter().map(|k| (k.offset..k.end, k.pts));
+
+        Ok(SegmentedFile {
+            // the program tables, which segments after the first lack
+            init: Some(0..index.header_size),
+            segments,
+            format: FileFormat::TransportStream,
+            codecs: None,
+            // every keyframe needs the program tables before it, which
+            // are in the playlist's map
+            keyframes: keyframe_segments(keyframes, last_pts, TS_TIMESCALE),
+        })
+    }
+
+    /// Reads and segments a file, which takes a pass over an MPEG-TS file
+    /// and over the boxes of an MP4 file.
+    pub async fn read(path: PathBuf, format: FileFormat) -> anyhow::Result<Self> {
+        tokio::task::spawn_blocking(move || {
+            let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+
+            match format {
+                FileFormat::Mp4 => Self::from_mp4(&Mp4Index::read(&mut file)?),
+                FileFormat::TransportStream => Self::from_ts(&TsIndex::read(&mut file)?),
+            }
+        })
+        .await?
+    }
+
+    /// Reads and segments a fragmented MP4 file which is still being
+    /// written, up to the start of its last keyframe. The segment after it
+    /// is left out, since it grows until the next keyframe.
+    pub async fn read_growing(path: PathBuf) -> anyhow::Result<Self> {
+        tokio::task::spawn_blocking(move || {
+            let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+            let mut segmented = Self::from_mp4(&Mp4Index::read_growing(&mut file)?)?;
+
+            if let Some(open) = segmented.segments.pop() {
+                segmented
+                    .keyframes
+                    .retain(|keyframe| keyframe.bytes.start < open.bytes.start);
+            }
+
+            Ok(segmented)
+        })
+        .await?
+    }
+
+    pub fn duration(&self) -> f64 {
+        self.segments.iter().map(|s| s.duration).sum()
+    }
+
+    /// An estimate of the bitrate in bits per second, as DASH requires.
+    fn bandwidth(&self) -> u64 {
+        let bytes = self
+            .segments
+            .iter()
+            .map(|s| s.bytes.end - s.bytes.start)
+            .sum::<u64>();
+
+        (bytes as f64 * 8.0 / self.duration().max(1.0)) as u64
+    }
+
+    /// An estimate of the bitrate of the keyframes alone, as the I-frame
+    /// streams of HLS require.
+    fn keyframe_bandwidth(&self) -> u64 {
+        let bytes = self
+            .keyframes
+            .iter()
+            .map(|s| s.bytes.end - s.bytes.start)
+            .sum::<u64>();
+
+        (bytes as f64 * 8.0 / self.duration().max(1.0)) as u64
+    }
+}
+
+/// Makes segments of keyframes at byte ranges and times, where each lasts
+/// until the next one and the last until `end_time`.
+fn keyframe_segments(
+    keyframes: impl Iterator<Item = (Range<u64>, u64)>,
+    end_time: u64,
+    timescale: f64,
+) -> Vec<Segment> {
+    let keyframes = keyframes.collect::<Vec<_>>();
+
+    keyframes
+        .iter()
+        .enumerate()
+        .map(|(i, (bytes, time))| {
+            let next = keyframes.get(i + 1).map_or(end_time, |(_, next)| *next);
+
+            Segment {
+                bytes: bytes.clone(),
+                duration: next.saturating_sub(*time) as f64 / timescale,
+            }
+        })
+        .collect()
+}
+
+/// Writes an HLS media playlist of files, each at its URI, where every
+/// segment is a byte range of a file.
+///
+/// Unless the files have `ended`, the playlist is an event playlist which
+/// players reload for the segments added to the last file as it grows.
+pub fn hls_playlist(files: &[(String, Arc<SegmentedFile>)], ended: bool) -> String {
+    media_playlist(files, |file| file.segments.as_slice(), false, ended)
+}
+
+/// Writes an HLS I-frame playlist of files, where every segment is a
+/// keyframe, so players can show thumbnails while scrubbing.
+pub fn hls_iframe_playlist(files: &[(String, Arc<SegmentedFile>)], ended: bool) -> String {
+    media_playlist(files, |file| file.keyframes.as_slice(), true, ended)
+}
+
+/// Writes an HLS master playlist which points to the media playlist and the
+/// I-frame playlist of files.
+pub fn hls_master_playlist(
+    files: &[(String, Arc<SegmentedFile>)],
+    media_uri: &str,
+    iframe_uri: &str,
+) -> String {
+    let bandwidth = files.iter().map(|(_, f)| f.bandwidth()).max().unwrap_or(0);
+    let keyframe_bandwidth = files
+        .iter()
+        .map(|(_, f)| f.keyframe_bandwidth())
+        .max()
+        .unwrap_or(0);
+    let codecs = files.iter().find_map(|(_, f)| f.codecs.as_deref());
+    // the I-frame stream has no audio
+    let video_codecs = codecs.map(|codecs| {
+        codecs
+            .split(',')
+            .filter(|codec| codec.starts_with("avc"))
+            .collect::<Vec<_>>()
+            .join(",")
+    });
+
+    let codecs_attribute = |codecs: Option<&str>| {
+        codecs
+            .filter(|c| !c.is_empty())
+            .map(|c| format!(",CODECS=\"{}\"", c))
+            .unwrap_or_default()
+    };
+
+    let mut playlist = String::new();
+
+    let _ = writeln!(playlist, "#EXTM3U");
+    let _ = writeln!(playlist, "#EXT-X-VERSION:7");
+    let _ = writeln!(playlist, "#EXT-X-INDEPENDENT-SEGMENTS");
+    let _ = writeln!(
+        playlist,
+        "#EXT-X-STREAM-INF:BANDWIDTH={}{}",
+        bandwidth,
+        codecs_attribute(codecs)
+    );
+    let _ = writeln!(playlist, "{}", media_uri);
+    let _ = writeln!(
+        playlist,
+        "#EXT-X-I-FRAME-STREAM-INF:BANDWIDTH={}{},URI=\"{}\"",
+        keyframe_bandwidth,
+        codecs_attribute(video_codecs.as_deref()),
+        iframe_uri
+    );
+
+    playlist
+}
+
+fn media_playlist(
+    files: &[(String, Arc<SegmentedFile>)],
+    segments: impl Fn(&SegmentedFile) -> &[Segment],
+    iframes_only: bool,
+    ended: bool,
+) -> String {
+    let target_duration = files
+        .iter()
+        .flat_map(|(_, file)| segments(file))
+        .map(|s| s.duration.ceil() as u64)
+        .max()
+        .unwrap_or(0);
+
+    let mut playlist = String::new();
+
+    // writing to a String can't fail
+    let _ = writeln!(playlist, "#EXTM3U");
+    let _ = writeln!(playlist, "#EXT-X-VERSION:7");
+    let _ = writeln!(playlist, "#EXT-X-TARGETDURATION:{}", target_duration);
+    let _ = writeln!(
+        playlist,
+        "#EXT-X-PLAYLIST-TYPE:{}",
+        if ended { "VOD" } else { "EVENT" }
+    );
+    let _ = writeln!(playlist, "#EXT-X-INDEPENDENT-SEGMENTS");
+    if iframes_only {
+        let _ = writeln!(playlist, "#EXT-X-I-FRAMES-ONLY");
+    }
+
+    for (i, (uri, file)) in files.iter().enumerate() {
+        // each file starts a new timeline with new codec parameters
+        if i > 0 {
+            let _ = writeln!(playlist, "#EXT-X-DISCONTINUITY");
+        }
+
+        if let Some(init) = &file.init {
+            let _ = writeln!(
+                playlist,
+                "#EXT-X-MAP:URI=\"{}\",BYTERANGE=\"{}@{}\"",
+                uri,
+                init.end - init.start,
+                init.start
+            );
+        }
+
+        for segment in segments(file) {
+            let _ = writeln!(playlist, "#EXTINF:{:.3},", segment.duration);
+            let _ = writeln!(
+                playlist,
+                "#EXT-X-BYTERANGE:{}@{}",
+                segment.bytes.end - segment.bytes.start,
+                segment.bytes.start
+            );
+            let _ = writeln!(playlist, "{}", uri);
+        }
+    }
+
+    if ended {
+        let _ = writeln!(playlist, "#EXT-X-ENDLIST");
+    }
+
+    playlist
+}
+
+/// Writes a static DASH manifest of fragmented MP4 files, each at its URI,
+/// with a period per file and segments which are byte ranges of it.
+pub fn dash_manifest(files: &[(String, Arc<SegmentedFile>)]) -> anyhow::Result<String> {
+    let total = files.iter().map(|(_, file)| file.duration()).sum::<f64>();
+
+    let mut mpd = String::new();
+
+    let _ = writeln!(mpd, r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    let _ = writeln!(
+        mpd,
+        r#"<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" profiles="urn:mpeg:dash:profile:isoff-on-demand:2011" type="static" mediaPresentationDuration="PT{:.3}S" minBufferTime="PT2S">"#,
+        total
+    );
+
+    for (i, (uri, file)) in files.iter().enumerate() {
+        if file.format != FileFormat::Mp4 {
+            anyhow::bail!("only MP4 files can be packaged as DASH");
+        }
+
+        let init = file
+            .init
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("file has no initialization"))?;
+
+        let _ = writeln!(
+            mpd,
+            r#"  <Period id="{}" duration="PT{:.3}S">"#,
+            i,
+            file.duration()
+        );
+        let _ = writeln!(
+            mpd,
+            r#"    <AdaptationSet mimeType="video/mp4" segmentAlignment="true">"#
+        );
+        let _ = writeln!(
+            mpd,
+            r#"      <Representation id="{}" bandwidth="{}"{}>"#,
+            i,
+            file.bandwidth(),
+            file.codecs
+                .as_ref()
+                .map(|c| format!(r#" codecs="{}""#, c))
+                .unwrap_or_default()
+        );
+        let _ = writeln!(mpd, r#"        <SegmentList timescale="1000">"#);
+        let _ = writeln!(
+            mpd,
+            r#"          <Initialization sourceURL="{}" range="{}-{}"/>"#,
+            uri,
+            init.start,
+            init.end - 1
+        );
+        let _ = writeln!(mpd, r#"          <SegmentTimeline>"#);
+        for segment in &file.segments {
+            let _ = writeln!(
+                mpd,
+                r#"            <S d="{}"/>"#,
+                (segment.duration * 1000.0).round() as u64
+            );
+        }
+        let _ = writeln!(mpd, r#"          </SegmentTimeline>"#);
+        for segment in &file.segments {
+            let _ = writeln!(
+                mpd,
+                r#"          <SegmentURL media="{}" mediaRange="{}-{}"/>"#,
+                uri,
+                segment.bytes.start,
+                segment.bytes.end - 1
+            );
+        }
+        let _ = writeln!(mpd, r#"        </SegmentList>"#);
+        let _ = writeln!(mpd, r#"      </Representation>"#);
+        let _ = writeln!(mpd, r#"    </AdaptationSet>"#);
+        let _ = writeln!(mpd, r#"  </Period>"#);
+    }
+
+    let _ = writeln!(mpd, r#"</MPD>"#);
+
+    Ok(mpd)
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+    growing: bool,
+}
+
+/// Remembers how recently played files are segmented, so they are only read
+/// once while they are being played. The least recently used files are
+/// forgotten first.
+pub struct PackagingCache {
+    capacity: usize,
+    /// The files with the tick they were last used at.
+    entries: Mutex<(u64, HashMap<CacheKey, (u64, Arc<SegmentedFile>)>)>,
+}
+
+impl PackagingCache {
+    pub fn new(capacity: usize) -> Self {
+        PackagingCache {
+            capacity,
+            entries: Mutex::new((0, HashMap::new())),
+        }
+    }
+
+    /// Returns how a file is segmented, reading it unless it is cached.
+    /// A file which has changed since it was cached is read again.
+    pub async fn segments(
+        &self,
+        path: &Path,
+        format: FileFormat,
+    ) -> anyhow::Result<Arc<SegmentedFile>> {
+        self.cached(path, format, false).await
+    }
+
+    /// Returns how a fragmented MP4 file which is still being written is
+    /// segmented so far, like [`SegmentedFile::read_growing`]. Only the
+    /// latest state of the file is kept in the cache.
+    pub async fn growing_segments(&self, path: &Path) -> anyhow::Result<Arc<SegmentedFile>> {
+        self.cached(path, FileFormat::Mp4, true).await
+    }
+
+    async fn cached(
+        &self,
+        path: &Path,
+        format: FileFormat,
+        growing: bool,
+    ) -> anyhow::Result<Arc<SegmentedFile>> {
+        let metadata = tokio::fs::metadata(path).await?;
+        let key = CacheKey {
+            path: path.to_path_buf(),
+            size: metadata.len(),
+            modified: metadata.modified()?,
+            growing,
+        };
+
+        {
+            let mut entries = self.entries.lock();
+            let (tick, files) = &mut *entries;
+
+            *tick += 1;
+            if let Some((used, file)) = files.get_mut(&key) {
+                *used = *tick;
+                return Ok(file.clone());
+            }
+        }
+
+        let file = if growing {
+            SegmentedFile::read_growing(path.to_path_buf()).await?
+        } else {
+            SegmentedFile::read(path.to_path_buf(), format).await?
+        };
+        let file = Arc::new(file);
+
+        let mut entries = self.entries.lock();
+        let (tick, files) = &mut *entries;
+
+        if growing {
+            files.retain(|cached, _| cached.path != key.path);
+        }
+        files.insert(key, (*tick, file.clone()));
+
+        while files.len() > self.capacity.max(1) {
+            let oldest = files
+                .iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(key, _)| key.clone());
+
+            match oldest {
+                Some(oldest) => files.remove(&oldest),
+                None => break,
+            };
+        }
+
+        Ok(file)
+    }
+}
+
+/// Serves a file, or the single byte range of it which was requested.
+pub async fn serve_file(
+    path: &Path,
+    content_type: &str,
+    headers: &HeaderMap,
+) -> anyhow::Result<Response<body::Full<bytes::Bytes>>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let size = file.metadata().await?.len();
+
+    let range = match headers.get(RANGE).and_then(|h| h.to_str().ok()) {
+        Some(range) => match parse_range(range, size) {
+            Some(range) => Some(range),
+            None => {
+                return Ok(Response::builder()
+                    .header("Content-Range", format!("bytes */{}", size))
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .body(body::Full::from("Invalid range"))
+                    .unwrap())
+            }
+        },
+        None => None,
+    };
+
+    let bytes = range.clone().unwrap_or(0..size);
+    let mut contents = vec![0; (bytes.end - bytes.start) as usize];
+    file.seek(SeekFrom::Start(bytes.start)).await?;
+    file.read_exact(&mut contents).await?;
+
+    let response = Response::builder()
+        .header("Content-Type", content_type)
+        .header("Accept-Ranges", "bytes")
+        .header("Access-Control-Allow-Origin", "*");
+
+    let response = match range {
+        Some(range) => response
+            .header(
+                "Content-Range",
+                format!("bytes {}-{}/{}", range.start, range.end - 1, size),
+            )
+            .status(StatusCode::PARTIAL_CONTENT),
+        None => response.status(StatusCode::OK),
+    };
+
+    Ok(response.body(body::Full::from(contents)).unwrap())
+}
+
+/// Parses a single range of a `Range` header, e.g. `bytes=0-499`.
+fn parse_range(header: &str, size: u64) -> Option<Range<u64>> {
+    let (start, end) = header.strip_prefix("bytes=")?.split_once('-')?;
+
+    let range = match (start.trim(), end.trim()) {
+        ("", suffix) => size.saturating_sub(suffix.parse().ok()?)..size,
+        (start, "") => start.parse().ok()?..size,
+        (start, end) => start.parse().ok()?..(end.parse::<u64>().ok()? + 1).min(size),
+    };
+
+    (range.start < range.end).then(|| range)
+}
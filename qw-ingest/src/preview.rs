@@ -0,0 +1,140 @@
+use axum::{
+    body::{self, boxed, BoxBody, StreamBody},
+    extract::{Extension, Path},
+};
+use bytes::Bytes;
+use hyper::{Response, StatusCode};
+use sh_media::Frame;
+use tokio::{sync::mpsc, time::sleep};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::*;
+
+use std::{sync::Arc, time::Duration};
+
+use crate::AppData;
+
+/// How long each keyframe of an animated preview is shown.
+const PREVIEW_FRAME_DURATION: Duration = Duration::from_millis(500);
+
+/// How wide the pictures of an animated preview are.
+#[cfg(feature = "transcode")]
+const PREVIEW_WIDTH: u32 = 320;
+
+/// Separates the pictures of the multipart preview response.
+const BOUNDARY: &str = "preview";
+
+/// Serves an animated preview of a stream as MJPEG, looping over its last
+/// sampled keyframes until the viewer goes away, e.g. to show when a
+/// stream in a directory is hovered.
+pub async fn preview(
+    Path(stream): Path<String>,
+    Extension(data): Extension<Arc<AppData>>,
+) -> Response<BoxBody> {
+    debug!("Received preview request for '{}'", stream);
+
+    let keyframes = {
+        let repo = data.stream_repo.read();
+
+        let stream_id = repo.stream_mapping.get(&stream);
+        let meta = stream_id.and_then(|id| repo.streams.get(id));
+
+        meta.map(|m| {
+            let snapshots = m.snapshots.read();
+            snapshots.recent.iter().cloned().collect::<Vec<_>>()
+        })
+        .unwrap_or_default()
+    };
+
+    if keyframes.is_empty() {
+        return error(StatusCode::NOT_FOUND, "Failed to find preview".into());
+    }
+
+    let pictures = match preview_pictures(keyframes).await {
+        Ok(pictures) if !pictures.is_empty() => pictures,
+        Ok(_) => {
+            return error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Stream has no keyframes which can be decoded".into(),
+            )
+        }
+        Err(e) => {
+            warn!("Failed to make a preview of '{}': {:?}", stream, e);
+
+            return error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to get preview: {}", e),
+            );
+        }
+    };
+
+    let (tx, rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        for picture in pictures.iter().cycle() {
+            let part = multipart_part(picture);
+            if tx.send(Ok::<_, std::io::Error>(part)).await.is_err() {
+                break;
+            }
+
+            sleep(PREVIEW_FRAME_DURATION).await;
+        }
+    });
+
+    Response::builder()
+        .header(
+            "Content-Type",
+            format!("multipart/x-mixed-replace; boundary={}", BOUNDARY),
+        )
+        .header("Cache-Control", "no-store")
+        .header("Access-Control-Allow-Origin", "*")
+        .status(StatusCode::OK)
+        .body(boxed(StreamBody::new(ReceiverStream::new(rx))))
+        .unwrap()
+}
+
+/// Encodes the keyframes as JPEG pictures outside of the async workers.
+async fn preview_pictures(keyframes: Vec<Frame>) -> anyhow::Result<Vec<Bytes>> {
+    tokio::task::spawn_blocking(move || encode_pictures(&keyframes)).await?
+}
+
+#[cfg(feature = "transcode")]
+fn encode_pictures(keyframes: &[Frame]) -> anyhow::Result<Vec<Bytes>> {
+    sh_transcode::encode_jpegs(keyframes, PREVIEW_WIDTH)
+}
+
+#[cfg(not(feature = "transcode"))]
+fn encode_pictures(_keyframes: &[Frame]) -> anyhow::Result<Vec<Bytes>> {
+    anyhow::bail!("qwer-ingest was built without the transcode feature")
+}
+
+/// Frames a JPEG picture as a part of a `multipart/x-mixed-replace`
+/// response, which browsers show one after another in an `<img>`.
+fn multipart_part(picture: &Bytes) -> Bytes {
+    let header = format!(
+        "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+        BOUNDARY,
+        picture.len()
+    );
+
+    let mut part = Vec::with_capacity(header.len() + picture.len() + 2);
+    part.extend_from_slice(header.as_bytes());
+    part.extend_from_slice(picture);
+    part.extend_from_slice(b"\r\n");
+
+    Bytes::from(part)
+}
+
+fn error(status: StatusCode, message: String) -> Response<BoxBody> {
+    Response::builder()
+        .status(status)
+        .body(boxed(body::Full::from(message)))
+        .unwrap()
+}
+
+#[test]
+fn frames_pictures_as_multipart_parts() {
+    let part = multipart_part(&Bytes::from_static(b"\xff\xd8\xff\xd9"));
+
+    let header = "--preview\r\nContent-Type: image/jpeg\r\nContent-Length: 4\r\n\r\n";
+    assert!(part.starts_with(header.as_bytes()));
+    assert_eq!(&part[header.len()..], b"\xff\xd8\xff\xd9\r\n");
+}
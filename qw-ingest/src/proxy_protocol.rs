@@ -0,0 +1,240 @@
+use axum::extract::connect_info::Connected;
+use hyper::server::accept::{self, Accept};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::timeout,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::*;
+
+use crate::tcp_tuning::TcpTuning;
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// How long a proxy has to send its header after connecting.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The longest header of version 1, including the line break.
+const MAX_V1_HEADER_LEN: usize = 107;
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Reads the address of the client from the PROXY protocol header, version
+/// 1 or 2, which load balancers in front of the server put at the start of
+/// connections.
+///
+/// Only connections from trusted proxies are expected to start with a
+/// header, since anyone else could claim to be any client with one.
+#[derive(Debug, Clone, Default)]
+pub struct ProxyProtocol {
+    trusted: Vec<IpAddr>,
+}
+
+impl ProxyProtocol {
+    pub fn new(trusted: Vec<IpAddr>) -> Self {
+        ProxyProtocol { trusted }
+    }
+
+    /// Returns the address of the client of a connection from `peer`,
+    /// reading the header first if the peer is a trusted proxy. Health
+    /// checks of the proxy itself keep the address of the peer.
+    pub async fn client_addr(
+        &self,
+        socket: &mut TcpStream,
+        peer: SocketAddr,
+    ) -> anyhow::Result<SocketAddr> {
+        if !self.trusted.contains(&peer.ip()) {
+            return Ok(peer);
+        }
+
+        let client = timeout(HEADER_TIMEOUT, read_header(socket))
+            .await
+            .map_err(|_| anyhow::anyhow!("Proxy {} did not send a PROXY header", peer))??;
+
+        Ok(client.unwrap_or(peer))
+    }
+}
+
+/// Reads a PROXY protocol header, without reading anything after it, and
+/// returns the source address it has, if any.
+async fn read_header(socket: &mut TcpStream) -> anyhow::Result<Option<SocketAddr>> {
+    // the shortest header of version 1 is longer than the signature of
+    // version 2
+    let mut start = [0; 12];
+    socket.read_exact(&mut start).await?;
+
+    if start == V2_SIGNATURE {
+        let mut header = [0; 4];
+        socket.read_exact(&mut header).await?;
+
+        let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let mut addresses = vec![0; len];
+        socket.read_exact(&mut addresses).await?;
+
+        parse_v2(header[0], header[1], &addresses)
+    } else if start.starts_with(b"PROXY ") {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= MAX_V1_HEADER_LEN {
+                anyhow::bail!("PROXY header is too long");
+            }
+            line.push(socket.read_u8().await?);
+        }
+
+        parse_v1(std::str::from_utf8(&line)?)
+    } else {
+        anyhow::bail!("Connection does not start with a PROXY header")
+    }
+}
+
+/// Parses a header of version 1, like
+/// `PROXY TCP4 192.0.2.1 198.51.100.1 56324 1935\r\n`.
+fn parse_v1(line: &str) -> anyhow::Result<Option<SocketAddr>> {
+    let mut fields = line.trim_end().split(' ').skip(1);
+
+    match fields.next() {
+        Some("TCP4") | Some("TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => anyhow::bail!("Invalid PROXY header {:?}", line),
+    }
+
+    let source: IpAddr = fields.next().unwrap_or_default().parse()?;
+    let _destination = fields.next();
+    let port: u16 = fields.next().unwrap_or_default().parse()?;
+
+    Ok(Some(SocketAddr::new(source, port)))
+}
+
+/// Parses the command, address family and addresses of a header of
+/// version 2.
+fn parse_v2(
+    version_command: u8,
+    family: u8,
+    addresses: &[u8],
+) -> anyhow::Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        anyhow::bail!(
+            "Unsupported PROXY protocol version {}",
+            version_command >> 4
+        );
+    }
+
+    // connections the proxy makes itself, like health checks
+    if version_command & 0x0f == 0 {
+        return Ok(None);
+    }
+
+    match family >> 4 {
+        // IPv4, with the source and destination addresses and ports
+        1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // IPv6
+        2 if addresses.len() >= 36 => {
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        // unix sockets, or addresses the proxy doesn't know
+        _ => Ok(None),
+    }
+}
+
+/// A connection to the HTTP server, with the address of its client.
+pub struct ProxiedStream {
+    stream: TcpStream,
+    client: SocketAddr,
+}
+
+impl AsyncRead for ProxiedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProxiedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Lets handlers take the address of the client as `ConnectInfo`.
+impl Connected<&ProxiedStream> for SocketAddr {
+    fn connect_info(target: &ProxiedStream) -> Self {
+        target.client
+    }
+}
+
+/// Accepts the connections of a listener for the HTTP server, where the
+/// headers of trusted proxies are read before the connections are served,
+/// and the sockets are tuned for sending streams.
+pub fn accept_proxied(
+    listener: TcpListener,
+    proxy: ProxyProtocol,
+    tuning: TcpTuning,
+) -> impl Accept<Conn = ProxiedStream, Error = io::Error> {
+    let (tx, rx) = mpsc::channel::<io::Result<ProxiedStream>>(64);
+    let proxy = Arc::new(proxy);
+    let tuning = Arc::new(tuning);
+
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, peer) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    error!("Failed to accept TCP connection: {:?}", e);
+                    continue;
+                }
+            };
+
+            let proxy = proxy.clone();
+            let tuning = tuning.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let _ = stream.set_nodelay(true);
+                if let Err(e) = tuning.apply(&stream) {
+                    warn!("Failed to tune TCP connection from {}: {:?}", peer, e);
+                }
+
+                match proxy.client_addr(&mut stream, peer).await {
+                    Ok(client) => {
+                        let _ = tx.send(Ok(ProxiedStream { stream, client })).await;
+                    }
+                    Err(e) => debug!("Dropping HTTP connection from {}: {:?}", peer, e),
+                }
+            });
+        }
+    });
+
+    accept::from_stream(ReceiverStream::new(rx))
+}
@@ -0,0 +1,166 @@
+use hyper::{
+    body::Sender,
+    client::HttpConnector,
+    header::{CONTENT_TYPE, TRANSFER_ENCODING},
+    Body, Client, Request, Response,
+};
+use hyper_rustls::HttpsConnector;
+use parking_lot::RwLock;
+use sh_fmp4::CmafMuxer;
+use sh_media::{
+    ByteWriteFilter2, FrameReadFilter, MediaFrameQueue, MediaFrameQueueReceiver, Muxer,
+};
+use tokio::time::sleep;
+use tracing::*;
+
+use std::{sync::Arc, time::Duration};
+
+use crate::StreamRepository;
+
+/// How long to wait before pushing to an origin again after a failure.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+type HttpsClient = Client<HttpsConnector<HttpConnector>>;
+
+/// Pushes a stream to an external origin as long as it is live.
+///
+/// The stream is sent as a single CMAF track file over a long-running
+/// chunked HTTP PUT, as in the DASH-IF live media ingest specification. Every
+/// fragment is sent as soon as it is muxed, and a new request is started
+/// from the next keyframe if the origin drops the connection.
+///
+/// If the push falls so far behind that the queue drops it, it continues
+/// from the next live keyframe in the same request.
+pub fn spawn_push(url: String, stream_session_id: i32, repo: Arc<RwLock<StreamRepository>>) {
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_native_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    let client = Client::builder().build(connector);
+
+    tokio::spawn(async move {
+        loop {
+            let read = match live_queue(&repo, stream_session_id) {
+                Some(queue) => queue.get_receiver_from_keyframe(),
+                None => break,
+            };
+
+            info!("Pushing stream to {}", url);
+
+            match push(&client, &url, read, &repo, stream_session_id).await {
+                Ok(()) => break,
+                Err(e) => warn!("Failed to push stream to {}: {:?}", url, e),
+            }
+
+            sleep(RETRY_DELAY).await;
+        }
+
+        info!("Stopped pushing stream to {}", url);
+    });
+}
+
+/// Returns the queue of a stream, if it is still live.
+fn live_queue(repo: &RwLock<StreamRepository>, stream_session_id: i32) -> Option<MediaFrameQueue> {
+    let repo = repo.read();
+
+    repo.streams
+        .get(&stream_session_id)
+        .filter(|state| state.is_live())
+        .map(|state| state.queue.clone())
+}
+
+async fn push(
+    client: &HttpsClient,
+    url: &str,
+    mut read: MediaFrameQueueReceiver,
+    repo: &RwLock<StreamRepository>,
+    stream_session_id: i32,
+) -> anyhow::Result<()> {
+    let muxer = CmafMuxer::aligned();
+    let (sender, body) = Body::channel();
+
+    let request = Request::put(url)
+        .header(CONTENT_TYPE, muxer.content_type())
+        .header(TRANSFER_ENCODING, "chunked")
+        .body(body)?;
+
+    let forward = async move {
+        let streams = read.start().await?;
+
+        let has_video = streams.iter().any(|s| s.is_video());
+
+        let mut write = muxer.mux(Box::new(BodyWriteFilter { sender }));
+        write.start(streams).await?;
+
+        let mut needs_keyframe = false;
+        loop {
+            // the queue also drops readers which fall behind, so the request
+            // only ends once the stream does
+            let frame = match read.read().await {
+                Ok(frame) => frame,
+                Err(_) => match live_queue(repo, stream_session_id) {
+                    Some(queue) => {
+                        debug!("Push to {} fell behind, continuing from live", url);
+                        read = queue.get_receiver();
+                        needs_keyframe = has_video;
+                        continue;
+                    }
+                    None => break,
+                },
+            };
+
+            if needs_keyframe {
+                if !(frame.stream.is_video() && frame.is_keyframe()) {
+                    continue;
+                }
+                needs_keyframe = false;
+            }
+
+            write.write(frame).await?;
+        }
+
+        Ok::<_, anyhow::Error>(())
+    };
+
+    let response = client.request(request);
+    tokio::pin!(forward, response);
+
+    // the origin may respond before the stream ends, e.g. to reject it
+    tokio::select! {
+        result = &mut forward => {
+            result?;
+            check_response(&response.await?)
+        }
+        response = &mut response => {
+            check_response(&response?)?;
+            forward.await
+        }
+    }
+}
+
+fn check_response(response: &Response<Body>) -> anyhow::Result<()> {
+    if !response.status().is_success() {
+        anyhow::bail!("Origin responded with {}", response.status());
+    }
+
+    Ok(())
+}
+
+/// Writes bytes into the body of a streaming HTTP request.
+struct BodyWriteFilter {
+    sender: Sender,
+}
+
+#[async_trait::async_trait]
+impl ByteWriteFilter2 for BodyWriteFilter {
+    async fn start(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn write(&mut self, bytes: bytes::Bytes) -> anyhow::Result<()> {
+        self.sender.send_data(bytes).await?;
+
+        Ok(())
+    }
+}
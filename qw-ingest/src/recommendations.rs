@@ -0,0 +1,261 @@
+use axum::{
+    body::{self, boxed, BoxBody},
+    extract::{Extension, Query},
+};
+use hyper::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use std::{sync::Arc, time::Duration};
+
+use crate::AppData;
+
+/// The longest keyframe interval which is recommended, which is also the
+/// longest the RTMP conformance report doesn't warn about.
+const MAX_KEYFRAME_INTERVAL_SECS: u64 = 4;
+
+/// The video bitrates in kbit/s which are recommended for H.264 up to a
+/// frame height, at up to 30 and at up to 60 frames per second.
+const BITRATES: [(u32, u32, u32); 5] = [
+    (480, 1500, 2500),
+    (720, 3000, 4500),
+    (1080, 4500, 6000),
+    (1440, 9000, 12000),
+    (2160, 16000, 24000),
+];
+
+/// The H.264 levels by the most macroblocks a frame and a second can have.
+const LEVELS: [(&str, u32, u32); 7] = [
+    ("3.0", 1620, 40500),
+    ("3.1", 3600, 108000),
+    ("3.2", 5120, 216000),
+    ("4.1", 8192, 245760),
+    ("4.2", 8704, 522240),
+    ("5.1", 36864, 983040),
+    ("5.2", 36864, 2073600),
+];
+
+/// The share of the egress capacity above which streamers are recommended
+/// lower bitrates.
+const BUSY_LOAD: f64 = 0.75;
+const OVERLOADED_LOAD: f64 = 0.9;
+
+/// The limits streamers are held to when the server recommends encoder
+/// settings.
+#[derive(Debug, Clone, Copy)]
+pub struct EncoderPolicy {
+    pub max_bitrate_kbps: u32,
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_fps: u32,
+    /// How much viewers can be sent in total in kbit/s, which recommended
+    /// bitrates go down towards, or `None` to not look at the load.
+    pub egress_capacity_kbps: Option<u64>,
+}
+
+/// What a streamer plans to send.
+#[derive(Deserialize)]
+pub struct RecommendationParams {
+    /// Like `1920x1080`.
+    pub resolution: Option<String>,
+    pub fps: Option<u32>,
+}
+
+/// Encoder settings which suit the server.
+#[derive(Debug, Serialize, TS)]
+pub struct Recommendation {
+    pub resolution: String,
+    pub fps: u32,
+    pub video_codec: &'static str,
+    pub profile: &'static str,
+    pub level: &'static str,
+    pub video_bitrate_kbps: u32,
+    pub max_bitrate_kbps: u32,
+    #[ts(type = "number")]
+    pub keyframe_interval_secs: u64,
+    /// The keyframe interval in frames, for encoders which ask for it that
+    /// way.
+    pub gop_frames: u32,
+    pub b_frames: u32,
+    pub audio_codec: &'static str,
+    pub audio_bitrate_kbps: u32,
+    pub audio_sample_rate: u32,
+    /// The share of the egress capacity which is used, if there is one.
+    pub load: Option<f64>,
+    /// Why the settings differ from what was asked for.
+    pub notes: Vec<String>,
+}
+
+/// Parses a resolution like `1920x1080`.
+pub fn parse_resolution(resolution: &str) -> Option<(u32, u32)> {
+    let (width, height) = resolution.split_once('x')?;
+    let (width, height) = (width.parse().ok()?, height.parse().ok()?);
+
+    if width == 0 || height == 0 {
+        return None;
+    }
+
+    Some((width, height))
+}
+
+/// Returns the settings which are recommended for a resolution and frame
+/// rate, under the policy and with a share of the egress capacity in use.
+fn recommend(
+    policy: &EncoderPolicy,
+    (width, height): (u32, u32),
+    fps: u32,
+    load: Option<f64>,
+    keyframe_interval: Duration,
+    low_latency: bool,
+) -> Recommendation {
+    let mut notes = Vec::new();
+
+    // larger pictures are scaled down to fit, keeping their aspect ratio,
+    // and to even sizes which every chroma subsampling allows
+    let scale = (policy.max_width as f64 / width as f64)
+        .min(policy.max_height as f64 / height as f64)
+        .min(1.0);
+    let (width, height) = if scale < 1.0 {
+        notes.push(format!(
+            "Resolution is limited to {}x{}",
+            policy.max_width, policy.max_height
+        ));
+
+        (
+            ((width as f64 * scale) as u32 & !1).max(2),
+            ((height as f64 * scale) as u32 & !1).max(2),
+        )
+    } else {
+        (width, height)
+    };
+
+    let fps = if fps > policy.max_fps {
+        notes.push(format!("Frame rate is limited to {}", policy.max_fps));
+        policy.max_fps
+    } else {
+        fps.max(1)
+    };
+
+    let (_, at_30, at_60) = BITRATES
+        .iter()
+        .find(|(max_height, _, _)| height <= *max_height)
+        .unwrap_or(&BITRATES[BITRATES.len() - 1]);
+    let mut bitrate = if fps > 30 { *at_60 } else { *at_30 };
+
+    if let Some(load) = load {
+        let factor = if load >= OVERLOADED_LOAD {
+            0.5
+        } else if load >= BUSY_LOAD {
+            0.75
+        } else {
+            1.0
+        };
+
+        if factor < 1.0 {
+            notes.push(format!(
+                "The server is busy, so the bitrate is lowered to {}%",
+                (factor * 100.0) as u32
+            ));
+            bitrate = (bitrate as f64 * factor) as u32;
+        }
+    }
+
+    if bitrate > policy.max_bitrate_kbps {
+        notes.push(format!(
+            "Bitrate is limited to {} kbit/s",
+            policy.max_bitrate_kbps
+        ));
+        bitrate = policy.max_bitrate_kbps;
+    }
+
+    let macroblocks = ((width + 15) / 16) * ((height + 15) / 16);
+    let level = LEVELS
+        .iter()
+        .find(|(_, max_fs, max_mbps)| macroblocks <= *max_fs && macroblocks * fps <= *max_mbps)
+        .map_or("5.2", |(level, _, _)| level);
+
+    // keyframes at the start of every segment, so segments can be cut
+    // where they should be
+    let keyframe_interval_secs = keyframe_interval
+        .as_secs()
+        .clamp(1, MAX_KEYFRAME_INTERVAL_SECS);
+
+    // B-frames delay every frame by as many frames as they refer ahead
+    let b_frames = if low_latency {
+        notes.push("B-frames are turned off for low-latency playback".into());
+        0
+    } else {
+        2
+    };
+
+    Recommendation {
+        resolution: format!("{}x{}", width, height),
+        fps,
+        video_codec: "h264",
+        profile: "high",
+        level,
+        video_bitrate_kbps: bitrate,
+        max_bitrate_kbps: policy.max_bitrate_kbps,
+        keyframe_interval_secs,
+        gop_frames: keyframe_interval_secs as u32 * fps,
+        b_frames,
+        audio_codec: "aac",
+        audio_bitrate_kbps: 160,
+        audio_sample_rate: 48000,
+        load,
+        notes,
+    }
+}
+
+fn error(status: StatusCode, message: &'static str) -> Response<BoxBody> {
+    Response::builder()
+        .status(status)
+        .body(boxed(body::Full::from(message)))
+        .unwrap()
+}
+
+/// Recommends encoder settings for a resolution and frame rate, which
+/// keep to the policies of the server and go easy on it when it is busy.
+/// Without a resolution or frame rate, the largest allowed are assumed.
+pub async fn recommendations(
+    Query(params): Query<RecommendationParams>,
+    Extension(data): Extension<Arc<AppData>>,
+) -> Response<BoxBody> {
+    let policy = &data.encoder_policy;
+
+    let resolution = match params.resolution.as_deref().map(parse_resolution) {
+        Some(Some(resolution)) => resolution,
+        Some(None) => return error(StatusCode::BAD_REQUEST, "Invalid resolution"),
+        None => (policy.max_width, policy.max_height),
+    };
+    let fps = params.fps.unwrap_or(policy.max_fps);
+
+    let load = policy.egress_capacity_kbps.map(|capacity| {
+        let kbps = data.deliveries.bytes_per_second() * 8 / 1000;
+
+        kbps as f64 / capacity as f64
+    });
+
+    let (keyframe_interval, low_latency) = match &data.hls {
+        Some(hls) => (hls.segment_duration, hls.part_duration.is_some()),
+        None => (Duration::from_secs(2), false),
+    };
+
+    let recommendation = recommend(
+        policy,
+        resolution,
+        fps,
+        load,
+        keyframe_interval,
+        low_latency,
+    );
+
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .header("Access-Control-Allow-Origin", "*")
+        .status(StatusCode::OK)
+        .body(boxed(body::Full::from(
+            serde_json::to_vec(&recommendation).unwrap(),
+        )))
+        .unwrap()
+}
@@ -0,0 +1,377 @@
+use axum::{
+    body,
+    extract::{Extension, Path, Query},
+    http::HeaderMap,
+    response::IntoResponse,
+};
+use hyper::{Response, StatusCode};
+use qw_proto::stream_info::stream_reply::{RecordingFailed, StreamType};
+use serde::Deserialize;
+use sh_fmp4::CmafMuxer;
+use sh_media::{
+    FileWriteFilter, FrameReadFilter, FrameWriteFilter, MediaFrameQueue, Muxer, Stream,
+    WaitForSyncFrameFilter,
+};
+use sh_mkv::MatroskaMuxer;
+use tokio::{
+    sync::{broadcast::Sender, mpsc},
+    time::timeout,
+};
+use tracing::*;
+
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use crate::{
+    archive::Archive, diagnostics::is_admin, naming::NameTemplate, AppData, SOURCE_RENDITION,
+};
+
+/// How long a recording waits for the publisher to reconnect after the
+/// stream ends.
+const RECONNECT_GRACE: Duration = Duration::from_secs(300);
+
+/// The branch of an ingest which feeds recordings of the source, while
+/// there are any.
+pub const RECORDER_BRANCH: &str = "recorder";
+
+/// A recording of a stream to fragmented MP4 or Matroska files, which runs until it is
+/// stopped or the stream has been gone for [`RECONNECT_GRACE`].
+///
+/// Each file starts on a keyframe. When the publisher reconnects or changes
+/// its codec parameters, the recording continues in a new file instead of
+/// mixing incompatible media in one.
+pub struct Recording {
+    archive: Arc<Archive>,
+    sources: mpsc::UnboundedSender<MediaFrameQueue>,
+}
+
+impl Recording {
+    /// Starts recording `queue` to the files of `archive`, sending a
+    /// [`RecordingFailed`] event to `events` if writing them fails.
+    pub fn start(queue: MediaFrameQueue, archive: Archive, events: Sender<StreamType>) -> Self {
+        let archive = Arc::new(archive);
+        let (sources, recv) = mpsc::unbounded_channel();
+
+        let task_archive = archive.clone();
+        tokio::spawn(async move {
+            let result = record(queue, &task_archive, recv).await;
+
+            let finished = task_archive.finish().await;
+            if let Err(e) = &finished {
+                warn!("Failed to finish recording archive: {:?}", e);
+            }
+
+            match result.and(finished) {
+                Ok(()) => info!("Finished recording"),
+                Err(e) => {
+                    warn!("Stopped recording: {:?}", e);
+
+                    let _ = events.send(StreamType::RecordingFailed(RecordingFailed {
+                        stream: task_archive.stream(),
+                        recording: task_archive.name().to_string(),
+                        error: format!("{:#}", e),
+                    }));
+                }
+            }
+        });
+
+        Recording { archive, sources }
+    }
+
+    /// Continues the recording from a new session of the same stream.
+    /// Returns `false` if the recording has already finished.
+    pub fn follow(&self, queue: MediaFrameQueue) -> bool {
+        self.sources.send(queue).is_ok()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.sources.is_closed()
+    }
+
+    /// The files written so far, in order.
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.archive.files()
+    }
+}
+
+async fn record(
+    mut source: MediaFrameQueue,
+    archive: &Archive,
+    mut sources: mpsc::UnboundedReceiver<MediaFrameQueue>,
+) -> anyhow::Result<()> {
+    loop {
+        // don't keep the queue alive, so we notice when the stream ends
+        let mut read = source.get_receiver_from_keyframe();
+        drop(source);
+
+        let mut streams = read.start().await?;
+        let mut write = open_file(archive, streams.clone()).await?;
+
+        source = loop {
+            tokio::select! {
+                frame = read.read() => match frame {
+                    Ok(frame) => {
+                        let stream = streams.iter_mut().find(|s| s.id == frame.stream.id);
+
+                        if let Some(stream) = stream {
+                            if !stream.is_compatible_with(&frame.stream) {
+                                info!("Codec parameters changed during recording");
+
+                                *stream = frame.stream.clone();
+                                write = open_file(archive, streams.clone()).await?;
+                            }
+                        }
+
+                        write.write(frame).await?;
+                    }
+                    Err(_) => {
+                        debug!("Recorded stream ended, waiting for it to come back");
+
+                        match timeout(RECONNECT_GRACE, sources.recv()).await {
+                            Ok(Some(queue)) => break queue,
+                            _ => return Ok(()),
+                        }
+                    }
+                },
+                queue = sources.recv() => match queue {
+                    Some(queue) => break queue,
+                    None => return Ok(()),
+                },
+            }
+        };
+
+        info!("Recorded stream was restarted");
+    }
+}
+
+/// Opens the next file of a recording, which is written once a video
+/// keyframe arrives.
+async fn open_file(
+    archive: &Archive,
+    streams: Vec<Stream>,
+) -> anyhow::Result<WaitForSyncFrameFilter> {
+    let (path, file) = archive.create_file().await?;
+    let file = FileWriteFilter::new(file).with_sync(archive.syncs_fragments());
+    let mux = archive.muxer().mux(Box::new(file));
+
+    let mut write = WaitForSyncFrameFilter::new(mux);
+    write.start(streams).await?;
+
+    info!("Recording to {}", path.display());
+
+    Ok(write)
+}
+
+/// Selects the muxer recordings are written with, from the extension of
+/// their format.
+pub fn recording_muxer(format: &str) -> anyhow::Result<Arc<dyn Muxer>> {
+    match format {
+        "mp4" => Ok(Arc::new(CmafMuxer::aligned())),
+        "mkv" => Ok(Arc::new(MatroskaMuxer)),
+        _ => anyhow::bail!("Unknown recording format '{}'", format),
+    }
+}
+
+fn file_list(files: &[PathBuf]) -> String {
+    files
+        .iter()
+        .map(|f| f.display().to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[derive(Deserialize)]
+pub struct RecordingParams {
+    /// Comma separated renditions to record, defaults to the source.
+    renditions: Option<String>,
+    /// How to name the recorded files, instead of the configured template.
+    template: Option<String>,
+}
+
+/// Returns the directory recordings of `rendition` are stored in.
+pub fn recording_dir<'a>(data: &'a AppData, rendition: &str) -> &'a std::path::Path {
+    data.recording_targets
+        .get(rendition)
+        .unwrap_or(&data.recording_dir)
+}
+
+/// Starts recording the selected renditions of `stream`, each to its own
+/// storage target, and returns the names of the new recordings.
+pub async fn start_recording(
+    Path(stream): Path<String>,
+    Query(params): Query<RecordingParams>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    if !is_admin(&data, &headers) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body::Full::from("Missing or invalid admin token"))
+            .unwrap();
+    }
+
+    let renditions = params
+        .renditions
+        .unwrap_or_else(|| SOURCE_RENDITION.to_string());
+
+    let template = match params.template.as_deref().map(str::parse::<NameTemplate>) {
+        Some(Ok(template)) => Some(template),
+        Some(Err(e)) => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(body::Full::from(format!("Invalid template: {}", e)))
+                .unwrap()
+        }
+        None => data.recording_template.clone(),
+    };
+
+    let (queues, graph) = {
+        let repo = data.stream_repo.read();
+
+        let state = match repo
+            .stream_mapping
+            .get(&stream)
+            .and_then(|id| repo.streams.get(id))
+        {
+            Some(state) => state,
+            None => {
+                return Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(body::Full::from("No stream with that name"))
+                    .unwrap()
+            }
+        };
+
+        let mut queues = Vec::new();
+        for rendition in renditions.split(',').map(str::trim) {
+            match state.recorded(rendition) {
+                Some(queue) => queues.push((rendition.to_string(), queue)),
+                None => {
+                    return Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(body::Full::from(format!(
+                            "Stream has no rendition '{}'",
+                            rendition
+                        )))
+                        .unwrap()
+                }
+            }
+        }
+
+        (queues, state.graph.clone())
+    };
+
+    // the ingest only feeds the recorder while the source is recorded
+    let recorder = queues
+        .iter()
+        .find(|(rendition, _)| rendition == SOURCE_RENDITION);
+    if let (Some((_, recorder)), Some(graph)) = (recorder, graph) {
+        if graph.topology().node(RECORDER_BRANCH).is_none() {
+            let write = Box::new(recorder.clone());
+
+            if let Err(e) = graph.add_branch(RECORDER_BRANCH, write).await {
+                warn!("Failed to start the recorder of '{}': {:?}", stream, e);
+
+                return Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(body::Full::from("Failed to start recording"))
+                    .unwrap();
+            }
+        }
+    }
+
+    let mut repo = data.stream_repo.write();
+
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let events = repo.event_sender();
+    let recordings = repo.recordings.entry(stream.clone()).or_default();
+    let mut names = Vec::new();
+
+    for (rendition, queue) in queues {
+        let active = recordings
+            .get(&rendition)
+            .map(|r| !r.is_finished())
+            .unwrap_or(false);
+        if active {
+            continue;
+        }
+
+        let name = if rendition == SOURCE_RENDITION {
+            format!("{}-{}", stream, timestamp)
+        } else {
+            format!("{}-{}-{}", stream, timestamp, rendition)
+        };
+        let dir = recording_dir(&data, &rendition).to_path_buf();
+        let mut archive = Archive::new(dir, name.clone(), stream.clone())
+            .with_fsync(data.fsync_policy)
+            .with_muxer(data.recording_muxer.clone());
+        if let Some(template) = &template {
+            archive = archive.with_template(template.clone(), rendition.clone());
+        }
+
+        recordings.insert(rendition, Recording::start(queue, archive, events.clone()));
+        names.push(name);
+    }
+
+    if names.is_empty() {
+        return Response::builder()
+            .status(StatusCode::CONFLICT)
+            .body(body::Full::from("Stream is already being recorded"))
+            .unwrap();
+    }
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .body(body::Full::from(names.join("\n")))
+        .unwrap()
+}
+
+/// Stops every recording of `stream` and lists the recorded files.
+pub async fn stop_recording(
+    Path(stream): Path<String>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    if !is_admin(&data, &headers) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(body::Full::from("Missing or invalid admin token"))
+            .unwrap();
+    }
+
+    let (recordings, graph) = {
+        let mut repo = data.stream_repo.write();
+        let graph = repo
+            .stream_mapping
+            .get(&stream)
+            .and_then(|id| repo.streams.get(id))
+            .and_then(|state| state.graph.clone());
+
+        (repo.recordings.remove(&stream), graph)
+    };
+
+    if let Some(graph) = graph {
+        graph.remove(RECORDER_BRANCH).await;
+    }
+
+    match recordings {
+        Some(recordings) if !recordings.is_empty() => {
+            let files = recordings
+                .values()
+                .flat_map(|r| r.files())
+                .collect::<Vec<_>>();
+
+            Response::builder()
+                .status(StatusCode::OK)
+                .body(body::Full::from(file_list(&files)))
+                .unwrap()
+        }
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(body::Full::from("Stream is not being recorded"))
+            .unwrap(),
+    }
+}
@@ -0,0 +1,581 @@
+use axum::{
+    body::{self, boxed, BoxBody},
+    extract::{Extension, Path},
+    http::HeaderMap,
+};
+use bytes::Bytes;
+use hyper::{Response, StatusCode};
+use parking_lot::Mutex;
+use qw_proto::stream_info::stream_reply::StreamType;
+use serde::{Deserialize, Serialize};
+use sh_ingest_rtmp::RtmpUrl;
+use sh_ingest_srt::{SrtConnection, SrtUrl};
+use sh_media::FrameReadFilter;
+use tokio::{
+    sync::{broadcast, watch},
+    time::sleep,
+};
+use tracing::*;
+use ts_rs::TS;
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{archive::write_atomically, diagnostics::is_admin, srt_playback, AppData};
+
+/// How long a target waits before reconnecting after its first failure.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The longest a target waits before reconnecting.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How long a connection has to last for the backoff to start over.
+const STABLE_CONNECTION: Duration = Duration::from_secs(30);
+
+/// How much of a stream SRT servers buffer to recover lost packets, unless
+/// the URL of a target asks for more.
+const SRT_LATENCY: Duration = Duration::from_millis(120);
+
+/// An RTMP or SRT server which a stream is restreamed to, like Twitch or
+/// YouTube.
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+pub struct RelayTarget {
+    #[serde(default)]
+    #[ts(type = "number")]
+    pub id: u64,
+    /// The name of the stream which is restreamed.
+    pub stream: String,
+    /// The URL the stream is published to, including the stream key, e.g.
+    /// `rtmp://a.rtmp.youtube.com/live2/<key>` or
+    /// `srt://host:port?streamid=<key>`.
+    pub url: String,
+}
+
+/// Where a target is restreamed to, which is sent FLV over RTMP or MPEG-TS
+/// over SRT.
+enum RelayUrl {
+    Rtmp(RtmpUrl),
+    Srt(SrtUrl),
+}
+
+impl RelayUrl {
+    fn host(&self) -> &str {
+        match self {
+            RelayUrl::Rtmp(url) => &url.host,
+            RelayUrl::Srt(url) => &url.host,
+        }
+    }
+
+    /// Publishes a stream until it ends, calling `connected` once the server
+    /// has accepted it.
+    async fn publish(
+        &self,
+        read: &mut (dyn FrameReadFilter + Send + Unpin),
+        connected: impl FnOnce(),
+    ) -> anyhow::Result<()> {
+        match self {
+            RelayUrl::Rtmp(url) => sh_ingest_rtmp::publish_rtmp(url, read, connected).await,
+            RelayUrl::Srt(url) => {
+                let connection = SrtConnection::connect(url, SRT_LATENCY).await?;
+                connected();
+
+                // the muxer stops once the server is gone, and the connection
+                // once the stream ends
+                let (tx, rx) = async_channel::bounded(srt_playback::PAYLOAD_QUEUE);
+                let (muxed, sent) = tokio::join!(srt_playback::mux(read, tx), connection.send(rx));
+
+                sent.and(muxed)
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for RelayUrl {
+    type Err = anyhow::Error;
+
+    fn from_str(url: &str) -> anyhow::Result<Self> {
+        if url.starts_with("srt://") {
+            Ok(RelayUrl::Srt(url.parse()?))
+        } else {
+            Ok(RelayUrl::Rtmp(url.parse()?))
+        }
+    }
+}
+
+/// Leaves the stream key out of the URL of a target, which is the end of
+/// the path for RTMP and the query for SRT.
+fn redacted_url(url: &str) -> String {
+    if url.starts_with("srt://") {
+        match url.split_once('?') {
+            Some((base, _query)) => format!("{}?streamid=<key>", base),
+            None => url.to_string(),
+        }
+    } else {
+        match url.rsplit_once('/') {
+            Some((base, _key)) => format!("{}/<key>", base),
+            None => url.to_string(),
+        }
+    }
+}
+
+/// How the restreaming to a target is going.
+#[derive(Debug, Clone, Default, Serialize, TS)]
+pub struct RelayStatus {
+    pub connected: bool,
+    /// How many times in a row connecting has failed.
+    pub failures: u32,
+    /// Why the last connection failed.
+    pub error: Option<String>,
+}
+
+struct Relay {
+    target: RelayTarget,
+    status: RelayStatus,
+    /// Stops the target's task when it is removed.
+    stop: watch::Sender<bool>,
+    /// The stream session the target's task restreams, if it runs.
+    session: Option<i32>,
+}
+
+/// The servers which streams are restreamed to while they are live, which
+/// watches the stream repository for streams going live.
+///
+/// Targets are kept in a JSON file, so they can be written by hand as well
+/// as through the API. Every target reconnects on its own, so one failing
+/// server doesn't hold up the others.
+pub struct RelayManager {
+    relays: Mutex<HashMap<u64, Relay>>,
+    /// Where targets are persisted, if anywhere.
+    path: Option<PathBuf>,
+    sync: bool,
+    /// Serializes writes of the targets file, so an older snapshot never
+    /// replaces a newer one.
+    persisting: tokio::sync::Mutex<()>,
+    next_id: AtomicU64,
+}
+
+impl RelayManager {
+    pub fn new(path: Option<PathBuf>, sync: bool) -> Self {
+        RelayManager {
+            relays: Mutex::new(HashMap::new()),
+            path,
+            sync,
+            persisting: tokio::sync::Mutex::new(()),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Reads the targets from the file, if there is one.
+    pub async fn load(&self) -> anyhow::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let json = match tokio::fs::read(path).await {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let targets: Vec<RelayTarget> = serde_json::from_slice(&json)?;
+
+        for target in targets {
+            target.url.parse::<RelayUrl>()?;
+            self.insert(target);
+        }
+
+        Ok(())
+    }
+
+    /// Adds a target, giving it an ID if it doesn't have one.
+    fn insert(&self, mut target: RelayTarget) -> RelayTarget {
+        if target.id == 0 {
+            target.id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.next_id.fetch_max(target.id + 1, Ordering::Relaxed);
+        }
+
+        let (stop, _) = watch::channel(false);
+        self.relays.lock().insert(
+            target.id,
+            Relay {
+                target: target.clone(),
+                status: RelayStatus::default(),
+                stop,
+                session: None,
+            },
+        );
+
+        target
+    }
+
+    fn remove(&self, id: u64) -> bool {
+        match self.relays.lock().remove(&id) {
+            Some(relay) => {
+                let _ = relay.stop.send(true);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The targets of a stream which don't restream its session yet, with
+    /// the receivers which tell their tasks to stop. The targets are marked
+    /// as restreaming the session.
+    fn claim_targets(
+        &self,
+        stream: &str,
+        stream_session_id: i32,
+    ) -> Vec<(RelayTarget, watch::Receiver<bool>)> {
+        self.relays
+            .lock()
+            .values_mut()
+            .filter(|relay| relay.target.stream == stream)
+            .filter(|relay| relay.session != Some(stream_session_id))
+            .map(|relay| {
+                relay.session = Some(stream_session_id);
+                (relay.target.clone(), relay.stop.subscribe())
+            })
+            .collect()
+    }
+
+    /// Notes that the task of a target stopped restreaming a session.
+    fn release(&self, id: u64, stream_session_id: i32) {
+        if let Some(relay) = self.relays.lock().get_mut(&id) {
+            if relay.session == Some(stream_session_id) {
+                relay.session = None;
+            }
+        }
+    }
+
+    fn update(&self, id: u64, f: impl FnOnce(&mut RelayStatus)) {
+        if let Some(relay) = self.relays.lock().get_mut(&id) {
+            f(&mut relay.status);
+        }
+    }
+
+    async fn persist(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+
+        let _persisting = self.persisting.lock().await;
+        let json = {
+            let relays = self.relays.lock();
+            let mut targets = relays.values().map(|r| &r.target).collect::<Vec<_>>();
+            targets.sort_by_key(|target| target.id);
+
+            serde_json::to_vec_pretty(&targets)
+        };
+
+        let result = match json {
+            Ok(json) => write_atomically(path, &json, self.sync).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            warn!(
+                "Failed to persist relay targets to {}: {:?}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Starts restreaming a live stream to those of its targets which don't
+/// restream it yet.
+fn start_relays(data: &Arc<AppData>, stream: &str, stream_session_id: i32) {
+    for (target, stopped) in data.relays.claim_targets(stream, stream_session_id) {
+        spawn_relay(target, stopped, stream_session_id, data.clone());
+    }
+}
+
+/// Starts restreaming every live stream to its targets.
+fn start_all_relays(data: &Arc<AppData>) {
+    let live = {
+        let repo = data.stream_repo.read();
+        repo.stream_mapping
+            .iter()
+            .filter(|(_, id)| repo.streams.contains_key(id))
+            .map(|(name, id)| (name.clone(), *id))
+            .collect::<Vec<_>>()
+    };
+
+    for (name, id) in live {
+        start_relays(data, &name, id);
+    }
+}
+
+/// Restreams streams to their targets whenever they go live, for as long as
+/// the server runs.
+pub fn spawn_relay_manager(data: Arc<AppData>) {
+    let mut events = data.stream_repo.read().events();
+
+    tokio::spawn(async move {
+        start_all_relays(&data);
+
+        loop {
+            let stream_session_id = match events.recv().await {
+                Ok(StreamType::StreamStarted(started)) => started.stream_session_id,
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    // streams which went live in the missed events are
+                    // caught up on, the others are already restreamed
+                    debug!("Relay manager missed {} stream events", missed);
+                    start_all_relays(&data);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+
+            let name = data
+                .stream_repo
+                .read()
+                .stream_mapping
+                .iter()
+                .find(|(_, id)| **id == stream_session_id)
+                .map(|(name, _)| name.clone());
+            if let Some(name) = name {
+                start_relays(&data, &name, stream_session_id);
+            }
+        }
+    });
+}
+
+/// Restreams a stream to a target as long as both the stream and the target
+/// exist, reconnecting with an exponential backoff when the connection
+/// fails.
+fn spawn_relay(
+    target: RelayTarget,
+    mut stopped: watch::Receiver<bool>,
+    stream_session_id: i32,
+    data: Arc<AppData>,
+) {
+    let url = match target.url.parse::<RelayUrl>() {
+        Ok(url) => url,
+        Err(e) => {
+            warn!("Not restreaming to invalid target {}: {:?}", target.id, e);
+            data.relays.release(target.id, stream_session_id);
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        let mut backoff = INITIAL_BACKOFF;
+
+        while !*stopped.borrow() {
+            let mut read = {
+                let repo = data.stream_repo.read();
+
+                match repo.streams.get(&stream_session_id) {
+                    Some(state) if state.is_live() => state.relay.get_receiver_from_keyframe(),
+                    _ => break,
+                }
+            };
+
+            // the stream key is not logged
+            info!(
+                "Restreaming '{}' to {} on {}",
+                target.stream,
+                target.id,
+                url.host()
+            );
+
+            let started = Instant::now();
+            let connected = || {
+                data.relays.update(target.id, |status| {
+                    status.connected = true;
+                    status.failures = 0;
+                })
+            };
+            let result = tokio::select! {
+                result = url.publish(&mut read, connected) => result,
+                _ = stopped.changed() => break,
+            };
+
+            data.relays.update(target.id, |status| {
+                status.connected = false;
+
+                if let Err(e) = &result {
+                    status.failures += 1;
+                    status.error = Some(e.to_string());
+                }
+            });
+
+            match result {
+                // the stream ended
+                Ok(()) => break,
+                Err(e) => warn!("Restreaming to {} failed: {:?}", target.id, e),
+            }
+
+            if started.elapsed() >= STABLE_CONNECTION {
+                backoff = INITIAL_BACKOFF;
+            }
+
+            tokio::select! {
+                _ = sleep(backoff) => {}
+                _ = stopped.changed() => break,
+            }
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+
+        data.relays.update(target.id, |s| s.connected = false);
+        data.relays.release(target.id, stream_session_id);
+        info!("Stopped restreaming to {}", target.id);
+    });
+}
+
+#[derive(Serialize, TS)]
+pub(crate) struct RelayInfo<'a> {
+    #[serde(flatten)]
+    target: &'a RelayTarget,
+    #[serde(flatten)]
+    status: &'a RelayStatus,
+}
+
+/// Lists the relay targets with how they are doing. Stream keys are left
+/// out of the URLs.
+pub async fn list_relays(
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> Response<BoxBody> {
+    if !is_admin(&data, &headers) {
+        return error(StatusCode::UNAUTHORIZED, "Missing or invalid admin token");
+    }
+
+    let json = {
+        let relays = data.relays.relays.lock();
+        let mut targets = relays.values().map(redacted).collect::<Vec<_>>();
+        targets.sort_by_key(|(target, _)| target.id);
+
+        let infos = targets
+            .iter()
+            .map(|(target, status)| RelayInfo { target, status })
+            .collect::<Vec<_>>();
+        serde_json::to_vec(&infos).unwrap()
+    };
+
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .status(StatusCode::OK)
+        .body(boxed(body::Full::from(json)))
+        .unwrap()
+}
+
+/// Shows how restreaming to a single target is going, with the stream key
+/// left out of its URL.
+pub async fn get_relay(
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> Response<BoxBody> {
+    if !is_admin(&data, &headers) {
+        return error(StatusCode::UNAUTHORIZED, "Missing or invalid admin token");
+    }
+
+    let json = {
+        let relays = data.relays.relays.lock();
+        let (target, status) = match relays.get(&id) {
+            Some(relay) => redacted(relay),
+            None => return error(StatusCode::NOT_FOUND, "No such relay target"),
+        };
+
+        serde_json::to_vec(&RelayInfo {
+            target: &target,
+            status: &status,
+        })
+        .unwrap()
+    };
+
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .status(StatusCode::OK)
+        .body(boxed(body::Full::from(json)))
+        .unwrap()
+}
+
+fn redacted(relay: &Relay) -> (RelayTarget, RelayStatus) {
+    let target = RelayTarget {
+        url: redacted_url(&relay.target.url),
+        ..relay.target.clone()
+    };
+
+    (target, relay.status.clone())
+}
+
+/// Adds a relay target from a JSON body with its `stream` and `url`, which
+/// starts right away if the stream is live.
+///
+/// The target is sent in the body rather than the query, so the stream key
+/// doesn't end up in access logs.
+pub async fn add_relay(
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+    body: Bytes,
+) -> Response<BoxBody> {
+    if !is_admin(&data, &headers) {
+        return error(StatusCode::UNAUTHORIZED, "Missing or invalid admin token");
+    }
+
+    let target = match serde_json::from_slice::<RelayTarget>(&body) {
+        Ok(target) if target.url.parse::<RelayUrl>().is_ok() && !target.stream.is_empty() => {
+            RelayTarget { id: 0, ..target }
+        }
+        _ => return error(StatusCode::BAD_REQUEST, "Invalid relay target"),
+    };
+
+    let target = data.relays.insert(target);
+    data.relays.persist().await;
+
+    let live = {
+        let repo = data.stream_repo.read();
+        repo.stream_mapping.get(&target.stream).copied()
+    };
+    if let Some(stream_session_id) = live {
+        start_relays(&data, &target.stream, stream_session_id);
+    }
+
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .status(StatusCode::CREATED)
+        .body(boxed(body::Full::from(
+            serde_json::json!({ "id": target.id }).to_string(),
+        )))
+        .unwrap()
+}
+
+/// Removes a relay target, which stops restreaming to it.
+pub async fn remove_relay(
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> Response<BoxBody> {
+    if !is_admin(&data, &headers) {
+        return error(StatusCode::UNAUTHORIZED, "Missing or invalid admin token");
+    }
+
+    if !data.relays.remove(id) {
+        return error(StatusCode::NOT_FOUND, "No such relay target");
+    }
+    data.relays.persist().await;
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .body(boxed(body::Full::from("Relay target removed")))
+        .unwrap()
+}
+
+fn error(status: StatusCode, message: &'static str) -> Response<BoxBody> {
+    Response::builder()
+        .status(status)
+        .body(boxed(body::Full::from(message)))
+        .unwrap()
+}
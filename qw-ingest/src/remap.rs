@@ -0,0 +1,271 @@
+use anyhow::Context;
+use sh_media::{Fraction, Frame, FrameReadFilter, MediaTime, Stream};
+use tokio::{sync::mpsc, time::sleep};
+use tracing::*;
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    authenticate_stream,
+    failover::{follow_input, InputEvent},
+    ingest,
+    supervisor::Backoff,
+    AppData, IngestSource,
+};
+
+/// How long to wait before trying again after a source stopped.
+const SOURCE_RETRY: Duration = Duration::from_secs(1);
+
+/// How many events of the sources are queued before frames of the tracks
+/// which aren't used are dropped, and the sources wait for the rest.
+const EVENT_QUEUE_LEN: usize = 256;
+
+/// The timebase which the sources are aligned in.
+const OUTPUT_TIMEBASE: Fraction = Fraction::new(1, 90000);
+
+const VIDEO: usize = 0;
+const AUDIO: usize = 1;
+
+/// Publishes `stream_key` as a stream with the video of `video_source` and
+/// the audio of `audio_source`, e.g. a stadium feed with commentary.
+///
+/// `audio_delay_ms` is added to the audio timestamps, to make up for one
+/// source reaching the ingest later than the other.
+pub fn spawn_remapped_stream(
+    stream_key: String,
+    video_source: String,
+    audio_source: String,
+    audio_delay_ms: i64,
+    data: Arc<AppData>,
+) {
+    tokio::spawn(async move {
+        let sources = [video_source, audio_source];
+
+        let mut backoff = Backoff::new(SOURCE_RETRY);
+
+        loop {
+            let started = Instant::now();
+            let result = run_stream(&stream_key, &sources, audio_delay_ms, data.clone()).await;
+            if let Err(e) = &result {
+                error!("Remapped stream with sources {:?} failed: {:?}", sources, e);
+            }
+
+            if !data.supervisor.should_restart(&result) {
+                break;
+            }
+
+            sleep(backoff.next(started.elapsed())).await;
+        }
+    });
+}
+
+async fn run_stream(
+    stream_key: &str,
+    sources: &[String; 2],
+    audio_delay_ms: i64,
+    data: Arc<AppData>,
+) -> anyhow::Result<()> {
+    let mut filter = RemapReadFilter::new(sources.clone(), audio_delay_ms, data.clone());
+
+    // only go live once both sources are
+    filter.start().await?;
+
+    let mut client = data.client.clone();
+    let (id, name) = authenticate_stream(&mut client, stream_key, false).await?;
+
+    info!(
+        "Starting stream {} with video of {} and audio of {}",
+        name, sources[VIDEO], sources[AUDIO]
+    );
+
+    ingest(id, name, IngestSource::new(Box::new(filter)), data).await
+}
+
+/// A pull filter which combines the video of one live stream with the audio
+/// of another.
+///
+/// The sources have unrelated timestamps, so each is aligned to the time its
+/// first frame arrived at. The output ends when either source stops.
+pub struct RemapReadFilter {
+    names: [String; 2],
+    events: mpsc::Receiver<InputEvent>,
+    epoch: Instant,
+    audio_delay: i64,
+
+    streams: [Option<Stream>; 2],
+    /// Added to the timestamps of each source, in [`OUTPUT_TIMEBASE`].
+    offsets: [Option<i64>; 2],
+    last_output: [Option<u64>; 2],
+    started: bool,
+    waiting_for_keyframe: bool,
+}
+
+impl RemapReadFilter {
+    pub fn new(names: [String; 2], audio_delay_ms: i64, data: Arc<AppData>) -> Self {
+        let (tx, events) = mpsc::channel(EVENT_QUEUE_LEN);
+
+        for (index, name) in names.iter().enumerate() {
+            // only the video of one source and the audio of the other are used
+            let is_wanted = move |frame: &Frame| frame.stream.is_video() == (index == VIDEO);
+
+            tokio::spawn(follow_input(
+                index,
+                name.clone(),
+                data.clone(),
+                tx.clone(),
+                is_wanted,
+            ));
+        }
+
+        RemapReadFilter {
+            names,
+            events,
+            epoch: Instant::now(),
+            audio_delay: audio_delay_ms * OUTPUT_TIMEBASE.denominator as i64 / 1000,
+            streams: [None, None],
+            offsets: [None, None],
+            last_output: [None, None],
+            started: false,
+            waiting_for_keyframe: true,
+        }
+    }
+
+    async fn next_event(&mut self) -> anyhow::Result<InputEvent> {
+        self.events
+            .recv()
+            .await
+            .context("remapped stream sources stopped")
+    }
+
+    fn handle(&mut self, event: InputEvent) -> anyhow::Result<Option<Frame>> {
+        match event {
+            InputEvent::Started(index, streams) => {
+                if self.started {
+                    // the tracks of a running stream can't change
+                    anyhow::bail!("{} restarted", self.names[index]);
+                }
+
+                let stream = streams
+                    .into_iter()
+                    .find(|s| {
+                        if index == VIDEO {
+                            s.is_video()
+                        } else {
+                            s.is_audio()
+                        }
+                    })
+                    .with_context(|| {
+                        let kind = if index == VIDEO { "video" } else { "audio" };
+                        format!("{} has no {}", self.names[index], kind)
+                    })?;
+
+                debug!("Remap source {} started", self.names[index]);
+
+                self.streams[index] = Some(Stream {
+                    id: index as u32,
+                    ..stream
+                });
+
+                Ok(None)
+            }
+            InputEvent::Ended(index) => {
+                if self.started {
+                    anyhow::bail!("{} stopped", self.names[index]);
+                }
+
+                self.streams[index] = None;
+
+                Ok(None)
+            }
+            InputEvent::Frame(index, frame) => {
+                let stream = match &self.streams[index] {
+                    Some(stream) if self.started => stream.clone(),
+                    _ => return Ok(None),
+                };
+
+                let is_video = index == VIDEO;
+                if self.waiting_for_keyframe {
+                    if !(is_video && frame.stream.is_video() && frame.is_keyframe()) {
+                        return Ok(None);
+                    }
+
+                    self.waiting_for_keyframe = false;
+                }
+                if is_video != frame.stream.is_video() {
+                    // the other track of the source
+                    return Ok(None);
+                }
+
+                Ok(Some(self.retime(index, stream, frame)))
+            }
+        }
+    }
+
+    /// Moves a frame onto the output timeline and the output stream.
+    fn retime(&mut self, index: usize, stream: Stream, mut frame: Frame) -> Frame {
+        let time = frame.time.in_base(OUTPUT_TIMEBASE);
+
+        let offset = match self.offsets[index] {
+            Some(offset) => offset,
+            None => {
+                let arrival = (self.epoch.elapsed().as_micros() * 9 / 100) as i64;
+                let delay = if index == AUDIO { self.audio_delay } else { 0 };
+                let offset = arrival + delay - time.dts.unwrap_or(time.pts) as i64;
+
+                self.offsets[index] = Some(offset);
+                offset
+            }
+        };
+
+        let shift = |ts: u64| (ts as i64 + offset).max(0) as u64;
+        let mut output = MediaTime {
+            pts: shift(time.pts),
+            dts: time.dts.map(shift),
+            timebase: OUTPUT_TIMEBASE,
+        };
+
+        // never go back in time, even if a source does
+        if let Some(last) = self.last_output[index] {
+            let decode = output.dts.unwrap_or(output.pts);
+            if decode < last {
+                let correction = last - decode;
+                output.pts += correction;
+                output.dts = output.dts.map(|dts| dts + correction);
+            }
+        }
+        self.last_output[index] = Some(output.dts.unwrap_or(output.pts));
+
+        frame.time = output.in_base(frame.time.timebase);
+        frame.stream = stream;
+        frame
+    }
+}
+
+#[async_trait::async_trait]
+impl FrameReadFilter for RemapReadFilter {
+    /// Waits for both sources to be live and returns the video stream of
+    /// one and the audio stream of the other.
+    async fn start(&mut self) -> anyhow::Result<Vec<Stream>> {
+        while !self.started {
+            let event = self.next_event().await?;
+            self.handle(event)?;
+
+            self.started = self.streams.iter().all(Option::is_some);
+        }
+
+        Ok(self.streams.iter().flatten().cloned().collect())
+    }
+
+    async fn read(&mut self) -> anyhow::Result<Frame> {
+        loop {
+            let event = self.next_event().await?;
+
+            if let Some(frame) = self.handle(event)? {
+                return Ok(frame);
+            }
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use bytes::Bytes;
+use sh_ingest_ts::{ProgramSelector, RistListener, TsReadFilter, UdpTsListener};
+use tokio::{task::JoinHandle, time::sleep};
+use tracing::*;
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use crate::{authenticate_stream, ingest, AppData, IngestSource};
+
+/// A program of the transport stream received by a RIST or UDP listener,
+/// and the stream key it is published with.
+#[derive(Debug, Clone)]
+pub struct TsProgram {
+    pub stream_key: String,
+    pub program: ProgramSelector,
+    /// The teletext page captions are extracted from, if any.
+    pub teletext_page: Option<u16>,
+}
+
+/// Listens for RIST senders on `addr` and publishes each of `programs` as
+/// its own stream, one sender at a time.
+///
+/// RIST has no way for the sender to identify a stream, so each program of
+/// a listener is bound to a single stream key.
+pub fn spawn_rist_listener(
+    addr: SocketAddr,
+    programs: Vec<TsProgram>,
+    buffer: Duration,
+    data: Arc<AppData>,
+) {
+    tokio::spawn(async move {
+        let result = match RistListener::bind(addr, buffer).await {
+            Ok(listener) => listen(TsListener::Rist(listener), addr, &programs, data).await,
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = result {
+            error!("RIST listener at {} failed: {:?}", addr, e);
+        }
+    });
+}
+
+/// Listens for MPEG-TS over UDP on `addr`, which may be a multicast group,
+/// and publishes each of `programs` like a RIST listener does.
+pub fn spawn_udp_listener(addr: SocketAddr, programs: Vec<TsProgram>, data: Arc<AppData>) {
+    tokio::spawn(async move {
+        let result = match UdpTsListener::bind(addr).await {
+            Ok(listener) => listen(TsListener::Udp(listener), addr, &programs, data).await,
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = result {
+            error!("UDP listener at {} failed: {:?}", addr, e);
+        }
+    });
+}
+
+/// A listener for transport streams from one sender at a time.
+enum TsListener {
+    Rist(RistListener),
+    Udp(UdpTsListener),
+}
+
+impl TsListener {
+    fn protocol(&self) -> &'static str {
+        match self {
+            TsListener::Rist(_) => "RIST",
+            TsListener::Udp(_) => "UDP",
+        }
+    }
+
+    async fn wait_for_sender(&self) -> anyhow::Result<SocketAddr> {
+        match self {
+            TsListener::Rist(listener) => listener.wait_for_sender().await,
+            TsListener::Udp(listener) => listener.wait_for_sender().await,
+        }
+    }
+
+    async fn receive(&self, output: async_channel::Sender<Bytes>) -> anyhow::Result<()> {
+        match self {
+            TsListener::Rist(listener) => listener.receive(output).await,
+            TsListener::Udp(listener) => listener.receive(output).await,
+        }
+    }
+}
+
+async fn listen(
+    listener: TsListener,
+    addr: SocketAddr,
+    programs: &[TsProgram],
+    data: Arc<AppData>,
+) -> anyhow::Result<()> {
+    let protocol = listener.protocol();
+
+    info!("Listening for {} at {} with {:?}", protocol, addr, programs);
+
+    loop {
+        let sender = listener.wait_for_sender().await?;
+
+        info!("Got a {} session from {}", protocol, sender);
+
+        let mut outputs = Vec::new();
+        let mut ingests = Vec::new();
+        for program in programs {
+            match start_program_ingest(program, &data).await {
+                Ok((tx, ingest)) => {
+                    outputs.push(tx);
+                    ingests.push(ingest);
+                }
+                Err(e) => warn!(
+                    "Failed to authenticate {} stream {:?} at {}: {:?}",
+                    protocol, program.program, addr, e
+                ),
+            }
+        }
+
+        if outputs.is_empty() {
+            // ignore the sender for a while instead of retrying for every
+            // packet
+            let (tx, _) = async_channel::bounded(1);
+            sleep(Duration::from_secs(5)).await;
+            listener.receive(tx).await?;
+            continue;
+        }
+
+        let (tx, rx) = async_channel::bounded(1024);
+        let fan_out = tokio::spawn(fan_out(rx, outputs));
+
+        // the ingests end once the sender goes idle and the channels close
+        listener.receive(tx).await?;
+        let _ = fan_out.await;
+
+        for ingest in ingests {
+            match ingest.await {
+                Ok(Err(e)) => error!("Failed to process {} ingest: {:?}", protocol, e),
+                Err(e) => error!("{} ingest task failed: {:?}", protocol, e),
+                Ok(Ok(())) => {}
+            }
+        }
+    }
+}
+
+async fn start_program_ingest(
+    program: &TsProgram,
+    data: &Arc<AppData>,
+) -> anyhow::Result<(async_channel::Sender<Bytes>, JoinHandle<anyhow::Result<()>>)> {
+    let mut client = data.client.clone();
+    let (id, name) = authenticate_stream(&mut client, &program.stream_key, false).await?;
+
+    let (tx, rx) = async_channel::bounded(1024);
+    let mut filter = TsReadFilter::with_program(rx, program.program);
+    let captions = program.teletext_page.map(|page| filter.captions(page));
+
+    let mut source = IngestSource::new(Box::new(filter));
+    source.captions = captions;
+
+    Ok((tx, tokio::spawn(ingest(id, name, source, data.clone()))))
+}
+
+/// Copies the received TS payloads to the demuxer of every program, until
+/// the input ends or every program has stopped reading.
+async fn fan_out(
+    input: async_channel::Receiver<Bytes>,
+    mut outputs: Vec<async_channel::Sender<Bytes>>,
+) {
+    while let Ok(payload) = input.recv().await {
+        let mut closed = Vec::new();
+        for (i, output) in outputs.iter().enumerate() {
+            if output.send(payload.clone()).await.is_err() {
+                closed.push(i);
+            }
+        }
+
+        for i in closed.into_iter().rev() {
+            outputs.remove(i);
+        }
+
+        if outputs.is_empty() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use serde::Deserialize;
+
+use std::{collections::HashMap, net::SocketAddr, path::Path};
+
+/// The app of a listener which policies apply to when no other app does.
+const ANY_APP: &str = "*";
+
+/// An address RTMP is listened for at, and the apps publishers can use
+/// there, like an unauthenticated port on an internal interface next to a
+/// public one.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RtmpListener {
+    pub addr: SocketAddr,
+    /// The policies by app, the first part of the path of RTMP URLs. Apps
+    /// which aren't listed are refused, unless there is a `*` app.
+    pub apps: HashMap<String, AppPolicy>,
+}
+
+/// What is allowed for streams which are published to an app.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AppPolicy {
+    /// Whether the streams are listed, rather than unlisted.
+    pub listed: bool,
+    /// The stream keys publishers are let in with by the name they publish
+    /// as, so encoders on a trusted network don't need a key of their own.
+    /// Names which aren't in here are refused, and without it publishers
+    /// have to give their stream key.
+    pub keys: Option<HashMap<String, String>>,
+}
+
+impl RtmpListener {
+    /// The listener of a server without a listener file, where the
+    /// `public` app is listed and every other app is unlisted.
+    pub fn with_defaults(addr: SocketAddr) -> Self {
+        let public = AppPolicy {
+            listed: true,
+            ..AppPolicy::default()
+        };
+
+        RtmpListener {
+            addr,
+            apps: HashMap::from([
+                ("public".to_string(), public),
+                (ANY_APP.to_string(), AppPolicy::default()),
+            ]),
+        }
+    }
+
+    pub fn policy(&self, app: &str) -> Option<&AppPolicy> {
+        self.apps.get(app).or_else(|| self.apps.get(ANY_APP))
+    }
+}
+
+impl AppPolicy {
+    /// Returns the stream key a publisher is authenticated with, from the
+    /// key it published with.
+    pub fn stream_key<'a>(&'a self, key: &'a str) -> Option<&'a str> {
+        match &self.keys {
+            Some(keys) => keys.get(key).map(String::as_str),
+            None => Some(key),
+        }
+    }
+}
+
+/// Reads the RTMP listeners from a JSON file.
+pub async fn load_rtmp_listeners(path: &Path) -> anyhow::Result<Vec<RtmpListener>> {
+    let json = tokio::fs::read(path).await?;
+    let listeners: Vec<RtmpListener> = serde_json::from_slice(&json)?;
+
+    if listeners.is_empty() {
+        anyhow::bail!("{} has no RTMP listeners", path.display());
+    }
+
+    Ok(listeners)
+}
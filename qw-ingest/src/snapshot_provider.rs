@@ -1,25 +1,51 @@
+use parking_lot::RwLock;
 use std::{
-    sync::{Arc, RwLock},
+    collections::VecDeque,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
 use sh_media::{Frame, FrameReadFilter, Stream};
 
+/// The number of keyframes kept around for animated previews.
+const PREVIEW_KEYFRAMES: usize = 6;
+
+/// Keyframes sampled from a stream at a fixed interval.
+#[derive(Default)]
+pub struct Snapshots {
+    /// The most recently sampled keyframe.
+    pub latest: Option<Frame>,
+
+    /// The last [`PREVIEW_KEYFRAMES`] sampled keyframes, oldest first.
+    pub recent: VecDeque<Frame>,
+}
+
+impl Snapshots {
+    fn add(&mut self, frame: &Frame) {
+        if self.recent.len() == PREVIEW_KEYFRAMES {
+            self.recent.pop_front();
+        }
+
+        self.recent.push_back(frame.clone());
+        self.latest = Some(frame.clone());
+    }
+}
+
 pub struct SnapshotProviderFilter {
     filter: Box<dyn FrameReadFilter + Send + Unpin>,
     last_report: Option<Instant>,
-    snapshot: Arc<RwLock<Option<Frame>>>,
+    snapshots: Arc<RwLock<Snapshots>>,
 }
 
 impl SnapshotProviderFilter {
     pub fn new(
         filter: Box<dyn FrameReadFilter + Send + Unpin>,
-        snapshot: Arc<RwLock<Option<Frame>>>,
+        snapshots: Arc<RwLock<Snapshots>>,
     ) -> Self {
         SnapshotProviderFilter {
             filter,
             last_report: None,
-            snapshot,
+            snapshots,
         }
     }
 
@@ -31,12 +57,12 @@ impl SnapshotProviderFilter {
                 Some(prev) => {
                     if now - prev > Duration::from_secs(10) {
                         self.last_report = Some(now);
-                        *self.snapshot.write().unwrap() = Some(frame.clone());
+                        self.snapshots.write().add(frame);
                     }
                 }
                 None => {
                     self.last_report = Some(now);
-                    *self.snapshot.write().unwrap() = Some(frame.clone());
+                    self.snapshots.write().add(frame);
                 }
             }
         }
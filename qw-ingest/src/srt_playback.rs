@@ -0,0 +1,228 @@
+use bytes::{Bytes, BytesMut};
+use sh_ingest_srt::{SrtConnection, SrtListener};
+use sh_ingest_ts::{MpegTsMuxer, TS_PACKET_SIZE};
+use sh_media::{
+    wait_for_sync_frame, ByteWriteFilter2, FrameRateFilter, FrameRatePolicy, FrameReadFilter, Muxer,
+};
+use tracing::*;
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use crate::{
+    bandwidth_analyzer::BandwidthAnalyzerFilter,
+    entitlement::{self, PlaybackParams},
+    viewer_auth, AppData, ViewGuard,
+};
+
+/// How many TS packets are sent in every SRT packet, the most which fit in
+/// an Ethernet frame.
+const PACKETS_PER_PAYLOAD: usize = 7;
+
+/// The most payloads queued for a caller before the muxer waits for it.
+pub(crate) const PAYLOAD_QUEUE: usize = 1024;
+
+/// Listens for SRT callers pulling streams as MPEG-TS, e.g. a production
+/// tool opening `srt://host:port?streamid=name`.
+///
+/// The stream ID is the name of the stream, or the access control syntax
+/// `#!::r=name,s=token`, where the session is the token a viewer would
+/// otherwise send as its session cookie.
+///
+/// The timestamps of video are smoothed or duplicated by `frame_rate`.
+pub fn spawn_srt_playback(
+    addr: SocketAddr,
+    latency: Duration,
+    frame_rate: FrameRatePolicy,
+    data: Arc<AppData>,
+) {
+    tokio::spawn(async move {
+        if let Err(e) = listen(addr, latency, frame_rate, data).await {
+            error!("SRT playback listener at {} failed: {:?}", addr, e);
+        }
+    });
+}
+
+async fn listen(
+    addr: SocketAddr,
+    latency: Duration,
+    frame_rate: FrameRatePolicy,
+    data: Arc<AppData>,
+) -> anyhow::Result<()> {
+    let listener = SrtListener::bind(addr, latency).await?;
+
+    info!("Listening for SRT playback at {}", addr);
+
+    loop {
+        let connection = listener.accept().await?;
+
+        let data = data.clone();
+        tokio::spawn(async move {
+            let peer = connection.peer();
+            if let Err(e) = play(connection, frame_rate, data).await {
+                debug!("SRT playback to {} stopped: {:?}", peer, e);
+            }
+        });
+    }
+}
+
+/// What an SRT caller asks to play.
+#[derive(Debug)]
+struct PlaybackRequest<'a> {
+    stream: &'a str,
+    token: Option<&'a str>,
+    /// Whether the caller wants to publish rather than play, which is
+    /// done on the ingest listener.
+    publish: bool,
+}
+
+impl<'a> PlaybackRequest<'a> {
+    fn parse(stream_id: &'a str) -> Self {
+        let fields = match stream_id.strip_prefix("#!::") {
+            Some(fields) => fields,
+            None => {
+                return PlaybackRequest {
+                    stream: stream_id,
+                    token: None,
+                    publish: false,
+                }
+            }
+        };
+
+        let field = |key: &str| {
+            fields
+                .split(',')
+                .find_map(|field| field.strip_prefix(key)?.strip_prefix('='))
+        };
+
+        PlaybackRequest {
+            stream: field("r").unwrap_or_default(),
+            token: field("s"),
+            publish: field("m") == Some("publish"),
+        }
+    }
+}
+
+async fn play(
+    connection: SrtConnection,
+    frame_rate: FrameRatePolicy,
+    data: Arc<AppData>,
+) -> anyhow::Result<()> {
+    let request = PlaybackRequest::parse(connection.stream_id().unwrap_or_default());
+    if request.publish {
+        connection.close().await;
+        anyhow::bail!("Caller wants to publish '{}'", request.stream);
+    }
+
+    let headers = match request.token {
+        Some(token) => viewer_auth::session_headers(&data, token),
+        None => Default::default(),
+    };
+    let params = PlaybackParams::default();
+    let mut session = match entitlement::authorize_playback(
+        &data,
+        request.stream,
+        connection.peer(),
+        &headers,
+        &params,
+    )
+    .await
+    {
+        Ok(session) => session,
+        Err(status) => {
+            connection.close().await;
+            anyhow::bail!("Not allowed to play '{}': {}", request.stream, status);
+        }
+    };
+
+    let (read, guard) = match ViewGuard::attach(request.stream.to_string(), &data, None) {
+        Some(attached) => attached,
+        None => {
+            connection.close().await;
+            anyhow::bail!("No stream named '{}'", request.stream);
+        }
+    };
+
+    info!(
+        "Playing '{}' to an SRT caller at {}",
+        request.stream,
+        connection.peer()
+    );
+
+    let sender = data.stream_stat_sender.clone();
+    let read = FrameRateFilter::new(Box::new(read), frame_rate);
+    let mut read = BandwidthAnalyzerFilter::new(Box::new(read), guard.0, false, sender);
+
+    // the caller stops once the muxer is done and the queue closes, and the
+    // muxer stops once the caller is gone
+    let (tx, rx) = async_channel::bounded(PAYLOAD_QUEUE);
+    let sending = async {
+        let (muxed, sent) = tokio::join!(mux(&mut read, tx), connection.send(rx));
+        if let Err(e) = muxed {
+            debug!("Stopped muxing '{}' for SRT: {:?}", request.stream, e);
+        }
+
+        sent
+    };
+
+    tokio::select! {
+        result = sending => result,
+        _ = entitlement::wait_for_kick(session.as_mut()) => {
+            connection.close().await;
+            anyhow::bail!("session was replaced by a newer one")
+        }
+    }
+}
+
+/// Muxes a stream into the payloads of SRT packets of MPEG-TS, from its
+/// next keyframe until it ends.
+pub(crate) async fn mux(
+    read: &mut (dyn FrameReadFilter + Send + Unpin),
+    output: async_channel::Sender<Bytes>,
+) -> anyhow::Result<()> {
+    let streams = read.start().await?;
+    let first_frame = wait_for_sync_frame(read).await?;
+
+    let mut write = MpegTsMuxer.mux(Box::new(PayloadWriter {
+        output,
+        pending: BytesMut::new(),
+    }));
+    write.start(streams).await?;
+    write.write(first_frame).await?;
+
+    // the queue closes when the stream ends
+    while let Ok(frame) = read.read().await {
+        write.write(frame).await?;
+    }
+
+    Ok(())
+}
+
+/// Splits a transport stream into the payloads of SRT packets.
+struct PayloadWriter {
+    output: async_channel::Sender<Bytes>,
+    /// The packets which don't fill a payload yet.
+    pending: BytesMut,
+}
+
+#[async_trait::async_trait]
+impl ByteWriteFilter2 for PayloadWriter {
+    async fn start(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn write(&mut self, bytes: Bytes) -> anyhow::Result<()> {
+        const PAYLOAD_SIZE: usize = PACKETS_PER_PAYLOAD * TS_PACKET_SIZE;
+
+        self.pending.extend_from_slice(&bytes);
+
+        while self.pending.len() >= PAYLOAD_SIZE {
+            let payload = self.pending.split_to(PAYLOAD_SIZE).freeze();
+            self.output
+                .send(payload)
+                .await
+                .map_err(|_| anyhow::anyhow!("SRT caller is gone"))?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,223 @@
+use axum::{
+    body::{self, boxed, BoxBody},
+    extract::Extension,
+    http::HeaderMap,
+};
+use futures::FutureExt;
+use hyper::{Response, StatusCode};
+use parking_lot::Mutex;
+use serde::Serialize;
+use tracing::*;
+use ts_rs::TS;
+
+use std::{
+    any::Any,
+    collections::VecDeque,
+    fmt,
+    future::Future,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{diagnostics::is_admin, AppData};
+
+/// How many panics are kept to be looked into.
+const RECENT_PANICS: usize = 32;
+
+/// The longest a pull-based source waits before it is started again.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How long a source has to run for the backoff to start over.
+const STABLE_RUN: Duration = Duration::from_secs(30);
+
+/// The error of a task which panicked.
+#[derive(Debug)]
+pub struct Panicked {
+    pub task: &'static str,
+    pub message: String,
+}
+
+impl fmt::Display for Panicked {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} panicked: {}", self.task, self.message)
+    }
+}
+
+impl std::error::Error for Panicked {}
+
+/// Returns whether an error is of a task which panicked, rather than one
+/// which failed.
+pub fn is_panic(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<Panicked>().is_some()
+}
+
+#[derive(Debug, Clone, Serialize, TS)]
+pub struct PanicRecord {
+    pub task: &'static str,
+    pub stream_session_id: Option<i32>,
+    pub message: String,
+    /// Unix timestamp of when the task panicked.
+    #[ts(type = "number")]
+    pub at: u64,
+}
+
+/// Catches the panics of the tasks of pipelines, so a bug in one stream
+/// ends only that stream instead of leaving its state behind, and keeps
+/// count of them.
+///
+/// The ingest uses the locks of `parking_lot`, which aren't poisoned by a
+/// panic, so what a task held when it panicked stays usable by the rest.
+pub struct Supervisor {
+    panics: AtomicU64,
+    restarts: AtomicU64,
+    recent: Mutex<VecDeque<PanicRecord>>,
+    /// Whether pull-based sources, like files and failover channels, are
+    /// started again after they panic.
+    pub restart_sources: bool,
+}
+
+impl Supervisor {
+    pub fn new(restart_sources: bool) -> Self {
+        Supervisor {
+            panics: AtomicU64::new(0),
+            restarts: AtomicU64::new(0),
+            recent: Mutex::new(VecDeque::with_capacity(RECENT_PANICS)),
+            restart_sources,
+        }
+    }
+
+    /// Runs a task of a stream, turning a panic into a [`Panicked`] error.
+    pub async fn run<T>(
+        &self,
+        task: &'static str,
+        stream_session_id: Option<i32>,
+        future: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        match AssertUnwindSafe(future).catch_unwind().await {
+            Ok(result) => result,
+            Err(payload) => {
+                let message = panic_message(payload);
+                error!(
+                    "{} of stream {:?} panicked: {}",
+                    task, stream_session_id, message
+                );
+
+                self.record(PanicRecord {
+                    task,
+                    stream_session_id,
+                    message: message.clone(),
+                    at: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                });
+
+                Err(Panicked { task, message }.into())
+            }
+        }
+    }
+
+    fn record(&self, record: PanicRecord) {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+
+        let mut recent = self.recent.lock();
+        if recent.len() == RECENT_PANICS {
+            recent.pop_front();
+        }
+        recent.push_back(record);
+    }
+
+    /// Decides whether a pull-based source is started again after it ended
+    /// with `result`.
+    pub fn should_restart(&self, result: &anyhow::Result<()>) -> bool {
+        let restart = match result {
+            Err(e) if is_panic(e) => self.restart_sources,
+            _ => true,
+        };
+
+        if restart {
+            self.restarts.fetch_add(1, Ordering::Relaxed);
+        }
+
+        restart
+    }
+}
+
+/// How long a pull-based source waits before it is started again, which
+/// doubles while it keeps failing soon after starting.
+pub struct Backoff {
+    initial: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration) -> Self {
+        Backoff {
+            initial,
+            current: initial,
+        }
+    }
+
+    /// Returns how long to wait after a run which lasted `ran_for`.
+    pub fn next(&mut self, ran_for: Duration) -> Duration {
+        if ran_for >= STABLE_RUN {
+            self.current = self.initial;
+        }
+
+        let wait = self.current;
+        self.current = (self.current * 2).min(MAX_BACKOFF);
+
+        wait
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[derive(Serialize, TS)]
+pub(crate) struct SupervisorStatus {
+    #[ts(type = "number")]
+    panics: u64,
+    #[ts(type = "number")]
+    restarts: u64,
+    recent: Vec<PanicRecord>,
+}
+
+/// Reports how many tasks panicked and were restarted, and the latest
+/// panics, as JSON.
+pub async fn status(
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> Response<BoxBody> {
+    if !is_admin(&data, &headers) {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(boxed(body::Full::from("Missing or invalid admin token")))
+            .unwrap();
+    }
+
+    let supervisor = &data.supervisor;
+    let status = SupervisorStatus {
+        panics: supervisor.panics.load(Ordering::Relaxed),
+        restarts: supervisor.restarts.load(Ordering::Relaxed),
+        recent: supervisor.recent.lock().iter().cloned().collect(),
+    };
+
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(boxed(body::Full::from(
+            serde_json::to_vec(&status).unwrap(),
+        )))
+        .unwrap()
+}
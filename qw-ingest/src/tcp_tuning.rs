@@ -0,0 +1,80 @@
+use tokio::net::TcpStream;
+
+use std::{io, os::unix::io::AsRawFd};
+
+/// Socket options of the TCP connections viewers are sent streams over.
+///
+/// How much the kernel buffers decides how far behind live a viewer falls
+/// when their connection can't keep up, before frames are held back in the
+/// delivery queue where they can be dropped instead.
+#[derive(Debug, Clone, Default)]
+pub struct TcpTuning {
+    /// The size of the send buffer in bytes, or the kernel's default.
+    pub send_buffer: Option<u32>,
+    /// The size of the receive buffer in bytes, or the kernel's default.
+    pub recv_buffer: Option<u32>,
+    /// How many unsent bytes the connection is writable below. A low mark
+    /// keeps frames out of the kernel until they can be sent soon.
+    pub notsent_lowat: Option<u32>,
+    /// The congestion control algorithm, like `bbr`, which has to be
+    /// loaded by the kernel.
+    pub congestion: Option<String>,
+}
+
+impl TcpTuning {
+    /// Sets the options on a newly accepted connection.
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        let fd = stream.as_raw_fd();
+
+        if let Some(size) = self.send_buffer {
+            set_int(fd, libc::SOL_SOCKET, libc::SO_SNDBUF, size)?;
+        }
+        if let Some(size) = self.recv_buffer {
+            set_int(fd, libc::SOL_SOCKET, libc::SO_RCVBUF, size)?;
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(lowat) = self.notsent_lowat {
+                set_int(fd, libc::IPPROTO_TCP, libc::TCP_NOTSENT_LOWAT, lowat)?;
+            }
+            if let Some(congestion) = &self.congestion {
+                set_bytes(
+                    fd,
+                    libc::IPPROTO_TCP,
+                    libc::TCP_CONGESTION,
+                    congestion.as_bytes(),
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn set_int(fd: libc::c_int, level: libc::c_int, name: libc::c_int, value: u32) -> io::Result<()> {
+    set_bytes(fd, level, name, &(value as libc::c_int).to_ne_bytes())
+}
+
+fn set_bytes(
+    fd: libc::c_int,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: &[u8],
+) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            value.as_ptr() as *const libc::c_void,
+            value.len() as libc::socklen_t,
+        )
+    };
+
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
@@ -0,0 +1,200 @@
+use axum::{
+    body,
+    extract::{ConnectInfo, Extension, Path, Query},
+    http::HeaderMap,
+    response::IntoResponse,
+};
+use hyper::{Response, StatusCode};
+use sh_media::{Frame, MediaFrameQueue};
+
+use std::{fmt::Write, net::SocketAddr, sync::Arc};
+
+use crate::{
+    entitlement::{self, PlaybackParams},
+    AppData,
+};
+
+fn find_queue(data: &AppData, stream: &str) -> Option<MediaFrameQueue> {
+    let repo = data.stream_repo.read();
+    let stream_id = repo.stream_mapping.get(stream)?;
+
+    repo.streams.get(stream_id).map(|s| s.queue.clone())
+}
+
+/// The length of a keyframe in the timebase of its stream, which is until
+/// the next keyframe.
+fn keyframe_duration(keyframe: &Frame, next: &Frame) -> u64 {
+    next.time.pts.saturating_sub(keyframe.time.pts)
+}
+
+fn seconds(frame: &Frame, ticks: u64) -> f64 {
+    let timebase = frame.stream.timebase;
+
+    ticks as f64 * timebase.numerator as f64 / timebase.denominator as f64
+}
+
+fn not_found(message: &'static str) -> Response<body::Full<bytes::Bytes>> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(body::Full::from(message))
+        .unwrap()
+}
+
+fn rejected(status: StatusCode) -> Response<body::Full<bytes::Bytes>> {
+    Response::builder()
+        .status(status)
+        .body(body::Full::from(""))
+        .unwrap()
+}
+
+/// Returns a live HLS I-frame playlist of the keyframes in the DVR window
+/// of a stream, so players can show thumbnails while scrubbing behind live.
+///
+/// The latest keyframe is left out until the next one arrives, since its
+/// duration isn't known before then.
+pub async fn dvr_iframe_playlist(
+    Path(stream): Path<String>,
+    Query(params): Query<PlaybackParams>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    if let Err(status) =
+        entitlement::authorize_request(&data, &stream, client, &headers, &params).await
+    {
+        return rejected(status);
+    }
+
+    let queue = match find_queue(&data, &stream) {
+        Some(queue) => queue,
+        None => return not_found("No stream with that name"),
+    };
+
+    let (sequence, keyframes) = queue.dvr_keyframes();
+    if keyframes.len() < 2 {
+        return not_found("No keyframes in the DVR window");
+    }
+
+    let durations = keyframes
+        .windows(2)
+        .map(|pair| seconds(&pair[0], keyframe_duration(&pair[0], &pair[1])))
+        .collect::<Vec<_>>();
+    let target_duration = durations.iter().map(|d| d.ceil() as u64).max().unwrap_or(0);
+
+    let mut playlist = String::new();
+
+    // writing to a String can't fail
+    let _ = writeln!(playlist, "#EXTM3U");
+    let _ = writeln!(playlist, "#EXT-X-VERSION:7");
+    let _ = writeln!(playlist, "#EXT-X-TARGETDURATION:{}", target_duration);
+    let _ = writeln!(playlist, "#EXT-X-MEDIA-SEQUENCE:{}", sequence);
+    let _ = writeln!(playlist, "#EXT-X-I-FRAMES-ONLY");
+    let _ = writeln!(playlist, "#EXT-X-MAP:URI=\"iframe-init.mp4\"");
+
+    for (i, duration) in durations.iter().enumerate() {
+        let _ = writeln!(playlist, "#EXTINF:{:.3},", duration);
+        let _ = writeln!(playlist, "iframes/{}", sequence + i as u64);
+    }
+
+    Response::builder()
+        .header("Content-Type", "application/vnd.apple.mpegurl")
+        .header("Access-Control-Allow-Origin", "*")
+        .status(StatusCode::OK)
+        .body(body::Full::from(playlist))
+        .unwrap()
+}
+
+/// Returns the initialization of the keyframes in the I-frame playlist of
+/// a stream.
+pub async fn dvr_iframe_init(
+    Path(stream): Path<String>,
+    Query(params): Query<PlaybackParams>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    if let Err(status) =
+        entitlement::authorize_request(&data, &stream, client, &headers, &params).await
+    {
+        return rejected(status);
+    }
+
+    let keyframe = find_queue(&data, &stream).and_then(|q| q.dvr_keyframes().1.pop());
+    let keyframe = match keyframe {
+        Some(keyframe) => keyframe,
+        None => return not_found("No keyframes in the DVR window"),
+    };
+
+    match sh_fmp4::video_init_fmp4(&keyframe.stream) {
+        Ok(bytes) => Response::builder()
+            .header("Content-Type", "video/mp4")
+            .header("Access-Control-Allow-Origin", "*")
+            .status(StatusCode::OK)
+            .body(body::Full::from(bytes))
+            .unwrap(),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Full::from(format!(
+                "Failed to write initialization: {:?}",
+                e
+            )))
+            .unwrap(),
+    }
+}
+
+/// Returns a keyframe of the I-frame playlist of a stream as a fragment,
+/// by its media sequence number.
+pub async fn dvr_iframe(
+    Path((stream, sequence)): Path<(String, u64)>,
+    Query(params): Query<PlaybackParams>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    if let Err(status) =
+        entitlement::authorize_request(&data, &stream, client, &headers, &params).await
+    {
+        return rejected(status);
+    }
+
+    let queue = match find_queue(&data, &stream) {
+        Some(queue) => queue,
+        None => return not_found("No stream with that name"),
+    };
+
+    let (first, keyframes) = queue.dvr_keyframes();
+    let index = match sequence.checked_sub(first) {
+        Some(index) => index,
+        None => return not_found("Keyframe has left the DVR window"),
+    };
+
+    // the sequence number comes from the viewer, so it may be anything
+    let pair = usize::try_from(index)
+        .ok()
+        .and_then(|index| Some((keyframes.get(index)?, keyframes.get(index.checked_add(1)?)?)));
+    let (keyframe, next) = match pair {
+        Some(pair) => pair,
+        None => return not_found("No such keyframe"),
+    };
+    let fragment_sequence = match u32::try_from(sequence) {
+        Ok(sequence) => sequence,
+        Err(_) => return not_found("No such keyframe"),
+    };
+
+    let duration = u32::try_from(keyframe_duration(keyframe, next)).unwrap_or(u32::MAX);
+    match sh_fmp4::keyframe_fragment(keyframe, fragment_sequence, keyframe.time.pts, duration) {
+        Ok(bytes) => Response::builder()
+            .header("Content-Type", "video/mp4")
+            .header("Access-Control-Allow-Origin", "*")
+            .status(StatusCode::OK)
+            .body(body::Full::from(bytes))
+            .unwrap(),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(body::Full::from(format!(
+                "Failed to write keyframe: {:?}",
+                e
+            )))
+            .unwrap(),
+    }
+}
@@ -0,0 +1,130 @@
+//! Generates the TypeScript definitions of the payloads of the API, the
+//! event feed and the MSE protocol into the `bindings` package, so frontends
+//! don't have to keep their own in sync by hand.
+//!
+//! The definitions are written to `bindings/index.d.ts` by `qwer-ingest
+//! export-typescript <path>`, which the build runs before packing the
+//! package.
+
+use sh_protocol::{OfferMessage, ReconnectHints, RenditionOffer, ResumeMessage};
+use ts_rs::TS;
+
+use std::collections::HashSet;
+
+use crate::{
+    anomaly::{Anomaly, Metric},
+    archive::{ArchivedFile, FileVerification, Manifest},
+    canary::CanaryResult,
+    compose::PipLayout,
+    congestion::CongestionCause,
+    delivery::{JoinPercentiles, JoinStage, ViewerInfo},
+    diagnostics::FrameSummary,
+    events::{
+        AnomalyEvent, AudioLevelsEvent, CongestionEvent, DiskFullEvent, RecordingFailedEvent,
+        StreamPhaseEvent, StreamStartedEvent, StreamStoppedEvent, ViewerEvent,
+    },
+    expected::WaitingPage,
+    frame_stats::TrackStats,
+    hls::CastInfo,
+    jobs::{Job, JobKind, JobState},
+    loudness::Loudness,
+    moderation::{ModerationAction, ModerationFlag, ModerationStatus},
+    packaging::FileFormat,
+    recommendations::Recommendation,
+    relay::{RelayInfo, RelayStatus, RelayTarget},
+    supervisor::{PanicRecord, SupervisorStatus},
+    vod::AssetInfo,
+    PhaseTransition, StreamPhase, StreamState, StreamSummary,
+};
+
+/// Types which ts-rs can't describe, because they flatten an `Option`.
+const HANDWRITTEN: &str = "\
+export type StreamHealth = Partial<CanaryResult> & { anomalies: Array<Anomaly>, };
+";
+
+/// Returns the contents of `bindings/index.d.ts`.
+pub fn definitions() -> String {
+    let decls = [
+        // the API
+        StreamPhase::decl(),
+        StreamSummary::decl(),
+        PhaseTransition::decl(),
+        StreamState::decl(),
+        ViewerInfo::decl(),
+        JoinStage::decl(),
+        JoinPercentiles::decl(),
+        PanicRecord::decl(),
+        SupervisorStatus::decl(),
+        Manifest::decl(),
+        ArchivedFile::decl(),
+        FileVerification::decl(),
+        CastInfo::decl(),
+        PipLayout::decl(),
+        FileFormat::decl(),
+        AssetInfo::decl(),
+        JobKind::decl(),
+        JobState::decl(),
+        Job::decl(),
+        Recommendation::decl(),
+        RelayTarget::decl(),
+        RelayStatus::decl(),
+        RelayInfo::decl(),
+        WaitingPage::decl(),
+        ModerationAction::decl(),
+        ModerationFlag::decl(),
+        ModerationStatus::decl(),
+        Loudness::decl(),
+        Metric::decl(),
+        Anomaly::decl(),
+        CanaryResult::decl(),
+        FrameSummary::decl(),
+        TrackStats::decl(),
+        // the event feed
+        StreamStartedEvent::decl(),
+        StreamStoppedEvent::decl(),
+        ViewerEvent::decl(),
+        AnomalyEvent::decl(),
+        CongestionCause::decl(),
+        CongestionEvent::decl(),
+        StreamPhaseEvent::decl(),
+        AudioLevelsEvent::decl(),
+        RecordingFailedEvent::decl(),
+        DiskFullEvent::decl(),
+        // the MSE protocol
+        RenditionOffer::decl(),
+        OfferMessage::decl(),
+        ReconnectHints::decl(),
+        ResumeMessage::decl(),
+    ];
+
+    let mut out = String::from("// Generated by `qwer-ingest export-typescript`.\n");
+    for decl in decls {
+        out.push_str("\nexport ");
+        out.push_str(&decl);
+        out.push('\n');
+    }
+    out.push('\n');
+    out.push_str(HANDWRITTEN);
+
+    out
+}
+
+#[test]
+fn definitions_are_declared_once() {
+    let definitions = definitions();
+    let mut names = HashSet::new();
+
+    for line in definitions.lines() {
+        let name = match line
+            .strip_prefix("export type ")
+            .or_else(|| line.strip_prefix("export interface "))
+        {
+            Some(rest) => rest.split(|c: char| !c.is_alphanumeric()).next().unwrap(),
+            None => continue,
+        };
+
+        assert!(names.insert(name), "{} is declared twice", name);
+    }
+
+    assert!(names.contains("StreamHealth"));
+}
@@ -0,0 +1,231 @@
+use anyhow::Context;
+use axum::http::{header::COOKIE, HeaderMap};
+use hyper::{body, client::HttpConnector, Client, Uri};
+use hyper_rustls::HttpsConnector;
+use jsonwebtoken::{
+    jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use parking_lot::RwLock;
+use serde::{de::DeserializeOwned, Deserialize};
+use tracing::*;
+
+use std::time::{Duration, Instant};
+
+use crate::AppData;
+
+/// How long the signing keys of the issuer are cached.
+const KEY_CACHE_DURATION: Duration = Duration::from_secs(3600);
+
+/// How often the keys may be fetched again because a token was signed with
+/// an unknown key, e.g. after the issuer rotated its keys.
+const KEY_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Where to find and how to validate the OIDC session of a viewer.
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    /// The issuer URL, which the discovery document is fetched from.
+    pub issuer: String,
+    pub audience: String,
+    /// The name of the cookie holding the ID token.
+    pub cookie: String,
+}
+
+/// The claims of a validated viewer session.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ViewerClaims {
+    /// The user the session belongs to.
+    pub sub: String,
+}
+
+#[derive(Deserialize)]
+struct DiscoveryDocument {
+    jwks_uri: String,
+}
+
+/// Validates OIDC ID tokens passed as session cookies, so only users who
+/// are logged in with the issuer can watch streams.
+pub struct OidcVerifier {
+    config: OidcConfig,
+    client: Client<HttpsConnector<HttpConnector>>,
+    keys: RwLock<Option<(Instant, JwkSet)>>,
+}
+
+impl OidcVerifier {
+    pub fn new(config: OidcConfig) -> Self {
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+
+        OidcVerifier {
+            config,
+            client: Client::builder().build(connector),
+            keys: RwLock::new(None),
+        }
+    }
+
+    /// Validates the session cookie in `headers` and returns its claims.
+    pub async fn verify(&self, headers: &HeaderMap) -> anyhow::Result<ViewerClaims> {
+        let token = session_cookie(headers, &self.config.cookie)
+            .context("request has no session cookie")?;
+
+        let header = jsonwebtoken::decode_header(token)?;
+        let kid = header.kid.context("session token has no key id")?;
+
+        let (key, algorithm) = match self.cached_key(&kid, false) {
+            Some(key) => key,
+            None => {
+                self.refresh_keys().await?;
+                self.cached_key(&kid, true)
+                    .with_context(|| format!("issuer has no key with id {}", kid))?
+            }
+        };
+
+        // the algorithm comes from the key, as a token could otherwise pick
+        // one the key was never meant for
+        let mut validation = Validation::new(algorithm);
+        validation.set_issuer(&[&self.config.issuer]);
+        validation.set_audience(&[&self.config.audience]);
+
+        let data = jsonwebtoken::decode::<ViewerClaims>(token, &key, &validation)?;
+
+        Ok(data.claims)
+    }
+
+    /// Returns the key with the given id and the algorithm it signs with,
+    /// unless the cached keys have to be fetched again. Stale keys are still
+    /// used when `allow_stale` is set.
+    fn cached_key(&self, kid: &str, allow_stale: bool) -> Option<(DecodingKey, Algorithm)> {
+        let keys = self.keys.read();
+        let (fetched, keys) = keys.as_ref()?;
+
+        if !allow_stale && fetched.elapsed() > KEY_CACHE_DURATION {
+            return None;
+        }
+
+        let jwk = keys.find(kid)?;
+
+        Some((DecodingKey::from_jwk(jwk).ok()?, key_algorithm(jwk)?))
+    }
+
+    async fn refresh_keys(&self) -> anyhow::Result<()> {
+        let recently_fetched = {
+            let keys = self.keys.read();
+            keys.as_ref()
+                .is_some_and(|(fetched, _)| fetched.elapsed() < KEY_REFRESH_INTERVAL)
+        };
+        if recently_fetched {
+            return Ok(());
+        }
+
+        let discovery_url = format!(
+            "{}/.well-known/openid-configuration",
+            self.config.issuer.trim_end_matches('/')
+        );
+        let discovery: DiscoveryDocument = self.get_json(&discovery_url).await?;
+        let keys: JwkSet = self.get_json(&discovery.jwks_uri).await?;
+
+        debug!(
+            "Fetched {} keys from {}",
+            keys.keys.len(),
+            discovery.jwks_uri
+        );
+
+        *self.keys.write() = Some((Instant::now(), keys));
+
+        Ok(())
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, url: &str) -> anyhow::Result<T> {
+        let response = self.client.get(url.parse::<Uri>()?).await?;
+
+        if !response.status().is_success() {
+            anyhow::bail!("{} responded with {}", url, response.status());
+        }
+
+        let bytes = body::to_bytes(response.into_body()).await?;
+
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+}
+
+/// Returns the algorithm of a signing key of the issuer, which is its `alg`
+/// or else the usual algorithm of its key type.
+fn key_algorithm(jwk: &Jwk) -> Option<Algorithm> {
+    if let Some(algorithm) = jwk.common.algorithm {
+        return Some(algorithm);
+    }
+
+    match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => Some(Algorithm::RS256),
+        AlgorithmParameters::EllipticCurve(params) => match params.curve {
+            EllipticCurve::P256 => Some(Algorithm::ES256),
+            EllipticCurve::P384 => Some(Algorithm::ES384),
+            // P-521 can't be verified
+            _ => None,
+        },
+        AlgorithmParameters::OctetKeyPair(_) => Some(Algorithm::EdDSA),
+        // a shared secret has no place in the public keys of an issuer
+        AlgorithmParameters::OctetKey(_) => None,
+    }
+}
+
+/// Builds the headers of a playback request with the session `token`, for
+/// protocols which can't send cookies, like SRT.
+pub fn session_headers(data: &AppData, token: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+
+    if let Some(verifier) = &data.viewer_auth {
+        let cookie = format!("{}={}", verifier.config.cookie, token);
+        if let Ok(cookie) = cookie.parse() {
+            headers.insert(COOKIE, cookie);
+        }
+    }
+
+    headers
+}
+
+fn session_cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .filter_map(|c| c.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Returns the viewer making a playback request, or an error if viewer
+/// authentication is enabled and the request has no valid session.
+pub async fn authorize_viewer(
+    data: &AppData,
+    headers: &HeaderMap,
+) -> anyhow::Result<Option<ViewerClaims>> {
+    match &data.viewer_auth {
+        Some(verifier) => verifier.verify(headers).await.map(Some),
+        None => Ok(None),
+    }
+}
+
+#[test]
+fn algorithm_comes_from_the_key() {
+    let jwk = |json: &str| serde_json::from_str::<Jwk>(json).unwrap();
+
+    let rsa = r#"{"kty": "RSA", "kid": "a", "n": "AQAB", "e": "AQAB"}"#;
+    assert_eq!(key_algorithm(&jwk(rsa)), Some(Algorithm::RS256));
+
+    let rsa_pss = r#"{"kty": "RSA", "alg": "PS384", "n": "AQAB", "e": "AQAB"}"#;
+    assert_eq!(key_algorithm(&jwk(rsa_pss)), Some(Algorithm::PS384));
+
+    let ec = r#"{"kty": "EC", "crv": "P-384", "x": "AA", "y": "AA"}"#;
+    assert_eq!(key_algorithm(&jwk(ec)), Some(Algorithm::ES384));
+
+    let okp = r#"{"kty": "OKP", "crv": "Ed25519", "x": "AA"}"#;
+    assert_eq!(key_algorithm(&jwk(okp)), Some(Algorithm::EdDSA));
+
+    let secret = r#"{"kty": "oct", "k": "c2VjcmV0"}"#;
+    assert_eq!(key_algorithm(&jwk(secret)), None);
+}
@@ -0,0 +1,291 @@
+use axum::{
+    body,
+    extract::{ConnectInfo, Extension, Path, Query},
+    http::HeaderMap,
+    response::IntoResponse,
+};
+use hyper::{Response, StatusCode};
+use parking_lot::RwLock;
+use serde::Serialize;
+use tokio::time::sleep;
+use tracing::*;
+use ts_rs::TS;
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path as FsPath, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    entitlement::{self, PlaybackParams},
+    packaging::{self, FileFormat},
+    AppData,
+};
+
+/// How often the VOD directory is scanned for new files.
+const SCAN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a file has to be left unmodified before it is considered
+/// complete.
+const SETTLE_TIME: Duration = Duration::from_secs(10);
+
+/// A completed file in the VOD directory.
+pub struct VodAsset {
+    pub path: PathBuf,
+    pub kind: FileFormat,
+    size: u64,
+    modified: SystemTime,
+}
+
+/// The files which are served as VOD, by asset name.
+#[derive(Default)]
+pub struct VodLibrary {
+    assets: HashMap<String, VodAsset>,
+}
+
+#[derive(Serialize, TS)]
+pub(crate) struct AssetInfo<'a> {
+    name: &'a str,
+    kind: FileFormat,
+    #[ts(type = "number")]
+    size: u64,
+}
+
+/// Watches `dir` for completed MP4 and MPEG-TS files and adds them to the
+/// library, removing them again once they are deleted.
+pub fn spawn_vod_watcher(dir: PathBuf, library: Arc<RwLock<VodLibrary>>) {
+    tokio::spawn(async move {
+        info!("Serving VOD assets from {}", dir.display());
+
+        loop {
+            let scan_dir = dir.clone();
+            match tokio::task::spawn_blocking(move || scan(&scan_dir)).await {
+                Ok(Ok(files)) => update_library(&library, files),
+                Ok(Err(e)) => warn!("Failed to scan VOD directory: {:?}", e),
+                Err(e) => warn!("Failed to scan VOD directory: {:?}", e),
+            }
+
+            sleep(SCAN_INTERVAL).await;
+        }
+    });
+}
+
+/// Finds the completed files in `dir` and its subdirectories, named after
+/// their path relative to `dir`.
+fn scan(dir: &FsPath) -> anyhow::Result<HashMap<String, VodAsset>> {
+    let mut assets = HashMap::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    let now = SystemTime::now();
+
+    while let Some(current) = dirs.pop() {
+        for entry in std::fs::read_dir(&current)? {
+            let entry = entry?;
+            let path = entry.path();
+            let metadata = entry.metadata()?;
+
+            if metadata.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+
+            let kind = match FileFormat::from_path(&path) {
+                Some(kind) => kind,
+                None => continue,
+            };
+
+            let modified = metadata.modified()?;
+            let settled = now
+                .duration_since(modified)
+                .is_ok_and(|age| age >= SETTLE_TIME);
+            if !settled {
+                continue;
+            }
+
+            let name = match asset_name(dir, &path) {
+                Some(name) => name,
+                None => continue,
+            };
+
+            assets.entry(name).or_insert(VodAsset {
+                path,
+                kind,
+                size: metadata.len(),
+                modified,
+            });
+        }
+    }
+
+    Ok(assets)
+}
+
+/// Names an asset after its path without the extension, e.g. `show/ep-1`
+/// for `show/ep-1.mp4`, with directories separated by `-`.
+fn asset_name(dir: &FsPath, path: &FsPath) -> Option<String> {
+    let relative = path.strip_prefix(dir).ok()?.with_extension("");
+
+    let name = relative
+        .components()
+        .map(|c| c.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()?
+        .join("-");
+
+    is_valid_name(&name).then(|| name)
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+fn update_library(library: &RwLock<VodLibrary>, files: HashMap<String, VodAsset>) {
+    let mut library = library.write();
+
+    library.assets.retain(|name, asset| {
+        let kept = files
+            .get(name)
+            .is_some_and(|file| file.size == asset.size && file.modified == asset.modified);
+
+        if !kept {
+            info!("Removing VOD asset {}", name);
+        }
+
+        kept
+    });
+
+    for (name, file) in files {
+        if !library.assets.contains_key(&name) {
+            info!("Adding VOD asset {} from {}", name, file.path.display());
+            library.assets.insert(name, file);
+        }
+    }
+}
+
+fn asset_file(data: &AppData, name: &str) -> Option<(PathBuf, FileFormat)> {
+    let library = data.vod.read();
+    let asset = library.assets.get(name)?;
+
+    Some((asset.path.clone(), asset.kind))
+}
+
+/// Finds the file of an asset for a viewer who may play it, checked like
+/// viewers of a live stream with the asset name as the stream, or returns
+/// the response to reject the request with.
+async fn authorized_asset(
+    data: &AppData,
+    name: &str,
+    client: SocketAddr,
+    headers: &HeaderMap,
+    params: &PlaybackParams,
+) -> Result<(PathBuf, FileFormat), Response<body::Full<bytes::Bytes>>> {
+    let file = asset_file(data, name).ok_or_else(|| {
+        Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(body::Full::from("No asset with that name"))
+            .unwrap()
+    })?;
+
+    entitlement::authorize_request(data, name, client, headers, params)
+        .await
+        .map_err(|status| {
+            Response::builder()
+                .status(status)
+                .body(body::Full::from("Not allowed to play the asset"))
+                .unwrap()
+        })?;
+
+    Ok(file)
+}
+
+/// Lists the VOD assets as JSON.
+pub async fn list_assets(Extension(data): Extension<Arc<AppData>>) -> impl IntoResponse {
+    let json = {
+        let library = data.vod.read();
+
+        let mut assets = library
+            .assets
+            .iter()
+            .map(|(name, asset)| AssetInfo {
+                name,
+                kind: asset.kind,
+                size: asset.size,
+            })
+            .collect::<Vec<_>>();
+        assets.sort_by_key(|a| a.name);
+
+        serde_json::to_string(&assets).unwrap()
+    };
+
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .header("Access-Control-Allow-Origin", "*")
+        .status(StatusCode::OK)
+        .body(body::Full::from(json))
+        .unwrap()
+}
+
+/// Returns an HLS playlist of a VOD asset, with segments which are byte
+/// ranges of the original file.
+pub async fn asset_playlist(
+    Path(asset): Path<String>,
+    Query(params): Query<PlaybackParams>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    let (path, kind) = match authorized_asset(&data, &asset, client, &headers, &params).await {
+        Ok(file) => file,
+        Err(response) => return response,
+    };
+
+    match data.packaging_cache.segments(&path, kind).await {
+        Ok(segments) => Response::builder()
+            .header("Content-Type", "application/vnd.apple.mpegurl")
+            .header("Access-Control-Allow-Origin", "*")
+            .status(StatusCode::OK)
+            .body(body::Full::from(packaging::hls_playlist(
+                &[(String::from("media"), segments)],
+                true,
+            )))
+            .unwrap(),
+        Err(e) => {
+            warn!("Failed to package VOD asset {}: {:?}", asset, e);
+
+            Response::builder()
+                .status(StatusCode::UNPROCESSABLE_ENTITY)
+                .body(body::Full::from(format!("Failed to package asset: {}", e)))
+                .unwrap()
+        }
+    }
+}
+
+/// Serves the file of a VOD asset, usually in the byte ranges of its
+/// playlist.
+pub async fn asset_media(
+    Path(asset): Path<String>,
+    Query(params): Query<PlaybackParams>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> impl IntoResponse {
+    let (path, kind) = match authorized_asset(&data, &asset, client, &headers, &params).await {
+        Ok(file) => file,
+        Err(response) => return response,
+    };
+
+    match packaging::serve_file(&path, kind.content_type(), &headers).await {
+        Ok(response) => response,
+        Err(e) => {
+            warn!("Failed to serve VOD asset {}: {:?}", asset, e);
+
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(body::Full::from("Failed to read asset"))
+                .unwrap()
+        }
+    }
+}
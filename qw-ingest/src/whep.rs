@@ -0,0 +1,180 @@
+use axum::{
+    body::{self, boxed, BoxBody},
+    extract::{ConnectInfo, Extension, Path, Query},
+    http::{header::CONTENT_TYPE, HeaderMap},
+};
+use bytes::Bytes;
+use hyper::{Response, StatusCode};
+use parking_lot::Mutex;
+use sh_transport_whep::WhepSession;
+use tracing::*;
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    bandwidth_analyzer::BandwidthAnalyzerFilter,
+    entitlement::{self, PlaybackParams},
+    viewer_auth, AppData, ViewGuard,
+};
+
+/// The WebRTC sessions of viewers playing streams over WHEP, by the ID in
+/// their resource URL.
+#[derive(Default)]
+pub struct WhepSessions {
+    sessions: Mutex<HashMap<String, Arc<WhepSession>>>,
+    next_id: AtomicU64,
+}
+
+/// Starts playing a stream to a WHEP client from its SDP offer, for
+/// sub-second latency where the MSE websocket would buffer.
+///
+/// Viewers are authorized like other playback requests. The answer points
+/// at the session's resource, which stops playback when deleted.
+pub async fn play(
+    Path(stream): Path<String>,
+    Query(params): Query<PlaybackParams>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+    offer: Bytes,
+) -> Response<BoxBody> {
+    let is_sdp = headers
+        .get(CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| h.starts_with("application/sdp"));
+    if !is_sdp {
+        return error(StatusCode::UNSUPPORTED_MEDIA_TYPE, "Expected an SDP offer");
+    }
+
+    let offer = match String::from_utf8(offer.to_vec()) {
+        Ok(offer) => offer,
+        Err(_) => return error(StatusCode::BAD_REQUEST, "Invalid SDP offer"),
+    };
+
+    let mut playback =
+        match entitlement::authorize_playback(&data, &stream, client, &headers, &params).await {
+            Ok(session) => session,
+            Err(status) => return error(status, "Not allowed to play this stream"),
+        };
+
+    let streams = {
+        let repo = data.stream_repo.read();
+
+        repo.stream_mapping
+            .get(&stream)
+            .and_then(|id| repo.streams.get(id))
+            .map(|state| state.queue.get_streams())
+    };
+    let streams = match streams {
+        Some(streams) => streams,
+        None => return error(StatusCode::NOT_FOUND, "No stream with that name"),
+    };
+
+    let (session, answer) = match WhepSession::accept(offer, &streams).await {
+        Ok(session) => session,
+        Err(e) => {
+            warn!("Failed to set up WHEP session: {:?}", e);
+            return error(StatusCode::BAD_REQUEST, "Failed to negotiate a session");
+        }
+    };
+    let session = Arc::new(session);
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let session_id = format!(
+        "{}-{}",
+        timestamp,
+        data.whep_sessions.next_id.fetch_add(1, Ordering::Relaxed)
+    );
+    data.whep_sessions
+        .sessions
+        .lock()
+        .insert(session_id.clone(), session.clone());
+
+    {
+        let stream = stream.clone();
+        let session_id = session_id.clone();
+        let data = data.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                result = send(&stream, &session, &data) => {
+                    if let Err(e) = result {
+                        debug!("WHEP viewer of '{}' stopped: {:?}", stream, e);
+                    }
+                }
+                _ = entitlement::wait_for_kick(playback.as_mut()) => {
+                    info!("Stopped a WHEP viewer of '{}' for a newer session", stream);
+                }
+            }
+
+            session.close().await;
+            data.whep_sessions.sessions.lock().remove(&session_id);
+        });
+    }
+
+    Response::builder()
+        .header(CONTENT_TYPE, "application/sdp")
+        .header("Location", format!("/whep/{}/{}", stream, session_id))
+        .status(StatusCode::CREATED)
+        .body(boxed(body::Full::from(answer)))
+        .unwrap()
+}
+
+/// Sends a stream to a viewer once it has connected, counting it as a
+/// viewer while it watches.
+async fn send(stream: &str, session: &WhepSession, data: &Arc<AppData>) -> anyhow::Result<()> {
+    session.connected().await?;
+
+    let (read, guard) = ViewGuard::attach(stream.to_string(), data, None)
+        .ok_or_else(|| anyhow::anyhow!("Stream ended before the viewer connected"))?;
+
+    info!("Playing '{}' to a WHEP viewer", stream);
+
+    let sender = data.stream_stat_sender.clone();
+    let mut read = BandwidthAnalyzerFilter::new(Box::new(read), guard.0, false, sender);
+
+    session.send(&mut read).await
+}
+
+/// Ends a WHEP session, which is how WHEP clients stop playing.
+pub async fn stop(
+    Path((stream, session_id)): Path<(String, String)>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> Response<BoxBody> {
+    if let Err(e) = viewer_auth::authorize_viewer(&data, &headers).await {
+        debug!("Rejected WHEP viewer of '{}': {:?}", stream, e);
+        return error(StatusCode::UNAUTHORIZED, "Not logged in");
+    }
+
+    let session = data.whep_sessions.sessions.lock().remove(&session_id);
+
+    match session {
+        Some(session) => {
+            session.close().await;
+
+            Response::builder()
+                .status(StatusCode::OK)
+                .body(boxed(body::Empty::new()))
+                .unwrap()
+        }
+        None => error(StatusCode::NOT_FOUND, "No such WHEP session"),
+    }
+}
+
+fn error(status: StatusCode, message: &'static str) -> Response<BoxBody> {
+    Response::builder()
+        .status(status)
+        .body(boxed(body::Full::from(message)))
+        .unwrap()
+}
@@ -0,0 +1,164 @@
+use axum::{
+    body::{self, boxed, BoxBody},
+    extract::{Extension, Path},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        HeaderMap,
+    },
+};
+use bytes::Bytes;
+use hyper::{Response, StatusCode};
+use parking_lot::Mutex;
+use sh_ingest_whip::WhipSession;
+use tracing::*;
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{authenticate_stream, ingest, AppData, IngestSource};
+
+/// The WebRTC sessions publishing streams over WHIP, by the ID in their
+/// resource URL, along with the stream key they were published with.
+#[derive(Default)]
+pub struct WhipSessions {
+    sessions: Mutex<HashMap<String, (String, Arc<WhipSession>)>>,
+    next_id: AtomicU64,
+}
+
+/// Starts publishing a stream from the SDP offer of a WHIP client, like a
+/// browser or OBS.
+///
+/// `app` is used like the app of an RTMP URL and the stream key is sent as
+/// the bearer token, which is how WHIP clients are configured. The answer
+/// points at the session's resource, which ends the stream when deleted.
+pub async fn publish(
+    Path(app): Path<String>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+    offer: Bytes,
+) -> Response<BoxBody> {
+    let key = match bearer_token(&headers) {
+        Some(key) => key,
+        None => return error(StatusCode::UNAUTHORIZED, "Missing stream key"),
+    };
+
+    let is_sdp = headers
+        .get(CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| h.starts_with("application/sdp"));
+    if !is_sdp {
+        return error(StatusCode::UNSUPPORTED_MEDIA_TYPE, "Expected an SDP offer");
+    }
+
+    let offer = match String::from_utf8(offer.to_vec()) {
+        Ok(offer) => offer,
+        Err(_) => return error(StatusCode::BAD_REQUEST, "Invalid SDP offer"),
+    };
+
+    let mut client = data.client.clone();
+    let (id, name) = match authenticate_stream(&mut client, key, app == "public").await {
+        Ok(stream) => stream,
+        Err(e) => {
+            warn!("Failed to authenticate WHIP publisher: {:?}", e);
+            return error(StatusCode::FORBIDDEN, "Invalid stream key");
+        }
+    };
+
+    let (session, answer, filter) = match WhipSession::accept(offer).await {
+        Ok(session) => session,
+        Err(e) => {
+            warn!("Failed to set up WHIP session: {:?}", e);
+            return error(StatusCode::BAD_REQUEST, "Failed to negotiate a session");
+        }
+    };
+
+    info!("Got a WHIP session for {} with app {}", name, app);
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let session_id = format!(
+        "{}-{}",
+        timestamp,
+        data.whip_sessions.next_id.fetch_add(1, Ordering::Relaxed)
+    );
+    data.whip_sessions
+        .sessions
+        .lock()
+        .insert(session_id.clone(), (key.to_string(), Arc::new(session)));
+
+    {
+        let session_id = session_id.clone();
+        let data = data.clone();
+        tokio::spawn(async move {
+            let source = IngestSource::new(Box::new(filter));
+            if let Err(e) = ingest(id, name, source, data.clone()).await {
+                error!("Failed to process WHIP ingest: {:?}", e);
+            }
+
+            data.whip_sessions.sessions.lock().remove(&session_id);
+        });
+    }
+
+    Response::builder()
+        .header(CONTENT_TYPE, "application/sdp")
+        .header("Location", format!("/whip/{}/{}", app, session_id))
+        .status(StatusCode::CREATED)
+        .body(boxed(body::Full::from(answer)))
+        .unwrap()
+}
+
+/// Ends a WHIP session, which is how WHIP clients stop publishing. The
+/// request has to carry the stream key the session was started with.
+pub async fn stop(
+    Path((_app, session_id)): Path<(String, String)>,
+    headers: HeaderMap,
+    Extension(data): Extension<Arc<AppData>>,
+) -> Response<BoxBody> {
+    let session = {
+        let mut sessions = data.whip_sessions.sessions.lock();
+
+        let authorized = sessions
+            .get(&session_id)
+            .map(|(key, _)| Some(key.as_str()) == bearer_token(&headers));
+
+        match authorized {
+            Some(true) => sessions.remove(&session_id).map(|(_, session)| session),
+            Some(false) => return error(StatusCode::FORBIDDEN, "Invalid stream key"),
+            None => None,
+        }
+    };
+
+    match session {
+        Some(session) => {
+            session.close().await;
+
+            Response::builder()
+                .status(StatusCode::OK)
+                .body(boxed(body::Empty::new()))
+                .unwrap()
+        }
+        None => error(StatusCode::NOT_FOUND, "No such WHIP session"),
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+}
+
+fn error(status: StatusCode, message: &'static str) -> Response<BoxBody> {
+    Response::builder()
+        .status(status)
+        .body(boxed(body::Full::from(message)))
+        .unwrap()
+}
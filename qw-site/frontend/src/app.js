@@ -73,6 +73,10 @@ class DebugLog {
 
 let LOG = new DebugLog(5000);
 
+// How many times reconnecting over WebSocket fails before falling back to
+// HLS, where the browser can play it.
+const HLS_FALLBACK_ATTEMPTS = 3;
+
 class StreamStatistics {
     #parent;
     #statsContainer;
@@ -320,6 +324,12 @@ class MseStream {
         this.stats = new StreamStatistics(this);
         this.frames = [];
         this.previousBufferRemoval = performance.now();
+        this.reconnectHints = { initialDelayMs: 1000, maxDelayMs: 30000, alternatives: [] };
+        this.reconnectAttempts = 0;
+        this.isPaused = false;
+        this.resumePoint = null;
+        this.resumeOffset = null;
+        this.isResuming = false;
     }
 
     set targetBuffer(target) {
@@ -350,10 +360,130 @@ class MseStream {
     }
 
     reconnect() {
+        if (this.resumePoint != null && this.mseBuffer != null) {
+            this.resumeStream();
+        } else {
+            this.removeStream();
+            this.attachStream();
+        }
+    }
+
+    // Reconnects while keeping the buffered video, and asks the server to
+    // continue from the last keyframe it sent so nothing is skipped.
+    resumeStream() {
+        LOG.debug(`Resuming stream at ${this.resumePoint.time} seconds`);
+
+        this.socketController?.abort();
+        this.webSocket?.close(1000, "Resuming stream");
+
+        this.frames = [];
+        this.isPaused = false;
+        this.isResuming = true;
+
+        let uri = new URL(this.streamUri);
+        uri.searchParams.set("resume", this.resumePoint.token);
+        this.connectWebSocket(uri.toString());
+    }
+
+    // Reconnects after a delay which grows with every failed attempt, as
+    // suggested by the server. Alternative URLs are tried in turn.
+    scheduleReconnect() {
+        if (this.reconnectAttempts >= HLS_FALLBACK_ATTEMPTS && this.canPlayHls()) {
+            this.fallBackToHls();
+            return;
+        }
+
+        let hints = this.reconnectHints;
+        let delay = Math.min(hints.initialDelayMs * Math.pow(2, this.reconnectAttempts), hints.maxDelayMs);
+        let urls = [this.originalUri ?? this.streamUri, ...hints.alternatives];
+
+        this.originalUri = urls[0];
+        this.streamUri = urls[this.reconnectAttempts % urls.length];
+        this.reconnectAttempts++;
+
+        LOG.debug(`Reconnecting to '${this.streamUri}' in ${delay} ms`);
+
+        clearTimeout(this.reconnectTimeout);
+        this.reconnectTimeout = setTimeout(() => this.reconnect(), delay);
+    }
+
+    canPlayHls() {
+        return this.videoElement.canPlayType("application/vnd.apple.mpegurl") !== "";
+    }
+
+    // Plays the HLS playlist of the stream instead, e.g. on networks which
+    // block WebSockets. The server starts it where MSE left off, if it can.
+    fallBackToHls() {
+        let uri = new URL(this.originalUri ?? this.streamUri);
+        uri.protocol = uri.protocol === "wss:" ? "https:" : "http:";
+        uri.pathname = uri.pathname.replace("/transport/mse/", "/hls/") + "/playlist.m3u8";
+        uri.search = "";
+        if (this.resumePoint != null) {
+            uri.searchParams.set("resume", this.resumePoint.token);
+        }
+
+        LOG.warn(`Falling back to HLS at '${uri}'`);
+
+        clearTimeout(this.reconnectTimeout);
         this.removeStream();
+        this.videoElement.src = uri.toString();
+        this.videoElement.play();
+    }
+
+    // Switches from HLS back to MSE, starting at the HLS segment with the
+    // media sequence number `sequence` so nothing is skipped.
+    upgradeToMse(sequence) {
+        // later reconnects start over from the plain stream URL
+        this.originalUri = this.originalUri ?? this.streamUri;
+
+        let uri = new URL(this.originalUri);
+        uri.searchParams.set("segment", sequence);
+
+        LOG.debug(`Upgrading to MSE from HLS segment ${sequence}`);
+
+        this.removeStream();
+        this.reconnectAttempts = 0;
+        this.streamUri = uri.toString();
         this.attachStream();
     }
 
+    // Asks the server to stop sending media, e.g. while nobody is watching.
+    pause() {
+        if (this.webSocket?.readyState === WebSocket.OPEN && !this.isPaused) {
+            LOG.debug("Pausing stream");
+            this.isPaused = true;
+            this.webSocket.send("pause");
+        }
+    }
+
+    // Asks the server to send media again, starting from the next keyframe.
+    resume() {
+        if (this.webSocket?.readyState === WebSocket.OPEN && this.isPaused) {
+            LOG.debug("Resuming stream");
+            this.isPaused = false;
+            this.webSocket.send("resume");
+        }
+    }
+
+    // Asks the server to send media faster than realtime, e.g. 1.5, so a
+    // stream which was started behind live catches up to it.
+    setCatchUpRate(rate) {
+        if (this.webSocket?.readyState === WebSocket.OPEN) {
+            LOG.debug(`Catching up at ${rate}x`);
+            this.webSocket.send(`rate:${rate}`);
+        }
+    }
+
+    // Pauses muted streams in background tabs, where nobody can see or
+    // hear them.
+    visibilityChanged() {
+        if (document.visibilityState === "hidden" && this.videoElement.muted) {
+            this.pause();
+        } else if (document.visibilityState === "visible") {
+            this.resume();
+        }
+    }
+
     getDebugLogs() {
         LOG.debug("Generating debug logs");
 
@@ -378,15 +508,29 @@ class MseStream {
         this.videoElement.addEventListener("stalled", (e) => LOG.warn("stalled"), { signal: signal });
         this.videoElement.addEventListener("suspend", (e) => LOG.warn("suspend"), { signal: signal });
         this.videoElement.addEventListener("error", (e) => LOG.error("error"), { signal: signal });
+        document.addEventListener("visibilitychange", this.visibilityChanged.bind(this), { signal: signal });
 
-        this.webSocket = new WebSocket(this.streamUri);
+        this.connectWebSocket(this.streamUri);
+
+        this.stats.createStatsContainer();
+    }
+
+    connectWebSocket(uri) {
+        this.socketController = new AbortController();
+        let signal = this.socketController.signal;
+
+        // the server offers the renditions of the stream, so one which MSE
+        // can decode is picked
+        let negotiateUri = new URL(uri);
+        negotiateUri.searchParams.set("negotiate", "true");
+        this.hasNegotiated = false;
+
+        this.webSocket = new WebSocket(negotiateUri.toString());
         this.webSocket.binaryType = "arraybuffer";
         this.webSocket.addEventListener("close", this.webSocketClose.bind(this), { signal: signal });
         this.webSocket.addEventListener("error", this.webSocketError.bind(this), { signal: signal });
         this.webSocket.addEventListener("open", this.webSocketOpen.bind(this), { signal: signal });
         this.webSocket.addEventListener("message", this.webSocketMessage.bind(this), { signal: signal });
-
-        this.stats.createStatsContainer();
     }
 
     removeStream() {
@@ -397,6 +541,7 @@ class MseStream {
 
         if (this.mseSource != null) {
             this.isExpectingData = false;
+            this.isPaused = false;
             this.hasStartedStream = false;
             this.hasInFlightUpdates = false;
             this.frames = [];
@@ -406,6 +551,11 @@ class MseStream {
             this.eventController?.abort();
         }
 
+        this.socketController?.abort();
+        this.resumePoint = null;
+        this.resumeOffset = null;
+        this.isResuming = false;
+
         this.stats.deleteStatsContainer();
 
         this.videoStarted = false;
@@ -493,6 +643,7 @@ class MseStream {
 
         if (!this.videoStarted && buffered >= this.targetBuffer) {
             LOG.debug(`Starting video with ${buffered} seconds buffered`);
+            this.reconnectAttempts = 0;
 
             if (this.onconnectionsuccess != null) {
                 this.onconnectionsuccess();
@@ -530,9 +681,35 @@ class MseStream {
             return;
         }
 
-        if (!this.hasStartedStream) {
+        if (!this.hasNegotiated) {
+            this.hasNegotiated = true;
+            this.pickRendition(JSON.parse(event.data));
+        } else if (!this.hasStartedStream) {
             this.hasStartedStream = true;
             this.webSocketMessageInit(event.data);
+        } else if (this.isResuming) {
+            this.isResuming = false;
+
+            // the buffered video can't be continued with other codecs
+            if (`video/mp4; codecs="${event.data}"` != this.codec) {
+                LOG.warn(`Codecs changed to ${event.data}, restarting stream`);
+                this.removeStream();
+                this.attachStream();
+                return;
+            }
+
+            this.frames.push({ timestampOffset: this.resumePoint.time });
+        } else if (typeof event.data === "string") {
+            let message = JSON.parse(event.data);
+
+            if (message.resumeToken !== undefined) {
+                // noted once the segments before it are buffered
+                this.frames.push({ resumeToken: message.resumeToken });
+                this.feedFrame();
+            } else {
+                this.reconnectHints = message;
+                LOG.debug(`Got reconnect hints: ${event.data}`);
+            }
         } else {
             var bytes = new Uint8Array(event.data);
             // this.networkBytes += bytes.length;
@@ -540,6 +717,26 @@ class MseStream {
         }
     }
 
+    // Picks the first rendition the browser can decode, or falls back to
+    // HLS when there is none, since the browser may still play it natively.
+    pickRendition(offer) {
+        let rendition = offer.renditions.find(
+            r => MediaSource.isTypeSupported(`video/mp4; codecs="${r.codecs}"`));
+
+        if (rendition === undefined) {
+            LOG.warn(`Can't decode any rendition of ${JSON.stringify(offer.renditions)}`);
+            this.webSocket.send("rendition:");
+
+            if (this.canPlayHls()) {
+                this.fallBackToHls();
+            }
+            return;
+        }
+
+        LOG.debug(`Picked rendition '${rendition.name}' with codecs ${rendition.codecs}`);
+        this.webSocket.send(`rendition:${rendition.name}`);
+    }
+
     registerVideoEvents() {
 
     }
@@ -596,10 +793,33 @@ class MseStream {
         this.videoElement.playbackRate = playbackRate;
     }
 
+    // Gets where the next appended segment is placed in the buffer
+    getBufferedEnd() {
+        const buffered = this.mseBuffer.buffered;
+
+        return buffered.length >= 1 ? buffered.end(buffered.length - 1) : 0;
+    }
+
     feedFrame() {
         if (this.mseBuffer != null && !this.hasInFlightUpdates) {
             var frame = this.frames.shift();
 
+            // resume markers are queued in between the segments
+            while (frame && !(frame instanceof Uint8Array)) {
+                if (frame.resumeToken !== undefined) {
+                    // a resumed stream overwrites what is buffered after
+                    // its offset, so the buffer doesn't end there yet
+                    let time = this.resumeOffset ?? this.getBufferedEnd();
+                    this.resumePoint = { token: frame.resumeToken, time: time };
+                    this.resumeOffset = null;
+                } else if (frame.timestampOffset !== undefined) {
+                    this.mseBuffer.timestampOffset = frame.timestampOffset;
+                    this.resumeOffset = frame.timestampOffset;
+                }
+
+                frame = this.frames.shift();
+            }
+
             if (frame) {
                 this.hasInFlightUpdates = true;
                 this.mseBuffer.appendBuffer(frame);
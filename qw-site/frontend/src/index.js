@@ -44,6 +44,7 @@ stream.onconnectionfail = function(event) {
     console.log("Failed to connect: " + event);
 
     clearInterval(pollInterval);
+    stream.scheduleReconnect();
 };
 //stream.statsContainer = container;
 stream.video = video;
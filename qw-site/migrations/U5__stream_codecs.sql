@@ -0,0 +1,3 @@
+ALTER TABLE stream_metadata
+ADD COLUMN video_codec TEXT,
+ADD COLUMN audio_codec TEXT;
@@ -0,0 +1,317 @@
+use std::{fmt, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use lettre::{
+    message::Mailbox,
+    transport::smtp::authentication::{Credentials, Mechanism},
+    Message, SmtpTransport, Transport,
+};
+use tokio::{task, time::sleep};
+use tracing::*;
+
+use crate::PostgresPool;
+
+const MIB: u64 = 1024 * 1024;
+
+/// Events which are important enough to notify operators about.
+#[derive(Debug, Clone)]
+pub enum CriticalEvent {
+    /// A stream went offline and did not come back within the grace period.
+    StreamDown { account_name: String, minutes: u64 },
+
+    /// The connection to the ingest server was lost and could not be
+    /// re-established.
+    IngestUnreachable { address: String },
+
+    /// A metric of a stream's ingest suddenly changed, which often comes
+    /// before the stream fails.
+    IngestAnomaly {
+        account_name: String,
+        metric: String,
+        value: f64,
+        expected: f64,
+    },
+
+    /// A disk of the origin which recordings are written to is nearly full.
+    OriginDiskFull {
+        path: String,
+        available_bytes: u64,
+        total_bytes: u64,
+    },
+
+    /// A recording of a stream stopped because writing it failed.
+    RecordingFailure {
+        account_name: String,
+        recording: String,
+        error: String,
+    },
+}
+
+impl CriticalEvent {
+    fn kind(&self) -> &'static str {
+        match self {
+            CriticalEvent::StreamDown { .. } => "stream-down",
+            CriticalEvent::IngestUnreachable { .. } => "ingest-unreachable",
+            CriticalEvent::IngestAnomaly { .. } => "ingest-anomaly",
+            CriticalEvent::OriginDiskFull { .. } => "origin-disk-full",
+            CriticalEvent::RecordingFailure { .. } => "recording-failure",
+        }
+    }
+
+    /// The name of the account whose stream the event is about, if it is
+    /// about one.
+    pub fn account_name(&self) -> Option<&str> {
+        match self {
+            CriticalEvent::StreamDown { account_name, .. }
+            | CriticalEvent::IngestAnomaly { account_name, .. }
+            | CriticalEvent::RecordingFailure { account_name, .. } => Some(account_name),
+            CriticalEvent::IngestUnreachable { .. } | CriticalEvent::OriginDiskFull { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for CriticalEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CriticalEvent::StreamDown {
+                account_name,
+                minutes,
+            } => write!(
+                f,
+                "The stream of {} has been down for more than {} minutes",
+                account_name, minutes
+            ),
+            CriticalEvent::IngestUnreachable { address } => {
+                write!(f, "The ingest server at {} is unreachable", address)
+            }
+            CriticalEvent::IngestAnomaly {
+                account_name,
+                metric,
+                value,
+                expected,
+            } => write!(
+                f,
+                "The {} of the stream of {} is {:.1}, where it was around {:.1}",
+                metric, account_name, value, expected
+            ),
+            CriticalEvent::OriginDiskFull {
+                path,
+                available_bytes,
+                total_bytes,
+            } => write!(
+                f,
+                "The disk of the origin at {} is nearly full, with {} of {} MiB left",
+                path,
+                available_bytes / MIB,
+                total_bytes / MIB
+            ),
+            CriticalEvent::RecordingFailure {
+                account_name,
+                recording,
+                error,
+            } => write!(
+                f,
+                "The recording {} of the stream of {} failed: {}",
+                recording, account_name, error
+            ),
+        }
+    }
+}
+
+/// Sends an email to a list of recipients when a [`CriticalEvent`] occurs.
+pub struct EmailNotifier {
+    smtp_server: String,
+    smtp_user: String,
+    smtp_pass: String,
+    from: Mailbox,
+    recipients: Vec<Mailbox>,
+    events: Vec<String>,
+    stream_down_minutes: u64,
+}
+
+impl EmailNotifier {
+    /// Creates a notifier from `QW_ALERT_*` environment variables, or `None`
+    /// if no recipients are configured.
+    pub fn from_env(
+        smtp_server: String,
+        smtp_user: String,
+        smtp_pass: String,
+        site_domain: &str,
+    ) -> anyhow::Result<Option<Self>> {
+        let recipients = crate::env("QW_ALERT_EMAILS", "");
+        if recipients.is_empty() {
+            return Ok(None);
+        }
+
+        let recipients = recipients
+            .split(',')
+            .map(|r| format!("<{}>", r.trim()).parse())
+            .collect::<Result<Vec<Mailbox>, _>>()
+            .context("parsing QW_ALERT_EMAILS")?;
+
+        let events = crate::env(
+            "QW_ALERT_EVENTS",
+            "stream-down,ingest-unreachable,origin-disk-full,recording-failure",
+        )
+        .split(',')
+        .map(|e| e.trim().to_string())
+        .collect();
+
+        let stream_down_minutes = crate::env("QW_ALERT_STREAM_DOWN_MINUTES", "5")
+            .parse()
+            .context("parsing QW_ALERT_STREAM_DOWN_MINUTES")?;
+
+        let from = format!("{} <no-reply@{}>", site_domain, site_domain)
+            .parse()
+            .context("parsing email source address")?;
+
+        Ok(Some(EmailNotifier {
+            smtp_server,
+            smtp_user,
+            smtp_pass,
+            from,
+            recipients,
+            events,
+            stream_down_minutes,
+        }))
+    }
+
+    fn is_enabled(&self, kind: &str) -> bool {
+        self.events.iter().any(|e| e == kind)
+    }
+
+    /// Sends an email about `event` in the background, if alerts for that
+    /// kind of event are enabled.
+    pub fn notify(self: &Arc<Self>, event: CriticalEvent) {
+        if !self.is_enabled(event.kind()) {
+            return;
+        }
+
+        warn!("Critical event: {}", event);
+
+        let notifier = self.clone();
+        task::spawn_blocking(move || {
+            if let Err(e) = notifier.send(&event) {
+                error!("Failed to send alert email: {:?}", e);
+            }
+        });
+    }
+
+    fn send(&self, event: &CriticalEvent) -> anyhow::Result<()> {
+        let credentials = Credentials::new(self.smtp_user.clone(), self.smtp_pass.clone());
+
+        let mailer = SmtpTransport::starttls_relay(&self.smtp_server)?
+            .credentials(credentials)
+            .authentication(vec![Mechanism::Plain])
+            .build();
+
+        let mut builder = Message::builder()
+            .from(self.from.clone())
+            .subject(format!("[alert] {}", event.kind()));
+        for to in &self.recipients {
+            builder = builder.to(to.clone());
+        }
+
+        let email = builder.body(event.to_string())?;
+
+        mailer.send(&email).context("Sending e-mail")?;
+
+        Ok(())
+    }
+
+    /// Raises [`CriticalEvent::StreamDown`] if the account which owned the
+    /// given stream session has not started streaming again after the
+    /// configured number of minutes.
+    pub fn watch_stream_down(self: &Arc<Self>, pool: Arc<PostgresPool>, stream_session_id: i32) {
+        let notifier = self.clone();
+
+        task::spawn(async move {
+            sleep(Duration::from_secs(notifier.stream_down_minutes * 60)).await;
+
+            match is_account_still_down(&pool, stream_session_id).await {
+                Ok(Some(account_name)) => notifier.notify(CriticalEvent::StreamDown {
+                    account_name,
+                    minutes: notifier.stream_down_minutes,
+                }),
+                Ok(None) => {}
+                Err(e) => error!("Failed to check if stream is still down: {}", e),
+            }
+        });
+    }
+}
+
+pub(crate) async fn session_account(
+    pool: &PostgresPool,
+    stream_session_id: i32,
+) -> anyhow::Result<Option<String>> {
+    let conn = pool.get().await?;
+
+    let row = conn
+        .query_opt(
+            "
+SELECT account.name FROM stream_session
+INNER JOIN account ON
+    stream_session.account_id = account.id
+WHERE
+    stream_session.id = $1
+            ",
+            &[&stream_session_id],
+        )
+        .await?;
+
+    Ok(row.map(|r| r.get::<_, String>(0)))
+}
+
+async fn is_account_still_down(
+    pool: &PostgresPool,
+    stream_session_id: i32,
+) -> anyhow::Result<Option<String>> {
+    let conn = pool.get().await?;
+
+    let row = conn
+        .query_opt(
+            "
+SELECT account.name FROM stream_session
+INNER JOIN account ON
+    stream_session.account_id = account.id
+WHERE
+    stream_session.id = $1 AND
+    NOT EXISTS (
+        SELECT 1 FROM stream_session AS newer
+        WHERE
+            newer.account_id = stream_session.account_id AND
+            newer.stop_time IS NULL
+    )
+            ",
+            &[&stream_session_id],
+        )
+        .await?;
+
+    Ok(row.map(|r| r.get::<_, String>(0)))
+}
+
+#[test]
+fn describes_storage_events() {
+    let full = CriticalEvent::OriginDiskFull {
+        path: "/recordings".into(),
+        available_bytes: 512 * MIB,
+        total_bytes: 10240 * MIB,
+    };
+    assert_eq!(full.kind(), "origin-disk-full");
+    assert_eq!(
+        full.to_string(),
+        "The disk of the origin at /recordings is nearly full, with 512 of 10240 MiB left"
+    );
+
+    let failure = CriticalEvent::RecordingFailure {
+        account_name: "streamer".into(),
+        recording: "streamer-1700000000".into(),
+        error: "No space left on device".into(),
+    };
+    assert_eq!(failure.kind(), "recording-failure");
+    assert_eq!(
+        failure.to_string(),
+        "The recording streamer-1700000000 of the stream of streamer failed: \
+         No space left on device"
+    );
+}
@@ -31,12 +31,18 @@ use std::{
     sync::Arc,
 };
 
+mod status;
 mod stream_auth;
 mod stream_service;
 
 mod account;
+mod alerts;
+mod warnings;
 
-use crate::stream_auth::ScuffedStreamAuthService;
+use crate::{
+    alerts::{CriticalEvent, EmailNotifier},
+    stream_auth::ScuffedStreamAuthService,
+};
 
 pub type PostgresManager = bb8_postgres::PostgresConnectionManager<tokio_postgres::NoTls>;
 pub type PostgresPool = bb8::Pool<PostgresManager>;
@@ -56,6 +62,9 @@ pub struct AppData {
     pub secret_key: String,
     pub web_url: String,
     pub site_domain: String,
+    pub public_status_pages: bool,
+    pub audio_levels: stream_service::AudioLevels,
+    pub warnings: warnings::Warnings,
 }
 
 pub(crate) struct AskamaTemplate<'a, T>(&'a T);
@@ -261,9 +270,30 @@ async fn start() -> anyhow::Result<()> {
             .context("failed to build database pool")?,
     );
 
-    let (send, recv) = mpsc::channel(1024);
+    let smtp_server = env::var("QW_SMTP_SERVER").context("QW_SMTP_SERVER not set")?;
+    let smtp_user = env::var("QW_SMTP_USER").context("QW_SMTP_USER not set")?;
+    let smtp_pass = env::var("QW_SMTP_PASS").context("QW_SMTP_PASS not set")?;
+    let site_domain = env::var("QW_DOMAIN").context("QW_DOMAIN not set")?;
+
+    let notifier = EmailNotifier::from_env(
+        smtp_server.clone(),
+        smtp_user.clone(),
+        smtp_pass.clone(),
+        &site_domain,
+    )?
+    .map(Arc::new);
 
-    let mut stream_session_service = stream_service::StreamSessionService::new(recv, pool.clone());
+    let (send, recv) = mpsc::channel(1024);
+    let audio_levels = stream_service::AudioLevels::default();
+    let warnings = warnings::Warnings::default();
+
+    let mut stream_session_service = stream_service::StreamSessionService::new(
+        recv,
+        pool.clone(),
+        audio_levels.clone(),
+        warnings.clone(),
+        notifier.clone(),
+    );
     stream_session_service
         .start()
         .await
@@ -275,12 +305,9 @@ async fn start() -> anyhow::Result<()> {
 
     let scuffed_service = ScuffedStreamAuthService::new(pool.clone());
 
-    let smtp_server = env::var("QW_SMTP_SERVER").context("QW_SMTP_SERVER not set")?;
-    let smtp_user = env::var("QW_SMTP_USER").context("QW_SMTP_USER not set")?;
-    let smtp_pass = env::var("QW_SMTP_PASS").context("QW_SMTP_PASS not set")?;
     let secret_key = env::var("QW_SECRET_KEY").context("QW_SECRET_KEY not set")?;
     let web_url = env::var("QW_WEB_URL").context("QW_WEB_URL not set")?;
-    let site_domain = env::var("QW_DOMAIN").context("QW_DOMAIN not set")?;
+    let public_status_pages = env("QW_PUBLIC_STATUS_PAGES", "false") == "true";
 
     let session_service = Arc::new(AccountSessionService::new(site_domain.clone(), &secret_key));
 
@@ -294,13 +321,17 @@ async fn start() -> anyhow::Result<()> {
         secret_key,
         web_url,
         site_domain,
+        public_status_pages,
         pool: pool.clone(),
+        audio_levels,
+        warnings,
     };
 
     let app = Router::new()
         .route("/:stream", get(stream_page))
         .route("/", get(index_page))
         .route("/streams", get(streams_page))
+        .route("/status/:stream", get(status::status_page_get_handler))
         .route(
             "/help",
             get(|| async { Redirect::permanent("/help/".parse().unwrap()) }),
@@ -308,7 +339,7 @@ async fn start() -> anyhow::Result<()> {
         .nest("/account", account::api_route())
         .layer(AddExtensionLayer::new(Arc::new(data)));
 
-    spawn_stream_info_loop(ingest_rpc_addr, send);
+    spawn_stream_info_loop(ingest_rpc_addr, send, notifier);
 
     let rpc_task = tokio::spawn(async move {
         debug!("Listening for RPC calls on {}", scuffed_rpc_addr);
@@ -367,7 +398,11 @@ async fn stream_info_listen(
     Ok(())
 }
 
-fn spawn_stream_info_loop(ingest_rpc_addr: String, send: Sender<StreamType>) {
+fn spawn_stream_info_loop(
+    ingest_rpc_addr: String,
+    send: Sender<StreamType>,
+    notifier: Option<Arc<EmailNotifier>>,
+) {
     tokio::spawn(async move {
         use backoff::{future::retry, ExponentialBackoff};
 
@@ -376,7 +411,7 @@ fn spawn_stream_info_loop(ingest_rpc_addr: String, send: Sender<StreamType>) {
             ..Default::default()
         };
 
-        let _ = retry(backoff, || async {
+        let result = retry(backoff, || async {
             if let Err(e) = stream_info_listen(ingest_rpc_addr.clone(), send.clone()).await {
                 warn!("Error while listening for stream info: {}", e);
 
@@ -386,6 +421,12 @@ fn spawn_stream_info_loop(ingest_rpc_addr: String, send: Sender<StreamType>) {
             }
         })
         .await;
+
+        if let (Err(_), Some(notifier)) = (result, notifier) {
+            notifier.notify(CriticalEvent::IngestUnreachable {
+                address: ingest_rpc_addr,
+            });
+        }
     });
 }
 
@@ -0,0 +1,211 @@
+use std::sync::Arc;
+
+use askama::Template;
+use axum::{
+    body::{boxed, BoxBody},
+    extract::{Extension, Path},
+    http::{Response, StatusCode, Uri},
+    response::{IntoResponse, Redirect},
+};
+
+use crate::{
+    account::session::Cookies, stream_service::AudioLevels, warnings::Warnings, AppData,
+    AskamaTemplate, PostgresConnection,
+};
+
+struct StatusWarning {
+    ago: String,
+    message: String,
+}
+
+struct StatusBwSample {
+    time: i64,
+    ingest_bytes: u32,
+    other_bytes: u32,
+}
+
+#[derive(Template)]
+#[template(path = "status.html")]
+struct StreamStatusTemplate {
+    name: String,
+    online: bool,
+    uptime: String,
+    viewers: i32,
+    encoder: Option<String>,
+    video_bitrate_kbps: Option<i32>,
+    video_codec: Option<String>,
+    audio_codec: Option<String>,
+    audio_level: Option<String>,
+    samples: Vec<StatusBwSample>,
+    warnings: Vec<StatusWarning>,
+}
+
+impl StreamStatusTemplate {
+    fn offline(name: String, warnings: Vec<StatusWarning>) -> Self {
+        StreamStatusTemplate {
+            name,
+            online: false,
+            uptime: String::new(),
+            viewers: 0,
+            encoder: None,
+            video_bitrate_kbps: None,
+            video_codec: None,
+            audio_codec: None,
+            audio_level: None,
+            samples: Vec::new(),
+            warnings,
+        }
+    }
+}
+
+pub(crate) async fn status_page_get_handler(
+    Path(stream): Path<String>,
+    Extension(data): Extension<Arc<AppData>>,
+    cookies: Cookies,
+) -> crate::Result<Response<BoxBody>> {
+    Ok(status_page(&data, &stream, cookies).await?)
+}
+
+async fn status_page(
+    data: &Arc<AppData>,
+    stream: &str,
+    cookies: Cookies,
+) -> anyhow::Result<Response<BoxBody>> {
+    let conn = data.pool.get().await?;
+
+    if !data.public_status_pages
+        && data
+            .session_service
+            .verify_auth_cookie(&conn, cookies)
+            .await?
+            .is_none()
+    {
+        return Ok(Redirect::to(Uri::from_static("/account/login"))
+            .into_response()
+            .map(boxed));
+    }
+
+    if let Some(template) =
+        get_stream_status(&conn, stream, &data.audio_levels, &data.warnings).await?
+    {
+        Ok(AskamaTemplate(&template).into_response())
+    } else {
+        Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(boxed(axum::body::Full::from("Stream not found")))
+            .unwrap())
+    }
+}
+
+async fn get_stream_status(
+    conn: &PostgresConnection<'_>,
+    stream: &str,
+    audio_levels: &AudioLevels,
+    warnings: &Warnings,
+) -> anyhow::Result<Option<StreamStatusTemplate>> {
+    let row = conn
+        .query_opt(
+            "
+SELECT id, name FROM account
+WHERE account.name ILIKE $1",
+            &[&stream],
+        )
+        .await?;
+
+    let (account_id, name) = match row.map(|r| (r.get::<_, i32>(0), r.get::<_, String>(1))) {
+        Some(account) => account,
+        None => return Ok(None),
+    };
+
+    let now = time::OffsetDateTime::now_utc();
+    let warnings = warnings
+        .read()
+        .await
+        .recent(&name, now)
+        .into_iter()
+        .map(|w| StatusWarning {
+            ago: format_duration(now - w.time),
+            message: w.message,
+        })
+        .collect::<Vec<_>>();
+
+    let row = conn
+        .query_opt(
+            "
+SELECT
+    stream_session.id,
+    stream_session.start_time,
+    stream_session.viewer_count,
+    stream_metadata.encoder,
+    stream_metadata.video_bitrate_kbps,
+    stream_metadata.video_codec,
+    stream_metadata.audio_codec
+FROM stream_session
+LEFT JOIN stream_metadata ON
+    stream_metadata.stream_session_id = stream_session.id
+WHERE
+    stream_session.account_id = $1 AND
+    stream_session.stop_time IS NULL
+LIMIT 1",
+            &[&account_id],
+        )
+        .await?;
+
+    let row = match row {
+        Some(row) => row,
+        None => return Ok(Some(StreamStatusTemplate::offline(name, warnings))),
+    };
+
+    let stream_session_id = row.get::<_, i32>(0);
+    let start = time::OffsetDateTime::from_unix_timestamp(row.get::<_, i64>(1))?;
+    let uptime = now - start;
+
+    let samples = conn
+        .query(
+            "
+SELECT time, ingest_bytes_since_prev, bytes_since_prev FROM bandwidth_usage
+WHERE stream_session_id = $1
+ORDER BY time
+        ",
+            &[&stream_session_id],
+        )
+        .await?
+        .iter()
+        .map(|r| StatusBwSample {
+            time: r.get::<_, i64>(0),
+            ingest_bytes: r.get::<_, i32>(1) as u32,
+            other_bytes: r.get::<_, i32>(2) as u32,
+        })
+        .collect::<Vec<_>>();
+
+    // the level is approximated for all channels at once, see the ingest
+    let audio_level = audio_levels.read().await.get(&stream_session_id).map(|l| {
+        format!(
+            "~{:.1} dBFS peak, ~{:.1} dBFS RMS",
+            l.approx_peak_db, l.approx_rms_db
+        )
+    });
+
+    Ok(Some(StreamStatusTemplate {
+        name,
+        online: true,
+        uptime: format_duration(uptime),
+        viewers: row.get::<_, i32>(2),
+        encoder: row.get::<_, Option<String>>(3),
+        video_bitrate_kbps: row.get::<_, Option<i32>>(4),
+        video_codec: row.get::<_, Option<String>>(5),
+        audio_codec: row.get::<_, Option<String>>(6),
+        audio_level,
+        samples,
+        warnings,
+    }))
+}
+
+fn format_duration(duration: time::Duration) -> String {
+    format!(
+        "{}h {}m {}s",
+        duration.whole_hours(),
+        duration.whole_minutes() % 60,
+        duration.whole_seconds() % 60
+    )
+}
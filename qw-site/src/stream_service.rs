@@ -1,10 +1,17 @@
 use std::sync::Arc;
 use std::{collections::HashMap, time::Duration};
 
-use crate::{PostgresConnection, PostgresPool};
+use crate::{
+    alerts::{session_account, CriticalEvent, EmailNotifier},
+    warnings::Warnings,
+    PostgresConnection, PostgresPool,
+};
 
 use bytesize::ByteSize;
-use qw_proto::stream_info::{stream_reply::StreamType, StreamMetadata};
+use qw_proto::stream_info::{
+    stream_reply::{StreamAudioLevels, StreamType},
+    StreamMetadata,
+};
 use tokio::sync::RwLock;
 use tokio::task;
 use tokio::{sync::mpsc, time::sleep};
@@ -110,8 +117,8 @@ pub async fn attach_stream_metadata(
     let _ = conn
         .execute(
             "
-INSERT INTO stream_metadata (stream_session_id, encoder, video_bitrate_kbps, parameter_sets)
-VALUES ($1, $2, $3, $4)
+INSERT INTO stream_metadata (stream_session_id, encoder, video_bitrate_kbps, parameter_sets, video_codec, audio_codec)
+VALUES ($1, $2, $3, $4, $5, $6)
 ON CONFLICT DO NOTHING
             ",
             &[
@@ -119,6 +126,8 @@ ON CONFLICT DO NOTHING
                 &meta.video_encoder,
                 &meta.video_bitrate_kbps.map(|b| b as i32),
                 &meta.parameter_sets,
+                &meta.video_codec,
+                &meta.audio_codec,
             ],
         )
         .await?;
@@ -221,6 +230,9 @@ WHERE
     Ok(sessions)
 }
 
+/// The latest audio levels of each live stream session, for status pages.
+pub type AudioLevels = Arc<RwLock<HashMap<i32, StreamAudioLevels>>>;
+
 #[derive(Default)]
 pub struct StreamInfo {
     viewers: i32,
@@ -258,15 +270,27 @@ pub struct StreamSessionService {
     pub pool: Arc<PostgresPool>,
     pub streams: HashMap<i32, StreamInfo>,
     aggregated_stats: RwLock<HashMap<i32, AggregatedStats>>,
+    audio_levels: AudioLevels,
+    warnings: Warnings,
+    notifier: Option<Arc<EmailNotifier>>,
 }
 
 impl StreamSessionService {
-    pub fn new(recv: mpsc::Receiver<StreamType>, pool: Arc<PostgresPool>) -> Self {
+    pub fn new(
+        recv: mpsc::Receiver<StreamType>,
+        pool: Arc<PostgresPool>,
+        audio_levels: AudioLevels,
+        warnings: Warnings,
+        notifier: Option<Arc<EmailNotifier>>,
+    ) -> Self {
         StreamSessionService {
             recv,
             pool,
             streams: HashMap::new(),
             aggregated_stats: RwLock::new(HashMap::new()),
+            audio_levels,
+            warnings,
+            notifier,
         }
     }
 
@@ -296,7 +320,7 @@ WHERE stop_time IS NULL
             res = async {
                 loop {
                     if let Some(msg) = self.recv.recv().await {
-                        handle_msg(&self.pool, &mut self.streams, &self.aggregated_stats, msg).await?;
+                        handle_msg(&self.pool, &mut self.streams, &self.aggregated_stats, &self.audio_levels, &self.warnings, &self.notifier, msg).await?;
                     }
                 }
             } => res,
@@ -327,8 +351,13 @@ async fn handle_msg(
     pool: &Arc<PostgresPool>,
     streams: &mut HashMap<i32, StreamInfo>,
     aggregated_stats: &RwLock<HashMap<i32, AggregatedStats>>,
+    audio_levels: &AudioLevels,
+    warnings: &Warnings,
+    notifier: &Option<Arc<EmailNotifier>>,
     msg: StreamType,
 ) -> anyhow::Result<()> {
+    let now = time::OffsetDateTime::now_utc();
+
     match msg {
         StreamType::StreamExisting(stream) => {
             streams.insert(
@@ -366,11 +395,31 @@ async fn handle_msg(
             }
         }
         StreamType::StreamStopped(stream) => {
+            if let Some(e) = &stream.error {
+                warn!(
+                    "Stream {} ended with an error: {e}",
+                    stream.stream_session_id
+                );
+
+                if let Some(account_name) = stream_account(pool, stream.stream_session_id).await {
+                    let message = format!("The stream ended with an error: {}", e);
+                    warnings
+                        .write()
+                        .await
+                        .warn_stream(&account_name, now, message);
+                }
+            }
+
             let conn = pool.get().await?;
             let end = time::OffsetDateTime::now_utc();
 
             stop_stream_session(&conn, stream.stream_session_id, end).await?;
             streams.remove(&stream.stream_session_id);
+            audio_levels.write().await.remove(&stream.stream_session_id);
+
+            if let Some(notifier) = notifier {
+                notifier.watch_stream_down(pool.clone(), stream.stream_session_id);
+            }
         }
         StreamType::StreamStats(stat) => {
             let mut stats = aggregated_stats.write().await;
@@ -384,7 +433,84 @@ async fn handle_msg(
                 entry.other_bytes += stat.bytes_since_last_stats as i32;
             }
         }
+        // levels are only for live meters, so only the latest are kept
+        StreamType::StreamAudioLevels(levels) => {
+            audio_levels
+                .write()
+                .await
+                .insert(levels.stream_session_id, levels);
+        }
+        // delivery stats of viewers are only for looking into playback
+        // issues while they happen
+        StreamType::ViewerStats(_) => {}
+        StreamType::StreamAnomaly(anomaly) => {
+            let account_name = match stream_account(pool, anomaly.stream_session_id).await {
+                Some(account_name) => account_name,
+                None => return Ok(()),
+            };
+
+            let event = CriticalEvent::IngestAnomaly {
+                account_name,
+                metric: anomaly.metric,
+                value: anomaly.value,
+                expected: anomaly.expected,
+            };
+            warn_stream(warnings, notifier, now, event).await;
+        }
+        StreamType::RecordingFailed(failed) => {
+            // streams are named after the accounts which own them
+            let event = CriticalEvent::RecordingFailure {
+                account_name: failed.stream,
+                recording: failed.recording,
+                error: failed.error,
+            };
+            warn_stream(warnings, notifier, now, event).await;
+        }
+        StreamType::DiskFull(full) => {
+            let event = CriticalEvent::OriginDiskFull {
+                path: full.path,
+                available_bytes: full.available_bytes,
+                total_bytes: full.total_bytes,
+            };
+            warnings.write().await.warn_origin(now, event.to_string());
+
+            if let Some(notifier) = notifier {
+                notifier.notify(event);
+            }
+        }
     }
 
     Ok(())
 }
+
+/// Looks up the name of the account which owns a stream session, for the
+/// warnings of its status page, which aren't worth failing over.
+async fn stream_account(pool: &PostgresPool, stream_session_id: i32) -> Option<String> {
+    match session_account(pool, stream_session_id).await {
+        Ok(account_name) => account_name,
+        Err(e) => {
+            error!("Failed to look up the account of a stream: {}", e);
+            None
+        }
+    }
+}
+
+/// Shows `event` on the status page of the stream it is about, and alerts
+/// about it if alerts for its kind are enabled.
+async fn warn_stream(
+    warnings: &Warnings,
+    notifier: &Option<Arc<EmailNotifier>>,
+    now: time::OffsetDateTime,
+    event: CriticalEvent,
+) {
+    if let Some(account_name) = event.account_name() {
+        warnings
+            .write()
+            .await
+            .warn_stream(account_name, now, event.to_string());
+    }
+
+    if let Some(notifier) = notifier {
+        notifier.notify(event);
+    }
+}
@@ -0,0 +1,96 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+
+use time::{Duration, OffsetDateTime};
+use tokio::sync::RwLock;
+
+/// How many warnings are kept of each stream, and of the origin.
+const RECENT_WARNINGS: usize = 10;
+
+/// How long warnings are shown for.
+const WARNING_AGE: Duration = Duration::DAY;
+
+/// Something which went wrong with a stream, as shown on its status page.
+#[derive(Debug, Clone)]
+pub struct Warning {
+    pub time: OffsetDateTime,
+    pub message: String,
+}
+
+/// The latest warnings of streams, from the events of the ingest which are
+/// also alerted about, for status pages.
+#[derive(Debug, Default)]
+pub struct RecentWarnings {
+    /// The warnings of each stream, by the lowercase name of the account
+    /// which owns it.
+    streams: HashMap<String, VecDeque<Warning>>,
+    /// The warnings which concern every stream, like a full disk of the
+    /// origin.
+    origin: VecDeque<Warning>,
+}
+
+impl RecentWarnings {
+    pub fn warn_stream(&mut self, account_name: &str, time: OffsetDateTime, message: String) {
+        let warnings = self.streams.entry(account_name.to_lowercase()).or_default();
+
+        push(warnings, Warning { time, message });
+    }
+
+    pub fn warn_origin(&mut self, time: OffsetDateTime, message: String) {
+        push(&mut self.origin, Warning { time, message });
+    }
+
+    /// Returns the warnings of the stream of an account and of the origin
+    /// since a day before `now`, the latest first.
+    pub fn recent(&self, account_name: &str, now: OffsetDateTime) -> Vec<Warning> {
+        let stream = self.streams.get(&account_name.to_lowercase());
+
+        let mut warnings = stream
+            .into_iter()
+            .flatten()
+            .chain(&self.origin)
+            .filter(|w| now - w.time < WARNING_AGE)
+            .cloned()
+            .collect::<Vec<_>>();
+        warnings.sort_by_key(|w| Reverse(w.time));
+        warnings.truncate(RECENT_WARNINGS);
+
+        warnings
+    }
+}
+
+fn push(warnings: &mut VecDeque<Warning>, warning: Warning) {
+    if warnings.len() == RECENT_WARNINGS {
+        warnings.pop_front();
+    }
+
+    warnings.push_back(warning);
+}
+
+pub type Warnings = Arc<RwLock<RecentWarnings>>;
+
+#[test]
+fn keeps_recent_warnings_of_streams_and_the_origin() {
+    let now = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+    let mut warnings = RecentWarnings::default();
+
+    warnings.warn_stream("Streamer", now - Duration::DAY * 2, "old".into());
+    warnings.warn_stream("streamer", now - Duration::minutes(5), "stream".into());
+    warnings.warn_stream("other", now - Duration::minutes(2), "other".into());
+    warnings.warn_origin(now - Duration::minutes(1), "origin".into());
+
+    let recent = warnings.recent("STREAMER", now);
+    let messages = recent
+        .iter()
+        .map(|w| w.message.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(messages, ["origin", "stream"]);
+
+    for i in 0..RECENT_WARNINGS {
+        warnings.warn_stream("streamer", now, i.to_string());
+    }
+    assert_eq!(warnings.recent("streamer", now).len(), RECENT_WARNINGS);
+}